@@ -89,6 +89,41 @@ fn main() {
         .status()
         .unwrap();
 
+    Command::new("scripts/generate_syscalls.py")
+        .arg(path.join("syscall_record_case_generated.rs"))
+        .status()
+        .unwrap();
+
+    // Recorded in traces by `rd record --copy-preload-src`.
+    let git_revision = Command::new("git")
+        .args(&["rev-parse", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+        .unwrap_or_default();
+    println!("cargo:rustc-env=RD_GIT_REVISION={}", git_revision);
+    // Pick up new commits and checkouts: HEAD changes on checkout, the branch
+    // it points to on commit.
+    let git_dir = Command::new("git")
+        .args(&["rev-parse", "--git-dir"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| PathBuf::from(String::from_utf8_lossy(&output.stdout).trim()));
+    if let Some(git_dir) = git_dir {
+        let head = git_dir.join("HEAD");
+        println!("cargo:rerun-if-changed={}", head.display());
+        if let Ok(contents) = std::fs::read_to_string(&head) {
+            if let Some(head_ref) = contents.trim().strip_prefix("ref: ") {
+                println!(
+                    "cargo:rerun-if-changed={}",
+                    git_dir.join(head_ref).display()
+                );
+            }
+        }
+    }
+
     println!("cargo:rerun-if-changed=scripts/generate_syscalls.py");
     println!("cargo:rerun-if-changed=scripts/syscalls.py");
 
@@ -83,19 +83,39 @@ def write_syscallname_arch(f, arch):
     f.write("}\n")
     f.write("\n")
 
+def rust_size_of(arg_descriptor):
+    t = arg_descriptor.replace('typename ', '').replace('struct ', '')
+    if t == 'int[2]':
+        return 'size_of::<[i32; 2]>()'
+    if t == 'unsigned int':
+        return 'size_of::<u32>()'
+    if t.startswith('Arch::Arch64::'):
+        return 'size_of::<x64::%s>()' % t[len('Arch::Arch64::'):]
+    if t.startswith('Arch::'):
+        t = t[len('Arch::'):]
+        # rr calls this statx_struct to avoid clashing with the statx() function
+        if t == 'statx_struct':
+            t = 'statx'
+        return 'arch_size_of!(Arch, %s)' % t
+    raise ValueError('Unknown argument type: %s' % arg_descriptor)
+
 def write_syscall_record_cases(f):
     def write_recorder_for_arg(syscall, arg):
         arg_descriptor = getattr(syscall, 'arg' + str(arg), None)
         if isinstance(arg_descriptor, str):
-            f.write("    syscall_state.reg_parameter<%s>(%d);\n"
-                    % (arg_descriptor, arg))
+            f.write("        syscall_state.reg_parameter_with_size(t, %d, ParamSize::from(%s), None);\n"
+                    % (arg, rust_size_of(arg_descriptor)))
+    f.write("// This file has been autogenerated. DO NOT MODIFY!\n")
+    f.write("{\n")
     for name, obj in syscalls.all():
         # Irregular syscalls will be handled by hand-written code elsewhere.
         if isinstance(obj, syscalls.RegularSyscall):
-            f.write("  case Arch::%s:\n" % name)
-            for arg in range(1,6):
+            f.write("    if sys == Arch::%s {\n" % name.upper())
+            for arg in range(1,7):
                 write_recorder_for_arg(obj, arg)
-            f.write("    return PREVENT_SWITCH;\n")
+            f.write("        return Switchable::PreventSwitch;\n")
+            f.write("    }\n")
+    f.write("}\n")
 
 has_syscall = string.Template("""${no_snake_case}
 pub fn has_${syscall}_syscall(arch: SupportedArch) -> bool {
@@ -183,7 +203,7 @@ generators_for = {
     'syscall_consts_for_tests_x64_generated': lambda f: write_syscall_consts_for_tests(f, 'x64'),
     'syscall_name_arch_x86_generated': lambda f: write_syscallname_arch(f, 'x86'),
     'syscall_name_arch_x64_generated': lambda f: write_syscallname_arch(f, 'x64'),
-    'SyscallRecordCase': write_syscall_record_cases,
+    'syscall_record_case_generated': write_syscall_record_cases,
     'syscall_helper_functions_generated': write_syscall_helper_functions,
 }
 
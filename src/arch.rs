@@ -1,4 +1,5 @@
 use crate::{
+    bindings::kernel::sock_filter,
     kernel_abi::{
         x64,
         x86,
@@ -597,6 +598,9 @@ pub trait Architecture: 'static {
     #[allow(non_camel_case_types)]
     type mmap_args: Copy + 'static;
 
+    #[allow(non_camel_case_types)]
+    type sock_fprog: Copy + Default + 'static;
+
     fn as_rptr<T: 'static>(p: Self::ptr<T>) -> RemotePtr<T>;
 
     fn from_remote_ptr<T: 'static>(p: RemotePtr<T>) -> Self::ptr<T>;
@@ -621,6 +625,11 @@ pub trait Architecture: 'static {
 
     fn get_iovec(msgdata: &Self::iovec) -> (RemotePtr<Void>, usize);
 
+    /// Returns the (len, filter) of the seccomp program.
+    fn get_sock_fprog(prog: &Self::sock_fprog) -> (usize, RemotePtr<sock_filter>);
+
+    fn set_sock_fprog(prog: &mut Self::sock_fprog, len: usize, filter: RemotePtr<sock_filter>);
+
     fn set_msghdr(
         msg: &mut Self::msghdr,
         msg_control: RemotePtr<u8>,
@@ -1100,6 +1109,7 @@ impl Architecture for X86Arch {
     type user_fpregs_struct = x86::user_fpregs_struct;
     type user = x86::user;
     type mmap_args = x86::mmap_args;
+    type sock_fprog = x86::sock_fprog;
 
     fn as_rptr<T: 'static>(p: Self::ptr<T>) -> RemotePtr<T> {
         p.rptr()
@@ -1151,6 +1161,15 @@ impl Architecture for X86Arch {
         (msgdata.iov_base.rptr(), msgdata.iov_len as usize)
     }
 
+    fn get_sock_fprog(prog: &Self::sock_fprog) -> (usize, RemotePtr<sock_filter>) {
+        (prog.len as usize, RemotePtr::cast(prog.filter.rptr()))
+    }
+
+    fn set_sock_fprog(prog: &mut Self::sock_fprog, len: usize, filter: RemotePtr<sock_filter>) {
+        prog.len = len.try_into().unwrap();
+        prog.filter = RemotePtr::<x86::sock_filter>::cast(filter).into();
+    }
+
     fn set_msghdr(
         msg: &mut Self::msghdr,
         msg_control: RemotePtr<u8>,
@@ -1654,6 +1673,7 @@ impl Architecture for X64Arch {
     type user_fpregs_struct = x64::user_fpregs_struct;
     type user = x64::user;
     type mmap_args = x64::mmap_args;
+    type sock_fprog = x64::sock_fprog;
 
     fn as_rptr<T: 'static>(p: Self::ptr<T>) -> RemotePtr<T> {
         p.rptr()
@@ -1705,6 +1725,15 @@ impl Architecture for X64Arch {
         (msgdata.iov_base.rptr(), msgdata.iov_len as usize)
     }
 
+    fn get_sock_fprog(prog: &Self::sock_fprog) -> (usize, RemotePtr<sock_filter>) {
+        (prog.len as usize, RemotePtr::cast(prog.filter.rptr()))
+    }
+
+    fn set_sock_fprog(prog: &mut Self::sock_fprog, len: usize, filter: RemotePtr<sock_filter>) {
+        prog.len = len.try_into().unwrap();
+        prog.filter = RemotePtr::<x64::sock_filter>::cast(filter).into();
+    }
+
     fn set_msghdr(
         msg: &mut Self::msghdr,
         msg_control: RemotePtr<u8>,
@@ -0,0 +1,305 @@
+//! Machine code templates used by the MonkeyPatcher.
+//!
+//! DIFF NOTE: rr generates these from assembly_templates.py into
+//! AssemblyTemplates.generated. Here we write them out by hand. Each template
+//! is a sequence of raw bytes with some "fields" (immediates) that get filled
+//! in by `substitute()`. Templates without fields can be recognized in tracee
+//! memory with `matches()`.
+//!
+//! The syscall stub templates must match the stubs in syscall_hook.S in the
+//! preload library.
+
+/// Offsets into the `preload_thread_locals` page used by the syscall stubs.
+/// See preload_interface_arch.rs.
+const X86_SYSCALLBUF_STUB_ALT_STACK: u32 = 0x7000_1000;
+const X86_STUB_SCRATCH_1: u32 = 0x7000_1008;
+const X86_ALT_STACK_NESTING_LEVEL: u32 = 0x7000_100c;
+const X64_SYSCALLBUF_STUB_ALT_STACK: u32 = 0x7000_1000;
+const X64_STUB_SCRATCH_1: u32 = 0x7000_1010;
+const X64_ALT_STACK_NESTING_LEVEL: u32 = 0x7000_1018;
+
+fn set_u32(buffer: &mut [u8], offset: usize, value: u32) {
+    buffer[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn set_u64(buffer: &mut [u8], offset: usize, value: u64) {
+    buffer[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+fn le32(v: u32) -> [u8; 4] {
+    v.to_le_bytes()
+}
+
+/// The kernel's `__kernel_vsyscall` using sysenter.
+pub struct X86SysenterVsyscallImplementation;
+
+impl X86SysenterVsyscallImplementation {
+    pub const SIZE: usize = 13;
+    const BYTES: [u8; 13] = [
+        0x51, // push %ecx
+        0x52, // push %edx
+        0x55, // push %ebp
+        0x89, 0xe5, // mov %esp,%ebp
+        0x0f, 0x34, // sysenter
+        0xcd, 0x80, // int $0x80
+        0x5d, // pop %ebp
+        0x5a, // pop %edx
+        0x59, // pop %ecx
+        0xc3, // ret
+    ];
+
+    pub fn matches(buffer: &[u8]) -> bool {
+        buffer.starts_with(&Self::BYTES)
+    }
+}
+
+/// The kernel's `__kernel_vsyscall` using syscall (on AMD CPUs).
+pub struct X86SysenterVsyscallImplementationAMD;
+
+impl X86SysenterVsyscallImplementationAMD {
+    pub const SIZE: usize = 13;
+    const BYTES: [u8; 13] = [
+        0x51, // push %ecx
+        0x52, // push %edx
+        0x55, // push %ebp
+        0x89, 0xcd, // mov %ecx,%ebp
+        0x0f, 0x05, // syscall
+        0xcd, 0x80, // int $0x80
+        0x5d, // pop %ebp
+        0x5a, // pop %edx
+        0x59, // pop %ecx
+        0xc3, // ret
+    ];
+
+    pub fn matches(buffer: &[u8]) -> bool {
+        buffer.starts_with(&Self::BYTES)
+    }
+}
+
+/// Jump from `__kernel_vsyscall` to the preload library's vsyscall hook.
+pub struct X86SysenterVsyscallSyscallHook;
+
+impl X86SysenterVsyscallSyscallHook {
+    pub const SIZE: usize = 5;
+
+    pub fn substitute(buffer: &mut [u8], syscall_hook_trampoline: u32) {
+        buffer[0] = 0xe9; // jmp $syscall_hook_trampoline
+        set_u32(buffer, 1, syscall_hook_trampoline);
+    }
+}
+
+/// Replacement for a vdso function that makes a real syscall.
+pub struct X86VsyscallMonkeypatch;
+
+impl X86VsyscallMonkeypatch {
+    pub const SIZE: usize = 21;
+    /// Offset of the syscall instruction from the start of the patch.
+    pub const SYSCALL_INSN_OFFSET: usize = 14;
+
+    #[rustfmt::skip]
+    pub fn substitute(buffer: &mut [u8], syscall_number: u32) {
+        let n = le32(syscall_number);
+        let bytes: [u8; Self::SIZE] = [
+            0x53, // push %ebx
+            0xb8, n[0], n[1], n[2], n[3], // mov $syscall_number,%eax
+            // __vdso functions use the C calling convention, so
+            // we have to set up the syscall parameters here.
+            // No x86-32 __vdso functions take more than two parameters.
+            0x8b, 0x5c, 0x24, 0x08, // mov 0x8(%esp),%ebx
+            0x8b, 0x4c, 0x24, 0x0c, // mov 0xc(%esp),%ecx
+            0xcd, 0x80, // int $0x80
+            // pad with NOPs to make room to dynamically patch the syscall
+            // with a call to the preload library, once syscall buffering
+            // has been initialized.
+            0x90, // nop
+            0x90, // nop
+            0x90, // nop
+            0x5b, // pop %ebx
+            0xc3, // ret
+        ];
+        buffer[0..Self::SIZE].copy_from_slice(&bytes);
+    }
+}
+
+/// Relative jump from a patched syscall site to its extended jump stub.
+pub struct X86SyscallStubMonkeypatch;
+
+impl X86SyscallStubMonkeypatch {
+    pub const SIZE: usize = 5;
+
+    pub fn substitute(buffer: &mut [u8], relative_addr: u32) {
+        buffer[0] = 0xe9; // jmp $relative_addr
+        set_u32(buffer, 1, relative_addr);
+    }
+}
+
+/// Stub in an extended jump page that switches to the alt stack and calls the
+/// syscall hook.
+pub struct X86SyscallStubExtendedJump;
+
+impl X86SyscallStubExtendedJump {
+    pub const SIZE: usize = 43;
+
+    #[rustfmt::skip]
+    pub fn substitute(buffer: &mut [u8], return_addr: u32, trampoline_relative_addr: u32) {
+        let scratch = le32(X86_STUB_SCRATCH_1);
+        let nesting = le32(X86_ALT_STACK_NESTING_LEVEL);
+        let alt_stack = le32(X86_SYSCALLBUF_STUB_ALT_STACK);
+        let ret = le32(return_addr);
+        let target = le32(trampoline_relative_addr);
+        let bytes: [u8; Self::SIZE] = [
+            // movl %esp,(stub_scratch_1)
+            0x89, 0x25, scratch[0], scratch[1], scratch[2], scratch[3],
+            // incl (alt_stack_nesting_level)
+            0xff, 0x05, nesting[0], nesting[1], nesting[2], nesting[3],
+            // cmpl $1,(alt_stack_nesting_level)
+            0x83, 0x3d, nesting[0], nesting[1], nesting[2], nesting[3], 0x01,
+            // jne dont_switch
+            0x75, 0x06,
+            // movl (syscallbuf_stub_alt_stack),%esp
+            0x8b, 0x25, alt_stack[0], alt_stack[1], alt_stack[2], alt_stack[3],
+            // dont_switch:
+            // pushl (stub_scratch_1)
+            0xff, 0x35, scratch[0], scratch[1], scratch[2], scratch[3],
+            // pushl $return_addr
+            0x68, ret[0], ret[1], ret[2], ret[3],
+            // jmp $trampoline_relative_addr
+            0xe9, target[0], target[1], target[2], target[3],
+        ];
+        buffer[0..Self::SIZE].copy_from_slice(&bytes);
+    }
+}
+
+/// Relative jump from a patched syscall site to its extended jump stub.
+pub struct X64JumpMonkeypatch;
+
+impl X64JumpMonkeypatch {
+    pub const SIZE: usize = 5;
+
+    pub fn substitute(buffer: &mut [u8], relative_addr: u32) {
+        buffer[0] = 0xe9; // jmp $relative_addr
+        set_u32(buffer, 1, relative_addr);
+    }
+}
+
+/// The start of glibc's `_dl_runtime_resolve_{fxsave,xsave,xsavec}`, which
+/// aligns the stack to 64 bytes.
+pub struct X64DLRuntimeResolve;
+
+impl X64DLRuntimeResolve {
+    pub const SIZE: usize = 8;
+    const BYTES: [u8; 8] = [
+        0x53, // push %rbx
+        0x48, 0x89, 0xe3, // mov %rsp,%rbx
+        0x48, 0x83, 0xe4, 0xc0, // and $0xffffffffffffffc0,%rsp
+    ];
+
+    pub fn matches(buffer: &[u8]) -> bool {
+        buffer.starts_with(&Self::BYTES)
+    }
+}
+
+/// Like `X64DLRuntimeResolve`, but aligning the stack to 16 bytes.
+pub struct X64DLRuntimeResolve2;
+
+impl X64DLRuntimeResolve2 {
+    pub const SIZE: usize = 8;
+    const BYTES: [u8; 8] = [
+        0x53, // push %rbx
+        0x48, 0x89, 0xe3, // mov %rsp,%rbx
+        0x48, 0x83, 0xe4, 0xf0, // and $0xfffffffffffffff0,%rsp
+    ];
+
+    pub fn matches(buffer: &[u8]) -> bool {
+        buffer.starts_with(&Self::BYTES)
+    }
+}
+
+/// Stub in an extended jump page that clears the x87 FDP register, then runs
+/// the instructions of `_dl_runtime_resolve_*` that the jump to this stub
+/// replaced and jumps back to the rest of it.
+///
+/// DIFF NOTE: rr hardcodes the `X64DLRuntimeResolve` instructions here. We
+/// copy whichever of the two variants was found.
+pub struct X64DLRuntimeResolvePrelude;
+
+impl X64DLRuntimeResolvePrelude {
+    pub const SIZE: usize = 30;
+
+    #[rustfmt::skip]
+    pub fn substitute(buffer: &mut [u8], original: &[u8], jump_target: u32) {
+        let bytes: [u8; 17] = [
+            // fstenv -32(%rsp)
+            0xd9, 0x74, 0x24, 0xe0,
+            // movq $0,-12(%rsp)
+            0x48, 0xc7, 0x44, 0x24, 0xf4, 0x00, 0x00, 0x00, 0x00,
+            // fldenv -32(%rsp)
+            0xd9, 0x64, 0x24, 0xe0,
+        ];
+        buffer[0..17].copy_from_slice(&bytes);
+        buffer[17..25].copy_from_slice(&original[0..X64DLRuntimeResolve::SIZE]);
+        buffer[25] = 0xe9; // jmp $jump_target
+        set_u32(buffer, 26, jump_target);
+    }
+}
+
+/// Replacement for a vdso function that makes a real syscall.
+pub struct X64VsyscallMonkeypatch;
+
+impl X64VsyscallMonkeypatch {
+    pub const SIZE: usize = 8;
+    /// Offset of the syscall instruction from the start of the patch.
+    pub const SYSCALL_INSN_OFFSET: usize = 5;
+
+    pub fn substitute(buffer: &mut [u8], syscall_number: u32) {
+        buffer[0] = 0xb8; // mov $syscall_number,%eax
+        set_u32(buffer, 1, syscall_number);
+        buffer[5] = 0x0f; // syscall
+        buffer[6] = 0x05;
+        buffer[7] = 0xc3; // ret
+    }
+}
+
+/// Stub in an extended jump page that switches to the alt stack and calls the
+/// syscall hook.
+pub struct X64SyscallStubExtendedJump;
+
+impl X64SyscallStubExtendedJump {
+    pub const SIZE: usize = 78;
+
+    #[rustfmt::skip]
+    pub fn substitute(buffer: &mut [u8], return_addr: u64, jump_target: u64) {
+        let scratch = le32(X64_STUB_SCRATCH_1);
+        let nesting = le32(X64_ALT_STACK_NESTING_LEVEL);
+        let alt_stack = le32(X64_SYSCALLBUF_STUB_ALT_STACK);
+        let bytes: [u8; 70] = [
+            // movq %rsp,(stub_scratch_1)
+            0x48, 0x89, 0x24, 0x25, scratch[0], scratch[1], scratch[2], scratch[3],
+            // incl (alt_stack_nesting_level)
+            0xff, 0x04, 0x25, nesting[0], nesting[1], nesting[2], nesting[3],
+            // cmpl $1,(alt_stack_nesting_level)
+            0x83, 0x3c, 0x25, nesting[0], nesting[1], nesting[2], nesting[3], 0x01,
+            // jne dont_switch
+            0x75, 0x08,
+            // movq (syscallbuf_stub_alt_stack),%rsp
+            0x48, 0x8b, 0x24, 0x25, alt_stack[0], alt_stack[1], alt_stack[2], alt_stack[3],
+            // dont_switch:
+            // lea -256(%rsp),%rsp
+            0x48, 0x8d, 0xa4, 0x24, 0x00, 0xff, 0xff, 0xff,
+            // pushq (stub_scratch_1)
+            0xff, 0x34, 0x25, scratch[0], scratch[1], scratch[2], scratch[3],
+            // pushq %rax (placeholder for the return address)
+            0x50,
+            // movl $return_addr_lo,(%rsp)
+            0xc7, 0x04, 0x24, 0, 0, 0, 0,
+            // movl $return_addr_hi,4(%rsp)
+            0xc7, 0x44, 0x24, 0x04, 0, 0, 0, 0,
+            // jmp *0(%rip)
+            0xff, 0x25, 0x00, 0x00, 0x00, 0x00,
+        ];
+        buffer[0..bytes.len()].copy_from_slice(&bytes);
+        set_u32(buffer, 52, return_addr as u32);
+        set_u32(buffer, 60, (return_addr >> 32) as u32);
+        set_u64(buffer, 70, jump_target);
+    }
+}
@@ -544,8 +544,11 @@ impl<'a> AutoRemoteSyscalls<'a> {
     ///  doing.  *ESPECIALLY* don't call this on a `t` other than
     ///  the one passed to the constructor, unless you really know
     ///  what you're doing.
-    pub fn restore_state_to(&mut self, maybe_other_task: Option<&'a mut dyn Task>) {
-        let some_t = maybe_other_task.unwrap_or(self.t);
+    pub fn restore_state_to(&mut self, maybe_other_task: Option<&mut dyn Task>) {
+        let some_t = match maybe_other_task {
+            Some(other_task) => other_task,
+            None => &mut *self.t,
+        };
         // Unmap our scratch region if required
         if self.scratch_mem_was_mapped {
             let mut remote = AutoRemoteSyscalls::new(some_t);
@@ -3,12 +3,15 @@ use exit_result::ExitResult;
 pub mod build_id_command;
 pub mod dump_command;
 pub mod exit_result;
+pub mod pack_command;
 pub mod ps_command;
 pub mod rd_options;
+pub mod recompress_command;
 pub mod record_command;
 pub mod replay_command;
 pub mod rerun_command;
 pub mod trace_info_command;
+pub mod verify_command;
 
 pub trait RdCommand {
     fn run(&mut self) -> ExitResult<()>;
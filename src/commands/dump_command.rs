@@ -138,6 +138,12 @@ impl DumpCommand {
             last_time = the_time;
         }
 
+        // Don't decompress all the frames before the ones we want. But read
+        // the trace the same way with or without an index when statistics
+        // are wanted, so they never depend on how the trace was read.
+        if !self.statistics {
+            trace.seek_to_indexed_frame(start);
+        }
         let process_raw_data = self.dump_syscallbuf || self.dump_recorded_data_metadata;
         while !trace.at_end() {
             let frame = trace.read_frame();
@@ -0,0 +1,560 @@
+use super::exit_result::ExitResult;
+use crate::{
+    commands::{
+        rd_options::{RdOptions, RdSubCommand},
+        RdCommand,
+    },
+    log::LogLevel::{LogDebug, LogWarn},
+    trace::{
+        compressed_reader::{BlockLocation, CompressedReader},
+        compressed_writer::CompressedWriter,
+        compression::Compression,
+        trace_index::{read_index, remap_index, write_index},
+        trace_reader::TraceReader,
+        trace_stream::{Substream, TraceStream, SUBSTREAMS},
+    },
+    trace_capnp::m_map,
+};
+use capnp::{
+    message::{self, ReaderOptions},
+    serialize_packed::{read_message, write_message},
+};
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, BTreeSet, HashMap},
+    ffi::{OsStr, OsString},
+    fs,
+    hash::Hasher,
+    io::{self, BufRead, Read},
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::{Path, PathBuf},
+};
+
+/// Make a trace self-contained: every file that mmaps are replayed from ends
+/// up inside the trace directory, so the trace keeps working after it is moved
+/// to another machine or the original files are replaced.
+///
+/// Files referenced by absolute path are hardlinked into the trace directory
+/// (or copied if that fails). Files with identical contents are collapsed
+/// into one. The mmaps substream is then rewritten to refer to the packed
+/// files, and the frame index is updated to match it.
+///
+/// Packing has to happen before the original files change: a file that was
+/// modified in place since the recording can't be recovered. Packing is
+/// refused if a file's size or mtime differs from what was recorded.
+pub struct PackCommand {
+    trace_dir: Option<PathBuf>,
+}
+
+impl PackCommand {
+    pub fn new(options: &RdOptions) -> PackCommand {
+        match options.cmd.clone() {
+            RdSubCommand::Pack { trace_dir } => PackCommand { trace_dir },
+            _ => panic!("Unexpected RdSubCommand variant. Not a `Pack` variant!"),
+        }
+    }
+}
+
+impl RdCommand for PackCommand {
+    fn run(&mut self) -> ExitResult<()> {
+        match self.pack() {
+            Ok(()) => ExitResult::Ok(()),
+            Err(e) => ExitResult::err_from(e, 1),
+        }
+    }
+}
+
+impl PackCommand {
+    fn pack(&self) -> io::Result<()> {
+        let trace = TraceReader::new(self.trace_dir.as_ref());
+        let dir = PathBuf::from(trace.dir());
+        let mmaps_path = trace.mmaps_path();
+
+        let backing_files = collect_backing_files(&mmaps_path)?;
+        check_backing_files(&dir, &backing_files)?;
+        let names: BTreeSet<OsString> = backing_files.keys().cloned().collect();
+        let file_map = pack_files(&dir, &names)?;
+
+        // Keep the codec and level the trace was recorded (or recompressed)
+        // with. Older traces only tell the codec.
+        let compression = match trace.substream_compression(Substream::Mmaps) {
+            Some(compression) => compression,
+            None => CompressedReader::new(&mmaps_path)
+                .compression()?
+                .unwrap_or_default(),
+        };
+        let mut new_mmaps_path = mmaps_path.clone();
+        new_mmaps_path.push(".tmp");
+        // Left over from an earlier attempt that failed
+        remove_file_if_exists(&new_mmaps_path)?;
+        let moved = rewrite_mmaps(&mmaps_path, &new_mmaps_path, &file_map, compression)?;
+
+        // Only the mmaps substream changed, but the index has to follow it.
+        let index_path = trace.index_path();
+        let mut new_index_path = index_path.clone();
+        new_index_path.push(".tmp");
+        let index = read_index(&index_path);
+        let has_new_index = !index.is_empty() && {
+            let mut old_blocks: Vec<Vec<BlockLocation>> = Vec::new();
+            for &s in SUBSTREAMS.iter() {
+                old_blocks.push(CompressedReader::new(&trace.path(s)).block_locations()?);
+            }
+            let mut new_blocks = old_blocks.clone();
+            new_blocks[Substream::Mmaps as usize] =
+                CompressedReader::new(&new_mmaps_path).block_locations()?;
+            let new_pos = |i: usize, pos: u64| {
+                if i != Substream::Mmaps as usize {
+                    return Some(pos);
+                }
+                let j = moved.binary_search_by_key(&pos, |&(old, _)| old).ok()?;
+                Some(moved[j].1)
+            };
+            match remap_index(&index, &old_blocks, &new_blocks, new_pos) {
+                Some(new_index) => {
+                    write_index(&new_index_path, &new_index)?;
+                    true
+                }
+                None => {
+                    log!(LogWarn, "Trace index doesn't match the trace, dropping it");
+                    false
+                }
+            }
+        };
+        if has_new_index {
+            fs::rename(&new_index_path, &index_path)?;
+        } else {
+            remove_file_if_exists(&index_path)?;
+        }
+        fs::rename(&new_mmaps_path, &mmaps_path)?;
+
+        // Files in the trace directory that turned out to be duplicates are
+        // no longer referenced.
+        for (name, packed_name) in &file_map {
+            if !is_absolute(name) && name != packed_name {
+                log!(LogDebug, "Removing duplicate {:?}", name);
+                fs::remove_file(dir.join(name))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn remove_file_if_exists(path: &OsStr) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn is_absolute(name: &OsStr) -> bool {
+    name.as_bytes().starts_with(b"/")
+}
+
+fn capnp_error(e: capnp::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e))
+}
+
+/// The size and mtime of a backing file when it was mapped during recording.
+#[derive(Copy, Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+struct RecordedStat {
+    size: i64,
+    mtime: i64,
+}
+
+/// Return the backing file names of all file-backed mappings, as they appear
+/// in the trace: either absolute or relative to the trace directory, with the
+/// stats recorded for each of them.
+///
+/// Like replay, don't expect stats for files rd copied into the trace or
+/// for mappings recorded without them.
+fn collect_backing_files(
+    mmaps_path: &OsStr,
+) -> io::Result<BTreeMap<OsString, BTreeSet<RecordedStat>>> {
+    let mut files: BTreeMap<OsString, BTreeSet<RecordedStat>> = BTreeMap::new();
+    let mut mmaps = CompressedReader::new(mmaps_path);
+    while !mmaps.at_end() {
+        let map_msg = read_message(&mut mmaps, ReaderOptions::new()).map_err(capnp_error)?;
+        let map = map_msg.get_root::<m_map::Reader>().map_err(capnp_error)?;
+        if let Ok(m_map::source::File(f)) = map.get_source().which() {
+            let name = f.get_backing_file_name().map_err(capnp_error)?;
+            let stats = files
+                .entry(OsStr::from_bytes(name).to_os_string())
+                .or_default();
+            let is_clone = name.starts_with(b"mmap_clone_");
+            let is_copy = name.starts_with(b"mmap_copy_");
+            let has_stat_buf = map.get_stat_mode() != 0
+                || map.get_stat_uid() != 0
+                || map.get_stat_gid() != 0
+                || map.get_stat_m_time() != 0;
+            if !is_clone && !is_copy && has_stat_buf {
+                stats.insert(RecordedStat {
+                    size: map.get_stat_size(),
+                    mtime: map.get_stat_m_time(),
+                });
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Fail if any of `files` no longer has the size and mtime it was recorded
+/// with. Packing a file that changed since the recording would make the
+/// divergence permanent, so it's better to stop.
+fn check_backing_files(
+    dir: &Path,
+    files: &BTreeMap<OsString, BTreeSet<RecordedStat>>,
+) -> io::Result<()> {
+    let mut changed = Vec::new();
+    for (name, stats) in files {
+        let path = dir.join(name);
+        let metadata = fs::metadata(&path)
+            .map_err(|e| io::Error::new(e.kind(), format!("Can't stat {:?}: {}", path, e)))?;
+        for stat in stats {
+            if stat.size != metadata.size() as i64 || stat.mtime != metadata.mtime() {
+                changed.push(format!(
+                    "{:?}: size {}/{}; mtime {}/{}",
+                    path,
+                    metadata.size(),
+                    stat.size,
+                    metadata.mtime(),
+                    stat.mtime
+                ));
+            }
+        }
+    }
+    if changed.is_empty() {
+        return Ok(());
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "Files changed since they were recorded, can't pack them:\n{}",
+            changed.join("\n")
+        ),
+    ))
+}
+
+fn content_hash(contents: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(contents);
+    hasher.finish()
+}
+
+/// Make sure each of `names` has a copy in `dir` and return a map from each
+/// name to the name of its packed copy, relative to `dir`.
+///
+/// Identical files are found by size and a hash of their contents, then
+/// confirmed by comparing contents, so hash collisions are harmless.
+fn pack_files(dir: &Path, names: &BTreeSet<OsString>) -> io::Result<HashMap<OsString, OsString>> {
+    let mut packed_by_hash: HashMap<(usize, u64), Vec<OsString>> = HashMap::new();
+    let mut file_map = HashMap::new();
+    for (i, name) in names.iter().enumerate() {
+        let path = dir.join(name);
+        let contents = fs::read(&path)
+            .map_err(|e| io::Error::new(e.kind(), format!("Can't read {:?}: {}", path, e)))?;
+        let candidates = packed_by_hash
+            .entry((contents.len(), content_hash(&contents)))
+            .or_default();
+
+        let mut packed_name = None;
+        for c in candidates.iter() {
+            if fs::read(dir.join(c))? == contents {
+                packed_name = Some(c.clone());
+                break;
+            }
+        }
+        let packed_name = match packed_name {
+            Some(packed_name) => packed_name,
+            None => {
+                let packed_name = if is_absolute(name) {
+                    pack_file(dir, &path, i)?
+                } else {
+                    name.clone()
+                };
+                candidates.push(packed_name.clone());
+                packed_name
+            }
+        };
+        log!(LogDebug, "Packing {:?} as {:?}", name, packed_name);
+        file_map.insert(name.clone(), packed_name);
+    }
+    Ok(file_map)
+}
+
+/// Hardlink or copy `path` into `dir`. Returns the new name relative to `dir`.
+fn pack_file(dir: &Path, path: &Path, index: usize) -> io::Result<OsString> {
+    let mut packed_name = OsString::from(format!("mmap_pack_{}_", index));
+    packed_name.push(path.file_name().unwrap_or_default());
+    let dest = dir.join(&packed_name);
+    if fs::hard_link(path, &dest).is_err() {
+        fs::copy(path, &dest)?;
+    }
+    Ok(packed_name)
+}
+
+/// Counts the bytes read through it.
+struct CountingReader<R> {
+    inner: R,
+    pos: u64,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for CountingReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos += amt as u64;
+        self.inner.consume(amt)
+    }
+}
+
+/// Write a copy of the mmaps substream to `new_path` in which file-backed
+/// mappings refer to the packed files. The stat data of the original files is
+/// erased because the packed files (and the machine the trace is replayed on)
+/// won't match it.
+///
+/// Returns the uncompressed position of every mapping in the old and in the
+/// new file, followed by the end of both files.
+fn rewrite_mmaps(
+    mmaps_path: &OsStr,
+    new_path: &OsStr,
+    file_map: &HashMap<OsString, OsString>,
+    compression: Compression,
+) -> io::Result<Vec<(u64, u64)>> {
+    let mut moved = Vec::new();
+    {
+        let mut mmaps = CountingReader {
+            inner: CompressedReader::new(mmaps_path),
+            pos: 0,
+        };
+        let mut writer =
+            CompressedWriter::new(new_path, TraceStream::mmaps_block_size(), 1, compression);
+        while !mmaps.inner.at_end() {
+            moved.push((mmaps.pos, writer.uncompressed_pos()));
+            let map_msg = read_message(&mut mmaps, ReaderOptions::new()).map_err(capnp_error)?;
+            let map = map_msg.get_root::<m_map::Reader>().map_err(capnp_error)?;
+            let packed_name = match map.get_source().which() {
+                Ok(m_map::source::File(f)) => {
+                    let name = f.get_backing_file_name().map_err(capnp_error)?;
+                    file_map.get(OsStr::from_bytes(name))
+                }
+                _ => None,
+            };
+
+            let mut new_msg = message::Builder::new_default();
+            new_msg.set_root(map).map_err(capnp_error)?;
+            if let Some(packed_name) = packed_name {
+                let mut new_map = new_msg.get_root::<m_map::Builder>().map_err(capnp_error)?;
+                new_map
+                    .reborrow()
+                    .get_source()
+                    .init_file()
+                    .set_backing_file_name(packed_name.as_bytes());
+                new_map.set_stat_mode(0);
+                new_map.set_stat_uid(0);
+                new_map.set_stat_gid(0);
+                new_map.set_stat_m_time(0);
+            }
+            write_message(&mut writer, &new_msg)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))?;
+        }
+        moved.push((mmaps.pos, writer.uncompressed_pos()));
+        writer.close(None);
+        if !writer.good() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Failed to write {:?}", new_path),
+            ));
+        }
+    }
+    Ok(moved)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        trace::compression::{Codec, Compression},
+        util::test_dir,
+    };
+
+    /// (backing file name, or None for a mapping whose data is in the trace)
+    /// The stats of the backing files are recorded as they are now.
+    fn write_mmaps(path: &OsStr, names: &[Option<&OsStr>], compression: Compression) {
+        let dir = Path::new(path).parent().unwrap();
+        let mut writer =
+            CompressedWriter::new(path, TraceStream::mmaps_block_size(), 1, compression);
+        for (i, name) in names.iter().enumerate() {
+            let mut msg = message::Builder::new_default();
+            let mut map = msg.init_root::<m_map::Builder>();
+            map.set_frame_time(i as i64);
+            map.set_stat_mode(0o100644);
+            map.set_stat_uid(1000);
+            map.set_stat_gid(1000);
+            if let Some(name) = name {
+                let metadata = fs::metadata(dir.join(name)).unwrap();
+                map.set_stat_size(metadata.size() as i64);
+                map.set_stat_m_time(metadata.mtime());
+            }
+            // Something repetitive, so the block really gets compressed.
+            map.set_fsname(b"/usr/lib/x86_64-linux-gnu/libpack-test.so.1.0.0");
+            match name {
+                Some(name) => map
+                    .get_source()
+                    .init_file()
+                    .set_backing_file_name(name.as_bytes()),
+                None => map.get_source().set_trace(()),
+            }
+            write_message(&mut writer, &msg).unwrap();
+        }
+        writer.close(None);
+        assert!(writer.good());
+    }
+
+    /// (backing file name, stat mode) of each mapping in the mmaps file.
+    fn read_mmaps(path: &OsStr) -> Vec<(Option<OsString>, u32)> {
+        let mut result = Vec::new();
+        let mut mmaps = CompressedReader::new(path);
+        while !mmaps.at_end() {
+            let map_msg = read_message(&mut mmaps, ReaderOptions::new()).unwrap();
+            let map = map_msg.get_root::<m_map::Reader>().unwrap();
+            let name = match map.get_source().which().unwrap() {
+                m_map::source::File(f) => {
+                    Some(OsStr::from_bytes(f.get_backing_file_name().unwrap()).to_os_string())
+                }
+                _ => None,
+            };
+            result.push((name, map.get_stat_mode()));
+        }
+        result
+    }
+
+    #[test]
+    fn pack_round_trip_test() {
+        let dir = test_dir("pack");
+        let outside = dir.join("outside");
+        let trace = dir.join("trace");
+        fs::create_dir(&outside).unwrap();
+        fs::create_dir(&trace).unwrap();
+        let lib_a = outside.join("liba.so");
+        let lib_a_copy = outside.join("liba-copy.so");
+        let lib_b = outside.join("libb.so");
+        fs::write(&lib_a, b"contents of a").unwrap();
+        fs::write(&lib_a_copy, b"contents of a").unwrap();
+        fs::write(&lib_b, b"contents of b").unwrap();
+        // A file rd already copied into the trace that duplicates liba.so.
+        fs::write(trace.join("mmap_clone_7_liba.so"), b"contents of a").unwrap();
+
+        let mmaps_path = trace.join("mmaps");
+        let zstd = Compression::new(Codec::Zstd);
+        write_mmaps(
+            mmaps_path.as_os_str(),
+            &[
+                Some(lib_a.as_os_str()),
+                None,
+                Some(lib_b.as_os_str()),
+                Some(lib_a_copy.as_os_str()),
+                Some(OsStr::new("mmap_clone_7_liba.so")),
+                Some(lib_a.as_os_str()),
+            ],
+            zstd,
+        );
+
+        let backing_files = collect_backing_files(mmaps_path.as_os_str()).unwrap();
+        assert_eq!(backing_files.len(), 4);
+        // No stats are expected for files rd copied into the trace.
+        assert!(backing_files[OsStr::new("mmap_clone_7_liba.so")].is_empty());
+        check_backing_files(&trace, &backing_files).unwrap();
+        let names: BTreeSet<OsString> = backing_files.keys().cloned().collect();
+        let file_map = pack_files(&trace, &names).unwrap();
+        let new_mmaps_path = trace.join("mmaps.new");
+        let moved = rewrite_mmaps(
+            mmaps_path.as_os_str(),
+            new_mmaps_path.as_os_str(),
+            &file_map,
+            zstd,
+        )
+        .unwrap();
+        // Every mapping, then the end of the files.
+        assert_eq!(moved.len(), 7);
+        assert_eq!(moved[0], (0, 0));
+        let old_len = CompressedReader::new(mmaps_path.as_os_str())
+            .uncompressed_bytes()
+            .unwrap();
+        let new_len = CompressedReader::new(new_mmaps_path.as_os_str())
+            .uncompressed_bytes()
+            .unwrap();
+        assert_eq!(moved[6], (old_len, new_len));
+        // Each new position is where the same mapping starts in the new file.
+        let mut new_mmaps = CountingReader {
+            inner: CompressedReader::new(new_mmaps_path.as_os_str()),
+            pos: 0,
+        };
+        for (i, &(_, new_pos)) in moved[0..6].iter().enumerate() {
+            assert_eq!(new_mmaps.pos, new_pos);
+            let map_msg = read_message(&mut new_mmaps, ReaderOptions::new()).unwrap();
+            let map = map_msg.get_root::<m_map::Reader>().unwrap();
+            assert_eq!(map.get_frame_time(), i as i64);
+        }
+        fs::rename(&new_mmaps_path, &mmaps_path).unwrap();
+
+        let maps = read_mmaps(mmaps_path.as_os_str());
+        assert_eq!(maps.len(), 6);
+        // The mapping whose data is in the trace is left alone.
+        assert_eq!(maps[1], (None, 0o100644));
+        let names: Vec<OsString> = maps
+            .iter()
+            .filter_map(|(name, mode)| {
+                assert!(name.is_none() || *mode == 0);
+                name.clone()
+            })
+            .collect();
+        // Identical files share one packed copy, named after the first of
+        // them in sort order. Absolute names sort before relative ones.
+        assert_eq!(names[0], "mmap_pack_0_liba-copy.so");
+        assert_eq!(names[2], names[0]);
+        assert_eq!(names[3], names[0]);
+        assert_eq!(names[4], names[0]);
+        assert_eq!(names[1], "mmap_pack_2_libb.so");
+        assert_eq!(fs::read(trace.join(&names[0])).unwrap(), b"contents of a");
+        assert_eq!(fs::read(trace.join(&names[1])).unwrap(), b"contents of b");
+        // The rewritten substream uses the compression it was given.
+        assert_eq!(
+            CompressedReader::new(mmaps_path.as_os_str())
+                .compression()
+                .unwrap(),
+            Some(zstd)
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pack_changed_file_test() {
+        let dir = test_dir("pack-changed");
+        let lib = dir.join("lib.so");
+        fs::write(&lib, b"contents").unwrap();
+        let mmaps_path = dir.join("mmaps");
+        write_mmaps(
+            mmaps_path.as_os_str(),
+            &[Some(lib.as_os_str())],
+            Compression::new(Codec::Zstd),
+        );
+
+        let backing_files = collect_backing_files(mmaps_path.as_os_str()).unwrap();
+        check_backing_files(&dir, &backing_files).unwrap();
+        fs::write(&lib, b"new contents").unwrap();
+        let e = check_backing_files(&dir, &backing_files).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
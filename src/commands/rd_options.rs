@@ -7,7 +7,12 @@ use crate::{
     session::record_session::TraceUuid,
     sig::Sig,
     ticks::Ticks,
-    trace::trace_frame::FrameTime,
+    trace::{
+        compressed_writer::MAX_BLOCK_SIZE,
+        compression::Compression,
+        trace_frame::FrameTime,
+        trace_stream::Substream,
+    },
     util::{find, page_size},
 };
 use libc::pid_t;
@@ -251,7 +256,8 @@ pub enum RdSubCommand {
         onfork: Option<pid_t>,
 
         /// Where <goto-event> := <event-num>. Start a debug server on reaching <event-num>
-        /// in the trace.  See -M in the general options
+        /// in the trace.  See -M in the general options. Replay still has to execute
+        /// every event before <event-num>, so this doesn't use the trace index
         #[structopt(short = "g", long = "goto", parse(try_from_str = parse_goto_event))]
         goto_event: Option<FrameTime>,
 
@@ -310,6 +316,21 @@ pub enum RdSubCommand {
         #[structopt(short = "u", long = "cpu-unbound")]
         cpu_unbound: bool,
 
+        /// Don't abort when the data written to RD_MAGIC_SAVE_DATA_FD during replay differs
+        /// from the recording. Report every such divergence at the end of the replay instead.
+        #[structopt(long = "keep-going-on-save-data-mismatch")]
+        keep_going_on_save_data_mismatch: bool,
+
+        /// Where <core-at> := <event-num>. Replay to <event-num>, write an ELF core file for the
+        /// current thread group there and exit
+        #[structopt(long = "core-at", parse(try_from_str = parse_goto_event))]
+        core_at: Option<FrameTime>,
+
+        /// Where to write the core file for --core-at. Default is core.<tid> in the current
+        /// directory
+        #[structopt(long = "core-file", parse(from_os_str))]
+        core_file: Option<PathBuf>,
+
         /// Execute gdb commands from <gdb-x-file>
         #[structopt(short = "x", long = "gdb-x")]
         gdb_x_file: Option<OsString>,
@@ -461,6 +482,20 @@ pub enum RdSubCommand {
         #[structopt(long = "copy-preload-src")]
         copy_preload_src: bool,
 
+        #[structopt(
+            long = "compression",
+            multiple = true,
+            number_of_values = 1,
+            parse(try_from_str = parse_compression),
+            help = "How to compress the trace.\n\
+                    Where <compression> := [<substream>=]<codec>[:<level>]\n\
+                    <substream>: events, data, mmaps or tasks. All substreams if omitted.\n\
+                    <codec>: brotli (the default, level 5), zstd, lz4 or none.\n\
+                    Later values override earlier ones, e.g.\n\
+                    --compression lz4 --compression data=zstd:1"
+        )]
+        compression: Option<Vec<(Option<Substream>, Compression)>>,
+
         /// Program being recorded
         exe: OsString,
 
@@ -519,12 +554,59 @@ pub enum RdSubCommand {
         trace_dir: Option<PathBuf>,
     },
 
+    /// Copy all files that mmaps are replayed from into the trace directory, deduplicating
+    /// identical files, so that the trace can be moved to another machine.
+    #[structopt(name = "pack")]
+    Pack {
+        /// Which directory is the trace data in? If omitted the latest trace dir is used
+        trace_dir: Option<PathBuf>,
+    },
+
+    /// Compress a trace again, e.g. with a codec that's slower but gives smaller traces
+    /// than the one it was recorded with.
+    #[structopt(name = "recompress")]
+    Recompress {
+        #[structopt(
+            long = "compression",
+            multiple = true,
+            number_of_values = 1,
+            parse(try_from_str = parse_compression),
+            help = "How to compress the trace. brotli:5 if omitted.\n\
+                    Where <compression> := [<substream>=]<codec>[:<level>]\n\
+                    <substream>: events, data, mmaps or tasks. All substreams if omitted.\n\
+                    <codec>: brotli, zstd, lz4 or none.\n\
+                    Later values override earlier ones."
+        )]
+        compression: Option<Vec<(Option<Substream>, Compression)>>,
+
+        /// Block size for all substreams in kB. Defaults to each substream's usual block size.
+        #[structopt(long = "block-size", parse(try_from_str = parse_block_size))]
+        block_size: Option<usize>,
+
+        /// Number of compression threads for each substream. Defaults to each substream's
+        /// usual number of threads.
+        #[structopt(long = "threads", parse(try_from_str = parse_threads))]
+        threads: Option<usize>,
+
+        /// Which directory is the trace data in? If omitted the latest trace dir is used
+        trace_dir: Option<PathBuf>,
+    },
+
     /// Dump information on the processes encountered during recording.
     #[structopt(name = "ps")]
     Ps {
         /// Which directory is the trace data in? If omitted the latest trace dir is used
         trace_dir: Option<PathBuf>,
     },
+
+    /// Check that a trace is intact without replaying it: every block decompresses, every
+    /// record parses and the files mmaps are replayed from haven't changed. Exits with a
+    /// nonzero status and lists what's wrong if not.
+    #[structopt(name = "verify")]
+    Verify {
+        /// Which directory is the trace data in? If omitted the latest trace dir is used
+        trace_dir: Option<PathBuf>,
+    },
 }
 
 fn parse_env_name_val(maybe_name_val: &OsStr) -> Result<(OsString, OsString), OsString> {
@@ -542,6 +624,16 @@ fn parse_env_name_val(maybe_name_val: &OsStr) -> Result<(OsString, OsString), Os
     }
 }
 
+fn parse_compression(spec: &str) -> Result<(Option<Substream>, Compression), String> {
+    match spec.find('=') {
+        Some(n) => match Substream::from_name(&spec[0..n]) {
+            Some(s) => Ok((Some(s), spec[n + 1..].parse()?)),
+            None => Err(format!("Unknown substream `{}`", &spec[0..n])),
+        },
+        None => Ok((None, spec.parse()?)),
+    }
+}
+
 fn parse_trace_id(maybe_trace_id: &str) -> Result<TraceUuid, Box<dyn Error>> {
     const SUM_GROUP_LENS: [u8; 5] = [8, 12, 16, 20, 32];
     // Parse UUIDs from string form optionally with hypens
@@ -642,6 +734,32 @@ fn parse_syscallbuf_size(maybe_size: &str) -> Result<usize, Box<dyn Error>> {
     }
 }
 
+fn parse_block_size(maybe_size: &str) -> Result<usize, Box<dyn Error>> {
+    let max_kb = MAX_BLOCK_SIZE / 1024;
+    match maybe_size.parse::<usize>() {
+        Err(e) => Err(Box::new(e)),
+        Ok(n) if n < 1 || n > max_kb => Err(Box::new(clap::Error::with_description(
+            &format!(
+                "Block size can be between 1 and {} (in units of kB)",
+                max_kb
+            ),
+            clap::ErrorKind::InvalidValue,
+        ))),
+        Ok(n) => Ok(n * 1024),
+    }
+}
+
+fn parse_threads(maybe_threads: &str) -> Result<usize, Box<dyn Error>> {
+    match maybe_threads.parse::<usize>() {
+        Err(e) => Err(Box::new(e)),
+        Ok(n) if n < 1 || n > 64 => Err(Box::new(clap::Error::with_description(
+            "Number of threads can be between 1 and 64",
+            clap::ErrorKind::InvalidValue,
+        ))),
+        Ok(n) => Ok(n),
+    }
+}
+
 fn parse_num_cores(maybe_num_cores: &str) -> Result<u32, Box<dyn Error>> {
     match maybe_num_cores.parse::<u32>() {
         Err(e) => Err(Box::new(e)),
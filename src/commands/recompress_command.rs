@@ -0,0 +1,306 @@
+use super::exit_result::ExitResult;
+use crate::{
+    commands::{
+        rd_options::{RdOptions, RdSubCommand},
+        RdCommand,
+    },
+    log::LogLevel::{LogDebug, LogWarn},
+    trace::{
+        compressed_reader::CompressedReader,
+        compressed_writer::{CompressedWriter, Sync},
+        compression::Compression,
+        trace_index::{read_index, remap_index, write_index},
+        trace_reader::TraceReader,
+        trace_stream::{compression_for_substreams, SUBSTREAMS, SUBSTREAM_COUNT},
+        trace_writer::copy_version_file_with_compression,
+    },
+};
+use std::{
+    ffi::{CString, OsStr, OsString},
+    fs,
+    io::{self, BufRead, Write},
+    os::unix::{ffi::OsStrExt, fs::symlink},
+    path::{Path, PathBuf},
+};
+
+/// Rewrite every substream of a trace with a different compression, block size
+/// or number of compression threads. The uncompressed data, and so everything
+/// else about the trace including its UUID, stays the same.
+///
+/// The new trace is put together in a directory next to the old one, with the
+/// files that don't change hardlinked into it, and the two directories are
+/// swapped in one final step. A failure part way through leaves the trace as
+/// it was, and readers never see new substreams with an old index.
+pub struct RecompressCommand {
+    compression: [Compression; SUBSTREAM_COUNT],
+    block_size: Option<usize>,
+    threads: Option<usize>,
+    trace_dir: Option<PathBuf>,
+}
+
+impl RecompressCommand {
+    pub fn new(options: &RdOptions) -> RecompressCommand {
+        match options.cmd.clone() {
+            RdSubCommand::Recompress {
+                compression,
+                block_size,
+                threads,
+                trace_dir,
+            } => RecompressCommand {
+                compression: compression_for_substreams(&compression.unwrap_or(Vec::new())),
+                block_size,
+                threads,
+                trace_dir,
+            },
+            _ => panic!("Unexpected RdSubCommand variant. Not a `Recompress` variant!"),
+        }
+    }
+}
+
+impl RdCommand for RecompressCommand {
+    fn run(&mut self) -> ExitResult<()> {
+        match self.recompress() {
+            Ok(()) => ExitResult::Ok(()),
+            Err(e) => ExitResult::err_from(e, 1),
+        }
+    }
+}
+
+impl RecompressCommand {
+    fn recompress(&self) -> io::Result<()> {
+        let trace = TraceReader::new(self.trace_dir.as_ref());
+        // Swap the trace directory itself, not a symlink to it like
+        // latest-trace.
+        let dir = fs::canonicalize(trace.dir())?;
+        let mut staging_name = dir.file_name().unwrap_or_default().to_os_string();
+        staging_name.push(".recompress");
+        let staging_dir = dir.with_file_name(staging_name);
+
+        let index_path = trace.index_path();
+        let substream_paths: Vec<OsString> = SUBSTREAMS.iter().map(|&s| trace.path(s)).collect();
+        // The version file records how the substreams are compressed.
+        let replaced: Vec<&OsStr> = substream_paths
+            .iter()
+            .chain(Some(&index_path))
+            .map(|path| Path::new(path).file_name().unwrap())
+            .chain(Some(OsStr::new("version")))
+            .collect();
+        let new_paths: Vec<OsString> = replaced
+            .iter()
+            .map(|&name| staging_dir.join(name).into_os_string())
+            .collect();
+
+        remove_leftover_staging_dir(&staging_dir)?;
+        fs::create_dir(&staging_dir)?;
+        let mut skipped = replaced.clone();
+        skipped.push(OsStr::new(STAGING_MARKER));
+        let result = fs::write(staging_dir.join(STAGING_MARKER), b"")
+            .and_then(|_| link_tree(&dir, &staging_dir, &skipped))
+            .and_then(|_| {
+                self.write_new_files(
+                    &trace,
+                    &new_paths[0..SUBSTREAM_COUNT],
+                    &new_paths[SUBSTREAM_COUNT],
+                )?;
+                copy_version_file_with_compression(
+                    &trace,
+                    &new_paths[SUBSTREAM_COUNT + 1],
+                    &self.compression,
+                )
+            });
+        if let Err(e) = result {
+            fs::remove_dir_all(&staging_dir).unwrap_or(());
+            return Err(e);
+        }
+
+        // The staging directory is now a complete copy of the trace. Swapping
+        // it with the trace in one step means readers see either the old
+        // trace or the new one, and the substreams always match the index.
+        if let Err(e) = exchange(&dir, &staging_dir) {
+            fs::remove_dir_all(&staging_dir).unwrap_or(());
+            return Err(e);
+        }
+        // The marker came along with the new trace. It belongs with the old
+        // one, so a failure to remove that is recognized by the next run.
+        fs::rename(dir.join(STAGING_MARKER), staging_dir.join(STAGING_MARKER))?;
+        if let Err(e) = fs::remove_dir_all(&staging_dir) {
+            log!(
+                LogWarn,
+                "Can't remove the old trace files in {:?}: {}",
+                staging_dir,
+                e
+            );
+        }
+        Ok(())
+    }
+
+    /// Write the recompressed substreams to `new_paths` and the matching index
+    /// to `new_index_path`. The index is left out if the trace has no usable
+    /// index.
+    fn write_new_files(
+        &self,
+        trace: &TraceReader,
+        new_paths: &[OsString],
+        new_index_path: &OsStr,
+    ) -> io::Result<()> {
+        let mut old_blocks = Vec::with_capacity(SUBSTREAM_COUNT);
+        let mut new_blocks = Vec::with_capacity(SUBSTREAM_COUNT);
+        for (&s, new_path) in SUBSTREAMS.iter().zip(new_paths) {
+            let compression = self.compression[s as usize];
+            log!(LogDebug, "Recompressing {} with {}", s.name(), compression);
+            let reader = CompressedReader::new(&trace.path(s));
+            old_blocks.push(reader.block_locations()?);
+            transcode(
+                reader,
+                new_path,
+                self.block_size.unwrap_or(s.block_size()),
+                self.threads.unwrap_or(s.threads()),
+                compression,
+            )?;
+            new_blocks.push(CompressedReader::new(new_path).block_locations()?);
+        }
+
+        let index = read_index(&trace.index_path());
+        if index.is_empty() {
+            return Ok(());
+        }
+        match remap_index(&index, &old_blocks, &new_blocks, |_, pos| Some(pos)) {
+            Some(new_index) => write_index(new_index_path, &new_index),
+            None => {
+                log!(LogWarn, "Trace index doesn't match the trace, dropping it");
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Created in the staging directory, so that a staging directory left over
+/// from an earlier run can be told apart from anything else with its name.
+const STAGING_MARKER: &str = ".rd-recompress";
+
+/// Remove the staging directory `path` if an earlier run that failed left it
+/// behind. Anything else by that name isn't ours to remove.
+fn remove_leftover_staging_dir(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+        Ok(_) => (),
+    }
+    match fs::symlink_metadata(path.join(STAGING_MARKER)) {
+        Ok(_) => {
+            log!(
+                LogDebug,
+                "Removing {:?} left over from an earlier run",
+                path
+            );
+            fs::remove_dir_all(path)
+        }
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!(
+                "{:?} is in the way and wasn't left behind by rd recompress. \
+                 Move it somewhere else and try again.",
+                path
+            ),
+        )),
+    }
+}
+
+/// Hardlink (or copy, if that fails) everything in directory `from` into
+/// directory `to`, except the entries named in `skip`. Permissions of
+/// directories are copied once they have been filled.
+fn link_tree(from: &Path, to: &Path, skip: &[&OsStr]) -> io::Result<()> {
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if skip.contains(&name.as_os_str()) {
+            continue;
+        }
+        let dest = to.join(&name);
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            fs::create_dir(&dest)?;
+            link_tree(&entry.path(), &dest, &[])?;
+        } else if file_type.is_symlink() {
+            symlink(fs::read_link(entry.path())?, &dest)?;
+        } else if fs::hard_link(entry.path(), &dest).is_err() {
+            fs::copy(entry.path(), &dest)?;
+        }
+    }
+    fs::set_permissions(to, fs::metadata(from)?.permissions())
+}
+
+/// Atomically swap directories `a` and `b`, which must be on the same
+/// filesystem.
+fn exchange(a: &Path, b: &Path) -> io::Result<()> {
+    let a = CString::new(a.as_os_str().as_bytes()).unwrap();
+    let b = CString::new(b.as_os_str().as_bytes()).unwrap();
+    let ret = unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            a.as_ptr(),
+            libc::AT_FDCWD,
+            b.as_ptr(),
+            libc::RENAME_EXCHANGE,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Copy all the (uncompressed) data of `reader` into a new compressed file.
+fn transcode(
+    mut reader: CompressedReader,
+    path: &OsStr,
+    block_size: usize,
+    threads: usize,
+    compression: Compression,
+) -> io::Result<()> {
+    let mut writer = CompressedWriter::new(path, block_size, threads, compression);
+    loop {
+        let len = {
+            let data = reader.fill_buf()?;
+            if data.is_empty() {
+                break;
+            }
+            writer.write_all(data)?;
+            data.len()
+        };
+        reader.consume(len);
+    }
+    writer.close(Some(Sync::Sync));
+    if !writer.good() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("Failed to write {:?}", path),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::test_dir;
+
+    #[test]
+    fn remove_leftover_staging_dir_test() {
+        let dir = test_dir("recompress-staging");
+        let staging_dir = dir.join("trace.recompress");
+        remove_leftover_staging_dir(&staging_dir).unwrap();
+
+        fs::create_dir(&staging_dir).unwrap();
+        fs::write(staging_dir.join("events"), b"not ours").unwrap();
+        let e = remove_leftover_staging_dir(&staging_dir).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+        assert!(staging_dir.join("events").exists());
+
+        fs::write(staging_dir.join(STAGING_MARKER), b"").unwrap();
+        remove_leftover_staging_dir(&staging_dir).unwrap();
+        assert!(!staging_dir.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,11 +1,15 @@
 use super::exit_result::ExitResult;
 use crate::{
     assert_prerequisites,
-    bindings::sysexits::EX_UNAVAILABLE,
+    bindings::{
+        kernel::{_LINUX_CAPABILITY_U32S_3, _LINUX_CAPABILITY_VERSION_3},
+        sysexits::EX_UNAVAILABLE,
+    },
     commands::{
         rd_options::{RdOptions, RdSubCommand},
         RdCommand,
     },
+    kernel_abi::native_arch,
     log::{notifying_abort, LogInfo, LogWarn},
     scheduler::TicksHowMany,
     session::record_session::{
@@ -18,20 +22,35 @@ use crate::{
     sig,
     sig::Sig,
     ticks::Ticks,
-    util::{check_for_leaks, page_size, running_under_rd, write_all, BindCPU},
+    trace::{
+        compression::Compression,
+        trace_stream::{compression_for_substreams, SUBSTREAM_COUNT},
+    },
+    util::{check_for_leaks, page_size, resource_path, running_under_rd, write_all, BindCPU},
     wait_status::{WaitStatus, WaitType},
 };
-use libc::{prctl, PR_SET_DUMPABLE, STDERR_FILENO};
+use libc::{
+    prctl,
+    PR_CAP_AMBIENT,
+    PR_CAP_AMBIENT_CLEAR_ALL,
+    PR_SET_DUMPABLE,
+    PR_SET_KEEPCAPS,
+    STDERR_FILENO,
+};
 use nix::{
+    errno::Errno,
     sys::signal::{kill, sigaction, signal, SaFlags, SigAction, SigHandler, SigSet, Signal},
-    unistd::{geteuid, getpid, Uid},
+    unistd::{execvp, geteuid, getpid, setresgid, setresuid, Gid, Uid},
 };
 use rand::random;
 use std::{
     env::var_os,
-    ffi::{OsStr, OsString},
+    ffi::{CStr, CString, OsStr, OsString},
+    fs,
     io,
     os::unix::ffi::{OsStrExt, OsStringExt},
+    path::PathBuf,
+    process::Command,
     sync::atomic::{AtomicBool, Ordering},
 };
 
@@ -95,6 +114,9 @@ pub struct RecordCommand {
     /// Copy preload sources to trace dir
     pub copy_preload_src: bool,
 
+    /// How to compress each substream, indexed by Substream
+    pub compression: [Compression; SUBSTREAM_COUNT],
+
     /// The signal to use for syscallbuf desched events
     pub syscallbuf_desched_sig: Sig,
 
@@ -102,6 +124,9 @@ pub struct RecordCommand {
     pub args: Vec<OsString>,
 }
 
+/// The git revision rd was built from.
+const RD_GIT_REVISION: &str = env!("RD_GIT_REVISION");
+
 /// This can be called during debugging to close the trace so it can be used
 /// later.
 pub fn force_close_record_session() {
@@ -142,6 +167,7 @@ impl RecordCommand {
                 setuid_sudo,
                 trace_id,
                 copy_preload_src,
+                compression,
             } => RecordCommand {
                 extra_env: env.unwrap_or(Vec::new()),
                 max_ticks: num_cpu_ticks.unwrap_or(TicksHowMany::DefaultMaxTicks as u64),
@@ -206,6 +232,7 @@ impl RecordCommand {
                 setuid_sudo,
                 trace_id: Box::new(trace_id.unwrap_or(TraceUuid::generate_new())),
                 copy_preload_src,
+                compression: compression_for_substreams(&compression.unwrap_or(Vec::new())),
                 syscallbuf_desched_sig: syscall_buffer_sig.unwrap_or(sig::SIGPWR),
                 args: {
                     let mut args = Vec::new();
@@ -218,8 +245,24 @@ impl RecordCommand {
         }
     }
 
-    fn exec_child(&self) {
-        unimplemented!()
+    /// Run the child directly, without recording it.
+    fn exec_child(&self) -> ! {
+        let args: Vec<CString> = self
+            .args
+            .iter()
+            .map(|arg| CString::new(arg.as_bytes()).unwrap())
+            .collect();
+        let args_ref: Vec<&CStr> = args.iter().map(|arg| arg.as_c_str()).collect();
+        // Only returns if it failed.
+        let err = execvp(&args[0], &args_ref).unwrap_err();
+        match err {
+            nix::Error::Sys(Errno::ENOENT) => eprintln!(
+                "execv failed: {:?} (or interpreter) not found ({})",
+                self.args[0], err
+            ),
+            _ => eprintln!("execv failed ({})", err),
+        }
+        unsafe { libc::_exit(1) }
     }
 
     fn record(&self) -> WaitStatus {
@@ -297,20 +340,47 @@ fn install_signal_handlers() {
 }
 
 fn save_rd_git_revision<T: AsRef<OsStr>>(dir: T) {
-    let _dir_os: &OsStr = dir.as_ref();
-    unimplemented!()
+    let mut path = PathBuf::from(dir.as_ref());
+    path.push("rd_git_revision");
+    if let Err(e) = fs::write(&path, RD_GIT_REVISION) {
+        fatal!("Can't write {:?}: {:?}", path, e);
+    }
 }
 
+/// Zip up the sources of the preload library (as installed in the resource
+/// path) into the trace, so the trace can be debugged without them.
 fn copy_preload_sources_to_trace<T: AsRef<OsStr>>(dir: T) {
-    let _dir_os: &OsStr = dir.as_ref();
-    unimplemented!()
+    let mut files_dir = PathBuf::from(dir.as_ref());
+    files_dir.push("files.rd");
+    if let Err(e) = fs::create_dir(&files_dir) {
+        if e.kind() != io::ErrorKind::AlreadyExists {
+            fatal!("Can't create {:?}: {:?}", files_dir, e);
+        }
+    }
+    let dest_path = files_dir.join("librrpreload.zip");
+    let mut src_path = PathBuf::from(resource_path());
+    src_path.push("share/rr/src");
+    let status = Command::new("zip")
+        .arg("-r")
+        .arg("-j")
+        .arg(&dest_path)
+        .arg(&src_path)
+        .status();
+    match status {
+        Ok(status) if status.success() => (),
+        Ok(status) => log!(
+            LogWarn,
+            "Can't zip up preload sources for trace. Exit status {}",
+            status
+        ),
+        Err(e) => log!(LogWarn, "Can't zip up preload sources for trace: {:?}", e),
+    }
 }
 
 impl RdCommand for RecordCommand {
     fn run(&mut self) -> ExitResult<()> {
         if running_under_rd() {
             if self.ignore_nested {
-                // Does not return!
                 self.exec_child();
             }
             return ExitResult::err_from(
@@ -401,8 +471,44 @@ impl RdCommand for RecordCommand {
     }
 }
 
+/// Switch to the user that ran sudo, keeping our capabilities. The first exec
+/// will reset both the keepcaps flag and the capabilities in the child.
 fn reset_uid_sudo() {
-    unimplemented!()
+    let sudo_id = |var: &str| -> u32 {
+        match var_os(var).and_then(|v| v.to_str().and_then(|v| v.parse().ok())) {
+            Some(id) => id,
+            None => fatal!("Invalid or missing {}", var),
+        }
+    };
+    let tracee_uid = Uid::from_raw(sudo_id("SUDO_UID"));
+    let tracee_gid = Gid::from_raw(sudo_id("SUDO_GID"));
+
+    // setresuid() drops our effective capabilities. Save them now and set them
+    // back after.
+    let header = native_arch::cap_header {
+        version: _LINUX_CAPABILITY_VERSION_3 as _,
+        pid: 0,
+    };
+    let mut data: [native_arch::cap_data; _LINUX_CAPABILITY_U32S_3 as usize] = Default::default();
+    if unsafe { libc::syscall(native_arch::CAPGET as _, &header, &mut data) } != 0 {
+        fatal!("Failed to read capabilities");
+    }
+    if let Err(e) = setresgid(tracee_gid, tracee_gid, tracee_gid) {
+        fatal!("Failed to setresgid({}): {:?}", tracee_gid, e);
+    }
+    // Keep the permitted capabilities across the uid change, so that we can
+    // make them effective again.
+    unsafe { prctl(PR_SET_KEEPCAPS, 1, 0, 0, 0) };
+    // Just make sure the ambient set is cleared, to avoid polluting the tracee
+    unsafe { prctl(PR_CAP_AMBIENT, PR_CAP_AMBIENT_CLEAR_ALL, 0, 0, 0) };
+    if let Err(e) = setresuid(tracee_uid, tracee_uid, tracee_uid) {
+        fatal!("Failed to setresuid({}): {:?}", tracee_uid, e);
+    }
+    if unsafe { libc::syscall(native_arch::CAPSET as _, &header, &data) } != 0 {
+        fatal!("Failed to set capabilities");
+    }
+    // Do make sure the ambient set is cleared, to avoid polluting the tracee
+    unsafe { prctl(PR_CAP_AMBIENT, PR_CAP_AMBIENT_CLEAR_ALL, 0, 0, 0) };
 }
 
 /// A terminating signal was received.  Set the `TERM_REQUEST` bit to
@@ -6,22 +6,45 @@ use crate::{
     assert_prerequisites,
     bindings::kernel::{gettimeofday, timeval},
     commands::RdCommand,
+    elf_core_file::{default_core_file_name, write_core_file},
     flags::Flags,
     gdb_server,
-    log::LogLevel::LogInfo,
+    gdb_server::{ConnectionFlags, GdbServer},
+    log::LogLevel::{LogDebug, LogInfo},
+    scoped_fd::ScopedFd,
     session::{
         replay_session,
         session_inner::{RunCommand, Statistics},
         SessionSharedPtr,
     },
-    trace::trace_frame::FrameTime,
-    util::running_under_rd,
+    trace::{
+        trace_frame::FrameTime,
+        trace_reader::TraceReader,
+        trace_task_event::TraceTaskEventVariant,
+    },
+    util::{check_for_leaks, running_under_rd},
 };
 use io::stderr;
-use libc::pid_t;
-use nix::unistd::{getpid, getppid};
+use libc::{pid_t, prctl, PR_SET_PDEATHSIG, SIGINT, SIGTERM};
+use nix::{
+    errno::Errno,
+    fcntl::OFlag,
+    sys::{
+        signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal},
+        wait::{waitpid, WaitStatus},
+    },
+    unistd::{close, fork, getpid, getppid, pipe2, ForkResult},
+};
 use replay_session::{ReplaySession, ReplayStatus};
-use std::{ffi::OsString, io, io::Write, path::PathBuf, ptr};
+use std::{
+    ffi::{OsStr, OsString},
+    io,
+    io::Write,
+    os::unix::ffi::OsStrExt,
+    path::PathBuf,
+    process::exit,
+    ptr,
+};
 
 #[derive(Copy, Clone, Eq, PartialEq)]
 enum CreatedHow {
@@ -77,6 +100,17 @@ pub struct ReplayCommand {
     /// to test the corresponding code.
     share_private_mappings: bool,
 
+    /// When true, report divergences in RD_MAGIC_SAVE_DATA_FD writes at the end
+    /// of the replay instead of aborting at the first one.
+    keep_going_on_save_data_mismatch: bool,
+
+    /// When Some(_), write a core file of the current thread group on reaching
+    /// this event and stop replaying.
+    core_at: Option<FrameTime>,
+
+    /// Where to write the core file for `core_at`.
+    core_file: Option<PathBuf>,
+
     /// When Some(_), display statistics every N steps.
     dump_interval: Option<u32>,
 
@@ -99,6 +133,9 @@ impl Default for ReplayCommand {
             redirect: true,
             cpu_unbound: false,
             share_private_mappings: false,
+            keep_going_on_save_data_mismatch: false,
+            core_at: None,
+            core_file: None,
             dump_interval: None,
             gdb_options: vec![],
             trace_dir: None,
@@ -125,6 +162,9 @@ impl ReplayCommand {
                 keep_listening,
                 trace_event,
                 cpu_unbound,
+                keep_going_on_save_data_mismatch,
+                core_at,
+                core_file,
                 gdb_x_file,
                 stats,
                 trace_dir,
@@ -203,6 +243,14 @@ impl ReplayCommand {
                 }
 
                 flags.cpu_unbound = cpu_unbound;
+                flags.keep_going_on_save_data_mismatch = keep_going_on_save_data_mismatch;
+
+                if core_at.is_some() {
+                    flags.core_at = core_at;
+                    flags.goto_event = FrameTime::MAX;
+                    flags.dont_launch_debugger = true;
+                }
+                flags.core_file = core_file;
 
                 if interpreter.is_some() {
                     flags.gdb_options.push("-i".into());
@@ -222,6 +270,7 @@ impl ReplayCommand {
             redirect_stdio: self.redirect,
             share_private_mappings: self.share_private_mappings,
             cpu_unbound: self.cpu_unbound,
+            keep_going_on_save_data_mismatch: self.keep_going_on_save_data_mismatch,
         }
     }
 
@@ -255,6 +304,11 @@ impl ReplayCommand {
             let result = replay_session.replay_step(cmd);
             let after_time: FrameTime = replay_session.trace_reader().time();
             debug_assert!(after_time >= before_time && after_time <= before_time + 1);
+            if self.core_at.map_or(false, |at| after_time >= at)
+                && result.status == ReplayStatus::ReplayContinue
+            {
+                return self.dump_core(replay_session, out);
+            }
             if last_dump_rectime == 0.0 {
                 last_dump_rectime = replay_session.trace_reader().recording_time();
             }
@@ -289,10 +343,27 @@ impl ReplayCommand {
             );
         }
 
+        report_save_data_mismatches(replay_session, out)?;
+
         log!(LogInfo, "Replayer successfully finished");
         Ok(())
     }
 
+    fn dump_core(&self, replay_session: &ReplaySession, out: &mut dyn Write) -> io::Result<()> {
+        let t = replay_session.current_task().unwrap();
+        let path = match &self.core_file {
+            Some(path) => path.clone(),
+            None => default_core_file_name(&**t.borrow()),
+        };
+        write_core_file(t.borrow_mut().as_mut(), &path)?;
+        writeln!(
+            out,
+            "Wrote core file {} at event {}",
+            path.display(),
+            replay_session.trace_reader().time()
+        )
+    }
+
     // DIFF NOTE: In rr a result code e.g. 0 is return. We simply return Ok(()) if there is no error.
     fn replay(&self) -> io::Result<()> {
         let mut target = gdb_server::Target::default();
@@ -307,6 +378,10 @@ impl ReplayCommand {
             }
             CreatedHow::CreatedNone => (),
         }
+        // DIFF NOTE: The trace index (see trace_index.rs) can't speed this
+        // up. Tracee state at an event only exists by replaying everything
+        // before it, so replay reads the trace sequentially. Only readers that
+        // don't execute the trace, like `rd dump`, seek.
         target.event = self.goto_event;
 
         // If we're not going to autolaunch the debugger, don't go
@@ -316,24 +391,198 @@ impl ReplayCommand {
             if target.event == FrameTime::MAX {
                 self.serve_replay_no_debugger(&mut stderr())?;
             } else {
-                unimplemented!();
+                let session = ReplaySession::create(self.trace_dir.as_ref(), self.session_flags());
+                let mut conn_flags = ConnectionFlags {
+                    dbg_port: self.dbg_port,
+                    dbg_host: self.dbg_host.clone(),
+                    keep_listening: self.keep_listening,
+                    debugger_params_write_pipe: None,
+                    debugger_name: self.gdb_binary_file_path.clone(),
+                };
+                GdbServer::new(session.clone(), &target).serve_replay(&mut conn_flags);
+                report_save_data_mismatches(session.as_replay().unwrap(), &mut stderr())?;
             }
 
-            // @TODO
+            // Everything should have been cleaned up by now.
+            check_for_leaks();
+            return Ok(());
         }
 
-        Ok(())
+        let (params_read_fd, params_write_fd) = match pipe2(OFlag::O_CLOEXEC) {
+            Ok(fds) => fds,
+            Err(e) => fatal!("Couldn't open debugger params pipe: {:?}", e),
+        };
+        let waiting_for_child = match unsafe { fork() } {
+            Ok(ForkResult::Child) => {
+                // Ensure only the parent has the read end of the pipe open. Then if
+                // the parent dies, our writes to the pipe will error out.
+                close(params_read_fd).unwrap_or(());
+
+                unsafe { prctl(PR_SET_PDEATHSIG, SIGTERM, 0, 0, 0) };
+
+                let session = ReplaySession::create(self.trace_dir.as_ref(), self.session_flags());
+                let mut conn_flags = ConnectionFlags {
+                    dbg_port: self.dbg_port,
+                    dbg_host: self.dbg_host.clone(),
+                    keep_listening: false,
+                    debugger_params_write_pipe: Some(ScopedFd::from_raw(params_write_fd)),
+                    debugger_name: self.gdb_binary_file_path.clone(),
+                };
+                let mut server = GdbServer::new(session.clone(), &target);
+
+                let sa = SigAction::new(
+                    SigHandler::Handler(handle_SIGINT_in_child),
+                    SaFlags::SA_RESTART,
+                    SigSet::empty(),
+                );
+                unsafe { sigaction(Signal::SIGINT, &sa) }.unwrap();
+
+                server.serve_replay(&mut conn_flags);
+                drop(server);
+                let result =
+                    report_save_data_mismatches(session.as_replay().unwrap(), &mut stderr());
+                drop(session);
+
+                // Everything should have been cleaned up by now.
+                check_for_leaks();
+                return result;
+            }
+            Ok(ForkResult::Parent { child }) => child,
+            Err(e) => fatal!("Couldn't fork debugger server: {:?}", e),
+        };
+        // Ensure only the child has the write end of the pipe open. Then if
+        // the child dies, our reads from the pipe will return EOF.
+        close(params_write_fd).unwrap_or(());
+        log!(
+            LogDebug,
+            "{}: forked debugger server {}",
+            getpid(),
+            waiting_for_child
+        );
+
+        let sa = SigAction::new(
+            SigHandler::Handler(handle_SIGINT_in_parent),
+            SaFlags::SA_RESTART,
+            SigSet::empty(),
+        );
+        unsafe { sigaction(Signal::SIGINT, &sa) }.unwrap();
+
+        {
+            let params_pipe_read_fd = ScopedFd::from_raw(params_read_fd);
+            GdbServer::launch_gdb(
+                &params_pipe_read_fd,
+                &self.gdb_binary_file_path,
+                &self.gdb_options,
+            );
+        }
+
+        // Child must have died before we were able to get debugger parameters
+        // and exec gdb. Exit with the exit status of the child.
+        loop {
+            let res = waitpid(waiting_for_child, None);
+            log!(
+                LogDebug,
+                "{}: waitpid({}) returned {:?}",
+                getpid(),
+                waiting_for_child,
+                res
+            );
+            match res {
+                Ok(WaitStatus::Exited(_, status)) => {
+                    log!(LogInfo, "Debugger server died.  Exiting.");
+                    exit(status);
+                }
+                Ok(WaitStatus::Signaled(_, _, _)) => {
+                    log!(LogInfo, "Debugger server died.  Exiting.");
+                    exit(1);
+                }
+                Ok(_) => (),
+                Err(nix::Error::Sys(Errno::EINTR)) => (),
+                Err(e) => fatal!(
+                    "{}: waitpid({}) failed: {:?}",
+                    getpid(),
+                    waiting_for_child,
+                    e
+                ),
+            }
+        }
     }
 }
 
+/// Print the save-data divergences `replay_session` (or any of its clones) saw
+/// with `--keep-going-on-save-data-mismatch`, and fail if there were any.
+fn report_save_data_mismatches(
+    replay_session: &ReplaySession,
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    let mismatches = replay_session.save_data_mismatches();
+    if mismatches.is_empty() {
+        return Ok(());
+    }
+    for mismatch in mismatches.iter() {
+        write!(out, "{}", mismatch)?;
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        format!(
+            "Replay finished with {} save-data divergence(s)",
+            mismatches.len()
+        ),
+    ))
+}
+
 impl RdCommand for ReplayCommand {
     fn run(&mut self) -> ExitResult<()> {
-        if self.target_command.is_some() {
-            unimplemented!()
+        if let Some(command) = self.target_command.as_ref() {
+            match find_pid_for_command(self.trace_dir.as_ref(), command) {
+                Some(pid) => self.target_process = Some(pid),
+                None => {
+                    return ExitResult::err_from(
+                        io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("No process {:?} found. Try 'rd ps'.", command),
+                        ),
+                        2,
+                    );
+                }
+            }
         }
 
         if self.process_created_how != CreatedHow::CreatedNone {
-            unimplemented!()
+            let target_process = self.target_process.unwrap();
+            if !pid_exists(self.trace_dir.as_ref(), target_process) {
+                return ExitResult::err_from(
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("No process {} found in trace. Try 'rd ps'.", target_process),
+                    ),
+                    2,
+                );
+            }
+            if self.process_created_how == CreatedHow::CreatedExec
+                && !pid_execs(self.trace_dir.as_ref(), target_process)
+            {
+                return ExitResult::err_from(
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "Process {} never exec()ed. Try 'rd ps', or use '-f'.",
+                            target_process
+                        ),
+                    ),
+                    2,
+                );
+            }
+        }
+
+        if self.core_file.is_some() && self.core_at.is_none() {
+            return ExitResult::err_from(
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "--core-file requires --core-at",
+                ),
+                2,
+            );
         }
 
         if self.dump_interval.is_some() && !self.dont_launch_debugger {
@@ -384,3 +633,63 @@ impl RdCommand for ReplayCommand {
 fn to_microseconds(tv: &timeval) -> u64 {
     (tv.tv_sec as u64) * 1000000 + (tv.tv_usec as u64)
 }
+
+/// Return the tid of the first task that exec()ed `command` (either exactly,
+/// or as the basename of the exec'ed path).
+fn find_pid_for_command(trace_dir: Option<&PathBuf>, command: &OsStr) -> Option<pid_t> {
+    let mut trace = TraceReader::new(trace_dir);
+    while let Some(e) = trace.read_task_event(None) {
+        match e.event_variant() {
+            TraceTaskEventVariant::Exec(exec) => {
+                let cmd = match exec.cmd_line().first() {
+                    Some(cmd) => cmd.as_bytes(),
+                    None => continue,
+                };
+                let command = command.as_bytes();
+                if cmd == command
+                    || (cmd.len() > command.len()
+                        && cmd.ends_with(command)
+                        && cmd[cmd.len() - command.len() - 1] == b'/')
+                {
+                    return Some(e.tid());
+                }
+            }
+            _ => continue,
+        }
+    }
+    None
+}
+
+fn pid_exists(trace_dir: Option<&PathBuf>, pid: pid_t) -> bool {
+    let mut trace = TraceReader::new(trace_dir);
+    while let Some(e) = trace.read_task_event(None) {
+        if e.tid() == pid {
+            return true;
+        }
+    }
+    false
+}
+
+fn pid_execs(trace_dir: Option<&PathBuf>, pid: pid_t) -> bool {
+    let mut trace = TraceReader::new(trace_dir);
+    while let Some(e) = trace.read_task_event(None) {
+        if e.tid() == pid {
+            if let TraceTaskEventVariant::Exec(_) = e.event_variant() {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+#[allow(non_snake_case)]
+extern "C" fn handle_SIGINT_in_parent(sig: i32) {
+    debug_assert_eq!(sig, SIGINT);
+    // Just ignore it.
+}
+
+#[allow(non_snake_case)]
+extern "C" fn handle_SIGINT_in_child(sig: i32) {
+    debug_assert_eq!(sig, SIGINT);
+    GdbServer::interrupt_replay_to_target();
+}
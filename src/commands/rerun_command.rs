@@ -363,6 +363,7 @@ impl ReRunCommand {
             redirect_stdio: false,
             share_private_mappings: false,
             cpu_unbound: self.cpu_unbound,
+            keep_going_on_save_data_mismatch: false,
         }
     }
 
@@ -486,8 +487,8 @@ impl ReRunCommand {
         task: &mut dyn Task,
     ) -> io::Result<()> {
         let diversion_session = replay.clone_diversion();
-        let diversion_ref = diversion_session.borrow_mut();
-        let t = diversion_ref.find_task_from_task_uid(task.tuid()).unwrap();
+        let diversion = diversion_session.as_diversion().unwrap();
+        let t = diversion.find_task_from_task_uid(task.tuid()).unwrap();
         let mut regs = t.borrow().regs_ref().clone();
         // align stack;
         let sp = RemotePtr::<usize>::new_from_val((regs.sp().as_usize() & !0xf) - 1);
@@ -505,10 +506,7 @@ impl ReRunCommand {
         };
 
         loop {
-            let result =
-                diversion_session
-                    .borrow()
-                    .diversion_step(t.borrow_mut().as_mut(), Some(cmd), None);
+            let result = diversion.diversion_step(t.borrow_mut().as_mut(), Some(cmd), None);
             self.write_regs(t.borrow_mut().as_mut(), 0, 0, &mut stdout())?;
             match result.break_status.signal {
                 Some(siginfo) => {
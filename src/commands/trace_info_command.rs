@@ -35,6 +35,7 @@ struct TraceHeader {
     xcr0: u64,
     bind_to_cpu: i32,
     cpuid_faulting: bool,
+    chaos_seed: Option<u64>,
     ticks_semantics: String,
     cpuid_records: Vec<[u32; 6]>,
     environ: Vec<String>,
@@ -48,6 +49,7 @@ impl RdCommand for TraceInfoCommand {
         let xcr0 = trace.xcr0();
         let bind_to_cpu = trace.bound_to_cpu();
         let cpuid_faulting = trace.uses_cpuid_faulting();
+        let chaos_seed = trace.chaos_seed();
         let ticks_semantics = match trace.ticks_semantics() {
             TicksSemantics::TicksRetiredConditionalBranches => "rcb".into(),
             TicksSemantics::TicksTakenBranches => "branches".into(),
@@ -64,6 +66,7 @@ impl RdCommand for TraceInfoCommand {
             redirect_stdio: false,
             share_private_mappings: false,
             cpu_unbound: true,
+            keep_going_on_save_data_mismatch: false,
         };
         let session = ReplaySession::create(self.trace_dir.as_ref(), flags);
         let replay_session = session.as_replay().unwrap();
@@ -92,6 +95,7 @@ impl RdCommand for TraceInfoCommand {
             xcr0,
             bind_to_cpu: bind_to_cpu.map_or(-1, |c| c.try_into().unwrap()),
             cpuid_faulting,
+            chaos_seed,
             ticks_semantics,
             cpuid_records,
             environ: environ_strings,
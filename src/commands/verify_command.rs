@@ -0,0 +1,641 @@
+use super::exit_result::ExitResult;
+use crate::{
+    commands::{
+        rd_options::{RdOptions, RdSubCommand},
+        RdCommand,
+    },
+    trace::{
+        compressed_reader::CompressedReader,
+        compressed_writer::{BlockHeader, MAX_BLOCK_SIZE},
+        compression::decompress,
+        trace_frame::FrameTime,
+        trace_reader::TraceReader,
+        trace_stream::{Substream, TraceStream, SUBSTREAMS, SUBSTREAM_COUNT},
+    },
+    trace_capnp::{frame, m_map, task_event},
+};
+use capnp::{message::ReaderOptions, serialize_packed::read_message};
+use nix::sys::stat::stat;
+use std::{
+    collections::HashSet,
+    ffi::{OsStr, OsString},
+    fs::File,
+    io::{self, stdout, Read, Write},
+    mem::{size_of, transmute},
+    os::unix::ffi::OsStrExt,
+    path::PathBuf,
+};
+
+/// Check that a trace can be read back completely, without replaying it: the
+/// version file can be read, every block of every substream decompresses, the
+/// events, mmaps and tasks substreams are sequences of valid messages in frame
+/// order, the raw data substream holds exactly the data the memWrites of the
+/// frames refer to, and the files mmaps are replayed from haven't changed since
+/// the recording.
+///
+/// Every problem found is reported, prefixed by the file it's in.
+pub struct VerifyCommand {
+    trace_dir: Option<PathBuf>,
+}
+
+impl VerifyCommand {
+    pub fn new(options: &RdOptions) -> VerifyCommand {
+        match options.cmd.clone() {
+            RdSubCommand::Verify { trace_dir } => VerifyCommand { trace_dir },
+            _ => panic!("Unexpected RdSubCommand variant. Not a `Verify` variant!"),
+        }
+    }
+}
+
+impl RdCommand for VerifyCommand {
+    fn run(&mut self) -> ExitResult<()> {
+        match self.verify(&mut stdout()) {
+            Ok(0) => ExitResult::Ok(()),
+            Ok(num_problems) => ExitResult::err_from(
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Found {} problem(s) in the trace", num_problems),
+                ),
+                1,
+            ),
+            Err(e) => ExitResult::err_from(e, 1),
+        }
+    }
+}
+
+/// What a problem was found in and what's wrong with it.
+struct Problem {
+    /// The name of the substream, or "version"
+    file: &'static str,
+    message: String,
+}
+
+/// What the events substream says about the other substreams.
+struct FrameSummary {
+    /// The time of the last frame in the trace
+    last_frame_time: FrameTime,
+    /// The total size of the memWrites of each frame that has any
+    mem_writes: Vec<(FrameTime, u64)>,
+}
+
+impl VerifyCommand {
+    /// Returns the number of problems found.
+    fn verify(&self, out: &mut dyn Write) -> io::Result<usize> {
+        // Not TraceReader::new(), which exits if the version file is broken.
+        let trace = match TraceReader::find_trace(self.trace_dir.as_ref()) {
+            Some(trace) => trace,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    match &self.trace_dir {
+                        Some(dir) => format!("No trace at {:?}", dir),
+                        None => "No latest trace".to_owned(),
+                    },
+                ))
+            }
+        };
+        let mut problems: Vec<Problem> = Vec::new();
+        if let Err(message) = TraceReader::check_version_file(&trace) {
+            problems.push(Problem {
+                file: "version",
+                message,
+            });
+        }
+
+        // A substream whose blocks are broken can't be parsed, and the position
+        // of anything after the broken block is lost.
+        let mut readable = [false; SUBSTREAM_COUNT];
+        for &s in &SUBSTREAMS {
+            readable[s as usize] = check_blocks(s, &trace.path(s), &mut problems)?;
+        }
+
+        let mut summary = None;
+        if readable[Substream::Events as usize] {
+            summary = check_events(&trace, &mut problems);
+        }
+        if let Some(summary) = summary.as_ref() {
+            if readable[Substream::RawData as usize] {
+                check_raw_data(&trace.path(Substream::RawData), summary, &mut problems)?;
+            }
+        }
+        let last_frame_time = summary.as_ref().map(|s| s.last_frame_time);
+        if readable[Substream::Mmaps as usize] {
+            check_mmaps(&trace, last_frame_time, &mut problems);
+        }
+        if readable[Substream::Tasks as usize] {
+            check_tasks(&trace, last_frame_time, &mut problems);
+        }
+
+        for p in &problems {
+            writeln!(out, "{}: {}", p.file, p.message)?;
+        }
+        if problems.is_empty() {
+            writeln!(out, "Trace {:?} is OK", trace.dir())?;
+        }
+        Ok(problems.len())
+    }
+}
+
+fn problem(problems: &mut Vec<Problem>, substream: Substream, message: String) {
+    problems.push(Problem {
+        file: substream.name(),
+        message,
+    });
+}
+
+/// Walk the block headers of a substream file and decompress every block.
+/// Returns false if any block is broken.
+fn check_blocks(s: Substream, path: &OsStr, problems: &mut Vec<Problem>) -> io::Result<bool> {
+    let mut f = match File::open(path) {
+        Ok(f) => f,
+        Err(e) => {
+            problem(problems, s, format!("can't open {:?}: {}", path, e));
+            return Ok(false);
+        }
+    };
+    let file_size = f.metadata()?.len();
+    let mut offset: u64 = 0;
+    let mut block: usize = 0;
+    let mut ok = true;
+    while offset < file_size {
+        let mut header_arr = [0u8; size_of::<BlockHeader>()];
+        if file_size - offset < header_arr.len() as u64 {
+            problem(
+                problems,
+                s,
+                format!(
+                    "block {} at offset {}: truncated block header ({} of {} bytes)",
+                    block,
+                    offset,
+                    file_size - offset,
+                    header_arr.len()
+                ),
+            );
+            return Ok(false);
+        }
+        f.read_exact(&mut header_arr)?;
+        offset += header_arr.len() as u64;
+        let header: BlockHeader = unsafe { transmute(header_arr) };
+
+        let compressed_length = header.compressed_length() as u64;
+        if compressed_length > file_size - offset {
+            problem(
+                problems,
+                s,
+                format!(
+                    "block {} at offset {}: compressed length {} runs past the end of the file ({} bytes left)",
+                    block,
+                    offset - header_arr.len() as u64,
+                    compressed_length,
+                    file_size - offset
+                ),
+            );
+            return Ok(false);
+        }
+        let mut compressed = vec![0u8; compressed_length as usize];
+        f.read_exact(&mut compressed)?;
+
+        let uncompressed_length = header.uncompressed_length as usize;
+        let maybe_error = match header.codec() {
+            None => Some("unknown codec".to_owned()),
+            Some(_) if uncompressed_length > MAX_BLOCK_SIZE => Some(format!(
+                "uncompressed length {} is larger than any block",
+                uncompressed_length
+            )),
+            Some(codec) => {
+                let mut uncompressed = vec![0u8; uncompressed_length];
+                if decompress(codec, &compressed, &mut uncompressed) {
+                    None
+                } else {
+                    Some(format!(
+                        "{} data doesn't decompress to {} bytes",
+                        codec.name(),
+                        uncompressed_length
+                    ))
+                }
+            }
+        };
+        // The header is fine, so we can carry on with the next block.
+        if let Some(error) = maybe_error {
+            problem(
+                problems,
+                s,
+                format!(
+                    "block {} at offset {}: {}",
+                    block,
+                    offset - header_arr.len() as u64,
+                    error
+                ),
+            );
+            ok = false;
+        }
+
+        offset += compressed_length;
+        block += 1;
+    }
+    Ok(ok)
+}
+
+/// Parse every frame. Returns `None` if the frames can't all be parsed.
+fn check_events(trace: &TraceStream, problems: &mut Vec<Problem>) -> Option<FrameSummary> {
+    let mut events = CompressedReader::new(&trace.path(Substream::Events));
+    let mut summary = FrameSummary {
+        last_frame_time: 0,
+        mem_writes: Vec::new(),
+    };
+    while !events.at_end() {
+        let time = summary.last_frame_time + 1;
+        let result = read_message(&mut events, ReaderOptions::new())
+            .and_then(|frame_msg| {
+                let frame = frame_msg.get_root::<frame::Reader>()?;
+                frame.get_event().which()?;
+                frame.get_registers()?.get_raw()?;
+                frame.get_extra_registers()?.get_raw()?;
+                let mut mem_writes_size: u64 = 0;
+                for w in frame.get_mem_writes()?.iter() {
+                    mem_writes_size = mem_writes_size.saturating_add(w.get_size());
+                }
+                Ok((frame.get_ticks(), mem_writes_size))
+            })
+            .map_err(|e| e.to_string())
+            .and_then(|(ticks, mem_writes_size)| {
+                if ticks < 0 {
+                    return Err(format!("invalid ticks value {}", ticks));
+                }
+                Ok(mem_writes_size)
+            });
+        match result {
+            Ok(mem_writes_size) => {
+                summary.last_frame_time = time;
+                if mem_writes_size > 0 {
+                    summary.mem_writes.push((time, mem_writes_size));
+                }
+            }
+            Err(e) => {
+                // We don't know where the next frame starts.
+                problem(
+                    problems,
+                    Substream::Events,
+                    format!("frame {}: {}", time, e),
+                );
+                return None;
+            }
+        }
+    }
+    Some(summary)
+}
+
+/// The raw data substream is just the memWrites data of all frames, one after
+/// the other, with nothing to tell where the data of a frame starts. So all we
+/// can check is that the data of each frame is there, and that there's none
+/// left over.
+fn check_raw_data(
+    path: &OsStr,
+    summary: &FrameSummary,
+    problems: &mut Vec<Problem>,
+) -> io::Result<()> {
+    let raw_data_bytes = CompressedReader::new(path).uncompressed_bytes()?;
+    let mut pos: u64 = 0;
+    for &(time, size) in &summary.mem_writes {
+        if size > raw_data_bytes - pos {
+            problem(
+                problems,
+                Substream::RawData,
+                format!(
+                    "frame {}: the memWrites need {} bytes at offset {}, but only {} are left",
+                    time,
+                    size,
+                    pos,
+                    raw_data_bytes - pos
+                ),
+            );
+            return Ok(());
+        }
+        pos += size;
+    }
+    if pos < raw_data_bytes {
+        problem(
+            problems,
+            Substream::RawData,
+            format!(
+                "holds {} bytes at offset {} that no memWrites of any frame refer to",
+                raw_data_bytes - pos,
+                pos
+            ),
+        );
+    }
+    Ok(())
+}
+
+/// Check that `time` comes no earlier than `prev_time` and is no later than
+/// the last frame, if we know that.
+fn check_frame_time(
+    time: i64,
+    prev_time: FrameTime,
+    last_frame_time: Option<FrameTime>,
+) -> Result<FrameTime, String> {
+    if time < prev_time as i64 {
+        return Err(format!(
+            "frame time {} is earlier than the previous record's {}",
+            time, prev_time
+        ));
+    }
+    match last_frame_time {
+        Some(last) if time as u64 > last => Err(format!(
+            "frame time {} is after the last frame {}",
+            time, last
+        )),
+        _ => Ok(time as FrameTime),
+    }
+}
+
+fn check_mmaps(
+    trace: &TraceStream,
+    last_frame_time: Option<FrameTime>,
+    problems: &mut Vec<Problem>,
+) {
+    let mut mmaps = CompressedReader::new(&trace.path(Substream::Mmaps));
+    let mut prev_time: FrameTime = 0;
+    let mut checked_files: HashSet<OsString> = HashSet::new();
+    let mut record: usize = 0;
+    while !mmaps.at_end() {
+        let result = read_message(&mut mmaps, ReaderOptions::new())
+            .and_then(|map_msg| {
+                let map = map_msg.get_root::<m_map::Reader>()?;
+                let mut backing_file = None;
+                if let m_map::source::File(f) = map.get_source().which()? {
+                    let name = f.get_backing_file_name()?.to_vec();
+                    backing_file = Some((
+                        name,
+                        map.get_stat_size(),
+                        map.get_stat_m_time(),
+                        map.get_stat_mode() != 0
+                            || map.get_stat_uid() != 0
+                            || map.get_stat_gid() != 0
+                            || map.get_stat_m_time() != 0,
+                    ));
+                }
+                map.get_fsname()?;
+                Ok((map.get_frame_time(), backing_file))
+            })
+            .map_err(|e| e.to_string());
+
+        let (time, backing_file) = match result {
+            Ok(r) => r,
+            Err(e) => {
+                // We don't know where the next record starts.
+                problem(
+                    problems,
+                    Substream::Mmaps,
+                    format!("record {}: {}", record, e),
+                );
+                return;
+            }
+        };
+        match check_frame_time(time, prev_time, last_frame_time) {
+            Ok(t) => prev_time = t,
+            Err(e) => problem(
+                problems,
+                Substream::Mmaps,
+                format!("record {}: {}", record, e),
+            ),
+        }
+
+        if let Some((name, size, mtime, has_stat_buf)) = backing_file {
+            let mut path = Vec::new();
+            if !name.starts_with(b"/") {
+                path.extend_from_slice(trace.dir().as_bytes());
+                path.push(b'/');
+            }
+            path.extend_from_slice(&name);
+            let path = OsString::from(OsStr::from_bytes(&path));
+            // Files are usually mapped many times. Only report them once.
+            if checked_files.insert(path.clone()) {
+                // Clones and copies are in the trace and their stat data is
+                // that of the original file. Packed traces have no stat data.
+                let in_trace = name.starts_with(b"mmap_clone_") || name.starts_with(b"mmap_copy_");
+                if let Some(e) = check_backing_file(&path, size, mtime, has_stat_buf && !in_trace) {
+                    problem(
+                        problems,
+                        Substream::Mmaps,
+                        format!("record {} (frame {}): {:?}: {}", record, time, path, e),
+                    );
+                }
+            }
+        }
+        record += 1;
+    }
+}
+
+fn check_backing_file(path: &OsStr, size: i64, mtime: i64, check_stat: bool) -> Option<String> {
+    let st = match stat(path) {
+        Ok(st) => st,
+        Err(e) => return Some(format!("can't stat backing file: {}", e)),
+    };
+    if !check_stat {
+        return None;
+    }
+    if st.st_size as i64 != size {
+        return Some(format!(
+            "size changed from {} to {} since the recording",
+            size, st.st_size
+        ));
+    }
+    if st.st_mtime as i64 != mtime {
+        return Some(format!(
+            "mtime changed from {} to {} since the recording",
+            mtime, st.st_mtime
+        ));
+    }
+    None
+}
+
+fn check_tasks(
+    trace: &TraceStream,
+    last_frame_time: Option<FrameTime>,
+    problems: &mut Vec<Problem>,
+) {
+    let mut tasks = CompressedReader::new(&trace.path(Substream::Tasks));
+    let mut prev_time: FrameTime = 0;
+    let mut record: usize = 0;
+    while !tasks.at_end() {
+        let result = read_message(&mut tasks, ReaderOptions::new())
+            .and_then(|task_msg| {
+                let task = task_msg.get_root::<task_event::Reader>()?;
+                if let task_event::Exec(r) = task.which()? {
+                    r.get_file_name()?;
+                    for cmd in r.get_cmd_line()?.iter() {
+                        cmd?;
+                    }
+                }
+                Ok(task.get_frame_time())
+            })
+            .map_err(|e| e.to_string());
+
+        match result {
+            Ok(time) => match check_frame_time(time, prev_time, last_frame_time) {
+                Ok(t) => prev_time = t,
+                Err(e) => problem(
+                    problems,
+                    Substream::Tasks,
+                    format!("record {}: {}", record, e),
+                ),
+            },
+            Err(e) => {
+                // We don't know where the next record starts.
+                problem(
+                    problems,
+                    Substream::Tasks,
+                    format!("record {}: {}", record, e),
+                );
+                return;
+            }
+        }
+        record += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        trace::{
+            compressed_writer::CompressedWriter,
+            compression::{Codec, Compression},
+        },
+        util::test_dir,
+    };
+    use std::{fs, path::Path};
+
+    const BLOCK_SIZE: usize = 4096;
+
+    /// Write three blocks of compressible data and return the file contents.
+    fn write_blocks(path: &Path) -> Vec<u8> {
+        let data: Vec<u8> = (0..3 * BLOCK_SIZE).map(|i| (i % 7) as u8).collect();
+        let mut writer = CompressedWriter::new(
+            path.as_os_str(),
+            BLOCK_SIZE,
+            1,
+            Compression::new(Codec::Zstd),
+        );
+        writer.write_all(&data).unwrap();
+        writer.close(None);
+        assert!(writer.good());
+        fs::read(path).unwrap()
+    }
+
+    fn check(path: &Path) -> (bool, Vec<String>) {
+        let mut problems = Vec::new();
+        let ok = check_blocks(Substream::Events, path.as_os_str(), &mut problems).unwrap();
+        (ok, problems.into_iter().map(|p| p.message).collect())
+    }
+
+    /// Replace the file at `path`, which CompressedWriter made read-only.
+    fn rewrite(path: &Path, contents: &[u8]) {
+        fs::remove_file(path).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    #[test]
+    fn check_blocks_test() {
+        let dir = test_dir("verify-blocks");
+        let path = dir.join("events");
+        write_blocks(&path);
+        assert_eq!(check(&path), (true, vec![]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupted_block_test() {
+        let dir = test_dir("verify-corrupted");
+        let path = dir.join("events");
+        let mut contents = write_blocks(&path);
+        let blocks = CompressedReader::new(path.as_os_str())
+            .block_locations()
+            .unwrap();
+        // Break the zstd frame magic at the start of the second block's data.
+        contents[blocks[1].file_offset as usize + size_of::<BlockHeader>()] ^= 0xff;
+        rewrite(&path, &contents);
+
+        let (ok, problems) = check(&path);
+        assert!(!ok);
+        // The other blocks are still checked, but only one is broken.
+        assert_eq!(
+            problems,
+            vec![format!(
+                "block 1 at offset {}: zstd data doesn't decompress to {} bytes",
+                blocks[1].file_offset, BLOCK_SIZE
+            )]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn truncated_stream_test() {
+        let dir = test_dir("verify-truncated");
+        let path = dir.join("events");
+        let contents = write_blocks(&path);
+        let blocks = CompressedReader::new(path.as_os_str())
+            .block_locations()
+            .unwrap();
+
+        // Cut off in the middle of the last block's data
+        let end = contents.len() - 1;
+        rewrite(&path, &contents[0..end]);
+        let (ok, problems) = check(&path);
+        assert!(!ok);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with(&format!("block 2 at offset {}: ", blocks[2].file_offset)));
+        assert!(problems[0].contains("runs past the end of the file"));
+
+        // Cut off in the middle of the last block's header
+        let end = blocks[2].file_offset as usize + 1;
+        rewrite(&path, &contents[0..end]);
+        let (ok, problems) = check(&path);
+        assert!(!ok);
+        assert_eq!(
+            problems,
+            vec![format!(
+                "block 2 at offset {}: truncated block header (1 of {} bytes)",
+                blocks[2].file_offset,
+                size_of::<BlockHeader>()
+            )]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn check_raw_data_test() {
+        let dir = test_dir("verify-raw-data");
+        let path = dir.join("data");
+        let mut writer = CompressedWriter::new(
+            path.as_os_str(),
+            BLOCK_SIZE,
+            1,
+            Compression::new(Codec::Zstd),
+        );
+        writer.write_all(&[0u8; 30]).unwrap();
+        writer.close(None);
+        assert!(writer.good());
+
+        let check = |mem_writes: Vec<(FrameTime, u64)>| {
+            let summary = FrameSummary {
+                last_frame_time: 10,
+                mem_writes,
+            };
+            let mut problems = Vec::new();
+            check_raw_data(path.as_os_str(), &summary, &mut problems).unwrap();
+            problems.into_iter().map(|p| p.message).collect::<Vec<_>>()
+        };
+        assert!(check(vec![(1, 10), (3, 20)]).is_empty());
+        assert_eq!(
+            check(vec![(1, 10), (3, 10), (4, 20)]),
+            vec!["frame 4: the memWrites need 20 bytes at offset 20, but only 10 are left"]
+        );
+        assert_eq!(
+            check(vec![(1, 10)]),
+            vec!["holds 20 bytes at offset 10 that no memWrites of any frame refer to"]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
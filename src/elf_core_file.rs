@@ -0,0 +1,454 @@
+//! Write an ELF core file for a tracee's thread group.
+//!
+//! The layout follows what the kernel produces for a core dump (see
+//! fs/binfmt_elf.c) so that gdb and other tools can load the file without rd:
+//! an ELF header, a PT_NOTE segment with the per-process and per-thread
+//! notes, then one PT_LOAD segment per mapping in the address space.
+
+use crate::{
+    bindings::kernel::{
+        EI_CLASS,
+        EI_DATA,
+        EI_OSABI,
+        EI_VERSION,
+        ELFCLASS32,
+        ELFCLASS64,
+        ELFDATA2LSB,
+        ELFMAG0,
+        ELFMAG1,
+        ELFMAG2,
+        ELFMAG3,
+        ELFOSABI_NONE,
+        EM_386,
+        EM_X86_64,
+        ET_CORE,
+        EV_CURRENT,
+        NT_AUXV,
+        NT_FILE,
+        NT_FPREGSET,
+        NT_PRSTATUS,
+        NT_X86_XSTATE,
+        PF_R,
+        PF_W,
+        PF_X,
+        PT_LOAD,
+        PT_NOTE,
+    },
+    extra_registers::Format,
+    kernel_abi::SupportedArch,
+    session::{address_space::address_space::Mapping, task::Task},
+    util::{ceil_page_size, page_size, read_auxv},
+};
+use nix::sys::mman::ProtFlags;
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+/// Registers and signal state of one thread, as it goes into the notes.
+struct ThreadNotes {
+    rec_tid: i32,
+    cursig: i32,
+    /// user_regs_struct for the tracee's architecture
+    regs: Vec<u8>,
+    /// user_fpregs_struct and the raw XSAVE area, if known
+    fpregs: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+/// A PT_LOAD segment: a mapping and as much of its contents as we could read.
+struct Segment {
+    mapping: Mapping,
+    data: Vec<u8>,
+}
+
+/// Write a core file for the thread group of `t` to `path`. `t` is reported
+/// as the thread that caused the dump, i.e. its notes come first.
+pub fn write_core_file(t: &mut dyn Task, path: &Path) -> io::Result<()> {
+    let arch = t.arch();
+    let cursig = current_signal(t);
+    let mut threads = vec![thread_notes(t, cursig)];
+    let others: Vec<_> = t.thread_group().task_set().iter().collect();
+    for other in others {
+        // `t` itself is borrowed up-stack.
+        if let Ok(mut ot) = other.try_borrow_mut() {
+            threads.push(thread_notes(ot.as_mut(), 0));
+        }
+    }
+
+    let mut mappings = Vec::new();
+    for (_, m) in &t.vm().maps() {
+        mappings.push(m.clone());
+    }
+    let mut segments = Vec::with_capacity(mappings.len());
+    for mapping in mappings {
+        let mut data = Vec::new();
+        data.resize(mapping.map.size(), 0u8);
+        let len = t
+            .read_bytes_fallible(mapping.map.start(), &mut data)
+            .unwrap_or(0);
+        data.truncate(len);
+        segments.push(Segment { mapping, data });
+    }
+
+    let w = ElfWriter::new(arch);
+    let notes = build_notes(&w, &threads, &read_auxv(t), &segments);
+
+    let phnum = 1 + segments.len();
+    if phnum >= 0xffff {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("Too many mappings ({}) for a core file", segments.len()),
+        ));
+    }
+    let headers_size = w.ehdr_size() + phnum * w.phdr_size();
+    let notes_offset = headers_size;
+    let mut offset = ceil_page_size(notes_offset + notes.len());
+
+    let mut out = Vec::with_capacity(headers_size);
+    w.write_ehdr(&mut out, phnum as u16);
+    w.write_phdr(
+        &mut out,
+        &ProgramHeader {
+            p_type: PT_NOTE,
+            p_flags: 0,
+            offset: notes_offset as u64,
+            vaddr: 0,
+            filesz: notes.len() as u64,
+            memsz: 0,
+            align: 0,
+        },
+    );
+    let mut segment_offsets = Vec::with_capacity(segments.len());
+    for s in &segments {
+        let prot = s.mapping.map.prot();
+        let mut flags = 0;
+        if prot.contains(ProtFlags::PROT_READ) {
+            flags |= PF_R;
+        }
+        if prot.contains(ProtFlags::PROT_WRITE) {
+            flags |= PF_W;
+        }
+        if prot.contains(ProtFlags::PROT_EXEC) {
+            flags |= PF_X;
+        }
+        w.write_phdr(
+            &mut out,
+            &ProgramHeader {
+                p_type: PT_LOAD,
+                p_flags: flags,
+                offset: offset as u64,
+                vaddr: s.mapping.map.start().as_usize() as u64,
+                filesz: s.data.len() as u64,
+                memsz: s.mapping.map.size() as u64,
+                align: page_size() as u64,
+            },
+        );
+        segment_offsets.push(offset);
+        offset = ceil_page_size(offset + s.data.len());
+    }
+    out.extend_from_slice(&notes);
+
+    let mut f = BufWriter::new(File::create(path)?);
+    f.write_all(&out)?;
+    let mut written = out.len();
+    for (s, &segment_offset) in segments.iter().zip(segment_offsets.iter()) {
+        f.write_all(&vec![0u8; segment_offset - written])?;
+        f.write_all(&s.data)?;
+        written = segment_offset + s.data.len();
+    }
+    f.flush()
+}
+
+/// "core.<tid>" in the current directory, like gdb's gcore. Uses the
+/// recorded tid so that the name matches what the tracee saw.
+pub fn default_core_file_name(t: &dyn Task) -> PathBuf {
+    PathBuf::from(format!("core.{}", t.rec_tid))
+}
+
+/// The signal that stopped `t`, if the current trace event is a signal.
+fn current_signal(t: &dyn Task) -> i32 {
+    match t.as_replay_task() {
+        Some(rt) => {
+            let frame = rt.current_trace_frame();
+            let ev = frame.event();
+            if ev.is_signal_event() {
+                ev.signal_event().siginfo.si_signo
+            } else {
+                0
+            }
+        }
+        None => 0,
+    }
+}
+
+fn thread_notes(t: &mut dyn Task, cursig: i32) -> ThreadNotes {
+    let arch = t.arch();
+    let regs = t.regs_ref().get_ptrace_for_arch(arch);
+    let extra_regs = t.extra_regs_ref();
+    let fpregs = if extra_regs.format() == Format::XSave {
+        Some((
+            extra_regs.get_user_fpregs_struct(arch),
+            extra_regs.data_bytes().to_vec(),
+        ))
+    } else {
+        None
+    };
+    ThreadNotes {
+        rec_tid: t.rec_tid,
+        cursig,
+        regs,
+        fpregs,
+    }
+}
+
+/// The kernel emits the process-wide notes after the first thread's
+/// NT_PRSTATUS, and each thread's floating point state after its NT_PRSTATUS.
+fn build_notes(
+    w: &ElfWriter,
+    threads: &[ThreadNotes],
+    auxv: &[u8],
+    segments: &[Segment],
+) -> Vec<u8> {
+    let mut notes = Vec::new();
+    for (i, thread) in threads.iter().enumerate() {
+        w.write_note(&mut notes, b"CORE", NT_PRSTATUS, &w.prstatus(thread));
+        if i == 0 {
+            w.write_note(&mut notes, b"CORE", NT_AUXV, auxv);
+            w.write_note(&mut notes, b"CORE", NT_FILE, &w.file_note(segments));
+        }
+        if let Some((fpregs, xstate)) = &thread.fpregs {
+            w.write_note(&mut notes, b"CORE", NT_FPREGSET, fpregs);
+            w.write_note(&mut notes, b"LINUX", NT_X86_XSTATE, xstate);
+        }
+    }
+    notes
+}
+
+struct ProgramHeader {
+    p_type: u32,
+    p_flags: u32,
+    offset: u64,
+    vaddr: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+
+/// Serializes ELF structures for either a 32-bit or a 64-bit tracee.
+struct ElfWriter {
+    arch: SupportedArch,
+}
+
+impl ElfWriter {
+    fn new(arch: SupportedArch) -> ElfWriter {
+        ElfWriter { arch }
+    }
+
+    fn word_size(&self) -> usize {
+        match self.arch {
+            SupportedArch::X86 => 4,
+            SupportedArch::X64 => 8,
+        }
+    }
+
+    fn ehdr_size(&self) -> usize {
+        match self.arch {
+            SupportedArch::X86 => 52,
+            SupportedArch::X64 => 64,
+        }
+    }
+
+    fn phdr_size(&self) -> usize {
+        match self.arch {
+            SupportedArch::X86 => 32,
+            SupportedArch::X64 => 56,
+        }
+    }
+
+    fn push_word(&self, out: &mut Vec<u8>, v: u64) {
+        match self.arch {
+            SupportedArch::X86 => out.extend_from_slice(&(v as u32).to_le_bytes()),
+            SupportedArch::X64 => out.extend_from_slice(&v.to_le_bytes()),
+        }
+    }
+
+    fn write_ehdr(&self, out: &mut Vec<u8>, phnum: u16) {
+        let mut ident = [0u8; 16];
+        ident[0] = ELFMAG0 as u8;
+        ident[1] = ELFMAG1 as u8;
+        ident[2] = ELFMAG2 as u8;
+        ident[3] = ELFMAG3 as u8;
+        ident[EI_CLASS as usize] = match self.arch {
+            SupportedArch::X86 => ELFCLASS32 as u8,
+            SupportedArch::X64 => ELFCLASS64 as u8,
+        };
+        ident[EI_DATA as usize] = ELFDATA2LSB as u8;
+        ident[EI_VERSION as usize] = EV_CURRENT as u8;
+        ident[EI_OSABI as usize] = ELFOSABI_NONE as u8;
+        out.extend_from_slice(&ident);
+
+        let machine = match self.arch {
+            SupportedArch::X86 => EM_386,
+            SupportedArch::X64 => EM_X86_64,
+        };
+        out.extend_from_slice(&(ET_CORE as u16).to_le_bytes());
+        out.extend_from_slice(&(machine as u16).to_le_bytes());
+        out.extend_from_slice(&(EV_CURRENT as u32).to_le_bytes());
+        // e_entry, e_phoff, e_shoff
+        self.push_word(out, 0);
+        self.push_word(out, self.ehdr_size() as u64);
+        self.push_word(out, 0);
+        // e_flags
+        out.extend_from_slice(&0u32.to_le_bytes());
+        out.extend_from_slice(&(self.ehdr_size() as u16).to_le_bytes());
+        out.extend_from_slice(&(self.phdr_size() as u16).to_le_bytes());
+        out.extend_from_slice(&phnum.to_le_bytes());
+        // e_shentsize, e_shnum, e_shstrndx
+        out.extend_from_slice(&[0u8; 6]);
+    }
+
+    fn write_phdr(&self, out: &mut Vec<u8>, ph: &ProgramHeader) {
+        out.extend_from_slice(&ph.p_type.to_le_bytes());
+        // p_flags comes second in Elf64_Phdr but seventh in Elf32_Phdr.
+        if self.arch == SupportedArch::X64 {
+            out.extend_from_slice(&ph.p_flags.to_le_bytes());
+        }
+        self.push_word(out, ph.offset);
+        self.push_word(out, ph.vaddr);
+        // p_paddr
+        self.push_word(out, 0);
+        self.push_word(out, ph.filesz);
+        self.push_word(out, ph.memsz);
+        if self.arch == SupportedArch::X86 {
+            out.extend_from_slice(&ph.p_flags.to_le_bytes());
+        }
+        self.push_word(out, ph.align);
+    }
+
+    /// Notes in core files are 4-byte aligned for both 32 and 64-bit.
+    fn write_note(&self, out: &mut Vec<u8>, name: &[u8], n_type: u32, desc: &[u8]) {
+        out.extend_from_slice(&(name.len() as u32 + 1).to_le_bytes());
+        out.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+        out.extend_from_slice(&n_type.to_le_bytes());
+        out.extend_from_slice(name);
+        out.push(0);
+        pad_to_4(out);
+        out.extend_from_slice(desc);
+        pad_to_4(out);
+    }
+
+    /// struct elf_prstatus. Fields we don't know (signal masks, parent
+    /// and session ids, times) are left zero.
+    fn prstatus(&self, thread: &ThreadNotes) -> Vec<u8> {
+        let word = self.word_size();
+        let mut out = Vec::new();
+        // pr_info: struct elf_siginfo { si_signo, si_code, si_errno }
+        out.extend_from_slice(&thread.cursig.to_le_bytes());
+        out.extend_from_slice(&[0u8; 8]);
+        // pr_cursig, then padding up to pr_sigpend
+        out.extend_from_slice(&(thread.cursig as i16).to_le_bytes());
+        out.extend_from_slice(&[0u8; 2]);
+        // pr_sigpend, pr_sighold
+        out.resize(out.len() + 2 * word, 0);
+        // pr_pid, pr_ppid, pr_pgrp, pr_sid
+        out.extend_from_slice(&thread.rec_tid.to_le_bytes());
+        out.resize(out.len() + 12, 0);
+        // pr_utime, pr_stime, pr_cutime, pr_cstime
+        out.resize(out.len() + 4 * 2 * word, 0);
+        out.extend_from_slice(&thread.regs);
+        // pr_fpvalid
+        let fpvalid: i32 = if thread.fpregs.is_some() { 1 } else { 0 };
+        out.extend_from_slice(&fpvalid.to_le_bytes());
+        let size = (out.len() + word - 1) / word * word;
+        out.resize(size, 0);
+        out
+    }
+
+    /// NT_FILE: count, page size, then (start, end, file offset in pages) for
+    /// each file-backed mapping, followed by their NUL-terminated names.
+    fn file_note(&self, segments: &[Segment]) -> Vec<u8> {
+        let files: Vec<&Mapping> = segments
+            .iter()
+            .map(|s| &s.mapping)
+            .filter(|m| m.recorded_map.fsname().as_bytes().starts_with(b"/"))
+            .collect();
+        let mut out = Vec::new();
+        self.push_word(&mut out, files.len() as u64);
+        self.push_word(&mut out, page_size() as u64);
+        for m in &files {
+            self.push_word(&mut out, m.map.start().as_usize() as u64);
+            self.push_word(&mut out, m.map.end().as_usize() as u64);
+            self.push_word(
+                &mut out,
+                m.recorded_map.file_offset_bytes() / page_size() as u64,
+            );
+        }
+        for m in &files {
+            out.extend_from_slice(m.recorded_map.fsname().as_bytes());
+            out.push(0);
+        }
+        out
+    }
+}
+
+fn pad_to_4(out: &mut Vec<u8>) {
+    let len: usize = (out.len() + 3) & !3;
+    out.resize(len, 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    #[test]
+    fn note_is_padded() {
+        let w = ElfWriter::new(SupportedArch::X64);
+        let mut out = Vec::new();
+        w.write_note(&mut out, b"CORE", NT_AUXV, &[1, 2, 3]);
+        // 12 byte header, "CORE\0" padded to 8, desc padded to 4
+        assert_eq!(out.len(), 24);
+        assert_eq!(u32::from_le_bytes(out[0..4].try_into().unwrap()), 5);
+        assert_eq!(u32::from_le_bytes(out[4..8].try_into().unwrap()), 3);
+    }
+
+    #[test]
+    fn header_sizes() {
+        for &(arch, ehdr, phdr, prstatus) in &[
+            (SupportedArch::X86, 52, 32, 144),
+            (SupportedArch::X64, 64, 56, 336),
+        ] {
+            let w = ElfWriter::new(arch);
+            let mut out = Vec::new();
+            w.write_ehdr(&mut out, 1);
+            assert_eq!(out.len(), ehdr);
+            out.clear();
+            w.write_phdr(
+                &mut out,
+                &ProgramHeader {
+                    p_type: PT_LOAD,
+                    p_flags: PF_R,
+                    offset: 0,
+                    vaddr: 0,
+                    filesz: 0,
+                    memsz: 0,
+                    align: 0,
+                },
+            );
+            assert_eq!(out.len(), phdr);
+            let regs_size = match arch {
+                SupportedArch::X86 => 17 * 4,
+                SupportedArch::X64 => 27 * 8,
+            };
+            let thread = ThreadNotes {
+                rec_tid: 1,
+                cursig: 0,
+                regs: vec![0; regs_size],
+                fpregs: None,
+            };
+            assert_eq!(w.prstatus(&thread).len(), prstatus);
+        }
+    }
+}
@@ -59,19 +59,19 @@ impl FdTable {
         let weak = Rc::downgrade(&rc);
         match rc.borrow_mut().as_virtual_perf_counter_monitor_mut() {
             None => (),
-            Some(v) => v.weak_self = weak,
+            Some(v) => v.set_weak_self(weak),
         }
 
         self.fds.insert(fd, rc);
         self.update_syscallbuf_fds_disabled(fd, t);
     }
-    pub fn emulate_ioctl(&self, fd: i32, t: &RecordTask, result: &mut u64) -> bool {
+    pub fn emulate_ioctl(&self, fd: i32, t: &mut RecordTask, result: &mut u64) -> bool {
         match self.fds.get(&fd) {
             Some(f) => f.borrow_mut().emulate_ioctl(t, result),
             None => false,
         }
     }
-    pub fn emulate_fcntl(&self, fd: i32, t: &RecordTask, result: &mut u64) -> bool {
+    pub fn emulate_fcntl(&self, fd: i32, t: &mut RecordTask, result: &mut u64) -> bool {
         match self.fds.get(&fd) {
             Some(f) => f.borrow_mut().emulate_fcntl(t, result),
             None => false,
@@ -80,19 +80,18 @@ impl FdTable {
     pub fn emulate_read(
         &self,
         fd: i32,
-        t: &RecordTask,
-        ranges: &Vec<Range>,
-        offset: &LazyOffset,
+        t: &mut RecordTask,
+        ranges: &[Range],
         result: &mut u64,
     ) -> bool {
         match self.fds.get(&fd) {
-            Some(f) => f.borrow().emulate_read(t, ranges, offset, result),
+            Some(f) => f.borrow().emulate_read(t, ranges, result),
             None => false,
         }
     }
-    pub fn filter_getdents(&self, fd: i32, t: &RecordTask) {
+    pub fn filter_getdents(&self, fd: i32, t: &mut RecordTask) {
         match self.fds.get(&fd) {
-            Some(f) => f.borrow_mut().filter_getdents(t),
+            Some(f) => f.borrow().filter_getdents(t),
             None => (),
         }
     }
@@ -262,14 +262,14 @@ pub trait FileMonitor {
     /// Return true if the ioctl should be fully emulated. If so the result
     /// is stored in the last parameter.
     /// Only called during recording.
-    fn emulate_ioctl(&mut self, _t: &RecordTask, _r: &mut u64) -> bool {
+    fn emulate_ioctl(&mut self, _t: &mut RecordTask, _r: &mut u64) -> bool {
         false
     }
 
     /// Return true if the fcntl should should be fully emulated. If so the
     /// result is stored in the last parameter.
     /// Only called during recording.
-    fn emulate_fcntl(&self, _t: &RecordTask, _r: &mut u64) -> bool {
+    fn emulate_fcntl(&mut self, _t: &mut RecordTask, _r: &mut u64) -> bool {
         false
     }
 
@@ -277,17 +277,13 @@ pub trait FileMonitor {
     /// result is stored in the last parameter. The emulation should write to the
     /// task's memory ranges.
     /// Only called during recording.
-    fn emulate_read(
-        &self,
-        _t: &RecordTask,
-        _vr: &Vec<Range>,
-        _o: &LazyOffset,
-        _l: &mut u64,
-    ) -> bool {
+    /// DIFF NOTE: rr also passes a LazyOffset. No monitor needs it and it
+    /// would need a second mutable borrow of the task.
+    fn emulate_read(&self, _t: &mut RecordTask, _vr: &[Range], _l: &mut u64) -> bool {
         false
     }
 
     /// Allows the FileMonitor to rewrite the output of a getdents/getdents64 call
     /// if desired.
-    fn filter_getdents(&self, _t: &RecordTask) {}
+    fn filter_getdents(&self, _t: &mut RecordTask) {}
 }
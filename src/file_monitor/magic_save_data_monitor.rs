@@ -1,13 +1,15 @@
 use crate::{
-    file_monitor::{
-        FileMonitor,
-        FileMonitorType,
-        FileMonitorType::MagicSaveData,
-        LazyOffset,
-        Range,
-    },
+    file_monitor::{FileMonitor, FileMonitorType, FileMonitorType::MagicSaveData, LazyOffset, Range},
+    log::LogLevel::LogWarn,
+    rd::RD_MAGIC_SAVE_DATA_FD,
     remote_ptr::{RemotePtr, Void},
     session::task::replay_task::ReplayTask,
+    trace::trace_frame::FrameTime,
+};
+use libc::pid_t;
+use std::{
+    cmp::{max, min},
+    fmt::{self, Display, Formatter},
 };
 
 /// A FileMonitor to track writes to RR_MAGIC_SAVE_DATA_FD.
@@ -35,7 +37,7 @@ impl FileMonitor for MagicSaveDataMonitor {
                     .trace_reader_mut()
                     .read_raw_data();
                 if rec.data != bytes {
-                    notify_save_data_error(rep_task, rec.addr, &rec.data, &bytes);
+                    notify_save_data_error(rep_task, rec.addr, r.data, &rec.data, &bytes);
                 }
             }
         }
@@ -48,11 +50,181 @@ impl MagicSaveDataMonitor {
     }
 }
 
+/// Maximum number of differing 16-byte rows shown in a mismatch report.
+const MAX_DIFF_ROWS: usize = 64;
+const DIFF_ROW_SIZE: usize = 16;
+
+/// A write to RD_MAGIC_SAVE_DATA_FD during replay whose data did not match
+/// what was written during recording.
+#[derive(Clone)]
+pub struct SaveDataMismatch {
+    pub time: FrameTime,
+    pub tid: pid_t,
+    pub rec_tid: pid_t,
+    pub rec_addr: RemotePtr<Void>,
+    pub rep_addr: RemotePtr<Void>,
+    pub rec_buf: Vec<u8>,
+    pub rep_buf: Vec<u8>,
+}
+
+impl Display for SaveDataMismatch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Divergence in contents of 'tracee-save buffer' at event {} in task {} (rec: {}):",
+            self.time, self.tid, self.rec_tid
+        )?;
+        writeln!(
+            f,
+            "  recorded: write({}, {}, {})",
+            RD_MAGIC_SAVE_DATA_FD,
+            self.rec_addr,
+            self.rec_buf.len()
+        )?;
+        writeln!(
+            f,
+            "  replayed: write({}, {}, {})",
+            RD_MAGIC_SAVE_DATA_FD,
+            self.rep_addr,
+            self.rep_buf.len()
+        )?;
+
+        let len = max(self.rec_buf.len(), self.rep_buf.len());
+        let mut rows_shown = 0;
+        let mut rows_differing = 0;
+        for row_start in (0..len).step_by(DIFF_ROW_SIZE) {
+            let row_end = row_start + DIFF_ROW_SIZE;
+            let rec_row = row_of(&self.rec_buf, row_start, row_end);
+            let rep_row = row_of(&self.rep_buf, row_start, row_end);
+            if rec_row == rep_row {
+                continue;
+            }
+            rows_differing += 1;
+            if rows_shown == MAX_DIFF_ROWS {
+                continue;
+            }
+            rows_shown += 1;
+            writeln!(f, "  {}:", self.rep_addr + row_start)?;
+            writeln!(f, "    rec: {}", hex_row(rec_row, rep_row))?;
+            writeln!(f, "    rep: {}", hex_row(rep_row, rec_row))?;
+        }
+        if rows_differing > rows_shown {
+            writeln!(
+                f,
+                "  ... and {} more differing rows",
+                rows_differing - rows_shown
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn row_of(buf: &[u8], start: usize, end: usize) -> &[u8] {
+    &buf[min(start, buf.len())..min(end, buf.len())]
+}
+
+/// Hex dump of `row`, with bytes that differ from `other` marked with '*'
+/// (in place of the separating space, so the columns stay aligned).
+/// Bytes past the end of the buffer are shown as "--".
+fn hex_row(row: &[u8], other: &[u8]) -> String {
+    let mut s = String::new();
+    for i in 0..DIFF_ROW_SIZE {
+        let cell = match row.get(i) {
+            Some(b) if other.get(i) == Some(b) => format!("{:02x} ", b),
+            Some(b) => format!("{:02x}*", b),
+            None => "-- ".into(),
+        };
+        s.push_str(&cell);
+    }
+    s.trim_end().into()
+}
+
 fn notify_save_data_error(
-    _t: &ReplayTask,
-    _addr: RemotePtr<Void>,
-    _rec_buf: &[u8],
-    _rep_buf: &[u8],
+    t: &ReplayTask,
+    rec_addr: RemotePtr<Void>,
+    rep_addr: RemotePtr<Void>,
+    rec_buf: &[u8],
+    rep_buf: &[u8],
 ) {
-    unimplemented!()
+    let mismatch = SaveDataMismatch {
+        time: t.current_frame_time(),
+        tid: t.tid,
+        rec_tid: t.rec_tid,
+        rec_addr,
+        rep_addr,
+        rec_buf: rec_buf.to_vec(),
+        rep_buf: rep_buf.to_vec(),
+    };
+    let session = t.session();
+    let replay_session = session.as_replay().unwrap();
+    if replay_session.flags().keep_going_on_save_data_mismatch {
+        log!(LogWarn, "{}", mismatch);
+        replay_session.add_save_data_mismatch(mismatch);
+    } else {
+        ed_assert!(t, false, "{}", mismatch);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn mismatch(rec_buf: Vec<u8>, rep_buf: Vec<u8>) -> SaveDataMismatch {
+        SaveDataMismatch {
+            time: 42,
+            tid: 1234,
+            rec_tid: 100,
+            rec_addr: RemotePtr::new_from_val(0x1000),
+            rep_addr: RemotePtr::new_from_val(0x2000),
+            rec_buf,
+            rep_buf,
+        }
+    }
+
+    #[test]
+    fn hex_row_test() {
+        assert_eq!(
+            hex_row(&[0x00, 0x01, 0xff], &[0x00, 0x02]),
+            "00 01*ff*-- -- -- -- -- -- -- -- -- -- -- -- --"
+        );
+        let row: Vec<u8> = (0..16).collect();
+        assert_eq!(
+            hex_row(&row, &row),
+            "00 01 02 03 04 05 06 07 08 09 0a 0b 0c 0d 0e 0f"
+        );
+        assert_eq!(
+            hex_row(&[], &[1]),
+            "-- -- -- -- -- -- -- -- -- -- -- -- -- -- -- --"
+        );
+    }
+
+    #[test]
+    fn display_test() {
+        let mut rec_buf = vec![0u8; 40];
+        let mut rep_buf = rec_buf.clone();
+        rec_buf[17] = 0xaa;
+        rep_buf[17] = 0xbb;
+        rep_buf.truncate(36);
+        assert_eq!(
+            mismatch(rec_buf, rep_buf).to_string(),
+            "Divergence in contents of 'tracee-save buffer' at event 42 in task 1234 (rec: 100):\n\
+             \x20 recorded: write(999, 0x1000, 40)\n\
+             \x20 replayed: write(999, 0x2000, 36)\n\
+             \x20 0x2010:\n\
+             \x20   rec: 00 aa*00 00 00 00 00 00 00 00 00 00 00 00 00 00\n\
+             \x20   rep: 00 bb*00 00 00 00 00 00 00 00 00 00 00 00 00 00\n\
+             \x20 0x2020:\n\
+             \x20   rec: 00 00 00 00 00*00*00*00*-- -- -- -- -- -- -- --\n\
+             \x20   rep: 00 00 00 00 -- -- -- -- -- -- -- -- -- -- -- --\n"
+        );
+    }
+
+    #[test]
+    fn display_limits_rows_test() {
+        let rec_buf = vec![0u8; DIFF_ROW_SIZE * (MAX_DIFF_ROWS + 3)];
+        let rep_buf = vec![1u8; rec_buf.len()];
+        let s = mismatch(rec_buf, rep_buf).to_string();
+        assert_eq!(s.matches("    rec: ").count(), MAX_DIFF_ROWS);
+        assert!(s.ends_with("  ... and 3 more differing rows\n"));
+    }
 }
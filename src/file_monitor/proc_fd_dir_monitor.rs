@@ -1,9 +1,18 @@
 use crate::{
+    arch::Architecture,
+    auto_remote_syscalls::AutoRemoteSyscalls,
+    fd_table::FdTableSharedPtr,
     file_monitor::{FileMonitor, FileMonitorType},
-    session::task::{record_task::RecordTask, Task},
+    remote_ptr::RemotePtr,
+    session::task::{
+        record_task::RecordTask,
+        task_common::{read_mem, write_mem},
+        Task,
+    },
     taskish_uid::TaskUid,
 };
-use std::ffi::OsStr;
+use libc::pid_t;
+use std::{convert::TryInto, ffi::OsStr, mem::size_of, os::unix::ffi::OsStrExt};
 
 /// A FileMonitor to intercept enumerations of /proc/<pid>/fd so that entries
 /// for rr's private fds can be hidden when <pid> is a tracee.
@@ -18,13 +27,228 @@ impl FileMonitor for ProcFdDirMonitor {
         FileMonitorType::ProcFd
     }
 
-    fn filter_getdents(&self, _t: &RecordTask) {
-        unimplemented!()
+    fn filter_getdents(&self, t: &mut RecordTask) {
+        ed_assert!(t, !t.session().is_replaying());
+        let tuid = match self.maybe_tuid {
+            Some(tuid) => tuid,
+            None => return,
+        };
+        let maybe_fds = if t.tuid() == tuid {
+            Some(t.fd_table_shr_ptr())
+        } else {
+            t.session()
+                .find_task_from_task_uid(tuid)
+                .map(|target| target.borrow().fd_table_shr_ptr())
+        };
+        match maybe_fds {
+            Some(fds) => filter_dirents(t, &fds),
+            None => (),
+        }
     }
 }
 
 impl ProcFdDirMonitor {
-    pub fn new(_t: &dyn Task, _pathname: &OsStr) -> ProcFdDirMonitor {
-        unimplemented!()
+    pub fn new(t: &dyn Task, pathname: &OsStr) -> ProcFdDirMonitor {
+        // XXX this makes some assumptions about namespaces... Probably fails
+        // if `t` is not the same pid namespace as rd
+        let mut path = pathname.as_bytes();
+        if path.ends_with(b"/") {
+            path = &path[0..path.len() - 1];
+        }
+        if path.starts_with(b"/proc/") && path.ends_with(b"/fd") {
+            let tid_str = String::from_utf8_lossy(&path[6..path.len() - 3]);
+            if let Ok(tid) = tid_str.parse::<pid_t>() {
+                let maybe_tuid = if t.rec_tid == tid {
+                    Some(t.tuid())
+                } else {
+                    t.session()
+                        .find_task_from_rec_tid(tid)
+                        .map(|ft| ft.borrow().tuid())
+                };
+                return ProcFdDirMonitor { maybe_tuid };
+            }
+        }
+        ProcFdDirMonitor { maybe_tuid: None }
+    }
+}
+
+/// Remove the entries for rd's fds (those for which `is_rd_fd` is true) from
+/// the `size` bytes of dirent structs in `buf`. Returns the number of bytes of
+/// valid dirent structs left in the buffer.
+/// `reclen_offset` and `name_offset` are the offsets of d_reclen and d_name
+/// in the dirent struct.
+fn filter_dirent_structs<F: Fn(i32) -> bool>(
+    is_rd_fd: F,
+    buf: &mut Vec<u8>,
+    size: usize,
+    reclen_offset: usize,
+    name_offset: usize,
+) -> usize {
+    let mut size = size;
+    let mut current_offset = 0;
+    while current_offset < size {
+        let reclen_bytes = &buf[current_offset + reclen_offset..current_offset + reclen_offset + 2];
+        let reclen = u16::from_ne_bytes(reclen_bytes.try_into().unwrap()) as usize;
+        let next_offset = current_offset + reclen;
+
+        let name_start = current_offset + name_offset;
+        let name_len = buf[name_start..next_offset]
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(next_offset - name_start);
+        let name = String::from_utf8_lossy(&buf[name_start..name_start + name_len]);
+        match name.parse::<i32>() {
+            Ok(fd) if is_rd_fd(fd) => {
+                // Skip this entry.
+                buf.drain(current_offset..next_offset);
+                size -= reclen;
+            }
+            // Either this is a tracee fd or not an fd at all (e.g. '.')
+            _ => current_offset = next_offset,
+        }
+    }
+
+    size
+}
+
+fn filter_dirents_arch<Arch: Architecture>(t: &mut RecordTask, fds: &FdTableSharedPtr) {
+    let mut regs = t.regs_ref().clone();
+    let ptr = RemotePtr::<u8>::from(regs.arg2());
+    let len = regs.arg3();
+
+    if regs.syscall_failed() || regs.syscall_result() == 0 {
+        return;
+    }
+
+    // struct linux_dirent { unsigned long d_ino; unsigned long d_off;
+    //   unsigned short d_reclen; char d_name[]; }
+    // struct linux_dirent64 { ino64_t d_ino; off64_t d_off;
+    //   unsigned short d_reclen; unsigned char d_type; char d_name[]; }
+    let (reclen_offset, name_offset) = if regs.original_syscallno() as i32 == Arch::GETDENTS64 {
+        (16, 19)
+    } else {
+        let word_size = size_of::<Arch::unsigned_long>();
+        (2 * word_size, 2 * word_size + 2)
+    };
+
+    loop {
+        let mut buf = read_mem(t, ptr, len, None);
+        let bytes = filter_dirent_structs(
+            |fd| fds.borrow().is_rd_fd(fd),
+            &mut buf,
+            regs.syscall_result(),
+            reclen_offset,
+            name_offset,
+        );
+
+        if bytes > 0 {
+            write_mem(t, ptr, &buf[0..bytes], None);
+            regs.set_syscall_result(bytes);
+            t.set_regs(&regs);
+            // Explicitly record what the kernel may have touched and we discarded,
+            // because it's userspace modification that will not be caught otherwise.
+            if len > bytes {
+                t.record_remote(RemotePtr::cast(ptr + bytes), len - bytes);
+            }
+            return;
+        }
+
+        // We filtered out all the entries, so we need to repeat the syscall.
+        {
+            let mut remote = AutoRemoteSyscalls::new(t);
+            let result = rd_syscall!(
+                remote,
+                regs.original_syscallno() as i32,
+                regs.arg1(),
+                regs.arg2(),
+                regs.arg3()
+            );
+            // Only copy over the syscall result. In particular, we don't want to
+            // copy the AutoRemoteSyscalls ip().
+            regs.set_syscall_result_signed(result);
+        }
+
+        if regs.syscall_failed() || regs.syscall_result() == 0 {
+            // Save the new syscall result, and record the buffer we will otherwise
+            // ignore.
+            t.record_remote(RemotePtr::cast(ptr), len);
+            t.set_regs(&regs);
+            return;
+        }
+    }
+}
+
+fn filter_dirents(t: &mut RecordTask, fds: &FdTableSharedPtr) {
+    let arch = t.arch();
+    rd_arch_function_selfless!(filter_dirents_arch, arch, t, fds)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Build linux_dirent64 structs for `names`, each padded to 8 bytes.
+    fn dirents64(names: &[&str]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for (i, name) in names.iter().enumerate() {
+            let reclen = (19 + name.len() + 1 + 7) & !7;
+            let mut dirent = vec![0u8; reclen];
+            dirent[0..8].copy_from_slice(&(i as u64 + 1).to_ne_bytes());
+            dirent[16..18].copy_from_slice(&(reclen as u16).to_ne_bytes());
+            dirent[19..19 + name.len()].copy_from_slice(name.as_bytes());
+            buf.extend_from_slice(&dirent);
+        }
+        buf
+    }
+
+    #[test]
+    fn filter_dirent_structs_test() {
+        let mut buf = dirents64(&[".", "..", "0", "1", "1000", "2", "1001"]);
+        let size = buf.len();
+        let left = filter_dirent_structs(|fd| fd >= 1000, &mut buf, size, 16, 19);
+        assert_eq!(
+            buf,
+            dirents64_without(&[".", "..", "0", "1", "1000", "2", "1001"], &[4, 6])
+        );
+        assert_eq!(left, buf.len());
+    }
+
+    #[test]
+    fn filter_dirent_structs_ignores_trailing_bytes_test() {
+        let mut buf = dirents64(&["1000", "3"]);
+        let size = buf.len();
+        // Bytes beyond `size` are not dirents and must be left alone.
+        buf.extend_from_slice(&[0xff; 8]);
+        let left = filter_dirent_structs(|fd| fd == 1000, &mut buf, size, 16, 19);
+        assert_eq!(&buf[0..left], &dirents64_without(&["1000", "3"], &[0])[..]);
+        assert_eq!(&buf[left..], &[0xff; 8]);
+    }
+
+    #[test]
+    fn filter_dirent_structs_nothing_to_filter_test() {
+        let mut buf = dirents64(&[".", "..", "0"]);
+        let expected = buf.clone();
+        let size = buf.len();
+        assert_eq!(
+            size,
+            filter_dirent_structs(|_| false, &mut buf, size, 16, 19)
+        );
+        assert_eq!(buf, expected);
+    }
+
+    /// The dirents of `names` with the entries at `skip` removed, keeping the
+    /// d_ino values of the remaining entries.
+    fn dirents64_without(names: &[&str], skip: &[usize]) -> Vec<u8> {
+        let all = dirents64(names);
+        let mut result = Vec::new();
+        let mut offset = 0;
+        for i in 0..names.len() {
+            let reclen = u16::from_ne_bytes([all[offset + 16], all[offset + 17]]) as usize;
+            if !skip.contains(&i) {
+                result.extend_from_slice(&all[offset..offset + reclen]);
+            }
+            offset += reclen;
+        }
+        result
     }
 }
@@ -1,29 +1,42 @@
 use crate::{
-    bindings::{perf_event::perf_event_attr, signal::siginfo_t},
+    bindings::{
+        fcntl::{f_owner_ex, F_OWNER_TID, F_SETOWN_EX, F_SETSIG},
+        perf_event::{
+            perf_event_attr,
+            PERF_EVENT_IOC_DISABLE,
+            PERF_EVENT_IOC_ENABLE,
+            PERF_EVENT_IOC_PERIOD,
+            PERF_EVENT_IOC_RESET,
+        },
+        signal::{siginfo_t, POLL_IN},
+    },
+    event::SignalDeterministic,
     file_monitor::{
         FileMonitor,
         FileMonitorSharedPtr,
         FileMonitorSharedWeakPtr,
         FileMonitorType,
-        LazyOffset,
         Range,
     },
+    log::LogLevel::LogDebug,
     perf_counters::PerfCounters,
+    remote_ptr::RemotePtr,
     session::{
-        task::{record_task::RecordTask, Task},
+        task::{record_task::RecordTask, task_common::read_val_mem, Task},
         SessionSharedWeakPtr,
     },
-    sig::Sig,
+    sig::{self, Sig},
     taskish_uid::TaskUid,
     ticks::Ticks,
 };
-use libc::pid_t;
+use libc::{c_ulong, pid_t, EINVAL, F_SETFL, O_ASYNC};
+use std::{cmp::min, convert::TryFrom};
 
 const VIRTUAL_PERF_COUNTER_SIGNAL_SI_ERRNO: i32 = -1337;
 
 /// A FileMonitor to virtualize the performance counter that rr uses to count
-/// ticks. Note that this doesn't support interrupts yet so recording rr replays
-/// that involve async signals will not work!
+/// ticks. Overflow interrupts are emulated by stopping the target task at the
+/// requested tick count and stashing the signal the tracee asked for.
 pub struct VirtualPerfCounterMonitor {
     initial_ticks: Ticks,
     target_ticks_: Ticks,
@@ -32,8 +45,11 @@ pub struct VirtualPerfCounterMonitor {
     flags: i32,
     sig: Option<Sig>,
     enabled: bool,
+    /// DIFF NOTE: rr only supports a single interrupt. We re-arm the interrupt
+    /// after each overflow like the kernel does for a sampling counter.
+    period: u64,
     session: SessionSharedWeakPtr,
-    pub weak_self: FileMonitorSharedWeakPtr,
+    weak_self: FileMonitorSharedWeakPtr,
 }
 
 impl Drop for VirtualPerfCounterMonitor {
@@ -52,7 +68,7 @@ impl VirtualPerfCounterMonitor {
         target: &dyn Task,
         attr: &perf_event_attr,
     ) -> VirtualPerfCounterMonitor {
-        let v = VirtualPerfCounterMonitor {
+        let mut v = VirtualPerfCounterMonitor {
             session: t.session().weak_self_ptr(),
             initial_ticks: target.tick_count(),
             target_ticks_: 0,
@@ -61,18 +77,31 @@ impl VirtualPerfCounterMonitor {
             flags: 0,
             sig: None,
             enabled: false,
+            period: 0,
             weak_self: Default::default(),
         };
 
         ed_assert!(t, VirtualPerfCounterMonitor::should_virtualize(attr));
         if t.session().is_recording() {
-            v.maybe_enable_interrupt(t.as_record_task().unwrap(), unsafe {
-                attr.__bindgen_anon_1.sample_period
-            });
+            v.maybe_enable_interrupt(t, unsafe { attr.__bindgen_anon_1.sample_period });
         }
         v
     }
 
+    /// DIFF NOTE: rr registers the monitor for interrupts in the constructor.
+    /// We can only do that once the monitor is shared and we know our weak
+    /// self pointer.
+    pub fn set_weak_self(&mut self, weak_self: FileMonitorSharedWeakPtr) {
+        self.weak_self = weak_self;
+        if self.period != 0 {
+            let session = self.session.upgrade().unwrap();
+            session
+                .tasks_with_interrupts
+                .borrow_mut()
+                .insert(self.target_tuid_, self.weak_self.clone());
+        }
+    }
+
     pub fn target_ticks(&self) -> Ticks {
         self.target_ticks_
     }
@@ -81,8 +110,28 @@ impl VirtualPerfCounterMonitor {
         self.target_tuid_
     }
 
-    pub fn synthesize_signal(&self, _t: &RecordTask) {
-        unimplemented!()
+    /// Stash the signal the tracee asked to get when the counter overflows
+    /// and arm the next overflow.
+    pub fn synthesize_signal(&mut self, t: &mut RecordTask) {
+        self.target_ticks_ += self.period;
+        if !self.enabled || self.flags & O_ASYNC == 0 {
+            log!(
+                LogDebug,
+                "Virtual perf counter overflow for {} doesn't need a signal",
+                t.tid
+            );
+            return;
+        }
+
+        let mut si: siginfo_t = Default::default();
+        si.si_signo = self.sig.unwrap_or(sig::SIGIO).as_raw();
+        si.si_errno = VIRTUAL_PERF_COUNTER_SIGNAL_SI_ERRNO;
+        si.si_code = POLL_IN as i32;
+        // Don't bother filling in the band or fd, they're hard to get and
+        // nobody seems to care.
+        // The signal is delivered at a tick count that replay can reach by
+        // itself, so treat it like any other asynchronous signal.
+        t.stash_synthetic_sig(&si, SignalDeterministic::NondeterministicSig);
     }
 
     pub fn is_virtual_perf_counter_signal(s: &siginfo_t) -> bool {
@@ -98,8 +147,39 @@ impl VirtualPerfCounterMonitor {
             .map(|f| f.upgrade().unwrap())
     }
 
-    fn maybe_enable_interrupt(&self, _t: &RecordTask, _after: u64) {
-        unimplemented!()
+    fn maybe_enable_interrupt(&mut self, t: &dyn Task, after: u64) {
+        if after == 0 || after > 0xffffffff {
+            return;
+        }
+
+        let session = self.session.upgrade().unwrap();
+        let maybe_previous = session
+            .tasks_with_interrupts
+            .borrow()
+            .get(&self.target_tuid_)
+            .cloned();
+        if let Some(previous) = maybe_previous {
+            if previous.ptr_eq(&self.weak_self) {
+                // We already have an interrupt scheduled, nothing to do.
+                return;
+            }
+            ed_assert!(
+                t,
+                false,
+                "Multiple virtual performance counters with interrupts for the same task are not supported."
+            );
+        }
+
+        self.enabled = true;
+        self.period = after;
+        self.target_ticks_ = self.initial_ticks + after;
+        // Before set_weak_self() we'll be registered there.
+        if self.weak_self.upgrade().is_some() {
+            session
+                .tasks_with_interrupts
+                .borrow_mut()
+                .insert(self.target_tuid_, self.weak_self.clone());
+        }
     }
 
     fn disable_interrupt(&self) {
@@ -113,6 +193,16 @@ impl VirtualPerfCounterMonitor {
             _ => (),
         }
     }
+
+    /// DIFF NOTE: Run `f` on the target task. `t` is already borrowed so
+    /// it's used directly if it's the target.
+    fn with_target<R, F: FnOnce(&dyn Task) -> R>(&self, t: &mut RecordTask, f: F) -> Option<R> {
+        if t.tuid() == self.target_tuid_ {
+            return Some(f(t));
+        }
+        let maybe_target = t.session().find_task_from_task_uid(self.target_tuid_);
+        maybe_target.map(|target| f(&**target.borrow()))
+    }
 }
 
 impl FileMonitor for VirtualPerfCounterMonitor {
@@ -128,21 +218,104 @@ impl FileMonitor for VirtualPerfCounterMonitor {
         FileMonitorType::VirtualPerfCounter
     }
 
-    fn emulate_ioctl(&mut self, _t: &RecordTask, _r: &mut u64) -> bool {
-        unimplemented!()
+    fn emulate_ioctl(&mut self, t: &mut RecordTask, result: &mut u64) -> bool {
+        let request = t.regs_ref().arg2() as u32 as c_ulong;
+        if request == PERF_EVENT_IOC_ENABLE {
+            *result = 0;
+            self.enabled = true;
+        } else if request == PERF_EVENT_IOC_DISABLE {
+            *result = 0;
+            self.enabled = false;
+        } else if request == PERF_EVENT_IOC_RESET {
+            *result = 0;
+            if let Some(ticks) = self.with_target(t, |target| target.tick_count()) {
+                self.initial_ticks = ticks;
+            }
+        } else if request == PERF_EVENT_IOC_PERIOD {
+            *result = 0;
+            self.disable_interrupt();
+            if let Some(ticks) = self.with_target(t, |target| target.tick_count()) {
+                self.initial_ticks = ticks;
+            }
+            let period_ptr = RemotePtr::<u64>::from(t.regs_ref().arg3());
+            let after = read_val_mem(t, period_ptr, None);
+            self.maybe_enable_interrupt(t, after);
+        } else {
+            ed_assert!(
+                t,
+                false,
+                "Unsupported perf event ioctl {:#x}",
+                t.regs_ref().arg2() as u32
+            );
+        }
+        true
     }
 
-    fn emulate_fcntl(&self, _t: &RecordTask, _r: &mut u64) -> bool {
-        unimplemented!()
+    fn emulate_fcntl(&mut self, t: &mut RecordTask, result: &mut u64) -> bool {
+        *result = -EINVAL as u64;
+        let cmd = t.regs_ref().arg2() as i32;
+        if cmd == F_SETOWN_EX as i32 {
+            let owner_ptr = RemotePtr::<f_owner_ex>::from(t.regs_ref().arg3());
+            let owner = read_val_mem(t, owner_ptr, None);
+            ed_assert_eq!(
+                t,
+                owner.type_,
+                F_OWNER_TID,
+                "Unsupported perf event F_SETOWN_EX type {}",
+                owner.type_
+            );
+            ed_assert_eq!(
+                t,
+                owner.pid,
+                self.target_tuid_.tid(),
+                "Perf event F_SETOWN_EX is only supported to the target tid"
+            );
+            self.owner_tid = owner.pid;
+            *result = 0;
+        } else if cmd == F_SETFL {
+            ed_assert!(
+                t,
+                t.regs_ref().arg3() as i32 & !O_ASYNC == 0,
+                "Unsupported perf event flags {:#x}",
+                t.regs_ref().arg3() as i32
+            );
+            self.flags = t.regs_ref().arg3() as i32;
+            *result = 0;
+        } else if cmd == F_SETSIG as i32 {
+            self.sig = Sig::try_from(t.regs_ref().arg3() as i32).ok();
+            *result = 0;
+        } else {
+            ed_assert!(
+                t,
+                false,
+                "Unsupported perf event fcntl {:#x}",
+                t.regs_ref().arg2() as u32
+            );
+        }
+        true
+    }
+
+    fn emulate_read(&self, t: &mut RecordTask, ranges: &[Range], result: &mut u64) -> bool {
+        let maybe_ticks = self.with_target(t, |target| target.tick_count());
+        *result = match maybe_ticks {
+            Some(ticks) => {
+                let val = (ticks - self.initial_ticks) as i64;
+                write_ranges(t, ranges, &val.to_ne_bytes()) as u64
+            }
+            None => 0,
+        };
+        true
     }
+}
 
-    fn emulate_read(
-        &self,
-        _t: &RecordTask,
-        _vr: &Vec<Range>,
-        _o: &LazyOffset,
-        _l: &mut u64,
-    ) -> bool {
-        unimplemented!()
+fn write_ranges(t: &mut RecordTask, ranges: &[Range], data: &[u8]) -> usize {
+    let mut remaining = data;
+    let mut result = 0;
+    for r in ranges {
+        let bytes = min(remaining.len(), r.length);
+        t.write_bytes(RemotePtr::cast(r.data), &remaining[0..bytes]);
+        remaining = &remaining[bytes..];
+        result += bytes;
     }
+    result
 }
@@ -0,0 +1,431 @@
+//! rd specific commands that are invoked from gdb.
+//!
+//! gdb sends these to us as `qRRCmd:<name>:<arg1>:...` packets (each
+//! component hex encoded) via the python `RDCmd` class defined in
+//! `GdbCommandHandler::gdb_macros()`.
+
+use crate::{
+    elf_core_file::{default_core_file_name, write_core_file},
+    gdb_server::GdbServer,
+    log::LogLevel::LogDebug,
+    session::{task::TaskSharedPtr, Session},
+};
+use std::{
+    ffi::{OsStr, OsString},
+    fmt::Write,
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::PathBuf,
+};
+
+type GdbCommandInvoker = fn(&mut GdbServer, &TaskSharedPtr, &[OsString]) -> String;
+
+/// A command that the user can type at the gdb prompt.
+pub struct GdbCommand {
+    name: &'static str,
+    docs: &'static str,
+    /// gdb commands whose output is passed to us as the first arguments
+    /// when this command is invoked.
+    auto_args: &'static [&'static str],
+    /// The python class on the gdb side that forwards the command to us.
+    python_class: &'static str,
+    invoker: GdbCommandInvoker,
+}
+
+impl GdbCommand {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn docs(&self) -> &'static str {
+        self.docs
+    }
+
+    pub fn invoke(
+        &self,
+        gdb_server: &mut GdbServer,
+        t: &TaskSharedPtr,
+        args: &[OsString],
+    ) -> String {
+        (self.invoker)(gdb_server, t, args)
+    }
+}
+
+const NOT_DURING_RECORD: &'static str = "Command not supported during recording.";
+
+static GDB_COMMANDS: [GdbCommand; 8] = [
+    GdbCommand {
+        name: "when",
+        docs: "Print the number of the last completely replayed rd event.",
+        auto_args: &[],
+        python_class: "RDCmd",
+        invoker: invoke_when,
+    },
+    GdbCommand {
+        name: "when-ticks",
+        docs: "Print the current rd tick count for the current thread.",
+        auto_args: &[],
+        python_class: "RDCmd",
+        invoker: invoke_when_ticks,
+    },
+    GdbCommand {
+        name: "when-tid",
+        docs: "Print the real tid for the current thread.",
+        auto_args: &[],
+        python_class: "RDCmd",
+        invoker: invoke_when_tid,
+    },
+    GdbCommand {
+        name: "checkpoint",
+        docs: "create a checkpoint representing a point in the execution\n\
+               use the 'restart' command to return to the checkpoint",
+        auto_args: &["rd-where"],
+        python_class: "RDCmd",
+        invoker: invoke_checkpoint,
+    },
+    GdbCommand {
+        name: "delete checkpoint",
+        docs: "remove a checkpoint created with the 'checkpoint' command",
+        auto_args: &[],
+        python_class: "RDCmd",
+        invoker: invoke_delete_checkpoint,
+    },
+    GdbCommand {
+        name: "info checkpoints",
+        docs: "list all checkpoints created with the 'checkpoint' command",
+        auto_args: &[],
+        python_class: "RDCmd",
+        invoker: invoke_info_checkpoints,
+    },
+    GdbCommand {
+        name: "restart",
+        docs: "restart at checkpoint N\n\
+               checkpoints are created with the 'checkpoint' command",
+        auto_args: &[],
+        python_class: "RDRestart",
+        invoker: invoke_restart,
+    },
+    GdbCommand {
+        name: "dump-core",
+        docs: "write an ELF core file of the current thread group\n\
+               usage: dump-core [FILE]   (default: core.<tid>)",
+        auto_args: &[],
+        python_class: "RDCmd",
+        invoker: invoke_dump_core,
+    },
+];
+
+fn invoke_when(gdb_server: &mut GdbServer, t: &TaskSharedPtr, _args: &[OsString]) -> String {
+    let session = gdb_server.current_session();
+    if session.as_replay().is_none() {
+        return NOT_DURING_RECORD.into();
+    }
+    let tb = t.borrow();
+    format!(
+        "Current event: {}",
+        tb.as_replay_task().unwrap().current_trace_frame().time()
+    )
+}
+
+fn invoke_when_ticks(gdb_server: &mut GdbServer, t: &TaskSharedPtr, _args: &[OsString]) -> String {
+    let session = gdb_server.current_session();
+    if session.as_replay().is_none() {
+        return NOT_DURING_RECORD.into();
+    }
+    format!("Current tick: {}", t.borrow().tick_count())
+}
+
+fn invoke_when_tid(gdb_server: &mut GdbServer, t: &TaskSharedPtr, _args: &[OsString]) -> String {
+    let session = gdb_server.current_session();
+    if session.as_replay().is_none() {
+        return NOT_DURING_RECORD.into();
+    }
+    format!("Current tid: {}", t.borrow().tid)
+}
+
+fn invoke_checkpoint(gdb_server: &mut GdbServer, _t: &TaskSharedPtr, args: &[OsString]) -> String {
+    let session = gdb_server.current_session();
+    if session.as_replay().is_none() {
+        return NOT_DURING_RECORD.into();
+    }
+    if gdb_server.in_debuggee_end_state() {
+        return "The program is not being run.".into();
+    }
+    let where_ = args
+        .get(1)
+        .map_or(String::from("???"), |w| w.to_string_lossy().into_owned());
+    match gdb_server.create_checkpoint() {
+        Some(id) => format!("Checkpoint {} at {}", id, where_),
+        None => "Can't create a checkpoint here.".into(),
+    }
+}
+
+fn invoke_delete_checkpoint(
+    gdb_server: &mut GdbServer,
+    _t: &TaskSharedPtr,
+    args: &[OsString],
+) -> String {
+    let session = gdb_server.current_session();
+    if session.as_replay().is_none() {
+        return NOT_DURING_RECORD.into();
+    }
+    let arg = args
+        .get(1)
+        .map_or(String::new(), |a| a.to_string_lossy().into_owned());
+    let id = match arg.trim().parse::<u32>() {
+        Ok(id) => id,
+        Err(_) => return format!("Invalid checkpoint number '{}'.", arg),
+    };
+    if gdb_server.delete_checkpoint(id) {
+        format!("Deleted checkpoint {}.", id)
+    } else {
+        format!("No checkpoint number {}.", id)
+    }
+}
+
+fn invoke_info_checkpoints(
+    gdb_server: &mut GdbServer,
+    _t: &TaskSharedPtr,
+    _args: &[OsString],
+) -> String {
+    let session = gdb_server.current_session();
+    if session.as_replay().is_none() {
+        return NOT_DURING_RECORD.into();
+    }
+    let checkpoints = gdb_server.checkpoints();
+    if checkpoints.is_empty() {
+        return "No checkpoints.".into();
+    }
+    let mut out = String::from("ID\tWhen");
+    for (id, time) in checkpoints {
+        write!(out, "\n{}\t{}", id, time).unwrap();
+    }
+    out
+}
+
+/// Only checks that the checkpoint exists. If it does, `RDRestart` on the gdb
+/// side then restarts from it with `run c<N>`, which gdb sends as a
+/// DREQ_RESTART request. An empty response means the checkpoint exists.
+fn invoke_restart(gdb_server: &mut GdbServer, _t: &TaskSharedPtr, args: &[OsString]) -> String {
+    let session = gdb_server.current_session();
+    if session.as_replay().is_none() {
+        return NOT_DURING_RECORD.into();
+    }
+    let arg = match args.get(1) {
+        Some(arg) => arg.to_string_lossy().into_owned(),
+        None => return String::new(),
+    };
+    let checkpoints = gdb_server.checkpoints();
+    if let Ok(id) = arg.parse::<u32>() {
+        if checkpoints.iter().any(|&(checkpoint_id, _)| checkpoint_id == id) {
+            return String::new();
+        }
+    }
+    let mut out = format!("Checkpoint {} not found.\nValid checkpoints:", arg);
+    for (id, _) in checkpoints {
+        write!(out, " {}", id).unwrap();
+    }
+    out
+}
+
+fn invoke_dump_core(gdb_server: &mut GdbServer, t: &TaskSharedPtr, args: &[OsString]) -> String {
+    let session = gdb_server.current_session();
+    if session.as_replay().is_none() {
+        return NOT_DURING_RECORD.into();
+    }
+    if gdb_server.in_debuggee_end_state() {
+        return "The program is not being run.".into();
+    }
+    let path = args
+        .get(1)
+        .map_or_else(|| default_core_file_name(&**t.borrow()), PathBuf::from);
+    match write_core_file(t.borrow_mut().as_mut(), &path) {
+        Ok(()) => format!("Saved core file {}.", path.display()),
+        Err(e) => format!("Can't write core file {}: {}", path.display(), e),
+    }
+}
+
+pub struct GdbCommandHandler;
+
+impl GdbCommandHandler {
+    /// Declare any registered command with supporting
+    /// wrapper code.
+    pub fn gdb_macros() -> String {
+        let mut s = String::from(GDB_MACROS_PRELUDE);
+        for cmd in GdbCommandHandler::commands() {
+            write!(s, "python {}('{}', [", cmd.python_class, cmd.name()).unwrap();
+            for (i, auto_arg) in cmd.auto_args.iter().enumerate() {
+                if i > 0 {
+                    s.push_str(", ");
+                }
+                write!(s, "'{}'", auto_arg).unwrap();
+            }
+            s.push_str("])\n");
+            if !cmd.docs().is_empty() {
+                write!(s, "document {}\n{}\nend\n", cmd.name(), cmd.docs()).unwrap();
+            }
+        }
+        s.push_str(GDB_MACROS_EPILOGUE);
+        s
+    }
+
+    pub fn command_for_name(name: &OsStr) -> Option<&'static GdbCommand> {
+        GdbCommandHandler::commands()
+            .iter()
+            .find(|cmd| cmd.name().as_bytes() == name.as_bytes())
+    }
+
+    /// Process an incoming GDB payload of the following form:
+    ///   <command name>:<arg1>:<arg2>:...
+    ///
+    /// NOTE: RD Command are typically sent with the qRRCmd: prefix which
+    /// should of been stripped already.
+    ///
+    /// Each component is hex encoded. The response is hex encoded too.
+    pub fn process_command(
+        gdb_server: &mut GdbServer,
+        t: &TaskSharedPtr,
+        payload: &OsStr,
+    ) -> OsString {
+        let args = parse_cmd(payload.as_bytes());
+        let name = args.first().cloned().unwrap_or_default();
+        let cmd = match GdbCommandHandler::command_for_name(&name) {
+            Some(cmd) => cmd,
+            None => {
+                return gdb_escape(
+                    format!("Command '{}' not found.\n", name.to_string_lossy()).as_bytes(),
+                );
+            }
+        };
+        log!(LogDebug, "invoking command: {}", cmd.name());
+        let mut resp = cmd.invoke(gdb_server, t, &args);
+        log!(LogDebug, "cmd response: {}", resp);
+        resp.push('\n');
+        gdb_escape(resp.as_bytes())
+    }
+
+    fn commands() -> &'static [GdbCommand] {
+        &GDB_COMMANDS
+    }
+}
+
+/// Split `payload` on ':' and hex decode each component. Components that
+/// aren't valid hex are passed through unchanged.
+fn parse_cmd(payload: &[u8]) -> Vec<OsString> {
+    payload
+        .split(|&c| c == b':')
+        .map(|part| OsString::from_vec(gdb_unescape(part).unwrap_or_else(|| part.to_vec())))
+        .collect()
+}
+
+fn gdb_unescape(encoded: &[u8]) -> Option<Vec<u8>> {
+    if encoded.len() % 2 != 0 {
+        return None;
+    }
+    encoded
+        .chunks(2)
+        .map(|pair| {
+            let s = std::str::from_utf8(pair).ok()?;
+            u8::from_str_radix(s, 16).ok()
+        })
+        .collect()
+}
+
+fn gdb_escape(bytes: &[u8]) -> OsString {
+    let mut s = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        write!(s, "{:02x}", b).unwrap();
+    }
+    s.into()
+}
+
+const GDB_MACROS_PRELUDE: &'static str = r#"
+set python print-stack full
+python
+
+import re
+
+def gdb_unescape(string):
+    result = ""
+    pos = 0
+    while pos < len(string):
+        result += chr(int(string[pos:pos+2], 16))
+        pos += 2
+    return result
+
+def gdb_escape(string):
+    result = ""
+    for curr_char in string:
+        result += format(ord(curr_char), '02x')
+    return result
+
+class RDCmd(gdb.Command):
+    def __init__(self, name, auto_args):
+        super(RDCmd, self).__init__(name,
+            gdb.COMMAND_USER, gdb.COMPLETE_NONE, False)
+        self.cmd_name = name
+        self.auto_args = auto_args
+
+    def invoke(self, arg, from_tty):
+        args = gdb.string_to_argv(arg)
+        response = self.rd_cmd(args)
+        if response is not None:
+            gdb.write(response)
+
+    def rd_cmd(self, args):
+        cmd_prefix = "maint packet qRRCmd:" + gdb_escape(self.cmd_name)
+        arg_str = ""
+        for auto_arg in self.auto_args:
+            arg_str += ":" + gdb_escape(gdb.execute(auto_arg, to_string=True))
+        for arg in args:
+            arg_str += ":" + gdb_escape(arg)
+        rv = gdb.execute(cmd_prefix + arg_str, to_string=True);
+        rv_match = re.search('received: "(.*)"', rv, re.MULTILINE);
+        if not rv_match:
+            gdb.write("Response error: " + rv)
+            return None
+        return gdb_unescape(rv_match.group(1))
+
+class RDRestart(RDCmd):
+    """rd checks the checkpoint, so that it can tell the user what's wrong
+    with it. gdb can't pass on any text from a failed "run"."""
+    def invoke(self, arg, from_tty):
+        args = gdb.string_to_argv(arg)
+        response = self.rd_cmd(args)
+        if response is None:
+            return
+        if response.strip():
+            gdb.write(response)
+            return
+        gdb.execute(("run c" + args[0]) if args else "run")
+
+class RDWhere(gdb.Command):
+    """Helper to get the location for checkpoints. Used by auto-args"""
+    def __init__(self):
+        super(RDWhere, self).__init__('rd-where',
+            gdb.COMMAND_USER, gdb.COMPLETE_NONE, False)
+
+    def invoke(self, arg, from_tty):
+        # Get the symbol name from 'frame 0' in the format:
+        # '#0  0x00007f9d81a04c46 in _dl_start (arg=0x7ffee1f1c740) at rtld.c:356
+        # 356 in rtld.c'
+        try:
+            rv = gdb.execute('frame 0', to_string=True)
+        except:
+            rv = "???" # This may occur if we're not running
+        m = re.match(r'#0\w*(.*)', rv)
+        if m:
+            rv = m.group(1)
+        else:
+            rv = rv + "???"
+        gdb.write(rv)
+
+RDWhere()
+
+end
+"#;
+
+const GDB_MACROS_EPILOGUE: &'static str = r#"set unwindonsignal on
+set non-stop off
+handle SIGURG stop
+set prompt (rd) 
+"#;
@@ -1,19 +1,82 @@
 use crate::{
     gdb_register::GdbRegister,
+    log::LogLevel::{LogDebug, LogError, LogInfo, LogWarn},
     registers::MAX_REG_SIZE_BYTES,
     remote_ptr::{RemotePtr, Void},
+    replay_timeline::RunDirection,
     scoped_fd::ScopedFd,
     session::SessionSharedPtr,
     sig::Sig,
+    util::resource_path,
+};
+use gdb_request::*;
+use libc::{
+    pid_t,
+    EACCES,
+    EBADF,
+    EBUSY,
+    EEXIST,
+    EFAULT,
+    EFBIG,
+    EINTR,
+    EINVAL,
+    EISDIR,
+    EMFILE,
+    ENAMETOOLONG,
+    ENFILE,
+    ENODEV,
+    ENOENT,
+    ENOSPC,
+    ENOTDIR,
+    EPERM,
+    EROFS,
+    ESPIPE,
+    O_APPEND,
+    O_CREAT,
+    O_EXCL,
+    O_RDONLY,
+    O_RDWR,
+    O_TRUNC,
+    O_WRONLY,
+};
+use nix::{
+    errno::Errno,
+    poll::{poll, PollFd, PollFlags},
+    sys::socket::{
+        accept,
+        bind,
+        listen,
+        setsockopt,
+        socket,
+        sockopt::ReuseAddr,
+        AddressFamily,
+        InetAddr,
+        IpAddr,
+        SockAddr,
+        SockFlag,
+        SockType,
+    },
+    unistd::{read, write},
+    Error,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    ffi::{OsStr, OsString},
+    fmt::{self, Display, Formatter},
+    fs,
+    net::Ipv4Addr,
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::PathBuf,
 };
-use libc::pid_t;
-use std::ffi::OsStr;
 
 include!(concat!(
     env!("OUT_DIR"),
     "/gdb_request_bindings_generated.rs"
 ));
 
+const INTERRUPT_CHAR: u8 = b'\x03';
+
 /// Represents a possibly-undefined register `name`.  `size` indicates how
 /// many bytes of `value` are valid, if any.
 #[derive(Clone, Debug)]
@@ -64,17 +127,165 @@ impl GdbRegisterValue {
             _ => panic!("Unexpected GdbRegisterValue: {:?}", self),
         }
     }
+
+    /// The first `size` bytes of the register value in native (little endian) order,
+    /// regardless of how the value is stored.
+    pub fn as_bytes(&self) -> Vec<u8> {
+        match self.value {
+            GdbRegisterValueData::Value(v) => v[0..self.size].to_owned(),
+            GdbRegisterValueData::Value1(v) => v.to_le_bytes()[0..self.size].to_owned(),
+            GdbRegisterValueData::Value2(v) => v.to_le_bytes()[0..self.size].to_owned(),
+            GdbRegisterValueData::Value4(v) => v.to_le_bytes()[0..self.size].to_owned(),
+            GdbRegisterValueData::Value8(v) => v.to_le_bytes()[0..self.size].to_owned(),
+        }
+    }
+}
+
+/// Descriptor for task.  Note: `tid` is not portable.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct GdbThreadId {
+    pub pid: pid_t,
+    pub tid: pid_t,
+}
+
+impl GdbThreadId {
+    pub const ANY: GdbThreadId = GdbThreadId { pid: 0, tid: 0 };
+    pub const ALL: GdbThreadId = GdbThreadId { pid: -1, tid: -1 };
+
+    pub fn new(pid: pid_t, tid: pid_t) -> GdbThreadId {
+        GdbThreadId { pid, tid }
+    }
 }
 
-/// @TODO
-pub struct GdbThreadId;
+impl Default for GdbThreadId {
+    fn default() -> Self {
+        Self::ANY
+    }
+}
 
-/// @TODO
-pub struct GdbRequest;
-/// @TODO
-pub struct GdbRestartType;
-/// @TODO
-pub struct GdbContAction;
+impl Display for GdbThreadId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.pid, self.tid)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum GdbRestartType {
+    RestartFromPrevious,
+    RestartFromEvent,
+    RestartFromCheckpoint,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum GdbActionType {
+    ActionContinue,
+    ActionStep,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct GdbContAction {
+    pub type_: GdbActionType,
+    pub target: GdbThreadId,
+    pub signal_to_deliver: Option<Sig>,
+}
+
+impl GdbContAction {
+    pub fn new(
+        type_: GdbActionType,
+        target: GdbThreadId,
+        signal_to_deliver: Option<Sig>,
+    ) -> GdbContAction {
+        GdbContAction {
+            type_,
+            target,
+            signal_to_deliver,
+        }
+    }
+}
+
+/// The parameters of a `GdbRequest`. Which variant is present depends on the
+/// `GdbRequestType` of the request.
+#[derive(Clone)]
+pub enum GdbRequestValue {
+    GdbRequestNone,
+    GdbRequestMem(Mem),
+    GdbRequestWatch(Watch),
+    GdbRequestRegisterValue(GdbRegisterValue),
+    GdbRequestRestart(Restart),
+    GdbRequestCont(Cont),
+    GdbRequestText(OsString),
+    GdbRequestTls(Tls),
+    GdbRequestSymbol(Symbol),
+    GdbRequestFileSetfs(FileSetfs),
+    GdbRequestFileOpen(FileOpen),
+    GdbRequestFilePread(FilePread),
+    GdbRequestFileClose(FileClose),
+}
+
+/// These requests are made by the debugger host and honored in proxy
+/// by rd, the target.
+#[derive(Clone)]
+pub struct GdbRequest {
+    pub type_: GdbRequestType,
+    pub value: GdbRequestValue,
+    pub target: GdbThreadId,
+    pub suppress_debugger_stop: bool,
+}
+
+impl Default for GdbRequest {
+    fn default() -> Self {
+        GdbRequest::new(DREQ_NONE, GdbRequestValue::GdbRequestNone)
+    }
+}
+
+macro_rules! gdb_request_accessors {
+    ($getter:ident, $getter_mut:ident, $variant:ident, $ty:ty) => {
+        pub fn $getter(&self) -> &$ty {
+            match &self.value {
+                GdbRequestValue::$variant(v) => v,
+                _ => panic!("Unexpected GdbRequest type: {}", self.type_),
+            }
+        }
+
+        pub fn $getter_mut(&mut self) -> &mut $ty {
+            let type_ = self.type_;
+            match &mut self.value {
+                GdbRequestValue::$variant(v) => v,
+                _ => panic!("Unexpected GdbRequest type: {}", type_),
+            }
+        }
+    };
+}
+
+impl GdbRequest {
+    pub fn new(type_: GdbRequestType, value: GdbRequestValue) -> GdbRequest {
+        GdbRequest {
+            type_,
+            value,
+            target: GdbThreadId::ANY,
+            suppress_debugger_stop: false,
+        }
+    }
+
+    /// Return nonzero if this requires that program execution be resumed
+    /// in some way.
+    pub fn is_resume_request(&self) -> bool {
+        self.type_ == DREQ_CONT
+    }
+
+    gdb_request_accessors!(mem, mem_mut, GdbRequestMem, Mem);
+    gdb_request_accessors!(watch, watch_mut, GdbRequestWatch, Watch);
+    gdb_request_accessors!(reg, reg_mut, GdbRequestRegisterValue, GdbRegisterValue);
+    gdb_request_accessors!(restart, restart_mut, GdbRequestRestart, Restart);
+    gdb_request_accessors!(cont, cont_mut, GdbRequestCont, Cont);
+    gdb_request_accessors!(text, text_mut, GdbRequestText, OsString);
+    gdb_request_accessors!(tls, tls_mut, GdbRequestTls, Tls);
+    gdb_request_accessors!(sym, sym_mut, GdbRequestSymbol, Symbol);
+    gdb_request_accessors!(file_setfs, file_setfs_mut, GdbRequestFileSetfs, FileSetfs);
+    gdb_request_accessors!(file_open, file_open_mut, GdbRequestFileOpen, FileOpen);
+    gdb_request_accessors!(file_pread, file_pread_mut, GdbRequestFilePread, FilePread);
+    gdb_request_accessors!(file_close, file_close_mut, GdbRequestFileClose, FileClose);
+}
 
 pub mod gdb_request {
     use super::{GdbContAction, GdbRestartType};
@@ -85,6 +296,7 @@ pub mod gdb_request {
     use libc::pid_t;
     use std::ffi::OsString;
 
+    #[derive(Clone, Default)]
     pub struct Mem {
         pub addr: usize,
         pub len: usize,
@@ -93,38 +305,45 @@ pub mod gdb_request {
         pub data: Vec<u8>,
     }
 
+    #[derive(Clone, Default)]
     pub struct Watch {
         pub addr: usize,
         pub kind: i32,
         pub conditions: Vec<Vec<u8>>,
     }
 
+    #[derive(Clone)]
     pub struct Restart {
         pub param: i32,
         pub param_str: OsString,
         pub type_: GdbRestartType,
     }
 
+    #[derive(Clone)]
     pub struct Cont {
         pub run_direction: RunDirection,
         pub actions: Vec<GdbContAction>,
     }
 
+    #[derive(Clone)]
     pub struct Tls {
         pub offset: usize,
         pub load_module: RemotePtr<Void>,
     }
 
+    #[derive(Clone)]
     pub struct Symbol {
         pub has_address: bool,
         pub address: RemotePtr<Void>,
         pub name: OsString,
     }
 
+    #[derive(Clone)]
     pub struct FileSetfs {
         pub pid: pid_t,
     }
 
+    #[derive(Clone)]
     pub struct FileOpen {
         pub file_name: OsString,
         /// In system format, not gdb's format
@@ -132,12 +351,14 @@ pub mod gdb_request {
         pub mode: i32,
     }
 
+    #[derive(Clone)]
     pub struct FilePread {
         pub fd: i32,
         pub size: usize,
         pub offset: u64,
     }
 
+    #[derive(Clone)]
     pub struct FileClose {
         pub fd: i32,
     }
@@ -145,7 +366,7 @@ pub mod gdb_request {
 
 #[derive(Copy, Clone)]
 pub struct GdbConnectionFeatures {
-    reverse_execution: bool,
+    pub reverse_execution: bool,
 }
 
 impl Default for GdbConnectionFeatures {
@@ -156,6 +377,66 @@ impl Default for GdbConnectionFeatures {
     }
 }
 
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum ProbePort {
+    DontProbe,
+    ProbePort,
+}
+
+/// Open a listening socket on `address`:`port`. If `probe` is `ProbePort::ProbePort`
+/// and the port is already in use, successive port numbers are tried. `port` is
+/// updated with the port actually bound to.
+pub fn open_socket(address: &str, port: &mut u16, probe: ProbePort) -> ScopedFd {
+    let listen_fd = match socket(
+        AddressFamily::Inet,
+        SockType::Stream,
+        SockFlag::SOCK_CLOEXEC,
+        None,
+    ) {
+        Ok(fd) => ScopedFd::from_raw(fd),
+        Err(e) => fatal!("Couldn't create socket: {:?}", e),
+    };
+
+    let ip: Ipv4Addr = match address.parse() {
+        Ok(ip) => ip,
+        Err(e) => fatal!("Couldn't parse address `{}': {:?}", address, e),
+    };
+
+    if let Err(e) = setsockopt(listen_fd.as_raw(), ReuseAddr, &true) {
+        fatal!("Couldn't set SO_REUSEADDR: {:?}", e);
+    }
+
+    loop {
+        let addr = SockAddr::new_inet(InetAddr::new(
+            IpAddr::from_std(&std::net::IpAddr::V4(ip)),
+            *port,
+        ));
+        match bind(listen_fd.as_raw(), &addr) {
+            Ok(()) => (),
+            Err(Error::Sys(Errno::EADDRINUSE))
+            | Err(Error::Sys(Errno::EACCES))
+            | Err(Error::Sys(Errno::EINVAL))
+                if probe == ProbePort::ProbePort =>
+            {
+                *port += 1;
+                continue;
+            }
+            Err(e) => fatal!("Couldn't bind to port {}: {:?}", *port, e),
+        }
+
+        match listen(listen_fd.as_raw(), 1 /*backlogged connection*/) {
+            Ok(()) => break,
+            Err(Error::Sys(Errno::EADDRINUSE)) if probe == ProbePort::ProbePort => {
+                *port += 1;
+                continue;
+            }
+            Err(e) => fatal!("Couldn't listen on port {}: {:?}", *port, e),
+        }
+    }
+
+    listen_fd
+}
+
 /// This struct wraps up the state of the gdb protocol, so that we can
 /// offer a (mostly) stateless interface to clients.
 pub struct GdbConnection {
@@ -184,20 +465,52 @@ pub struct GdbConnection {
     connection_alive_: bool,
     /// client supports multiprocess extension
     multiprocess_supported_: bool,
+    /// Signals that gdb has asked us to pass through to the tracee without stopping.
+    pass_signals: HashSet<i32>,
+    /// Sessions cloned for the user's `checkpoint` commands, keyed by
+    /// checkpoint id.
+    checkpoints: HashMap<u32, SessionSharedPtr>,
 }
 
 impl GdbConnection {
+    pub const CPU_X86_64: u32 = 0x1;
+    pub const CPU_AVX: u32 = 0x2;
+    pub const CPU_AARCH64: u32 = 0x4;
+    pub const CPU_PKU: u32 = 0x8;
+
     /// Call this when the target of |req| is needed to fulfill the
     /// request, but the target is dead.  This situation is a symptom of a
     /// gdb or rr bug.
-    pub fn notify_no_such_thread(_req: &GdbRequest) {
-        unimplemented!()
+    pub fn notify_no_such_thread(&mut self, req: &GdbRequest) {
+        debug_assert!(req.target == self.req.target && req.type_ == self.req.type_);
+
+        // '10' is the errno ECHILD.  We use it as a magic code to
+        // notify the user that the thread that was the target of this
+        // request has died, and either gdb didn't notice that, or rd
+        // didn't notify gdb.  Either way, the user should restart
+        // their debugging session.
+        log!(
+            LogError,
+            "Targeted thread no longer exists; this is the result of either a gdb or\n\
+             rd bug.  Please restart your debugging session and avoid doing whatever\n\
+             triggered this bug."
+        );
+        self.write_packet(b"E10");
+        self.consume_request();
     }
 
     /// Finish a DREQ_RESTART request.  Should be invoked after replay
     /// restarts and prior GdbConnection has been restored.
-    pub fn notify_restart() {
-        unimplemented!()
+    pub fn notify_restart(&mut self) {
+        debug_assert_eq!(DREQ_RESTART, self.req.type_);
+
+        // These threads may not exist at the first trace-stop after
+        // restart.  The gdb client should reset this state, but help
+        // it out just in case.
+        self.resume_thread = GdbThreadId::ANY;
+        self.query_thread = GdbThreadId::ANY;
+
+        self.req = GdbRequest::default();
     }
 
     /// Return the current request made by the debugger host, that needs to
@@ -209,120 +522,333 @@ impl GdbConnection {
     ///
     /// The target should peek at the debugger request in between execution
     /// steps.  A new request may need to be serviced.
-    pub fn get_request() -> GdbRequest {
-        unimplemented!()
+    pub fn get_request(&mut self) -> GdbRequest {
+        if DREQ_RESTART == self.req.type_ {
+            log!(LogDebug, "consuming RESTART request");
+            self.notify_restart();
+            // gdb wants to be notified with a stop packet when
+            // the process "relaunches".  In rd's case, the
+            // traceee may be very far away from process creation,
+            // but that's OK.
+            self.req = GdbRequest::new(DREQ_GET_STOP_REASON, GdbRequestValue::GdbRequestNone);
+            self.req.target = self.query_thread;
+            return self.req.clone();
+        }
+
+        // Can't ask for the next request until you've satisfied the
+        // current one, for requests that need an immediate
+        // response.
+        debug_assert!(!request_needs_immediate_response(&self.req));
+
+        if !self.sniff_packet() && self.req.is_resume_request() {
+            // There's no new request data available and gdb has
+            // already asked us to resume.  OK, do that (or keep
+            // doing that) now.
+            return self.req.clone();
+        }
+
+        loop {
+            // There's either new request data, or we have nothing
+            // to do.  Either way, block until we read a complete
+            // packet from gdb.
+            self.read_packet();
+
+            if !self.connection_alive_ {
+                self.req = GdbRequest::new(DREQ_DETACH, GdbRequestValue::GdbRequestNone);
+                return self.req.clone();
+            }
+
+            if self.process_packet() {
+                // We couldn't process the packet internally,
+                // so the target has to do something.
+                return self.req.clone();
+            }
+            // The packet we got was "internal", gdb details.
+            // Nothing for the target to do yet.  Keep waiting.
+        }
     }
 
     /// Notify the host that this process has exited with |code|.
-    pub fn notify_exit_code(_code: i32) {
-        unimplemented!()
+    pub fn notify_exit_code(&mut self, code: i32) {
+        debug_assert!(self.req.is_resume_request() || self.req.type_ == DREQ_INTERRUPT);
+
+        let buf = format!("W{:02x}", code);
+        self.write_packet(buf.as_bytes());
+
+        self.consume_request();
     }
 
     /// Notify the host that this process has exited from |sig|.
-    pub fn notify_exit_signal(_sig: Sig) {
-        unimplemented!()
+    pub fn notify_exit_signal(&mut self, sig: Sig) {
+        debug_assert!(self.req.is_resume_request() || self.req.type_ == DREQ_INTERRUPT);
+
+        let buf = format!("X{:02x}", sig.as_raw());
+        self.write_packet(buf.as_bytes());
+
+        self.consume_request();
     }
 
     /// Notify the host that a resume request has "finished", i.e., the
     /// target has stopped executing for some reason.  |sig| is the signal
-    /// that stopped execution, or 0 if execution stopped otherwise.
-    pub fn notify_stop(_which: GdbThreadId, _sig: Sig, _watch_addr: Option<usize>) {
-        unimplemented!()
+    /// that stopped execution, or `None` if execution stopped otherwise.
+    pub fn notify_stop(
+        &mut self,
+        thread: GdbThreadId,
+        sig: Option<Sig>,
+        watch_addr: Option<usize>,
+    ) {
+        debug_assert!(self.req.is_resume_request() || self.req.type_ == DREQ_INTERRUPT);
+
+        if self.tgid != thread.pid {
+            log!(
+                LogDebug,
+                "ignoring stop of {} because we're debugging tgid {}",
+                thread,
+                self.tgid
+            );
+            // Re-use the existing continue request to advance to
+            // the next stop we're willing to tell gdb about.
+            return;
+        }
+        self.send_stop_reply_packet(thread, sig, watch_addr);
+
+        // This isn't documented in the gdb remote protocol, but if we
+        // don't do this, gdb will sometimes continue the current thread
+        // when the user asks to continue a different thread.
+        self.query_thread = thread;
+
+        self.consume_request();
     }
 
     /// Notify the debugger that a restart request failed.
-    pub fn notify_restart_failed() {
-        unimplemented!()
+    pub fn notify_restart_failed(&mut self) {
+        debug_assert_eq!(DREQ_RESTART, self.req.type_);
+
+        // TODO: it's not known by this author whether gdb knows how
+        // to recover from a failed "run" request.
+        self.write_packet(b"E01");
+
+        self.consume_request();
     }
 
     /// Tell the host that |thread| is the current thread.
-    pub fn reply_get_current_thread(_thread: GdbThreadId) {
-        unimplemented!()
+    pub fn reply_get_current_thread(&mut self, thread: GdbThreadId) {
+        debug_assert_eq!(DREQ_GET_CURRENT_THREAD, self.req.type_);
+
+        let buf = if self.multiprocess_supported_ {
+            format!("QCp{:02x}.{:02x}", thread.pid, thread.tid)
+        } else {
+            format!("QC{:02x}", thread.tid)
+        };
+        self.write_packet(buf.as_bytes());
+
+        self.consume_request();
     }
 
     /// Reply with the target thread's |auxv| pairs. |auxv.empty()|
     /// if there was an error reading the auxiliary vector.
-    pub fn reply_get_auxv(_auxv: &[u8]) {
-        unimplemented!()
+    pub fn reply_get_auxv(&mut self, auxv: &[u8]) {
+        debug_assert_eq!(DREQ_GET_AUXV, self.req.type_);
+
+        if !auxv.is_empty() {
+            self.write_binary_packet(b"l", auxv);
+        } else {
+            self.write_packet(b"E01");
+        }
+
+        self.consume_request();
     }
 
     /// Reply with the target thread's executable file name
-    pub fn reply_get_exec_file(_exec_file: &OsStr) {
-        unimplemented!()
+    pub fn reply_get_exec_file(&mut self, exec_file: &OsStr) {
+        debug_assert_eq!(DREQ_GET_EXEC_FILE, self.req.type_);
+
+        if !exec_file.is_empty() {
+            self.write_binary_packet(b"l", exec_file.as_bytes());
+        } else {
+            self.write_packet(b"E01");
+        }
+
+        self.consume_request();
     }
 
     /// |alive| is true if the requested thread is alive, false if dead.
-    pub fn reply_get_is_thread_alive(_alive: bool) {
-        unimplemented!()
+    pub fn reply_get_is_thread_alive(&mut self, alive: bool) {
+        debug_assert_eq!(DREQ_GET_IS_THREAD_ALIVE, self.req.type_);
+
+        self.write_packet(if alive { &b"OK"[..] } else { &b"E01"[..] });
+
+        self.consume_request();
     }
 
     /// |info| is a string containing data about the request target that
     /// might be relevant to the debugger user.
-    pub fn reply_get_thread_extra_info(_info: &OsStr) {
-        unimplemented!()
+    pub fn reply_get_thread_extra_info(&mut self, info: &OsStr) {
+        debug_assert_eq!(DREQ_GET_THREAD_EXTRA_INFO, self.req.type_);
+
+        log!(LogDebug, "thread extra info: {:?}", info);
+        // rr sends the terminating nul too
+        let mut data = info.as_bytes().to_vec();
+        data.push(0);
+        self.write_hex_bytes_packet(&data);
+
+        self.consume_request();
     }
 
     /// |ok| is true if req->target can be selected, false otherwise.
-    pub fn reply_select_thread(_ok: bool) {
-        unimplemented!()
+    pub fn reply_select_thread(&mut self, ok: bool) {
+        debug_assert!(
+            DREQ_SET_CONTINUE_THREAD == self.req.type_ || DREQ_SET_QUERY_THREAD == self.req.type_
+        );
+
+        if ok && DREQ_SET_CONTINUE_THREAD == self.req.type_ {
+            self.resume_thread = self.req.target;
+        } else if ok && DREQ_SET_QUERY_THREAD == self.req.type_ {
+            self.query_thread = self.req.target;
+        }
+        self.write_packet(if ok { &b"OK"[..] } else { &b"E01"[..] });
+
+        self.consume_request();
     }
 
     /// The first |mem.size()| bytes of the request were read into |mem|.
     /// |mem.size()| must be less than or equal to the length of the request.
-    pub fn reply_get_mem(_mem: &[u8]) {
-        unimplemented!()
+    pub fn reply_get_mem(&mut self, mem: &[u8]) {
+        debug_assert_eq!(DREQ_GET_MEM, self.req.type_);
+        debug_assert!(mem.len() <= self.req.mem().len);
+
+        if self.req.mem().len > 0 && mem.is_empty() {
+            self.write_packet(b"E01");
+        } else {
+            self.write_hex_bytes_packet(mem);
+        }
+
+        self.consume_request();
     }
 
     /// |ok| is true if a SET_MEM request succeeded, false otherwise.  This
     /// function *must* be called whenever a SET_MEM request is made,
     /// regardless of success/failure or special interpretation.
-    pub fn reply_set_mem(_ok: bool) {
-        unimplemented!()
+    pub fn reply_set_mem(&mut self, ok: bool) {
+        debug_assert_eq!(DREQ_SET_MEM, self.req.type_);
+
+        self.write_packet(if ok { &b"OK"[..] } else { &b"E01"[..] });
+
+        self.consume_request();
     }
 
     /// Reply to the DREQ_SEARCH_MEM request.
     /// |found| is true if we found the searched-for bytes starting at address
     /// |addr|.
-    pub fn reply_search_mem(_found: bool, _addr: RemotePtr<Void>) {
-        unimplemented!()
+    pub fn reply_search_mem(&mut self, found: bool, addr: RemotePtr<Void>) {
+        debug_assert_eq!(DREQ_SEARCH_MEM, self.req.type_);
+
+        if found {
+            let buf = format!("1,{:x}", addr.as_usize());
+            self.write_packet(buf.as_bytes());
+        } else {
+            self.write_packet(b"0");
+        }
+
+        self.consume_request();
     }
 
-    /// Reply to the DREQ_GET_OFFSETS request.
-    pub fn reply_get_offsets(/* TODO*/) {
-        unimplemented!()
+    /// Reply to the DREQ_GET_OFFSETS request. We don't relocate anything, so
+    /// this is the empty "unsupported" reply, which makes gdb use the
+    /// addresses in the symbol files as they are.
+    pub fn reply_get_offsets(&mut self) {
+        debug_assert_eq!(DREQ_GET_OFFSETS, self.req.type_);
+
+        self.write_packet(b"");
+
+        self.consume_request();
     }
 
     /// Send |value| back to the debugger host.  |value| may be undefined.
-    pub fn reply_get_reg(_value: &GdbRegisterValue) {
-        unimplemented!()
+    pub fn reply_get_reg(&mut self, value: &GdbRegisterValue) {
+        debug_assert_eq!(DREQ_GET_REG, self.req.type_);
+
+        let buf = print_reg_value(value);
+        self.write_packet(&buf);
+
+        self.consume_request();
     }
 
     /// Send |file| back to the debugger host.  |file| may contain
     /// undefined register values.
-    pub fn reply_get_regs(_file: &[GdbRegisterValue]) {
-        unimplemented!()
+    pub fn reply_get_regs(&mut self, file: &[GdbRegisterValue]) {
+        debug_assert_eq!(DREQ_GET_REGS, self.req.type_);
+
+        let mut buf = Vec::<u8>::with_capacity(file.len() * 2 * MAX_REG_SIZE_BYTES);
+        for reg in file {
+            buf.extend_from_slice(&print_reg_value(reg));
+        }
+        self.write_packet(&buf);
+
+        self.consume_request();
     }
 
     /// Pass |ok = true| iff the requested register was successfully set.
-    pub fn reply_set_reg(_ok: bool) {
-        unimplemented!()
+    pub fn reply_set_reg(&mut self, ok: bool) {
+        debug_assert_eq!(DREQ_SET_REG, self.req.type_);
+
+        // TODO: what happens if we're forced to reply to a
+        // set-register request with |ok = false|, leading us to
+        // pretend not to understand the packet?  If, later, an
+        // experimental session needs the register set, what happens?
+        if ok {
+            self.write_packet(b"OK");
+        } else {
+            self.write_packet(b"");
+        }
+
+        self.consume_request();
     }
 
     /// Reply to the DREQ_GET_STOP_REASON request.
-    pub fn reply_get_stop_reason(_which: GdbThreadId, _sig: Sig) {
-        unimplemented!()
+    pub fn reply_get_stop_reason(&mut self, which: GdbThreadId, sig: Option<Sig>) {
+        debug_assert_eq!(DREQ_GET_STOP_REASON, self.req.type_);
+
+        self.send_stop_reply_packet(which, sig, None);
+
+        self.consume_request();
     }
 
     /// |threads| contains the list of live threads, of which there are
     /// |len|.
-    pub fn reply_get_thread_list(_threads: &[GdbThreadId]) {
-        unimplemented!()
+    pub fn reply_get_thread_list(&mut self, threads: &[GdbThreadId]) {
+        debug_assert_eq!(DREQ_GET_THREAD_LIST, self.req.type_);
+
+        let ids: Vec<String> = threads
+            .iter()
+            .filter(|t| self.tgid == t.pid)
+            .map(|t| {
+                if self.multiprocess_supported_ {
+                    format!("p{:02x}.{:02x}", t.pid, t.tid)
+                } else {
+                    format!("{:02x}", t.tid)
+                }
+            })
+            .collect();
+
+        if ids.is_empty() {
+            self.write_packet(b"l");
+        } else {
+            let buf = format!("m{}", ids.join(","));
+            self.write_packet(buf.as_bytes());
+        }
+
+        self.consume_request();
     }
 
     /// |ok| is true if the request was successfully applied, false if
     /// not.
-    pub fn reply_watchpoint_request(_ok: bool) {
-        unimplemented!()
+    pub fn reply_watchpoint_request(&mut self, ok: bool) {
+        debug_assert!(DREQ_WATCH_FIRST <= self.req.type_ && self.req.type_ <= DREQ_WATCH_LAST);
+
+        self.write_packet(if ok { &b"OK"[..] } else { &b"E01"[..] });
+
+        self.consume_request();
     }
 
     /// DREQ_DETACH was processed.
@@ -330,63 +856,135 @@ impl GdbConnection {
     /// There's no functional reason to reply to the detach request.
     /// However, some versions of gdb expect a response and time out
     /// awaiting it, wasting developer time.
-    pub fn reply_detach() {
-        unimplemented!()
+    pub fn reply_detach(&mut self) {
+        debug_assert_eq!(DREQ_DETACH, self.req.type_);
+
+        self.write_packet(b"OK");
+
+        self.consume_request();
     }
 
     /// Pass the siginfo_t and its size (as requested by the debugger) in
     /// |si_bytes| and |num_bytes| if successfully read.  Otherwise pass
     /// |si_bytes = nullptr|.
-    pub fn reply_read_siginfo(_si_bytes: &[u8]) {
-        unimplemented!()
+    pub fn reply_read_siginfo(&mut self, si_bytes: &[u8]) {
+        debug_assert_eq!(DREQ_READ_SIGINFO, self.req.type_);
+
+        if si_bytes.is_empty() {
+            self.write_packet(b"E01");
+        } else {
+            self.write_binary_packet(b"l", si_bytes);
+        }
+
+        self.consume_request();
     }
 
-    /// Not yet implemented, but call this after a WRITE_SIGINFO request
-    /// anyway.
-    pub fn reply_write_siginfo(/* TODO*/) {
-        unimplemented!()
+    /// Call this after a WRITE_SIGINFO request. A replay can't deliver a
+    /// different signal than the one recorded, so writing siginfo is
+    /// unsupported and gets the empty reply.
+    pub fn reply_write_siginfo(&mut self) {
+        debug_assert_eq!(DREQ_WRITE_SIGINFO, self.req.type_);
+
+        self.write_packet(b"");
+
+        self.consume_request();
     }
 
     /// Send a manual text response to a rr cmd (maintenance) packet.
-    pub fn reply_rd_cmd(_text: &OsStr) {
-        unimplemented!()
+    pub fn reply_rd_cmd(&mut self, text: &OsStr) {
+        debug_assert_eq!(DREQ_RR_CMD, self.req.type_);
+
+        self.write_packet(text.as_bytes());
+
+        self.consume_request();
     }
 
     /// Send a qSymbol response to gdb, requesting the address of the
     /// symbol |name|.
-    pub fn send_qsymbol(_name: &OsStr) {
-        unimplemented!()
+    pub fn send_qsymbol(&mut self, name: &OsStr) {
+        debug_assert_eq!(DREQ_QSYMBOL, self.req.type_);
+
+        self.write_hex_bytes_packet_with_prefix(b"qSymbol:", name.as_bytes());
+
+        self.consume_request();
     }
 
     /// The "all done" response to a qSymbol packet from gdb.
-    pub fn qsymbols_finished() {
-        unimplemented!()
+    pub fn qsymbols_finished(&mut self) {
+        debug_assert_eq!(DREQ_QSYMBOL, self.req.type_);
+
+        self.write_packet(b"OK");
+
+        self.consume_request();
     }
 
     /// Respond to a qGetTLSAddr packet.  If |ok| is true, then respond
     /// with |address|.  If |ok| is false, respond with an error.
-    pub fn reply_tls_addr(_ok: bool, _addr: RemotePtr<Void>) {
-        unimplemented!()
+    pub fn reply_tls_addr(&mut self, ok: bool, addr: RemotePtr<Void>) {
+        debug_assert_eq!(DREQ_TLS, self.req.type_);
+
+        if ok {
+            let buf = format!("{:x}", addr.as_usize());
+            self.write_packet(buf.as_bytes());
+        } else {
+            self.write_packet(b"E01");
+        }
+
+        self.consume_request();
     }
 
     /// Respond to a vFile:setfs
-    pub fn reply_setfs(_err: i32) {
-        unimplemented!()
+    pub fn reply_setfs(&mut self, err: i32) {
+        debug_assert_eq!(DREQ_FILE_SETFS, self.req.type_);
+
+        if err != 0 {
+            self.send_file_error_reply(err);
+        } else {
+            self.write_packet(b"F0");
+        }
+
+        self.consume_request();
     }
 
     /// Respond to a vFile:open
-    pub fn reply_open(_fd: i32, _err: i32) {
-        unimplemented!()
+    pub fn reply_open(&mut self, fd: i32, err: i32) {
+        debug_assert_eq!(DREQ_FILE_OPEN, self.req.type_);
+
+        if err != 0 {
+            self.send_file_error_reply(err);
+        } else {
+            let buf = format!("F{:x}", fd);
+            self.write_packet(buf.as_bytes());
+        }
+
+        self.consume_request();
     }
 
     /// Respond to a vFile:pread
-    pub fn reply_pread(_bytes: &[u8], _err: i32) {
-        unimplemented!()
+    pub fn reply_pread(&mut self, bytes: &[u8], err: i32) {
+        debug_assert_eq!(DREQ_FILE_PREAD, self.req.type_);
+
+        if err != 0 {
+            self.send_file_error_reply(err);
+        } else {
+            let buf = format!("F{:x};", bytes.len());
+            self.write_binary_packet(buf.as_bytes(), bytes);
+        }
+
+        self.consume_request();
     }
 
     /// Respond to a vFile:close
-    pub fn reply_close(_err: i32) {
-        unimplemented!()
+    pub fn reply_close(&mut self, err: i32) {
+        debug_assert_eq!(DREQ_FILE_CLOSE, self.req.type_);
+
+        if err != 0 {
+            self.send_file_error_reply(err);
+        } else {
+            self.write_packet(b"F0");
+        }
+
+        self.consume_request();
     }
 
     /// Create a checkpoint of the given Session with the given id. Delete the
@@ -394,27 +992,40 @@ impl GdbConnection {
     ///
     /// DIFF NOTE: The checkpoint id is signed in rr
     /// DIFF NOTE: In rr we pass in a ReplaySession shared pointer
-    fn created_checkpoint(_checkpoint: SessionSharedPtr, _checkpoint_id: u32) {
-        unimplemented!()
+    pub fn created_checkpoint(&mut self, checkpoint: SessionSharedPtr, checkpoint_id: u32) {
+        debug_assert!(checkpoint.as_replay().is_some());
+        self.checkpoints.insert(checkpoint_id, checkpoint);
     }
 
     /// Delete the checkpoint with the given id. Silently fail if the checkpoint
     /// does not exist.
-
+    ///
     /// DIFF NOTE: The checkpoint id is signed in rr
-    pub fn delete_checkpoint(_checkpoint_id: u32) {
-        unimplemented!()
+    pub fn delete_checkpoint(&mut self, checkpoint_id: u32) {
+        self.checkpoints.remove(&checkpoint_id);
     }
 
-    /// Get the checkpoint with the given id. Return null if not found.
-    pub fn get_checkpoint(_checkpoint_id: u32) -> SessionSharedPtr {
-        unimplemented!()
+    /// Get the checkpoint with the given id. Return None if not found.
+    pub fn get_checkpoint(&self, checkpoint_id: u32) -> Option<SessionSharedPtr> {
+        self.checkpoints.get(&checkpoint_id).cloned()
+    }
+
+    /// The ids of all the checkpoints, in increasing order.
+    pub fn checkpoint_ids(&self) -> Vec<u32> {
+        let mut ids: Vec<u32> = self.checkpoints.keys().copied().collect();
+        ids.sort();
+        ids
     }
 
     /// Return true if there's a new packet to be read/process (whether
     /// incomplete or not), and false if there isn't one.
-    pub fn sniff_packet() -> bool {
-        unimplemented!()
+    pub fn sniff_packet(&mut self) -> bool {
+        if self.skip_to_packet_start() {
+            // We've already seen a (possibly partial) packet.
+            return true;
+        }
+        parser_assert(self.inbuf.is_empty());
+        poll_incoming(&self.sock_fd, 0 /*don't wait*/)
     }
 
     pub fn features(&self) -> GdbConnectionFeatures {
@@ -429,70 +1040,187 @@ impl GdbConnection {
         self.cpu_features_
     }
 
-    pub fn new(_tgid: pid_t, _features: GdbConnectionFeatures) -> GdbConnection {
-        unimplemented!()
+    /// Signals the debugger has asked us (via QPassSignals) to deliver to the
+    /// tracee without reporting a stop.
+    pub fn is_pass_signal(&self, sig: Sig) -> bool {
+        self.pass_signals.contains(&to_gdb_signum(sig.as_raw()))
+    }
+
+    pub fn new(tgid: pid_t, features: GdbConnectionFeatures) -> GdbConnection {
+        GdbConnection {
+            req: GdbRequest::default(),
+            resume_thread: GdbThreadId::ANY,
+            query_thread: GdbThreadId::ANY,
+            tgid,
+            cpu_features_: 0,
+            no_ack: false,
+            sock_fd: ScopedFd::new(),
+            inbuf: Vec::new(),
+            packetend: 0,
+            outbuf: Vec::new(),
+            features_: features,
+            connection_alive_: true,
+            multiprocess_supported_: false,
+            pass_signals: HashSet::new(),
+            checkpoints: HashMap::new(),
+        }
     }
 
     /// Wait for a debugger client to connect to |dbg|'s socket.  Blocks
     /// indefinitely.
-    pub fn await_debugger(_listen_fd: &ScopedFd) {
-        unimplemented!()
+    pub fn await_debugger(&mut self, listen_fd: &ScopedFd) {
+        // We might restart this debugging session, so don't set the
+        // socket fd CLOEXEC.
+        match accept(listen_fd.as_raw()) {
+            Ok(fd) => self.sock_fd = ScopedFd::from_raw(fd),
+            Err(e) => fatal!("Failed to accept debugger connection: {:?}", e),
+        }
     }
 
     ///  Returns false if the connection has been closed
-    pub fn is_connection_alive() -> bool {
-        unimplemented!()
+    pub fn is_connection_alive(&self) -> bool {
+        self.connection_alive_
     }
 
     /// read() incoming data exactly one time, successfully.  May block.
-    fn read_data_once() {
-        unimplemented!()
+    fn read_data_once(&mut self) {
+        // Wait until there's data, instead of busy-looping on
+        // EAGAIN.
+        poll_incoming(&self.sock_fd, -1 /* wait forever */);
+        let mut buf = [0u8; 4096];
+        match read(self.sock_fd.as_raw(), &mut buf) {
+            Ok(nread) if nread > 0 => self.inbuf.extend_from_slice(&buf[0..nread]),
+            _ => {
+                log!(
+                    LogInfo,
+                    "Could not read data from gdb socket, marking connection as closed"
+                );
+                self.connection_alive_ = false;
+            }
+        }
     }
 
     /// Send all pending output to gdb.  May block.
-    fn write_flush() {
-        unimplemented!()
+    fn write_flush(&mut self) {
+        let mut write_index: usize = 0;
+
+        log!(
+            LogDebug,
+            "write_flush: '{}'",
+            String::from_utf8_lossy(&self.outbuf)
+        );
+
+        while write_index < self.outbuf.len() {
+            poll_outgoing(&self.sock_fd, -1 /*wait forever*/);
+            match write(self.sock_fd.as_raw(), &self.outbuf[write_index..]) {
+                Ok(nwritten) => write_index += nwritten,
+                Err(_) => {
+                    log!(
+                        LogInfo,
+                        "Could not write data to gdb socket, marking connection as closed"
+                    );
+                    self.connection_alive_ = false;
+                    self.outbuf.clear();
+                    return;
+                }
+            }
+        }
+        self.outbuf.clear();
     }
 
-    fn write_data_raw(_data: &[u8]) {
-        unimplemented!()
+    fn write_data_raw(&mut self, data: &[u8]) {
+        self.outbuf.extend_from_slice(data);
     }
 
     /// @TODO: Correct size chosen?
-    fn write_hex(_hex: usize) {
-        unimplemented!()
+    fn write_hex(&mut self, hex: usize) {
+        let buf = format!("{:02x}", hex);
+        self.write_data_raw(buf.as_bytes());
     }
 
-    fn write_packet_bytes(_data: &[u8]) {
-        unimplemented!()
+    fn write_packet_bytes(&mut self, data: &[u8]) {
+        let data = encode_run_length(data);
+        let data = data.as_slice();
+        let checksum = data.iter().fold(0u8, |acc, &b| acc.wrapping_add(b));
+        self.write_data_raw(b"$");
+        self.write_data_raw(data);
+        self.write_data_raw(b"#");
+        self.write_hex(checksum as usize);
     }
 
-    fn write_packet(_data: &[u8]) {
-        unimplemented!()
+    fn write_packet(&mut self, data: &[u8]) {
+        self.write_packet_bytes(data)
     }
 
     /// DIFF NOTE: num_bytes is a ssize_t in rr
-    fn write_binary_packet(_pfx: &[u8], _data: &[u8]) {
-        unimplemented!()
-    }
+    fn write_binary_packet(&mut self, pfx: &[u8], data: &[u8]) {
+        let buf = escape_binary_data(pfx, data);
+        log!(
+            LogDebug,
+            " ***** NOTE: writing binary data, upcoming debug output may be truncated"
+        );
+        self.write_packet_bytes(&buf);
+    }
+
+    fn write_hex_bytes_packet_with_prefix(&mut self, prefix: &[u8], data: &[u8]) {
+        if prefix.is_empty() && data.is_empty() {
+            self.write_packet(b"");
+            return;
+        }
 
-    fn write_hex_bytes_packet_with_prefix(_prefix: &[u8], _data: &[u8]) {
-        unimplemented!()
+        let mut buf = Vec::<u8>::with_capacity(prefix.len() + 2 * data.len());
+        buf.extend_from_slice(prefix);
+        for b in data {
+            buf.extend_from_slice(format!("{:02x}", b).as_bytes());
+        }
+        self.write_packet(&buf);
     }
 
-    fn write_hex_bytes_packet(_data: &[u8]) {
-        unimplemented!()
+    fn write_hex_bytes_packet(&mut self, data: &[u8]) {
+        self.write_hex_bytes_packet_with_prefix(b"", data)
     }
 
-    fn write_xfer_response(_data: &[u8], _offset: u64, _len: u64) {
-        unimplemented!()
+    fn write_xfer_response(&mut self, data: &[u8], offset: u64, len: u64) {
+        let size = data.len() as u64;
+        if offset > size {
+            self.write_packet(b"E01");
+            return;
+        }
+        if offset == size {
+            self.write_packet(b"l");
+            return;
+        }
+        if offset + len < size {
+            self.write_binary_packet(b"m", &data[offset as usize..(offset + len) as usize]);
+            return;
+        }
+        self.write_binary_packet(b"l", &data[offset as usize..]);
     }
 
     /// Consume bytes in the input buffer until start-of-packet ('$') or
     /// the interrupt character is seen.  Does not block.  Return true if
     /// seen, false if not.
-    fn skip_to_packet_start() -> bool {
-        unimplemented!()
+    fn skip_to_packet_start(&mut self) -> bool {
+        match self
+            .inbuf
+            .iter()
+            .position(|&c| c == b'$' || c == INTERRUPT_CHAR)
+        {
+            None => {
+                // Discard all read bytes, which we don't care
+                // about.
+                self.inbuf.clear();
+                false
+            }
+            Some(end) => {
+                // Discard bytes up to start-of-packet.
+                self.inbuf.drain(0..end);
+
+                parser_assert(1 <= self.inbuf.len());
+                parser_assert(b'$' == self.inbuf[0] || INTERRUPT_CHAR == self.inbuf[0]);
+                true
+            }
+        }
     }
 
     /// Block until the sequence of bytes
@@ -501,55 +1229,1436 @@ impl GdbConnection {
     ///
     /// has been read from the client fd.  This is one (or more) gdb
     /// packet(s).
-    fn read_packet() {
-        unimplemented!()
+    fn read_packet(&mut self) {
+        // Read and discard bytes until we see the start of a
+        // packet.
+        //
+        // NB: we're ignoring "+/-" responses from gdb.  There doesn't
+        // seem to be any sane reason why we would send a damaged
+        // packet to gdb over TCP, then see a "-" reply from gdb and
+        // somehow magically fix our bug that led to the malformed
+        // packet in the first place.
+        while !self.skip_to_packet_start() && self.connection_alive_ {
+            self.read_data_once();
+        }
+
+        if !self.connection_alive_ {
+            return;
+        }
+
+        if self.inbuf[0] == INTERRUPT_CHAR {
+            // Interrupts are kind of an ugly duckling in the gdb
+            // protocol ...
+            self.packetend = 1;
+            return;
+        }
+
+        // Read until we see end-of-packet.
+        let mut checkedlen: usize = 0;
+        loop {
+            if let Some(p) = self.inbuf[checkedlen..].iter().position(|&c| c == b'#') {
+                self.packetend = checkedlen + p;
+                break;
+            }
+            checkedlen = self.inbuf.len();
+            self.read_data_once();
+            if !self.connection_alive_ {
+                return;
+            }
+        }
+
+        // NB: we're ignoring the gdb packet checksums here too.  If
+        // gdb is corrupted enough to garble a checksum over TCP, it's
+        // not really clear why asking for the packet again might make
+        // the bug go away.
+        parser_assert(b'$' == self.inbuf[0] && self.packetend < self.inbuf.len());
+
+        // Acknowledge receipt of the packet.
+        if !self.no_ack {
+            self.write_data_raw(b"+");
+            self.write_flush();
+        }
     }
 
     /// Return true if we need to do something in a debugger request,
     /// false if we already handled the packet internally.
-    fn xfer(_name: &OsStr, _args: &[&OsStr]) -> bool {
-        unimplemented!()
+    fn xfer(&mut self, name: &[u8], args: &[u8]) -> bool {
+        let (mode, args) = split_once(args, b':');
+        parser_assert(args.is_some());
+        let args = args.unwrap();
+
+        if mode != b"read" && mode != b"write" {
+            self.write_packet(b"");
+            return false;
+        }
+
+        let (annex, args) = split_once(args, b':');
+        parser_assert(args.is_some());
+        let args = args.unwrap();
+
+        let (offset, mut args) = parse_hex(args);
+        let mut len: u64 = 0;
+        if mode == b"read" {
+            parser_assert(args.first() == Some(&b','));
+            let (l, rest) = parse_hex(&args[1..]);
+            len = l;
+            parser_assert(rest.is_empty());
+        } else {
+            parser_assert(args.first() == Some(&b':'));
+            args = &args[1..];
+        }
+
+        log!(
+            LogDebug,
+            "gdb asks us to transfer {} mode={}, annex={}, offset={} len={}",
+            String::from_utf8_lossy(name),
+            String::from_utf8_lossy(mode),
+            String::from_utf8_lossy(annex),
+            offset,
+            len
+        );
+
+        if name == b"auxv" {
+            if !annex.is_empty() {
+                self.write_packet(b"E00");
+                return false;
+            }
+            if mode != b"read" {
+                self.write_packet(b"");
+                return false;
+            }
+
+            self.req = GdbRequest::new(DREQ_GET_AUXV, GdbRequestValue::GdbRequestNone);
+            self.req.target = self.query_thread;
+            // XXX handle offset/len here!
+            return true;
+        }
+
+        if name == b"exec-file" {
+            if mode != b"read" {
+                self.write_packet(b"");
+                return false;
+            }
+
+            self.req = GdbRequest::new(DREQ_GET_EXEC_FILE, GdbRequestValue::GdbRequestNone);
+            let (pid, _) = parse_hex(annex);
+            self.req.target = GdbThreadId::new(pid as pid_t, pid as pid_t);
+            // XXX handle offset/len here!
+            return true;
+        }
+
+        if name == b"siginfo" {
+            if !annex.is_empty() {
+                self.write_packet(b"E00");
+                return false;
+            }
+            if mode == b"read" {
+                self.req = GdbRequest::new(
+                    DREQ_READ_SIGINFO,
+                    GdbRequestValue::GdbRequestMem(Mem {
+                        addr: offset as usize,
+                        len: len as usize,
+                        data: Vec::new(),
+                    }),
+                );
+                self.req.target = self.query_thread;
+                return true;
+            }
+
+            self.req = GdbRequest::new(DREQ_WRITE_SIGINFO, GdbRequestValue::GdbRequestNone);
+            self.req.target = self.query_thread;
+            return true;
+        }
+
+        if name == b"features" {
+            if mode != b"read" {
+                self.write_packet(b"");
+                return false;
+            }
+
+            let desc = if !annex.is_empty() && annex != b"target.xml" {
+                read_target_desc(OsStr::from_bytes(annex))
+            } else {
+                read_target_desc(OsStr::new(target_description_name(self.cpu_features_)))
+            };
+            self.write_xfer_response(&desc, offset, len);
+            return false;
+        }
+
+        self.write_packet(b"");
+        false
     }
 
     /// Return true if we need to do something in a debugger request,
     /// false if we already handled the packet internally.
-    fn query(_payload: &[u8]) -> bool {
-        unimplemented!()
+    fn query(&mut self, payload: &[u8]) -> bool {
+        let (name, args) = split_once(payload, b':');
+
+        if name.starts_with(b"RRCmd") {
+            log!(
+                LogDebug,
+                "gdb requests rd cmd: {}",
+                String::from_utf8_lossy(name)
+            );
+            self.req = GdbRequest::new(
+                DREQ_RR_CMD,
+                GdbRequestValue::GdbRequestText(OsString::from_vec(args.unwrap_or(b"").to_vec())),
+            );
+            return true;
+        }
+        if name == b"C" {
+            log!(LogDebug, "gdb requests current thread ID");
+            self.req = GdbRequest::new(DREQ_GET_CURRENT_THREAD, GdbRequestValue::GdbRequestNone);
+            return true;
+        }
+        if name == b"Attached" {
+            log!(LogDebug, "gdb asks if this is a new or existing process");
+            // Tell gdb this is an existing process; it might be
+            // (see emergency_debug()).
+            self.write_packet(b"1");
+            return false;
+        }
+        if name == b"fThreadInfo" {
+            log!(LogDebug, "gdb asks for thread list");
+            self.req = GdbRequest::new(DREQ_GET_THREAD_LIST, GdbRequestValue::GdbRequestNone);
+            return true;
+        }
+        if name == b"sThreadInfo" {
+            // "end of list"
+            self.write_packet(b"l");
+            return false;
+        }
+        if name == b"GetTLSAddr" {
+            log!(LogDebug, "gdb asks for TLS addr");
+            parser_assert(args.is_some());
+            let (target, args) = parse_threadid(args.unwrap());
+            parser_assert(args.first() == Some(&b','));
+            let (offset, args) = parse_hex(&args[1..]);
+            parser_assert(args.first() == Some(&b','));
+            let (load_module, args) = parse_hex(&args[1..]);
+            parser_assert(args.is_empty());
+            self.req = GdbRequest::new(
+                DREQ_TLS,
+                GdbRequestValue::GdbRequestTls(Tls {
+                    offset: offset as usize,
+                    load_module: RemotePtr::new_from_val(load_module as usize),
+                }),
+            );
+            self.req.target = target;
+            return true;
+        }
+        if name == b"Offsets" {
+            log!(LogDebug, "gdb asks for section offsets");
+            self.req = GdbRequest::new(DREQ_GET_OFFSETS, GdbRequestValue::GdbRequestNone);
+            self.req.target = self.query_thread;
+            return true;
+        }
+        if name.first() == Some(&b'P') {
+            // The docs say not to use this packet ...
+            self.write_packet(b"");
+            return false;
+        }
+        if name == b"Supported" {
+            let args = args.unwrap_or(b"");
+            // TODO process these
+            log!(LogDebug, "gdb supports {}", String::from_utf8_lossy(args));
+
+            self.multiprocess_supported_ = contains_subslice(args, b"multiprocess+");
+
+            let mut supported = String::from(
+                // Encourage gdb to use very large packets since we support any packet size
+                "PacketSize=1048576\
+                 ;QStartNoAckMode+\
+                 ;qXfer:features:read+\
+                 ;qXfer:auxv:read+\
+                 ;qXfer:exec-file:read+\
+                 ;qXfer:siginfo:read+\
+                 ;qXfer:siginfo:write+\
+                 ;multiprocess+\
+                 ;ConditionalBreakpoints+\
+                 ;QPassSignals+",
+            );
+            if self.features().reverse_execution {
+                supported.push_str(";ReverseContinue+;ReverseStep+");
+            }
+            self.write_packet(supported.as_bytes());
+            return false;
+        }
+        if name == b"Symbol" {
+            log!(
+                LogDebug,
+                "gdb is ready for symbol lookups, but we don't support them"
+            );
+            self.write_packet(b"");
+            return false;
+        }
+        if name.starts_with(b"ThreadExtraInfo") {
+            // ThreadExtraInfo is a special snowflake that
+            // delimits its args with ','.
+            parser_assert(args.is_none());
+            let (_, args) = split_once(payload, b',' /*sic*/);
+            parser_assert(args.is_some());
+            let (target, args) = parse_threadid(args.unwrap());
+            parser_assert(args.is_empty());
+
+            self.req = GdbRequest::new(DREQ_GET_THREAD_EXTRA_INFO, GdbRequestValue::GdbRequestNone);
+            self.req.target = target;
+            return true;
+        }
+        if name == b"TStatus" {
+            log!(LogDebug, "gdb asks for trace status");
+            // XXX from the docs, it appears that we should reply
+            // with "T0" here.  But if we do, gdb keeps bothering
+            // us with trace queries.  So pretend we don't know
+            // what it's talking about.
+            self.write_packet(b"");
+            return false;
+        }
+        if name == b"Xfer" {
+            parser_assert(args.is_some());
+            let (name, args) = split_once(args.unwrap(), b':');
+            parser_assert(args.is_some());
+
+            return self.xfer(name, args.unwrap());
+        }
+        if name == b"Search" {
+            parser_assert(args.is_some());
+            let (name, args) = split_once(args.unwrap(), b':');
+            if name == b"memory" && args.is_some() {
+                let (addr, args) = parse_hex(args.unwrap());
+                parser_assert(args.first() == Some(&b';'));
+                let (len, args) = parse_hex(&args[1..]);
+                parser_assert(args.first() == Some(&b';'));
+                let data = read_binary_data(&args[1..]);
+
+                self.req = GdbRequest::new(
+                    DREQ_SEARCH_MEM,
+                    GdbRequestValue::GdbRequestMem(Mem {
+                        addr: addr as usize,
+                        len: len as usize,
+                        data,
+                    }),
+                );
+                self.req.target = self.query_thread;
+                log!(
+                    LogDebug,
+                    "gdb searching memory (addr={:#x}, len={})",
+                    addr,
+                    len
+                );
+                return true;
+            }
+            self.write_packet(b"");
+            return false;
+        }
+
+        self.unhandled_req(&format!(
+            "Unhandled gdb query: q{}",
+            String::from_utf8_lossy(name)
+        ));
+        false
     }
 
     /// Return true if we need to do something in a debugger request,
     /// false if we already handled the packet internally.
-    fn set_var(_payload: &[u8]) -> bool {
-        unimplemented!()
+    fn set_var(&mut self, payload: &[u8]) -> bool {
+        let (name, args) = split_once(payload, b':');
+
+        if name == b"StartNoAckMode" {
+            self.write_packet(b"OK");
+            self.no_ack = true;
+            return false;
+        }
+        if name == b"PassSignals" {
+            self.pass_signals.clear();
+            for sig in args.unwrap_or(b"").split(|&c| c == b';') {
+                if sig.is_empty() {
+                    continue;
+                }
+                let (signum, rest) = parse_hex(sig);
+                parser_assert(rest.is_empty());
+                log!(LogDebug, "registered {} by QPassSignal", signum);
+                self.pass_signals.insert(signum as i32);
+            }
+            self.write_packet(b"OK");
+            return false;
+        }
+
+        self.unhandled_req(&format!(
+            "Unhandled gdb set: Q{}",
+            String::from_utf8_lossy(name)
+        ));
+        false
     }
 
     /// Return true if we need to do something in a debugger request,
     /// false if we already handled the packet internally.
-    fn process_vpacket(_payload: &[u8]) -> bool {
-        unimplemented!()
+    fn process_vpacket(&mut self, payload: &[u8]) -> bool {
+        let (name, args) = split_once(payload, b';');
+
+        if name == b"Cont" {
+            let mut actions: Vec<GdbContAction> = Vec::new();
+            let mut maybe_default_action: Option<GdbContAction> = None;
+
+            for action_str in args.unwrap_or(b"").split(|&c| c == b';') {
+                if action_str.is_empty() {
+                    continue;
+                }
+                let (cmd, maybe_target) = split_once(action_str, b':');
+                let target = match maybe_target {
+                    Some(target_str) => {
+                        let (target, rest) = parse_threadid(target_str);
+                        parser_assert(rest.is_empty());
+                        Some(target)
+                    }
+                    None => None,
+                };
+
+                let (action, signal_to_deliver) = match cmd[0] {
+                    b'C' | b'S' => {
+                        let (sig, rest) = parse_hex(&cmd[1..]);
+                        if !rest.is_empty() {
+                            self.unhandled_req(&format!(
+                                "Unhandled vCont command parameters {}",
+                                String::from_utf8_lossy(cmd)
+                            ));
+                            return false;
+                        }
+                        let action = if cmd[0] == b'C' {
+                            GdbActionType::ActionContinue
+                        } else {
+                            GdbActionType::ActionStep
+                        };
+                        (action, Sig::try_from(sig as i32).ok())
+                    }
+                    b'c' => (GdbActionType::ActionContinue, None),
+                    b's' => (GdbActionType::ActionStep, None),
+                    _ => {
+                        self.unhandled_req(&format!(
+                            "Unhandled vCont command {}",
+                            String::from_utf8_lossy(action_str)
+                        ));
+                        return false;
+                    }
+                };
+
+                match target {
+                    Some(target) => {
+                        actions.push(GdbContAction::new(action, target, signal_to_deliver))
+                    }
+                    None => {
+                        if maybe_default_action.is_some() {
+                            self.unhandled_req(
+                                "Unhandled vCont command with multiple default actions",
+                            );
+                            return false;
+                        }
+                        maybe_default_action = Some(GdbContAction::new(
+                            action,
+                            GdbThreadId::ALL,
+                            signal_to_deliver,
+                        ));
+                    }
+                }
+            }
+
+            if let Some(default_action) = maybe_default_action {
+                actions.push(default_action);
+            }
+
+            self.req = GdbRequest::new(
+                DREQ_CONT,
+                GdbRequestValue::GdbRequestCont(Cont {
+                    run_direction: RunDirection::RunForward,
+                    actions,
+                }),
+            );
+            return true;
+        }
+
+        if name == b"Cont?" {
+            log!(LogDebug, "gdb queries which continue commands we support");
+            self.write_packet(b"vCont;c;C;s;S;");
+            return false;
+        }
+
+        if name == b"Kill" {
+            // We can't kill tracees or replay can diverge.  We
+            // assume that this kill request is being made because
+            // a "vRun" restart is coming right up.  We know how
+            // to implement vRun, so we'll ignore this one.
+            log!(LogDebug, "gdb asks us to kill tracee(s); ignoring");
+            self.write_packet(b"OK");
+            return false;
+        }
+
+        if name == b"Run" {
+            let args = args.unwrap_or(b"");
+            let (filename, args) = split_once(args, b';');
+            if !filename.is_empty() {
+                fatal!(
+                    "gdb wants us to run the exe image `{}', but we don't support that.",
+                    String::from_utf8_lossy(filename)
+                );
+            }
+            let mut restart = Restart {
+                param: -1,
+                param_str: OsString::new(),
+                type_: GdbRestartType::RestartFromPrevious,
+            };
+            if let Some(args) = args {
+                let (arg1, rest) = split_once(args, b';');
+                if let Some(rest) = rest {
+                    log!(
+                        LogDebug,
+                        "Ignoring extra parameters {}",
+                        String::from_utf8_lossy(rest)
+                    );
+                }
+                let event_str = decode_ascii_encoded_hex_str(arg1);
+                let maybe_param = if event_str.first() == Some(&b'c') {
+                    restart.type_ = GdbRestartType::RestartFromCheckpoint;
+                    restart.param_str = OsString::from_vec(event_str[1..].to_vec());
+                    parse_c_integer(&event_str[1..])
+                } else {
+                    restart.type_ = GdbRestartType::RestartFromEvent;
+                    parse_c_integer(&event_str)
+                };
+                match maybe_param {
+                    Some(param) => {
+                        restart.param = param as i32;
+                        log!(
+                            LogDebug,
+                            "next replayer restarting from {} {}",
+                            if restart.type_ == GdbRestartType::RestartFromCheckpoint {
+                                "checkpoint"
+                            } else {
+                                "event"
+                            },
+                            param
+                        );
+                    }
+                    None => {
+                        log!(
+                            LogDebug,
+                            "Couldn't parse event string `{}'; restarting from previous",
+                            String::from_utf8_lossy(&event_str)
+                        );
+                        restart.type_ = GdbRestartType::RestartFromPrevious;
+                        restart.param = -1;
+                    }
+                }
+            }
+            self.req = GdbRequest::new(DREQ_RESTART, GdbRequestValue::GdbRequestRestart(restart));
+            return true;
+        }
+
+        if name.starts_with(b"File:") {
+            let operation = &payload[5..];
+            if operation.starts_with(b"open:") {
+                let (file_name, rest) = split_once(&operation[5..], b',');
+                parser_assert(rest.is_some());
+                let (flags, rest) = parse_hex_signed(rest.unwrap());
+                parser_assert(rest.first() == Some(&b','));
+                let (mode, rest) = parse_hex_signed(&rest[1..]);
+                parser_assert(rest.is_empty());
+                parser_assert((mode & !0o777) == 0);
+                self.req = GdbRequest::new(
+                    DREQ_FILE_OPEN,
+                    GdbRequestValue::GdbRequestFileOpen(FileOpen {
+                        file_name: OsString::from_vec(decode_ascii_encoded_hex_str(file_name)),
+                        flags: gdb_open_flags_to_system_flags(flags),
+                        mode: mode as i32,
+                    }),
+                );
+                return true;
+            } else if operation.starts_with(b"close:") {
+                let (fd, rest) = parse_hex_signed(&operation[6..]);
+                parser_assert(rest.is_empty());
+                self.req = GdbRequest::new(
+                    DREQ_FILE_CLOSE,
+                    GdbRequestValue::GdbRequestFileClose(FileClose { fd: fd as i32 }),
+                );
+                return true;
+            } else if operation.starts_with(b"pread:") {
+                let (fd, rest) = parse_hex_signed(&operation[6..]);
+                parser_assert(rest.first() == Some(&b','));
+                let (size, rest) = parse_hex_signed(&rest[1..]);
+                parser_assert(rest.first() == Some(&b','));
+                parser_assert(size >= 0);
+                let (offset, rest) = parse_hex_signed(&rest[1..]);
+                parser_assert(rest.is_empty());
+                parser_assert(offset >= 0);
+                self.req = GdbRequest::new(
+                    DREQ_FILE_PREAD,
+                    GdbRequestValue::GdbRequestFilePread(FilePread {
+                        fd: fd as i32,
+                        size: size as usize,
+                        offset: offset as u64,
+                    }),
+                );
+                return true;
+            } else if operation.starts_with(b"setfs:") {
+                let (pid, rest) = parse_hex_signed(&operation[6..]);
+                parser_assert(rest.is_empty());
+                self.req = GdbRequest::new(
+                    DREQ_FILE_SETFS,
+                    GdbRequestValue::GdbRequestFileSetfs(FileSetfs { pid: pid as pid_t }),
+                );
+                return true;
+            } else {
+                self.write_packet(b"");
+                return false;
+            }
+        }
+
+        self.unhandled_req(&format!(
+            "Unhandled gdb vpacket: v{}",
+            String::from_utf8_lossy(name)
+        ));
+        false
     }
 
     /// Return true if we need to do something in a debugger request,
     /// false if we already handled the packet internally.
-    fn process_bpacket(_payload: &[u8]) -> bool {
-        unimplemented!()
+    fn process_bpacket(&mut self, payload: &[u8]) -> bool {
+        let action = match payload {
+            b"c" => GdbActionType::ActionContinue,
+            b"s" => GdbActionType::ActionStep,
+            _ => {
+                self.unhandled_req(&format!(
+                    "Unhandled gdb bpacket: b{}",
+                    String::from_utf8_lossy(payload)
+                ));
+                return false;
+            }
+        };
+        self.req = GdbRequest::new(
+            DREQ_CONT,
+            GdbRequestValue::GdbRequestCont(Cont {
+                run_direction: RunDirection::RunBackward,
+                actions: vec![GdbContAction::new(action, self.resume_thread, None)],
+            }),
+        );
+        true
     }
 
     /// Return true if we need to do something in a debugger request,
     /// false if we already handled the packet internally.
-    fn process_packet() -> bool {
-        unimplemented!()
+    fn process_packet(&mut self) -> bool {
+        parser_assert(
+            INTERRUPT_CHAR == self.inbuf[0]
+                || (b'$' == self.inbuf[0]
+                    && self.inbuf.iter().position(|&c| c == b'#') == Some(self.packetend)),
+        );
+
+        if INTERRUPT_CHAR == self.inbuf[0] {
+            log!(LogDebug, "gdb requests interrupt");
+            self.req = GdbRequest::new(DREQ_INTERRUPT, GdbRequestValue::GdbRequestNone);
+            self.inbuf.remove(0);
+            return true;
+        }
+
+        let packet = decode_run_length(&self.inbuf[1..self.packetend]);
+        parser_assert(!packet.is_empty());
+        let request = packet[0];
+        let payload: Vec<u8> = packet[1..].to_vec();
+
+        log!(
+            LogDebug,
+            "raw request {}{}",
+            request as char,
+            String::from_utf8_lossy(&payload)
+        );
+
+        let ret = match request {
+            b'b' => self.process_bpacket(&payload),
+            b'c' => {
+                log!(LogDebug, "gdb is asking to continue");
+                self.req = GdbRequest::new(
+                    DREQ_CONT,
+                    GdbRequestValue::GdbRequestCont(Cont {
+                        run_direction: RunDirection::RunForward,
+                        actions: vec![GdbContAction::new(
+                            GdbActionType::ActionContinue,
+                            GdbThreadId::ANY,
+                            None,
+                        )],
+                    }),
+                );
+                true
+            }
+            b'D' => {
+                log!(LogDebug, "gdb is detaching from us");
+                self.req = GdbRequest::new(DREQ_DETACH, GdbRequestValue::GdbRequestNone);
+                true
+            }
+            b'g' => {
+                self.req = GdbRequest::new(DREQ_GET_REGS, GdbRequestValue::GdbRequestNone);
+                self.req.target = self.query_thread;
+                log!(LogDebug, "gdb requests registers");
+                true
+            }
+            b'G' => {
+                // XXX we can't let gdb spray registers in general,
+                // because it may cause replay to diverge.  But some
+                // writes may be OK.  Let's see how far we can get
+                // with ignoring these requests.
+                self.write_packet(b"");
+                false
+            }
+            b'H' => {
+                parser_assert(!payload.is_empty());
+                let type_ = if payload.first() == Some(&b'c') {
+                    DREQ_SET_CONTINUE_THREAD
+                } else {
+                    DREQ_SET_QUERY_THREAD
+                };
+                let (target, rest) = parse_threadid(&payload[1..]);
+                parser_assert(rest.is_empty());
+                self.req = GdbRequest::new(type_, GdbRequestValue::GdbRequestNone);
+                self.req.target = target;
+
+                log!(LogDebug, "gdb selecting {}", self.req.target);
+                true
+            }
+            b'k' => {
+                log!(LogInfo, "gdb requests kill, exiting");
+                self.write_packet(b"OK");
+                self.write_flush();
+                std::process::exit(0);
+            }
+            b'm' => {
+                let (addr, rest) = parse_hex(&payload);
+                parser_assert(!rest.is_empty());
+                let (len, rest) = parse_hex(&rest[1..]);
+                parser_assert(rest.is_empty());
+                self.req = GdbRequest::new(
+                    DREQ_GET_MEM,
+                    GdbRequestValue::GdbRequestMem(Mem {
+                        addr: addr as usize,
+                        len: len as usize,
+                        data: Vec::new(),
+                    }),
+                );
+                self.req.target = self.query_thread;
+
+                log!(
+                    LogDebug,
+                    "gdb requests memory (addr={:#x}, len={})",
+                    addr,
+                    len
+                );
+                true
+            }
+            b'M' => {
+                // We can't allow the debugger to write arbitrary data
+                // to memory, or the replay may diverge.
+                // TODO: parse this packet in case some oddball gdb
+                // decides to send it instead of 'X'
+                self.write_packet(b"");
+                false
+            }
+            b'p' => {
+                let (regno, rest) = parse_hex(&payload);
+                parser_assert(rest.is_empty());
+                match GdbRegister::try_from(regno as u32) {
+                    Ok(name) => {
+                        self.req = GdbRequest::new(
+                            DREQ_GET_REG,
+                            GdbRequestValue::GdbRequestRegisterValue(undefined_reg(name)),
+                        );
+                        self.req.target = self.query_thread;
+                        log!(LogDebug, "gdb requests register value ({})", name);
+                        true
+                    }
+                    Err(()) => {
+                        log!(LogWarn, "gdb requests unknown register {}", regno);
+                        self.write_packet(b"");
+                        false
+                    }
+                }
+            }
+            b'P' => {
+                let (regno, rest) = parse_hex(&payload);
+                parser_assert(rest.first() == Some(&b'='));
+                match GdbRegister::try_from(regno as u32) {
+                    Ok(name) => {
+                        let reg = read_reg_value(name, &rest[1..]);
+                        self.req = GdbRequest::new(
+                            DREQ_SET_REG,
+                            GdbRequestValue::GdbRequestRegisterValue(reg),
+                        );
+                        self.req.target = self.query_thread;
+                        true
+                    }
+                    Err(()) => {
+                        log!(LogWarn, "gdb tries to set unknown register {}", regno);
+                        self.write_packet(b"");
+                        false
+                    }
+                }
+            }
+            b'q' => self.query(&payload),
+            b'Q' => self.set_var(&payload),
+            b'T' => {
+                let (target, rest) = parse_threadid(&payload);
+                parser_assert(rest.is_empty());
+                self.req =
+                    GdbRequest::new(DREQ_GET_IS_THREAD_ALIVE, GdbRequestValue::GdbRequestNone);
+                self.req.target = target;
+                log!(LogDebug, "gdb wants to know if {} is alive", target);
+                true
+            }
+            b'v' => self.process_vpacket(&payload),
+            b'X' => {
+                let (addr, rest) = parse_hex(&payload);
+                parser_assert(rest.first() == Some(&b','));
+                let (len, rest) = parse_hex(&rest[1..]);
+                parser_assert(rest.first() == Some(&b':'));
+                let data = read_binary_data(&rest[1..]);
+                parser_assert(len as usize == data.len());
+
+                log!(
+                    LogDebug,
+                    "gdb setting memory (addr={:#x}, len={}, data={})",
+                    addr,
+                    len,
+                    to_hex_string(&data, 32)
+                );
+
+                self.req = GdbRequest::new(
+                    DREQ_SET_MEM,
+                    GdbRequestValue::GdbRequestMem(Mem {
+                        addr: addr as usize,
+                        len: len as usize,
+                        data,
+                    }),
+                );
+                self.req.target = self.query_thread;
+                true
+            }
+            b'z' | b'Z' => {
+                let (type_, rest) = parse_hex_signed(&payload);
+                parser_assert(rest.first() == Some(&b','));
+                if !(0 <= type_ && type_ <= 4) {
+                    log!(LogWarn, "Unknown watch type {}", type_);
+                    self.write_packet(b"");
+                    false
+                } else {
+                    let (addr, rest) = parse_hex(&rest[1..]);
+                    parser_assert(rest.first() == Some(&b','));
+                    let (kind, mut rest) = parse_hex(&rest[1..]);
+                    let mut conditions: Vec<Vec<u8>> = Vec::new();
+                    if rest.first() == Some(&b';') {
+                        rest = &rest[1..];
+                        while rest.first() == Some(&b'X') {
+                            let (len, r) = parse_hex(&rest[1..]);
+                            parser_assert(r.first() == Some(&b','));
+                            let r = &r[1..];
+                            parser_assert(r.len() >= 2 * len as usize);
+                            let bytes = decode_hex_bytes(&r[0..2 * len as usize]);
+                            conditions.push(bytes);
+                            rest = &r[2 * len as usize..];
+                        }
+                    }
+                    parser_assert(rest.is_empty());
+
+                    let base = if request == b'Z' {
+                        DREQ_SET_SW_BREAK
+                    } else {
+                        DREQ_REMOVE_SW_BREAK
+                    };
+                    self.req = GdbRequest::new(
+                        base + type_ as GdbRequestType,
+                        GdbRequestValue::GdbRequestWatch(Watch {
+                            addr: addr as usize,
+                            kind: kind as i32,
+                            conditions,
+                        }),
+                    );
+
+                    log!(
+                        LogDebug,
+                        "gdb requests {} breakpoint (addr={:#x}, len={})",
+                        if request == b'Z' { "set" } else { "remove" },
+                        addr,
+                        kind
+                    );
+                    true
+                }
+            }
+            b'!' => {
+                log!(LogDebug, "gdb requests extended mode");
+                self.write_packet(b"OK");
+                false
+            }
+            b'?' => {
+                log!(LogDebug, "gdb requests stop reason");
+                self.req = GdbRequest::new(DREQ_GET_STOP_REASON, GdbRequestValue::GdbRequestNone);
+                self.req.target = self.query_thread;
+                true
+            }
+            _ => {
+                self.unhandled_req(&format!("Unhandled gdb request '{}'", request as char));
+                false
+            }
+        };
+
+        // Erase the newly processed packet from the input buffer. The checksum
+        // after the '#' will be skipped later as we look for the next packet start.
+        self.inbuf.drain(0..self.packetend + 1);
+
+        // If we processed the request internally, consume it.
+        if !ret {
+            self.consume_request();
+        }
+        ret
+    }
+
+    /// Reply to a request we don't understand with an empty packet, which gdb
+    /// interprets as "unsupported".
+    fn unhandled_req(&mut self, msg: &str) {
+        self.write_packet(b"");
+        log!(LogInfo, "{}", msg);
+    }
+
+    fn consume_request(&mut self) {
+        self.req = GdbRequest::default();
+        self.write_flush();
+    }
+
+    fn send_stop_reply_packet(
+        &mut self,
+        thread: GdbThreadId,
+        sig: Option<Sig>,
+        watch_addr: Option<usize>,
+    ) {
+        let watch = match watch_addr {
+            Some(addr) => format!("watch:{:x};", addr),
+            None => String::new(),
+        };
+        let signum = to_gdb_signum(sig.map_or(0, |s| s.as_raw()));
+        let buf = if self.multiprocess_supported_ {
+            format!(
+                "T{:02x}thread:p{:02x}.{:02x};{}",
+                signum, thread.pid, thread.tid, watch
+            )
+        } else {
+            format!("T{:02x}thread:{:02x};{}", signum, thread.tid, watch)
+        };
+        self.write_packet(buf.as_bytes());
+    }
+
+    fn send_file_error_reply(&mut self, system_errno: i32) {
+        let gdb_err = match system_errno {
+            EPERM => 1,
+            ENOENT => 2,
+            EINTR => 4,
+            EBADF => 9,
+            EACCES => 13,
+            EFAULT => 14,
+            EBUSY => 16,
+            EEXIST => 17,
+            ENODEV => 19,
+            ENOTDIR => 20,
+            EISDIR => 21,
+            EINVAL => 22,
+            ENFILE => 23,
+            EMFILE => 24,
+            EFBIG => 27,
+            ENOSPC => 28,
+            ESPIPE => 29,
+            EROFS => 30,
+            ENAMETOOLONG => 91,
+            _ => 9999,
+        };
+        let buf = format!("F-01,{:x}", gdb_err);
+        self.write_packet(buf.as_bytes());
+    }
+}
+
+fn request_needs_immediate_response(req: &GdbRequest) -> bool {
+    match req.type_ {
+        DREQ_NONE | DREQ_CONT => false,
+        _ => true,
     }
+}
 
-    fn consume_request() {
-        unimplemented!()
+fn poll_socket(sock_fd: &ScopedFd, events: PollFlags, timeout_ms: i32) -> bool {
+    let mut pfd = [PollFd::new(sock_fd.as_raw(), events)];
+    match poll(&mut pfd, timeout_ms) {
+        Ok(ret) => ret > 0,
+        Err(Error::Sys(Errno::EINTR)) => false,
+        Err(_) => {
+            log!(LogInfo, "gdb socket has been closed");
+            false
+        }
     }
+}
 
-    fn send_stop_reply_packet(_thread: GdbThreadId, _sig: Sig, _watch_addr: Option<usize>) {
-        unimplemented!()
+fn poll_incoming(sock_fd: &ScopedFd, timeout_ms: i32) -> bool {
+    // TODO: |POLLERR
+    poll_socket(sock_fd, PollFlags::POLLIN, timeout_ms)
+}
+
+fn poll_outgoing(sock_fd: &ScopedFd, timeout_ms: i32) {
+    // TODO: |POLLERR
+    poll_socket(sock_fd, PollFlags::POLLOUT, timeout_ms);
+}
+
+fn parser_assert(cond: bool) {
+    if !cond {
+        log!(LogError, "Failed to parse gdb request");
+        debug_assert!(false);
+        std::process::exit(2);
+    }
+}
+
+/// Split `s` at the first occurrence of `delim`. The delimiter is not included
+/// in either part. If `delim` is not found, the whole slice is returned as the
+/// first element.
+fn split_once(s: &[u8], delim: u8) -> (&[u8], Option<&[u8]>) {
+    match s.iter().position(|&c| c == delim) {
+        Some(i) => (&s[0..i], Some(&s[i + 1..])),
+        None => (s, None),
+    }
+}
+
+fn contains_subslice(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+/// Like strtoul(s, &end, 16): parse as many hex digits as possible from the
+/// start of `s` and return the value and the unparsed remainder.
+fn parse_hex(s: &[u8]) -> (u64, &[u8]) {
+    let mut value: u64 = 0;
+    let mut i = 0;
+    while i < s.len() {
+        match (s[i] as char).to_digit(16) {
+            Some(d) => value = value.wrapping_mul(16).wrapping_add(d as u64),
+            None => break,
+        }
+        i += 1;
+    }
+    (value, &s[i..])
+}
+
+/// Like strtol(s, &end, 16): same as `parse_hex()` but accepts a leading '-'.
+fn parse_hex_signed(s: &[u8]) -> (i64, &[u8]) {
+    if s.first() == Some(&b'-') {
+        let (v, rest) = parse_hex(&s[1..]);
+        (-(v as i64), rest)
+    } else {
+        let (v, rest) = parse_hex(s);
+        (v as i64, rest)
+    }
+}
+
+/// Like strtoll(s, &end, 0) but only succeeds if the whole of `s` is consumed.
+fn parse_c_integer(s: &[u8]) -> Option<i64> {
+    let (negative, s) = match s.first() {
+        Some(b'-') => (true, &s[1..]),
+        Some(b'+') => (false, &s[1..]),
+        _ => (false, s),
+    };
+    let (radix, digits) = if s.starts_with(b"0x") || s.starts_with(b"0X") {
+        (16, &s[2..])
+    } else if s.len() > 1 && s[0] == b'0' {
+        (8, &s[1..])
+    } else {
+        (10, s)
+    };
+    if digits.is_empty() {
+        return None;
+    }
+    let v = i64::from_str_radix(std::str::from_utf8(digits).ok()?, radix).ok()?;
+    Some(if negative { -v } else { v })
+}
+
+fn decode_hex_bytes(encoded: &[u8]) -> Vec<u8> {
+    parser_assert(encoded.len() % 2 == 0);
+    encoded
+        .chunks(2)
+        .map(|pair| {
+            let (b, rest) = parse_hex(pair);
+            parser_assert(rest.is_empty());
+            b as u8
+        })
+        .collect()
+}
+
+fn decode_ascii_encoded_hex_str(encoded: &[u8]) -> Vec<u8> {
+    let decoded = decode_hex_bytes(encoded);
+    parser_assert(decoded.iter().all(|&c| c < 128));
+    decoded
+}
+
+fn to_hex_string(bytes: &[u8], max_len: usize) -> String {
+    let mut s = String::new();
+    for (i, b) in bytes.iter().enumerate() {
+        if i >= max_len {
+            s.push_str("...");
+            break;
+        }
+        s.push_str(&format!("{:02x}", b));
+    }
+    s
+}
+
+/// Undo the escaping in binary data sent to us by gdb.
+fn read_binary_data(payload: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(payload.len());
+    let mut iter = payload.iter();
+    while let Some(&b) = iter.next() {
+        if b'}' == b {
+            let escaped = iter.next();
+            parser_assert(escaped.is_some());
+            data.push(0x20 ^ *escaped.unwrap());
+        } else {
+            data.push(b);
+        }
+    }
+    data
+}
+
+/// Escape `data` (prefixed by `pfx`) so that it can be sent as part of a
+/// binary packet.
+fn escape_binary_data(pfx: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::<u8>::with_capacity(pfx.len() + 2 * data.len());
+    buf.extend_from_slice(pfx);
+    for &b in data {
+        match b {
+            b'#' | b'$' | b'}' | b'*' => {
+                buf.push(b'}');
+                buf.push(b ^ 0x20);
+            }
+            _ => buf.push(b),
+        }
+    }
+    buf
+}
+
+/// Repeat counts are sent as a printable character: `c*n` stands for `c`
+/// followed by `n - RUN_LENGTH_BASE` more copies of `c`.
+const RUN_LENGTH_BASE: u8 = 29;
+
+/// The longest run one repeat count can stand for, as the count character
+/// can't be above '~'.
+const MAX_RUN_LENGTH: usize = (b'~' - RUN_LENGTH_BASE) as usize + 1;
+
+/// Expand the run-length encoding in packet data from gdb. gdb expands runs
+/// before it undoes binary escaping, so a run after an escape repeats the
+/// whole escape sequence.
+fn decode_run_length(data: &[u8]) -> Vec<u8> {
+    let mut decoded = Vec::with_capacity(data.len());
+    // Where the last (possibly escaped) character starts in `decoded`
+    let mut last_char: Option<usize> = None;
+    let mut i = 0;
+    while i < data.len() {
+        match data[i] {
+            b'*' => {
+                parser_assert(last_char.is_some() && i + 1 < data.len());
+                let count = data[i + 1].checked_sub(RUN_LENGTH_BASE);
+                parser_assert(count.is_some());
+                let start = last_char.unwrap();
+                let repeated = decoded[start..].to_vec();
+                for _ in 0..count.unwrap() {
+                    decoded.extend_from_slice(&repeated);
+                }
+                last_char = Some(decoded.len() - repeated.len());
+                i += 2;
+            }
+            b'}' if i + 1 < data.len() => {
+                last_char = Some(decoded.len());
+                decoded.extend_from_slice(&data[i..i + 2]);
+                i += 2;
+            }
+            c => {
+                last_char = Some(decoded.len());
+                decoded.push(c);
+                i += 1;
+            }
+        }
+    }
+    decoded
+}
+
+/// Run-length encode packet data for gdb. Escape sequences are never part of
+/// a run, see `decode_run_length()`.
+fn encode_run_length(data: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < data.len() {
+        let c = data[i];
+        if c == b'}' && i + 1 < data.len() {
+            encoded.extend_from_slice(&data[i..i + 2]);
+            i += 2;
+            continue;
+        }
+
+        let mut run = 1;
+        while run < MAX_RUN_LENGTH && i + run < data.len() && data[i + run] == c {
+            run += 1;
+        }
+        // A run of 4 is the shortest that gets any shorter, and these
+        // characters can't be repeated.
+        if run < 4 || c == b'#' || c == b'$' || c == b'*' {
+            encoded.extend_from_slice(&data[i..i + run]);
+            i += run;
+            continue;
+        }
+        // '#' and '$' can't be used as repeat counts
+        let mut count = (run - 1) as u8 + RUN_LENGTH_BASE;
+        if count == b'#' || count == b'$' {
+            count = b'#' - 1;
+            run = (count - RUN_LENGTH_BASE) as usize + 1;
+        }
+        encoded.extend_from_slice(&[c, b'*', count]);
+        i += run;
+    }
+    encoded
+}
+
+/// Parse a gdb thread-id from the start of `s`. Returns the thread-id and
+/// the remainder of `s` just after the last character in the thread-id.
+fn parse_threadid(s: &[u8]) -> (GdbThreadId, &[u8]) {
+    let (multiprocess, s) = if s.first() == Some(&b'p') {
+        (true, &s[1..])
+    } else {
+        (false, s)
+    };
+
+    let (pid, rest) = parse_hex_signed(s);
+    let mut t = GdbThreadId::new(pid as pid_t, 0);
+    let mut rest = rest;
+
+    match rest.first() {
+        // terminators (single process, no PID or TID, depending on 'p' prefix)
+        None | Some(b';') | Some(b',') => {
+            if multiprocess {
+                t.tid = -1;
+            } else {
+                t.tid = t.pid;
+                t.pid = -1;
+            }
+        }
+        // multiprocess syntax "<pid>.<tid>"
+        Some(b'.') => {
+            let (tid, r) = parse_hex_signed(&rest[1..]);
+            t.tid = tid as pid_t;
+            rest = r;
+        }
+        _ => (),
+    }
+
+    parser_assert(rest.is_empty() || rest[0] == b';' || rest[0] == b',');
+    (t, rest)
+}
+
+fn undefined_reg(name: GdbRegister) -> GdbRegisterValue {
+    GdbRegisterValue {
+        name,
+        value: GdbRegisterValueData::Value([0u8; MAX_REG_SIZE_BYTES]),
+        defined: false,
+        size: 0,
+    }
+}
+
+/// Read the encoded register value in `s`.
+fn read_reg_value(name: GdbRegister, s: &[u8]) -> GdbRegisterValue {
+    if s.first() == Some(&b'x') {
+        return undefined_reg(name);
+    }
+
+    let bytes = decode_hex_bytes(s);
+    parser_assert(bytes.len() <= MAX_REG_SIZE_BYTES);
+    let mut value = [0u8; MAX_REG_SIZE_BYTES];
+    value[0..bytes.len()].copy_from_slice(&bytes);
+    GdbRegisterValue {
+        name,
+        value: GdbRegisterValueData::Value(value),
+        defined: true,
+        size: bytes.len(),
+    }
+}
+
+/// Format `reg` in the manner gdb expects.
+fn print_reg_value(reg: &GdbRegisterValue) -> Vec<u8> {
+    parser_assert(reg.size <= MAX_REG_SIZE_BYTES);
+    if reg.defined {
+        // gdb wants the register value in native endianness.
+        // reg.value read in native endianness is exactly that.
+        reg.as_bytes()
+            .iter()
+            .flat_map(|b| format!("{:02x}", b).into_bytes())
+            .collect()
+    } else {
+        b"xx".repeat(reg.size)
+    }
+}
+
+fn gdb_open_flags_to_system_flags(flags: i64) -> i32 {
+    let mut ret = match flags & 3 {
+        0 => O_RDONLY,
+        1 => O_WRONLY,
+        2 => O_RDWR,
+        _ => {
+            parser_assert(false);
+            return 0;
+        }
+    };
+    parser_assert(flags & !(3 | 0x8 | 0x200 | 0x400 | 0x800) == 0);
+    if flags & 0x8 != 0 {
+        ret |= O_APPEND;
+    }
+    if flags & 0x200 != 0 {
+        ret |= O_CREAT;
+    }
+    if flags & 0x400 != 0 {
+        ret |= O_TRUNC;
+    }
+    if flags & 0x800 != 0 {
+        ret |= O_EXCL;
+    }
+    ret
+}
+
+fn read_target_desc(file_name: &OsStr) -> Vec<u8> {
+    let mut path = PathBuf::from(resource_path());
+    path.push("share/rr");
+    path.push(file_name);
+    match fs::read(&path) {
+        Ok(desc) => desc,
+        Err(e) => fatal!(
+            "Failed to load target description file {:?}: {:?}",
+            file_name,
+            e
+        ),
+    }
+}
+
+fn target_description_name(cpu_features: u32) -> &'static str {
+    // This doesn't scale, but it's what gdb does...
+    match cpu_features {
+        0 => "i386-linux.xml",
+        GdbConnection::CPU_X86_64 => "amd64-linux.xml",
+        GdbConnection::CPU_AVX => "i386-avx-linux.xml",
+        f if f == GdbConnection::CPU_X86_64 | GdbConnection::CPU_AVX => "amd64-avx-linux.xml",
+        f if f == GdbConnection::CPU_PKU | GdbConnection::CPU_AVX => "i386-pkeys-linux.xml",
+        f if f == GdbConnection::CPU_X86_64 | GdbConnection::CPU_PKU | GdbConnection::CPU_AVX => {
+            "amd64-pkeys-linux.xml"
+        }
+        GdbConnection::CPU_AARCH64 => "aarch64-core.xml",
+        _ => fatal!("Unknown features"),
+    }
+}
+
+/// Translate linux-x86 `sig` to gdb's internal numbering.  Translation
+/// made according to gdb/include/gdb/signals.def.
+fn to_gdb_signum(sig: i32) -> i32 {
+    match sig {
+        0 => 0,
+        libc::SIGHUP => 1,
+        libc::SIGINT => 2,
+        libc::SIGQUIT => 3,
+        libc::SIGILL => 4,
+        libc::SIGTRAP => 5,
+        libc::SIGABRT /*case SIGIOT*/ => 6,
+        libc::SIGBUS => 10,
+        libc::SIGFPE => 8,
+        libc::SIGKILL => 9,
+        libc::SIGUSR1 => 30,
+        libc::SIGSEGV => 11,
+        libc::SIGUSR2 => 31,
+        libc::SIGPIPE => 13,
+        libc::SIGALRM => 14,
+        libc::SIGTERM => 15,
+        // gdb hasn't heard of SIGSTKFLT, so this is
+        // arbitrarily made up.  SIGDANGER just sounds cool.
+        libc::SIGSTKFLT => 38, /*GDB_SIGNAL_DANGER*/
+        /*case SIGCLD*/ libc::SIGCHLD => 20,
+        libc::SIGCONT => 19,
+        libc::SIGSTOP => 17,
+        libc::SIGTSTP => 18,
+        libc::SIGTTIN => 21,
+        libc::SIGTTOU => 22,
+        libc::SIGURG => 16,
+        libc::SIGXCPU => 24,
+        libc::SIGXFSZ => 25,
+        libc::SIGVTALRM => 26,
+        libc::SIGPROF => 27,
+        libc::SIGWINCH => 28,
+        /*case SIGPOLL*/ libc::SIGIO => 23,
+        libc::SIGPWR => 32,
+        libc::SIGSYS => 12,
+        32 => 77,
+        // GDB_SIGNAL_REALTIME_33 is numbered 45, hence this offset.
+        33..=127 => sig + 12,
+        _ => fatal!("Unknown signal {}", sig),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn parse_threadids() {
+        let (t, rest) = parse_threadid(b"p1a.1b;c");
+        assert_eq!(t, GdbThreadId::new(0x1a, 0x1b));
+        assert_eq!(rest, b";c");
+
+        let (t, rest) = parse_threadid(b"p1a");
+        assert_eq!(t, GdbThreadId::new(0x1a, -1));
+        assert!(rest.is_empty());
+
+        let (t, rest) = parse_threadid(b"-1");
+        assert_eq!(t, GdbThreadId::new(-1, -1));
+        assert!(rest.is_empty());
+
+        let (t, _) = parse_threadid(b"2f");
+        assert_eq!(t, GdbThreadId::new(-1, 0x2f));
+    }
+
+    #[test]
+    pub fn binary_data_round_trip() {
+        let data = b"a#b$c}d*e\x00".to_vec();
+        let escaped = escape_binary_data(b"l", &data);
+        assert_eq!(escaped[0], b'l');
+        assert!(!escaped[1..].contains(&b'#'));
+        assert!(!escaped[1..].contains(&b'$'));
+        assert_eq!(read_binary_data(&escaped[1..]), data);
+    }
+
+    #[test]
+    pub fn run_length_encoding() {
+        assert_eq!(decode_run_length(b"0* "), b"0000".to_vec());
+        assert_eq!(decode_run_length(b"ab*!c"), b"abbbbbc".to_vec());
+        // A run after an escape repeats the escape sequence
+        assert_eq!(decode_run_length(b"}]* "), b"}]}]}]}]".to_vec());
+
+        assert_eq!(encode_run_length(b"0000"), b"0* ".to_vec());
+        assert_eq!(encode_run_length(b"xxx"), b"xxx".to_vec());
+        // Escape sequences and '#'/'$' repeat counts are avoided
+        assert_eq!(encode_run_length(b"}]}]}]}]"), b"}]}]}]}]".to_vec());
+        assert!(!encode_run_length(&[b'0'; 7]).contains(&b'#'));
+        assert!(!encode_run_length(&[b'0'; 8]).contains(&b'$'));
+
+        for len in 0..300 {
+            let mut data = vec![b'0'; len];
+            data.extend_from_slice(b"}]}]x");
+            assert_eq!(decode_run_length(&encode_run_length(&data)), data);
+        }
     }
 
-    fn send_file_error_reply(_system_errno: i32) {
-        unimplemented!()
+    #[test]
+    pub fn parse_numbers() {
+        assert_eq!(parse_hex(b"7fff,10"), (0x7fff, &b",10"[..]));
+        assert_eq!(parse_hex_signed(b"-1;"), (-1, &b";"[..]));
+        assert_eq!(parse_c_integer(b"1234"), Some(1234));
+        assert_eq!(parse_c_integer(b"0x10"), Some(16));
+        assert_eq!(parse_c_integer(b"12a"), None);
+        assert_eq!(decode_ascii_encoded_hex_str(b"6869"), b"hi".to_vec());
     }
 }
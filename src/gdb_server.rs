@@ -1,11 +1,128 @@
 use crate::{
+    bindings::signal::siginfo_t,
     extra_registers::ExtraRegisters,
-    gdb_connection::GdbRegisterValue,
-    gdb_register::GdbRegister,
-    registers::Registers,
+    gdb_command_handler::GdbCommandHandler,
+    gdb_connection::{
+        open_socket,
+        GdbActionType,
+        GdbConnection,
+        GdbConnectionFeatures,
+        GdbRegisterValue,
+        GdbRegisterValueData,
+        GdbRequest,
+        GdbRequestType,
+        GdbRestartType,
+        GdbThreadId,
+        ProbePort,
+        DREQ_CONT,
+        DREQ_DETACH,
+        DREQ_FILE_CLOSE,
+        DREQ_FILE_OPEN,
+        DREQ_FILE_PREAD,
+        DREQ_FILE_SETFS,
+        DREQ_GET_AUXV,
+        DREQ_GET_CURRENT_THREAD,
+        DREQ_GET_EXEC_FILE,
+        DREQ_GET_IS_THREAD_ALIVE,
+        DREQ_GET_MEM,
+        DREQ_GET_OFFSETS,
+        DREQ_GET_REG,
+        DREQ_GET_REGS,
+        DREQ_GET_STOP_REASON,
+        DREQ_GET_THREAD_EXTRA_INFO,
+        DREQ_GET_THREAD_LIST,
+        DREQ_INTERRUPT,
+        DREQ_NONE,
+        DREQ_QSYMBOL,
+        DREQ_READ_SIGINFO,
+        DREQ_REMOVE_HW_BREAK,
+        DREQ_REMOVE_RDWR_WATCH,
+        DREQ_REMOVE_RD_WATCH,
+        DREQ_REMOVE_SW_BREAK,
+        DREQ_REMOVE_WR_WATCH,
+        DREQ_RESTART,
+        DREQ_RR_CMD,
+        DREQ_SEARCH_MEM,
+        DREQ_SET_CONTINUE_THREAD,
+        DREQ_SET_HW_BREAK,
+        DREQ_SET_MEM,
+        DREQ_SET_QUERY_THREAD,
+        DREQ_SET_RDWR_WATCH,
+        DREQ_SET_RD_WATCH,
+        DREQ_SET_REG,
+        DREQ_SET_SW_BREAK,
+        DREQ_SET_WR_WATCH,
+        DREQ_TLS,
+        DREQ_WRITE_SIGINFO,
+    },
+    gdb_expression::{GdbExpression, GdbExpressionValue},
+    gdb_register::{
+        GdbRegister,
+        DREG_64_YMM15H,
+        DREG_GS_BASE,
+        DREG_ORIG_EAX,
+        DREG_ORIG_RAX,
+        DREG_YMM7H,
+    },
+    kernel_abi::{syscall_number_for_execve, SupportedArch},
+    log::LogLevel::{LogDebug, LogError, LogInfo, LogWarn},
+    registers::{Registers, MAX_REG_SIZE_BYTES},
+    remote_code_ptr::RemoteCodePtr,
+    remote_ptr::{RemotePtr, Void},
+    replay_timeline::{BreakpointCondition, Mark, ReplayTimeline, RunDirection},
+    scoped_fd::ScopedFd,
+    session::{
+        address_space::{memory_range::MemoryRange, BreakpointType, WatchType},
+        diversion_session::DiversionStatus,
+        replay_session::{ReplayResult, ReplaySession, ReplayStatus},
+        session_inner::{BreakStatus, RunCommand},
+        task::{Task, TaskSharedPtr},
+        Session,
+        SessionSharedPtr,
+    },
+    sig::Sig,
+    taskish_uid::{TaskUid, ThreadGroupUid},
     trace::trace_frame::FrameTime,
+    util::{
+        cpuid,
+        find,
+        floor_page_size,
+        page_size,
+        read_to_end,
+        tmp_dir,
+        to_cstr_array,
+        to_cstring_array,
+        u8_raw_slice_mut,
+        u8_slice,
+        write_all,
+        AVX_FEATURE_FLAG,
+        CPUID_GETEXTENDEDFEATURES,
+        CPUID_GETFEATURES,
+        PKU_FEATURE_FLAG,
+    },
+};
+use libc::{pid_t, EACCES, EBADF, EIO, ENOENT, O_RDONLY, PATH_MAX, SIGKILL, SIGTRAP};
+use nix::{
+    fcntl::OFlag,
+    unistd::{execvpe, getpid, mkstemp, read, unlink},
+};
+use std::{
+    cell::RefCell,
+    cmp::{max, min},
+    collections::HashMap,
+    convert::TryFrom,
+    env,
+    ffi::{CString, OsStr, OsString},
+    io::{stderr, Write},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    ptr::copy_nonoverlapping,
+    sync::atomic::{AtomicBool, Ordering},
 };
-use libc::pid_t;
+
+/// Set from a signal handler when the user interrupts `rd replay` before
+/// the debugger has been attached. See `GdbServer::interrupt_replay_to_target()`.
+static STOP_REPLAYING_TO_TARGET: AtomicBool = AtomicBool::new(false);
 
 #[derive(Clone)]
 pub struct Target {
@@ -33,15 +150,1673 @@ impl Default for Target {
     }
 }
 
-pub struct GdbServer;
+pub struct ConnectionFlags {
+    /// `None` or `Some(0)` to let GdbServer choose the port, otherwise the
+    /// specific port to listen on.
+    pub dbg_port: Option<u16>,
+    pub dbg_host: String,
+    pub keep_listening: bool,
+    /// If `Some`, then when the gdbserver is set up, we write its connection
+    /// parameters through this pipe. `GdbServer::launch_gdb()` is passed the
+    /// other end of this pipe to exec gdb with the parameters.
+    pub debugger_params_write_pipe: Option<ScopedFd>,
+    /// Name of the debugger to suggest. Only used if
+    /// `debugger_params_write_pipe` is `None`.
+    pub debugger_name: PathBuf,
+}
+
+impl Default for ConnectionFlags {
+    fn default() -> Self {
+        Self {
+            dbg_port: None,
+            dbg_host: "127.0.0.1".into(),
+            keep_listening: false,
+            debugger_params_write_pipe: None,
+            debugger_name: "gdb".into(),
+        }
+    }
+}
+
+/// What `GdbServer::launch_gdb()` needs to know to connect gdb to the server.
+/// Sent as raw bytes from the server process to the process that execs gdb.
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct DebuggerParams {
+    exe_image: [u8; PATH_MAX as usize],
+    /// INET_ADDRSTRLEN, omitted for header churn
+    host: [u8; 16],
+    port: u16,
+}
+
+impl Default for DebuggerParams {
+    fn default() -> Self {
+        DebuggerParams {
+            exe_image: [0u8; PATH_MAX as usize],
+            host: [0u8; 16],
+            port: 0,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum ReportState {
+    ReportNormal,
+    ReportThreadsDead,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum ContinueOrStop {
+    ContinueDebugging,
+    StopDebugging,
+}
+
+pub struct GdbServer {
+    target: Target,
+    /// dbg is never `None` while a debugger is attached.
+    dbg: Option<GdbConnection>,
+    /// The ThreadGroupUid of the task being debugged.
+    debuggee_tguid: ThreadGroupUid,
+    /// The TaskUid of the last continued task.
+    last_continue_tuid: TaskUid,
+    /// The TaskUid of the last queried task.
+    last_query_tuid: TaskUid,
+    final_event: FrameTime,
+    /// siginfo for last notified stop.
+    stop_siginfo: siginfo_t,
+    in_debuggee_end_state: bool,
+    /// True when a restart was attempted but didn't succeed.
+    failed_restart: bool,
+    /// True when gdb interrupted a resume request and we haven't reported a
+    /// stop for it yet.
+    interrupt_pending: bool,
+    /// Where we go back to when gdb asks to restart without naming an event.
+    /// This is the point at which the debugger was (most recently) activated.
+    debugger_restart_mark: Option<Mark>,
+    /// Files opened by the debugger via vFile:open, keyed by the fd we gave gdb.
+    files: HashMap<i32, ScopedFd>,
+    /// The id of the last checkpoint the debugger created.
+    last_checkpoint_id: u32,
+    /// The pid for gdb's last vFile:setfs
+    file_scope_pid: pid_t,
+    timeline: ReplayTimeline,
+}
 
 impl GdbServer {
+    /// Create a gdbserver serving the replay session `session`.
+    pub fn new(session: SessionSharedPtr, target: &Target) -> GdbServer {
+        debug_assert!(session.as_replay().is_some());
+        GdbServer {
+            target: target.clone(),
+            dbg: None,
+            debuggee_tguid: ThreadGroupUid::new(),
+            last_continue_tuid: TaskUid::new(),
+            last_query_tuid: TaskUid::new(),
+            final_event: FrameTime::MAX,
+            stop_siginfo: Default::default(),
+            in_debuggee_end_state: false,
+            failed_restart: false,
+            interrupt_pending: false,
+            debugger_restart_mark: None,
+            files: HashMap::new(),
+            last_checkpoint_id: 0,
+            file_scope_pid: 0,
+            timeline: ReplayTimeline::new(session),
+        }
+    }
+
+    /// Actually run the server. Returns only when the debugger disconnects.
+    pub fn serve_replay(&mut self, flags: &mut ConnectionFlags) {
+        loop {
+            let result = self.replay_step_forward(RunCommand::RunContinue, self.target.event);
+            if result.status == ReplayStatus::ReplayExited {
+                log!(LogInfo, "Debugger was not launched before end of trace");
+                return;
+            }
+            if self.at_target() {
+                break;
+            }
+        }
+
+        // Don't probe if the user specified a port.  Explicitly
+        // selecting a port is usually done by scripts, which would
+        // presumably break if a different port were to be selected by
+        // rd (otherwise why would they specify a port in the first
+        // place).  So fail with a clearer error message.
+        let (mut port, probe) = match flags.dbg_port {
+            Some(port) if port > 0 => (port, ProbePort::DontProbe),
+            _ => (getpid().as_raw() as u16, ProbePort::ProbePort),
+        };
+        let t = self.current_replay().current_task().unwrap();
+        let listen_fd: ScopedFd = open_socket(&flags.dbg_host, &mut port, probe);
+        let exe_image = t.borrow().vm().exe_image().to_owned();
+        match flags.debugger_params_write_pipe.as_mut() {
+            Some(params_pipe) => {
+                let mut params = DebuggerParams::default();
+                copy_truncated(&mut params.exe_image, exe_image.as_bytes());
+                copy_truncated(&mut params.host, flags.dbg_host.as_bytes());
+                params.port = port;
+                write_all(params_pipe.as_raw(), u8_slice(&params));
+                params_pipe.close();
+            }
+            None => {
+                eprint!("Launch gdb with\n  ");
+                GdbServer::print_debugger_launch_command(
+                    &exe_image,
+                    &flags.dbg_host,
+                    port,
+                    &flags.debugger_name,
+                    &mut stderr(),
+                );
+            }
+        }
+
+        self.debuggee_tguid = t.borrow().thread_group().tguid();
+
+        loop {
+            log!(LogDebug, "initializing debugger connection");
+            self.dbg = Some(await_connection(&**t.borrow(), &listen_fd));
+            self.activate_debugger();
+
+            let mut last_resume_request = GdbRequest::default();
+            while self.debug_one_step(&mut last_resume_request) == ContinueOrStop::ContinueDebugging
+            {
+            }
+
+            self.remove_breakpoints_and_watchpoints();
+            if !flags.keep_listening {
+                break;
+            }
+        }
+
+        log!(LogDebug, "debugger server exiting ...");
+    }
+
+    /// exec()'s gdb using parameters read from `params_pipe_fd` (and sent through
+    /// the pipe passed to `serve_replay()`).
+    pub fn launch_gdb(
+        params_pipe_fd: &ScopedFd,
+        gdb_binary_file_path: &Path,
+        gdb_options: &[OsString],
+    ) {
+        let gdb_command_file = create_gdb_command_file(&GdbCommandHandler::gdb_macros());
+
+        let mut params = DebuggerParams::default();
+        let buf = unsafe { &mut *u8_raw_slice_mut(&mut params) };
+        let mut nread: usize = 0;
+        while nread < buf.len() {
+            match read(params_pipe_fd.as_raw(), &mut buf[nread..]) {
+                // pipe was closed. Probably rd failed/died.
+                Ok(0) => return,
+                Ok(n) => nread += n,
+                Err(e) if e.as_errno() == Some(nix::errno::Errno::EINTR) => continue,
+                Err(e) => fatal!("Error reading debugger params: {:?}", e),
+            }
+        }
+
+        let host = c_string_bytes(&params.host);
+        let exe_image = c_string_bytes(&params.exe_image);
+
+        let mut args: Vec<OsString> = Vec::new();
+        args.push(gdb_binary_file_path.into());
+        push_default_gdb_options(&mut args);
+        args.push("-x".into());
+        args.push(gdb_command_file);
+        let mut did_set_remote = false;
+        for (i, opt) in gdb_options.iter().enumerate() {
+            if !did_set_remote
+                && opt == "-ex"
+                && i + 1 < gdb_options.len()
+                && needs_target(&gdb_options[i + 1])
+            {
+                push_target_remote_cmd(&mut args, OsStr::from_bytes(host), params.port);
+                did_set_remote = true;
+            }
+            args.push(opt.clone());
+        }
+        if !did_set_remote {
+            push_target_remote_cmd(&mut args, OsStr::from_bytes(host), params.port);
+        }
+        args.push(OsStr::from_bytes(exe_image).to_owned());
+
+        let mut env_vars: Vec<OsString> = Vec::new();
+        for (k, v) in env::vars_os() {
+            let mut var = k;
+            var.push("=");
+            var.push(v);
+            env_vars.push(var);
+        }
+        env_vars.push("GDB_UNDER_RD=1".into());
+
+        log!(LogDebug, "launching {:?}", args);
+        let c_args = to_cstring_array(&args);
+        let c_env = to_cstring_array(&env_vars);
+        let c_gdb = CString::new(gdb_binary_file_path.as_os_str().as_bytes()).unwrap();
+        // execvpe() only returns on failure.
+        let _ = execvpe(&c_gdb, &to_cstr_array(&c_args), &to_cstr_array(&c_env));
+        clean_fatal!("Failed to exec {:?}.", gdb_binary_file_path);
+    }
+
+    /// Called from a signal handler (or other thread) during serve_replay,
+    /// this will cause the replay-to-target phase to be interrupted and
+    /// debugging started wherever the replay happens to be.
+    pub fn interrupt_replay_to_target() {
+        STOP_REPLAYING_TO_TARGET.store(true, Ordering::SeqCst);
+    }
+
     /// Return the register `which`, which may not have a defined value.
     pub fn get_reg(
-        _regs: &Registers,
-        _extra_regs: &ExtraRegisters,
-        _which: GdbRegister,
+        regs: &Registers,
+        extra_regs: &ExtraRegisters,
+        which: GdbRegister,
     ) -> GdbRegisterValue {
-        unimplemented!()
+        let mut buf = [0u8; MAX_REG_SIZE_BYTES];
+        let maybe_size = match regs.read_register(&mut buf, which) {
+            Some(size) => Some(size),
+            None => extra_regs.read_register(&mut buf, which),
+        };
+
+        match maybe_size {
+            Some(size) => {
+                let value = match size {
+                    1 => GdbRegisterValueData::Value1(buf[0]),
+                    2 => GdbRegisterValueData::Value2(u16::from_le_bytes([buf[0], buf[1]])),
+                    4 => GdbRegisterValueData::Value4(u32::from_le_bytes(
+                        <[u8; 4]>::try_from(&buf[0..4]).unwrap(),
+                    )),
+                    8 => GdbRegisterValueData::Value8(u64::from_le_bytes(
+                        <[u8; 8]>::try_from(&buf[0..8]).unwrap(),
+                    )),
+                    _ => GdbRegisterValueData::Value(buf),
+                };
+                GdbRegisterValue {
+                    name: which,
+                    value,
+                    defined: true,
+                    size,
+                }
+            }
+            None => GdbRegisterValue {
+                name: which,
+                value: GdbRegisterValueData::Value(buf),
+                defined: false,
+                size: 0,
+            },
+        }
+    }
+
+    pub fn print_debugger_launch_command(
+        exe_image: &OsStr,
+        host: &str,
+        port: u16,
+        debugger_name: &Path,
+        out: &mut dyn Write,
+    ) {
+        let mut options: Vec<OsString> = Vec::new();
+        push_default_gdb_options(&mut options);
+        push_target_remote_cmd(&mut options, OsStr::new(host), port);
+        let mut line: Vec<u8> = Vec::new();
+        line.extend_from_slice(debugger_name.as_os_str().as_bytes());
+        line.push(b' ');
+        for opt in &options {
+            line.push(b'\'');
+            line.extend_from_slice(opt.as_bytes());
+            line.extend_from_slice(b"' ");
+        }
+        line.extend_from_slice(exe_image.as_bytes());
+        line.push(b'\n');
+        out.write_all(&line).unwrap_or(());
+    }
+
+    /// The session that gdb requests currently act on.
+    pub fn current_session(&self) -> SessionSharedPtr {
+        self.timeline.current_session_shr_ptr()
+    }
+
+    /// The TaskUid of the task gdb most recently resumed (or stopped in).
+    pub fn last_continue_tuid(&self) -> TaskUid {
+        self.last_continue_tuid
+    }
+
+    fn current_replay(&self) -> &ReplaySession {
+        self.timeline.current_session()
+    }
+
+    fn dbg(&mut self) -> &mut GdbConnection {
+        self.dbg.as_mut().unwrap()
+    }
+
+    /// True when the debuggee has run to its end and only gdb's view of its
+    /// final state remains.
+    pub fn in_debuggee_end_state(&self) -> bool {
+        self.in_debuggee_end_state
+    }
+
+    /// Checkpoint the current session for the debugger. Returns the id gdb
+    /// can later `restart` from, or None if we can't checkpoint here.
+    pub fn create_checkpoint(&mut self) -> Option<u32> {
+        let checkpoint = self.timeline.checkpoint_current_session()?;
+        self.last_checkpoint_id += 1;
+        let id = self.last_checkpoint_id;
+        self.dbg().created_checkpoint(checkpoint, id);
+        Some(id)
+    }
+
+    /// Returns false if there was no checkpoint with id `id`.
+    pub fn delete_checkpoint(&mut self, id: u32) -> bool {
+        if self.dbg().get_checkpoint(id).is_none() {
+            return false;
+        }
+        self.dbg().delete_checkpoint(id);
+        true
+    }
+
+    /// The ids of the debugger's checkpoints with the events they were taken
+    /// at, in increasing order of id.
+    pub fn checkpoints(&mut self) -> Vec<(u32, FrameTime)> {
+        let mut result = Vec::new();
+        for id in self.dbg().checkpoint_ids() {
+            let checkpoint = self.dbg().get_checkpoint(id).unwrap();
+            let time = checkpoint.as_replay().unwrap().current_frame_time();
+            result.push((id, time));
+        }
+        result
+    }
+
+    /// Return true if we should stop replaying forward and hand control over
+    /// to the debugger.
+    fn at_target(&self) -> bool {
+        let replay = self.current_replay();
+        // Don't launch the debugger for the initial rd fork child.
+        // No one ever wants that to happen.
+        if !replay.done_initial_exec() {
+            return false;
+        }
+        let t = match replay.current_task() {
+            Some(t) => t,
+            None => return false,
+        };
+        if STOP_REPLAYING_TO_TARGET.load(Ordering::SeqCst) {
+            return true;
+        }
+        // When we decide to create the debugger, we want to do so
+        // based on the frame we're *about to* replay, without
+        // modifying the trace reader.
+        // NB: we'll happily attach to whichever task within the
+        // group happens to be scheduled here.  We don't take
+        // "attach to process" to mean "attach to thread-group
+        // leader".
+        let tb = t.borrow();
+        replay.current_trace_frame().time() > self.target.event
+            && (self.target.pid.is_none() || Some(tb.tgid()) == self.target.pid)
+            && (!self.target.require_exec || tb.execed())
+            // Ensure we're at the start of processing an event. We don't
+            // want to attach while we're finishing an exec() since that's a
+            // slightly confusing state.
+            && !replay.current_step_key().in_execution()
+    }
+
+    /// The trace was recorded at `next_frame.time() - 1`; attach the debugger
+    /// there and remember this point so we can restart to it.
+    fn activate_debugger(&mut self) {
+        let replay = self.current_replay();
+        let completed_event: FrameTime = replay.current_trace_frame().time() - 1;
+        let t = replay.current_task().unwrap();
+        let tgid = t.borrow().tgid();
+        if self.target.event > 0 || self.target.pid.is_some() {
+            if STOP_REPLAYING_TO_TARGET.load(Ordering::SeqCst) {
+                eprint!(
+                    "\x07\n\
+                    --------------------------------------------------\n \
+                    ---> Interrupted; attached to NON-TARGET process {} at event {}.\n\
+                    --------------------------------------------------\n",
+                    tgid, completed_event
+                );
+            } else {
+                eprint!(
+                    "\x07\n\
+                    --------------------------------------------------\n \
+                    ---> Reached target process {} at event {}.\n\
+                    --------------------------------------------------\n",
+                    tgid, completed_event
+                );
+            }
+        }
+
+        // Store the current tgid and event as the "execution target"
+        // for the next replay session, if we end up restarting.  This
+        // allows us to determine if a later session has reached this
+        // target without necessarily replaying up to this point.
+        self.target.pid = Some(tgid);
+        self.target.require_exec = false;
+        self.target.event = completed_event;
+
+        // Don't allow reverse execution to go back before this point, and come
+        // back here when gdb asks for a plain restart.
+        let mark = self.timeline.mark();
+        self.timeline.set_reverse_execution_barrier(mark.clone());
+        self.debugger_restart_mark = Some(mark);
+
+        let tuid = t.borrow().tuid();
+        self.last_query_tuid = tuid;
+        self.last_continue_tuid = tuid;
+    }
+
+    /// Replay forward one step, filtering out any breakpoint hits whose
+    /// conditions aren't satisfied.
+    fn replay_step_forward(
+        &mut self,
+        command: RunCommand,
+        stop_at_time: FrameTime,
+    ) -> ReplayResult {
+        self.timeline.replay_step_forward(command, stop_at_time)
+    }
+
+    fn debug_one_step(&mut self, last_resume_request: &mut GdbRequest) -> ContinueOrStop {
+        let mut req: GdbRequest;
+
+        if self.in_debuggee_end_state {
+            // Treat the state where the last thread is about to exit like
+            // termination.
+            req = self.process_debugger_requests(ReportState::ReportNormal);
+            // If it's a forward execution request, fake the exited state.
+            if req.is_resume_request() && req.cont().run_direction == RunDirection::RunForward {
+                if !self.interrupt_pending {
+                    return self.handle_exited_state(last_resume_request);
+                }
+                // Just process this. We're getting it after a restart.
+            } else if req.type_ != DREQ_DETACH {
+                self.in_debuggee_end_state = false;
+            }
+            // Otherwise (e.g. detach, restart, interrupt or reverse-exec) process
+            // the request as normal.
+        } else if !self.interrupt_pending || last_resume_request.type_ == DREQ_NONE {
+            req = self.process_debugger_requests(ReportState::ReportNormal);
+        } else {
+            req = last_resume_request.clone();
+        }
+
+        if req.type_ == DREQ_INTERRUPT {
+            self.interrupt_pending = true;
+            if !last_resume_request.is_resume_request()
+                || last_resume_request.cont().run_direction == RunDirection::RunBackward
+            {
+                // Nothing is running, so there's nothing to interrupt. A reverse
+                // execution that was interrupted has already returned to where
+                // it started.
+                self.interrupt_pending = false;
+                let session = self.current_session();
+                self.notify_stop_internal(&**session, None, None);
+                return ContinueOrStop::ContinueDebugging;
+            }
+            req = last_resume_request.clone();
+        } else if let Some(s) = self.detach_or_restart(&req) {
+            *last_resume_request = GdbRequest::default();
+            return s;
+        }
+
+        debug_assert!(req.is_resume_request());
+        *last_resume_request = req.clone();
+
+        let t = self.current_replay().current_task().unwrap();
+        let command = compute_run_command_from_actions(&**t.borrow(), &req);
+        let result = if req.cont().run_direction == RunDirection::RunForward {
+            // Ignore gdb's signal_to_deliver; we just have to follow the replay.
+            let result = self.replay_step_forward(command, self.target.event);
+            if result.status == ReplayStatus::ReplayExited {
+                return self.handle_exited_state(last_resume_request);
+            }
+            result
+        } else {
+            self.reverse_step(command)
+        };
+
+        let did_notify = if !req.suppress_debugger_stop {
+            self.maybe_notify_stop(&req, &result.break_status)
+        } else {
+            false
+        };
+        if did_notify {
+            *last_resume_request = GdbRequest::default();
+        }
+
+        if req.cont().run_direction == RunDirection::RunBackward {
+            return ContinueOrStop::ContinueDebugging;
+        }
+        if let Some(task) = result.break_status.task.as_ref().and_then(|w| w.upgrade()) {
+            if is_last_thread_exit(&result.break_status)
+                && task.borrow().thread_group().tguid() == self.debuggee_tguid
+            {
+                self.in_debuggee_end_state = true;
+            }
+        }
+        ContinueOrStop::ContinueDebugging
+    }
+
+    /// Execute backwards until the next stop gdb would want to hear about.
+    /// Running backwards never reaches the end of the trace; if we get back to
+    /// the point where the debugger was activated the returned break status
+    /// has `task_exit` set.
+    fn reverse_step(&mut self, command: RunCommand) -> ReplayResult {
+        let debuggee_tguid = self.debuggee_tguid;
+        let last_continue_tuid = self.last_continue_tuid;
+        // Both closures need the connection, but never at the same time.
+        let dbg = RefCell::new(self.dbg.as_mut().unwrap());
+        let stop_filter = |t: &TaskSharedPtr, break_status: &BreakStatus| -> bool {
+            if t.borrow().thread_group().tguid() != debuggee_tguid {
+                return false;
+            }
+            // Don't stop for a signal where gdb has said not to.
+            match break_status
+                .signal
+                .as_ref()
+                .and_then(|siginfo| Sig::try_from(siginfo.si_signo).ok())
+            {
+                Some(sig) => !dbg.borrow().is_pass_signal(sig),
+                None => true,
+            }
+        };
+        let mut interrupt_check = || dbg.borrow_mut().sniff_packet();
+        match command {
+            RunCommand::RunContinue => self
+                .timeline
+                .reverse_continue(&stop_filter, &mut interrupt_check),
+            RunCommand::RunSinglestep => self
+                .timeline
+                .reverse_singlestep(last_continue_tuid, &mut interrupt_check),
+            _ => fatal!("Unknown RunCommand"),
+        }
+    }
+
+    fn handle_exited_state(&mut self, last_resume_request: &mut GdbRequest) -> ContinueOrStop {
+        // TODO return real exit code, if it's useful.
+        self.dbg().notify_exit_code(0);
+        let final_event = self.current_replay().trace_reader().time();
+        self.final_event = final_event;
+        let req = self.process_debugger_requests(ReportState::ReportThreadsDead);
+        if let Some(s) = self.detach_or_restart(&req) {
+            *last_resume_request = GdbRequest::default();
+            return s;
+        }
+        fatal!("Received continue/interrupt request after end-of-trace.");
+    }
+
+    /// If `req` is a detach or restart request, handle it and return what
+    /// to do next.
+    fn detach_or_restart(&mut self, req: &GdbRequest) -> Option<ContinueOrStop> {
+        if req.type_ == DREQ_RESTART {
+            self.restart_session(req);
+            Some(ContinueOrStop::ContinueDebugging)
+        } else if req.type_ == DREQ_DETACH {
+            Some(ContinueOrStop::StopDebugging)
+        } else {
+            None
+        }
+    }
+
+    /// Process debugger requests until a resume (or restart/detach/interrupt)
+    /// request is received, which is returned.
+    fn process_debugger_requests(&mut self, state: ReportState) -> GdbRequest {
+        loop {
+            let mut req = self.dbg().get_request();
+            req.suppress_debugger_stop = false;
+
+            if req.type_ == DREQ_READ_SIGINFO {
+                let mut si_bytes = vec![0u8; req.mem().len];
+                let siginfo = u8_slice(&self.stop_siginfo);
+                let len = min(si_bytes.len(), siginfo.len());
+                si_bytes[0..len].copy_from_slice(&siginfo[0..len]);
+                self.dbg().reply_read_siginfo(&si_bytes);
+
+                // READ_SIGINFO is usually the start of a diversion. It can also be
+                // triggered by "print $_siginfo" but that is rare so we just assume it's
+                // a diversion start; if "print $_siginfo" happens we'll print the correct
+                // siginfo and then incorrectly start a diversion and go haywire :-(.
+                // Ideally we'd come up with a better way to detect diversions so that
+                // "print $_siginfo" works.
+                req = self.divert();
+                if req.type_ == DREQ_NONE {
+                    continue;
+                }
+                // Carry on to process the request that was rejected by
+                // the diversion session
+            }
+
+            if req.is_resume_request() {
+                return req;
+            }
+
+            if req.type_ == DREQ_INTERRUPT {
+                log!(LogDebug, "  request to interrupt");
+                return req;
+            }
+
+            if req.type_ == DREQ_RESTART {
+                log!(
+                    LogDebug,
+                    "  request to restart at event {}",
+                    req.restart().param
+                );
+                return req;
+            }
+
+            if req.type_ == DREQ_DETACH {
+                log!(LogDebug, "  debugger detached");
+                self.dbg().reply_detach();
+                return req;
+            }
+
+            let session = self.current_session();
+            self.dispatch_debugger_request(&session, &req, state);
+        }
     }
+
+    /// Run the requests of a gdb function call (e.g. `print obj->to_string()`)
+    /// in a diversion forked from the current replay state, so that the replay
+    /// itself is never disturbed.
+    ///
+    /// gdb brackets such calls with READ_SIGINFO/WRITE_SIGINFO requests which
+    /// we use to refcount the diversion. Once the last reference is dropped the
+    /// next resume request ends the diversion.
+    ///
+    /// Returns the request that ended the diversion, which must be processed
+    /// against the replay session, or a DREQ_NONE request if there's nothing
+    /// left to do.
+    fn divert(&mut self) -> GdbRequest {
+        log!(
+            LogDebug,
+            "Starting debugging diversion for {:?}",
+            self.current_replay() as *const ReplaySession
+        );
+
+        let diversion_session = self.timeline.clone_diversion();
+        let mut diversion_refcount: u32 = 1;
+        let saved_query_tuid = self.last_query_tuid;
+
+        let mut req: GdbRequest;
+        loop {
+            req = self.dbg().get_request();
+            if req.is_resume_request() {
+                if diversion_refcount == 0 {
+                    // Shut down the diversion
+                    break;
+                }
+
+                let diversion = diversion_session.as_diversion().unwrap();
+                let t = match diversion.find_task_from_task_uid(self.last_continue_tuid) {
+                    Some(t) => t,
+                    None => {
+                        log!(LogDebug, "  diversion task is gone; ending diversion");
+                        break;
+                    }
+                };
+                let command = compute_run_command_from_actions(&**t.borrow(), &req);
+                let result = diversion.diversion_step(t.borrow_mut().as_mut(), Some(command), None);
+
+                match result.status {
+                    DiversionStatus::DiversionExited => {
+                        diversion_refcount = 0;
+                        self.maybe_notify_stop(&req, &result.break_status);
+                        req = GdbRequest::default();
+                        break;
+                    }
+                    DiversionStatus::DiversionContinue => {
+                        self.maybe_notify_stop(&req, &result.break_status);
+                        continue;
+                    }
+                }
+            }
+
+            if req.type_ == DREQ_RESTART {
+                // Debugger client requested that we restart execution
+                // from the beginning.  Restart our debug session.
+                log!(
+                    LogDebug,
+                    "  request to restart at event {}; terminating diversion",
+                    req.restart().param
+                );
+                break;
+            }
+
+            if req.type_ == DREQ_DETACH {
+                log!(LogDebug, "  debugger detached; terminating diversion");
+                self.dbg().reply_detach();
+                break;
+            }
+
+            if req.type_ == DREQ_READ_SIGINFO {
+                log!(LogDebug, "Adding ref to diversion session");
+                diversion_refcount += 1;
+                let si_bytes = vec![0u8; req.mem().len];
+                self.dbg().reply_read_siginfo(&si_bytes);
+                continue;
+            }
+
+            if req.type_ == DREQ_WRITE_SIGINFO {
+                log!(LogDebug, "Removing reference to diversion session ...");
+                debug_assert!(diversion_refcount > 0);
+                diversion_refcount -= 1;
+                if diversion_refcount == 0 {
+                    log!(LogDebug, "  ... dying at next continue request");
+                }
+                self.dbg().reply_write_siginfo();
+                continue;
+            }
+
+            self.dispatch_debugger_request(&diversion_session, &req, ReportState::ReportNormal);
+        }
+
+        log!(LogDebug, "... ending debugging diversion");
+        diversion_session.kill_all_tasks();
+        self.last_query_tuid = saved_query_tuid;
+        req
+    }
+
+    fn dispatch_debugger_request(
+        &mut self,
+        session: &SessionSharedPtr,
+        req: &GdbRequest,
+        state: ReportState,
+    ) {
+        debug_assert!(!req.is_resume_request());
+
+        // These requests don't require a target task.
+        match req.type_ {
+            DREQ_RESTART => {
+                debug_assert!(false);
+                return;
+            }
+            DREQ_GET_CURRENT_THREAD => {
+                let threadid = get_threadid_from_tuid(&**session, self.last_continue_tuid);
+                self.dbg().reply_get_current_thread(threadid);
+                return;
+            }
+            DREQ_GET_OFFSETS => {
+                // TODO
+                self.dbg().reply_get_offsets();
+                return;
+            }
+            DREQ_GET_THREAD_LIST => {
+                let mut tids: Vec<GdbThreadId> = Vec::new();
+                if state != ReportState::ReportThreadsDead {
+                    for t in session.tasks().values() {
+                        tids.push(get_threadid(&**t.borrow()));
+                    }
+                }
+                self.dbg().reply_get_thread_list(&tids);
+                return;
+            }
+            DREQ_INTERRUPT => {
+                self.notify_stop_internal(&**session, None, None);
+                return;
+            }
+            DREQ_GET_EXEC_FILE => {
+                // We shouldn't normally receive this since we try to pass the exe file
+                // name on gdb's command line, but the user might start gdb manually
+                // and this is easy to support in case some other debugger or
+                // configuration needs it.
+                let maybe_t = if req.target.tid > 0 {
+                    session
+                        .find_thread_group_from_pid(req.target.tid)
+                        .and_then(|tg| tg.borrow().task_set().iter().next())
+                } else {
+                    session.find_task_from_task_uid(self.last_continue_tuid)
+                };
+                let exec_file = match maybe_t {
+                    Some(t) => t.borrow().vm().exe_image().to_owned(),
+                    None => OsString::new(),
+                };
+                self.dbg().reply_get_exec_file(&exec_file);
+                return;
+            }
+            DREQ_FILE_SETFS => {
+                // Only the filesystem as seen by the remote stub is supported currently
+                self.file_scope_pid = req.file_setfs().pid;
+                self.dbg().reply_setfs(0);
+                return;
+            }
+            DREQ_FILE_OPEN => {
+                // We only support reading files
+                if req.file_open().flags == O_RDONLY {
+                    let fd = self.open_file(&req.file_open().file_name);
+                    let err = if fd >= 0 { 0 } else { ENOENT };
+                    self.dbg().reply_open(fd, err);
+                } else {
+                    self.dbg().reply_open(-1, EACCES);
+                }
+                return;
+            }
+            DREQ_FILE_PREAD => {
+                let read_req = req.file_pread().clone();
+                let maybe_result = self.files.get(&read_req.fd).map(|fd| {
+                    let size = min(read_req.size, 1024 * 1024);
+                    let mut data = vec![0u8; size];
+                    match read_to_end(fd, read_req.offset, &mut data) {
+                        Ok(nread) => {
+                            data.truncate(nread);
+                            (data, 0)
+                        }
+                        Err(_) => (Vec::new(), EIO),
+                    }
+                });
+                match maybe_result {
+                    Some((data, err)) => self.dbg().reply_pread(&data, err),
+                    None => self.dbg().reply_pread(&[], EIO),
+                }
+                return;
+            }
+            DREQ_FILE_CLOSE => {
+                if self.files.remove(&req.file_close().fd).is_some() {
+                    self.dbg().reply_close(0);
+                } else {
+                    self.dbg().reply_close(EBADF);
+                }
+                return;
+            }
+            _ => (),
+        }
+
+        let is_query = req.type_ != DREQ_SET_CONTINUE_THREAD;
+        let maybe_target = if req.target.tid > 0 {
+            session.find_task_from_rec_tid(req.target.tid)
+        } else if is_query {
+            session.find_task_from_task_uid(self.last_query_tuid)
+        } else {
+            session.find_task_from_task_uid(self.last_continue_tuid)
+        };
+        if let Some(target) = maybe_target.as_ref() {
+            let tuid = target.borrow().tuid();
+            if is_query {
+                self.last_query_tuid = tuid;
+            } else {
+                self.last_continue_tuid = tuid;
+            }
+        }
+
+        // These requests query or manipulate which task is the
+        // target, so it's OK if the task doesn't exist.
+        match req.type_ {
+            DREQ_GET_IS_THREAD_ALIVE => {
+                self.dbg().reply_get_is_thread_alive(maybe_target.is_some());
+                return;
+            }
+            DREQ_GET_THREAD_EXTRA_INFO => {
+                let name = match maybe_target.as_ref() {
+                    Some(t) => t.borrow().name().to_owned(),
+                    None => OsString::new(),
+                };
+                self.dbg().reply_get_thread_extra_info(&name);
+                return;
+            }
+            DREQ_SET_CONTINUE_THREAD | DREQ_SET_QUERY_THREAD => {
+                self.dbg().reply_select_thread(maybe_target.is_some());
+                return;
+            }
+            _ => (),
+        }
+
+        // These requests require a valid target task.  We don't trust
+        // the debugger to use the information provided above to only
+        // query valid tasks.
+        let target = match maybe_target {
+            Some(t) => t,
+            None => {
+                self.dbg().notify_no_such_thread(req);
+                return;
+            }
+        };
+
+        match req.type_ {
+            DREQ_GET_AUXV => {
+                let auxv = target.borrow().vm().saved_auxv().to_vec();
+                self.dbg().reply_get_auxv(&auxv);
+            }
+            DREQ_GET_MEM => {
+                let mut mem = vec![0u8; req.mem().len];
+                let addr = RemotePtr::<Void>::new_from_val(req.mem().addr);
+                let nread = target
+                    .borrow_mut()
+                    .read_bytes_fallible(addr, &mut mem)
+                    .unwrap_or(0);
+                mem.truncate(nread);
+                target
+                    .borrow()
+                    .vm()
+                    .replace_breakpoints_with_original_values(&mut mem, RemotePtr::cast(addr));
+                self.dbg().reply_get_mem(&mem);
+            }
+            DREQ_SET_MEM => {
+                // gdb has been observed to send requests of length 0 at
+                // odd times
+                // (e.g. before sending the magic write to create a checkpoint)
+                if req.mem().len == 0 {
+                    self.dbg().reply_set_mem(true);
+                    return;
+                }
+                // We only allow the debugger to write memory if the
+                // memory will be written to an diversion session.
+                // Arbitrary writes to replay sessions cause
+                // divergence.
+                if !session.is_diversion() {
+                    log!(
+                        LogError,
+                        "Attempt to write memory outside diversion session"
+                    );
+                    self.dbg().reply_set_mem(false);
+                    return;
+                }
+                log!(
+                    LogDebug,
+                    "Writing {} bytes to {:#x}",
+                    req.mem().len,
+                    req.mem().addr
+                );
+                // TODO fallible
+                target.borrow_mut().write_bytes_helper(
+                    RemotePtr::new_from_val(req.mem().addr),
+                    &req.mem().data,
+                    None,
+                    Default::default(),
+                );
+                self.dbg().reply_set_mem(true);
+            }
+            DREQ_SEARCH_MEM => {
+                let where_ =
+                    MemoryRange::new_range(RemotePtr::new_from_val(req.mem().addr), req.mem().len);
+                let found = search_memory(&target, where_, &req.mem().data);
+                self.dbg()
+                    .reply_search_mem(found.is_some(), found.unwrap_or(RemotePtr::null()));
+            }
+            DREQ_GET_REG => {
+                let reg = {
+                    let mut tb = target.borrow_mut();
+                    let extra_regs = tb.extra_regs_ref().clone();
+                    GdbServer::get_reg(tb.regs_ref(), &extra_regs, req.reg().name)
+                };
+                self.dbg().reply_get_reg(&reg);
+            }
+            DREQ_GET_REGS => {
+                let (regs, extra_regs) = {
+                    let mut tb = target.borrow_mut();
+                    let extra_regs = tb.extra_regs_ref().clone();
+                    (tb.regs_ref().clone(), extra_regs)
+                };
+                self.dispatch_regs_request(&regs, &extra_regs);
+            }
+            DREQ_SET_REG => {
+                if !session.is_diversion() {
+                    // gdb sets orig_eax to -1 during a restart. For a
+                    // replay session this is not correct (we might be
+                    // restarting from an rd checkpoint inside a system
+                    // call, and we must not tamper with replay state), so
+                    // just ignore it.
+                    let arch = target.borrow().arch();
+                    let name = req.reg().name;
+                    if (arch == SupportedArch::X86 && name == DREG_ORIG_EAX)
+                        || (arch == SupportedArch::X64 && name == DREG_ORIG_RAX)
+                    {
+                        self.dbg().reply_set_reg(true);
+                        return;
+                    }
+                    log!(
+                        LogError,
+                        "Attempt to write register outside diversion session"
+                    );
+                    self.dbg().reply_set_reg(false);
+                    return;
+                }
+                if req.reg().defined {
+                    let mut tb = target.borrow_mut();
+                    let mut regs = tb.regs_ref().clone();
+                    regs.write_register(&req.reg().as_bytes(), req.reg().name);
+                    tb.set_regs(&regs);
+                }
+                // currently infallible
+                self.dbg().reply_set_reg(true);
+            }
+            DREQ_GET_STOP_REASON => {
+                let threadid = get_threadid_from_tuid(&**session, self.last_continue_tuid);
+                let sig = Sig::try_from(self.stop_siginfo.si_signo).ok();
+                self.dbg().reply_get_stop_reason(threadid, sig);
+            }
+            DREQ_SET_SW_BREAK => {
+                {
+                    let tb = target.borrow();
+                    ed_assert_eq!(
+                        &tb,
+                        req.watch().kind as usize,
+                        bkpt_instruction_length(tb.arch()),
+                        "Debugger setting bad breakpoint insn"
+                    );
+                }
+                let addr = RemoteCodePtr::from_val(req.watch().addr);
+                let ok = if session.is_diversion() {
+                    // Breakpoints gdb inserts during a diversion only live as
+                    // long as the diversion does.
+                    let vm = target.borrow().vm_shr_ptr();
+                    vm.add_breakpoint(target.borrow_mut().as_mut(), addr, BreakpointType::BkptUser)
+                } else {
+                    self.timeline.add_breakpoint(
+                        target.borrow_mut().as_mut(),
+                        addr,
+                        breakpoint_condition(req),
+                    )
+                };
+                self.dbg().reply_watchpoint_request(ok);
+            }
+            DREQ_SET_HW_BREAK | DREQ_SET_RD_WATCH | DREQ_SET_WR_WATCH | DREQ_SET_RDWR_WATCH => {
+                let addr = RemotePtr::new_from_val(req.watch().addr);
+                let num_bytes = req.watch().kind as usize;
+                let ok = if session.is_diversion() {
+                    let vm = target.borrow().vm_shr_ptr();
+                    vm.add_watchpoint(
+                        addr,
+                        num_bytes,
+                        watchpoint_type(req.type_),
+                        target.borrow_mut().as_mut(),
+                    )
+                } else {
+                    self.timeline.add_watchpoint(
+                        target.borrow_mut().as_mut(),
+                        addr,
+                        num_bytes,
+                        watchpoint_type(req.type_),
+                        breakpoint_condition(req),
+                    )
+                };
+                self.dbg().reply_watchpoint_request(ok);
+            }
+            DREQ_REMOVE_SW_BREAK => {
+                let addr = RemoteCodePtr::from_val(req.watch().addr);
+                if session.is_diversion() {
+                    let vm = target.borrow().vm_shr_ptr();
+                    vm.remove_breakpoint(
+                        addr,
+                        BreakpointType::BkptUser,
+                        target.borrow_mut().as_mut(),
+                    );
+                } else {
+                    self.timeline
+                        .remove_breakpoint(target.borrow_mut().as_mut(), addr);
+                }
+                self.dbg().reply_watchpoint_request(true);
+            }
+            DREQ_REMOVE_HW_BREAK
+            | DREQ_REMOVE_RD_WATCH
+            | DREQ_REMOVE_WR_WATCH
+            | DREQ_REMOVE_RDWR_WATCH => {
+                let addr = RemotePtr::new_from_val(req.watch().addr);
+                let num_bytes = req.watch().kind as usize;
+                if session.is_diversion() {
+                    let vm = target.borrow().vm_shr_ptr();
+                    vm.remove_watchpoint(
+                        addr,
+                        num_bytes,
+                        watchpoint_type(req.type_),
+                        target.borrow_mut().as_mut(),
+                    );
+                } else {
+                    self.timeline.remove_watchpoint(
+                        target.borrow_mut().as_mut(),
+                        addr,
+                        num_bytes,
+                        watchpoint_type(req.type_),
+                    );
+                }
+                self.dbg().reply_watchpoint_request(true);
+            }
+            DREQ_READ_SIGINFO => {
+                log!(LogWarn, "READ_SIGINFO request outside of diversion session");
+                self.dbg().reply_read_siginfo(&[]);
+            }
+            DREQ_WRITE_SIGINFO => {
+                log!(
+                    LogWarn,
+                    "WRITE_SIGINFO request outside of diversion session"
+                );
+                self.dbg().reply_write_siginfo();
+            }
+            DREQ_RR_CMD => {
+                let text = GdbCommandHandler::process_command(self, &target, req.text());
+                self.dbg().reply_rd_cmd(&text);
+            }
+            DREQ_QSYMBOL => {
+                // We don't need any symbols from gdb.
+                self.dbg().qsymbols_finished();
+            }
+            DREQ_TLS => {
+                // @TODO We don't have thread_db support so we can't look up TLS addresses.
+                self.dbg().reply_tls_addr(false, RemotePtr::null());
+            }
+            _ => fatal!("Unknown debugger request {}", req.type_),
+        }
+    }
+
+    fn dispatch_regs_request(&mut self, regs: &Registers, extra_regs: &ExtraRegisters) {
+        // Send values for all the registers we sent XML register descriptions for.
+        // Those descriptions are controlled by GdbConnection::cpu_features().
+        let have_avx = self.dbg().cpu_features() & GdbConnection::CPU_AVX != 0;
+        let end: GdbRegister = match regs.arch() {
+            SupportedArch::X86 => {
+                if have_avx {
+                    DREG_YMM7H
+                } else {
+                    DREG_ORIG_EAX
+                }
+            }
+            SupportedArch::X64 => {
+                if have_avx {
+                    DREG_64_YMM15H
+                } else {
+                    DREG_GS_BASE
+                }
+            }
+        };
+        let mut rs: Vec<GdbRegisterValue> = Vec::new();
+        for regno in 0..=end.as_usize() as u32 {
+            let r = GdbRegister::try_from(regno).unwrap();
+            rs.push(GdbServer::get_reg(regs, extra_regs, r));
+        }
+        self.dbg().reply_get_regs(&rs);
+    }
+
+    /// If `break_status` indicates a stop that we should report to gdb,
+    /// report it. Returns true if a stop was reported.
+    fn maybe_notify_stop(&mut self, req: &GdbRequest, break_status: &BreakStatus) -> bool {
+        let mut do_stop = false;
+        let mut watch_addr: Option<usize> = None;
+        if !break_status.watchpoints_hit.is_empty() {
+            do_stop = true;
+            self.stop_siginfo = Default::default();
+            self.stop_siginfo.si_signo = SIGTRAP;
+            let addr = break_status.watchpoints_hit[0].addr;
+            watch_addr = Some(addr.as_usize());
+            log!(LogDebug, "Stopping for watchpoint at {}", addr);
+        }
+        if break_status.breakpoint_hit || break_status.singlestep_complete {
+            do_stop = true;
+            self.stop_siginfo = Default::default();
+            self.stop_siginfo.si_signo = SIGTRAP;
+            if break_status.breakpoint_hit {
+                log!(LogDebug, "Stopping for breakpoint");
+            } else {
+                log!(LogDebug, "Stopping for singlestep");
+            }
+        }
+        if let Some(siginfo) = break_status.signal.as_ref() {
+            let sig = Sig::try_from(siginfo.si_signo).ok();
+            // Signals gdb asked us to pass don't stop execution.
+            if sig.map_or(true, |s| !self.dbg().is_pass_signal(s)) {
+                do_stop = true;
+                self.stop_siginfo = **siginfo;
+                log!(LogDebug, "Stopping for signal {:?}", sig);
+            }
+        }
+        let reached_start =
+            req.cont().run_direction == RunDirection::RunBackward && break_status.task_exit;
+        if (is_last_thread_exit(break_status) || reached_start)
+            && self.dbg().features().reverse_execution
+        {
+            do_stop = true;
+            self.stop_siginfo = Default::default();
+            if req.cont().run_direction == RunDirection::RunForward {
+                // The exit of the last task in a thread group generates a fake SIGKILL,
+                // when reverse-execution is enabled, because users often want to run
+                // backwards from the end of the task.
+                self.stop_siginfo.si_signo = SIGKILL;
+                log!(LogDebug, "Stopping for synthetic SIGKILL");
+            } else {
+                // The start of the debuggee task-group should trigger a silent stop.
+                self.stop_siginfo.si_signo = 0;
+                log!(
+                    LogDebug,
+                    "Stopping at start of execution while running backwards"
+                );
+            }
+        }
+        let mut maybe_t = break_status.task.as_ref().and_then(|w| w.upgrade());
+        if let Some(in_exec_task) = self.is_in_exec() {
+            do_stop = true;
+            self.stop_siginfo = Default::default();
+            maybe_t = Some(in_exec_task);
+            log!(LogDebug, "Stopping at exec");
+        }
+        if self.interrupt_pending && !do_stop {
+            if let Some(t) = maybe_t.as_ref() {
+                if t.borrow().thread_group().tguid() == self.debuggee_tguid {
+                    do_stop = true;
+                    self.stop_siginfo = Default::default();
+                    log!(LogDebug, "Stopping for interrupt");
+                }
+            }
+        }
+        match maybe_t {
+            Some(t) if do_stop && t.borrow().thread_group().tguid() == self.debuggee_tguid => {
+                // Notify the debugger and process any new requests
+                // that might have triggered before resuming.
+                let threadid = get_threadid(&**t.borrow());
+                let sig = Sig::try_from(self.stop_siginfo.si_signo).ok();
+                self.dbg().notify_stop(threadid, sig, watch_addr);
+                let tuid = t.borrow().tuid();
+                self.last_query_tuid = tuid;
+                self.last_continue_tuid = tuid;
+                self.interrupt_pending = false;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Report a stop of the last continued thread (or no thread at all) with
+    /// `sig`.
+    fn notify_stop_internal(
+        &mut self,
+        session: &dyn Session,
+        sig: Option<Sig>,
+        watch_addr: Option<usize>,
+    ) {
+        let threadid = get_threadid_from_tuid(session, self.last_continue_tuid);
+        self.stop_siginfo = Default::default();
+        self.stop_siginfo.si_signo = sig.map_or(0, |s| s.as_raw());
+        self.dbg().notify_stop(threadid, sig, watch_addr);
+        self.interrupt_pending = false;
+    }
+
+    /// If the next replay step is the successful exit of an execve(), returns
+    /// the task doing the exec.
+    fn is_in_exec(&self) -> Option<TaskSharedPtr> {
+        let replay = self.current_replay();
+        let t = replay.current_task()?;
+        let syscallno = syscall_number_for_execve(t.borrow().arch());
+        if replay.next_step_is_successful_syscall_exit(syscallno) {
+            Some(t)
+        } else {
+            None
+        }
+    }
+
+    /// Restart replay, either at the point the debugger was attached or at
+    /// the event gdb asked for.
+    fn restart_session(&mut self, req: &GdbRequest) {
+        debug_assert_eq!(req.type_, DREQ_RESTART);
+
+        self.in_debuggee_end_state = false;
+        self.remove_breakpoints_and_watchpoints();
+
+        match req.restart().type_ {
+            GdbRestartType::RestartFromCheckpoint => {
+                let maybe_checkpoint = if req.restart().param >= 0 {
+                    self.dbg().get_checkpoint(req.restart().param as u32)
+                } else {
+                    None
+                };
+                let checkpoint = match maybe_checkpoint {
+                    Some(checkpoint) => checkpoint,
+                    None => {
+                        // The gdb `restart` command checks the checkpoint
+                        // first, see invoke_restart(). A failed "run" can only
+                        // be answered with an error code.
+                        log!(
+                            LogInfo,
+                            "Checkpoint {} not found",
+                            req.restart().param_str.to_string_lossy()
+                        );
+                        self.failed_restart = true;
+                        self.dbg().notify_restart_failed();
+                        return;
+                    }
+                };
+
+                self.files.clear();
+                self.timeline.restore_checkpoint(&checkpoint);
+                let mark = self.timeline.mark();
+                let before_barrier = match self.timeline.reverse_execution_barrier() {
+                    Some(barrier) => mark.time() < barrier.time(),
+                    None => false,
+                };
+                if before_barrier {
+                    // We restarted from a later event since this checkpoint was
+                    // taken. Don't leave the checkpoint outside the range that
+                    // reverse execution can reach.
+                    self.timeline.set_reverse_execution_barrier(mark.clone());
+                }
+                self.last_query_tuid = mark.tuid();
+                self.last_continue_tuid = mark.tuid();
+                self.failed_restart = false;
+                // gdb will ask for the stop reason after the restart.
+                self.stop_siginfo = Default::default();
+                return;
+            }
+            GdbRestartType::RestartFromPrevious => {
+                if let Some(mark) = self.debugger_restart_mark.clone() {
+                    self.files.clear();
+                    self.timeline.seek_to_mark(&mark);
+                    self.last_query_tuid = mark.tuid();
+                    self.last_continue_tuid = mark.tuid();
+                    self.failed_restart = false;
+                    // gdb will ask for the stop reason after the restart.
+                    self.stop_siginfo = Default::default();
+                    return;
+                }
+            }
+            GdbRestartType::RestartFromEvent => {
+                // Note that we don't reset the target pid; we intentionally keep targeting
+                // the same process no matter what is running when we hit the event.
+                self.target.event = min(
+                    self.final_event.saturating_sub(1),
+                    max(req.restart().param, 0) as FrameTime,
+                );
+            }
+        }
+
+        STOP_REPLAYING_TO_TARGET.store(false, Ordering::SeqCst);
+        self.failed_restart = false;
+
+        self.files.clear();
+        self.timeline.seek_to_before_event(self.target.event);
+
+        loop {
+            let result = self.replay_step_forward(RunCommand::RunContinue, self.target.event);
+            // We should never reach the end of the trace without hitting the stop
+            // condition below.
+            debug_assert!(result.status != ReplayStatus::ReplayExited);
+            if let Some(t) = result.break_status.task.as_ref().and_then(|w| w.upgrade()) {
+                if is_last_thread_exit(&result.break_status)
+                    && Some(t.borrow().thread_group().tgid) == self.target.pid
+                {
+                    // Debuggee task is about to exit. Stop here.
+                    self.in_debuggee_end_state = true;
+                    break;
+                }
+            }
+            if self.at_target() {
+                break;
+            }
+        }
+        log!(LogDebug, "Reached target");
+        self.activate_debugger();
+        // gdb will ask for the stop reason after the restart.
+        self.stop_siginfo = Default::default();
+    }
+
+    /// Remove all user breakpoints and watchpoints from every address space in
+    /// the session.
+    fn remove_breakpoints_and_watchpoints(&mut self) {
+        self.timeline.remove_breakpoints_and_watchpoints();
+    }
+
+    /// Open `file_name` (as seen by the task gdb last selected with vFile:setfs,
+    /// or the last continued task) and return the fd number we report to gdb,
+    /// or -1 on failure.
+    fn open_file(&mut self, file_name: &OsStr) -> i32 {
+        let session = self.current_session();
+        let maybe_t = if self.file_scope_pid > 0 {
+            session
+                .find_thread_group_from_pid(self.file_scope_pid)
+                .and_then(|tg| tg.borrow().task_set().iter().next())
+        } else {
+            session.find_task_from_task_uid(self.last_continue_tuid)
+        };
+        let t = match maybe_t {
+            Some(t) => t,
+            None => return -1,
+        };
+        let mut path = OsString::from(format!("/proc/{}/root", t.borrow().tid));
+        path.push(file_name);
+        let fd = ScopedFd::open_path(path.as_os_str(), OFlag::O_RDONLY);
+        if !fd.is_open() {
+            return -1;
+        }
+        let mut ret_fd = 0;
+        while self.files.contains_key(&ret_fd) {
+            ret_fd += 1;
+        }
+        self.files.insert(ret_fd, fd);
+        ret_fd
+    }
+}
+
+fn await_connection(t: &dyn Task, listen_fd: &ScopedFd) -> GdbConnection {
+    let features = GdbConnectionFeatures {
+        reverse_execution: true,
+    };
+    let mut dbg = GdbConnection::new(t.tgid(), features);
+    dbg.set_cpu_features(get_cpu_features(t.arch()));
+    dbg.await_debugger(listen_fd);
+    dbg
+}
+
+fn get_cpu_features(arch: SupportedArch) -> u32 {
+    let mut cpu_features = match arch {
+        SupportedArch::X86 => 0,
+        SupportedArch::X64 => GdbConnection::CPU_X86_64,
+    };
+
+    let cpuid_data = cpuid(CPUID_GETEXTENDEDFEATURES, 0);
+    if (cpuid_data.ecx & PKU_FEATURE_FLAG) == PKU_FEATURE_FLAG {
+        // PKU (Skylake) implies AVX (Sandy Bridge).
+        cpu_features |= GdbConnection::CPU_AVX | GdbConnection::CPU_PKU;
+        return cpu_features;
+    }
+
+    let cpuid_data = cpuid(CPUID_GETFEATURES, 0);
+    // We're assuming here that AVX support on the system making the recording
+    // is the same as the AVX support during replay. But if that's not true,
+    // rd is totally broken anyway.
+    if (cpuid_data.ecx & AVX_FEATURE_FLAG) == AVX_FEATURE_FLAG {
+        cpu_features |= GdbConnection::CPU_AVX;
+    }
+
+    cpu_features
+}
+
+fn get_threadid(t: &dyn Task) -> GdbThreadId {
+    GdbThreadId::new(t.tgid(), t.rec_tid)
+}
+
+fn get_threadid_from_tuid(session: &dyn Session, tuid: TaskUid) -> GdbThreadId {
+    match session.find_task_from_task_uid(tuid) {
+        Some(t) => get_threadid(&**t.borrow()),
+        None => GdbThreadId::new(tuid.tid(), tuid.tid()),
+    }
+}
+
+fn matches_threadid(t: &dyn Task, target: GdbThreadId) -> bool {
+    (target.pid <= 0 || target.pid == t.tgid()) && (target.tid <= 0 || target.tid == t.rec_tid)
+}
+
+fn compute_run_command_from_actions(t: &dyn Task, req: &GdbRequest) -> RunCommand {
+    for action in &req.cont().actions {
+        if matches_threadid(t, action.target) {
+            // We can only run task `t`; neither diversion nor replay sessions
+            // support running multiple threads. So even if gdb tells us to continue
+            // multiple threads, we don't do that.
+            return if action.type_ == GdbActionType::ActionStep {
+                RunCommand::RunSinglestep
+            } else {
+                RunCommand::RunContinue
+            };
+        }
+    }
+    // gdb told us to run (or step) some thread that's not `t`, without resuming
+    // `t`. It sometimes does this even though its target thread is entering a
+    // blocking syscall and `t` must run before gdb's target thread can make
+    // progress. So, allow `t` to run anyway.
+    RunCommand::RunContinue
+}
+
+fn is_last_thread_exit(break_status: &BreakStatus) -> bool {
+    // The task set may be empty if the task has already exited.
+    break_status.task_exit
+        && break_status
+            .task
+            .as_ref()
+            .and_then(|w| w.upgrade())
+            .map_or(true, |t| t.borrow().thread_group().task_set().len() <= 1)
+}
+
+fn watchpoint_type(req: GdbRequestType) -> WatchType {
+    match req {
+        DREQ_SET_HW_BREAK | DREQ_REMOVE_HW_BREAK => WatchType::WatchExec,
+        DREQ_SET_WR_WATCH | DREQ_REMOVE_WR_WATCH => WatchType::WatchWrite,
+        // x86 can't watch just reads; read/write is the closest we can do.
+        DREQ_REMOVE_RDWR_WATCH | DREQ_SET_RDWR_WATCH | DREQ_REMOVE_RD_WATCH | DREQ_SET_RD_WATCH => {
+            WatchType::WatchReadWrite
+        }
+        _ => fatal!("Unknown dbg request {}", req),
+    }
+}
+
+/// A breakpoint condition made of the agent expressions gdb sent with a
+/// breakpoint or watchpoint request. The condition holds if any of the
+/// expressions does.
+struct GdbServerBreakpointCondition {
+    expressions: Vec<GdbExpression>,
+}
+
+impl BreakpointCondition for GdbServerBreakpointCondition {
+    fn evaluate(&self, t: &mut dyn Task) -> bool {
+        for e in &self.expressions {
+            let mut v = GdbExpressionValue::new(0);
+            // Break if evaluation fails or the result is nonzero
+            if !e.evaluate(t, &mut v) || v != GdbExpressionValue::new(0) {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+fn breakpoint_condition(req: &GdbRequest) -> Option<Box<dyn BreakpointCondition>> {
+    if req.watch().conditions.is_empty() {
+        return None;
+    }
+    Some(Box::new(GdbServerBreakpointCondition {
+        expressions: req
+            .watch()
+            .conditions
+            .iter()
+            .map(|c| GdbExpression::new(c))
+            .collect(),
+    }))
+}
+
+fn bkpt_instruction_length(arch: SupportedArch) -> usize {
+    match arch {
+        SupportedArch::X86 | SupportedArch::X64 => 1,
+    }
+}
+
+fn search_memory(
+    t: &TaskSharedPtr,
+    where_: MemoryRange,
+    find_bytes: &[u8],
+) -> Option<RemotePtr<Void>> {
+    if find_bytes.is_empty() {
+        return None;
+    }
+    let mut ranges: Vec<MemoryRange> = Vec::new();
+    for (_, m) in &t.borrow().vm().maps() {
+        let r = MemoryRange::from_range(m.map.start(), m.map.end() + find_bytes.len() - 1)
+            .intersect(&where_);
+        ranges.push(r);
+    }
+
+    let mut buf = vec![0u8; page_size() + find_bytes.len() - 1];
+    for mut r in ranges {
+        // We basically read page by page here, but we read past the end of the
+        // page to handle the case where a found string crosses page boundaries.
+        // This approach isn't great for handling long search strings but gdb's find
+        // command isn't really suited to that.
+        // Reading page by page lets us avoid problems where some pages in a
+        // mapping aren't readable (e.g. reading beyond end of file).
+        while r.size() >= find_bytes.len() {
+            let len = min(buf.len(), r.size());
+            let nread = t
+                .borrow_mut()
+                .read_bytes_fallible(r.start(), &mut buf[0..len])
+                .unwrap_or(0);
+            if nread >= find_bytes.len() {
+                if let Some(offset) = find(&buf[0..nread], find_bytes) {
+                    return Some(r.start() + offset);
+                }
+            }
+            let next_page = floor_page_size(r.start().as_usize()) + page_size();
+            r = MemoryRange::from_range(min(r.end(), RemotePtr::new_from_val(next_page)), r.end());
+        }
+    }
+    None
+}
+
+fn push_default_gdb_options(vec: &mut Vec<OsString>) {
+    // The gdb protocol uses the "vRun" packet to reload
+    // remote targets.  The packet is specified to be like
+    // "vCont", in which gdb waits infinitely long for a
+    // stop reply packet.  But in practice, gdb client
+    // expects the vRun to complete within the remote-reply
+    // timeout, after which it issues vCont.  The timeout
+    // causes gdb<-->rd communication to go haywire.
+    //
+    // rd can take a very long time indeed to send the
+    // stop-reply to gdb after restarting replay; the time
+    // to reach a specified execution target is
+    // theoretically unbounded.  Timing out on vRun is
+    // technically a gdb bug, but because the rd replay and
+    // the gdb reload models don't quite match up, we'll
+    // work around it on the rd side by disabling the
+    // remote-reply timeout.
+    vec.push("-l".into());
+    vec.push("10000".into());
+    // For now, avoid requesting binary files through vFile. That is slow and
+    // hard to make work correctly, because gdb requests files based on the
+    // names it sees in memory and in ELF, and those names may be symlinks to
+    // the filenames in the trace, so it's hard to match those names to files in
+    // the trace.
+    vec.push("-ex".into());
+    vec.push("set sysroot /".into());
+}
+
+fn push_target_remote_cmd(vec: &mut Vec<OsString>, host: &OsStr, port: u16) {
+    vec.push("-ex".into());
+    // If we omit the address, then gdb can try to resolve "localhost" which
+    // in some broken environments may not actually resolve to the local host
+    let mut cmd = OsString::from("target extended-remote ");
+    cmd.push(host);
+    cmd.push(format!(":{}", port));
+    vec.push(cmd);
+}
+
+/// Returns true if the gdb command `option` needs the remote target to be
+/// connected before it can run (so we need to insert our "target" command
+/// before it).
+fn needs_target(option: &OsStr) -> bool {
+    option.as_bytes().starts_with(b"continue")
+}
+
+/// Write `macros` to an unlinked temporary file and return a path that gdb can
+/// use to read it.
+fn create_gdb_command_file(macros: &str) -> OsString {
+    let mut template = tmp_dir();
+    template.push("/rd-gdb-commands-XXXXXX");
+    let (fd, path) = match mkstemp(template.as_os_str()) {
+        Ok(res) => res,
+        Err(e) => fatal!("Failed to create gdb command file: {:?}", e),
+    };
+    // This fd is just leaked. That's fine since we only call this once
+    // per rd invocation at the moment.
+    unlink(&path).unwrap_or(());
+    write_all(fd, macros.as_bytes());
+    OsString::from(format!("/proc/{}/fd/{}", getpid(), fd))
+}
+
+/// Copy as much of `src` into `dest` as fits, leaving at least one trailing
+/// NUL byte.
+fn copy_truncated(dest: &mut [u8], src: &[u8]) {
+    let len = min(dest.len() - 1, src.len());
+    unsafe { copy_nonoverlapping(src.as_ptr(), dest.as_mut_ptr(), len) };
+}
+
+/// The bytes of the NUL-terminated string in `buf`.
+fn c_string_bytes(buf: &[u8]) -> &[u8] {
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    &buf[0..len]
 }
@@ -23,8 +23,13 @@ pub enum SupportedArch {
     X64,
 }
 
-pub fn sigaction_sigset_size(_arch: SupportedArch) -> usize {
-    unimplemented!()
+/// The size of the signal mask in the kernel's `struct sigaction`, which is
+/// what `rt_sigaction` and `rt_sigprocmask` expect as their sigsetsize.
+pub fn sigaction_sigset_size(arch: SupportedArch) -> usize {
+    match arch {
+        SupportedArch::X86 => size_of::<x86::kernel_sigset_t>(),
+        SupportedArch::X64 => size_of::<x64::kernel_sigset_t>(),
+    }
 }
 
 impl Default for SupportedArch {
@@ -405,9 +410,15 @@ pub mod x64 {
     // IMPORTANT ! ////////////////////////
     include!("include/base_arch_defns.rs");
 
+    pub type legacy_uid_t = uid_t;
+    pub type legacy_gid_t = gid_t;
+
+    /// The x64 kernel uses the same layout for stat and stat64
+    pub type stat = stat64;
+
     #[repr(C)]
     #[derive(Copy, Clone, Default)]
-    struct stat64 {
+    pub struct stat64 {
         pub st_dev: dev_t,
         pub st_ino: ino_t,
         pub st_nlink: nlink_t,
@@ -854,6 +865,34 @@ pub mod x86 {
     // IMPORTANT ! ////////////////////////
     include!("include/base_arch_defns.rs");
 
+    pub type legacy_uid_t = uint16_t;
+    pub type legacy_gid_t = uint16_t;
+
+    /// The kernel's `struct stat` (not glibc's) used by the x86 stat/lstat/fstat
+    /// syscalls.
+    #[repr(C)]
+    #[derive(Copy, Clone, Default)]
+    pub struct stat {
+        pub st_dev: unsigned_long,
+        pub st_ino: unsigned_long,
+        pub st_mode: unsigned_short,
+        pub st_nlink: unsigned_short,
+        pub st_uid: unsigned_short,
+        pub st_gid: unsigned_short,
+        pub st_rdev: unsigned_long,
+        pub st_size: unsigned_long,
+        pub st_blksize: unsigned_long,
+        pub st_blocks: unsigned_long,
+        pub st_atime: unsigned_long,
+        pub st_atime_nsec: unsigned_long,
+        pub st_mtime: unsigned_long,
+        pub st_mtime_nsec: unsigned_long,
+        pub st_ctime: unsigned_long,
+        pub st_ctime_nsec: unsigned_long,
+        pub __unused4: unsigned_long,
+        pub __unused5: unsigned_long,
+    }
+
     /// @TODO Check this in x86
     #[repr(C, packed)]
     pub struct stat64 {
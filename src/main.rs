@@ -33,15 +33,18 @@ mod kernel_metadata;
 mod perf_counters;
 #[macro_use]
 mod registers;
+mod assembly_templates;
 mod commands;
 mod core;
 mod cpuid_bug_detector;
+mod elf_core_file;
 mod emu_fs;
 mod event;
 pub mod extra_registers;
 mod fast_forward;
 mod fd_table;
 mod file_monitor;
+mod gdb_command_handler;
 mod gdb_connection;
 mod gdb_expression;
 mod gdb_register;
@@ -53,6 +56,7 @@ mod preload_interface;
 mod preload_interface_arch;
 mod rd;
 mod record_signal;
+mod record_syscall;
 mod remote_code_ptr;
 mod remote_ptr;
 mod replay_syscall;
@@ -76,10 +80,13 @@ use crate::{
     commands::{
         build_id_command::BuildIdCommand,
         dump_command::DumpCommand,
+        pack_command::PackCommand,
         ps_command::PsCommand,
         rd_options::{RdOptions, RdSubCommand},
+        recompress_command::RecompressCommand,
         rerun_command::ReRunCommand,
         trace_info_command::TraceInfoCommand,
+        verify_command::VerifyCommand,
         RdCommand,
     },
     perf_counters::init_pmu,
@@ -148,9 +155,18 @@ fn main() -> ExitResult<()> {
         RdSubCommand::Ps { .. } => {
             return PsCommand::new(&options).run();
         }
+        RdSubCommand::Pack { .. } => {
+            return PackCommand::new(&options).run();
+        }
+        RdSubCommand::Recompress { .. } => {
+            return RecompressCommand::new(&options).run();
+        }
         RdSubCommand::Record { .. } => {
             return RecordCommand::new(&options).run();
         }
+        RdSubCommand::Verify { .. } => {
+            return VerifyCommand::new(&options).run();
+        }
         _ => (),
     }
 
@@ -1,8 +1,7 @@
 //! Support tracees that share memory read-only with a non-tracee that
-//! writes to the memory. Currently this just supports limited cases that
-//! suffice for dconf: no remapping, coalescing or splitting of the memory is
-//! allowed (`subrange` below just asserts). It doesn't handle mappings where
-//! the mapping has more pages than the file.
+//! writes to the memory. This supports dconf and other shared memory segments
+//! (e.g. in /dev/shm) that are written by processes outside the recording. It
+//! doesn't handle mappings where the mapping has more pages than the file.
 //!
 //! After such memory is mapped in the tracee, we also map it in rd at `real_mem`
 //! and replace the tracee's mapping with a "shadow buffer" that's only shared
@@ -14,51 +13,215 @@
 //! that if the tracee is woken up by some IPC mechanism (or after sched_yield),
 //! it will get a chance to see updated memory values.
 
-use crate::session::{address_space::address_space, task::record_task::RecordTask};
+use crate::{
+    auto_remote_syscalls::{AutoRemoteSyscalls, PreserveContents::DiscardContents},
+    log::LogLevel::LogDebug,
+    remote_ptr::{RemotePtr, Void},
+    session::{
+        address_space::{address_space, memory_range::MemoryRangeKey},
+        task::record_task::RecordTask,
+    },
+    util::ceil_page_size,
+};
+use libc::{c_void, memcmp, S_IFMT, S_IFREG};
+use nix::sys::mman::{mmap, munmap, MapFlags, ProtFlags};
 use std::{
     cell::RefCell,
+    ffi::OsStr,
+    os::unix::ffi::OsStrExt,
+    ptr::{copy_nonoverlapping, null_mut},
     rc::{Rc, Weak},
+    slice,
 };
 
 pub type MonitoredSharedMemorySharedPtr = Rc<RefCell<MonitoredSharedMemory>>;
 pub type MonitoredSharedMemorySharedWeakPtr = Weak<RefCell<MonitoredSharedMemory>>;
 
+const DCONF_SUFFIX: &[u8] = b"/dconf/user";
+const DEV_SHM_PREFIX: &[u8] = b"/dev/shm/";
+/// PulseAudio hands its clients memfds for the audio buffers. We make the
+/// tracee's own memfd_create("pulseaudio") fail (see record_syscall.rs), but
+/// the server's memfds still get passed to it over a socket.
+const PULSEAUDIO_MEMFD_PREFIX: &[u8] = b"/memfd:pulseaudio";
+
+/// The mapping of the shared memory in rd's address space. It is unmapped when
+/// the MonitoredSharedMemory and all its subranges are gone.
+struct RealMem {
+    addr: *mut c_void,
+    size: usize,
+}
+
+impl Drop for RealMem {
+    fn drop(&mut self) {
+        unsafe { munmap(self.addr, self.size) }.unwrap();
+    }
+}
+
 pub struct MonitoredSharedMemory {
-    real_mem: *mut [u8],
+    real_mem: Rc<RealMem>,
+    /// Offset of this (sub)range within `real_mem`
+    offset: usize,
+    size: usize,
 }
 
 impl MonitoredSharedMemory {
+    /// If `m`, which was just mapped shared from `tracee_fd`, is memory that
+    /// other processes might write to, shadow it and start monitoring it.
     pub fn maybe_monitor(
-        _t: &RecordTask,
-        _filename: &str,
-        _m: &address_space::Mapping,
-        _tracee_fd: i32,
-        _offset: usize,
+        t: &mut RecordTask,
+        file_name: &OsStr,
+        m: &address_space::Mapping,
+        tracee_fd: i32,
+        offset: u64,
     ) {
-        unimplemented!()
+        if !is_externally_shared(file_name.as_bytes()) {
+            return;
+        }
+
+        let size = m.map.size();
+        // Touching pages of `real_mem` beyond the end of the file would SIGBUS.
+        match m.mapped_file_stat {
+            Some(st)
+                if st.st_mode & S_IFMT == S_IFREG
+                    && offset + size as u64 <= ceil_page_size(st.st_size as usize) as u64 => {}
+            _ => {
+                log!(
+                    LogDebug,
+                    "Not monitoring {:?}: mapping extends past the end of the file",
+                    file_name
+                );
+                return;
+            }
+        }
+
+        let mut remote = AutoRemoteSyscalls::new(t);
+        let fd = remote.retrieve_fd(tracee_fd);
+        let real_mem = match unsafe {
+            mmap(
+                null_mut(),
+                size,
+                ProtFlags::PROT_READ,
+                MapFlags::MAP_SHARED,
+                fd.as_raw(),
+                offset as i64,
+            )
+        } {
+            Ok(addr) => addr,
+            Err(e) => fatal!("Failed to map {:?} in rd: {:?}", file_name, e),
+        };
+        log!(LogDebug, "Monitoring shared memory {:?}", file_name);
+
+        let result = Rc::new(RefCell::new(MonitoredSharedMemory::new(real_mem, size)));
+
+        let shared = remote.steal_mapping(m, Some(result));
+        // m may be invalid now
+        unsafe {
+            copy_nonoverlapping(
+                real_mem as *const u8,
+                shared.local_addr.unwrap().as_ptr() as *mut u8,
+                size,
+            );
+        }
     }
 
-    pub fn check_all(_t: &RecordTask) {
-        unimplemented!()
+    /// Check all monitored shared memory in `t`'s address space for changes
+    /// made by processes outside the recording.
+    pub fn check_all(t: &mut RecordTask) {
+        let addrs: Vec<RemotePtr<Void>> = t.vm().monitored_addrs().iter().copied().collect();
+        for a in addrs {
+            let maybe_m = t.vm().mapping_of(a).map(|m| m.clone());
+            if let Some(m) = maybe_m {
+                if let Some(monitored) = m.monitored_shared_memory.clone() {
+                    monitored.borrow().check_for_changes(t, m);
+                }
+            }
+        }
     }
 
-    /// This feature is currently unsupported
-    pub fn subrange(&self, _start: usize, _size: usize) -> MonitoredSharedMemorySharedPtr {
-        assert!(
-            false,
-            "Subranges in monitored shared memory not supported yet!"
-        );
-        unimplemented!()
+    /// A MonitoredSharedMemory for the `size` bytes at `start` within this
+    /// one. It shares our mapping of the real memory.
+    pub fn subrange(&self, start: usize, size: usize) -> MonitoredSharedMemorySharedPtr {
+        debug_assert!(start + size <= self.size);
+        Rc::new(RefCell::new(MonitoredSharedMemory {
+            real_mem: self.real_mem.clone(),
+            offset: self.offset + start,
+            size,
+        }))
     }
 
-    fn check_for_changes(&self, _t: &RecordTask, _m: &address_space::Mapping) {
-        unimplemented!()
+    fn check_for_changes(&self, t: &mut RecordTask, m: address_space::Mapping) {
+        ed_assert_eq!(t, m.map.size(), self.size);
+        let mut local_addr = m.local_addr;
+        if local_addr.is_none() {
+            // reestablish local mapping after a fork or whatever
+            let mut remote = AutoRemoteSyscalls::new(t);
+            let new_start = remote.recreate_shared_mmap(
+                MemoryRangeKey(*m.map),
+                Some(DiscardContents),
+                m.monitored_shared_memory.clone(),
+            );
+            local_addr = remote
+                .vm()
+                .mapping_of(new_start)
+                .and_then(|new_m| new_m.local_addr);
+            if local_addr.is_none() {
+                // The mapping was removed!
+                return;
+            }
+        }
+
+        let local = local_addr.unwrap().as_ptr();
+        let real = unsafe { (self.real_mem.addr as *const u8).add(self.offset) };
+        if unsafe { memcmp(local, real as *const c_void, self.size) } == 0 {
+            return;
+        }
+        log!(
+            LogDebug,
+            "Shared memory at {} changed outside the recording",
+            m.map.start()
+        );
+        let real_data = unsafe { slice::from_raw_parts(real, self.size) };
+        unsafe {
+            copy_nonoverlapping(real, local as *mut u8, self.size);
+        }
+        t.record_local(m.map.start(), real_data);
     }
 
     /// real_mem is pointer within rd's address space to the memory shared between
     /// the tracee (which just becomes a "shadow buffer") and the non-rd process.
     /// See description above.
-    fn new(real_mem: *mut [u8]) -> MonitoredSharedMemory {
-        MonitoredSharedMemory { real_mem }
+    fn new(real_mem: *mut c_void, size: usize) -> MonitoredSharedMemory {
+        MonitoredSharedMemory {
+            real_mem: Rc::new(RealMem {
+                addr: real_mem,
+                size,
+            }),
+            offset: 0,
+            size,
+        }
+    }
+}
+
+/// Is `file_name` memory that processes outside the recording are likely to
+/// write to?
+fn is_externally_shared(file_name: &[u8]) -> bool {
+    file_name.ends_with(DCONF_SUFFIX)
+        || file_name.starts_with(DEV_SHM_PREFIX)
+        || file_name.starts_with(PULSEAUDIO_MEMFD_PREFIX)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn externally_shared_test() {
+        assert!(is_externally_shared(b"/run/user/1000/dconf/user"));
+        assert!(is_externally_shared(b"/dev/shm/sidecar"));
+        assert!(is_externally_shared(b"/memfd:pulseaudio (deleted)"));
+        assert!(!is_externally_shared(b"/memfd:other (deleted)"));
+        assert!(!is_externally_shared(b"/dev/shmem"));
+        assert!(!is_externally_shared(b"/tmp/dev/shm/x"));
+        assert!(!is_externally_shared(b"/home/user/dconf/user.bak"));
     }
 }
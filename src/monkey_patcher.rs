@@ -1,18 +1,71 @@
 use crate::{
     arch::Architecture,
-    preload_interface::syscall_patch_hook,
+    assembly_templates::{
+        X64DLRuntimeResolve,
+        X64DLRuntimeResolve2,
+        X64DLRuntimeResolvePrelude,
+        X64JumpMonkeypatch,
+        X64SyscallStubExtendedJump,
+        X64VsyscallMonkeypatch,
+        X86SyscallStubExtendedJump,
+        X86SyscallStubMonkeypatch,
+        X86SysenterVsyscallImplementation,
+        X86SysenterVsyscallImplementationAMD,
+        X86SysenterVsyscallSyscallHook,
+        X86VsyscallMonkeypatch,
+    },
+    auto_remote_syscalls::AutoRemoteSyscalls,
+    kernel_abi::{get_syscall_instruction_arch, syscall_instruction_length, SupportedArch},
+    kernel_metadata::syscall_name,
+    log::{LogDebug, LogWarn},
+    preload_interface::{
+        syscall_patch_hook,
+        SYSCALLBUF_LIB_FILENAME_32,
+        SYSCALLBUF_LIB_FILENAME_BASE,
+        SYSCALLBUF_LIB_FILENAME_PADDED,
+    },
+    preload_interface_arch::rdcall_init_preload_params,
     remote_code_ptr::RemoteCodePtr,
     remote_ptr::{RemotePtr, Void},
-    session::{address_space::address_space, task::record_task::RecordTask},
+    scoped_fd::ScopedFd,
+    session::{
+        address_space::{address_space, kernel_mapping::KernelMapping, MappingFlags},
+        task::{
+            record_task::RecordTask,
+            task_common::{read_mem, read_val_mem},
+            task_inner::WriteFlags,
+            Task,
+        },
+        Session,
+    },
+    trace::trace_writer::{MappingOrigin, RecordInTrace},
+    util::{find, page_size},
+};
+use goblin::elf::{section_header::SHT_NOBITS, Elf};
+use nix::{
+    fcntl::{readlink, OFlag},
+    sys::mman::{MapFlags, ProtFlags},
 };
 use std::{
-    collections::{HashMap, HashSet},
-    ffi::OsStr,
+    cmp::min,
+    collections::{BTreeMap, HashSet},
+    convert::TryInto,
+    ffi::{OsStr, OsString},
+    fs,
+    mem::size_of,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
 };
 
 const MAX_VDSO_SIZE: usize = 16384;
 const VDSO_ABSOLUTE_ADDRESS: usize = 0xffffe000;
 
+/// Symbol values in the vdso may be absolute, so only the low bits are an
+/// offset into the vdso.
+const VDSO_OFFSET_MASK: usize = 0xffff;
+
+const NOP: u8 = 0x90;
+
 #[derive(Clone)]
 pub struct MonkeyPatcher {
     pub x86_vsyscall: RemotePtr<Void>,
@@ -21,15 +74,17 @@ pub struct MonkeyPatcher {
     pub extended_jump_pages: Vec<ExtendedJumpPage>,
 
     /// Syscalls in the VDSO that we patched to be direct syscalls. These can
-    /// always be safely patched to jump to the syscallbuf.  
+    /// always be safely patched to jump to the syscallbuf.
     pub patched_vdso_syscalls: HashSet<RemoteCodePtr>,
 
     /// Addresses/lengths of syscallbuf stubs.
-    pub syscallbuf_stubs: HashMap<RemotePtr<u8>, usize>,
+    /// DIFF NOTE: A BTreeMap rather than a HashMap so that we can find the stub
+    /// containing an address in is_jump_stub_instruction().
+    pub syscallbuf_stubs: BTreeMap<RemotePtr<u8>, usize>,
 
     /// The list of supported syscall patches obtained from the preload
     /// library. Each one matches a specific byte signature for the instruction(s)
-    /// after a syscall instruction.    
+    /// after a syscall instruction.
     syscall_hooks: Vec<syscall_patch_hook>,
 
     /// The addresses of the instructions following syscalls that we've tried
@@ -37,6 +92,7 @@ pub struct MonkeyPatcher {
     tried_to_patch_syscall_addresses: HashSet<RemoteCodePtr>,
 }
 
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub enum MmapMode {
     MmapExec,
     MmapSyscall,
@@ -84,11 +140,17 @@ impl MonkeyPatcher {
     /// Apply any necessary patching immediately after exec.
     /// In this hook we patch everything that doesn't depend on the preload
     /// library being loaded.
-    pub fn patch_after_exec(_t: &RecordTask) {
-        unimplemented!()
+    pub fn patch_after_exec(&mut self, t: &mut RecordTask) {
+        ed_assert!(
+            t,
+            t.vm().task_set().iter().count() == 1,
+            "Can't have multiple threads immediately after exec!"
+        );
+
+        rd_arch_function_selfless!(patch_after_exec_arch, t.arch(), t, self)
     }
 
-    pub fn patch_at_preload_init(&self, t: &RecordTask) {
+    pub fn patch_at_preload_init(&mut self, t: &mut RecordTask) {
         // NB: the tracee can't be interrupted with a signal while
         // we're processing the rdcall, because it's masked off all
         // signals.
@@ -102,59 +164,461 @@ impl MonkeyPatcher {
     /// and execution should resume normally to execute the patched code.
     /// Zero or more mapping operations are also recorded to the trace and must
     /// be replayed.
-    pub fn try_patch_syscall(_t: &RecordTask) -> bool {
-        unimplemented!()
+    pub fn try_patch_syscall(&mut self, t: &mut RecordTask) -> bool {
+        if self.syscall_hooks.is_empty() {
+            // Syscall hooks not set up yet. Don't spew warnings, and don't
+            // fill tried_to_patch_syscall_addresses with addresses that we might be
+            // able to patch later.
+            return false;
+        }
+        if t.emulated_ptracer.is_some() {
+            // Syscall patching can confuse ptracers, which may be surprised to see
+            // a syscall instruction at the current IP but then when running
+            // forwards, that the syscall occurs deep in the preload library instead.
+            return false;
+        }
+        if t.is_in_traced_syscall() {
+            // Never try to patch the traced-syscall in our preload library!
+            return false;
+        }
+
+        let r = t.regs_ref().clone();
+        let ip = r.ip();
+        if self.tried_to_patch_syscall_addresses.contains(&ip) {
+            return false;
+        }
+
+        // We could examine the current syscall number and if it's not one that
+        // we support syscall buffering for, refuse to patch the syscall instruction.
+        // This would, on the face of it, reduce overhead since patching the
+        // instruction just means a useless trip through the syscall buffering logic.
+        // However, it actually wouldn't help much since we'd still do a switch
+        // on the syscall number in this function instead, and due to context
+        // switching costs any overhead saved would be insignificant.
+        // Also, implementing that would require keeping a buffered-syscalls
+        // list in sync with the preload code, which is unnecessary complexity.
+
+        self.tried_to_patch_syscall_addresses.insert(ip);
+
+        let t_arch = t.arch();
+        let mut arch = t_arch;
+        if !get_syscall_instruction_arch(t, ip.decrement_by_syscall_insn_length(t_arch), &mut arch)
+            || arch != t_arch
+        {
+            log!(
+                LogDebug,
+                "Declining to patch cross-architecture syscall at {}",
+                ip
+            );
+            return false;
+        }
+
+        let mut following_bytes_buf = [0u8; 256];
+        let bytes_count = t
+            .read_bytes_fallible(ip.to_data_ptr::<Void>(), &mut following_bytes_buf)
+            .unwrap_or(0);
+        let following_bytes = &following_bytes_buf[0..bytes_count];
+        let syscallno = r.original_syscallno() as i32;
+        let bytes_to_log = &following_bytes[0..min(bytes_count, size_of::<[u8; 14]>())];
+
+        for hook in self.syscall_hooks.clone().iter() {
+            let next_instruction_length = hook.next_instruction_length as usize;
+            if bytes_count < next_instruction_length
+                || following_bytes[0..next_instruction_length]
+                    != hook.next_instruction_bytes[0..next_instruction_length]
+            {
+                continue;
+            }
+
+            // Search for a following short-jump instruction that targets an
+            // instruction after the syscall. False positives are OK.
+            // glibc-2.23.1-8.fc24.x86_64's __clock_nanosleep needs this.
+            let mut found_potential_interfering_branch = false;
+            // If this was a VDSO syscall we patched, we don't have to worry about
+            // this check since the function doesn't do anything except execute our
+            // syscall and return.
+            // Otherwise the Linux 4.12 VDSO triggers the interfering-branch check.
+            if !self
+                .patched_vdso_syscalls
+                .contains(&ip.decrement_by_syscall_insn_length(arch))
+            {
+                for i in 0..bytes_count.saturating_sub(1) {
+                    let b = following_bytes[i];
+                    // Check for short conditional or unconditional jump
+                    if b == 0xeb || (b >= 0x70 && b < 0x80) {
+                        let offset = i as isize + 2 + following_bytes[i + 1] as i8 as isize;
+                        let interferes = if hook.is_multi_instruction != 0 {
+                            offset >= 0 && offset < next_instruction_length as isize
+                        } else {
+                            offset == 0
+                        };
+                        if interferes {
+                            log!(
+                                LogDebug,
+                                "Found potential interfering branch at {}",
+                                ip.to_data_ptr::<u8>() + i
+                            );
+                            // We can't patch this because it would jump straight back into
+                            // the middle of our patch code.
+                            found_potential_interfering_branch = true;
+                        }
+                    }
+                }
+            }
+
+            if found_potential_interfering_branch {
+                continue;
+            }
+
+            if !safe_for_syscall_patching(ip, ip + next_instruction_length, t) {
+                log!(
+                    LogDebug,
+                    "Temporarily declining to patch syscall at {} because a different task has its ip in the patched range",
+                    ip
+                );
+                self.tried_to_patch_syscall_addresses.remove(&ip);
+                return false;
+            }
+
+            // Get out of executing the current syscall before we patch it.
+            if !t.exit_syscall_and_prepare_restart() {
+                return false;
+            }
+
+            if !patch_syscall_with_hook(self, t, hook) {
+                log!(
+                    LogDebug,
+                    "Could not allocate an extended jump for syscall at {}; it will be executed unpatched",
+                    ip
+                );
+            } else {
+                log!(
+                    LogDebug,
+                    "Patched syscall at {} syscall {} tid {} bytes {:?}",
+                    ip,
+                    syscall_name(syscallno, arch),
+                    t.tid,
+                    bytes_to_log
+                );
+            }
+
+            // Return to caller, which resume normal execution.
+            return true;
+        }
+
+        log!(
+            LogDebug,
+            "Failed to patch syscall at {} syscall {} tid {} bytes {:?}",
+            ip,
+            syscall_name(syscallno, arch),
+            t.tid,
+            bytes_to_log
+        );
+        false
     }
 
     pub fn init_dynamic_syscall_patching(
-        _t: &RecordTask,
-        _syscall_patch_hook_count: usize,
-        _syscall_patch_hooks: RemotePtr<syscall_patch_hook>,
+        &mut self,
+        t: &mut RecordTask,
+        syscall_patch_hook_count: usize,
+        syscall_patch_hooks: RemotePtr<syscall_patch_hook>,
     ) {
-        unimplemented!()
+        if syscall_patch_hook_count > 0 {
+            self.syscall_hooks = read_mem(t, syscall_patch_hooks, syscall_patch_hook_count, None);
+        }
     }
 
-    /// Try to allocate a stub from the sycall patching stub buffer. Returns null
-    /// if there's no buffer or we've run out of free stubs.
-
-    pub fn allocate_stub(_t: &RecordTask, _bytes: usize) -> RemotePtr<u8> {
-        unimplemented!()
+    /// Try to allocate a stub of `bytes` bytes in an extended jump page that is within
+    /// 2GB of `t`'s current ip. Returns null if no such space could be found.
+    pub fn allocate_stub(&mut self, t: &mut RecordTask, bytes: usize) -> RemotePtr<u8> {
+        let from = t.ip().to_data_ptr::<u8>();
+        allocate_extended_jump(t, &mut self.extended_jump_pages, from, bytes)
     }
 
     /// Apply any necessary patching immediately after an mmap. We use this to
     /// patch libpthread.so.
     pub fn patch_after_mmap(
-        _t: &RecordTask,
-        _start: RemotePtr<Void>,
-        _size: usize,
-        _offset_pages: usize,
-        _child_fd: i32,
-        _mode: MmapMode,
+        &mut self,
+        t: &mut RecordTask,
+        start: RemotePtr<Void>,
+        size: usize,
+        offset_pages: usize,
+        child_fd: i32,
+        mode: MmapMode,
     ) {
-        unimplemented!()
+        let file_name = match t.vm().mapping_of(start) {
+            Some(m) if file_may_need_instrumentation(&m) => m.map.fsname().to_owned(),
+            _ => return,
+        };
+
+        let open_fd = if child_fd >= 0 {
+            let fd = t.open_fd(child_fd, OFlag::O_RDONLY);
+            ed_assert!(t, fd.is_open(), "Failed to open child fd {}", child_fd);
+            fd
+        } else {
+            let path = format!(
+                "/proc/{}/map_files/{:x}-{:x}",
+                t.tid,
+                start.as_usize(),
+                start.as_usize() + size
+            );
+            // Reading these directly requires CAP_SYS_ADMIN, so open the link target
+            // instead.
+            let link = match readlink(path.as_str()) {
+                Ok(link) => link,
+                Err(_) => return,
+            };
+            let fd = ScopedFd::open_path(link.as_os_str(), OFlag::O_RDONLY);
+            if !fd.is_open() {
+                return;
+            }
+            fd
+        };
+
+        let data = match fs::read(format!("/proc/self/fd/{}", open_fd.as_raw())) {
+            Ok(data) => data,
+            Err(_) => return,
+        };
+        let reader = ElfReader::new(data);
+        // Check for symbols first in the library itself, regardless of whether
+        // there is a debuglink.  For example, on Fedora 26, the .symtab and
+        // .strtab sections are stripped from the debuginfo file for
+        // libpthread.so.
+        let mut syms = reader.read_symbols(false);
+        if syms.is_empty() {
+            if let Some(debug_reader) = reader.open_debug_file(&file_name) {
+                syms = debug_reader.read_symbols(false);
+            }
+        }
+        for sym in &syms {
+            if sym.name == "__elision_aconf" {
+                // Setting __elision_aconf.retry_try_xbegin to zero means that
+                // pthread rwlocks don't try to use elision at all. See ELIDE_LOCK
+                // in glibc's elide.h.
+                set_and_record_bytes(
+                    t,
+                    &reader,
+                    sym.addr + 8,
+                    &0i32.to_le_bytes(),
+                    start,
+                    size,
+                    offset_pages,
+                );
+            }
+            if sym.name == "elision_init" {
+                // Make elision_init return without doing anything. This means
+                // the __elision_available and __pthread_force_elision flags will
+                // remain zero, disabling elision for mutexes. See glibc's
+                // elision-conf.c.
+                set_and_record_bytes(t, &reader, sym.addr, &[0xc3], start, size, offset_pages);
+            }
+            // The following operations can only be applied once because after the
+            // patch is applied the code no longer matches the expected template.
+            // For replaying a replay to work, we need to only apply these changes
+            // during a real exec, not during the mmap operations performed when rd
+            // replays an exec.
+            if mode == MmapMode::MmapExec
+                && (sym.name == "_dl_runtime_resolve_fxsave"
+                    || sym.name == "_dl_runtime_resolve_xsave"
+                    || sym.name == "_dl_runtime_resolve_xsavec")
+            {
+                patch_dl_runtime_resolve(self, t, &reader, sym.addr, start, size, offset_pages);
+            }
+        }
+    }
+
+    /// Return true if `p` is inside one of the syscallbuf stubs we've written
+    /// into an extended jump page.
+    pub fn is_jump_stub_instruction(&self, p: RemoteCodePtr) -> bool {
+        let pp = p.to_data_ptr::<u8>();
+        match self.syscallbuf_stubs.range(..=pp).next_back() {
+            Some((&stub_start, &stub_size)) => pp < stub_start + stub_size,
+            None => false,
+        }
+    }
+}
+
+fn patch_at_preload_init_arch<Arch: Architecture>(t: &mut RecordTask, patcher: &mut MonkeyPatcher) {
+    let params_addr = t.regs_ref().arg1();
+    let params = read_val_mem(
+        t,
+        RemotePtr::<rdcall_init_preload_params<Arch>>::new_from_val(params_addr),
+        None,
+    );
+    if params.syscallbuf_enabled == 0 {
+        return;
     }
 
-    pub fn is_jump_stub_instruction(_p: RemoteCodePtr) -> bool {
-        unimplemented!()
+    if Arch::arch() == SupportedArch::X86 {
+        let syscallhook_vsyscall_entry = Arch::as_rptr(params.syscallhook_vsyscall_entry);
+        // We patch __kernel_vsyscall to jump to our
+        // hook, by directly jumping to the absolute
+        // address of our trampoline.
+        let mut patch = [0u8; X86SysenterVsyscallSyscallHook::SIZE];
+        // We're patching in a relative jump, so we need to compute the offset from
+        // the end of the jump to our actual destination.
+        let relative_addr = syscallhook_vsyscall_entry
+            .as_usize()
+            .wrapping_sub(patcher.x86_vsyscall.as_usize() + patch.len());
+        X86SysenterVsyscallSyscallHook::substitute(&mut patch, relative_addr as u32);
+        write_and_record_bytes(t, patcher.x86_vsyscall, &patch);
+        log!(
+            LogDebug,
+            "monkeypatched __kernel_vsyscall to jump to {}",
+            syscallhook_vsyscall_entry
+        );
     }
+
+    patcher.init_dynamic_syscall_patching(
+        t,
+        params.syscall_patch_hook_count as usize,
+        Arch::as_rptr(params.syscall_patch_hooks),
+    );
+}
+
+/// An ELF image, either the vdso or a mapped file, read into our memory.
+///
+/// DIFF NOTE: rr has separate ElfReader, ElfFileReader, VdsoReader and SymbolTable
+/// classes. We simply parse the whole image with goblin when we need something.
+struct ElfReader {
+    data: Vec<u8>,
 }
 
-fn patch_at_preload_init_arch<Arch: Architecture>(_t: &RecordTask, _patcher: &MonkeyPatcher) {
-    unimplemented!()
+struct Symbol {
+    name: String,
+    addr: usize,
 }
 
-struct VdsoReader;
-/// @TODO Remove
-struct ElfReader;
-/// @TODO Remove
-struct SymbolTable;
+impl ElfReader {
+    fn new(data: Vec<u8>) -> ElfReader {
+        ElfReader { data }
+    }
+
+    /// Read the vdso of `t`'s address space.
+    fn new_from_vdso(t: &mut RecordTask) -> ElfReader {
+        let vdso = t.vm().vdso();
+        ElfReader::new(read_mem(t, vdso.start(), vdso.size(), None))
+    }
+
+    /// Read the dynamic symbols (.dynsym/.dynstr) if `dynamic` is true,
+    /// otherwise the static ones (.symtab/.strtab).
+    fn read_symbols(&self, dynamic: bool) -> Vec<Symbol> {
+        let elf = match Elf::parse(&self.data) {
+            Ok(elf) => elf,
+            Err(e) => {
+                log!(LogDebug, "Could not parse ELF image: {:?}", e);
+                return Vec::new();
+            }
+        };
+        let (syms, strtab) = if dynamic {
+            (&elf.dynsyms, &elf.dynstrtab)
+        } else {
+            (&elf.syms, &elf.strtab)
+        };
+        syms.iter()
+            .filter_map(|sym| {
+                strtab
+                    .get(sym.st_name)
+                    .and_then(|r| r.ok())
+                    .map(|name| Symbol {
+                        name: name.to_owned(),
+                        addr: sym.st_value as usize,
+                    })
+            })
+            .collect()
+    }
+
+    /// The file name and CRC in the .gnu_debuglink section, if there is one.
+    fn read_debuglink(&self) -> Option<(OsString, u32)> {
+        let (start, end) = self.find_section_file_offsets(".gnu_debuglink")?;
+        let section = self.data.get(start..end)?;
+        // A NUL terminated file name, padded to 4 bytes, followed by the CRC.
+        let name_len = section.iter().position(|&c| c == 0)?;
+        let crc_offset = (name_len + 4) & !3;
+        let crc_bytes = section.get(crc_offset..crc_offset + 4)?;
+        Some((
+            OsStr::from_bytes(&section[0..name_len]).to_owned(),
+            u32::from_le_bytes(crc_bytes.try_into().unwrap()),
+        ))
+    }
+
+    /// Find and read the separate debug file for the ELF file `elf_file_name`
+    /// (this image) via its .gnu_debuglink.
+    ///
+    /// DIFF NOTE: Like rr, we only look in /usr/lib/debug/<dir of elf_file_name>/.
+    fn open_debug_file(&self, elf_file_name: &OsStr) -> Option<ElfReader> {
+        if !elf_file_name.as_bytes().starts_with(b"/") {
+            return None;
+        }
+        let (debuglink_name, debuglink_crc) = self.read_debuglink()?;
+        let mut debug_path = PathBuf::from("/usr/lib/debug");
+        debug_path.push(Path::new(elf_file_name).parent()?.strip_prefix("/").ok()?);
+        debug_path.push(debuglink_name);
+        let data = fs::read(&debug_path).ok()?;
+        // Verify that the CRC checks out
+        if crc32(&data) != debuglink_crc {
+            log!(LogDebug, "CRC mismatch for debug file {:?}", debug_path);
+            return None;
+        }
+        Some(ElfReader::new(data))
+    }
+
+    /// Convert an address in the ELF image to a file offset.
+    fn addr_to_offset(&self, addr: usize) -> Option<usize> {
+        let elf = Elf::parse(&self.data).ok()?;
+        for section in &elf.section_headers {
+            // Skip the section if it's not in the file
+            if section.sh_type == SHT_NOBITS {
+                continue;
+            }
+            let section_start = section.sh_addr as usize;
+            if section_start <= addr && addr < section_start + section.sh_size as usize {
+                return Some(addr - section_start + section.sh_offset as usize);
+            }
+        }
+        None
+    }
+
+    /// The [start, end) file offsets of the section `name`, if present.
+    fn find_section_file_offsets(&self, name: &str) -> Option<(usize, usize)> {
+        let elf = Elf::parse(&self.data).ok()?;
+        for section in &elf.section_headers {
+            if elf.shdr_strtab.get(section.sh_name).and_then(|r| r.ok()) == Some(name) {
+                let start = section.sh_offset as usize;
+                return Some((start, start + section.sh_size as usize));
+            }
+        }
+        None
+    }
+}
 
-fn write_and_record_bytes(_t: &RecordTask, _child_addr: RemotePtr<Void>, _buf: &[u8]) {
-    unimplemented!()
+/// The CRC-32 (as in zlib) used for .gnu_debuglink.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
 }
 
-fn write_and_record_mem<T>(_t: &RecordTask, _child_addr: RemotePtr<T>, _vals: &[T]) {
-    unimplemented!()
+fn write_and_record_bytes(t: &mut RecordTask, child_addr: RemotePtr<Void>, buf: &[u8]) {
+    let mut ok = true;
+    t.write_bytes_helper(child_addr, buf, Some(&mut ok), WriteFlags::empty());
+    ed_assert!(
+        t,
+        ok,
+        "Could not write {} bytes at {}",
+        buf.len(),
+        child_addr
+    );
+    t.record_local(child_addr, buf);
 }
 
 /// RecordSession sets up an LD_PRELOAD environment variable with an entry
@@ -172,57 +636,90 @@ fn write_and_record_mem<T>(_t: &RecordTask, _child_addr: RemotePtr<T>, _vals: &[
 /// because we're recording an rr replay).
 ////
 fn setup_library_path_arch<Arch: Architecture>(
-    _t: &RecordTask,
-    _env_var: &OsStr,
-    _soname_base: &OsStr,
-    _soname_padded: &OsStr,
-    _soname_32: &OsStr,
-) {
-    unimplemented!()
-}
-
-fn setup_preload_library_path<Arch: Architecture>(_t: &RecordTask) {
-    unimplemented!()
-}
-
-fn setup_audit_library_path<Arch: Architecture>(_t: &RecordTask) {
-    unimplemented!()
-}
-
-fn patch_syscall_with_hook_arch<Arch: Architecture>(
-    _patcher: &MonkeyPatcher,
-    _t: &RecordTask,
-    _hook: &syscall_patch_hook,
-) -> bool {
-    unimplemented!()
-}
-
-fn substitute<Arch: Architecture>(
-    _buffer: &[u8],
-    _return_addr: u64,
-    _trampoline_relative_addr: u64,
+    t: &mut RecordTask,
+    env_var: &OsStr,
+    soname_base: &OsStr,
+    soname_padded: &OsStr,
+    soname_32: &OsStr,
 ) {
-    unimplemented!()
-}
+    let lib_name = if size_of::<Arch::unsigned_word>() < size_of::<usize>() {
+        soname_32
+    } else {
+        soname_padded
+    };
+    let mut env_assignment = env_var.to_owned();
+    env_assignment.push("=");
 
-fn substitute_extended_jump<Arch: Architecture>(
-    _buffer: &[u8],
-    _patch_addr: u64,
-    _return_addr: u64,
-    _target_addr: u64,
-) {
-    unimplemented!()
+    let mut p = RemotePtr::<Arch::unsigned_word>::cast(t.regs_ref().sp());
+    let argc: usize = read_val_mem(t, p, None).try_into().unwrap();
+    // skip argc, argc parameters, and trailing NULL
+    p += 1 + argc + 1;
+    loop {
+        let envp: usize = read_val_mem(t, p, None).try_into().unwrap();
+        if envp == 0 {
+            log!(LogDebug, "{:?} not found", env_var);
+            return;
+        }
+        let env_cstr = t.read_c_str(RemotePtr::new_from_val(envp));
+        let env = env_cstr.as_bytes();
+        if !env.starts_with(env_assignment.as_bytes()) {
+            p += 1;
+            continue;
+        }
+        let lib_pos = match find(env, soname_base.as_bytes()) {
+            Some(lib_pos) => lib_pos,
+            None => {
+                log!(LogDebug, "{:?} not found in {:?}", soname_base, env_var);
+                return;
+            }
+        };
+        if let Some(mut next_colon) = env[lib_pos..]
+            .iter()
+            .position(|&c| c == b':')
+            .map(|i| lib_pos + i)
+        {
+            while next_colon + 1 < env.len() && env[next_colon + 1] == b':' {
+                next_colon += 1;
+            }
+            if next_colon + 1 < lib_pos + soname_padded.len() {
+                log!(
+                    LogDebug,
+                    "Insufficient space for {:?} in {:?} before next ':'",
+                    lib_name,
+                    env_var
+                );
+                return;
+            }
+        }
+        if env.len() < lib_pos + lib_name.len() {
+            log!(
+                LogDebug,
+                "Insufficient space for {:?} in {:?} before end of string",
+                lib_name,
+                env_var
+            );
+            return;
+        }
+        let dest = RemotePtr::<Void>::new_from_val(envp + lib_pos);
+        write_and_record_bytes(t, dest, lib_name.as_bytes());
+        return;
+    }
 }
 
-/// Allocate an extended jump in an extended jump page and return its address.
-/// The resulting address must be within 2G of from_end, and the instruction
-/// there must jump to to_start.
-fn allocate_extended_jump(
-    _t: &RecordTask,
-    _pages: Vec<ExtendedJumpPage>,
-    _from_end: RemotePtr<u8>,
-) -> RemotePtr<u8> {
-    unimplemented!()
+/// DIFF NOTE: rd does not support rr's LD_AUDIT library (librraudit.so) so there is
+/// no setup_audit_library_path().
+fn setup_preload_library_path<Arch: Architecture>(t: &mut RecordTask) {
+    const_assert_eq!(
+        SYSCALLBUF_LIB_FILENAME_PADDED.len(),
+        SYSCALLBUF_LIB_FILENAME_32.len()
+    );
+    setup_library_path_arch::<Arch>(
+        t,
+        OsStr::new("LD_PRELOAD"),
+        OsStr::new(SYSCALLBUF_LIB_FILENAME_BASE),
+        OsStr::new(SYSCALLBUF_LIB_FILENAME_PADDED),
+        OsStr::new(SYSCALLBUF_LIB_FILENAME_32),
+    );
 }
 
 /// Some functions make system calls while storing local variables in memory
@@ -243,108 +740,596 @@ fn allocate_extended_jump(
 /// instruction. So, we allocate "extender pages" --- pages of memory within
 /// 2GB of the patch site, that contain the stub code. We don't really need this
 /// on x86, but we do it there too for consistency.
-fn patch_syscall_with_hook_x86ish(
-    _patcher: &MonkeyPatcher,
-    _t: &RecordTask,
-    _hook: syscall_patch_hook,
+///
+/// DIFF NOTE: rr has a separate patch_syscall_with_hook_x86ish() templated on
+/// the jump patch types. Both our archs are x86ish so we do it all here.
+fn patch_syscall_with_hook_arch<Arch: Architecture>(
+    patcher: &mut MonkeyPatcher,
+    t: &mut RecordTask,
+    hook: &syscall_patch_hook,
 ) -> bool {
-    unimplemented!()
+    let jump_patch_size = jump_patch_size::<Arch>();
+    let extended_jump_size = extended_jump_size::<Arch>();
+    // We're patching in a relative jump, so we need to compute the offset from
+    // the end of the jump to our actual destination.
+    let jump_patch_start = t.ip().to_data_ptr::<u8>();
+    let jump_patch_end = jump_patch_start + jump_patch_size;
+    let return_addr = jump_patch_start
+        + syscall_instruction_length(Arch::arch())
+        + hook.next_instruction_length as usize;
+
+    let extended_jump_start = allocate_extended_jump(
+        t,
+        &mut patcher.extended_jump_pages,
+        jump_patch_end,
+        extended_jump_size,
+    );
+    if extended_jump_start.is_null() {
+        return false;
+    }
+
+    let mut stub_patch = vec![0u8; extended_jump_size];
+    substitute_extended_jump::<Arch>(
+        &mut stub_patch,
+        extended_jump_start.as_usize() as u64,
+        return_addr.as_usize() as u64,
+        hook.hook_address,
+    );
+    write_and_record_bytes(t, extended_jump_start, &stub_patch);
+
+    patcher
+        .syscallbuf_stubs
+        .insert(extended_jump_start, extended_jump_size);
+
+    let jump_offset = extended_jump_start.as_isize() - jump_patch_end.as_isize();
+    let jump_offset32 = jump_offset as i32;
+    ed_assert_eq!(
+        t,
+        jump_offset32 as isize,
+        jump_offset,
+        "allocate_extended_jump didn't work"
+    );
+
+    let mut jump_patch = vec![0u8; jump_patch_size];
+    substitute_jump::<Arch>(&mut jump_patch, jump_offset32 as u32);
+    write_and_record_bytes(t, jump_patch_start, &jump_patch);
+
+    // pad with NOPs to the next instruction
+    let nops = vec![NOP; return_addr - jump_patch_end];
+    write_and_record_bytes(t, jump_patch_end, &nops);
+
+    true
+}
+
+fn jump_patch_size<Arch: Architecture>() -> usize {
+    match Arch::arch() {
+        SupportedArch::X86 => X86SyscallStubMonkeypatch::SIZE,
+        SupportedArch::X64 => X64JumpMonkeypatch::SIZE,
+    }
+}
+
+fn extended_jump_size<Arch: Architecture>() -> usize {
+    match Arch::arch() {
+        SupportedArch::X86 => X86SyscallStubExtendedJump::SIZE,
+        SupportedArch::X64 => X64SyscallStubExtendedJump::SIZE,
+    }
+}
+
+/// Fill `buffer` with a relative jump by `relative_addr` bytes.
+fn substitute_jump<Arch: Architecture>(buffer: &mut [u8], relative_addr: u32) {
+    match Arch::arch() {
+        SupportedArch::X86 => X86SyscallStubMonkeypatch::substitute(buffer, relative_addr),
+        SupportedArch::X64 => X64JumpMonkeypatch::substitute(buffer, relative_addr),
+    }
+}
+
+/// Fill `buffer` with the stub that will live at `patch_addr`, calls the hook at
+/// `target_addr` and then returns to `return_addr`.
+fn substitute_extended_jump<Arch: Architecture>(
+    buffer: &mut [u8],
+    patch_addr: u64,
+    return_addr: u64,
+    target_addr: u64,
+) {
+    match Arch::arch() {
+        SupportedArch::X86 => {
+            let trampoline_relative_addr =
+                target_addr.wrapping_sub(patch_addr + X86SyscallStubExtendedJump::SIZE as u64);
+            X86SyscallStubExtendedJump::substitute(
+                buffer,
+                return_addr as u32,
+                trampoline_relative_addr as u32,
+            )
+        }
+        SupportedArch::X64 => {
+            X64SyscallStubExtendedJump::substitute(buffer, return_addr, target_addr)
+        }
+    }
+}
+
+/// Allocate an extended jump in an extended jump page and return its address.
+/// The resulting address must be within 2G of from_end, and the instruction
+/// there must jump to to_start.
+fn allocate_extended_jump(
+    t: &mut RecordTask,
+    pages: &mut Vec<ExtendedJumpPage>,
+    from_end: RemotePtr<u8>,
+    size: usize,
+) -> RemotePtr<u8> {
+    let fits_in_i32 = |offset: isize| offset as i32 as isize == offset;
+    let maybe_index = pages.iter().position(|page| {
+        let page_jump_start = page.addr + page.allocated;
+        fits_in_i32(page_jump_start.as_isize() - from_end.as_isize())
+            && page.allocated + size <= page_size()
+    });
+
+    let index = match maybe_index {
+        Some(index) => index,
+        None => {
+            // We're looking for a gap of three pages --- one page to allocate and
+            // a page on each side as a guard page.
+            let required_space = 3 * page_size();
+            let after = t.vm().mapping_of(from_end).map(|m| m.map.start());
+            let free_mem = t.vm().find_free_memory(required_space, after);
+
+            let addr = free_mem + page_size();
+            if !fits_in_i32(addr.as_isize() - from_end.as_isize()) {
+                log!(LogDebug, "Can't find space close enough for the jump");
+                return RemotePtr::null();
+            }
+
+            let prot = ProtFlags::PROT_READ | ProtFlags::PROT_EXEC;
+            let flags = MapFlags::MAP_PRIVATE | MapFlags::MAP_ANONYMOUS | MapFlags::MAP_FIXED;
+            {
+                let mut remote = AutoRemoteSyscalls::new(t);
+                remote.infallible_mmap_syscall(Some(addr), page_size(), prot, flags, -1, 0);
+            }
+            let recorded = KernelMapping::new_with_opts(
+                addr,
+                addr + page_size(),
+                OsStr::new(""),
+                KernelMapping::NO_DEVICE,
+                KernelMapping::NO_INODE,
+                prot,
+                flags,
+                0,
+            );
+            let d = t.trace_writer_mut().write_mapped_region(
+                t,
+                &recorded,
+                &recorded.fake_stat(),
+                &[],
+                Some(MappingOrigin::PatchMapping),
+                None,
+            );
+            ed_assert_eq!(t, d, RecordInTrace::DontRecordInTrace);
+            t.vm_shr_ptr().map(
+                t,
+                addr,
+                page_size(),
+                prot,
+                flags,
+                0,
+                OsStr::new(""),
+                KernelMapping::NO_DEVICE,
+                KernelMapping::NO_INODE,
+                None,
+                Some(&recorded),
+                None,
+                None,
+                None,
+            );
+            *t.vm().mapping_flags_of_mut(addr) |= MappingFlags::IS_PATCH_STUBS;
+
+            pages.push(ExtendedJumpPage::new(addr));
+            pages.len() - 1
+        }
+    };
+
+    let page = &mut pages[index];
+    let jump_addr = page.addr + page.allocated;
+    page.allocated += size;
+    jump_addr
 }
 
 fn patch_syscall_with_hook(
-    _patcher: &MonkeyPatcher,
-    _t: &RecordTask,
-    _hook: &syscall_patch_hook,
+    patcher: &mut MonkeyPatcher,
+    t: &mut RecordTask,
+    hook: &syscall_patch_hook,
 ) -> bool {
-    unimplemented!()
+    rd_arch_function_selfless!(patch_syscall_with_hook_arch, t.arch(), patcher, t, hook)
 }
 
 fn task_safe_for_syscall_patching(
-    _t: &RecordTask,
-    _start: RemoteCodePtr,
-    _end: RemoteCodePtr,
+    t: &RecordTask,
+    start: RemoteCodePtr,
+    end: RemoteCodePtr,
 ) -> bool {
-    unimplemented!()
+    if t.is_running() {
+        let ip = t.ip();
+        if start <= ip && ip < end {
+            return false;
+        }
+    }
+    for e in &t.pending_events {
+        if e.is_syscall_event() {
+            let ip = e.syscall_event().regs.ip();
+            if start <= ip && ip < end {
+                return false;
+            }
+        }
+    }
+    true
 }
 
-fn safe_for_syscall_patching(_start: RemoteCodePtr, _end: RemoteCodePtr, _exclude: &RecordTask) {
-    unimplemented!()
+fn safe_for_syscall_patching(
+    start: RemoteCodePtr,
+    end: RemoteCodePtr,
+    exclude: &RecordTask,
+) -> bool {
+    let session = exclude.session();
+    for (&tid, rc_t) in session.tasks().iter() {
+        // `exclude` is already borrowed by our caller
+        if tid == exclude.rec_tid {
+            continue;
+        }
+        let tb = rc_t.borrow();
+        if !task_safe_for_syscall_patching(tb.as_record_task().unwrap(), start, end) {
+            return false;
+        }
+    }
+    true
 }
 
 /// Return true iff |addr| points to a known |__kernel_vsyscall()|
 /// implementation.
-fn is_kernel_vsyscall(_t: &RecordTask, _addr: RemotePtr<Void>) -> bool {
-    unimplemented!()
+fn is_kernel_vsyscall(t: &mut RecordTask, addr: RemotePtr<Void>) -> bool {
+    let mut ok = true;
+    let code = read_mem(
+        t,
+        addr,
+        X86SysenterVsyscallImplementation::SIZE,
+        Some(&mut ok),
+    );
+    ok && (X86SysenterVsyscallImplementation::matches(&code)
+        || X86SysenterVsyscallImplementationAMD::matches(&code))
 }
 
 /// Return the address of a recognized |__kernel_vsyscall()|
 /// implementation in |t|'s address space.
 fn locate_and_verify_kernel_vsyscall(
-    _t: &RecordTask,
-    _reader: &ElfReader,
-    _syms: &SymbolTable,
+    t: &mut RecordTask,
+    reader: &ElfReader,
+    syms: &[Symbol],
 ) -> RemotePtr<Void> {
-    unimplemented!()
+    let mut kernel_vsyscall = RemotePtr::null();
+    // It is unlikely but possible that multiple, versioned __kernel_vsyscall
+    // symbols will exist.  But we can't rely on setting |kernel_vsyscall| to
+    // catch that case, because only one of the versioned symbols will
+    // actually match what we expect to see, and the matching one might be
+    // the first one.  Therefore, we have this separate flag to alert us to
+    // this possibility.
+    let mut seen_kernel_vsyscall = false;
+
+    for sym in syms.iter().filter(|sym| sym.name == "__kernel_vsyscall") {
+        let file_offset = match vdso_symbol_offset(t, reader, sym.addr) {
+            Some(file_offset) => file_offset,
+            None => continue,
+        };
+        let candidate = t.vm().vdso().start() + file_offset;
+        if is_kernel_vsyscall(t, candidate) {
+            if seen_kernel_vsyscall {
+                // There's more than one that matches.
+                return RemotePtr::null();
+            }
+            kernel_vsyscall = candidate;
+        }
+        seen_kernel_vsyscall = true;
+    }
+
+    kernel_vsyscall
+}
+
+/// Convert the value of a symbol in the vdso to an offset from the start of
+/// the vdso. The symbol values can be absolute or relative to the vdso start.
+fn vdso_symbol_offset(t: &RecordTask, reader: &ElfReader, sym_addr: usize) -> Option<usize> {
+    let file_offset = match reader.addr_to_offset(sym_addr) {
+        Some(file_offset) => file_offset,
+        None => {
+            log!(LogDebug, "Can't convert address {:#x} to offset", sym_addr);
+            return None;
+        }
+    };
+    let sym_offset = if file_offset >= VDSO_ABSOLUTE_ADDRESS && t.arch() == SupportedArch::X86 {
+        file_offset - VDSO_ABSOLUTE_ADDRESS
+    } else {
+        file_offset & VDSO_OFFSET_MASK
+    };
+    let vdso_size = min(t.vm().vdso().size(), MAX_VDSO_SIZE);
+    if sym_offset >= vdso_size {
+        log!(
+            LogWarn,
+            "Symbol at {:#x} (offset {:#x}) is outside the vdso",
+            sym_addr,
+            sym_offset
+        );
+        return None;
+    }
+    Some(sym_offset)
 }
 
 /// VDSOs are filled with overhead critical functions related to getting the
 /// time and current CPU.  We need to ensure that these syscalls get redirected
 /// into actual trap-into-the-kernel syscalls so rr can intercept them.
-fn patch_after_exec_arch<Arch: Architecture>(_t: &RecordTask, _patcher: &MonkeyPatcher) {
-    unimplemented!()
+fn patch_after_exec_arch<Arch: Architecture>(t: &mut RecordTask, patcher: &mut MonkeyPatcher) {
+    setup_preload_library_path::<Arch>(t);
+
+    let reader = ElfReader::new_from_vdso(t);
+    let syms = reader.read_symbols(true);
+
+    let syscalls_to_monkeypatch: &[NamedSyscall] = match Arch::arch() {
+        SupportedArch::X86 => {
+            patcher.x86_vsyscall = locate_and_verify_kernel_vsyscall(t, &reader, &syms);
+            if patcher.x86_vsyscall.is_null() {
+                fatal!(
+                    "Failed to monkeypatch vdso: your __kernel_vsyscall implementation is unsupported."
+                );
+            }
+            &[
+                NamedSyscall::new("__vdso_clock_gettime", Arch::CLOCK_GETTIME),
+                NamedSyscall::new("__vdso_gettimeofday", Arch::GETTIMEOFDAY),
+                NamedSyscall::new("__vdso_time", Arch::TIME),
+            ]
+        }
+        SupportedArch::X64 => &[
+            NamedSyscall::new("__vdso_clock_gettime", Arch::CLOCK_GETTIME),
+            NamedSyscall::new("__vdso_clock_getres", Arch::CLOCK_GETRES),
+            NamedSyscall::new("__vdso_gettimeofday", Arch::GETTIMEOFDAY),
+            NamedSyscall::new("__vdso_time", Arch::TIME),
+            NamedSyscall::new("__vdso_getcpu", Arch::GETCPU),
+        ],
+    };
+
+    let vdso_start = t.vm().vdso().start();
+    for syscall in syscalls_to_monkeypatch {
+        for sym in syms.iter().filter(|sym| sym.name == syscall.name) {
+            let sym_offset = match vdso_symbol_offset(t, &reader, sym.addr) {
+                Some(sym_offset) => sym_offset,
+                None => continue,
+            };
+            let absolute_address = vdso_start + sym_offset;
+
+            let (patch, syscall_insn_offset) = match Arch::arch() {
+                SupportedArch::X86 => {
+                    let mut patch = vec![0u8; X86VsyscallMonkeypatch::SIZE];
+                    X86VsyscallMonkeypatch::substitute(&mut patch, syscall.syscall_number as u32);
+                    (patch, X86VsyscallMonkeypatch::SYSCALL_INSN_OFFSET)
+                }
+                SupportedArch::X64 => {
+                    let mut patch = vec![0u8; X64VsyscallMonkeypatch::SIZE];
+                    X64VsyscallMonkeypatch::substitute(&mut patch, syscall.syscall_number as u32);
+                    (patch, X64VsyscallMonkeypatch::SYSCALL_INSN_OFFSET)
+                }
+            };
+            write_and_record_bytes(t, absolute_address, &patch);
+            // Record the location of the syscall instruction, skipping the
+            // "mov $syscall_number,%eax" etc.
+            patcher
+                .patched_vdso_syscalls
+                .insert(RemoteCodePtr::from_val(
+                    absolute_address.as_usize() + syscall_insn_offset,
+                ));
+            log!(
+                LogDebug,
+                "monkeypatched {} to syscall {} at {} ({:#x})",
+                syscall.name,
+                syscall.syscall_number,
+                absolute_address,
+                sym_offset
+            );
+        }
+    }
+
+    if Arch::arch() == SupportedArch::X64 {
+        let maps: Vec<(RemotePtr<Void>, usize, u64)> = t
+            .vm()
+            .maps()
+            .into_iter()
+            .map(|(_, m)| (m.map.start(), m.map.size(), m.map.file_offset_bytes()))
+            .collect();
+        for (start, size, offset_bytes) in maps {
+            patcher.patch_after_mmap(
+                t,
+                start,
+                size,
+                offset_bytes as usize / page_size(),
+                -1,
+                MmapMode::MmapExec,
+            );
+        }
+    }
+
+    obliterate_debug_info(t, &reader);
 }
 
-struct NamedSyscall<'a> {
-    pub name: &'a OsStr,
+struct NamedSyscall {
+    pub name: &'static str,
     pub syscall_number: i32,
 }
 
-fn erase_section(_t: &RecordTask, _reader: &VdsoReader, _name: &OsStr) {
-    unimplemented!()
+impl NamedSyscall {
+    const fn new(name: &'static str, syscall_number: i32) -> NamedSyscall {
+        NamedSyscall {
+            name,
+            syscall_number,
+        }
+    }
 }
 
-fn obliterate_debug_info(_t: &RecordTask, _reader: &VdsoReader) {
-    unimplemented!()
+fn erase_section(t: &mut RecordTask, reader: &ElfReader, name: &str) {
+    if let Some((start, end)) = reader.find_section_file_offsets(name) {
+        if start > 0 && end > start {
+            let zeroes = vec![0u8; end - start];
+            let addr = t.vm().vdso().start() + start;
+            write_and_record_bytes(t, addr, &zeroes);
+        }
+    }
+}
+
+fn obliterate_debug_info(t: &mut RecordTask, reader: &ElfReader) {
+    erase_section(t, reader, ".eh_frame");
+    erase_section(t, reader, ".eh_frame_hdr");
+    erase_section(t, reader, ".note");
 }
 
 fn resolve_address(
-    _reader: &ElfReader,
-    _elf_addr: usize,
-    _map_start: RemotePtr<Void>,
-    _map_size: usize,
-    _map_offset_pages: usize,
+    reader: &ElfReader,
+    elf_addr: usize,
+    map_start: RemotePtr<Void>,
+    map_size: usize,
+    map_offset_pages: usize,
 ) -> RemotePtr<Void> {
-    unimplemented!()
+    let file_offset = match reader.addr_to_offset(elf_addr) {
+        Some(file_offset) => file_offset,
+        None => {
+            log!(LogWarn, "ELF address {:#x} not in file", elf_addr);
+            return RemotePtr::null();
+        }
+    };
+    let map_offset = map_offset_pages * page_size();
+    if file_offset < map_offset || file_offset + 32 > map_offset + map_size {
+        // The value(s) to be set are outside the mapped range. This happens
+        // because code and data can be mapped in separate, partial mmaps in which
+        // case some symbols will be outside the mapped range.
+        return RemotePtr::null();
+    }
+    map_start + (file_offset - map_offset)
 }
 
 fn set_and_record_bytes(
-    _t: &RecordTask,
-    _reader: &ElfReader,
-    _elf_addr: usize,
-    _bytes: &[u8],
-    _map_start: RemotePtr<Void>,
-    _map_size: usize,
-    _map_offset_pages: usize,
+    t: &mut RecordTask,
+    reader: &ElfReader,
+    elf_addr: usize,
+    bytes: &[u8],
+    map_start: RemotePtr<Void>,
+    map_size: usize,
+    map_offset_pages: usize,
 ) {
-    unimplemented!()
+    let addr = resolve_address(reader, elf_addr, map_start, map_size, map_offset_pages);
+    if addr.is_null() {
+        return;
+    }
+    let mut ok = true;
+    t.write_bytes_helper(addr, bytes, Some(&mut ok), WriteFlags::empty());
+    // Writing can fail when the value appears to be in the mapped range, but it
+    // actually is beyond the file length.
+    if ok {
+        t.record_local(addr, bytes);
+    }
 }
 
 /// Patch _dl_runtime_resolve_(fxsave,xsave,xsavec) to clear "FDP Data Pointer"
 /// register so that CPU-specific behaviors involving that register don't leak
 /// into stack memory.
 fn patch_dl_runtime_resolve(
-    _patcher: &MonkeyPatcher,
-    _t: &RecordTask,
-    _reader: &ElfReader,
-    _elf_addr: usize,
-    _bytes: &[u8],
-    _map_start: RemotePtr<Void>,
-    _map_size: usize,
-    _map_offset_pages: usize,
+    patcher: &mut MonkeyPatcher,
+    t: &mut RecordTask,
+    reader: &ElfReader,
+    elf_addr: usize,
+    map_start: RemotePtr<Void>,
+    map_size: usize,
+    map_offset_pages: usize,
 ) {
-    unimplemented!()
+    if t.arch() != SupportedArch::X64 {
+        return;
+    }
+    let addr = resolve_address(reader, elf_addr, map_start, map_size, map_offset_pages);
+    if addr.is_null() {
+        return;
+    }
+
+    let mut impl_bytes = [0u8; X64DLRuntimeResolve::SIZE];
+    t.read_bytes_helper(addr, &mut impl_bytes, None);
+    if !X64DLRuntimeResolve::matches(&impl_bytes) && !X64DLRuntimeResolve2::matches(&impl_bytes) {
+        log!(
+            LogWarn,
+            "_dl_runtime_resolve implementation doesn't look right"
+        );
+        return;
+    }
+
+    // We're patching in a relative jump, so we need to compute the offset from
+    // the end of the jump to our actual destination.
+    let jump_patch_start = addr;
+    let jump_patch_end = jump_patch_start + X64JumpMonkeypatch::SIZE;
+
+    let extended_jump_start = allocate_extended_jump(
+        t,
+        &mut patcher.extended_jump_pages,
+        jump_patch_end,
+        X64DLRuntimeResolvePrelude::SIZE,
+    );
+    if extended_jump_start.is_null() {
+        return;
+    }
+
+    let mut stub_patch = [0u8; X64DLRuntimeResolvePrelude::SIZE];
+    let return_offset = (jump_patch_start.as_isize() + X64DLRuntimeResolve::SIZE as isize)
+        - (extended_jump_start.as_isize() + X64DLRuntimeResolvePrelude::SIZE as isize);
+    let return_offset32 = return_offset as i32;
+    ed_assert_eq!(
+        t,
+        return_offset32 as isize,
+        return_offset,
+        "allocate_extended_jump didn't work like it should"
+    );
+    X64DLRuntimeResolvePrelude::substitute(&mut stub_patch, &impl_bytes, return_offset32 as u32);
+    write_and_record_bytes(t, extended_jump_start, &stub_patch);
+
+    // Pad with NOPs to the next instruction.
+    let mut jump_patch = [0x90u8; X64DLRuntimeResolve::SIZE];
+    let jump_offset = extended_jump_start.as_isize() - jump_patch_end.as_isize();
+    let jump_offset32 = jump_offset as i32;
+    ed_assert_eq!(
+        t,
+        jump_offset32 as isize,
+        jump_offset,
+        "allocate_extended_jump didn't work like it should"
+    );
+    X64JumpMonkeypatch::substitute(&mut jump_patch, jump_offset32 as u32);
+    write_and_record_bytes(t, addr, &jump_patch);
+}
+
+fn file_may_need_instrumentation(map: &address_space::Mapping) -> bool {
+    let fsname = map.map.fsname().as_bytes();
+    let file_part = match fsname.iter().rposition(|&c| c == b'/') {
+        Some(pos) => pos + 1,
+        None => 0,
+    };
+    let file_name = &fsname[file_part..];
+    find(file_name, b"libpthread").is_some() || find(file_name, b"ld").is_some()
 }
 
-fn file_may_need_instrumentation(_map: &address_space::Mapping) -> bool {
-    unimplemented!()
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn crc32_test() {
+        assert_eq!(0, crc32(b""));
+        assert_eq!(0xcbf4_3926, crc32(b"123456789"));
+    }
+
+    #[test]
+    fn dl_runtime_resolve_prelude_test() {
+        let original = [0x53, 0x48, 0x89, 0xe3, 0x48, 0x83, 0xe4, 0xf0];
+        assert!(!X64DLRuntimeResolve::matches(&original));
+        assert!(X64DLRuntimeResolve2::matches(&original));
+
+        let mut stub = [0u8; X64DLRuntimeResolvePrelude::SIZE];
+        X64DLRuntimeResolvePrelude::substitute(&mut stub, &original, -0x1000i32 as u32);
+        assert_eq!(&stub[17..25], &original);
+        assert_eq!(stub[25], 0xe9);
+        assert_eq!(&stub[26..30], &(-0x1000i32).to_le_bytes());
+    }
 }
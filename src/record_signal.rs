@@ -1,5 +1,780 @@
-use crate::session::task::record_task::RecordTask;
+//! Signal handling during recording.
+//!
+//! `handle_signal()` is called when a tracee is about to receive a signal.
+//! Signals that rd uses internally (the desched signal, the time slice
+//! signal, trapped instructions etc.) are consumed here. Everything else is
+//! turned into an `EvSignal` event on the task's event stack which the
+//! `RecordSession` then delivers.
+use crate::{
+    arch::Architecture,
+    auto_remote_syscalls::{AutoRemoteSyscalls, AutoRestoreMem, MemParamsEnabled},
+    bindings::{
+        perf_event::{PERF_EVENT_IOC_DISABLE, PERF_EVENT_IOC_ENABLE},
+        ptrace::PTRACE_EVENT_SECCOMP,
+        signal::{siginfo_t, POLL_IN},
+    },
+    event::{
+        DeschedEventData,
+        Event,
+        EventType,
+        SignalDeterministic,
+        SignalEventData,
+        SyscallEventData,
+    },
+    file_monitor::virtual_perf_counter_monitor::VirtualPerfCounterMonitor,
+    kernel_abi::{
+        sigaction_sigset_size,
+        syscall_number_for_rt_sigaction,
+        syscall_number_for_rt_sigprocmask,
+    },
+    kernel_metadata::syscall_name,
+    kernel_supplement::sig_set_t,
+    log::LogDebug,
+    perf_counters::TIME_SLICE_SIGNAL,
+    preload_interface::{syscallbuf_hdr, syscallbuf_record},
+    preload_interface_arch::preload_thread_locals,
+    remote_code_ptr::RemoteCodePtr,
+    remote_ptr::{RemotePtr, Void},
+    session::{
+        address_space::{address_space::AddressSpace, kernel_mapping::KernelMapping},
+        session_inner::PtraceSyscallSeccompOrdering,
+        task::{
+            record_task::{FlushSyscallbuf, RecordTask, SignalDisposition},
+            task_common::read_val_mem,
+            task_inner::{ResumeRequest, TicksRequest, WaitRequest},
+            Task,
+        },
+    },
+    sig::{self, Sig},
+    trace::trace_writer::RecordInTrace,
+    util::{
+        ceil_page_size,
+        cpuid,
+        floor_page_size,
+        page_size,
+        signal_bit,
+        trapped_instruction_at,
+        trapped_instruction_len,
+        TrappedInstruction,
+    },
+    wait_status::WaitStatus,
+};
+use libc::{ioctl, prlimit, rlimit, PR_TSC_SIGSEGV, RLIMIT_STACK, RLIM_INFINITY, SIG_BLOCK};
+use nix::sys::mman::MapFlags;
+use std::{
+    cmp::{max, min},
+    convert::TryFrom,
+    ffi::OsStr,
+    mem::{size_of, zeroed},
+    ptr,
+};
 
-pub fn disarm_desched_event(_t: &RecordTask) {
-    unimplemented!()
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum SignalBlocked {
+    SigUnblocked = 0,
+    SigBlocked = 1,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum SignalHandled {
+    SignalHandled,
+    SignalPtraceStop,
+    DeferSignal,
+}
+
+/// Try to grow downward-growing mappings by this much at a time to reduce
+/// the number of SIGSEGVs we need to process.
+const GROW_MAP_SIZE: usize = 0x10000;
+
+pub fn disarm_desched_event(t: &RecordTask) {
+    if t.desched_fd.is_open()
+        && unsafe { ioctl(t.desched_fd.as_raw(), PERF_EVENT_IOC_DISABLE as _, 0) } != 0
+    {
+        fatal!("Failed to disarm desched event");
+    }
+}
+
+pub fn arm_desched_event(t: &RecordTask) {
+    if t.desched_fd.is_open()
+        && unsafe { ioctl(t.desched_fd.as_raw(), PERF_EVENT_IOC_ENABLE as _, 0) } != 0
+    {
+        fatal!("Failed to arm desched event");
+    }
+}
+
+fn restore_sighandler_if_not_default(t: &mut RecordTask, sig: Sig) {
+    if t.sig_disposition(sig) != SignalDisposition::SignalDefault {
+        log!(LogDebug, "Restoring signal handler for {}", sig);
+        let sa = t.signal_action(sig);
+        let arch = t.arch();
+        let mut remote = AutoRemoteSyscalls::new(t);
+        let mut child_sa = AutoRestoreMem::new(&mut remote, Some(&sa), sa.len());
+        let child_sa_addr = child_sa.get().unwrap().as_usize();
+        rd_infallible_syscall!(
+            child_sa,
+            syscall_number_for_rt_sigaction(arch),
+            sig.as_raw(),
+            child_sa_addr,
+            0,
+            sigaction_sigset_size(arch)
+        );
+    }
+}
+
+/// Restore the blocked-ness and sigaction for `sig` from `t`'s local
+/// copy. The kernel may have reset these when it forced a synchronous
+/// signal on the tracee.
+fn restore_signal_state(t: &mut RecordTask, sig: Sig, signal_was_blocked: SignalBlocked) {
+    restore_sighandler_if_not_default(t, sig);
+    if signal_was_blocked == SignalBlocked::SigBlocked {
+        log!(LogDebug, "Restoring signal blocked-ness for {}", sig);
+        let arch = t.arch();
+        let sigset_size = sigaction_sigset_size(arch);
+        let mask: sig_set_t = signal_bit(sig);
+        ed_assert!(t, sigset_size >= size_of::<sig_set_t>());
+        let mut bytes = vec![0u8; sigset_size];
+        bytes[0..size_of::<sig_set_t>()].copy_from_slice(&mask.to_le_bytes());
+        {
+            let mut remote = AutoRemoteSyscalls::new(t);
+            let mut child_block = AutoRestoreMem::new(&mut remote, Some(&bytes), bytes.len());
+            let child_block_addr = child_block.get().unwrap().as_usize();
+            rd_infallible_syscall!(
+                child_block,
+                syscall_number_for_rt_sigprocmask(arch),
+                SIG_BLOCK,
+                child_block_addr,
+                0,
+                sigset_size
+            );
+        }
+        // We just changed the sigmask ourselves.
+        t.invalidate_sigmask();
+    }
+}
+
+/// Return true if `t` was stopped because of a SIGSEGV resulting
+/// from a disabled instruction and `t` was updated appropriately, false
+/// otherwise.
+fn try_handle_trapped_instruction(t: &mut RecordTask, si: &siginfo_t) -> bool {
+    ed_assert_eq!(t, si.si_signo, sig::SIGSEGV.as_raw());
+
+    let ip = t.ip();
+    let trapped_instruction = trapped_instruction_at(t, ip);
+    match trapped_instruction {
+        TrappedInstruction::Rdtsc | TrappedInstruction::Rdtscp => {
+            if t.tsc_mode == PR_TSC_SIGSEGV {
+                return false;
+            }
+        }
+        TrappedInstruction::CpuId => {
+            if t.cpuid_mode == 0 {
+                return false;
+            }
+        }
+        _ => return false,
+    }
+
+    let len = trapped_instruction_len(trapped_instruction);
+    ed_assert!(t, len > 0);
+
+    let mut r = t.regs_ref().clone();
+    if trapped_instruction == TrappedInstruction::Rdtsc
+        || trapped_instruction == TrappedInstruction::Rdtscp
+    {
+        // DIFF NOTE: Newer rr versions try to patch RDTSC here (via
+        // Monkeypatcher::try_patch_trapping_instruction) so they don't take a
+        // SIGSEGV every time. That needs RDTSC hooks in the preload library,
+        // which we don't have, so every RDTSC traps and is recorded here.
+        let current_time = unsafe { core::arch::x86_64::_rdtsc() };
+        r.set_rdtsc_output(current_time);
+
+        log!(LogDebug, " trapped for rdtsc: returning {}", current_time);
+    } else if trapped_instruction == TrappedInstruction::CpuId {
+        let eax = r.syscallno() as u32;
+        let ecx = r.cx() as u32;
+        let mut cpuid_data = cpuid(eax, ecx);
+        t.session()
+            .as_record()
+            .unwrap()
+            .disable_cpuid_features()
+            .amend_cpuid_data(eax, ecx, &mut cpuid_data);
+        r.set_cpuid_output(
+            cpuid_data.eax,
+            cpuid_data.ebx,
+            cpuid_data.ecx,
+            cpuid_data.edx,
+        );
+        log!(LogDebug, " trapped for cpuid: {:#x}:{:#x}", eax, ecx);
+    }
+
+    r.set_ip(r.ip() + len);
+    t.set_regs(&r);
+    t.record_event(Some(Event::instruction_trap()), None, None, None);
+    t.push_event(Event::noop());
+    true
+}
+
+/// Return true if `t` was stopped because of a SIGSEGV and we want to retry
+/// the instruction after emulating MAP_GROWSDOWN.
+fn try_grow_map(t: &mut RecordTask, si: &siginfo_t) -> bool {
+    ed_assert_eq!(t, si.si_signo, sig::SIGSEGV.as_raw());
+
+    let addr = RemotePtr::<Void>::from(unsafe { si._sifields._sigfault.si_addr } as usize);
+
+    if t.vm().mapping_of(addr).is_some() {
+        log!(LogDebug, "try_grow_map {}: address already mapped", addr);
+        return false;
+    }
+
+    let maybe_km = t
+        .vm()
+        .maps_starting_at(floor_page_size(addr))
+        .into_iter()
+        .next()
+        .map(|(_, m)| m.map.clone());
+    let km = match maybe_km {
+        Some(km) => km,
+        None => {
+            log!(
+                LogDebug,
+                "try_grow_map {}: no later map to grow downward",
+                addr
+            );
+            return false;
+        }
+    };
+    if !km.flags().contains(MapFlags::MAP_GROWSDOWN) {
+        log!(
+            LogDebug,
+            "try_grow_map {}: map is not MAP_GROWSDOWN ({})",
+            addr,
+            km
+        );
+        return false;
+    }
+    if addr.as_usize() >= page_size() && t.vm().mapping_of(addr - page_size()).is_some() {
+        log!(
+            LogDebug,
+            "try_grow_map {}: address would be in guard page",
+            addr
+        );
+        return false;
+    }
+
+    let mut limit_bottom = RemotePtr::<Void>::null();
+    let mut stack_limit: rlimit = unsafe { zeroed() };
+    let ret = unsafe { prlimit(t.tid, RLIMIT_STACK, ptr::null(), &mut stack_limit) };
+    if ret >= 0 && stack_limit.rlim_cur != RLIM_INFINITY {
+        limit_bottom = ceil_page_size(km.end() - stack_limit.rlim_cur as usize);
+        if limit_bottom > addr {
+            log!(LogDebug, "try_grow_map {}: RLIMIT_STACK exceeded", addr);
+            return false;
+        }
+    }
+
+    // Try to grow by GROW_MAP_SIZE at a time to reduce signal frequency.
+    let mut new_start = floor_page_size(addr);
+    if km.start().as_usize() >= GROW_MAP_SIZE {
+        let possible_new_start = max(limit_bottom, min(new_start, km.start() - GROW_MAP_SIZE));
+        // Ensure that no mapping exists between possible_new_start - page_size()
+        // and new_start. If there is, possible_new_start is not valid, in which
+        // case we just abandon the optimization.
+        if possible_new_start.as_usize() >= page_size()
+            && t.vm()
+                .mapping_of(possible_new_start - page_size())
+                .is_none()
+            && t.vm()
+                .maps_starting_at(possible_new_start - page_size())
+                .into_iter()
+                .next()
+                .map_or(false, |(_, m)| m.map.start() == km.start())
+        {
+            new_start = possible_new_start;
+        }
+    }
+    log!(LogDebug, "try_grow_map {}: trying to grow map {}", addr, km);
+
+    let num_bytes = km.start() - new_start;
+    let flags = (km.flags() & !MapFlags::MAP_GROWSDOWN) | MapFlags::MAP_ANONYMOUS;
+    {
+        let mut remote =
+            AutoRemoteSyscalls::new_with_mem_params(t, MemParamsEnabled::DisableMemoryParams);
+        remote.infallible_mmap_syscall(
+            Some(new_start),
+            num_bytes,
+            km.prot(),
+            flags | MapFlags::MAP_FIXED,
+            -1,
+            0,
+        );
+    }
+
+    let new_km = t.vm_shr_ptr().map(
+        t,
+        new_start,
+        num_bytes,
+        km.prot(),
+        km.flags() | MapFlags::MAP_ANONYMOUS,
+        0,
+        OsStr::new(""),
+        KernelMapping::NO_DEVICE,
+        KernelMapping::NO_INODE,
+        None,
+        None,
+        None,
+        None,
+        None,
+    );
+    let st = new_km.fake_stat();
+    let record_in_trace =
+        t.trace_writer_mut()
+            .write_mapped_region(t, &new_km, &st, &[], None, None);
+    ed_assert_eq!(t, record_in_trace, RecordInTrace::DontRecordInTrace);
+    // No need to flush syscallbuf here. It's safe to map these pages "early"
+    // before they're really needed.
+    t.record_event(
+        Some(Event::grow_map()),
+        Some(FlushSyscallbuf::DontFlushSyscallbuf),
+        None,
+        None,
+    );
+    t.push_event(Event::noop());
+    log!(LogDebug, "try_grow_map {}: extended map {}", addr, new_km);
+    true
+}
+
+fn get_stub_scratch_1_arch<Arch: Architecture>(t: &mut RecordTask) -> RemoteCodePtr {
+    let locals = read_val_mem(
+        t,
+        RemotePtr::<preload_thread_locals<Arch>>::cast(AddressSpace::preload_thread_locals_start()),
+        None,
+    );
+    Arch::as_rptr(locals.stub_scratch_1).to_code_ptr()
+}
+
+fn get_stub_scratch_1(t: &mut RecordTask) -> RemoteCodePtr {
+    let arch = t.arch();
+    rd_arch_function_selfless!(get_stub_scratch_1_arch, arch, t)
+}
+
+fn read_desched_signal_may_be_relevant(t: &mut RecordTask) -> bool {
+    let addr = RemotePtr::<u8>::cast(t.syscallbuf_child)
+        + offset_of!(syscallbuf_hdr, desched_signal_may_be_relevant);
+    read_val_mem(t, addr, None) != 0
+}
+
+fn read_desched_rec_syscallno(t: &mut RecordTask) -> i32 {
+    let addr = RemotePtr::<u16>::cast(
+        RemotePtr::<u8>::cast(t.desched_rec()) + offset_of!(syscallbuf_record, syscallno),
+    );
+    read_val_mem(t, addr, None) as i32
+}
+
+/// This function is responsible for handling breakpoints we set in syscallbuf
+/// code to detect sigprocmask calls and syscallbuf exit. It's called when we
+/// get a SIGTRAP. Returns true if the SIGTRAP was caused by one of our
+/// breakpoints and has been handled.
+pub fn handle_syscallbuf_breakpoint(t: &mut RecordTask) -> bool {
+    if t.is_at_syscallbuf_final_instruction_breakpoint() {
+        log!(
+            LogDebug,
+            "Reached final syscallbuf instruction, singlestepping to enable signal dispatch"
+        );
+        // Emulate the effects of the final syscallbuf instruction, which is a
+        // jump through stub_scratch_1. Now we're in a state where signals can
+        // be dispatched.
+        let target = get_stub_scratch_1(t);
+        t.emulate_jump(target);
+        return true;
+    }
+
+    if !t.is_at_syscallbuf_syscall_entry_breakpoint() {
+        return false;
+    }
+
+    let mut r = t.regs_ref().clone();
+    r.set_ip(r.ip().decrement_by_bkpt_insn_length(t.arch()));
+    t.set_regs(&r);
+
+    if t.is_at_traced_syscall_entry() {
+        // We will automatically dispatch stashed signals now since this is an
+        // allowed place to dispatch signals.
+        log!(
+            LogDebug,
+            "Allowing signal dispatch at traced-syscall breakpoint"
+        );
+        return true;
+    }
+
+    // We're at an untraced-syscall entry point. If the desched event is armed
+    // it could fire while we deliver the signal, so disarm it. The syscallbuf
+    // code rearms it if it retries the syscall.
+    if read_desched_signal_may_be_relevant(t) {
+        disarm_desched_event(t);
+    }
+    log!(
+        LogDebug,
+        "Allowing signal dispatch at untraced-syscall breakpoint"
+    );
+    true
+}
+
+/// `t` is being delivered a signal, and its state changed.
+/// The desched signal fired, so advance `t` through the desched'd syscall.
+fn handle_desched_event(t: &mut RecordTask, si: &siginfo_t) {
+    ed_assert!(
+        t,
+        t.session()
+            .as_record()
+            .unwrap()
+            .syscallbuf_desched_sig()
+            .as_raw()
+            == si.si_signo
+            && si.si_code == POLL_IN as i32
+            && unsafe { si._sifields._sigpoll.si_fd } == t.desched_fd_child,
+        "Tracee is using SIGPWR/SIGSTKFLT??? (code={}, fd={})",
+        si.si_code,
+        unsafe { si._sifields._sigpoll.si_fd }
+    );
+
+    // If the tracee isn't in the critical section where a desched
+    // event is relevant, we can ignore it.  See the long comments
+    // in syscall_buffer.c.
+    //
+    // It's OK if the tracee is in the critical section for a
+    // may-block syscall B, but this signal was delivered by an
+    // event programmed by a previous may-block syscall A.
+    //
+    // If we're running in a signal handler inside an interrupted syscallbuf
+    // system call, never do anything here. Syscall buffering is disabled and
+    // the desched_signal_may_be_relevant was set by the outermost syscallbuf
+    // invocation.
+    if !read_desched_signal_may_be_relevant(t) || t.running_inside_desched() {
+        log!(LogDebug, "  (not entering may-block syscall; resuming)");
+        // We have to disarm the event just in case the tracee
+        // has cleared the relevancy flag, but not yet
+        // disarmed the event itself.
+        disarm_desched_event(t);
+        t.push_event(Event::noop());
+        return;
+    }
+
+    // The desched event just fired.  That implies that the
+    // arm-desched ioctl went into effect, and that the
+    // disarm-desched syscall didn't take effect.  Since a signal
+    // is pending for the tracee, then if the tracee was in a
+    // syscall, linux has exited it with an -ERESTART* error code.
+    // That means the tracee is about to (re-)enter either
+    //
+    //  1. buffered syscall
+    //  2. disarm-desched ioctl syscall
+    //
+    // We can figure out which one by simply issuing a
+    // ptrace(SYSCALL) and examining the tracee's registers.
+    //
+    // If the tracee enters the disarm-desched ioctl, it's going
+    // to commit a record of the buffered syscall to the
+    // syscallbuf, and we can safely send the tracee back on its
+    // way, ignoring the desched completely.
+    //
+    // If it enters the buffered syscall, then the desched event
+    // has served its purpose and we need to prepare the tracee
+    // to be context-switched.
+    //
+    // An annoyance of the desched signal is that we sometimes see
+    // *two* signals for one desched. So we "work around" this by
+    // silently discarding any further desched signals we see while
+    // advancing the tracee.
+    //
+    // One really fun edge case is that sometimes the desched
+    // signal will interrupt the arm-desched syscall itself.
+    // Continuing to the next syscall boundary seems to restart
+    // the arm-desched syscall, and advancing to the boundary
+    // again exits it and we start receiving desched signals
+    // again.
+    //
+    // That may be a kernel bug, but we handle it by just
+    // continuing until we we continue past the arm-desched
+    // syscall *and* stop seeing signals.
+    loop {
+        // Prevent further desched notifications from firing
+        // while we're advancing the tracee.  We're going to
+        // leave it in a consistent state anyway, so the event
+        // is no longer useful.  We have to do this in each
+        // loop iteration because a restarted arm-desched
+        // syscall may have re-armed the event.
+        disarm_desched_event(t);
+
+        t.resume_execution(
+            ResumeRequest::ResumeSyscall,
+            WaitRequest::ResumeWait,
+            TicksRequest::ResumeUnlimitedTicks,
+            None,
+        );
+
+        if t.status().is_syscall() {
+            if t.is_arm_desched_event_syscall() {
+                continue;
+            }
+            break;
+        }
+
+        if t.maybe_ptrace_event() == PTRACE_EVENT_SECCOMP {
+            ed_assert!(
+                t,
+                t.session().syscall_seccomp_ordering_.get()
+                    == PtraceSyscallSeccompOrdering::SeccompBeforeSyscall
+            );
+            // This is the old kernel event ordering. This must be a SECCOMP event
+            // for the buffered syscall; it's not rd-generated because this is an
+            // untraced syscall, but it could be generated by a tracee's
+            // seccomp filter.
+            break;
+        }
+
+        // Completely ignore spurious desched signals and
+        // signals that aren't going to be delivered to the
+        // tracee.
+        //
+        // Also ignore time-slice signals.  If the tracee ends
+        // up at the disarm-desched ioctl, we'll reschedule it
+        // with the ticks interrupt still programmed.  At worst,
+        // the tracee will get an extra time-slice out of
+        // this, on average, so we don't worry too much about
+        // it.
+        //
+        // @TODO it's theoretically possible for this to
+        // happen an unbounded number of consecutive times
+        // and the tracee never switched out.
+        ed_assert!(
+            t,
+            t.maybe_stop_sig().is_sig(),
+            "expected stop-signal, got {}",
+            t.status()
+        );
+        let sig = t.maybe_stop_sig().unwrap_sig();
+        if t.session().as_record().unwrap().syscallbuf_desched_sig() == sig
+            || TIME_SLICE_SIGNAL == sig
+            || t.is_sig_ignored(sig)
+        {
+            log!(LogDebug, "  dropping ignored {}", sig);
+            continue;
+        }
+
+        log!(LogDebug, "  stashing {}", sig);
+        t.stash_sig();
+    }
+
+    if t.is_disarm_desched_event_syscall() {
+        log!(
+            LogDebug,
+            "  (at disarm-desched, so finished buffered syscall; resuming)"
+        );
+        t.push_event(Event::noop());
+        return;
+    }
+
+    if !t.desched_rec().is_null() {
+        // We're already processing a desched. We probably reexecuted the
+        // system call (e.g. because a signal was processed) and the syscall
+        // blocked again. Carry on with the current desched.
+    } else {
+        // This prevents the syscallbuf record counter from being
+        // reset until we've finished guiding the tracee through this
+        // interrupted call.  We use the record counter for
+        // assertions.
+        ed_assert!(t, !t.delay_syscallbuf_reset_for_desched);
+        t.delay_syscallbuf_reset_for_desched = true;
+        log!(LogDebug, "Desched initiated");
+
+        // The tracee is (re-)entering the buffered syscall.  Stash
+        // away this breadcrumb so that we can figure out what syscall
+        // the tracee was in, and how much "scratch" space it carved
+        // off the syscallbuf, if needed.
+        let desched_rec = t.next_syscallbuf_record();
+        t.push_event(Event::new_desched_event(DeschedEventData {
+            rec: desched_rec,
+        }));
+        let call = read_desched_rec_syscallno(t);
+
+        // The descheduled syscall was interrupted by a signal, like
+        // all other may-restart syscalls, with the exception that
+        // this one has already been restarted (which we'll detect
+        // back in the main loop).
+        let arch = t.arch();
+        let mut ev = SyscallEventData::new(call, arch);
+        ev.desched_rec = desched_rec;
+        t.push_event(Event::new_syscall_interruption_event(ev));
+    }
+
+    // For some syscalls (at least poll) but not all (at least not read),
+    // repeated cont_syscall()s above of the same interrupted syscall
+    // can set $orig_eax to 0 ... for unclear reasons. Fix that up here
+    // otherwise we'll get a divergence during replay, which will not
+    // encounter this problem.
+    let call = read_desched_rec_syscallno(t);
+    let mut regs = t.regs_ref().clone();
+    regs.set_original_syscallno(call as isize);
+    t.set_regs(&regs);
+    t.ev_mut().syscall_mut().regs = regs;
+    // runnable_state_changed will observe us entering this syscall and change
+    // state to EnteringSyscall
+
+    log!(
+        LogDebug,
+        "  resuming (and probably switching out) blocked `{}'",
+        syscall_name(call, t.ev().syscall().arch())
+    );
+}
+
+fn is_safe_to_deliver_signal(t: &mut RecordTask) -> bool {
+    if !t.is_in_syscallbuf() {
+        // The tracee is outside the syscallbuf code,
+        // so in most cases can't possibly affect
+        // syscallbuf critical sections.  The
+        // exception is signal handlers "re-entering"
+        // desched'd syscalls, which are OK per
+        // handle_desched_event.
+        return true;
+    }
+
+    if t.is_in_untraced_syscall() && !t.desched_rec().is_null() {
+        // Untraced syscalls always use the architectural syscall instruction,
+        // and we're in one that was desched'd. It's safe to deliver the
+        // signal there.
+        log!(
+            LogDebug,
+            "Safe to deliver signal at {} because the syscall was desched'd",
+            t.ip()
+        );
+        return true;
+    }
+
+    if t.is_in_traced_syscall() {
+        log!(
+            LogDebug,
+            "Safe to deliver signal at {} because in traced syscall",
+            t.ip()
+        );
+        return true;
+    }
+
+    // Don't deliver signals just before entering rd page syscall
+    // instructions, or in the middle of syscallbuf bookkeeping. The final
+    // exit instruction is fine since the syscallbuf state is consistent
+    // there.
+    if t.ip() == t.syscallbuf_code_layout.syscallbuf_final_exit_instruction {
+        log!(
+            LogDebug,
+            "Safe to deliver signal at {} because at final syscallbuf instruction",
+            t.ip()
+        );
+        return true;
+    }
+
+    log!(LogDebug, "Not safe to deliver signal at {}", t.ip());
+    false
+}
+
+/// Handle the given signal for `t`.
+/// Returns SignalHandled if we handled the signal, SignalPtraceStop if we
+/// didn't handle the signal due to an emulated ptrace-stop, and DeferSignal
+/// if the signal can't be handled right now (e.g. because `t` is in a
+/// syscallbuf critical section).
+/// Signals that rd consumes itself are fully processed here. Other signals
+/// result in an `EvSignal` event being pushed on `t`'s event stack.
+pub fn handle_signal(
+    t: &mut RecordTask,
+    si: &mut siginfo_t,
+    deterministic: SignalDeterministic,
+    signal_was_blocked: SignalBlocked,
+) -> SignalHandled {
+    let sig = Sig::try_from(si.si_signo).unwrap();
+    log!(
+        LogDebug,
+        "{}: handling signal {} (pevent: {}, event: {})",
+        t.tid,
+        sig,
+        t.maybe_ptrace_event(),
+        t.ev()
+    );
+
+    // Conservatively invalidate the sigmask in case just accepting a signal has
+    // sigmask effects.
+    t.invalidate_sigmask();
+
+    if deterministic == SignalDeterministic::DeterministicSig {
+        // When a deterministic signal is triggered by the current instruction,
+        // the kernel forcibly unblocks it and resets SIG_IGN to SIG_DFL.
+        let was_ignored = t.is_sig_ignored(sig);
+        if sig == sig::SIGSEGV && (try_handle_trapped_instruction(t, si) || try_grow_map(t, si)) {
+            if signal_was_blocked == SignalBlocked::SigBlocked || was_ignored {
+                restore_signal_state(t, sig, signal_was_blocked);
+            }
+            return SignalHandled::SignalHandled;
+        }
+        if signal_was_blocked == SignalBlocked::SigBlocked || was_ignored {
+            log!(
+                LogDebug,
+                "{} was blocked or ignored; the kernel reset it to SIG_DFL",
+                sig
+            );
+            t.did_set_sig_handler_default(sig);
+        }
+    }
+
+    // See if this signal occurred because of an rd implementation detail,
+    // and fudge t appropriately.
+    if t.session().as_record().unwrap().syscallbuf_desched_sig() == sig {
+        handle_desched_event(t, si);
+        return SignalHandled::SignalHandled;
+    }
+
+    if sig == TIME_SLICE_SIGNAL {
+        if t.next_pmc_interrupt_is_for_user {
+            let maybe_vpmc = VirtualPerfCounterMonitor::interrupting_virtual_pmc_for_task(t);
+            ed_assert!(t, maybe_vpmc.is_some());
+            // Synthesize the requested signal.
+            maybe_vpmc
+                .unwrap()
+                .borrow_mut()
+                .as_virtual_perf_counter_monitor_mut()
+                .unwrap()
+                .synthesize_signal(t);
+
+            t.next_pmc_interrupt_is_for_user = false;
+            return SignalHandled::SignalHandled;
+        }
+
+        t.push_event(Event::sched());
+        return SignalHandled::SignalHandled;
+    }
+
+    if !is_safe_to_deliver_signal(t) {
+        return SignalHandled::DeferSignal;
+    }
+
+    if !t.set_siginfo_for_synthetic_sigchld(si) {
+        return SignalHandled::DeferSignal;
+    }
+
+    if t.emulate_ptrace_stop(WaitStatus::for_stop_sig(sig), Some(si), None) {
+        // Record a SCHED event so that replay progresses the tracee to the
+        // current point before we notify the tracer.
+        t.record_event(
+            Some(Event::sched()),
+            Some(FlushSyscallbuf::FlushSyscallbuf),
+            None,
+            None,
+        );
+        // The tracee won't run again until its ptracer resumes it.
+        return SignalHandled::SignalPtraceStop;
+    }
+
+    let disposition = t.sig_resolved_disposition(sig, deterministic);
+    t.push_event(Event::new_signal_event(
+        EventType::EvSignal,
+        SignalEventData::new(si, deterministic, disposition),
+    ));
+    SignalHandled::SignalHandled
 }
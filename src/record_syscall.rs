@@ -0,0 +1,5597 @@
+//! Recording of system calls.
+//!
+//! `rec_prepare_syscall()` is called at syscall entry. It sets up any scratch
+//! memory the syscall needs (so that output parameters are written somewhere
+//! the tracee can't race with), and decides whether we may context switch
+//! while the syscall is running.
+//!
+//! `rec_process_syscall()` is called at syscall exit. It records the outputs
+//! of the syscall in the trace and copies any scratch data back to the
+//! locations the tracee expects.
+use crate::{
+    arch::{Architecture, NativeArch},
+    auto_remote_syscalls::{AutoRemoteSyscalls, MemParamsEnabled},
+    bindings::{
+        kernel::{
+            user_desc,
+            _LINUX_CAPABILITY_U32S_1,
+            _LINUX_CAPABILITY_U32S_2,
+            _LINUX_CAPABILITY_U32S_3,
+            _LINUX_CAPABILITY_VERSION_1,
+            _LINUX_CAPABILITY_VERSION_2,
+            _LINUX_CAPABILITY_VERSION_3,
+            FIONREAD,
+            FUTEX_CMP_REQUEUE,
+            FUTEX_CMP_REQUEUE_PI,
+            FUTEX_LOCK_PI,
+            FUTEX_REQUEUE,
+            FUTEX_TRYLOCK_PI,
+            FUTEX_UNLOCK_PI,
+            FUTEX_WAIT,
+            FUTEX_WAIT_BITSET,
+            FUTEX_WAIT_REQUEUE_PI,
+            FUTEX_WAKE,
+            FUTEX_WAKE_BITSET,
+            FUTEX_WAKE_OP,
+            GETALL,
+            GETNCNT,
+            GETPID,
+            GETVAL,
+            GETZCNT,
+            IPC_64,
+            IPC_INFO,
+            IPC_RMID,
+            IPC_SET,
+            IPC_STAT,
+            MSGCTL,
+            MSGGET,
+            MSGRCV,
+            MSGSND,
+            MSG_INFO,
+            MSG_STAT,
+            NT_FPREGSET,
+            NT_PRSTATUS,
+            NT_X86_XSTATE,
+            Q_GETFMT,
+            Q_GETINFO,
+            Q_GETQUOTA,
+            Q_QUOTAOFF,
+            Q_QUOTAON,
+            Q_SETINFO,
+            Q_SETQUOTA,
+            Q_SYNC,
+            SEMCTL,
+            SEMGET,
+            SEMOP,
+            SEMTIMEDOP,
+            SEM_INFO,
+            SEM_STAT,
+            SETALL,
+            SETVAL,
+            SHMAT,
+            SHMCTL,
+            SHMDT,
+            SHMGET,
+            SHM_INFO,
+            SHM_LOCK,
+            SHM_STAT,
+            SHM_UNLOCK,
+            SIOCETHTOOL,
+            SIOCGIFADDR,
+            SIOCGIFBRDADDR,
+            SIOCGIFCONF,
+            SIOCGIFDSTADDR,
+            SIOCGIFFLAGS,
+            SIOCGIFHWADDR,
+            SIOCGIFINDEX,
+            SIOCGIFMAP,
+            SIOCGIFMTU,
+            SIOCGIFNAME,
+            SIOCGIFNETMASK,
+            SIOCGIFTXQLEN,
+            SIOCGIWRATE,
+            SUBCMDSHIFT,
+            SYS_ACCEPT,
+            SYS_ACCEPT4,
+            SYS_BIND,
+            SYS_CONNECT,
+            SYS_GETPEERNAME,
+            SYS_GETSOCKNAME,
+            SYS_GETSOCKOPT,
+            SYS_LISTEN,
+            SYS_RECV,
+            SYS_RECVFROM,
+            SYS_RECVMMSG,
+            SYS_RECVMSG,
+            SYS_SEND,
+            SYS_SENDMMSG,
+            SYS_SENDMSG,
+            SYS_SENDTO,
+            SYS_SETSOCKOPT,
+            SYS_SHUTDOWN,
+            SYS_SOCKET,
+            SYS_SOCKETPAIR,
+            TCGETA,
+            TCGETS,
+            TIOCGETD,
+            TIOCGPGRP,
+            TIOCGSID,
+            TIOCGWINSZ,
+            TIOCOUTQ,
+        },
+        perf_event::perf_event_attr,
+        prctl::{ARCH_GET_FS, ARCH_GET_GS, ARCH_SET_FS, ARCH_SET_GS},
+        ptrace::{
+            PTRACE_ATTACH,
+            PTRACE_CONT,
+            PTRACE_DETACH,
+            PTRACE_EVENT_CLONE,
+            PTRACE_EVENT_FORK,
+            PTRACE_EVENT_VFORK,
+            PTRACE_EVENT_VFORK_DONE,
+            PTRACE_GETEVENTMSG,
+            PTRACE_GETFPREGS,
+            PTRACE_GETREGS,
+            PTRACE_GETREGSET,
+            PTRACE_GETSIGINFO,
+            PTRACE_INTERRUPT,
+            PTRACE_KILL,
+            PTRACE_O_TRACECLONE,
+            PTRACE_O_TRACEEXEC,
+            PTRACE_O_TRACEEXIT,
+            PTRACE_O_TRACEFORK,
+            PTRACE_O_TRACESECCOMP,
+            PTRACE_O_TRACESYSGOOD,
+            PTRACE_O_TRACEVFORK,
+            PTRACE_O_TRACEVFORKDONE,
+            PTRACE_PEEKDATA,
+            PTRACE_PEEKTEXT,
+            PTRACE_POKEDATA,
+            PTRACE_POKETEXT,
+            PTRACE_SEIZE,
+            PTRACE_SETFPREGS,
+            PTRACE_SETOPTIONS,
+            PTRACE_SETREGS,
+            PTRACE_SINGLESTEP,
+            PTRACE_SYSCALL,
+            PTRACE_SYSEMU,
+            PTRACE_SYSEMU_SINGLESTEP,
+            PTRACE_TRACEME,
+        },
+        signal::{siginfo_t, SI_USER},
+    },
+    event::{
+        Event,
+        EventType,
+        OpenedFd,
+        SignalDeterministic,
+        SignalEventData,
+        Switchable,
+        SyscallState,
+    },
+    extra_registers::Format,
+    file_monitor::{
+        base_file_monitor::BaseFileMonitor,
+        mmapped_file_monitor::MmappedFileMonitor,
+        proc_fd_dir_monitor::ProcFdDirMonitor,
+        proc_mem_monitor::ProcMemMonitor,
+        stdio_monitor::StdioMonitor,
+        virtual_perf_counter_monitor::VirtualPerfCounterMonitor,
+        FileMonitor,
+        Range,
+    },
+    kernel_abi::{
+        is_clone_syscall,
+        is_exit_group_syscall,
+        is_exit_syscall,
+        is_vfork_syscall,
+        syscall_number_for_inotify_init,
+        syscall_number_for_munmap,
+        syscall_number_for_pause,
+        x64,
+        x86,
+        CloneTLSType,
+        FcntlOperation,
+        MmapCallingSemantics,
+        SelectCallingSemantics,
+        SupportedArch,
+    },
+    kernel_metadata::{
+        errno_name,
+        is_sigreturn,
+        ptrace_req_name,
+        shm_flags_to_mmap_prot,
+        syscall_name,
+    },
+    kernel_supplement::{
+        ARCH_GET_CPUID,
+        ARCH_SET_CPUID,
+        GRND_NONBLOCK,
+        PR_GET_SPECULATION_CTRL,
+        PR_SET_SPECULATION_CTRL,
+        SECCOMP_SET_MODE_FILTER,
+    },
+    log::{LogDebug, LogInfo, LogWarn},
+    monitored_shared_memory::MonitoredSharedMemory,
+    monkey_patcher::MmapMode,
+    preload_interface::{syscallbuf_hdr, syscallbuf_record},
+    preload_interface_arch::rdcall_init_buffers_params,
+    registers::{with_converted_registers, Registers},
+    remote_ptr::{RemotePtr, Void},
+    seccomp_filter_rewriter::SECCOMP_MAGIC_SKIP_ORIGINAL_SYSCALLNO,
+    session::{
+        address_space::{address_space::AddressSpace, kernel_mapping::KernelMapping},
+        session_inner::SessionInner,
+        task::{
+            record_task::{EmulatedStopType, RecordTask, WaitType},
+            task_common::{read_mem, read_val_mem, write_mem, write_val_mem},
+            task_inner::{ResumeRequest, TicksRequest, WaitRequest},
+            Task,
+            TaskSharedPtr,
+            TaskSharedWeakPtr,
+        },
+    },
+    sig::{self, Sig},
+    trace::{
+        trace_task_event::TraceTaskEvent,
+        trace_writer::{MappingOrigin, RecordInTrace},
+    },
+    util::{
+        ceil_page_size,
+        clone_flags_to_task_flags,
+        extract_clone_parameters,
+        is_proc_fd_dir,
+        is_proc_mem_file,
+        page_size,
+        u8_slice,
+        CloneParameters,
+    },
+    wait_status::WaitStatus,
+};
+use libc::{
+    gid_t,
+    loff_t,
+    off64_t,
+    pid_t,
+    CLONE_PARENT,
+    CLONE_THREAD,
+    CLONE_UNTRACED,
+    CLONE_VFORK,
+    CLONE_VM,
+    EINVAL,
+    EIO,
+    ENOPROTOOPT,
+    ENOSYS,
+    ENOTTY,
+    EPERM,
+    ESRCH,
+    FUTEX_CMD_MASK,
+    KEYCTL_ASSUME_AUTHORITY,
+    KEYCTL_CHOWN,
+    KEYCTL_CLEAR,
+    KEYCTL_DESCRIBE,
+    KEYCTL_DH_COMPUTE,
+    KEYCTL_GET_KEYRING_ID,
+    KEYCTL_GET_PERSISTENT,
+    KEYCTL_GET_SECURITY,
+    KEYCTL_INSTANTIATE,
+    KEYCTL_INSTANTIATE_IOV,
+    KEYCTL_INVALIDATE,
+    KEYCTL_JOIN_SESSION_KEYRING,
+    KEYCTL_LINK,
+    KEYCTL_NEGATE,
+    KEYCTL_READ,
+    KEYCTL_REJECT,
+    KEYCTL_REVOKE,
+    KEYCTL_SEARCH,
+    KEYCTL_SESSION_TO_PARENT,
+    KEYCTL_SETPERM,
+    KEYCTL_SET_REQKEY_KEYRING,
+    KEYCTL_SET_TIMEOUT,
+    KEYCTL_UNLINK,
+    KEYCTL_UPDATE,
+    MADV_DODUMP,
+    MADV_DOFORK,
+    MADV_DONTDUMP,
+    MADV_DONTFORK,
+    MADV_DONTNEED,
+    MADV_FREE,
+    MADV_HUGEPAGE,
+    MADV_HWPOISON,
+    MADV_MERGEABLE,
+    MADV_NOHUGEPAGE,
+    MADV_NORMAL,
+    MADV_RANDOM,
+    MADV_REMOVE,
+    MADV_SEQUENTIAL,
+    MADV_SOFT_OFFLINE,
+    MADV_UNMERGEABLE,
+    MADV_WILLNEED,
+    MAP_32BIT,
+    MAP_FIXED,
+    MAP_GROWSDOWN,
+    MSG_DONTWAIT,
+    O_DIRECT,
+    PRIO_PROCESS,
+    PR_CAPBSET_DROP,
+    PR_CAPBSET_READ,
+    PR_CAP_AMBIENT,
+    PR_GET_CHILD_SUBREAPER,
+    PR_GET_DUMPABLE,
+    PR_GET_ENDIAN,
+    PR_GET_FPEMU,
+    PR_GET_FPEXC,
+    PR_GET_KEEPCAPS,
+    PR_GET_NAME,
+    PR_GET_NO_NEW_PRIVS,
+    PR_GET_PDEATHSIG,
+    PR_GET_SECCOMP,
+    PR_GET_THP_DISABLE,
+    PR_GET_TIMERSLACK,
+    PR_GET_TSC,
+    PR_GET_UNALIGN,
+    PR_MCE_KILL,
+    PR_MCE_KILL_GET,
+    PR_SET_CHILD_SUBREAPER,
+    PR_SET_DUMPABLE,
+    PR_SET_KEEPCAPS,
+    PR_SET_NAME,
+    PR_SET_NO_NEW_PRIVS,
+    PR_SET_PDEATHSIG,
+    PR_SET_PTRACER,
+    PR_SET_SECCOMP,
+    PR_SET_THP_DISABLE,
+    PR_SET_TIMERSLACK,
+    PR_SET_TSC,
+    PR_TSC_ENABLE,
+    PR_TSC_SIGSEGV,
+    P_ALL,
+    P_PGID,
+    P_PID,
+    SECCOMP_MODE_FILTER,
+    SECCOMP_MODE_STRICT,
+    SIGCHLD,
+    SIGSTOP,
+    SOL_PACKET,
+    STDERR_FILENO,
+    STDIN_FILENO,
+    STDOUT_FILENO,
+    S_IFMT,
+    S_IFREG,
+    S_IWUSR,
+    WNOHANG,
+    WNOWAIT,
+    WUNTRACED,
+};
+use nix::{
+    sched::CpuSet,
+    sys::{
+        mman::{MapFlags, ProtFlags},
+        stat::{major, minor, stat},
+    },
+    unistd::ttyname,
+};
+use std::{
+    cell::RefCell,
+    cmp::{max, min},
+    convert::{TryFrom, TryInto},
+    ffi::{OsStr, OsString},
+    fs::File,
+    mem::{size_of, zeroed},
+    os::unix::{
+        ffi::{OsStrExt, OsStringExt},
+        fs::FileExt,
+    },
+    rc::Rc,
+};
+
+pub type TaskSyscallStateSharedPtr = Rc<RefCell<TaskSyscallState>>;
+
+/// Size of a struct `$t` for the architecture `$arch`.
+///
+/// We can't simply do `size_of::<Arch::$t>()` because most of the kernel
+/// structs are not associated types of the Architecture trait.
+macro_rules! arch_size_of {
+    ($arch:ty, $t:ident) => {
+        match <$arch>::arch() {
+            SupportedArch::X86 => size_of::<x86::$t>(),
+            SupportedArch::X64 => size_of::<x64::$t>(),
+        }
+    };
+}
+
+/// Offset of field `$f` in struct `$t` for the architecture `$arch`.
+macro_rules! arch_offset_of {
+    ($arch:ty, $t:ident, $f:ident) => {
+        match <$arch>::arch() {
+            SupportedArch::X86 => offset_of!(x86::$t, $f),
+            SupportedArch::X64 => offset_of!(x64::$t, $f),
+        }
+    };
+}
+
+/// Modes used to register syscall memory parameter with TaskSyscallState.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ArgMode {
+    /// Syscall memory parameter is an in-parameter only.
+    /// This is only important when we want to move the buffer to scratch memory
+    /// so we can modify it without making the modifications potentially visible
+    /// to user code. Otherwise, such parameters can be ignored.
+    In,
+    /// Syscall memory parameter is out-parameter only.
+    Out,
+    /// Syscall memory parameter is an in-out parameter.
+    InOut,
+    /// Syscall memory parameter is an in-out parameter but we must not use
+    /// scratch (e.g. for futexes, we must use the actual memory word).
+    InOutNoScratch,
+}
+
+/// Specifies how to determine the size of a syscall memory
+/// parameter. There is usually an incoming size determined before the syscall
+/// executes (which we need in order to allocate scratch memory), combined
+/// with an optional final size taken from the syscall result or a specific
+/// memory location after the syscall has executed. The minimum of the incoming
+/// and final sizes is used, if both are present.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct ParamSize {
+    incoming_size: usize,
+    /// If non-null, the size is limited by the value at this location after
+    /// the syscall.
+    mem_ptr: RemotePtr<Void>,
+    /// Size of the value at mem_ptr or in the syscall result register.
+    read_size: usize,
+    /// If true, the size is limited by the value of the syscall result.
+    from_syscall: bool,
+}
+
+impl Default for ParamSize {
+    fn default() -> Self {
+        ParamSize::from(usize::MAX)
+    }
+}
+
+impl From<usize> for ParamSize {
+    fn from(incoming_size: usize) -> Self {
+        ParamSize {
+            incoming_size,
+            mem_ptr: RemotePtr::null(),
+            read_size: 0,
+            from_syscall: false,
+        }
+    }
+}
+
+impl ParamSize {
+    /// p points to a tracee location that is already initialized with a
+    /// "maximum buffer size" passed in by the tracee, and which will be filled
+    /// in with the size of the data by the kernel when the syscall exits.
+    pub fn from_initialized_mem<T: Copy + TryInto<usize>>(
+        t: &mut RecordTask,
+        p: RemotePtr<T>,
+    ) -> ParamSize {
+        let incoming_size = if p.is_null() {
+            0
+        } else {
+            match read_val_mem(t, p, None).try_into() {
+                Ok(s) => s,
+                Err(_) => usize::MAX,
+            }
+        };
+        let mut r = ParamSize::from(incoming_size);
+        r.mem_ptr = RemotePtr::cast(p);
+        r.read_size = size_of::<T>();
+        r
+    }
+
+    /// p points to a tracee location which will be filled in with the size of
+    /// the data by the kernel when the syscall exits, but the location
+    /// is uninitialized before the syscall.
+    pub fn from_mem<T>(p: RemotePtr<T>) -> ParamSize {
+        let mut r = ParamSize::default();
+        r.mem_ptr = RemotePtr::cast(p);
+        r.read_size = size_of::<T>();
+        r
+    }
+
+    /// When the syscall exits, the syscall result will be of type T and contain
+    /// the size of the data. 'incoming_size', if present, is a bound on the size
+    /// of the data.
+    pub fn from_syscall_result<T>(maybe_incoming_size: Option<usize>) -> ParamSize {
+        let mut r = ParamSize::from(maybe_incoming_size.unwrap_or(usize::MAX));
+        r.from_syscall = true;
+        r.read_size = size_of::<T>();
+        r
+    }
+
+    pub fn limit_size(&self, max: usize) -> ParamSize {
+        let mut r = *self;
+        r.incoming_size = min(r.incoming_size, max);
+        r
+    }
+
+    pub fn incoming_size(&self) -> usize {
+        self.incoming_size
+    }
+
+    /// Return true if 'other' takes its dynamic size from the same source as
+    /// this.
+    /// When multiple syscall memory parameters take their dynamic size from the
+    /// same source, the source size is distributed among them, with the first
+    /// registered parameter taking up to its max_size bytes, followed by the next,
+    /// etc. This lets us efficiently record iovec buffers.
+    pub fn is_same_source(&self, other: &ParamSize) -> bool {
+        ((!self.mem_ptr.is_null() && other.mem_ptr == self.mem_ptr)
+            || (self.from_syscall && other.from_syscall))
+            && (self.read_size == other.read_size)
+    }
+
+    /// Compute the actual size after the syscall has executed.
+    /// `already_consumed` bytes are subtracted from the syscall-result/
+    /// memory-location part of the size.
+    pub fn eval(&self, t: &mut RecordTask, already_consumed: usize) -> usize {
+        let mut s = self.incoming_size;
+        if !self.mem_ptr.is_null() {
+            let mem_size: usize = match self.read_size {
+                4 => read_val_mem(t, RemotePtr::<u32>::cast(self.mem_ptr), None) as usize,
+                8 => read_val_mem(t, RemotePtr::<u64>::cast(self.mem_ptr), None) as usize,
+                _ => {
+                    ed_assert!(t, false, "Unknown read_size");
+                    return 0;
+                }
+            };
+            ed_assert!(t, already_consumed <= mem_size);
+            s = min(s, mem_size - already_consumed);
+        }
+        if self.from_syscall {
+            let mut syscall_size = max(0, t.regs_ref().syscall_result_signed()) as usize;
+            match self.read_size {
+                4 => syscall_size = syscall_size as u32 as usize,
+                8 => syscall_size = syscall_size as u64 as usize,
+                _ => ed_assert!(t, false, "Unknown read_size"),
+            }
+            ed_assert!(t, already_consumed <= syscall_size);
+            s = min(s, syscall_size - already_consumed);
+        }
+        ed_assert!(t, s < usize::MAX);
+        s
+    }
+}
+
+/// Upon successful syscall completion, each RestoreAndRecordScratch record
+/// in param_list consumes num_bytes from the t->scratch_ptr
+/// buffer, copying the data to remote_dest and recording the data at
+/// remote_dest. If ptr_in_reg is greater than zero, updates the task's
+/// ptr_in_reg register with 'remote_dest'. If ptr_in_memory is non-null,
+/// updates the ptr_in_memory location with the value 'remote_dest'.
+#[derive(Clone)]
+struct MemoryParam {
+    dest: RemotePtr<Void>,
+    scratch: RemotePtr<Void>,
+    num_bytes: ParamSize,
+    ptr_in_memory: RemotePtr<Void>,
+    /// DIFF NOTE: In rr a value of 0 means "not in a register".
+    ptr_in_reg: Option<usize>,
+    mode: ArgMode,
+}
+
+type AfterSyscallAction = Box<dyn Fn(&mut RecordTask)>;
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum WriteBack {
+    WriteBack,
+    NoWriteBack,
+}
+
+/// When tasks enter syscalls that may block and so must be
+/// prepared for a context-switch, and the syscall params
+/// include (in)outparams that point to buffers, we need to
+/// redirect those arguments to scratch memory.  This allows rd
+/// to serialize execution of what may be multiple blocked
+/// syscalls completing "simultaneously" (from rd's
+/// perspective).  After the syscall exits, we restore the data
+/// saved in scratch memory to the original buffers.
+///
+/// Then during replay, we simply restore the saved data to the
+/// tracee's passed-in buffer args and continue on.
+///
+/// This is implemented by having rec_prepare_syscall_arch set up
+/// a record in param_list for syscall in-memory  parameter (whether
+/// "in" or "out"). Then done_preparing is called, which does the actual
+/// scratch setup. process_syscall_results is called when the syscall is
+/// done, to write back scratch results to the real parameters and
+/// clean everything up.
+///
+/// ... a fly in this ointment is may-block buffered syscalls.
+/// If a task blocks in one of those, it will look like it just
+/// entered a syscall that needs a scratch buffer.  However,
+/// it's too late at that point to fudge the syscall args,
+/// because processing of the syscall has already begun in the
+/// kernel.  But that's OK: the syscallbuf code has already
+/// swapped out the original buffer-pointers for pointers into
+/// the syscallbuf (which acts as its own scratch memory).  We
+/// just have to worry about setting things up properly for
+/// replay.
+///
+/// The descheduled syscall will "abort" its commit into the
+/// syscallbuf, so the outparam data won't actually be saved
+/// there (and thus, won't be restored during replay).  During
+/// replay, we have to restore them like we restore the
+/// non-buffered-syscall scratch data. This is done by recording
+/// the relevant syscallbuf record data in rec_process_syscall_arch.
+///
+/// DIFF NOTE: In rr the TaskSyscallState holds a pointer to the task. Here we
+/// pass the task explicitly to every method that needs it.
+/// rr's `ArgMutator` is not supported as nothing uses it yet.
+pub struct TaskSyscallState {
+    param_list: Vec<MemoryParam>,
+    /// Tracks the position in t's scratch_ptr buffer where we should allocate
+    /// the next scratch area.
+    scratch: RemotePtr<Void>,
+
+    after_syscall_actions: Vec<AfterSyscallAction>,
+
+    pub exec_saved_event: Option<Box<TraceTaskEvent>>,
+
+    /// The tracee or child whose stop a wait syscall is being emulated for.
+    pub emulate_wait_for_child: Option<TaskSharedWeakPtr>,
+
+    /// Saved syscall-entry registers, used by code paths that modify the
+    /// registers temporarily.
+    pub syscall_entry_registers: Registers,
+
+    /// When nonzero, syscall is expected to return the given errno and we should
+    /// die if it does not. This is set when we detect an error condition during
+    /// syscall-enter preparation.
+    pub expect_errno: i32,
+
+    /// When should_emulate_result is true, syscall result should be adjusted to
+    /// be emulated_result.
+    pub should_emulate_result: bool,
+    pub emulated_result: usize,
+
+    /// Records whether the syscall is switchable. Only valid when
+    /// preparation_done is true.
+    pub switchable: Switchable,
+
+    /// Whether we should write back the syscall results from scratch. Only
+    /// valid when preparation_done is true.
+    pub write_back: WriteBack,
+
+    /// When true, this syscall has already been prepared and should not
+    /// be set up again.
+    pub preparation_done: bool,
+    /// When true, the scratch area is enabled, otherwise we're letting
+    /// syscall outputs be written directly to their destinations.
+    /// Only valid when preparation_done is true.
+    pub scratch_enabled: bool,
+
+    /// Miscellaneous saved data that can be used by particular syscalls
+    pub saved_data: Vec<u8>,
+}
+
+impl TaskSyscallState {
+    pub fn new(arch: SupportedArch) -> TaskSyscallState {
+        TaskSyscallState {
+            param_list: Vec::new(),
+            scratch: RemotePtr::null(),
+            after_syscall_actions: Vec::new(),
+            exec_saved_event: None,
+            emulate_wait_for_child: None,
+            syscall_entry_registers: Registers::new(arch),
+            expect_errno: 0,
+            should_emulate_result: false,
+            emulated_result: 0,
+            switchable: Switchable::PreventSwitch,
+            write_back: WriteBack::NoWriteBack,
+            preparation_done: false,
+            scratch_enabled: false,
+            saved_data: Vec::new(),
+        }
+    }
+
+    pub fn init(&mut self, t: &RecordTask) {
+        if self.preparation_done {
+            return;
+        }
+        self.scratch = t.scratch_ptr;
+    }
+
+    /// Identify a syscall memory parameter whose address is in register 'arg'
+    /// with type T.
+    /// Returns a RemotePtr to the data in the child (before scratch relocation)
+    /// or null if parameters have already been prepared (the syscall is
+    /// resuming).
+    pub fn reg_parameter<T>(
+        &mut self,
+        t: &mut RecordTask,
+        arg: usize,
+        maybe_mode: Option<ArgMode>,
+    ) -> RemotePtr<T> {
+        RemotePtr::cast(self.reg_parameter_with_size(
+            t,
+            arg,
+            ParamSize::from(size_of::<T>()),
+            maybe_mode,
+        ))
+    }
+
+    /// Identify a syscall memory parameter whose address is in register 'arg'
+    /// with size 'size'.
+    /// Returns a RemotePtr to the data in the child (before scratch relocation)
+    /// or null if parameters have already been prepared (the syscall is
+    /// resuming).
+    pub fn reg_parameter_with_size(
+        &mut self,
+        t: &mut RecordTask,
+        arg: usize,
+        param_size: ParamSize,
+        maybe_mode: Option<ArgMode>,
+    ) -> RemotePtr<Void> {
+        let mode = maybe_mode.unwrap_or(ArgMode::Out);
+        ed_assert!(
+            t,
+            !self.preparation_done,
+            "Tried to set scratch after preparation"
+        );
+
+        let dest = RemotePtr::from(self.syscall_entry_registers.arg(arg as i32));
+        if dest.is_null() {
+            return RemotePtr::null();
+        }
+        let mut param = MemoryParam {
+            dest,
+            scratch: RemotePtr::null(),
+            num_bytes: param_size,
+            ptr_in_memory: RemotePtr::null(),
+            ptr_in_reg: None,
+            mode,
+        };
+        if mode != ArgMode::InOutNoScratch {
+            param.scratch = self.scratch;
+            self.scratch = self.scratch + param.num_bytes.incoming_size;
+            align_scratch(&mut self.scratch, None);
+            param.ptr_in_reg = Some(arg);
+        }
+        self.param_list.push(param);
+        dest
+    }
+
+    /// Identify a syscall memory parameter whose address is in memory at
+    /// location 'addr_of_buf_ptr' with type T.
+    /// Returns a RemotePtr to the data in the child (before scratch relocation)
+    /// or null if parameters have already been prepared (the syscall is
+    /// resuming).
+    /// addr_of_buf_ptr must be in a buffer identified by some init_..._parameter
+    /// call.
+    pub fn mem_ptr_parameter<T>(
+        &mut self,
+        t: &mut RecordTask,
+        addr_of_buf_ptr: RemotePtr<Void>,
+        maybe_mode: Option<ArgMode>,
+    ) -> RemotePtr<T> {
+        RemotePtr::cast(self.mem_ptr_parameter_with_size(
+            t,
+            addr_of_buf_ptr,
+            ParamSize::from(size_of::<T>()),
+            maybe_mode,
+        ))
+    }
+
+    /// Identify a syscall memory parameter whose address is in memory at
+    /// location 'addr_of_buf_ptr' with type T.
+    /// Returns a RemotePtr to the data in the child (before scratch relocation)
+    /// or null if parameters have already been prepared (the syscall is
+    /// resuming).
+    /// addr_of_buf_ptr must be in a buffer identified by some init_..._parameter
+    /// call.
+    pub fn mem_ptr_parameter_with_size(
+        &mut self,
+        t: &mut RecordTask,
+        addr_of_buf_ptr: RemotePtr<Void>,
+        param_size: ParamSize,
+        maybe_mode: Option<ArgMode>,
+    ) -> RemotePtr<Void> {
+        let mode = maybe_mode.unwrap_or(ArgMode::Out);
+        ed_assert!(
+            t,
+            !self.preparation_done,
+            "Tried to set scratch after preparation"
+        );
+        if addr_of_buf_ptr.is_null() {
+            return RemotePtr::null();
+        }
+
+        let dest = get_remote_ptr(t, addr_of_buf_ptr);
+        if dest.is_null() {
+            return RemotePtr::null();
+        }
+
+        let mut param = MemoryParam {
+            dest,
+            scratch: RemotePtr::null(),
+            num_bytes: param_size,
+            ptr_in_memory: RemotePtr::null(),
+            ptr_in_reg: None,
+            mode,
+        };
+        if mode != ArgMode::InOutNoScratch {
+            param.scratch = self.scratch;
+            self.scratch = self.scratch + param.num_bytes.incoming_size;
+            align_scratch(&mut self.scratch, None);
+            param.ptr_in_memory = addr_of_buf_ptr;
+        }
+        self.param_list.push(param);
+        dest
+    }
+
+    /// Internal method that takes 'ptr', an address within some memory parameter,
+    /// and relocates it to the parameter's location in scratch memory.
+    fn relocate_pointer_to_scratch(&self, p: RemotePtr<Void>) -> RemotePtr<Void> {
+        let mut num_relocations: usize = 0;
+        let mut result = RemotePtr::null();
+        for param in &self.param_list {
+            if param.dest <= p && p < param.dest + param.num_bytes.incoming_size {
+                result = param.scratch + (p - param.dest);
+                num_relocations += 1;
+            }
+        }
+        debug_assert!(
+            num_relocations > 0,
+            "Pointer in non-scratch memory being updated to point to scratch?"
+        );
+        debug_assert!(
+            num_relocations <= 1,
+            "Overlapping buffers containing relocated pointer?"
+        );
+        result
+    }
+
+    /// Register a closure that will run after the syscall has exited and the
+    /// parameters have been processed.
+    pub fn after_syscall_action(&mut self, action: AfterSyscallAction) {
+        self.after_syscall_actions.push(action)
+    }
+
+    pub fn emulate_result(&mut self, result: usize) {
+        self.should_emulate_result = true;
+        self.emulated_result = result;
+    }
+
+    pub fn emulate_result_signed(&mut self, result: isize) {
+        self.emulate_result(result as usize)
+    }
+
+    /// Internal method that takes care of the scratch setup.
+    /// This is also used by the code path for syscalls that need to be resumed
+    /// after a desched; in that case preparation_done is already true.
+    fn done_preparing_internal(&mut self, t: &mut RecordTask, sw: Switchable) -> Switchable {
+        ed_assert!(t, !self.preparation_done);
+
+        self.preparation_done = true;
+        self.write_back = WriteBack::WriteBack;
+        self.switchable = sw;
+
+        if t.scratch_ptr.is_null() {
+            return self.switchable;
+        }
+
+        ed_assert!(t, self.scratch >= t.scratch_ptr);
+
+        if sw == Switchable::AllowSwitch && self.scratch > t.scratch_ptr + t.usable_scratch_size() {
+            log!(
+                LogWarn,
+                "`{}' needed a scratch buffer of size {}, but only {} was available.  \
+                 Disabling context switching: deadlock may follow.",
+                t.ev().syscall().syscall_name(),
+                self.scratch - t.scratch_ptr,
+                t.usable_scratch_size()
+            );
+            self.switchable = Switchable::PreventSwitch;
+        }
+        if self.switchable == Switchable::PreventSwitch || self.param_list.is_empty() {
+            return self.switchable;
+        }
+
+        self.scratch_enabled = true;
+
+        // Step 1: Copy all IN/IN_OUT parameters to their scratch areas
+        for param in &self.param_list {
+            ed_assert!(
+                t,
+                param.num_bytes.incoming_size < usize::MAX,
+                "Unknown incoming size for parameter"
+            );
+            if param.mode == ArgMode::InOut || param.mode == ArgMode::In {
+                // Initialize scratch buffer with input data
+                let buf = read_mem::<u8>(
+                    t,
+                    RemotePtr::cast(param.dest),
+                    param.num_bytes.incoming_size,
+                    None,
+                );
+                write_mem(t, RemotePtr::<u8>::cast(param.scratch), &buf, None);
+            }
+        }
+        // Step 2: Update pointers in registers/memory to point to scratch areas
+        let mut r = t.regs_ref().clone();
+        for i in 0..self.param_list.len() {
+            let param = self.param_list[i].clone();
+            match param.ptr_in_reg {
+                Some(arg) => r.set_arg(arg, param.scratch.as_usize()),
+                None => (),
+            }
+            if !param.ptr_in_memory.is_null() {
+                // Pointers being relocated must themselves be in scratch memory.
+                // We don't want to modify non-scratch memory. Find the pointer's
+                // location in scratch memory.
+                let p = self.relocate_pointer_to_scratch(param.ptr_in_memory);
+                // Update pointer to point to scratch.
+                // Note that this can only happen after step 1 is complete and all
+                // parameter data has been copied to scratch memory.
+                set_remote_ptr(t, p, param.scratch);
+            }
+            // If the number of bytes to record is coming from a memory location,
+            // update that location to scratch.
+            if !param.num_bytes.mem_ptr.is_null() {
+                let relocated = self.relocate_pointer_to_scratch(param.num_bytes.mem_ptr);
+                self.param_list[i].num_bytes.mem_ptr = relocated;
+            }
+        }
+        t.set_regs(&r);
+        self.switchable
+    }
+
+    /// Called when a syscall exits to copy results from scratch memory to their
+    /// original destinations, update registers, etc.
+    pub fn done_preparing(&mut self, t: &mut RecordTask, sw: Switchable) -> Switchable {
+        if self.preparation_done {
+            return self.switchable;
+        }
+
+        let sw = self.done_preparing_internal(t, sw);
+        ed_assert!(t, sw == self.switchable);
+        self.switchable
+    }
+
+    /// Called when a syscall exits to copy results from scratch memory to their
+    /// original destinations, update registers, etc.
+    fn eval_param_size(
+        &self,
+        t: &mut RecordTask,
+        i: usize,
+        actual_sizes: &mut Vec<usize>,
+    ) -> usize {
+        debug_assert_eq!(actual_sizes.len(), i);
+
+        let mut already_consumed: usize = 0;
+        for j in 0..i {
+            if self.param_list[j]
+                .num_bytes
+                .is_same_source(&self.param_list[i].num_bytes)
+            {
+                already_consumed += actual_sizes[j];
+            }
+        }
+        let size = self.param_list[i].num_bytes.eval(t, already_consumed);
+        actual_sizes.push(size);
+        size
+    }
+
+    /// Called when a syscall exits to copy results from scratch memory to their
+    /// original destinations, update registers, etc.
+    pub fn process_syscall_results(&mut self, t: &mut RecordTask) {
+        ed_assert!(t, self.preparation_done);
+
+        // XXX what's the best way to handle failed syscalls? Currently we just
+        // record everything as if it succeeded. That handles failed syscalls that
+        // wrote partial results, but doesn't handle syscalls that failed with
+        // EFAULT.
+        let mut actual_sizes: Vec<usize> = Vec::new();
+        if self.scratch_enabled {
+            let scratch_num_bytes = self.scratch - t.scratch_ptr;
+            let data = read_mem::<u8>(t, RemotePtr::cast(t.scratch_ptr), scratch_num_bytes, None);
+            let mut r = t.regs_ref().clone();
+            // Step 1: compute actual sizes of all buffers and copy outputs
+            // from scratch back to their origin
+            for i in 0..self.param_list.len() {
+                let size = self.eval_param_size(t, i, &mut actual_sizes);
+                let param = &self.param_list[i];
+                if self.write_back == WriteBack::WriteBack
+                    && (param.mode == ArgMode::InOut || param.mode == ArgMode::Out)
+                {
+                    let offset = param.scratch - t.scratch_ptr;
+                    let d = &data[offset..offset + size];
+                    t.write_bytes_helper(param.dest, d, None, Default::default());
+                }
+            }
+            let mut memory_cleaned_up = false;
+            // Step 2: restore modified in-memory pointers and registers
+            for param in &self.param_list {
+                match param.ptr_in_reg {
+                    Some(arg) => r.set_arg(arg, param.dest.as_usize()),
+                    None => (),
+                }
+                if !param.ptr_in_memory.is_null() {
+                    memory_cleaned_up = true;
+                    set_remote_ptr(t, param.ptr_in_memory, param.dest);
+                }
+            }
+            if self.write_back == WriteBack::WriteBack {
+                // Step 3: record all output memory areas
+                for (i, param) in self.param_list.iter().enumerate() {
+                    let size = actual_sizes[i];
+                    if param.mode == ArgMode::InOutNoScratch {
+                        t.record_remote(param.dest, size);
+                    } else if param.mode == ArgMode::InOut || param.mode == ArgMode::Out {
+                        // If pointers in memory were fixed up in step 2, then record
+                        // from tracee memory to ensure we record such fixes. Otherwise we
+                        // can record from our local data.
+                        // XXX This optimization can be improved if necessary...
+                        if memory_cleaned_up {
+                            t.record_remote(param.dest, size);
+                        } else {
+                            let offset = param.scratch - t.scratch_ptr;
+                            t.record_local(param.dest, &data[offset..offset + size]);
+                        }
+                    }
+                }
+            }
+            t.set_regs(&r);
+        } else {
+            for i in 0..self.param_list.len() {
+                let size = self.eval_param_size(t, i, &mut actual_sizes);
+                t.record_remote(self.param_list[i].dest, size);
+            }
+        }
+
+        if self.should_emulate_result {
+            let mut r = t.regs_ref().clone();
+            r.set_syscall_result(self.emulated_result);
+            t.set_regs(&r);
+        }
+
+        for action in &self.after_syscall_actions {
+            action(t);
+        }
+    }
+
+    /// Called when a syscall has been completely aborted to undo any changes we
+    /// made.
+    pub fn abort_syscall_results(&mut self, t: &mut RecordTask) {
+        ed_assert!(t, self.preparation_done);
+
+        if self.scratch_enabled {
+            let mut r = t.regs_ref().clone();
+            // restore modified in-memory pointers and registers
+            for param in &self.param_list {
+                match param.ptr_in_reg {
+                    Some(arg) => r.set_arg(arg, param.dest.as_usize()),
+                    None => (),
+                }
+                if !param.ptr_in_memory.is_null() {
+                    set_remote_ptr(t, param.ptr_in_memory, param.dest);
+                }
+            }
+            t.set_regs(&r);
+        }
+    }
+}
+
+fn align_scratch(scratch: &mut RemotePtr<Void>, maybe_amount: Option<usize>) {
+    let amount = maybe_amount.unwrap_or(8);
+    *scratch = RemotePtr::from((scratch.as_usize() + amount - 1) & !(amount - 1));
+}
+
+/// Read a pointer sized value (for the architecture of `t`) at `addr`.
+fn get_remote_ptr(t: &mut RecordTask, addr: RemotePtr<Void>) -> RemotePtr<Void> {
+    match t.arch() {
+        SupportedArch::X86 => {
+            RemotePtr::from(read_val_mem(t, RemotePtr::<u32>::cast(addr), None) as usize)
+        }
+        SupportedArch::X64 => {
+            RemotePtr::from(read_val_mem(t, RemotePtr::<u64>::cast(addr), None) as usize)
+        }
+    }
+}
+
+/// Write a pointer sized value (for the architecture of `t`) at `addr`.
+fn set_remote_ptr(t: &mut RecordTask, addr: RemotePtr<Void>, value: RemotePtr<Void>) {
+    match t.arch() {
+        SupportedArch::X86 => write_val_mem(
+            t,
+            RemotePtr::<u32>::cast(addr),
+            &(value.as_usize() as u32),
+            None,
+        ),
+        SupportedArch::X64 => write_val_mem(
+            t,
+            RemotePtr::<u64>::cast(addr),
+            &(value.as_usize() as u64),
+            None,
+        ),
+    }
+}
+
+/// Read an `Arch::unsigned_word` sized value at `addr`.
+fn read_word<Arch: Architecture>(t: &mut RecordTask, addr: RemotePtr<Void>) -> usize {
+    let w: Arch::unsigned_word = read_val_mem(t, RemotePtr::cast(addr), None);
+    w.try_into().unwrap()
+}
+
+/// Returns the TaskSyscallState for `t`, creating it if necessary.
+fn syscall_state_for(t: &mut RecordTask) -> TaskSyscallStateSharedPtr {
+    match &t.syscall_state {
+        Some(state) => state.clone(),
+        None => {
+            let state = Rc::new(RefCell::new(TaskSyscallState::new(t.arch())));
+            t.syscall_state = Some(state.clone());
+            state
+        }
+    }
+}
+
+/// Prepare `t` to enter its current syscall event.  Return `AllowSwitch` if
+/// a context-switch is allowed for `t`, `PreventSwitch` if not.
+pub fn rec_prepare_syscall(t: &mut RecordTask) -> Switchable {
+    let syscall_state_shr = syscall_state_for(t);
+    let mut syscall_state = syscall_state_shr.borrow_mut();
+    syscall_state.init(t);
+    if !syscall_state.preparation_done {
+        syscall_state.syscall_entry_registers = t.regs_ref().clone();
+    }
+
+    let s = rec_prepare_syscall_internal(t, &mut syscall_state);
+    let syscallno = t.ev().syscall().number;
+    if is_sigreturn(syscallno, t.arch()) {
+        // There isn't going to be an exit event for this syscall, so remove
+        // syscall_state now.
+        drop(syscall_state);
+        t.syscall_state = None;
+        return s;
+    }
+    syscall_state.done_preparing(t, s)
+}
+
+fn rec_prepare_syscall_internal(
+    t: &mut RecordTask,
+    syscall_state: &mut TaskSyscallState,
+) -> Switchable {
+    let arch = t.ev().syscall().arch();
+    let regs = t.regs_ref().clone();
+    with_converted_registers(&regs, arch, |converted_regs| {
+        rd_arch_function_selfless!(
+            rec_prepare_syscall_arch,
+            arch,
+            t,
+            syscall_state,
+            converted_regs
+        )
+    })
+}
+
+/// Prepare `t` for its current syscall event to be interrupted and
+/// possibly restarted.
+pub fn rec_prepare_restart_syscall(t: &mut RecordTask) {
+    let arch = t.ev().syscall().arch();
+    rd_arch_function_selfless!(rec_prepare_restart_syscall_arch, arch, t);
+    t.syscall_state = None;
+}
+
+fn rec_prepare_restart_syscall_arch<Arch: Architecture>(t: &mut RecordTask) {
+    let sys = t.ev().syscall().number;
+    if sys == Arch::NANOSLEEP || sys == Arch::CLOCK_NANOSLEEP || sys == Arch::CLOCK_NANOSLEEP_TIME64
+    {
+        // Hold on to the remaining-time outparam that the syscall
+        // wrote so it can be recorded along with the interruption.
+        match t.syscall_state.clone() {
+            Some(syscall_state) => syscall_state.borrow_mut().process_syscall_results(t),
+            None => (),
+        }
+        return;
+    }
+
+    if sys == Arch::SIGSUSPEND || sys == Arch::RT_SIGSUSPEND {
+        t.invalidate_sigmask();
+        return;
+    }
+
+    if sys == Arch::WAIT4 || sys == Arch::WAITID || sys == Arch::WAITPID {
+        let mut r = t.regs_ref().clone();
+        r.set_original_syscallno(t.ev().syscall().regs.original_syscallno());
+        t.set_regs(&r);
+        t.in_wait_type = WaitType::WaitTypeNone;
+    }
+}
+
+/// Inside a fork/clone/vfork syscall, the child has just been created.
+/// Record its clone event and set it up the way the record session expects.
+fn prepare_clone<Arch: Architecture>(
+    t: &mut RecordTask,
+    syscall_state: &mut TaskSyscallState,
+) -> Switchable {
+    let mut r: Registers = t.regs_ref().clone();
+    let original_syscall = r.original_syscallno() as i32;
+    let mut params: CloneParameters = Default::default();
+    let flags: usize;
+    let ptrace_event: u32;
+    let mut termination_signal = SIGCHLD;
+
+    if is_clone_syscall(original_syscall, r.arch()) {
+        params = extract_clone_parameters(t);
+        flags = r.arg1();
+        r.set_arg1(flags & !(CLONE_UNTRACED as usize));
+        t.set_regs(&r);
+        termination_signal = (flags & 0xff) as i32;
+        if flags & CLONE_VFORK as usize != 0 {
+            ptrace_event = PTRACE_EVENT_VFORK;
+        } else if termination_signal == SIGCHLD {
+            ptrace_event = PTRACE_EVENT_FORK;
+        } else {
+            ptrace_event = PTRACE_EVENT_CLONE;
+        }
+    } else if is_vfork_syscall(original_syscall, r.arch()) {
+        ptrace_event = PTRACE_EVENT_VFORK;
+        flags = (CLONE_VM | CLONE_VFORK | SIGCHLD) as usize;
+    } else {
+        ptrace_event = PTRACE_EVENT_FORK;
+        flags = SIGCHLD as usize;
+    }
+
+    loop {
+        t.resume_execution(
+            ResumeRequest::ResumeSyscall,
+            WaitRequest::ResumeWait,
+            TicksRequest::ResumeNoTicks,
+            None,
+        );
+        // XXX handle stray signals?
+        if t.maybe_ptrace_event().is_ptrace_event() {
+            break;
+        }
+        ed_assert!(t, t.maybe_stop_sig().is_not_sig());
+        ed_assert!(t, t.regs_ref().syscall_result_signed() < 0);
+        if !t.regs_ref().syscall_may_restart() {
+            log!(
+                LogDebug,
+                "clone failed, returning {}",
+                errno_name(-t.regs_ref().syscall_result_signed() as i32)
+            );
+            syscall_state.emulate_result(t.regs_ref().syscall_result());
+            // clone failed and we're existing the syscall with an error. Reenter
+            // the syscall so that we're in the same state as the normal execution
+            // path.
+            t.ev_mut().syscall_mut().failed_during_preparation = true;
+            // Restore register we might have changed
+            r.set_arg1(syscall_state.syscall_entry_registers.arg1());
+            r.set_syscallno(Arch::GETTID as isize);
+            r.set_ip(r.ip().decrement_by_syscall_insn_length(r.arch()));
+            t.set_regs(&r);
+            t.enter_syscall();
+            r.set_ip(t.regs_ref().ip());
+            r.set_syscallno(original_syscall as isize);
+            r.set_original_syscallno(original_syscall as isize);
+            t.set_regs(&r);
+            t.canonicalize_regs(t.arch());
+            return Switchable::AllowSwitch;
+        }
+        // Reenter the syscall. If we try to return an ERESTART* error using the
+        // code path above, our set_syscallno(SYS_gettid) fails to take effect and
+        // we actually do the clone, and things get horribly confused.
+        r.set_syscallno(r.original_syscallno());
+        r.set_ip(r.ip().decrement_by_syscall_insn_length(r.arch()));
+        t.set_regs(&r);
+        t.enter_syscall();
+    }
+
+    ed_assert_eq!(t, t.maybe_ptrace_event().unwrap_event(), ptrace_event);
+
+    // Ideally we'd just use t.get_ptrace_eventmsg_pid() here, but
+    // kernels failed to translate that value from other pid namespaces to
+    // our pid namespace until June 2014:
+    // https://github.com/torvalds/linux/commit/4e52365f279564cef0ddd41db5237f0471381093
+    let new_tid: pid_t = if flags & CLONE_THREAD as usize != 0 {
+        t.find_newborn_thread()
+    } else {
+        let parent = if flags & CLONE_PARENT as usize != 0 {
+            t.get_parent_pid()
+        } else {
+            t.real_tgid()
+        };
+        t.find_newborn_process(parent)
+    };
+
+    let new_task_shr_ptr: TaskSharedPtr = t.session().clone_task(
+        t,
+        clone_flags_to_task_flags(flags as i32),
+        params.stack,
+        params.tls,
+        params.ctid,
+        new_tid,
+        None,
+    );
+    let mut new_task_b = new_task_shr_ptr.borrow_mut();
+    let new_task = new_task_b.as_record_task_mut().unwrap();
+
+    // Restore modified registers in cloned task
+    let mut new_r = new_task.regs_ref().clone();
+    new_r.set_original_syscallno(syscall_state.syscall_entry_registers.original_syscallno());
+    new_r.set_arg1(syscall_state.syscall_entry_registers.arg1());
+    new_task.set_regs(&new_r);
+    let new_arch = new_task.detect_syscall_arch();
+    new_task.canonicalize_regs(new_arch);
+    new_task.set_termination_signal(Sig::try_from(termination_signal).ok());
+
+    // record child id here
+    if is_clone_syscall(original_syscall, r.arch()) {
+        let child_params = extract_clone_parameters(new_task);
+        t.record_remote_even_if_null_for(params.ptid);
+
+        if Arch::CLONE_TLS_TYPE == CloneTLSType::UserDescPointer {
+            t.record_remote_even_if_null(params.tls, size_of::<user_desc>());
+            new_task.record_remote_even_if_null(child_params.tls, size_of::<user_desc>());
+        } else {
+            debug_assert!(Arch::CLONE_TLS_TYPE == CloneTLSType::PthreadStructurePointer);
+        }
+        new_task.record_remote_even_if_null_for(child_params.ptid);
+        new_task.record_remote_even_if_null_for(child_params.ctid);
+    }
+    let ev = TraceTaskEvent::for_clone(
+        new_task.tid,
+        t.tid,
+        new_task.own_namespace_rec_tid,
+        flags as i32,
+    );
+    t.trace_writer_mut().write_task_event(&ev);
+
+    init_scratch_memory(new_task, None);
+
+    // If the tracee wants a PTRACE_EVENT_CLONE/FORK/VFORK, emulate it.
+    if t.emulated_ptrace_options & ptrace_option_for_event(ptrace_event) != 0
+        && flags & CLONE_UNTRACED as usize == 0
+    {
+        let tracer = t.emulated_ptracer.as_ref().unwrap().upgrade().unwrap();
+        new_task.set_emulated_ptracer(tracer.borrow_mut().as_record_task_mut().unwrap());
+        new_task.emulated_ptrace_seized = t.emulated_ptrace_seized;
+        new_task.emulated_ptrace_options = t.emulated_ptrace_options;
+        t.emulated_ptrace_event_msg = new_task.rec_tid as usize;
+        t.emulate_ptrace_stop(WaitStatus::for_ptrace_event(ptrace_event), None, None);
+        // ptrace(2) man page says that SIGSTOP is used here, but it's really
+        // SIGTRAP (in 4.4.4-301.fc23.x86_64 anyway).
+        new_task.apply_group_stop(sig::SIGTRAP);
+    }
+
+    // Restore our register modifications now, so that the emulated ptracer will
+    // see the original registers without our modifications if it inspects them
+    // in the ptrace event.
+    r = t.regs_ref().clone();
+    r.set_arg1(syscall_state.syscall_entry_registers.arg1());
+    r.set_original_syscallno(syscall_state.syscall_entry_registers.original_syscallno());
+    t.set_regs(&r);
+    t.canonicalize_regs(t.arch());
+
+    // We're in a PTRACE_EVENT_FORK/VFORK/CLONE so the next PTRACE_SYSCALL for
+    // `t` will go to the exit of the syscall, as expected.
+    Switchable::PreventSwitch
+}
+
+/// ptrace options we know how to emulate.
+const SUPPORTED_PTRACE_OPTIONS: u32 = PTRACE_O_TRACESYSGOOD
+    | PTRACE_O_TRACEEXIT
+    | PTRACE_O_TRACEFORK
+    | PTRACE_O_TRACEVFORK
+    | PTRACE_O_TRACEVFORKDONE
+    | PTRACE_O_TRACECLONE
+    | PTRACE_O_TRACEEXEC
+    | PTRACE_O_TRACESECCOMP;
+
+fn ptrace_option_for_event(ptrace_event: u32) -> u32 {
+    match ptrace_event {
+        PTRACE_EVENT_FORK => PTRACE_O_TRACEFORK,
+        PTRACE_EVENT_CLONE => PTRACE_O_TRACECLONE,
+        PTRACE_EVENT_VFORK => PTRACE_O_TRACEVFORK,
+        _ => fatal!("Unsupported ptrace event {}", ptrace_event),
+    }
+}
+
+/// Return the task with tid `pid`, if any. `t` can never be its own ptrace
+/// partner (and it is already borrowed) so it is never returned.
+fn get_ptrace_partner(t: &RecordTask, pid: pid_t) -> Option<TaskSharedPtr> {
+    if pid == t.rec_tid {
+        return None;
+    }
+    t.session().find_task_from_rec_tid(pid)
+}
+
+fn prepare_ptrace_attach(
+    t: &RecordTask,
+    pid: pid_t,
+    syscall_state: &mut TaskSyscallState,
+) -> Option<TaskSharedPtr> {
+    if pid == t.rec_tid {
+        syscall_state.emulate_result_signed(-EPERM as isize);
+        return None;
+    }
+    let tracee_rc = match get_ptrace_partner(t, pid) {
+        Some(tracee_rc) => tracee_rc,
+        None => {
+            syscall_state.emulate_result_signed(-ESRCH as isize);
+            return None;
+        }
+    };
+    {
+        let tracee_b = tracee_rc.borrow();
+        let tracee = tracee_b.as_record_task().unwrap();
+        if tracee.emulated_ptracer.is_some() || tracee.tgid() == t.tgid() {
+            syscall_state.emulate_result_signed(-EPERM as isize);
+            return None;
+        }
+    }
+    Some(tracee_rc)
+}
+
+/// Return the tracee `pid` if `t` is its emulated ptracer and the tracee is
+/// stopped, otherwise make the syscall fail with ESRCH.
+fn verify_ptrace_target(
+    t: &RecordTask,
+    syscall_state: &mut TaskSyscallState,
+    pid: pid_t,
+) -> Option<TaskSharedPtr> {
+    if let Some(tracee_rc) = get_ptrace_partner(t, pid) {
+        let ok = {
+            let tracee_b = tracee_rc.borrow();
+            let tracee = tracee_b.as_record_task().unwrap();
+            tracee
+                .emulated_ptracer
+                .as_ref()
+                .map_or(false, |p| p.ptr_eq(&t.weak_self_ptr()))
+                && tracee.emulated_stop_type != EmulatedStopType::NotStopped
+        };
+        if ok {
+            return Some(tracee_rc);
+        }
+    }
+    syscall_state.emulate_result_signed(-ESRCH as isize);
+    None
+}
+
+fn ptrace_attach_to_already_stopped_task(t: &mut RecordTask) {
+    ed_assert!(t, t.emulated_stop_type == EmulatedStopType::GroupStop);
+    // tracee is already stopped because of a group-stop signal.
+    // Sending a SIGSTOP won't work, but we don't need to.
+    t.force_emulate_ptrace_stop(WaitStatus::for_stop_sig(sig::SIGSTOP));
+    let mut si: siginfo_t = Default::default();
+    si.si_signo = SIGSTOP;
+    si.si_code = SI_USER;
+    t.save_ptrace_signal_siginfo(&si);
+}
+
+fn prepare_ptrace_cont(tracee: &mut RecordTask, maybe_sig: Option<Sig>, command: u32) {
+    if let Some(sig) = maybe_sig {
+        let si = tracee.take_ptrace_signal_siginfo(sig);
+        log!(LogDebug, "Doing ptrace resume with signal {}", sig);
+        // Treat signal as nondeterministic; it won't happen just by
+        // replaying the tracee.
+        let disposition =
+            tracee.sig_resolved_disposition(sig, SignalDeterministic::NondeterministicSig);
+        tracee.push_event(Event::new_signal_event(
+            EventType::EvSignal,
+            SignalEventData::new(&si, SignalDeterministic::NondeterministicSig, disposition),
+        ));
+    }
+
+    tracee.emulated_stop_type = EmulatedStopType::NotStopped;
+    tracee.emulated_stop_pending = false;
+    tracee.emulated_stop_code = WaitStatus::default();
+    tracee.emulated_ptrace_cont_command = command;
+
+    if tracee.ev().is_syscall_event()
+        && SyscallState::ProcessingSyscall == tracee.ev().syscall().state
+    {
+        // Continue the task since we didn't in enter_syscall
+        tracee.resume_execution(
+            ResumeRequest::ResumeSyscall,
+            WaitRequest::ResumeNonblocking,
+            TicksRequest::ResumeNoTicks,
+            None,
+        );
+    }
+}
+
+fn prepare_ptrace<Arch: Architecture>(
+    t: &mut RecordTask,
+    syscall_state: &mut TaskSyscallState,
+) -> Switchable {
+    let regs = t.regs_ref().clone();
+    let pid = regs.arg2_signed() as pid_t;
+    let mut emulate = true;
+    let command = regs.arg1() as u32;
+    match command {
+        PTRACE_ATTACH => {
+            if let Some(tracee_rc) = prepare_ptrace_attach(t, pid, syscall_state) {
+                {
+                    let mut tracee_b = tracee_rc.borrow_mut();
+                    let tracee = tracee_b.as_record_task_mut().unwrap();
+                    tracee.set_emulated_ptracer(t);
+                    tracee.emulated_ptrace_seized = false;
+                    tracee.emulated_ptrace_options = 0;
+                    syscall_state.emulate_result(0);
+                    if tracee.emulated_stop_type == EmulatedStopType::NotStopped {
+                        // Send SIGSTOP to this specific thread. Otherwise the kernel might
+                        // deliver SIGSTOP to some other thread of the process, and we won't
+                        // generate any ptrace event if that thread isn't being ptraced.
+                        tracee.tgkill(sig::SIGSTOP);
+                    } else {
+                        ptrace_attach_to_already_stopped_task(tracee);
+                    }
+                }
+                // We're the ptracer so force_emulate_ptrace_stop() couldn't notify us.
+                t.send_synthetic_sigchld_if_necessary();
+            }
+        }
+        PTRACE_TRACEME => {
+            let maybe_tracer = get_ptrace_partner(t, t.get_parent_pid());
+            match maybe_tracer {
+                None => syscall_state.emulate_result_signed(-ESRCH as isize),
+                Some(_) if t.emulated_ptracer.is_some() => {
+                    syscall_state.emulate_result_signed(-EPERM as isize)
+                }
+                Some(tracer_rc) => {
+                    let mut tracer_b = tracer_rc.borrow_mut();
+                    t.set_emulated_ptracer(tracer_b.as_record_task_mut().unwrap());
+                    t.emulated_ptrace_seized = false;
+                    t.emulated_ptrace_options = 0;
+                    syscall_state.emulate_result(0);
+                }
+            }
+        }
+        PTRACE_SEIZE => {
+            if let Some(tracee_rc) = prepare_ptrace_attach(t, pid, syscall_state) {
+                let data = regs.arg4() as u32;
+                if regs.arg3() != 0 {
+                    syscall_state.emulate_result_signed(-EIO as isize);
+                } else if data & !SUPPORTED_PTRACE_OPTIONS != 0 {
+                    log!(LogWarn, "Unsupported ptrace options {:#x}", data);
+                    syscall_state.emulate_result_signed(-EINVAL as isize);
+                } else {
+                    {
+                        let mut tracee_b = tracee_rc.borrow_mut();
+                        let tracee = tracee_b.as_record_task_mut().unwrap();
+                        tracee.set_emulated_ptracer(t);
+                        tracee.emulated_ptrace_seized = true;
+                        tracee.emulated_ptrace_options = data;
+                        if tracee.emulated_stop_type == EmulatedStopType::GroupStop {
+                            ptrace_attach_to_already_stopped_task(tracee);
+                        }
+                    }
+                    t.send_synthetic_sigchld_if_necessary();
+                    syscall_state.emulate_result(0);
+                }
+            }
+        }
+        PTRACE_SETOPTIONS => {
+            if let Some(tracee_rc) = verify_ptrace_target(t, syscall_state, pid) {
+                let data = regs.arg4() as u32;
+                if data & !SUPPORTED_PTRACE_OPTIONS != 0 {
+                    log!(LogWarn, "Unsupported ptrace options {:#x}", data);
+                    syscall_state.emulate_result_signed(-EINVAL as isize);
+                } else {
+                    tracee_rc
+                        .borrow_mut()
+                        .as_record_task_mut()
+                        .unwrap()
+                        .emulated_ptrace_options = data;
+                    syscall_state.emulate_result(0);
+                }
+            }
+        }
+        PTRACE_GETEVENTMSG => {
+            if let Some(tracee_rc) = verify_ptrace_target(t, syscall_state, pid) {
+                let datap = syscall_state.reg_parameter::<Arch::unsigned_long>(t, 4, None);
+                let msg = tracee_rc
+                    .borrow()
+                    .as_record_task()
+                    .unwrap()
+                    .emulated_ptrace_event_msg;
+                let bytes = msg.to_le_bytes();
+                write_mem(
+                    t,
+                    RemotePtr::<u8>::cast(datap),
+                    &bytes[0..size_of::<Arch::unsigned_long>()],
+                    None,
+                );
+                syscall_state.emulate_result(0);
+            }
+        }
+        PTRACE_GETSIGINFO => {
+            if let Some(tracee_rc) = verify_ptrace_target(t, syscall_state, pid) {
+                let datap = syscall_state.reg_parameter::<Arch::siginfo_t>(t, 4, None);
+                let si = tracee_rc
+                    .borrow()
+                    .as_record_task()
+                    .unwrap()
+                    .get_saved_ptrace_siginfo()
+                    .clone();
+                if Arch::arch() != NativeArch::arch() {
+                    // DIFF NOTE: rr converts the siginfo to the x86 layout here. We
+                    // don't have that conversion yet, so refuse instead of handing
+                    // a 32-bit ptracer a garbled siginfo.
+                    log!(
+                        LogWarn,
+                        "PTRACE_GETSIGINFO from a 32-bit ptracer is not supported"
+                    );
+                    syscall_state.emulate_result_signed(-EIO as isize);
+                } else {
+                    write_mem(
+                        t,
+                        RemotePtr::<u8>::cast(datap),
+                        &u8_slice(&si)[0..size_of::<Arch::siginfo_t>()],
+                        None,
+                    );
+                    syscall_state.emulate_result(0);
+                }
+            }
+        }
+        PTRACE_GETREGS => {
+            if let Some(tracee_rc) = verify_ptrace_target(t, syscall_state, pid) {
+                let data = syscall_state.reg_parameter::<Arch::user_regs_struct>(t, 4, None);
+                let regs_data = tracee_rc
+                    .borrow()
+                    .regs_ref()
+                    .get_ptrace_for_arch(Arch::arch());
+                ed_assert_eq!(t, regs_data.len(), size_of::<Arch::user_regs_struct>());
+                write_mem(t, RemotePtr::<u8>::cast(data), &regs_data, None);
+                syscall_state.emulate_result(0);
+            }
+        }
+        PTRACE_GETFPREGS => {
+            if let Some(tracee_rc) = verify_ptrace_target(t, syscall_state, pid) {
+                let data = syscall_state.reg_parameter::<Arch::user_fpregs_struct>(t, 4, None);
+                let regs_data = tracee_rc
+                    .borrow_mut()
+                    .extra_regs_ref()
+                    .get_user_fpregs_struct(Arch::arch());
+                ed_assert_eq!(t, regs_data.len(), size_of::<Arch::user_fpregs_struct>());
+                write_mem(t, RemotePtr::<u8>::cast(data), &regs_data, None);
+                syscall_state.emulate_result(0);
+            }
+        }
+        PTRACE_GETREGSET => {
+            if let Some(tracee_rc) = verify_ptrace_target(t, syscall_state, pid) {
+                match regs.arg3() as u32 {
+                    NT_PRSTATUS => {
+                        let regs_data = tracee_rc
+                            .borrow()
+                            .regs_ref()
+                            .get_ptrace_for_arch(Arch::arch());
+                        ptrace_get_reg_set::<Arch>(t, syscall_state, &regs_data);
+                    }
+                    NT_FPREGSET => {
+                        let regs_data = tracee_rc
+                            .borrow_mut()
+                            .extra_regs_ref()
+                            .get_user_fpregs_struct(Arch::arch());
+                        ptrace_get_reg_set::<Arch>(t, syscall_state, &regs_data);
+                    }
+                    NT_X86_XSTATE => {
+                        let maybe_xsave = {
+                            let mut tracee = tracee_rc.borrow_mut();
+                            let extra_regs = tracee.extra_regs_ref();
+                            match extra_regs.format() {
+                                Format::XSave => Some(extra_regs.data()),
+                                _ => None,
+                            }
+                        };
+                        match maybe_xsave {
+                            Some(regs_data) => {
+                                ptrace_get_reg_set::<Arch>(t, syscall_state, &regs_data)
+                            }
+                            None => syscall_state.emulate_result_signed(-EINVAL as isize),
+                        }
+                    }
+                    _ => {
+                        syscall_state.expect_errno = EINVAL;
+                        emulate = false;
+                    }
+                }
+            }
+        }
+        PTRACE_SETREGS | PTRACE_SETFPREGS => {
+            if verify_ptrace_target(t, syscall_state, pid).is_some() {
+                // The actual register effects are performed by
+                // on_syscall_exit()
+                syscall_state.emulate_result(0);
+            }
+        }
+        PTRACE_PEEKTEXT | PTRACE_PEEKDATA => {
+            if let Some(tracee_rc) = verify_ptrace_target(t, syscall_state, pid) {
+                // The actual syscall returns the data via the 'data' out-parameter.
+                // The behavior of returning the data as the system call result is
+                // provided by the glibc wrapper.
+                let datap = syscall_state.reg_parameter::<Arch::unsigned_word>(t, 4, None);
+                let addr = RemotePtr::<u8>::from(regs.arg3());
+                let mut ok = true;
+                let v = read_mem(
+                    &mut **tracee_rc.borrow_mut(),
+                    addr,
+                    size_of::<Arch::unsigned_word>(),
+                    Some(&mut ok),
+                );
+                if ok {
+                    write_mem(t, RemotePtr::<u8>::cast(datap), &v, None);
+                    syscall_state.emulate_result(0);
+                } else {
+                    syscall_state.emulate_result_signed(-EIO as isize);
+                }
+            }
+        }
+        PTRACE_POKETEXT | PTRACE_POKEDATA => {
+            if let Some(tracee_rc) = verify_ptrace_target(t, syscall_state, pid) {
+                let addr = RemotePtr::<u8>::from(regs.arg3());
+                let bytes = regs.arg4().to_le_bytes();
+                let data = &bytes[0..size_of::<Arch::unsigned_word>()];
+                let mut tracee_b = tracee_rc.borrow_mut();
+                let tracee = tracee_b.as_record_task_mut().unwrap();
+                let mut ok = true;
+                write_mem(tracee, addr, data, Some(&mut ok));
+                if ok {
+                    // Since we're recording data that might not be for `t`, we have to
+                    // handle this specially during replay.
+                    tracee.record_local(RemotePtr::cast(addr), data);
+                    syscall_state.emulate_result(0);
+                } else {
+                    syscall_state.emulate_result_signed(-EIO as isize);
+                }
+            }
+        }
+        PTRACE_CONT
+        | PTRACE_SYSCALL
+        | PTRACE_SINGLESTEP
+        | PTRACE_SYSEMU
+        | PTRACE_SYSEMU_SINGLESTEP => {
+            if let Some(tracee_rc) = verify_ptrace_target(t, syscall_state, pid) {
+                match Sig::try_from(regs.arg4() as i32) {
+                    Err(_) if regs.arg4() != 0 => {
+                        syscall_state.emulate_result_signed(-EIO as isize)
+                    }
+                    maybe_sig => {
+                        prepare_ptrace_cont(
+                            tracee_rc.borrow_mut().as_record_task_mut().unwrap(),
+                            maybe_sig.ok(),
+                            command,
+                        );
+                        syscall_state.emulate_result(0);
+                    }
+                }
+            }
+        }
+        PTRACE_DETACH => {
+            if let Some(tracee_rc) = verify_ptrace_target(t, syscall_state, pid) {
+                let mut tracee_b = tracee_rc.borrow_mut();
+                let tracee = tracee_b.as_record_task_mut().unwrap();
+                tracee.clear_emulated_ptracer(t);
+                tracee.emulated_ptrace_seized = false;
+                tracee.emulated_ptrace_options = 0;
+                prepare_ptrace_cont(tracee, Sig::try_from(regs.arg4() as i32).ok(), 0);
+                syscall_state.emulate_result(0);
+            }
+        }
+        PTRACE_KILL => {
+            if let Some(tracee_rc) = verify_ptrace_target(t, syscall_state, pid) {
+                tracee_rc
+                    .borrow()
+                    .as_record_task()
+                    .unwrap()
+                    .tgkill(sig::SIGKILL);
+                syscall_state.emulate_result(0);
+            }
+        }
+        PTRACE_INTERRUPT => {
+            // PTRACE_INTERRUPT isn't emulated. Fail loudly rather than let the
+            // kernel reject it in some confusing way.
+            log!(
+                LogWarn,
+                "Emulated {} is not supported",
+                ptrace_req_name(command)
+            );
+            syscall_state.emulate_result_signed(-EIO as isize);
+        }
+        _ => {
+            syscall_state.expect_errno = EIO;
+            emulate = false;
+        }
+    }
+
+    if emulate {
+        let mut r = t.regs_ref().clone();
+        r.set_arg1(usize::MAX);
+        t.set_regs(&r);
+    }
+    Switchable::PreventSwitch
+}
+
+/// Write `regs` into the buffer described by the iovec in arg4 of a
+/// PTRACE_GETREGSET, truncating it to the buffer size, and update the iovec's
+/// length.
+fn ptrace_get_reg_set<Arch: Architecture>(
+    t: &mut RecordTask,
+    syscall_state: &mut TaskSyscallState,
+    regs: &[u8],
+) {
+    let piov = syscall_state.reg_parameter::<Arch::iovec>(t, 4, Some(ArgMode::InOut));
+    let mut iov = read_val_mem(t, piov, None);
+    let (iov_base, iov_len) = Arch::get_iovec(&iov);
+    let iov_len = min(iov_len, regs.len());
+    Arch::set_iovec(&mut iov, iov_base, iov_len);
+    write_val_mem(t, piov, &iov, None);
+    let data = syscall_state.mem_ptr_parameter_with_size(
+        t,
+        RemotePtr::cast(piov) + arch_offset_of!(Arch, iovec, iov_base),
+        ParamSize::from(iov_len),
+        None,
+    );
+    write_mem(t, data, &regs[0..iov_len], None);
+    syscall_state.emulate_result(0);
+}
+
+/// If `t` is waiting for a tracee (or stopped child) that has a stop to report,
+/// remember it in `syscall_state` so the wait can be emulated and return true.
+fn maybe_emulate_wait(t: &RecordTask, syscall_state: &mut TaskSyscallState, options: i32) -> bool {
+    for tracee in t.emulated_ptrace_tracees.iter() {
+        let tracee_b = tracee.borrow();
+        let rtracee = tracee_b.as_record_task().unwrap();
+        if t.is_waiting_for_ptrace(rtracee) && rtracee.emulated_stop_pending {
+            syscall_state.emulate_wait_for_child = Some(Rc::downgrade(&tracee));
+            return true;
+        }
+    }
+    if options & WUNTRACED != 0 {
+        for child_process in t.thread_group().children().iter() {
+            for child in child_process.borrow().task_set().iter() {
+                let child_b = child.borrow();
+                let rchild = child_b.as_record_task().unwrap();
+                if rchild.emulated_stop_type == EmulatedStopType::GroupStop
+                    && rchild.emulated_stop_pending
+                    && t.is_waiting_for(rchild)
+                {
+                    syscall_state.emulate_wait_for_child = Some(Rc::downgrade(&child));
+                    return true;
+                }
+            }
+        }
+    }
+    false
+}
+
+fn maybe_pause_instead_of_waiting(t: &mut RecordTask, options: i32) {
+    if t.in_wait_type != WaitType::WaitTypePid || (options & WNOHANG) != 0 {
+        return;
+    }
+    let child_rc = match get_ptrace_partner(t, t.in_wait_pid) {
+        Some(child_rc) => child_rc,
+        None => return,
+    };
+    {
+        let child_b = child_rc.borrow();
+        let child = child_b.as_record_task().unwrap();
+        if !t.is_waiting_for_ptrace(child) || t.is_waiting_for(child) {
+            return;
+        }
+    }
+    // OK, t is waiting for a ptrace child by tid, but since t is not really
+    // ptracing child, entering a real wait syscall will not actually wait for
+    // the child, so the kernel may error out with ECHILD (non-ptracers can't
+    // wait on specific threads of another process, or for non-child processes).
+    // To avoid this problem, we'll replace the wait syscall with a pause()
+    // syscall.
+    // It would be nice if we didn't have to do this, but I can't see a better
+    // way.
+    let mut r = t.regs_ref().clone();
+    r.set_original_syscallno(syscall_number_for_pause(t.arch()) as isize);
+    t.set_regs(&r);
+}
+
+fn prepare_exit(t: &mut RecordTask, exit_code: i32) {
+    // RecordSession is responsible for ensuring we don't get here with
+    // pending signals.
+    ed_assert!(t, !t.has_any_stashed_sig());
+
+    t.stable_exit = true;
+    t.exit_code = exit_code;
+    t.session()
+        .as_record()
+        .unwrap()
+        .scheduler()
+        .in_stable_exit(t);
+
+    let arch = t.ev().syscall().arch();
+    let original_syscallno = t.regs_ref().original_syscallno() as i32;
+    ed_assert!(
+        t,
+        is_exit_syscall(original_syscallno, arch)
+            || is_exit_group_syscall(original_syscallno, arch),
+        "Tracee should have been at exit/exit_group, but instead at {}",
+        t.ev().syscall().syscall_name()
+    );
+
+    // Destroy our buffers now. It's possible for another mapping to be created
+    // overlapping our buffers before this task truly exits, and we don't want
+    // to trash that mapping by destroying our buffers then. Replay does the
+    // same thing at the same point.
+    if !t.exit_syscall_and_prepare_restart() {
+        // The task died. RecordSession will pick up the PTRACE_EVENT_EXIT.
+        return;
+    }
+    t.destroy_buffers();
+    // Reenter the exit syscall.
+    t.enter_syscall();
+    let mut r = t.regs_ref().clone();
+    r.set_original_syscallno(original_syscallno as isize);
+    t.set_regs(&r);
+}
+
+/// Memory advice values we know to be harmless for recording.
+fn is_known_madvise(advice: i32) -> bool {
+    match advice {
+        MADV_NORMAL | MADV_RANDOM | MADV_SEQUENTIAL | MADV_WILLNEED | MADV_DONTNEED
+        | MADV_REMOVE | MADV_DONTFORK | MADV_DOFORK | MADV_SOFT_OFFLINE | MADV_HWPOISON
+        | MADV_MERGEABLE | MADV_UNMERGEABLE | MADV_HUGEPAGE | MADV_NOHUGEPAGE | MADV_DONTDUMP
+        | MADV_DODUMP => true,
+        _ => false,
+    }
+}
+
+/// Opening these files is known to cause trouble under rd, so we make such
+/// opens fail with ENOENT.
+fn is_blacklisted_filename(filename: &[u8]) -> bool {
+    filename.starts_with(b"/dev/dri/")
+        || filename == b"/dev/nvidiactl"
+        || filename == b"/usr/share/alsa/alsa.conf"
+        || filename == b"/dev/nvidia-uvm"
+}
+
+fn is_blacklisted_memfd(name: &[u8]) -> bool {
+    name == b"pulseaudio"
+}
+
+fn rec_prepare_syscall_arch<Arch: Architecture>(
+    t: &mut RecordTask,
+    syscall_state: &mut TaskSyscallState,
+    regs: &Registers,
+) -> Switchable {
+    let sys = t.ev().syscall().number;
+
+    if t.regs_ref().original_syscallno() == SECCOMP_MAGIC_SKIP_ORIGINAL_SYSCALLNO {
+        // rd vetoed this syscall. Don't do any pre-processing.
+        return Switchable::PreventSwitch;
+    }
+
+    syscall_state.syscall_entry_registers = regs.clone();
+
+    if !t.desched_rec().is_null() {
+        // |t| was descheduled while in a buffered syscall.  We normally don't
+        // use scratch memory for the call, because the syscallbuf itself
+        // is serving that purpose. More importantly, we *can't* set up
+        // scratch for |t|, because it's already in the syscall. Instead, we will
+        // record the syscallbuf memory in rec_process_syscall_arch.
+        //
+        // However there is one case where we use scratch memory: when
+        // sys_read's block-cloning path is interrupted. In that case, record
+        // the scratch memory.
+        if sys == Arch::READ && regs.arg2() == t.scratch_ptr.as_usize() {
+            syscall_state.reg_parameter_with_size(
+                t,
+                2,
+                ParamSize::from_syscall_result::<Arch::signed_long>(Some(regs.arg3())),
+                Some(ArgMode::InOutNoScratch),
+            );
+        }
+        return Switchable::AllowSwitch;
+    }
+
+    if sys < 0 {
+        // Invalid syscall. Don't let it accidentally match a
+        // syscall number below that's for an undefined syscall.
+        syscall_state.expect_errno = ENOSYS;
+        return Switchable::PreventSwitch;
+    }
+
+    // Syscalls that are fully described by their parameter types.
+    include!(concat!(
+        env!("OUT_DIR"),
+        "/syscall_record_case_generated.rs"
+    ));
+
+    if sys == Arch::SPLICE {
+        syscall_state.reg_parameter::<loff_t>(t, 2, Some(ArgMode::InOut));
+        syscall_state.reg_parameter::<loff_t>(t, 4, Some(ArgMode::InOut));
+        return Switchable::AllowSwitch;
+    }
+
+    if sys == Arch::SENDFILE {
+        syscall_state.reg_parameter_with_size(
+            t,
+            3,
+            ParamSize::from(size_of::<Arch::off_t>()),
+            Some(ArgMode::InOut),
+        );
+        return Switchable::AllowSwitch;
+    }
+
+    if sys == Arch::SENDFILE64 {
+        syscall_state.reg_parameter::<off64_t>(t, 3, Some(ArgMode::InOut));
+        return Switchable::AllowSwitch;
+    }
+
+    if sys == Arch::COPY_FILE_RANGE {
+        syscall_state.reg_parameter::<loff_t>(t, 2, Some(ArgMode::InOut));
+        syscall_state.reg_parameter::<loff_t>(t, 4, Some(ArgMode::InOut));
+        return Switchable::AllowSwitch;
+    }
+
+    if sys == Arch::CAPGET {
+        let hdr: x86::__user_cap_header_struct =
+            read_val_mem(t, RemotePtr::from(regs.arg1()), None);
+        let mut struct_count: usize = 0;
+        match hdr.version {
+            _LINUX_CAPABILITY_VERSION_1 => struct_count = _LINUX_CAPABILITY_U32S_1 as usize,
+            _LINUX_CAPABILITY_VERSION_2 => struct_count = _LINUX_CAPABILITY_U32S_2 as usize,
+            _LINUX_CAPABILITY_VERSION_3 => struct_count = _LINUX_CAPABILITY_U32S_3 as usize,
+            _ => (),
+        }
+        if struct_count > 0 {
+            syscall_state.reg_parameter_with_size(
+                t,
+                2,
+                ParamSize::from(size_of::<x86::__user_cap_data_struct>() * struct_count),
+                Some(ArgMode::Out),
+            );
+        }
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::VFORK || sys == Arch::FORK || sys == Arch::CLONE {
+        return prepare_clone::<Arch>(t, syscall_state);
+    }
+
+    if sys == Arch::EXIT {
+        prepare_exit(t, regs.arg1() as i32);
+        return Switchable::AllowSwitch;
+    }
+
+    if sys == Arch::EXIT_GROUP {
+        if t.thread_group().task_set().len() == 1 {
+            prepare_exit(t, regs.arg1() as i32);
+            return Switchable::AllowSwitch;
+        }
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::EXECVE {
+        let mut cmd_line: Vec<OsString> = Vec::new();
+        let mut argv = RemotePtr::<Void>::from(regs.arg2());
+        loop {
+            let p = get_remote_ptr(t, argv);
+            if p.is_null() {
+                break;
+            }
+            cmd_line.push(OsString::from_vec(
+                t.read_c_str(RemotePtr::cast(p)).into_bytes(),
+            ));
+            argv = argv + size_of::<Arch::unsigned_word>();
+        }
+
+        // Save the event. We can't record it here because the exec might fail.
+        let raw_filename = t.read_c_str(RemotePtr::from(regs.arg1()));
+        syscall_state.exec_saved_event = Some(Box::new(TraceTaskEvent::for_exec(
+            t.tid,
+            OsStr::from_bytes(raw_filename.as_bytes()),
+            cmd_line,
+        )));
+
+        // This can trigger exits of non-main threads, so we have to
+        // allow them to be handled.
+        return Switchable::AllowSwitch;
+    }
+
+    if sys == Arch::FCNTL || sys == Arch::FCNTL64 {
+        return prepare_fcntl::<Arch>(t, syscall_state, regs);
+    }
+
+    // int futex(int *uaddr, int op, int val, const struct timespec *timeout,
+    //           int *uaddr2, int val3);
+    // futex parameters are in-out but they can't be moved to scratch
+    // addresses.
+    if sys == Arch::FUTEX_TIME64 || sys == Arch::FUTEX {
+        return prepare_futex::<Arch>(t, syscall_state, regs);
+    }
+
+    if sys == Arch::GETRANDOM {
+        syscall_state.reg_parameter_with_size(
+            t,
+            1,
+            ParamSize::from_syscall_result::<i32>(Some(regs.arg2())),
+            None,
+        );
+        return if GRND_NONBLOCK as usize & regs.arg3() != 0 {
+            Switchable::PreventSwitch
+        } else {
+            Switchable::AllowSwitch
+        };
+    }
+
+    if sys == Arch::SET_THREAD_AREA {
+        syscall_state.reg_parameter::<user_desc>(t, 1, Some(ArgMode::InOut));
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::IPC {
+        return prepare_ipc_multiplexer::<Arch>(t, syscall_state, regs);
+    }
+
+    if sys == Arch::MSGCTL
+        || sys == Arch::SEMCTL
+        || sys == Arch::SHMCTL
+        || sys == Arch::MSGRCV
+        || sys == Arch::MSGSND
+        || sys == Arch::SEMOP
+        || sys == Arch::SEMTIMEDOP
+        || sys == Arch::SEMTIMEDOP_TIME64
+        || sys == Arch::SHMAT
+        || sys == Arch::SHMDT
+    {
+        return prepare_ipc::<Arch>(t, syscall_state, regs, sys);
+    }
+
+    if sys == Arch::SOCKETCALL {
+        return prepare_socketcall::<Arch>(t, syscall_state, regs);
+    }
+
+    if sys == Arch::CONNECT
+        || sys == Arch::SENDTO
+        || sys == Arch::SENDMSG
+        || sys == Arch::ACCEPT
+        || sys == Arch::ACCEPT4
+        || sys == Arch::RECVFROM
+        || sys == Arch::RECVMSG
+        || sys == Arch::SENDMMSG
+        || sys == Arch::RECVMMSG
+        || sys == Arch::RECVMMSG_TIME64
+        || sys == Arch::GETSOCKNAME
+        || sys == Arch::GETPEERNAME
+        || sys == Arch::GETSOCKOPT
+        || sys == Arch::SETSOCKOPT
+    {
+        return prepare_socket_syscall::<Arch>(t, syscall_state, regs, sys);
+    }
+
+    if sys == Arch::_NEWSELECT {
+        syscall_state.reg_parameter_with_size(
+            t,
+            2,
+            ParamSize::from(arch_size_of!(Arch, fd_set)),
+            Some(ArgMode::InOut),
+        );
+        syscall_state.reg_parameter_with_size(
+            t,
+            3,
+            ParamSize::from(arch_size_of!(Arch, fd_set)),
+            Some(ArgMode::InOut),
+        );
+        syscall_state.reg_parameter_with_size(
+            t,
+            4,
+            ParamSize::from(arch_size_of!(Arch, fd_set)),
+            Some(ArgMode::InOut),
+        );
+        syscall_state.reg_parameter_with_size(
+            t,
+            5,
+            ParamSize::from(arch_size_of!(Arch, timeval)),
+            Some(ArgMode::InOut),
+        );
+        return Switchable::AllowSwitch;
+    }
+
+    if sys == Arch::SELECT {
+        match Arch::SELECT_SEMANTICS {
+            SelectCallingSemantics::SelectStructArguments => {
+                let argsp = syscall_state.reg_parameter_with_size(
+                    t,
+                    1,
+                    ParamSize::from(arch_size_of!(Arch, select_args)),
+                    Some(ArgMode::In),
+                );
+                for &offset in &[
+                    arch_offset_of!(Arch, select_args, read_fds),
+                    arch_offset_of!(Arch, select_args, write_fds),
+                    arch_offset_of!(Arch, select_args, except_fds),
+                ] {
+                    syscall_state.mem_ptr_parameter_with_size(
+                        t,
+                        argsp + offset,
+                        ParamSize::from(arch_size_of!(Arch, fd_set)),
+                        Some(ArgMode::InOut),
+                    );
+                }
+                syscall_state.mem_ptr_parameter_with_size(
+                    t,
+                    argsp + arch_offset_of!(Arch, select_args, timeout),
+                    ParamSize::from(arch_size_of!(Arch, timeval)),
+                    Some(ArgMode::InOut),
+                );
+            }
+            SelectCallingSemantics::SelectRegisterArguments => {
+                for arg in 2..=4 {
+                    syscall_state.reg_parameter_with_size(
+                        t,
+                        arg,
+                        ParamSize::from(arch_size_of!(Arch, fd_set)),
+                        Some(ArgMode::InOut),
+                    );
+                }
+                syscall_state.reg_parameter_with_size(
+                    t,
+                    5,
+                    ParamSize::from(arch_size_of!(Arch, timeval)),
+                    Some(ArgMode::InOut),
+                );
+            }
+        }
+        return Switchable::AllowSwitch;
+    }
+
+    if sys == Arch::PSELECT6 || sys == Arch::PSELECT6_TIME64 {
+        for arg in 2..=4 {
+            syscall_state.reg_parameter_with_size(
+                t,
+                arg,
+                ParamSize::from(arch_size_of!(Arch, fd_set)),
+                Some(ArgMode::InOut),
+            );
+        }
+        let timespec_size = if sys == Arch::PSELECT6 {
+            arch_size_of!(Arch, timespec)
+        } else {
+            size_of::<x64::timespec>()
+        };
+        syscall_state.reg_parameter_with_size(
+            t,
+            5,
+            ParamSize::from(timespec_size),
+            Some(ArgMode::InOut),
+        );
+        return Switchable::AllowSwitch;
+    }
+
+    if sys == Arch::POLL || sys == Arch::PPOLL || sys == Arch::PPOLL_TIME64 {
+        let nfds = regs.arg2();
+        syscall_state.reg_parameter_with_size(
+            t,
+            1,
+            ParamSize::from(arch_size_of!(Arch, pollfd) * nfds),
+            Some(ArgMode::InOut),
+        );
+        if sys == Arch::PPOLL {
+            syscall_state.reg_parameter_with_size(
+                t,
+                3,
+                ParamSize::from(arch_size_of!(Arch, timespec)),
+                Some(ArgMode::InOut),
+            );
+        } else if sys == Arch::PPOLL_TIME64 {
+            syscall_state.reg_parameter_with_size(
+                t,
+                3,
+                ParamSize::from(size_of::<x64::timespec>()),
+                Some(ArgMode::InOut),
+            );
+        }
+        return Switchable::AllowSwitch;
+    }
+
+    if sys == Arch::PERF_EVENT_OPEN {
+        let pid = regs.arg2_signed() as pid_t;
+        let cpu = regs.arg3_signed() as i32;
+        let flags = regs.arg5();
+        let maybe_target = t.session().find_task_from_rec_tid(pid);
+        if maybe_target.is_some() && cpu == -1 && flags == 0 {
+            let attr: perf_event_attr = read_val_mem(t, RemotePtr::from(regs.arg1()), None);
+            if VirtualPerfCounterMonitor::should_virtualize(&attr) {
+                let mut r = t.regs_ref().clone();
+                // Turn this into an inotify_init() syscall. This just gives us an
+                // allocated fd. Syscalls using this fd will be emulated (or if
+                // the fd is closed, it'll go away).
+                r.set_original_syscallno(syscall_number_for_inotify_init(t.arch()) as isize);
+                t.set_regs(&r);
+                // The monitor is installed in rec_process_syscall_arch, once we
+                // know the fd.
+            }
+        }
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::CLOCK_NANOSLEEP || sys == Arch::CLOCK_NANOSLEEP_TIME64 {
+        let timespec_size = if sys == Arch::CLOCK_NANOSLEEP {
+            arch_size_of!(Arch, timespec)
+        } else {
+            size_of::<x64::timespec>()
+        };
+        syscall_state.reg_parameter_with_size(t, 4, ParamSize::from(timespec_size), None);
+        return Switchable::AllowSwitch;
+    }
+
+    if sys == Arch::NANOSLEEP {
+        syscall_state.reg_parameter_with_size(
+            t,
+            2,
+            ParamSize::from(arch_size_of!(Arch, timespec)),
+            None,
+        );
+        return Switchable::AllowSwitch;
+    }
+
+    if sys == Arch::SCHED_YIELD {
+        t.session()
+            .as_record()
+            .unwrap()
+            .scheduler()
+            .schedule_one_round_robin(t);
+        return Switchable::AllowSwitch;
+    }
+
+    if sys == Arch::RT_SIGPENDING {
+        syscall_state.reg_parameter_with_size(t, 1, ParamSize::from(regs.arg2()), None);
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::RT_SIGTIMEDWAIT || sys == Arch::RT_SIGTIMEDWAIT_TIME64 {
+        syscall_state.reg_parameter_with_size(
+            t,
+            2,
+            ParamSize::from(arch_size_of!(Arch, siginfo_t)),
+            None,
+        );
+        return Switchable::AllowSwitch;
+    }
+
+    if sys == Arch::RT_SIGPROCMASK || sys == Arch::SIGPROCMASK {
+        syscall_state.reg_parameter_with_size(
+            t,
+            3,
+            ParamSize::from(arch_size_of!(Arch, kernel_sigset_t)),
+            None,
+        );
+        let setp = RemotePtr::<u64>::from(regs.arg2());
+        if !setp.is_null() {
+            let sig_set: u64 = read_val_mem(t, setp, None);
+            syscall_state.saved_data = sig_set.to_le_bytes().to_vec();
+            // Don't let the tracee block TIME_SLICE_SIGNAL or
+            // SYSCALLBUF_DESCHED_SIGNAL.
+            let rd_signal_mask = t.session().as_record().unwrap().rd_signal_mask();
+            write_val_mem(t, setp, &(sig_set & !rd_signal_mask), None);
+        }
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::GETGROUPS {
+        // We could record a little less data by restricting the recorded data
+        // to the syscall result * sizeof(Arch::legacy_gid_t), but that would
+        // require more infrastructure and it's not worth worrying about.
+        syscall_state.reg_parameter_with_size(
+            t,
+            2,
+            ParamSize::from(regs.arg1_signed() as i32 as usize * arch_size_of!(Arch, legacy_gid_t)),
+            None,
+        );
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::GETGROUPS32 {
+        // We could record a little less data by restricting the recorded data
+        // to the syscall result * sizeof(Arch::gid_t), but that would
+        // require more infrastructure and it's not worth worrying about.
+        syscall_state.reg_parameter_with_size(
+            t,
+            2,
+            ParamSize::from(regs.arg1_signed() as i32 as usize * size_of::<gid_t>()),
+            None,
+        );
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::WRITE || sys == Arch::WRITEV {
+        let fd = regs.arg1_signed() as i32;
+        return t.fd_table().will_write(t, fd);
+    }
+
+    if sys == Arch::PWRITE64 || sys == Arch::PWRITEV {
+        let fd = regs.arg1_signed() as i32;
+        return t.fd_table().will_write(t, fd);
+    }
+
+    // ssize_t read(int fd, void *buf, size_t count);
+    if sys == Arch::READ {
+        let fd = regs.arg1_signed() as i32;
+        let buf = RemotePtr::<Void>::from(regs.arg2());
+        let ranges = [Range::new(buf, regs.arg3())];
+        let mut result: u64 = 0;
+        let emulated = t
+            .fd_table_shr_ptr()
+            .borrow()
+            .emulate_read(fd, t, &ranges, &mut result);
+        if emulated {
+            // Don't perform this syscall.
+            let mut r = regs.clone();
+            r.set_arg1(usize::MAX);
+            t.set_regs(&r);
+            t.record_remote(buf, result as usize);
+            syscall_state.emulate_result(result as usize);
+            return Switchable::PreventSwitch;
+        }
+        // @TODO rr clones file data into the trace here. For now we just
+        // record the data read.
+        syscall_state.reg_parameter_with_size(
+            t,
+            2,
+            ParamSize::from_syscall_result::<Arch::signed_long>(Some(regs.arg3())),
+            None,
+        );
+        return Switchable::AllowSwitch;
+    }
+
+    if sys == Arch::PREAD64 {
+        syscall_state.reg_parameter_with_size(
+            t,
+            2,
+            ParamSize::from_syscall_result::<Arch::signed_long>(Some(regs.arg3())),
+            None,
+        );
+        return Switchable::AllowSwitch;
+    }
+
+    if sys == Arch::READV || sys == Arch::PREADV {
+        let iovcnt = regs.arg3();
+        let iovecsp_void = syscall_state.reg_parameter_with_size(
+            t,
+            2,
+            ParamSize::from(size_of::<Arch::iovec>() * iovcnt),
+            Some(ArgMode::In),
+        );
+        let iovecsp = RemotePtr::<Arch::iovec>::cast(iovecsp_void);
+        let iovecs = read_mem(t, iovecsp, iovcnt, None);
+        let io_size = ParamSize::from_syscall_result::<Arch::signed_long>(None);
+        for i in 0..iovcnt {
+            let (_, iov_len) = Arch::get_iovec(&iovecs[i]);
+            syscall_state.mem_ptr_parameter_with_size(
+                t,
+                RemotePtr::cast(iovecsp + i) + arch_offset_of!(Arch, iovec, iov_base),
+                io_size.limit_size(iov_len),
+                None,
+            );
+        }
+        return Switchable::AllowSwitch;
+    }
+
+    // pid_t waitpid(pid_t pid, int *status, int options);
+    // pid_t wait4(pid_t pid, int *status, int options, struct rusage
+    // *rusage);
+    if sys == Arch::WAITPID || sys == Arch::WAIT4 {
+        syscall_state.reg_parameter::<i32>(t, 2, Some(ArgMode::InOut));
+        if sys == Arch::WAIT4 {
+            syscall_state.reg_parameter_with_size(
+                t,
+                4,
+                ParamSize::from(arch_size_of!(Arch, rusage)),
+                None,
+            );
+        }
+        let pid = regs.arg1_signed() as pid_t;
+        if pid < -1 {
+            t.in_wait_type = WaitType::WaitTypePgid;
+            t.in_wait_pid = -pid;
+        } else if pid == -1 {
+            t.in_wait_type = WaitType::WaitTypeAny;
+        } else if pid == 0 {
+            t.in_wait_type = WaitType::WaitTypeSamePgid;
+        } else {
+            t.in_wait_type = WaitType::WaitTypePid;
+            t.in_wait_pid = pid;
+        }
+        let options = regs.arg3() as i32;
+        if maybe_emulate_wait(t, syscall_state, options) {
+            let mut r = t.regs_ref().clone();
+            // Set options to an invalid value to force syscall to fail
+            r.set_arg3(0xffffffff);
+            t.set_regs(&r);
+            return Switchable::PreventSwitch;
+        }
+        maybe_pause_instead_of_waiting(t, options);
+        return Switchable::AllowSwitch;
+    }
+
+    if sys == Arch::WAITID {
+        syscall_state.reg_parameter_with_size(
+            t,
+            3,
+            ParamSize::from(arch_size_of!(Arch, siginfo_t)),
+            Some(ArgMode::InOut),
+        );
+        t.in_wait_pid = regs.arg2() as pid_t;
+        match regs.arg1() as u32 {
+            P_ALL => t.in_wait_type = WaitType::WaitTypeAny,
+            P_PID => t.in_wait_type = WaitType::WaitTypePid,
+            P_PGID => t.in_wait_type = WaitType::WaitTypePgid,
+            _ => {
+                syscall_state.expect_errno = EINVAL;
+            }
+        }
+        let options = regs.arg4() as i32;
+        if maybe_emulate_wait(t, syscall_state, options) {
+            let mut r = t.regs_ref().clone();
+            // Set options to an invalid value to force syscall to fail
+            r.set_arg4(0xffffffff);
+            t.set_regs(&r);
+            return Switchable::PreventSwitch;
+        }
+        maybe_pause_instead_of_waiting(t, options);
+        return Switchable::AllowSwitch;
+    }
+
+    if sys == Arch::SETPRIORITY {
+        // The syscall might fail due to insufficient
+        // permissions (e.g. while trying to decrease the nice value
+        // while not root).
+        // We'll choose to honor the new value anyway since we'd like
+        // to be able to test configurations where a child thread
+        // has a lower nice value than its parent, which requires
+        // lowering the child's nice value.
+        if regs.arg1() as u32 == PRIO_PROCESS as u32 {
+            let tid = regs.arg2_signed() as pid_t;
+            let priority = regs.arg3_signed() as i32;
+            if tid == 0 || tid == t.rec_tid {
+                t.session()
+                    .as_record()
+                    .unwrap()
+                    .scheduler()
+                    .update_task_priority(t, priority);
+            } else {
+                match t.session().find_task_from_rec_tid(tid) {
+                    Some(target) => {
+                        let target_b = target.borrow();
+                        t.session()
+                            .as_record()
+                            .unwrap()
+                            .scheduler()
+                            .update_task_priority(target_b.as_record_task().unwrap(), priority);
+                    }
+                    None => (),
+                }
+            }
+        }
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::PAUSE {
+        return Switchable::AllowSwitch;
+    }
+
+    // int ptrace(enum __ptrace_request request, pid_t pid,
+    //            void *addr, void *data);
+    if sys == Arch::PTRACE {
+        return prepare_ptrace::<Arch>(t, syscall_state);
+    }
+
+    if sys == Arch::BRK
+        || sys == Arch::MUNMAP
+        || sys == Arch::PROCESS_VM_READV
+        || sys == Arch::PROCESS_VM_WRITEV
+        || sys == Arch::RDCALL_NOTIFY_SYSCALL_HOOK_EXIT
+        || sys == Arch::MREMAP
+        || sys == Arch::SHMAT
+        || sys == Arch::SHMDT
+    {
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::MMAP {
+        match Arch::MMAP_SEMANTICS {
+            MmapCallingSemantics::StructArguments => {
+                let args: Arch::mmap_args = read_val_mem(t, RemotePtr::from(regs.arg1()), None);
+                let (_, _, flags, _, _) = Arch::get_mmap_args(&args);
+                // XXX fix this
+                ed_assert!(t, flags & MAP_GROWSDOWN == 0);
+            }
+            MmapCallingSemantics::RegisterArguments => {
+                prepare_mmap_register_params(t);
+            }
+        }
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::MMAP2 {
+        prepare_mmap_register_params(t);
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::MPROTECT {
+        // Since we're stripping MAP_GROWSDOWN from kernel mmap calls, we need
+        // to implement PROT_GROWSDOWN ourselves.
+        t.vm_shr_ptr().fixup_mprotect_growsdown_parameters(t);
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::MADVISE {
+        let advice = regs.arg3() as i32;
+        if advice == MADV_FREE {
+            // MADV_FREE introduces nondeterminism --- the kernel zeroes the
+            // pages when under memory pressure. So we don't allow it.
+            let mut r = t.regs_ref().clone();
+            r.set_arg3(usize::MAX);
+            t.set_regs(&r);
+            let orig_advice = regs.arg3();
+            syscall_state.after_syscall_action(Box::new(move |t: &mut RecordTask| {
+                let mut r = t.regs_ref().clone();
+                r.set_arg3(orig_advice);
+                t.set_regs(&r);
+            }));
+        } else if !is_known_madvise(advice) {
+            syscall_state.expect_errno = EINVAL;
+        }
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::MINCORE {
+        syscall_state.reg_parameter_with_size(
+            t,
+            3,
+            ParamSize::from((regs.arg2() + page_size() - 1) / page_size()),
+            None,
+        );
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::GET_MEMPOLICY {
+        syscall_state.reg_parameter::<i32>(t, 1, None);
+        let maxnode = regs.arg3();
+        let align_mask = 8 * size_of::<Arch::unsigned_word>() - 1;
+        let aligned_maxnode = (maxnode + align_mask) & !align_mask;
+        syscall_state.reg_parameter_with_size(t, 2, ParamSize::from(aligned_maxnode / 8), None);
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::MODIFY_LDT {
+        let func = regs.arg1_signed() as i32;
+        if func == 0 || func == 2 {
+            syscall_state.reg_parameter_with_size(
+                t,
+                2,
+                ParamSize::from_syscall_result::<i32>(Some(regs.arg3())),
+                None,
+            );
+        }
+        // @TODO Set the LDT in the replay session, the way set_thread_area is
+        // handled. For now only reads are recorded.
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::GET_THREAD_AREA {
+        syscall_state.reg_parameter::<user_desc>(t, 1, Some(ArgMode::InOut));
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::SIGSUSPEND || sys == Arch::RT_SIGSUSPEND {
+        t.invalidate_sigmask();
+        return Switchable::AllowSwitch;
+    }
+
+    if sys == Arch::SIGRETURN || sys == Arch::RT_SIGRETURN {
+        // If a sigreturn/rt_sigreturn restores a signal mask, we need to
+        // update our view of it.
+        t.invalidate_sigmask();
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::SIGACTION || sys == Arch::RT_SIGACTION {
+        syscall_state.reg_parameter_with_size(
+            t,
+            3,
+            ParamSize::from(size_of::<Arch::kernel_sigaction>()),
+            None,
+        );
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::GETXATTR || sys == Arch::LGETXATTR || sys == Arch::FGETXATTR {
+        syscall_state.reg_parameter_with_size(
+            t,
+            3,
+            ParamSize::from_syscall_result::<isize>(Some(regs.arg4())),
+            None,
+        );
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::LISTXATTR || sys == Arch::LLISTXATTR || sys == Arch::FLISTXATTR {
+        syscall_state.reg_parameter_with_size(
+            t,
+            2,
+            ParamSize::from_syscall_result::<isize>(Some(regs.arg3())),
+            None,
+        );
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::SCHED_SETAFFINITY {
+        // Ignore all sched_setaffinity syscalls. They might interfere
+        // with our own affinity settings.
+        let mut r = t.regs_ref().clone();
+        // Set arg1 to an invalid PID to ensure this syscall is ignored.
+        r.set_arg1(usize::MAX);
+        t.set_regs(&r);
+        syscall_state.emulate_result(0);
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::SCHED_GETAFFINITY {
+        syscall_state.reg_parameter_with_size(
+            t,
+            3,
+            ParamSize::from_syscall_result::<i32>(Some(regs.arg2())),
+            None,
+        );
+        syscall_state.after_syscall_action(Box::new(|t: &mut RecordTask| {
+            if t.regs_ref().syscall_failed() {
+                return;
+            }
+            // Report the affinity mask the scheduler is pretending the task has.
+            let mask = t
+                .session()
+                .as_record()
+                .unwrap()
+                .scheduler()
+                .pretend_affinity_mask();
+            let len = min(size_of::<CpuSet>(), t.regs_ref().syscall_result());
+            let mask_bytes =
+                unsafe { std::slice::from_raw_parts(&mask as *const CpuSet as *const u8, len) }
+                    .to_vec();
+            let addr = RemotePtr::<u8>::from(t.regs_ref().arg3());
+            write_mem(t, addr, &mask_bytes, None);
+            t.record_local(RemotePtr::cast(addr), &mask_bytes);
+        }));
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::SCHED_GETATTR {
+        syscall_state.reg_parameter_with_size(t, 2, ParamSize::from(regs.arg3()), None);
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::SETSID || sys == Arch::SETPGID {
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::GETCWD {
+        syscall_state.reg_parameter_with_size(
+            t,
+            1,
+            ParamSize::from_syscall_result::<Arch::signed_long>(Some(regs.arg2())),
+            None,
+        );
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::GETDENTS || sys == Arch::GETDENTS64 {
+        syscall_state.reg_parameter_with_size(
+            t,
+            2,
+            ParamSize::from_syscall_result::<i32>(Some(regs.arg3())),
+            None,
+        );
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::READLINK {
+        syscall_state.reg_parameter_with_size(
+            t,
+            2,
+            ParamSize::from_syscall_result::<Arch::signed_long>(Some(regs.arg3())),
+            None,
+        );
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::READLINKAT {
+        syscall_state.reg_parameter_with_size(
+            t,
+            3,
+            ParamSize::from_syscall_result::<Arch::signed_long>(Some(regs.arg4())),
+            None,
+        );
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::IO_SETUP {
+        // Prevent the io_setup from running and fake an ENOSYS return. We want
+        // to discourage applications from using this API because the async
+        // reads are writes by the kernel that can race with userspace execution.
+        let mut r = t.regs_ref().clone();
+        r.set_arg2(0);
+        t.set_regs(&r);
+        syscall_state.emulate_result_signed(-ENOSYS as isize);
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::MEMFD_CREATE {
+        let name = t.read_c_str(RemotePtr::from(regs.arg1()));
+        if is_blacklisted_memfd(name.as_bytes()) {
+            log!(LogWarn, "Cowardly refusing to memfd_create {:?}", name);
+            let mut r = t.regs_ref().clone();
+            r.set_arg1(0);
+            t.set_regs(&r);
+        }
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::OPEN {
+        let pathname = t.read_c_str(RemotePtr::from(regs.arg1()));
+        if is_blacklisted_filename(pathname.as_bytes()) {
+            // NB: the file will still be open in the process's file table, but let's
+            // hope this gross hack dies before we have to worry about that.
+            log!(LogWarn, "Cowardly refusing to open {:?}", pathname);
+            let mut r = t.regs_ref().clone();
+            r.set_arg1(0);
+            t.set_regs(&r);
+        }
+        return Switchable::AllowSwitch;
+    }
+
+    if sys == Arch::OPENAT {
+        let pathname = t.read_c_str(RemotePtr::from(regs.arg2()));
+        if is_blacklisted_filename(pathname.as_bytes()) {
+            log!(LogWarn, "Cowardly refusing to open {:?}", pathname);
+            let mut r = t.regs_ref().clone();
+            r.set_arg2(0);
+            t.set_regs(&r);
+        }
+        return Switchable::AllowSwitch;
+    }
+
+    if sys == Arch::CLOSE {
+        if t.fd_table().is_rd_fd(regs.arg1() as i32) {
+            // Don't let processes close this fd. Abort with EBADF by setting
+            // oldfd to -1, as if the fd is already closed.
+            let mut r = t.regs_ref().clone();
+            r.set_arg1(usize::MAX);
+            t.set_regs(&r);
+        }
+        return Switchable::AllowSwitch;
+    }
+
+    if sys == Arch::DUP2 || sys == Arch::DUP3 {
+        if t.fd_table().is_rd_fd(regs.arg2() as i32) {
+            // Don't let processes dup over this fd. Abort with EBADF by setting
+            // oldfd to -1.
+            let mut r = t.regs_ref().clone();
+            r.set_arg1(usize::MAX);
+            t.set_regs(&r);
+        }
+        return Switchable::PreventSwitch;
+    }
+
+    // This should be kept in sync with the syscallbuf's list of syscalls that
+    // may block.
+    if sys == Arch::FSYNC
+        || sys == Arch::SYNC
+        || sys == Arch::SYNCFS
+        || sys == Arch::FDATASYNC
+        || sys == Arch::SYNC_FILE_RANGE
+        || sys == Arch::MSYNC
+    {
+        return Switchable::AllowSwitch;
+    }
+
+    if sys == Arch::IOCTL {
+        return prepare_ioctl::<Arch>(t, syscall_state, regs);
+    }
+
+    if sys == Arch::_SYSCTL {
+        let argsp = syscall_state.reg_parameter_with_size(
+            t,
+            1,
+            ParamSize::from(arch_size_of!(Arch, __sysctl_args)),
+            Some(ArgMode::In),
+        );
+        let oldlenp = syscall_state.mem_ptr_parameter_with_size(
+            t,
+            argsp + arch_offset_of!(Arch, __sysctl_args, oldlenp),
+            ParamSize::from(size_of::<Arch::unsigned_word>()),
+            Some(ArgMode::InOut),
+        );
+        if !oldlenp.is_null() {
+            let oldlen = read_word::<Arch>(t, oldlenp);
+            syscall_state.mem_ptr_parameter_with_size(
+                t,
+                argsp + arch_offset_of!(Arch, __sysctl_args, oldval),
+                ParamSize::from(oldlen),
+                None,
+            );
+        }
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::QUOTACTL {
+        match (regs.arg1() >> SUBCMDSHIFT) as u32 {
+            Q_GETQUOTA => {
+                syscall_state.reg_parameter_with_size(
+                    t,
+                    4,
+                    ParamSize::from(arch_size_of!(Arch, dqblk)),
+                    None,
+                );
+            }
+            Q_GETINFO => {
+                syscall_state.reg_parameter_with_size(
+                    t,
+                    4,
+                    ParamSize::from(arch_size_of!(Arch, dqinfo)),
+                    None,
+                );
+            }
+            Q_GETFMT => {
+                syscall_state.reg_parameter::<i32>(t, 4, None);
+            }
+            Q_SETQUOTA => {
+                fatal!("Trying to set disk quota usage, this may interfere with rd recording");
+            }
+            Q_QUOTAON | Q_QUOTAOFF | Q_SETINFO | Q_SYNC => (),
+            _ => {
+                // Don't set expect_errno here because quotactl can fail with
+                // various error codes before checking the command
+            }
+        }
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::SYSFS {
+        let option = regs.arg1();
+        if option == 2 {
+            let remote_buf = RemotePtr::<u8>::from(regs.arg3());
+            // Assume no filesystem type name is more than 1K
+            let mut buf = [0u8; 1024];
+            let nread = t.read_bytes_fallible(RemotePtr::cast(remote_buf), &mut buf);
+            match nread {
+                Ok(n) if n > 0 => {
+                    let len = match buf[0..n].iter().position(|&c| c == 0) {
+                        Some(pos) => pos + 1,
+                        None => n,
+                    };
+                    syscall_state.reg_parameter_with_size(t, 3, ParamSize::from(len), None);
+                }
+                _ => (),
+            }
+        }
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::MQ_TIMEDRECEIVE || sys == Arch::MQ_TIMEDRECEIVE_TIME64 {
+        syscall_state.reg_parameter_with_size(
+            t,
+            2,
+            ParamSize::from_syscall_result::<Arch::signed_long>(Some(regs.arg3())),
+            None,
+        );
+        syscall_state.reg_parameter::<u32>(t, 4, None);
+        return Switchable::AllowSwitch;
+    }
+
+    if sys == Arch::NAME_TO_HANDLE_AT {
+        let handle_bytes: u32 = read_val_mem(t, RemotePtr::<u32>::from(regs.arg3()), None);
+        syscall_state.reg_parameter_with_size(
+            t,
+            3,
+            ParamSize::from(size_of::<u32>() + size_of::<i32>() + handle_bytes as usize),
+            None,
+        );
+        syscall_state.reg_parameter::<i32>(t, 4, None);
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::KEYCTL {
+        match regs.arg1() as u32 {
+            KEYCTL_DESCRIBE | KEYCTL_READ | KEYCTL_GET_SECURITY | KEYCTL_DH_COMPUTE => {
+                syscall_state.reg_parameter_with_size(
+                    t,
+                    3,
+                    ParamSize::from_syscall_result::<Arch::signed_long>(Some(regs.arg4())),
+                    None,
+                );
+            }
+            KEYCTL_GET_KEYRING_ID
+            | KEYCTL_JOIN_SESSION_KEYRING
+            | KEYCTL_UPDATE
+            | KEYCTL_REVOKE
+            | KEYCTL_CHOWN
+            | KEYCTL_SETPERM
+            | KEYCTL_CLEAR
+            | KEYCTL_LINK
+            | KEYCTL_UNLINK
+            | KEYCTL_SEARCH
+            | KEYCTL_INSTANTIATE
+            | KEYCTL_INSTANTIATE_IOV
+            | KEYCTL_NEGATE
+            | KEYCTL_REJECT
+            | KEYCTL_SET_REQKEY_KEYRING
+            | KEYCTL_SET_TIMEOUT
+            | KEYCTL_ASSUME_AUTHORITY
+            | KEYCTL_SESSION_TO_PARENT
+            | KEYCTL_INVALIDATE
+            | KEYCTL_GET_PERSISTENT => (),
+            _ => {
+                syscall_state.expect_errno = EINVAL;
+            }
+        }
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::PRCTL {
+        return prepare_prctl::<Arch>(t, syscall_state, regs);
+    }
+
+    if sys == Arch::ARCH_PRCTL {
+        match regs.arg1() as u32 {
+            ARCH_SET_FS | ARCH_SET_GS => (),
+            ARCH_GET_FS | ARCH_GET_GS => {
+                syscall_state.reg_parameter_with_size(
+                    t,
+                    2,
+                    ParamSize::from(size_of::<Arch::unsigned_word>()),
+                    None,
+                );
+            }
+            ARCH_SET_CPUID => {
+                if !SessionInner::has_cpuid_faulting() {
+                    // Not supported. Our emulation in rd would be faulty anyway.
+                    syscall_state.expect_errno = EINVAL;
+                } else {
+                    // Prevent the actual SET_CPUID call.
+                    let mut r = t.regs_ref().clone();
+                    r.set_arg1(usize::MAX);
+                    t.set_regs(&r);
+                    t.cpuid_mode = if regs.arg2() != 0 { 1 } else { 0 };
+                    syscall_state.emulate_result(0);
+                }
+            }
+            ARCH_GET_CPUID => {
+                if !SessionInner::has_cpuid_faulting() {
+                    // Not supported. Our emulation in rd would be faulty anyway.
+                    syscall_state.expect_errno = EINVAL;
+                } else {
+                    let mut r = t.regs_ref().clone();
+                    r.set_arg1(usize::MAX);
+                    t.set_regs(&r);
+                    syscall_state.emulate_result(t.cpuid_mode as usize);
+                }
+            }
+            _ => {
+                syscall_state.expect_errno = EINVAL;
+            }
+        }
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::SECCOMP {
+        if regs.arg1() as u32 == SECCOMP_SET_MODE_FILTER {
+            // Prevent the actual seccomp call. We'll install a patched filter
+            // afterwards. See rec_process_syscall_arch().
+            let mut r = t.regs_ref().clone();
+            r.set_arg1(usize::MAX);
+            t.set_regs(&r);
+        }
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::BPF {
+        // bpf programs can write to user memory and have side effects we
+        // can't track. Pretend it doesn't exist.
+        let mut r = t.regs_ref().clone();
+        r.set_arg1(usize::MAX);
+        t.set_regs(&r);
+        syscall_state.emulate_result_signed(-ENOSYS as isize);
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::RDCALL_INIT_BUFFERS {
+        // This is a bit subtle. The params are "In" from the point of view of
+        // the tracee but we write to them in init_buffers() and they need to
+        // be recorded so replay can restore them.
+        syscall_state.reg_parameter_with_size(
+            t,
+            1,
+            ParamSize::from(size_of::<rdcall_init_buffers_params<Arch>>()),
+            Some(ArgMode::InOut),
+        );
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::RDCALL_INIT_PRELOAD {
+        syscall_state.emulate_result(0);
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::RDCALL_NOTIFY_CONTROL_MSG
+        || sys == Arch::RDCALL_RELOAD_AUXV
+        || sys == Arch::RDCALL_MPROTECT_RECORD
+    {
+        syscall_state.emulate_result(0);
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::EPOLL_WAIT || sys == Arch::EPOLL_PWAIT {
+        syscall_state.reg_parameter_with_size(
+            t,
+            2,
+            ParamSize::from(arch_size_of!(Arch, epoll_event) * regs.arg3_signed() as usize),
+            None,
+        );
+        return Switchable::AllowSwitch;
+    }
+
+    if sys == Arch::PERSONALITY {
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::RESTART_SYSCALL {
+        return Switchable::AllowSwitch;
+    }
+
+    log!(
+        LogDebug,
+        "Unsupported or unhandled syscall {}",
+        syscall_name(sys, Arch::arch())
+    );
+    syscall_state.expect_errno = ENOSYS;
+    Switchable::PreventSwitch
+}
+
+fn prepare_mmap_register_params(t: &mut RecordTask) {
+    let mut r = t.regs_ref().clone();
+    let flags = r.arg4() as i32;
+    if t.session().as_record().unwrap().enable_chaos()
+        && flags & (MAP_FIXED | MAP_32BIT) == 0
+        && r.arg1() == 0
+    {
+        // No address hint was provided. Randomize the allocation address.
+        let mut len = r.arg2();
+        if flags & MAP_GROWSDOWN != 0 {
+            // Ensure stacks can grow to the minimum size we choose
+            // (minus the guard page).
+            len = max(AddressSpace::chaos_mode_min_stack_size() as usize, len);
+        }
+        let addr = AddressSpace::chaos_mode_find_free_memory(t, len);
+        if !addr.is_null() {
+            // Don't use MAP_FIXED here. The kernel may still place the mapping
+            // elsewhere but that's fine.
+            r.set_arg1_from_remote_ptr(addr + len - r.arg2());
+        }
+    }
+
+    // Since we're stripping MAP_GROWSDOWN from kernel mmap calls, we need
+    // to implement PROT_GROWSDOWN ourselves.
+    let mask_flag = MAP_GROWSDOWN as usize;
+    r.set_arg4(r.arg4() & !mask_flag);
+    t.set_regs(&r);
+}
+
+fn prepare_fcntl<Arch: Architecture>(
+    t: &mut RecordTask,
+    syscall_state: &mut TaskSyscallState,
+    regs: &Registers,
+) -> Switchable {
+    let fd = regs.arg1() as i32;
+    let mut result: u64 = 0;
+    let emulated = t
+        .fd_table_shr_ptr()
+        .borrow()
+        .emulate_fcntl(fd, t, &mut result);
+    if emulated {
+        // Don't perform this syscall.
+        let mut r = regs.clone();
+        r.set_arg1(usize::MAX);
+        t.set_regs(&r);
+        syscall_state.emulate_result(result as usize);
+        return Switchable::PreventSwitch;
+    }
+
+    let op = regs.arg2_signed() as i32;
+    if op == FcntlOperation::DUPFD as i32
+        || op == FcntlOperation::DUPFD_CLOEXEC as i32
+        || op == FcntlOperation::GETFD as i32
+        || op == FcntlOperation::GETFL as i32
+        || op == FcntlOperation::SETFL as i32
+        || op == FcntlOperation::SETLK as i32
+        || op == FcntlOperation::SETLK64 as i32
+        || op == FcntlOperation::OFD_SETLK as i32
+        || op == FcntlOperation::SETOWN as i32
+        || op == FcntlOperation::GETOWN as i32
+        || op == FcntlOperation::SETOWN_EX as i32
+        || op == FcntlOperation::GETSIG as i32
+        || op == FcntlOperation::SETSIG as i32
+        || op == FcntlOperation::SETPIPE_SZ as i32
+        || op == FcntlOperation::GETPIPE_SZ as i32
+        || op == FcntlOperation::ADD_SEALS as i32
+        || op == FcntlOperation::SET_RW_HINT as i32
+        || op == FcntlOperation::SET_FILE_RW_HINT as i32
+    {
+        // Nothing to record.
+    } else if op == FcntlOperation::SETFD as i32 {
+        if t.fd_table().is_rd_fd(fd) {
+            // Don't let tracee set FD_CLOEXEC on this fd. Disable the syscall,
+            // but emulate a successful return.
+            let mut r = regs.clone();
+            r.set_arg1(usize::MAX);
+            t.set_regs(&r);
+            syscall_state.emulate_result(0);
+        }
+    } else if op == FcntlOperation::GETLK as i32 {
+        syscall_state.reg_parameter_with_size(
+            t,
+            3,
+            ParamSize::from(arch_size_of!(Arch, _flock)),
+            Some(ArgMode::InOut),
+        );
+    } else if op == FcntlOperation::OFD_GETLK as i32 || op == FcntlOperation::GETLK64 as i32 {
+        // flock and flock64 better be different on 32-bit architectures,
+        // but on 64-bit architectures, it's OK if they're the same.
+        syscall_state.reg_parameter_with_size(
+            t,
+            3,
+            ParamSize::from(arch_size_of!(Arch, flock64)),
+            Some(ArgMode::InOut),
+        );
+    } else if op == FcntlOperation::GETOWN_EX as i32 {
+        syscall_state.reg_parameter_with_size(
+            t,
+            3,
+            ParamSize::from(arch_size_of!(Arch, f_owner_ex)),
+            None,
+        );
+    } else if op == FcntlOperation::SETLKW as i32
+        || op == FcntlOperation::SETLKW64 as i32
+        || op == FcntlOperation::OFD_SETLKW as i32
+    {
+        // SETLKW blocks, but doesn't write any
+        // outparam data to the |struct flock|
+        // argument, so no need for scratch.
+        return Switchable::AllowSwitch;
+    } else if op == FcntlOperation::GET_RW_HINT as i32
+        || op == FcntlOperation::GET_FILE_RW_HINT as i32
+    {
+        syscall_state.reg_parameter::<i64>(t, 3, None);
+    } else {
+        // Unknown command should trigger EINVAL.
+        syscall_state.expect_errno = EINVAL;
+    }
+
+    Switchable::PreventSwitch
+}
+
+fn prepare_futex<Arch: Architecture>(
+    t: &mut RecordTask,
+    syscall_state: &mut TaskSyscallState,
+    regs: &Registers,
+) -> Switchable {
+    syscall_state.reg_parameter::<i32>(t, 1, Some(ArgMode::InOutNoScratch));
+    let op = regs.arg2_signed() as u32 & FUTEX_CMD_MASK as u32;
+    match op {
+        FUTEX_WAKE | FUTEX_WAKE_BITSET => Switchable::PreventSwitch,
+        FUTEX_REQUEUE | FUTEX_CMP_REQUEUE | FUTEX_WAKE_OP => {
+            syscall_state.reg_parameter::<i32>(t, 5, Some(ArgMode::InOutNoScratch));
+            Switchable::PreventSwitch
+        }
+        FUTEX_WAIT | FUTEX_WAIT_BITSET => Switchable::AllowSwitch,
+        FUTEX_LOCK_PI
+        | FUTEX_UNLOCK_PI
+        | FUTEX_TRYLOCK_PI
+        | FUTEX_CMP_REQUEUE_PI
+        | FUTEX_WAIT_REQUEUE_PI => {
+            // Priority-inheritance futexes aren't supported. Make the syscall
+            // fail with ENOSYS, which glibc handles gracefully.
+            let mut r = regs.clone();
+            r.set_arg2(usize::MAX);
+            t.set_regs(&r);
+            syscall_state.emulate_result_signed(-ENOSYS as isize);
+            Switchable::PreventSwitch
+        }
+        _ => {
+            syscall_state.expect_errno = EINVAL;
+            Switchable::PreventSwitch
+        }
+    }
+}
+
+fn prepare_msgctl<Arch: Architecture>(
+    t: &mut RecordTask,
+    syscall_state: &mut TaskSyscallState,
+    cmd: u32,
+    ptr_reg: usize,
+) -> Switchable {
+    match cmd {
+        IPC_STAT | MSG_STAT => {
+            syscall_state.reg_parameter_with_size(
+                t,
+                ptr_reg,
+                ParamSize::from(arch_size_of!(Arch, msqid64_ds)),
+                None,
+            );
+        }
+        IPC_INFO | MSG_INFO => {
+            syscall_state.reg_parameter_with_size(
+                t,
+                ptr_reg,
+                ParamSize::from(arch_size_of!(Arch, msginfo)),
+                None,
+            );
+        }
+        IPC_SET | IPC_RMID => (),
+        _ => {
+            syscall_state.expect_errno = EINVAL;
+        }
+    }
+    Switchable::PreventSwitch
+}
+
+fn prepare_shmctl<Arch: Architecture>(
+    t: &mut RecordTask,
+    syscall_state: &mut TaskSyscallState,
+    cmd: u32,
+    ptr_reg: usize,
+) -> Switchable {
+    match cmd {
+        IPC_SET | IPC_RMID | SHM_LOCK | SHM_UNLOCK => (),
+        IPC_STAT | SHM_STAT => {
+            syscall_state.reg_parameter_with_size(
+                t,
+                ptr_reg,
+                ParamSize::from(arch_size_of!(Arch, shmid64_ds)),
+                None,
+            );
+        }
+        IPC_INFO => {
+            syscall_state.reg_parameter_with_size(
+                t,
+                ptr_reg,
+                ParamSize::from(arch_size_of!(Arch, shminfo64)),
+                None,
+            );
+        }
+        SHM_INFO => {
+            syscall_state.reg_parameter_with_size(
+                t,
+                ptr_reg,
+                ParamSize::from(arch_size_of!(Arch, shm_info)),
+                None,
+            );
+        }
+        _ => {
+            syscall_state.expect_errno = EINVAL;
+        }
+    }
+    Switchable::PreventSwitch
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum SemctlDereference {
+    Dereference,
+    UseDirectly,
+}
+
+fn prepare_semctl<Arch: Architecture>(
+    t: &mut RecordTask,
+    syscall_state: &mut TaskSyscallState,
+    semid: i32,
+    cmd: u32,
+    ptr_reg: usize,
+    deref: SemctlDereference,
+) -> Switchable {
+    let param_size = match cmd {
+        IPC_SET | IPC_RMID | GETNCNT | GETPID | GETVAL | GETZCNT | SETALL | SETVAL => None,
+        IPC_STAT | SEM_STAT => Some(ParamSize::from(arch_size_of!(Arch, semid64_ds))),
+        IPC_INFO | SEM_INFO => Some(ParamSize::from(arch_size_of!(Arch, seminfo))),
+        GETALL => {
+            let mut ds: x64::semid64_ds = Default::default();
+            let ret = unsafe {
+                libc::syscall(
+                    libc::SYS_semctl,
+                    semid,
+                    0,
+                    IPC_STAT | IPC_64,
+                    &mut ds as *mut x64::semid64_ds,
+                )
+            };
+            ed_assert_eq!(t, ret, 0);
+            Some(ParamSize::from(size_of::<u16>() * ds.sem_nsems as usize))
+        }
+        _ => {
+            syscall_state.expect_errno = EINVAL;
+            None
+        }
+    };
+
+    match param_size {
+        Some(size) => {
+            if deref == SemctlDereference::Dereference {
+                let p = syscall_state.reg_parameter_with_size(
+                    t,
+                    ptr_reg,
+                    ParamSize::from(size_of::<Arch::unsigned_word>()),
+                    None,
+                );
+                syscall_state.mem_ptr_parameter_with_size(t, p, size, None);
+            } else {
+                syscall_state.reg_parameter_with_size(t, ptr_reg, size, None);
+            }
+        }
+        None => (),
+    }
+    Switchable::PreventSwitch
+}
+
+/// The x86 `ipc` multiplexer syscall.
+fn prepare_ipc_multiplexer<Arch: Architecture>(
+    t: &mut RecordTask,
+    syscall_state: &mut TaskSyscallState,
+    regs: &Registers,
+) -> Switchable {
+    match regs.arg1_signed() as u32 {
+        MSGGET | SHMDT | SHMGET | SEMGET => (),
+        MSGCTL => {
+            let cmd = regs.arg3_signed() as u32 & !IPC_64;
+            return prepare_msgctl::<Arch>(t, syscall_state, cmd, 5);
+        }
+        MSGSND | SEMOP | SEMTIMEDOP => return Switchable::AllowSwitch,
+        MSGRCV => {
+            let msgsize = regs.arg3();
+            let kluge_args = syscall_state.reg_parameter_with_size(
+                t,
+                5,
+                ParamSize::from(arch_size_of!(Arch, ipc_kludge_args)),
+                Some(ArgMode::In),
+            );
+            syscall_state.mem_ptr_parameter_with_size(
+                t,
+                kluge_args + arch_offset_of!(Arch, ipc_kludge_args, msgbuf),
+                ParamSize::from(size_of::<Arch::signed_long>() + msgsize),
+                None,
+            );
+            return Switchable::AllowSwitch;
+        }
+        SHMAT => {
+            // Insane legacy feature: ipc SHMAT returns its pointer via an
+            // in-memory out parameter.
+            syscall_state.reg_parameter_with_size(
+                t,
+                4,
+                ParamSize::from(size_of::<Arch::unsigned_word>()),
+                None,
+            );
+        }
+        SHMCTL => {
+            let cmd = regs.arg3_signed() as u32 & !IPC_64;
+            return prepare_shmctl::<Arch>(t, syscall_state, cmd, 5);
+        }
+        SEMCTL => {
+            let cmd = regs.arg4_signed() as u32 & !IPC_64;
+            return prepare_semctl::<Arch>(
+                t,
+                syscall_state,
+                regs.arg2_signed() as i32,
+                cmd,
+                5,
+                SemctlDereference::Dereference,
+            );
+        }
+        _ => {
+            syscall_state.expect_errno = EINVAL;
+        }
+    }
+    Switchable::PreventSwitch
+}
+
+/// The individual SysV IPC syscalls.
+fn prepare_ipc<Arch: Architecture>(
+    t: &mut RecordTask,
+    syscall_state: &mut TaskSyscallState,
+    regs: &Registers,
+    sys: i32,
+) -> Switchable {
+    if sys == Arch::MSGCTL {
+        return prepare_msgctl::<Arch>(t, syscall_state, regs.arg2_signed() as u32 & !IPC_64, 3);
+    }
+    if sys == Arch::MSGRCV {
+        syscall_state.reg_parameter_with_size(
+            t,
+            2,
+            ParamSize::from(size_of::<Arch::signed_long>() + regs.arg3()),
+            None,
+        );
+        return Switchable::AllowSwitch;
+    }
+    if sys == Arch::MSGSND
+        || sys == Arch::SEMOP
+        || sys == Arch::SEMTIMEDOP
+        || sys == Arch::SEMTIMEDOP_TIME64
+    {
+        return Switchable::AllowSwitch;
+    }
+    if sys == Arch::SEMCTL {
+        let cmd = regs.arg3_signed() as u32 & !IPC_64;
+        return prepare_semctl::<Arch>(
+            t,
+            syscall_state,
+            regs.arg1_signed() as i32,
+            cmd,
+            4,
+            SemctlDereference::UseDirectly,
+        );
+    }
+    if sys == Arch::SHMCTL {
+        let cmd = regs.arg2_signed() as u32 & !IPC_64;
+        return prepare_shmctl::<Arch>(t, syscall_state, cmd, 3);
+    }
+    // shmat, shmdt
+    Switchable::PreventSwitch
+}
+
+fn prepare_recvmsg<Arch: Architecture>(
+    t: &mut RecordTask,
+    syscall_state: &mut TaskSyscallState,
+    msgp: RemotePtr<Void>,
+    io_size: ParamSize,
+) {
+    let namelen_ptr = RemotePtr::<u32>::cast(msgp + arch_offset_of!(Arch, msghdr, msg_namelen));
+    let namelen_size = ParamSize::from_initialized_mem(t, namelen_ptr);
+    syscall_state.mem_ptr_parameter_with_size(
+        t,
+        msgp + arch_offset_of!(Arch, msghdr, msg_name),
+        namelen_size,
+        None,
+    );
+
+    let msg_iovlen = read_word::<Arch>(t, msgp + arch_offset_of!(Arch, msghdr, msg_iovlen));
+    let iovecsp_void = syscall_state.mem_ptr_parameter_with_size(
+        t,
+        msgp + arch_offset_of!(Arch, msghdr, msg_iov),
+        ParamSize::from(size_of::<Arch::iovec>() * msg_iovlen),
+        Some(ArgMode::In),
+    );
+    let iovecsp = RemotePtr::<Arch::iovec>::cast(iovecsp_void);
+    let iovecs = read_mem(t, iovecsp, msg_iovlen, None);
+    for i in 0..msg_iovlen {
+        let (_, iov_len) = Arch::get_iovec(&iovecs[i]);
+        syscall_state.mem_ptr_parameter_with_size(
+            t,
+            RemotePtr::cast(iovecsp + i) + arch_offset_of!(Arch, iovec, iov_base),
+            io_size.limit_size(iov_len),
+            None,
+        );
+    }
+
+    let controllen_ptr = RemotePtr::<Arch::unsigned_word>::cast(
+        msgp + arch_offset_of!(Arch, msghdr, msg_controllen),
+    );
+    let controllen_size = ParamSize::from_initialized_mem(t, controllen_ptr);
+    syscall_state.mem_ptr_parameter_with_size(
+        t,
+        msgp + arch_offset_of!(Arch, msghdr, msg_control),
+        controllen_size,
+        None,
+    );
+}
+
+fn prepare_recvmmsg<Arch: Architecture>(
+    t: &mut RecordTask,
+    syscall_state: &mut TaskSyscallState,
+    mmsgp: RemotePtr<Void>,
+    vlen: usize,
+) {
+    for i in 0..vlen {
+        let msgp = mmsgp + i * arch_size_of!(Arch, mmsghdr);
+        prepare_recvmsg::<Arch>(
+            t,
+            syscall_state,
+            msgp + arch_offset_of!(Arch, mmsghdr, msg_hdr),
+            ParamSize::from_mem(RemotePtr::<u32>::cast(
+                msgp + arch_offset_of!(Arch, mmsghdr, msg_len),
+            )),
+        );
+    }
+}
+
+fn block_sock_opt(flags: usize) -> Switchable {
+    if flags & MSG_DONTWAIT as usize != 0 {
+        Switchable::PreventSwitch
+    } else {
+        Switchable::AllowSwitch
+    }
+}
+
+/// From linux/if_packet.h
+const PACKET_RX_RING: i32 = 5;
+const PACKET_TX_RING: i32 = 13;
+
+/// Turn a setsockopt() for packet ring buffers into a failed syscall. The
+/// kernel writes into those buffers asynchronously, which we can't record.
+fn maybe_block_setsockopt(
+    t: &mut RecordTask,
+    syscall_state: &mut TaskSyscallState,
+    level: i32,
+    optname: i32,
+) {
+    if level == SOL_PACKET && (optname == PACKET_RX_RING || optname == PACKET_TX_RING) {
+        // Let the kernel reject this syscall.
+        let mut r = t.regs_ref().clone();
+        r.set_arg1(usize::MAX);
+        t.set_regs(&r);
+        syscall_state.emulate_result_signed(-ENOPROTOOPT as isize);
+    }
+}
+
+/// Direct socket syscalls (i.e. not via socketcall).
+fn prepare_socket_syscall<Arch: Architecture>(
+    t: &mut RecordTask,
+    syscall_state: &mut TaskSyscallState,
+    regs: &Registers,
+    sys: i32,
+) -> Switchable {
+    if sys == Arch::CONNECT {
+        return Switchable::AllowSwitch;
+    }
+
+    if sys == Arch::SENDTO {
+        return block_sock_opt(regs.arg4());
+    }
+
+    if sys == Arch::SENDMSG {
+        return block_sock_opt(regs.arg3());
+    }
+
+    if sys == Arch::SENDMMSG {
+        let vlen = regs.arg3() as u32 as usize;
+        syscall_state.reg_parameter_with_size(
+            t,
+            2,
+            ParamSize::from(arch_size_of!(Arch, mmsghdr) * vlen),
+            Some(ArgMode::InOut),
+        );
+        return block_sock_opt(regs.arg4());
+    }
+
+    if sys == Arch::GETSOCKOPT {
+        let optlen_ptr = syscall_state.reg_parameter::<u32>(t, 5, Some(ArgMode::InOut));
+        let optval_size = ParamSize::from_initialized_mem(t, optlen_ptr);
+        syscall_state.reg_parameter_with_size(t, 4, optval_size, None);
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::SETSOCKOPT {
+        maybe_block_setsockopt(t, syscall_state, regs.arg2() as i32, regs.arg3() as i32);
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::ACCEPT || sys == Arch::ACCEPT4 {
+        let addrlen_ptr = syscall_state.reg_parameter::<u32>(t, 3, Some(ArgMode::InOut));
+        let addr_size = ParamSize::from_initialized_mem(t, addrlen_ptr);
+        syscall_state.reg_parameter_with_size(t, 2, addr_size, None);
+        return Switchable::AllowSwitch;
+    }
+
+    if sys == Arch::GETSOCKNAME || sys == Arch::GETPEERNAME {
+        let addrlen_ptr = syscall_state.reg_parameter::<u32>(t, 3, Some(ArgMode::InOut));
+        let addr_size = ParamSize::from_initialized_mem(t, addrlen_ptr);
+        syscall_state.reg_parameter_with_size(t, 2, addr_size, None);
+        return Switchable::PreventSwitch;
+    }
+
+    if sys == Arch::RECVFROM {
+        syscall_state.reg_parameter_with_size(
+            t,
+            2,
+            ParamSize::from_syscall_result::<Arch::signed_long>(Some(regs.arg3())),
+            None,
+        );
+        let addrlen_ptr = syscall_state.reg_parameter::<u32>(t, 6, Some(ArgMode::InOut));
+        let addr_size = ParamSize::from_initialized_mem(t, addrlen_ptr);
+        syscall_state.reg_parameter_with_size(t, 5, addr_size, None);
+        return Switchable::AllowSwitch;
+    }
+
+    if sys == Arch::RECVMSG {
+        let msgp = syscall_state.reg_parameter_with_size(
+            t,
+            2,
+            ParamSize::from(arch_size_of!(Arch, msghdr)),
+            Some(ArgMode::InOut),
+        );
+        prepare_recvmsg::<Arch>(
+            t,
+            syscall_state,
+            msgp,
+            ParamSize::from_syscall_result::<Arch::signed_long>(None),
+        );
+        return block_sock_opt(regs.arg3());
+    }
+
+    if sys == Arch::RECVMMSG || sys == Arch::RECVMMSG_TIME64 {
+        let vlen = regs.arg3() as u32 as usize;
+        let mmsgp = syscall_state.reg_parameter_with_size(
+            t,
+            2,
+            ParamSize::from(arch_size_of!(Arch, mmsghdr) * vlen),
+            Some(ArgMode::InOut),
+        );
+        prepare_recvmmsg::<Arch>(t, syscall_state, mmsgp, vlen);
+        return block_sock_opt(regs.arg4());
+    }
+
+    unreachable!()
+}
+
+/// The x86 `socketcall` multiplexer syscall.
+fn prepare_socketcall<Arch: Architecture>(
+    t: &mut RecordTask,
+    syscall_state: &mut TaskSyscallState,
+    regs: &Registers,
+) -> Switchable {
+    // int socketcall(int call, unsigned long *args) {
+    //   long a[6];
+    //   copy_from_user(a,args);
+    //   sys_recv(a0, (void __user *)a1, a[2], a[3]);
+    // }
+    //
+    // (from http://lxr.linux.no/#linux+v3.6.3/net/socket.c#L2354)
+    match regs.arg1() as u32 {
+        // int socket(int domain, int type, int protocol);
+        // int bind(int sockfd, const struct sockaddr *addr, socklen_t addrlen);
+        // int listen(int sockfd, int backlog)
+        // int shutdown(int socket, int how)
+        SYS_SOCKET | SYS_BIND | SYS_LISTEN | SYS_SHUTDOWN => (),
+
+        SYS_SETSOCKOPT => {
+            let argsp = RemotePtr::<Void>::from(regs.arg2());
+            let level = read_word::<Arch>(t, argsp + arch_offset_of!(Arch, setsockopt_args, level));
+            let optname =
+                read_word::<Arch>(t, argsp + arch_offset_of!(Arch, setsockopt_args, optname));
+            maybe_block_setsockopt(t, syscall_state, level as i32, optname as i32);
+        }
+
+        // int connect(int sockfd, const struct sockaddr *addr, socklen_t addrlen);
+        SYS_CONNECT => return Switchable::AllowSwitch,
+
+        // int getsockopt(int sockfd, int level, int optname, const void *optval,
+        //                socklen_t* optlen);
+        SYS_GETSOCKOPT => {
+            let argsp = syscall_state.reg_parameter_with_size(
+                t,
+                2,
+                ParamSize::from(arch_size_of!(Arch, getsockopt_args)),
+                Some(ArgMode::In),
+            );
+            let optlen_ptr = syscall_state.mem_ptr_parameter::<u32>(
+                t,
+                argsp + arch_offset_of!(Arch, getsockopt_args, optlen),
+                Some(ArgMode::InOut),
+            );
+            let optval_size = ParamSize::from_initialized_mem(t, optlen_ptr);
+            syscall_state.mem_ptr_parameter_with_size(
+                t,
+                argsp + arch_offset_of!(Arch, getsockopt_args, optval),
+                optval_size,
+                None,
+            );
+        }
+
+        // int socketpair(int domain, int type, int protocol, int sv[2]);
+        //
+        // values returned in sv
+        SYS_SOCKETPAIR => {
+            let argsp = syscall_state.reg_parameter_with_size(
+                t,
+                2,
+                ParamSize::from(arch_size_of!(Arch, socketpair_args)),
+                Some(ArgMode::In),
+            );
+            syscall_state.mem_ptr_parameter_with_size(
+                t,
+                argsp + arch_offset_of!(Arch, socketpair_args, sv),
+                ParamSize::from(size_of::<[i32; 2]>()),
+                None,
+            );
+        }
+
+        // int getpeername(int sockfd, struct sockaddr *addr, socklen_t *addrlen);
+        // int getsockname(int sockfd, struct sockaddr *addr, socklen_t *addrlen);
+        SYS_GETPEERNAME | SYS_GETSOCKNAME => {
+            let argsp = syscall_state.reg_parameter_with_size(
+                t,
+                2,
+                ParamSize::from(arch_size_of!(Arch, getsockname_args)),
+                Some(ArgMode::In),
+            );
+            let addrlen_ptr = syscall_state.mem_ptr_parameter::<u32>(
+                t,
+                argsp + arch_offset_of!(Arch, getsockname_args, addrlen),
+                Some(ArgMode::InOut),
+            );
+            let addr_size = ParamSize::from_initialized_mem(t, addrlen_ptr);
+            syscall_state.mem_ptr_parameter_with_size(
+                t,
+                argsp + arch_offset_of!(Arch, getsockname_args, addr),
+                addr_size,
+                None,
+            );
+        }
+
+        // ssize_t recv([int sockfd, void *buf, size_t len, int flags])
+        SYS_RECV => {
+            let argsp = syscall_state.reg_parameter_with_size(
+                t,
+                2,
+                ParamSize::from(arch_size_of!(Arch, recv_args)),
+                Some(ArgMode::In),
+            );
+            let len = read_word::<Arch>(t, argsp + arch_offset_of!(Arch, recv_args, len));
+            syscall_state.mem_ptr_parameter_with_size(
+                t,
+                argsp + arch_offset_of!(Arch, recv_args, buf),
+                ParamSize::from_syscall_result::<Arch::signed_long>(Some(len)),
+                None,
+            );
+            return Switchable::AllowSwitch;
+        }
+
+        // int accept([int sockfd, struct sockaddr *addr, socklen_t *addrlen])
+        // int accept4([int sockfd, struct sockaddr *addr, socklen_t *addrlen, int flags])
+        SYS_ACCEPT | SYS_ACCEPT4 => {
+            let args_size = if regs.arg1() as u32 == SYS_ACCEPT {
+                arch_size_of!(Arch, accept_args)
+            } else {
+                arch_size_of!(Arch, accept4_args)
+            };
+            let argsp = syscall_state.reg_parameter_with_size(
+                t,
+                2,
+                ParamSize::from(args_size),
+                Some(ArgMode::In),
+            );
+            let addrlen_ptr = syscall_state.mem_ptr_parameter::<u32>(
+                t,
+                argsp + arch_offset_of!(Arch, accept_args, addrlen),
+                Some(ArgMode::InOut),
+            );
+            let addr_size = ParamSize::from_initialized_mem(t, addrlen_ptr);
+            syscall_state.mem_ptr_parameter_with_size(
+                t,
+                argsp + arch_offset_of!(Arch, accept_args, addr),
+                addr_size,
+                None,
+            );
+            return Switchable::AllowSwitch;
+        }
+
+        // ssize_t recvfrom([int sockfd, void *buf, size_t len, int flags,
+        //                  struct sockaddr *src_addr, socklen_t *addrlen]);
+        SYS_RECVFROM => {
+            let argsp = syscall_state.reg_parameter_with_size(
+                t,
+                2,
+                ParamSize::from(arch_size_of!(Arch, recvfrom_args)),
+                Some(ArgMode::In),
+            );
+            let len = read_word::<Arch>(t, argsp + arch_offset_of!(Arch, recvfrom_args, len));
+            syscall_state.mem_ptr_parameter_with_size(
+                t,
+                argsp + arch_offset_of!(Arch, recvfrom_args, buf),
+                ParamSize::from_syscall_result::<Arch::signed_long>(Some(len)),
+                None,
+            );
+            let addrlen_ptr = syscall_state.mem_ptr_parameter::<u32>(
+                t,
+                argsp + arch_offset_of!(Arch, recvfrom_args, addrlen),
+                Some(ArgMode::InOut),
+            );
+            let addr_size = ParamSize::from_initialized_mem(t, addrlen_ptr);
+            syscall_state.mem_ptr_parameter_with_size(
+                t,
+                argsp + arch_offset_of!(Arch, recvfrom_args, src_addr),
+                addr_size,
+                None,
+            );
+            return Switchable::AllowSwitch;
+        }
+
+        // ssize_t recvmsg(int sockfd, struct msghdr *msg, int flags);
+        SYS_RECVMSG => {
+            let argsp = syscall_state.reg_parameter_with_size(
+                t,
+                2,
+                ParamSize::from(arch_size_of!(Arch, recvmsg_args)),
+                Some(ArgMode::In),
+            );
+            let msgp = syscall_state.mem_ptr_parameter_with_size(
+                t,
+                argsp + arch_offset_of!(Arch, recvmsg_args, msg),
+                ParamSize::from(arch_size_of!(Arch, msghdr)),
+                Some(ArgMode::InOut),
+            );
+            prepare_recvmsg::<Arch>(
+                t,
+                syscall_state,
+                msgp,
+                ParamSize::from_syscall_result::<Arch::signed_long>(None),
+            );
+            let flags: i32 = read_val_mem(
+                t,
+                RemotePtr::cast(argsp + arch_offset_of!(Arch, recvmsg_args, flags)),
+                None,
+            );
+            return block_sock_opt(flags as usize);
+        }
+
+        // int recvmmsg(int sockfd, struct mmsghdr *msgvec, unsigned int vlen,
+        //              unsigned int flags, struct timespec *timeout);
+        SYS_RECVMMSG => {
+            let argsp = syscall_state.reg_parameter_with_size(
+                t,
+                2,
+                ParamSize::from(arch_size_of!(Arch, recvmmsg_args)),
+                Some(ArgMode::In),
+            );
+            let vlen: u32 = read_val_mem(
+                t,
+                RemotePtr::cast(argsp + arch_offset_of!(Arch, recvmmsg_args, vlen)),
+                None,
+            );
+            let flags: u32 = read_val_mem(
+                t,
+                RemotePtr::cast(argsp + arch_offset_of!(Arch, recvmmsg_args, flags)),
+                None,
+            );
+            let mmsgp = syscall_state.mem_ptr_parameter_with_size(
+                t,
+                argsp + arch_offset_of!(Arch, recvmmsg_args, msgvec),
+                ParamSize::from(arch_size_of!(Arch, mmsghdr) * vlen as usize),
+                Some(ArgMode::InOut),
+            );
+            prepare_recvmmsg::<Arch>(t, syscall_state, mmsgp, vlen as usize);
+            return block_sock_opt(flags as usize);
+        }
+
+        // ssize_t sendmsg(int sockfd, const struct msghdr *msg, int flags)
+        SYS_SENDMSG => {
+            let argsp = RemotePtr::<Void>::from(regs.arg2());
+            let flags: i32 = read_val_mem(
+                t,
+                RemotePtr::cast(argsp + arch_offset_of!(Arch, sendmsg_args, flags)),
+                None,
+            );
+            return block_sock_opt(flags as usize);
+        }
+
+        SYS_SENDMMSG => {
+            let argsp = syscall_state.reg_parameter_with_size(
+                t,
+                2,
+                ParamSize::from(arch_size_of!(Arch, sendmmsg_args)),
+                Some(ArgMode::In),
+            );
+            let vlen: u32 = read_val_mem(
+                t,
+                RemotePtr::cast(argsp + arch_offset_of!(Arch, sendmmsg_args, vlen)),
+                None,
+            );
+            let flags: u32 = read_val_mem(
+                t,
+                RemotePtr::cast(argsp + arch_offset_of!(Arch, sendmmsg_args, flags)),
+                None,
+            );
+            syscall_state.mem_ptr_parameter_with_size(
+                t,
+                argsp + arch_offset_of!(Arch, sendmmsg_args, msgvec),
+                ParamSize::from(arch_size_of!(Arch, mmsghdr) * vlen as usize),
+                Some(ArgMode::InOut),
+            );
+            return block_sock_opt(flags as usize);
+        }
+
+        SYS_SEND | SYS_SENDTO => return Switchable::AllowSwitch,
+
+        _ => {
+            syscall_state.expect_errno = EINVAL;
+        }
+    }
+    Switchable::PreventSwitch
+}
+
+const IOC_NRBITS: u32 = 8;
+const IOC_TYPEBITS: u32 = 8;
+const IOC_SIZEBITS: u32 = 14;
+const IOC_NRSHIFT: u32 = 0;
+const IOC_TYPESHIFT: u32 = IOC_NRSHIFT + IOC_NRBITS;
+const IOC_SIZESHIFT: u32 = IOC_TYPESHIFT + IOC_TYPEBITS;
+const IOC_DIRSHIFT: u32 = IOC_SIZESHIFT + IOC_SIZEBITS;
+const IOC_WRITE: u32 = 1;
+const IOC_READ: u32 = 2;
+
+const fn ioc(dir: u32, type_: u8, nr: u32, size: u32) -> u32 {
+    (dir << IOC_DIRSHIFT)
+        | ((type_ as u32) << IOC_TYPESHIFT)
+        | (nr << IOC_NRSHIFT)
+        | (size << IOC_SIZESHIFT)
+}
+
+fn ioc_dir(request: u32) -> u32 {
+    request >> IOC_DIRSHIFT
+}
+
+fn ioc_size(request: u32) -> u32 {
+    (request >> IOC_SIZESHIFT) & ((1 << IOC_SIZEBITS) - 1)
+}
+
+fn ioc_type(request: u32) -> u32 {
+    (request >> IOC_TYPESHIFT) & ((1 << IOC_TYPEBITS) - 1)
+}
+
+fn ioc_nr(request: u32) -> u32 {
+    (request >> IOC_NRSHIFT) & ((1 << IOC_NRBITS) - 1)
+}
+
+const TIOCGPTN: u32 = ioc(IOC_READ, b'T', 0x30, 4);
+const TIOCGPKT: u32 = ioc(IOC_READ, b'T', 0x38, 4);
+const TIOCGPTLCK: u32 = ioc(IOC_READ, b'T', 0x39, 4);
+const TIOCGEXCL: u32 = ioc(IOC_READ, b'T', 0x40, 4);
+
+/// Record the page above the top of `t`'s stack. The SIOC* ioctls
+/// have been observed to write beyond the end of tracees' stacks, as
+/// if they had allocated scratch space for themselves. All we can do
+/// for now is try to record the scratch data.
+fn record_page_below_stack_ptr(t: &mut RecordTask) {
+    let sp = t.regs_ref().sp();
+    t.record_remote(RemotePtr::cast(sp - page_size()), page_size());
+}
+
+fn prepare_ioctl<Arch: Architecture>(
+    t: &mut RecordTask,
+    syscall_state: &mut TaskSyscallState,
+    regs: &Registers,
+) -> Switchable {
+    let fd = regs.arg1() as i32;
+    let mut result: u64 = 0;
+    let emulated = t
+        .fd_table_shr_ptr()
+        .borrow()
+        .emulate_ioctl(fd, t, &mut result);
+    if emulated {
+        // Don't perform this syscall.
+        let mut r = regs.clone();
+        r.set_arg1(usize::MAX);
+        t.set_regs(&r);
+        syscall_state.emulate_result(result as usize);
+        return Switchable::PreventSwitch;
+    }
+
+    let request = regs.arg2() as u32;
+    let dir = ioc_dir(request);
+    let size = ioc_size(request);
+
+    log!(
+        LogDebug,
+        "handling ioctl({:#x}): type:{:#x} nr:{:#x} dir:{:#x} size:{}",
+        request,
+        ioc_type(request),
+        ioc_nr(request),
+        dir,
+        size
+    );
+
+    ed_assert!(
+        t,
+        !t.is_desched_event_syscall(),
+        "Failed to skip past desched ioctl()"
+    );
+
+    // Some ioctl()s are irregular and don't follow the _IOC()
+    // conventions.  Special case them here.
+    match request {
+        SIOCETHTOOL => {
+            let ifrp = syscall_state.reg_parameter_with_size(
+                t,
+                3,
+                ParamSize::from(arch_size_of!(Arch, ifreq)),
+                Some(ArgMode::In),
+            );
+            // ifr_ifru.ifru_data
+            syscall_state.mem_ptr_parameter_with_size(
+                t,
+                ifrp + arch_offset_of!(Arch, ifreq, ifr_ifru),
+                ParamSize::from(arch_size_of!(Arch, ethtool_cmd)),
+                None,
+            );
+            syscall_state.after_syscall_action(Box::new(record_page_below_stack_ptr));
+            return Switchable::PreventSwitch;
+        }
+
+        SIOCGIFCONF => {
+            let ifconfp = syscall_state.reg_parameter_with_size(
+                t,
+                3,
+                ParamSize::from(arch_size_of!(Arch, ifconf)),
+                Some(ArgMode::InOut),
+            );
+            let ifc_len_ptr =
+                RemotePtr::<i32>::cast(ifconfp + arch_offset_of!(Arch, ifconf, ifc_len));
+            let buf_size = ParamSize::from_initialized_mem(t, ifc_len_ptr);
+            // ifc_ifcu.ifcu_buf
+            syscall_state.mem_ptr_parameter_with_size(
+                t,
+                ifconfp + arch_offset_of!(Arch, ifconf, ifc_ifcu),
+                buf_size,
+                None,
+            );
+            syscall_state.after_syscall_action(Box::new(record_page_below_stack_ptr));
+            return Switchable::PreventSwitch;
+        }
+
+        SIOCGIFADDR | SIOCGIFFLAGS | SIOCGIFINDEX | SIOCGIFMTU | SIOCGIFNAME => {
+            syscall_state.reg_parameter_with_size(
+                t,
+                3,
+                ParamSize::from(arch_size_of!(Arch, ifreq)),
+                None,
+            );
+            syscall_state.after_syscall_action(Box::new(record_page_below_stack_ptr));
+            return Switchable::PreventSwitch;
+        }
+
+        SIOCGIFHWADDR | SIOCGIFBRDADDR | SIOCGIFNETMASK | SIOCGIFDSTADDR | SIOCGIFMAP
+        | SIOCGIFTXQLEN => {
+            syscall_state.reg_parameter_with_size(
+                t,
+                3,
+                ParamSize::from(arch_size_of!(Arch, ifreq)),
+                Some(ArgMode::InOut),
+            );
+            syscall_state.after_syscall_action(Box::new(record_page_below_stack_ptr));
+            return Switchable::PreventSwitch;
+        }
+
+        SIOCGIWRATE => {
+            // SIOCGIWRATE hasn't been the subject of a copy-paste
+            // pattern like SIOCGIFNAME above.
+            syscall_state.reg_parameter_with_size(
+                t,
+                3,
+                ParamSize::from(arch_size_of!(Arch, iwreq)),
+                None,
+            );
+            syscall_state.after_syscall_action(Box::new(record_page_below_stack_ptr));
+            return Switchable::PreventSwitch;
+        }
+
+        TCGETS => {
+            syscall_state.reg_parameter_with_size(
+                t,
+                3,
+                ParamSize::from(arch_size_of!(Arch, termios)),
+                None,
+            );
+            return Switchable::PreventSwitch;
+        }
+
+        TCGETA => {
+            syscall_state.reg_parameter_with_size(
+                t,
+                3,
+                ParamSize::from(arch_size_of!(Arch, termio)),
+                None,
+            );
+            return Switchable::PreventSwitch;
+        }
+
+        FIONREAD | TIOCOUTQ | TIOCGETD => {
+            syscall_state.reg_parameter::<i32>(t, 3, None);
+            return Switchable::PreventSwitch;
+        }
+
+        TIOCGWINSZ => {
+            syscall_state.reg_parameter_with_size(
+                t,
+                3,
+                ParamSize::from(arch_size_of!(Arch, winsize)),
+                None,
+            );
+            return Switchable::PreventSwitch;
+        }
+
+        TIOCGPGRP | TIOCGSID => {
+            syscall_state.reg_parameter::<pid_t>(t, 3, None);
+            return Switchable::PreventSwitch;
+        }
+
+        TIOCGPTN | TIOCGPKT | TIOCGPTLCK | TIOCGEXCL => {
+            syscall_state.reg_parameter_with_size(t, 3, ParamSize::from(size as usize), None);
+            return Switchable::PreventSwitch;
+        }
+
+        _ => (),
+    }
+
+    // In ioctl language, "_IOC_READ" means "outparam".  Both
+    // READ and WRITE can be set for inout params.
+    if dir & IOC_READ == 0 {
+        // If the kernel isn't going to write any data back to
+        // us, we hope and pray that the result of the ioctl
+        // (observable to the tracee) is deterministic.
+        // We're also assuming it doesn't block.
+        // This is risky! Many ioctls use irregular ioctl codes
+        // that do not have the _IOC_READ bit set but actually do write to
+        // user-space!
+        log!(LogDebug, "  (presumed ignorable ioctl, nothing to do)");
+        return Switchable::PreventSwitch;
+    }
+
+    // @TODO rr has a long list of "regular" ioctls whose only observable
+    // effect is to write `size` bytes to the buffer passed in. Add them as
+    // they are needed.
+    syscall_state.expect_errno = EINVAL;
+    Switchable::PreventSwitch
+}
+
+fn prepare_prctl<Arch: Architecture>(
+    t: &mut RecordTask,
+    syscall_state: &mut TaskSyscallState,
+    regs: &Registers,
+) -> Switchable {
+    match regs.arg1_signed() as i32 {
+        PR_GET_CHILD_SUBREAPER
+        | PR_GET_ENDIAN
+        | PR_GET_FPEMU
+        | PR_GET_FPEXC
+        | PR_GET_PDEATHSIG
+        | PR_GET_UNALIGN => {
+            syscall_state.reg_parameter::<i32>(t, 2, None);
+        }
+
+        PR_GET_KEEPCAPS
+        | PR_GET_NO_NEW_PRIVS
+        | PR_GET_TIMERSLACK
+        | PR_MCE_KILL
+        | PR_MCE_KILL_GET
+        | PR_SET_CHILD_SUBREAPER
+        | PR_SET_KEEPCAPS
+        | PR_SET_NAME
+        | PR_SET_PDEATHSIG
+        | PR_SET_TIMERSLACK
+        | PR_CAP_AMBIENT
+        | PR_CAPBSET_DROP
+        | PR_CAPBSET_READ
+        | PR_GET_THP_DISABLE
+        | PR_SET_THP_DISABLE => (),
+
+        op if op == PR_GET_SPECULATION_CTRL as i32 || op == PR_SET_SPECULATION_CTRL as i32 => (),
+
+        PR_SET_DUMPABLE => {
+            if regs.arg2() == 0 {
+                // Don't let processes make themselves undumpable. If a process
+                // becomes undumpable, calling perf_event_open on it fails.
+                let mut r = regs.clone();
+                r.set_arg1(usize::MAX);
+                t.set_regs(&r);
+                syscall_state.emulate_result(0);
+                t.thread_group_mut().dumpable = false;
+            } else if regs.arg2() == 1 {
+                t.thread_group_mut().dumpable = true;
+            }
+        }
+
+        PR_GET_DUMPABLE => {
+            let dumpable = t.thread_group().dumpable;
+            syscall_state.emulate_result(if dumpable { 1 } else { 0 });
+        }
+
+        PR_GET_SECCOMP => {
+            syscall_state.emulate_result(t.prctl_seccomp_status as usize);
+        }
+
+        PR_GET_TSC => {
+            // Prevent the actual GET_TSC call and return our emulated state.
+            syscall_state.reg_parameter::<i32>(t, 2, Some(ArgMode::InOut));
+            let mut r = regs.clone();
+            r.set_arg1(usize::MAX);
+            t.set_regs(&r);
+            syscall_state.emulate_result(0);
+            syscall_state.after_syscall_action(Box::new(|t: &mut RecordTask| {
+                let ptr = RemotePtr::<i32>::from(t.regs_ref().arg2());
+                let tsc_mode = t.tsc_mode;
+                write_val_mem(t, ptr, &tsc_mode, None);
+                t.record_local_for(ptr, &tsc_mode);
+            }));
+        }
+
+        PR_SET_TSC => {
+            // Prevent the actual SET_TSC call.
+            let mut r = regs.clone();
+            r.set_arg1(usize::MAX);
+            t.set_regs(&r);
+            let val = regs.arg2() as i32;
+            if val != PR_TSC_ENABLE && val != PR_TSC_SIGSEGV {
+                syscall_state.emulate_result_signed(-EINVAL as isize);
+            } else {
+                syscall_state.emulate_result(0);
+                t.tsc_mode = val;
+            }
+        }
+
+        PR_GET_NAME => {
+            syscall_state.reg_parameter_with_size(t, 2, ParamSize::from(16), None);
+        }
+
+        PR_SET_NO_NEW_PRIVS => {
+            if regs.arg2() != 1 {
+                syscall_state.expect_errno = EINVAL;
+            }
+        }
+
+        PR_SET_SECCOMP => {
+            // Allow all known seccomp calls. We must allow the seccomp call
+            // that rd triggers when spawning the initial tracee.
+            match regs.arg2() as u32 {
+                SECCOMP_MODE_STRICT => (),
+                SECCOMP_MODE_FILTER => {
+                    // If we're bootstrapping then this must be rd's own syscall
+                    // filter, so just install it normally now.
+                    if t.session().done_initial_exec() {
+                        // Prevent the actual prctl call. We'll install a patched
+                        // filter afterwards. See rec_process_syscall_arch().
+                        let mut r = regs.clone();
+                        r.set_arg1(usize::MAX);
+                        t.set_regs(&r);
+                    }
+                }
+                _ => {
+                    syscall_state.expect_errno = EINVAL;
+                }
+            }
+        }
+
+        PR_SET_PTRACER => {
+            // Prevent any PR_SET_PTRACER call, but pretend it succeeded, since
+            // we don't want any interference with our ptracing.
+            let mut r = regs.clone();
+            r.set_arg1(usize::MAX);
+            t.set_regs(&r);
+            syscall_state.emulate_result(0);
+        }
+
+        _ => {
+            syscall_state.expect_errno = EINVAL;
+        }
+    }
+
+    Switchable::PreventSwitch
+}
+
+/// Call this when `t` has just exited its current syscall. Records the
+/// syscall's outputs and performs any post-processing the syscall needs.
+pub fn rec_process_syscall(t: &mut RecordTask) {
+    let syscall_state_shr = syscall_state_for(t);
+    let number = t.ev().syscall().number;
+    let arch = t.ev().syscall().arch();
+    if arch != t.arch() {
+        log!(
+            LogWarn,
+            "Cross architecture syscall detected. Support is best effort"
+        );
+    }
+    {
+        let mut syscall_state = syscall_state_shr.borrow_mut();
+        rd_arch_function_selfless!(rec_process_syscall_arch, arch, t, &mut syscall_state);
+        // DIFF NOTE: In rr this happens inside the arch specific function. Doing it
+        // here means that any syscall specific processing above (e.g. deciding not
+        // to write back results) always takes effect before the results are recorded.
+        syscall_state.process_syscall_results(t);
+    }
+    let regs = t.regs_ref().clone();
+    t.on_syscall_exit(number, arch, &regs);
+    t.syscall_state = None;
+
+    MonitoredSharedMemory::check_all(t);
+}
+
+/// Call this when `t` has exited a syscall that was prepared but which will
+/// not be processed by rec_process_syscall(), e.g. because it was interrupted
+/// and will not be restarted.
+pub fn rec_abort_prepared_syscall(t: &mut RecordTask) {
+    match t.syscall_state.clone() {
+        Some(syscall_state) => {
+            log!(
+                LogDebug,
+                "Aborting syscall {}",
+                t.ev().syscall().syscall_name()
+            );
+            syscall_state.borrow_mut().abort_syscall_results(t);
+            t.syscall_state = None;
+        }
+        None => (),
+    }
+}
+
+/// Call this when `t` has just returned from a sigreturn/rt_sigreturn.
+pub fn rec_did_sigreturn(t: &mut RecordTask) {
+    t.invalidate_sigmask();
+}
+
+fn rec_process_syscall_arch<Arch: Architecture>(
+    t: &mut RecordTask,
+    syscall_state: &mut TaskSyscallState,
+) {
+    let sys = t.ev().syscall().number;
+
+    if t.regs_ref().original_syscallno() == SECCOMP_MAGIC_SKIP_ORIGINAL_SYSCALLNO {
+        // rd vetoed this syscall. Don't do any post-processing.
+        return;
+    }
+
+    log!(
+        LogDebug,
+        "{}: processing: {} -- time: {}",
+        t.tid,
+        t.ev(),
+        t.trace_time()
+    );
+
+    let rec = t.desched_rec();
+    if !rec.is_null() {
+        // If the syscallbuf has already been unmapped, there's no need to record
+        // the entry.
+        if !t.syscallbuf_child.is_null() {
+            let size_addr = RemotePtr::<u32>::cast(
+                RemotePtr::<u8>::cast(rec) + offset_of!(syscallbuf_record, size),
+            );
+            let size = read_val_mem(t, size_addr, None) as usize;
+            t.record_remote(
+                RemotePtr::<Void>::cast(rec) + size_of::<syscallbuf_record>(),
+                size - size_of::<syscallbuf_record>(),
+            );
+        }
+        return;
+    }
+
+    if syscall_state.expect_errno != 0 {
+        if syscall_state.expect_errno == EINVAL
+            && sys == Arch::IOCTL
+            && t.regs_ref().syscall_result_signed() == -ENOTTY as isize
+        {
+            // Unsupported ioctl was called, but is not supported for this device,
+            // so we can safely ignore it.
+            return;
+        }
+        ed_assert_eq!(
+            t,
+            t.regs_ref().syscall_result_signed(),
+            -syscall_state.expect_errno as isize,
+            "Expected {} for '{}' but got result {} (errno {})",
+            errno_name(syscall_state.expect_errno),
+            syscall_name(sys, Arch::arch()),
+            t.regs_ref().syscall_result_signed(),
+            errno_name(-t.regs_ref().syscall_result_signed() as i32)
+        );
+        return;
+    }
+
+    // Here we handle syscalls that need work that can only happen after the
+    // syscall completes --- and that our TaskSyscallState infrastructure can't
+    // handle.
+    if sys == Arch::FORK || sys == Arch::VFORK || sys == Arch::CLONE {
+        let is_vfork = sys == Arch::VFORK
+            || (sys == Arch::CLONE
+                && syscall_state.syscall_entry_registers.arg1() & CLONE_VFORK as usize != 0);
+        // The vfork child has exec'd or exited by now.
+        if is_vfork
+            && t.regs_ref().syscall_result_signed() >= 0
+            && t.emulated_ptrace_options & PTRACE_O_TRACEVFORKDONE != 0
+        {
+            t.emulated_ptrace_event_msg = t.regs_ref().syscall_result();
+            t.emulate_ptrace_stop(
+                WaitStatus::for_ptrace_event(PTRACE_EVENT_VFORK_DONE),
+                None,
+                None,
+            );
+        }
+        return;
+    }
+
+    if sys == Arch::EXECVE {
+        process_execve(t, syscall_state);
+        return;
+    }
+
+    if sys == Arch::BRK {
+        process_brk(t);
+        return;
+    }
+
+    if sys == Arch::MMAP {
+        match Arch::MMAP_SEMANTICS {
+            MmapCallingSemantics::StructArguments => {
+                let args: Arch::mmap_args =
+                    read_val_mem(t, RemotePtr::from(t.regs_ref().arg1()), None);
+                let (len, prot, flags, fd, offset) = Arch::get_mmap_args(&args);
+                process_mmap(t, len, prot, flags, fd, offset / 4096);
+            }
+            MmapCallingSemantics::RegisterArguments => {
+                let mut r = t.regs_ref().clone();
+                r.set_arg1(syscall_state.syscall_entry_registers.arg1());
+                r.set_arg4(syscall_state.syscall_entry_registers.arg4());
+                t.set_regs(&r);
+                process_mmap(
+                    t,
+                    r.arg2(),
+                    r.arg3_signed() as i32,
+                    r.arg4_signed() as i32,
+                    r.arg5_signed() as i32,
+                    r.arg6() / 4096,
+                );
+            }
+        }
+        return;
+    }
+
+    if sys == Arch::MMAP2 {
+        let mut r = t.regs_ref().clone();
+        r.set_arg1(syscall_state.syscall_entry_registers.arg1());
+        r.set_arg4(syscall_state.syscall_entry_registers.arg4());
+        t.set_regs(&r);
+        process_mmap(
+            t,
+            r.arg2(),
+            r.arg3_signed() as i32,
+            r.arg4_signed() as i32,
+            r.arg5_signed() as i32,
+            r.arg6(),
+        );
+        return;
+    }
+
+    if sys == Arch::MREMAP {
+        let r = t.regs_ref().clone();
+        process_mremap(t, RemotePtr::from(r.arg1()), r.arg2(), r.arg3());
+        return;
+    }
+
+    if sys == Arch::SHMAT {
+        let r = t.regs_ref().clone();
+        process_shmat(
+            t,
+            r.arg1_signed() as i32,
+            r.arg3_signed() as i32,
+            RemotePtr::from(r.syscall_result()),
+        );
+        return;
+    }
+
+    if sys == Arch::IPC {
+        let r = t.regs_ref().clone();
+        if r.arg1() as u32 == SHMAT && !r.syscall_failed() {
+            let out_ptr = RemotePtr::<Void>::from(r.arg4());
+            let out = read_word::<Arch>(t, out_ptr);
+            process_shmat(
+                t,
+                r.arg2_signed() as i32,
+                r.arg3_signed() as i32,
+                RemotePtr::from(out),
+            );
+        }
+        return;
+    }
+
+    if sys == Arch::NANOSLEEP || sys == Arch::CLOCK_NANOSLEEP || sys == Arch::CLOCK_NANOSLEEP_TIME64
+    {
+        // If the sleep completes, the kernel doesn't write back to the
+        // remaining-time argument.
+        if t.regs_ref().syscall_result() as i32 == 0 {
+            syscall_state.write_back = WriteBack::NoWriteBack;
+        }
+        return;
+    }
+
+    if sys == Arch::OPEN || sys == Arch::OPENAT {
+        let mut r = t.regs_ref().clone();
+        if !r.syscall_failed() {
+            let fd = r.syscall_result_signed() as i32;
+            let flags = if sys == Arch::OPENAT {
+                r.arg3_signed()
+            } else {
+                r.arg2_signed()
+            } as i32;
+            handle_opened_file(t, fd, flags);
+        }
+        // Undo any blacklisting we did in rec_prepare_syscall_arch.
+        if sys == Arch::OPENAT {
+            r.set_arg2(syscall_state.syscall_entry_registers.arg2());
+        } else {
+            r.set_arg1(syscall_state.syscall_entry_registers.arg1());
+        }
+        t.set_regs(&r);
+        return;
+    }
+
+    if sys == Arch::MEMFD_CREATE {
+        let mut r = t.regs_ref().clone();
+        r.set_arg1(syscall_state.syscall_entry_registers.arg1());
+        t.set_regs(&r);
+        return;
+    }
+
+    if sys == Arch::PERF_EVENT_OPEN {
+        if t.regs_ref().original_syscallno() as i32 == syscall_number_for_inotify_init(t.arch()) {
+            ed_assert!(t, !t.regs_ref().syscall_failed());
+            let fd = t.regs_ref().syscall_result_signed() as i32;
+            let mut r = t.regs_ref().clone();
+            r.set_original_syscallno(syscall_state.syscall_entry_registers.original_syscallno());
+            t.set_regs(&r);
+            let attr: perf_event_attr = read_val_mem(t, RemotePtr::from(r.arg1()), None);
+            let target_tid = r.arg2_signed() as pid_t;
+            let monitor = if target_tid == t.rec_tid {
+                VirtualPerfCounterMonitor::new(t, t, &attr)
+            } else {
+                let target_shr = t.session().find_task_from_rec_tid(target_tid).unwrap();
+                let target = target_shr.borrow();
+                VirtualPerfCounterMonitor::new(t, target.as_ref(), &attr)
+            };
+            t.fd_table_shr_ptr()
+                .borrow_mut()
+                .add_monitor(t, fd, Box::new(monitor));
+        }
+        return;
+    }
+
+    if sys == Arch::PROCESS_VM_READV {
+        let r = t.regs_ref().clone();
+        record_iovec_output::<Arch>(
+            t,
+            None,
+            RemotePtr::from(r.arg2()),
+            r.arg3() as u32,
+            r.syscall_result_signed(),
+        );
+        return;
+    }
+
+    if sys == Arch::PROCESS_VM_WRITEV {
+        let r = t.regs_ref().clone();
+        let dest_tid = r.arg1_signed() as pid_t;
+        if dest_tid == t.rec_tid {
+            record_iovec_output::<Arch>(
+                t,
+                None,
+                RemotePtr::from(r.arg4()),
+                r.arg5() as u32,
+                r.syscall_result_signed(),
+            );
+        } else {
+            match t.session().find_task_from_rec_tid(dest_tid) {
+                Some(dest_shr) => {
+                    let mut dest_b = dest_shr.borrow_mut();
+                    let dest = dest_b.as_record_task_mut().unwrap();
+                    record_iovec_output::<Arch>(
+                        t,
+                        Some(dest),
+                        RemotePtr::from(r.arg4()),
+                        r.arg5() as u32,
+                        r.syscall_result_signed(),
+                    );
+                }
+                None => (),
+            }
+        }
+        return;
+    }
+
+    if sys == Arch::GETDENTS || sys == Arch::GETDENTS64 {
+        let fd = t.regs_ref().arg1() as i32;
+        t.fd_table_shr_ptr().borrow().filter_getdents(fd, t);
+        return;
+    }
+
+    if sys == Arch::WAITPID || sys == Arch::WAIT4 || sys == Arch::WAITID {
+        t.in_wait_type = WaitType::WaitTypeNone;
+        // Restore possibly-modified registers
+        let mut r = t.regs_ref().clone();
+        r.set_arg1(syscall_state.syscall_entry_registers.arg1());
+        r.set_arg2(syscall_state.syscall_entry_registers.arg2());
+        r.set_arg3(syscall_state.syscall_entry_registers.arg3());
+        r.set_arg4(syscall_state.syscall_entry_registers.arg4());
+        r.set_original_syscallno(syscall_state.syscall_entry_registers.original_syscallno());
+        t.set_regs(&r);
+
+        let maybe_tracee = syscall_state
+            .emulate_wait_for_child
+            .as_ref()
+            .and_then(|w| w.upgrade());
+        if let Some(tracee_rc) = maybe_tracee {
+            let mut tracee_b = tracee_rc.borrow_mut();
+            let tracee = tracee_b.as_record_task_mut().unwrap();
+            // Finish emulation of ptrace result or stop-signal
+            let mut r = t.regs_ref().clone();
+            r.set_syscall_result(if sys == Arch::WAITID {
+                0
+            } else {
+                tracee.tid as usize
+            });
+            t.set_regs(&r);
+            if sys == Arch::WAITID {
+                let sip = RemotePtr::<Arch::siginfo_t>::from(r.arg3());
+                if !sip.is_null() {
+                    let mut si: Arch::siginfo_t = unsafe { zeroed() };
+                    // si_signo is the first member of siginfo_t on all architectures.
+                    unsafe { *(&mut si as *mut Arch::siginfo_t as *mut i32) = SIGCHLD };
+                    tracee.set_siginfo_for_waited_task::<Arch>(&mut si);
+                    write_val_mem(t, sip, &si, None);
+                }
+            } else {
+                let statusp = RemotePtr::<i32>::from(r.arg2());
+                if !statusp.is_null() {
+                    write_val_mem(t, statusp, &tracee.emulated_stop_code.get(), None);
+                }
+            }
+            let options = if sys == Arch::WAITID {
+                r.arg4() as i32
+            } else {
+                r.arg3() as i32
+            };
+            if options & WNOWAIT == 0 {
+                tracee.emulated_stop_pending = false;
+            }
+        }
+        return;
+    }
+
+    if sys == Arch::SIGPROCMASK || sys == Arch::RT_SIGPROCMASK {
+        let setp = RemotePtr::<u64>::from(t.regs_ref().arg2());
+        if !setp.is_null() && syscall_state.saved_data.len() == size_of::<u64>() {
+            // Restore the mask the tracee passed in.
+            let mut bytes = [0u8; size_of::<u64>()];
+            bytes.copy_from_slice(&syscall_state.saved_data);
+            write_val_mem(t, setp, &u64::from_le_bytes(bytes), None);
+        }
+        return;
+    }
+
+    if sys == Arch::SECCOMP {
+        // Restore arg1 in case we modified it to disable the syscall
+        let mut r = t.regs_ref().clone();
+        r.set_arg1(syscall_state.syscall_entry_registers.arg1());
+        t.set_regs(&r);
+        if t.regs_ref().arg1() as u32 == SECCOMP_SET_MODE_FILTER {
+            ed_assert!(
+                t,
+                t.session().done_initial_exec(),
+                "no seccomp calls during spawn"
+            );
+            install_patched_seccomp_filter(t);
+        }
+        return;
+    }
+
+    if sys == Arch::PRCTL {
+        // Restore arg1 in case we modified it to disable the syscall
+        let mut r = t.regs_ref().clone();
+        r.set_arg1(syscall_state.syscall_entry_registers.arg1());
+        t.set_regs(&r);
+        if t.regs_ref().arg1_signed() as i32 == PR_SET_SECCOMP
+            && t.regs_ref().arg2() as u32 == SECCOMP_MODE_FILTER
+            && t.session().done_initial_exec()
+        {
+            install_patched_seccomp_filter(t);
+        }
+        return;
+    }
+
+    if sys == Arch::ARCH_PRCTL
+        || sys == Arch::SCHED_SETAFFINITY
+        || sys == Arch::CLOSE
+        || sys == Arch::DUP2
+        || sys == Arch::DUP3
+        || sys == Arch::FCNTL
+        || sys == Arch::FCNTL64
+        || sys == Arch::IOCTL
+        || sys == Arch::PTRACE
+        || sys == Arch::BPF
+        || sys == Arch::SETSOCKOPT
+        || sys == Arch::SOCKETCALL
+    {
+        // Restore possibly-modified registers
+        let mut r = t.regs_ref().clone();
+        r.set_arg1(syscall_state.syscall_entry_registers.arg1());
+        t.set_regs(&r);
+        return;
+    }
+
+    if sys == Arch::IO_SETUP || sys == Arch::FUTEX || sys == Arch::FUTEX_TIME64 {
+        // Restore possibly-modified registers
+        let mut r = t.regs_ref().clone();
+        r.set_arg2(syscall_state.syscall_entry_registers.arg2());
+        t.set_regs(&r);
+        return;
+    }
+
+    if sys == Arch::RDCALL_INIT_BUFFERS {
+        t.init_buffers();
+        return;
+    }
+
+    if sys == Arch::RDCALL_INIT_PRELOAD {
+        t.at_preload_init();
+        return;
+    }
+
+    if sys == Arch::RDCALL_NOTIFY_SYSCALL_HOOK_EXIT {
+        ed_assert!(t, !t.syscallbuf_child.is_null());
+        let child_addr = RemotePtr::<u8>::cast(t.syscallbuf_child)
+            + offset_of!(syscallbuf_hdr, notify_on_syscall_hook_exit);
+        write_val_mem(t, child_addr, &0u8, None);
+        t.record_remote_for(child_addr);
+
+        let mut r = t.regs_ref().clone();
+        r.set_syscall_result(syscall_state.syscall_entry_registers.arg1());
+        t.set_regs(&r);
+        return;
+    }
+
+    if sys == Arch::RDCALL_RELOAD_AUXV {
+        // An rd replaying under us has set up the initial address space of
+        // one of its tasks, so the auxv we saved at exec is stale.
+        let target_tid = t.regs_ref().arg1() as pid_t;
+        if target_tid == t.rec_tid {
+            t.vm_shr_ptr().save_auxv(t);
+        } else if let Some(target_rc) = t.session().find_task_from_rec_tid(target_tid) {
+            let mut target = target_rc.borrow_mut();
+            target.vm_shr_ptr().save_auxv(target.as_mut());
+        }
+        return;
+    }
+}
+
+/// Record the output of a process_vm_readv/process_vm_writev in `dest`
+/// (or `t` if `maybe_dest` is `None`).
+fn record_iovec_output<Arch: Architecture>(
+    t: &mut RecordTask,
+    maybe_dest: Option<&mut RecordTask>,
+    piov: RemotePtr<Arch::iovec>,
+    iov_cnt: u32,
+    result: isize,
+) {
+    // Check for the syscall failing
+    if result <= 0 {
+        return;
+    }
+    let mut remaining = result as usize;
+    let iovs = read_mem(t, piov, iov_cnt as usize, None);
+    let dest = match maybe_dest {
+        Some(dest) => dest,
+        None => t,
+    };
+    for iov in &iovs {
+        if remaining == 0 {
+            break;
+        }
+        let (iov_base, iov_len) = Arch::get_iovec(iov);
+        let amount = min(remaining, iov_len);
+        dest.record_remote(iov_base, amount);
+        remaining -= amount;
+    }
+}
+
+/// Returns true if any address space in the session maps the file `st`
+/// MAP_SHARED.
+fn is_mapped_shared(t: &RecordTask, st: &libc::stat) -> bool {
+    for vm in &t.session().vms() {
+        for (_, m) in &vm.maps() {
+            if m.map.device() == st.st_dev
+                && m.map.inode() == st.st_ino
+                && m.map.flags().contains(MapFlags::MAP_SHARED)
+            {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+/// The /proc/<tid>/fd/<fd> link reflects the access mode of the open file.
+fn is_writable(t: &RecordTask, fd: i32) -> bool {
+    let lst = t.lstat_fd(fd);
+    lst.st_mode & S_IWUSR != 0
+}
+
+/// Returns true if `pathname` is the terminal rd itself is writing to.
+fn is_rd_terminal(pathname: &OsStr) -> bool {
+    if pathname == "/dev/tty" {
+        // XXX the tracee's /dev/tty could refer to a tty other than
+        // the one rd is writing to, but it's unlikely.
+        return true;
+    }
+    for fd in &[STDIN_FILENO, STDOUT_FILENO, STDERR_FILENO] {
+        match ttyname(*fd) {
+            Ok(name) if name.as_os_str() == pathname => return true,
+            _ => (),
+        }
+    }
+    false
+}
+
+/// The file `fd` has just been opened by `t`. Install a file monitor for it
+/// if it needs special handling.
+/// This must be kept in sync with replay_syscall's handle_opened_files.
+fn handle_opened_file(t: &mut RecordTask, fd: i32, flags: i32) -> OsString {
+    let mut pathname = t.file_name_of_fd(fd);
+    let st = t.stat_fd(fd);
+
+    let maybe_file_monitor: Option<Box<dyn FileMonitor>> =
+        if is_mapped_shared(t, &st) && is_writable(t, fd) {
+            // This is quite a subtle calculation. We want to make sure that we
+            // record all writes to files that are mapped shared into some tracee
+            // so that we can replay the changes to the mapping.
+            log!(LogInfo, "Installing MmappedFileMonitor for {}", fd);
+            Some(Box::new(MmappedFileMonitor::new(t, fd)))
+        } else if is_rd_terminal(&pathname) {
+            // This will let rd event annotations echo to the terminal. It will also
+            // ensure writes to this fd are not syscall-buffered.
+            log!(LogInfo, "Installing StdioMonitor for {}", fd);
+            pathname = OsString::from("terminal");
+            Some(Box::new(StdioMonitor::new(STDERR_FILENO)))
+        } else if is_proc_mem_file(&pathname) {
+            log!(LogInfo, "Installing ProcMemMonitor for {}", fd);
+            Some(Box::new(ProcMemMonitor::new(t, &pathname)))
+        } else if is_proc_fd_dir(&pathname) {
+            log!(LogInfo, "Installing ProcFdDirMonitor for {}", fd);
+            Some(Box::new(ProcFdDirMonitor::new(t, &pathname)))
+        } else if flags & O_DIRECT != 0 {
+            // O_DIRECT can impose unknown alignment requirements, in which case
+            // syscallbuf records will not be properly aligned and will cause I/O
+            // to fail. Disable syscall buffering for O_DIRECT files.
+            log!(LogInfo, "Installing BaseFileMonitor for {}", fd);
+            Some(Box::new(BaseFileMonitor::new()))
+        } else {
+            None
+        };
+
+    match maybe_file_monitor {
+        Some(file_monitor) => {
+            // Write absolute file name
+            t.ev_mut().syscall_mut().opened.push(OpenedFd {
+                path: pathname.clone(),
+                fd,
+                device: st.st_dev,
+                inode: st.st_ino,
+            });
+            t.fd_table_shr_ptr()
+                .borrow_mut()
+                .add_monitor(t, fd, file_monitor);
+        }
+        None => (),
+    }
+
+    pathname
+}
+
+/// Where the scratch memory of a task that has just exec'd gets mapped.
+/// Using a fixed address avoids scratch memory perturbing the layout of
+/// the new address space.
+const FIXED_SCRATCH_PTR: usize = 0x68000000;
+
+/// Map a fresh scratch area for `t` and record the mapping in the trace.
+/// If `maybe_addr` is provided the area is mapped MAP_FIXED at that address.
+fn init_scratch_memory(t: &mut RecordTask, maybe_addr: Option<RemotePtr<Void>>) {
+    let scratch_size = 512 * page_size();
+    let sz = scratch_size;
+    // The PROT_EXEC looks scary, and it is, but it's to prevent
+    // this region from being coalesced with another anonymous
+    // segment mapped just after this one.  If we named this
+    // segment, we could remove this hack.
+    let prot = ProtFlags::PROT_READ | ProtFlags::PROT_WRITE | ProtFlags::PROT_EXEC;
+    let mut flags = MapFlags::MAP_PRIVATE | MapFlags::MAP_ANONYMOUS;
+    if maybe_addr.is_some() {
+        flags |= MapFlags::MAP_FIXED;
+    }
+    {
+        // initialize the scratchpad for blocking system calls
+        let mut remote = AutoRemoteSyscalls::new(t);
+        let scratch_ptr = remote.infallible_mmap_syscall(maybe_addr, sz, prot, flags, -1, 0);
+        remote.task_mut().scratch_ptr = scratch_ptr;
+        remote.task_mut().scratch_size = scratch_size;
+    }
+
+    t.setup_preload_thread_locals();
+
+    // record this mmap for the replay
+    let mut r = t.regs_ref().clone();
+    let saved_result = r.syscall_result();
+    r.set_syscall_result(t.scratch_ptr.as_usize());
+    t.set_regs(&r);
+
+    let km = t.vm_shr_ptr().map(
+        t,
+        t.scratch_ptr,
+        sz,
+        prot,
+        flags & !MapFlags::MAP_FIXED,
+        0,
+        OsStr::new(""),
+        KernelMapping::NO_DEVICE,
+        KernelMapping::NO_INODE,
+        None,
+        None,
+        None,
+        None,
+        None,
+    );
+    let st: libc::stat = unsafe { zeroed() };
+    let record_in_trace = t
+        .trace_writer_mut()
+        .write_mapped_region(t, &km, &st, &[], None, None);
+    ed_assert_eq!(t, record_in_trace, RecordInTrace::DontRecordInTrace);
+
+    r.set_syscall_result(saved_result);
+    t.set_regs(&r);
+}
+
+/// Bits of a /proc/<pid>/pagemap entry
+const PAGEMAP_PRESENT: u64 = 1 << 63;
+const PAGEMAP_SWAPPED: u64 = 1 << 62;
+const PAGEMAP_FILE_PAGE_OR_SHARED_ANON: u64 = 1 << 61;
+
+/// Returns the pages of `km` that are anonymous (i.e. no longer backed by
+/// the mapped file) and resident or swapped.
+fn anonymous_pages_of(t: &RecordTask, km: &KernelMapping) -> Vec<RemotePtr<Void>> {
+    let path = format!("/proc/{}/pagemap", t.tid);
+    let f = match File::open(&path) {
+        Ok(f) => f,
+        Err(e) => fatal!("Can't open {}: {:?}", path, e),
+    };
+    let num_pages = km.size() / page_size();
+    let mut buf = vec![0u8; num_pages * size_of::<u64>()];
+    let offset = (km.start().as_usize() / page_size() * size_of::<u64>()) as u64;
+    if f.read_exact_at(&mut buf, offset).is_err() {
+        fatal!("Can't read {}", path);
+    }
+
+    let mut pages = Vec::new();
+    for (i, entry) in buf.chunks_exact(size_of::<u64>()).enumerate() {
+        let mut bytes = [0u8; size_of::<u64>()];
+        bytes.copy_from_slice(entry);
+        let v = u64::from_le_bytes(bytes);
+        if v & PAGEMAP_FILE_PAGE_OR_SHARED_ANON == 0 && v & (PAGEMAP_PRESENT | PAGEMAP_SWAPPED) != 0
+        {
+            pages.push(km.start() + i * page_size());
+        }
+    }
+    pages
+}
+
+fn process_execve(t: &mut RecordTask, syscall_state: &mut TaskSyscallState) {
+    if t.regs_ref().syscall_failed() {
+        return;
+    }
+
+    t.post_exec_syscall();
+    let fds_to_close = t.fd_table_shr_ptr().borrow_mut().fds_to_close_after_exec(t);
+    t.ev_mut().syscall_mut().exec_fds_to_close = fds_to_close;
+
+    // @TODO rr checks for a privileged (setuid etc.) executable here
+
+    for start in &[
+        AddressSpace::rd_page_start(),
+        AddressSpace::preload_thread_locals_start(),
+    ] {
+        let km = t.vm().mapping_of(*start).unwrap().map.clone();
+        let mode = t.trace_writer_mut().write_mapped_region(
+            t,
+            &km,
+            &km.fake_stat(),
+            &[],
+            Some(MappingOrigin::RdBufferMapping),
+            None,
+        );
+        ed_assert_eq!(t, mode, RecordInTrace::DontRecordInTrace);
+    }
+
+    // Write out stack mappings first since during replay we need to set up the
+    // stack before any files get mapped.
+    let mut stacks: Vec<KernelMapping> = Vec::new();
+    let mut maybe_vvar: Option<KernelMapping> = None;
+    for (_, m) in &t.vm().maps() {
+        if m.map.is_stack() {
+            stacks.push(m.map.clone());
+        } else if m.map.is_vvar() {
+            maybe_vvar = Some(m.map.clone());
+        }
+    }
+
+    {
+        let mut remote =
+            AutoRemoteSyscalls::new_with_mem_params(t, MemParamsEnabled::DisableMemoryParams);
+        let munmap_no = syscall_number_for_munmap(remote.arch());
+
+        match maybe_vvar {
+            Some(vvar) => {
+                // We're not going to map [vvar] during replay --- that wouldn't
+                // make sense, since it contains data from the kernel that isn't
+                // correct for replay, and we patch out the vdso syscalls that
+                // would use it. Unmapping it now makes recording look more like
+                // replay.
+                rd_infallible_syscall!(remote, munmap_no, vvar.start().as_usize(), vvar.size());
+                remote
+                    .task()
+                    .vm()
+                    .unmap(remote.task(), vvar.start(), vvar.size());
+            }
+            None => (),
+        }
+
+        for km in &stacks {
+            let buf;
+            {
+                let rt = remote.task_mut().as_record_task_mut().unwrap();
+                let mode = rt.trace_writer_mut().write_mapped_region(
+                    rt,
+                    km,
+                    &km.fake_stat(),
+                    &[],
+                    Some(MappingOrigin::ExecMapping),
+                    None,
+                );
+                ed_assert_eq!(rt, mode, RecordInTrace::RecordInTrace);
+                buf = read_mem::<u8>(rt, RemotePtr::cast(km.start()), km.size(), None);
+                rt.record_local(km.start(), &buf);
+            }
+
+            // Remove MAP_GROWSDOWN from stacks by remapping the memory and
+            // writing the contents back.
+            let flags = (km.flags() & !MapFlags::MAP_GROWSDOWN) | MapFlags::MAP_ANONYMOUS;
+            rd_infallible_syscall!(remote, munmap_no, km.start().as_usize(), km.size());
+            if remote
+                .task()
+                .vm()
+                .mapping_of(km.start() - page_size())
+                .is_none()
+            {
+                // Unmap an extra page below the stack so that our new mapping
+                // doesn't accidentally get merged with the stack mapping of
+                // another task.
+                rd_infallible_syscall!(
+                    remote,
+                    munmap_no,
+                    (km.start() - page_size()).as_usize(),
+                    page_size()
+                );
+            }
+            remote.infallible_mmap_syscall(Some(km.start()), km.size(), km.prot(), flags, -1, 0);
+            write_mem(
+                remote.task_mut(),
+                RemotePtr::<u8>::cast(km.start()),
+                &buf,
+                None,
+            );
+        }
+    }
+
+    // The kernel may zero part of the last page in each data mapping according
+    // to ELF BSS metadata. So we record any anonymous pages of each file
+    // mapping in the trace.
+    let mut pages_to_record: Vec<RemotePtr<Void>> = Vec::new();
+
+    let mut kms: Vec<KernelMapping> = Vec::new();
+    for (_, m) in &t.vm().maps() {
+        kms.push(m.map.clone());
+    }
+    for km in &kms {
+        if km.start() == AddressSpace::rd_page_start()
+            || km.start() == AddressSpace::preload_thread_locals_start()
+        {
+            continue;
+        }
+        if km.is_stack() || km.is_vsyscall() {
+            // [stack] has already been handled.
+            // [vsyscall] can't be read via /proc/<pid>/mem, *should*
+            // be the same across all execs, and can't be munmapped so we can't fix
+            // it even if it does vary. Plus no-one should be using it anymore.
+            continue;
+        }
+        let st = match stat(km.fsname()) {
+            Ok(st) => st,
+            Err(_) => km.fake_stat(),
+        };
+        let mode = t.trace_writer_mut().write_mapped_region(
+            t,
+            km,
+            &st,
+            &[],
+            Some(MappingOrigin::ExecMapping),
+            None,
+        );
+        if mode == RecordInTrace::RecordInTrace {
+            if st.st_size > 0 {
+                let end = max(st.st_size as i64 - km.file_offset_bytes() as i64, 0) as usize;
+                t.record_remote(km.start(), min(end, km.size()));
+            } else {
+                // st_size is not valid. Some device files are mmappable but have zero
+                // size. We also take this path if there's no file at all (vdso etc).
+                t.record_remote(km.start(), km.size());
+            }
+        } else if km.prot().contains(ProtFlags::PROT_WRITE)
+            && km.flags().contains(MapFlags::MAP_PRIVATE)
+        {
+            pages_to_record.extend(anonymous_pages_of(t, km));
+        }
+    }
+
+    init_scratch_memory(t, Some(RemotePtr::from(FIXED_SCRATCH_PTR)));
+
+    for p in pages_to_record {
+        t.record_remote(p, page_size());
+    }
+
+    // Patch LD_PRELOAD and VDSO after saving the mappings. Replay will apply
+    // patches to the saved mappings.
+    let vm = t.vm_shr_ptr();
+    vm.monkeypatcher_mut().unwrap().patch_after_exec(t);
+
+    // Find the lowest mapping of the executable so replay can tell it apart
+    // from the interpreter.
+    let exe_base = {
+        let exe_image = t.vm().exe_image().to_owned();
+        kms.iter()
+            .filter(|km| km.fsname() == exe_image)
+            .map(|km| km.start())
+            .min()
+            .unwrap_or(RemotePtr::null())
+    };
+    let mut ev = syscall_state.exec_saved_event.take().unwrap();
+    ev.set_exe_base(exe_base);
+    t.trace_writer_mut().write_task_event(&ev);
+}
+
+fn process_brk(t: &mut RecordTask) {
+    let old_brk = ceil_page_size(t.vm().current_brk());
+    let new_brk = ceil_page_size(RemotePtr::<Void>::from(t.regs_ref().syscall_result()));
+    let km = if old_brk < new_brk {
+        // Read the kernel's mapping. There doesn't seem to be any other way to
+        // get the correct prot bits for heaps. Usually it's READ|WRITE but
+        // there seem to be exceptions depending on system settings.
+        let kernel_info = AddressSpace::read_kernel_mapping(t, old_brk);
+        ed_assert_eq!(t, kernel_info.device(), KernelMapping::NO_DEVICE);
+        ed_assert_eq!(t, kernel_info.inode(), KernelMapping::NO_INODE);
+        kernel_info.subrange(old_brk, new_brk)
+    } else {
+        // Write a dummy KernelMapping that indicates an unmap
+        KernelMapping::new_with_opts(
+            new_brk,
+            old_brk,
+            OsStr::new(""),
+            KernelMapping::NO_DEVICE,
+            KernelMapping::NO_INODE,
+            ProtFlags::empty(),
+            MapFlags::empty(),
+            0,
+        )
+    };
+    let d = t
+        .trace_writer_mut()
+        .write_mapped_region(t, &km, &km.fake_stat(), &[], None, None);
+    ed_assert_eq!(t, d, RecordInTrace::DontRecordInTrace);
+    let result = RemotePtr::from(t.regs_ref().syscall_result());
+    t.vm_shr_ptr().brk(t, result, km.prot());
+}
+
+fn process_mmap(
+    t: &mut RecordTask,
+    length: usize,
+    prot_raw: i32,
+    flags_raw: i32,
+    fd: i32,
+    offset_pages: usize,
+) {
+    if t.regs_ref().syscall_failed() {
+        // We purposely mess with the flags argument of mmap, so it's possible
+        // that it failed due to that.
+        return;
+    }
+
+    let size = ceil_page_size(length);
+    let offset = offset_pages as u64 * 4096;
+    let addr = RemotePtr::<Void>::from(t.regs_ref().syscall_result());
+    let prot = ProtFlags::from_bits_truncate(prot_raw);
+    let flags = MapFlags::from_bits_truncate(flags_raw);
+
+    if flags.contains(MapFlags::MAP_ANONYMOUS) {
+        let km = if !flags.contains(MapFlags::MAP_SHARED) {
+            // Anonymous mappings are by definition not backed by any file-like
+            // object, and are initialized to zero, so there's no nondeterminism
+            // to record.
+            t.vm_shr_ptr().map(
+                t,
+                addr,
+                size,
+                prot,
+                flags,
+                0,
+                OsStr::new(""),
+                KernelMapping::NO_DEVICE,
+                KernelMapping::NO_INODE,
+                None,
+                None,
+                None,
+                None,
+                None,
+            )
+        } else {
+            ed_assert!(t, !flags.contains(MapFlags::MAP_GROWSDOWN));
+            // Read the kernel's mapping. There doesn't seem to be any other way to
+            // get the correct device/inode numbers. Fortunately anonymous shared
+            // mappings are rare.
+            let kernel_info = AddressSpace::read_kernel_mapping(t, addr);
+            t.vm_shr_ptr().map(
+                t,
+                addr,
+                size,
+                prot,
+                flags,
+                0,
+                kernel_info.fsname(),
+                kernel_info.device(),
+                kernel_info.inode(),
+                None,
+                None,
+                None,
+                None,
+                None,
+            )
+        };
+        let d = t
+            .trace_writer_mut()
+            .write_mapped_region(t, &km, &km.fake_stat(), &[], None, None);
+        ed_assert_eq!(t, d, RecordInTrace::DontRecordInTrace);
+        return;
+    }
+
+    ed_assert!(t, fd >= 0, "Valid fd required for file mapping");
+    ed_assert!(t, !flags.contains(MapFlags::MAP_GROWSDOWN));
+
+    let mut st = t.stat_fd(fd);
+    let file_name = t.file_name_of_fd(fd);
+    // /dev/zero mappings behave like anonymous mappings
+    let effectively_anonymous = major(st.st_rdev) == 1 && minor(st.st_rdev) == 5;
+
+    let km = t.vm_shr_ptr().map(
+        t,
+        addr,
+        size,
+        prot,
+        flags,
+        offset,
+        &file_name,
+        st.st_dev,
+        st.st_ino,
+        Some(st),
+        None,
+        None,
+        None,
+        None,
+    );
+
+    if st.st_size == 0 && st.st_mode & S_IFMT != S_IFREG {
+        // Some device files are mmappable but have zero size. Increasing the
+        // size here is safe even if the mapped size is greater than the real size.
+        st.st_size = (offset + size as u64) as i64;
+    }
+
+    let mut monitor_this_fd = false;
+    if flags.contains(MapFlags::MAP_SHARED) && !effectively_anonymous {
+        monitor_this_fd = monitor_fd_for_mapping(t, fd);
+    }
+
+    let mode = t.trace_writer_mut().write_mapped_region(
+        t,
+        &km,
+        &st,
+        &[],
+        Some(MappingOrigin::SyscallMapping),
+        Some(!monitor_this_fd),
+    );
+    if mode == RecordInTrace::RecordInTrace {
+        if st.st_size > 0 {
+            let end = max(st.st_size as i64 - km.file_offset_bytes() as i64, 0) as usize;
+            t.record_remote(addr, min(end, size));
+        } else {
+            // st_size is not valid. Some device files are mmappable but have zero
+            // size. We also take this path if there's no file at all (vdso etc).
+            t.record_remote(addr, size);
+        }
+    }
+
+    if prot.contains(ProtFlags::PROT_WRITE)
+        && flags.contains(MapFlags::MAP_SHARED)
+        && !effectively_anonymous
+    {
+        log!(
+            LogDebug,
+            "{:?} is SHARED|WRITEABLE; that's not handled correctly yet. \
+             Optimistically hoping it's not written by programs outside the rd tracee tree.",
+            file_name
+        );
+    }
+
+    if !flags.contains(MapFlags::MAP_SHARED) {
+        let vm = t.vm_shr_ptr();
+        vm.monkeypatcher_mut().unwrap().patch_after_mmap(
+            t,
+            addr,
+            size,
+            offset_pages,
+            fd,
+            MmapMode::MmapSyscall,
+        );
+    }
+
+    if (prot & (ProtFlags::PROT_READ | ProtFlags::PROT_WRITE)) == ProtFlags::PROT_READ
+        && flags.contains(MapFlags::MAP_SHARED)
+        && !effectively_anonymous
+    {
+        let m = t.vm().mapping_of(addr).unwrap().clone();
+        MonitoredSharedMemory::maybe_monitor(t, &file_name, &m, fd, offset);
+    }
+}
+
+fn install_patched_seccomp_filter(t: &mut RecordTask) {
+    let session = t.session();
+    session
+        .as_record()
+        .unwrap()
+        .seccomp_filter_rewriter()
+        .install_patched_seccomp_filter(t);
+}
+
+/// If the file mapped shared via `fd` is writable, install a
+/// MmappedFileMonitor on it so that writes through the fd get reflected in
+/// the recorded mapping. Returns true if `fd` is being monitored.
+///
+/// DIFF NOTE: rr also looks for other fds referring to the same file in all
+/// the tracees' fd tables, monitoring them or reporting them as extra fds.
+/// @TODO Do that too.
+fn monitor_fd_for_mapping(t: &mut RecordTask, fd: i32) -> bool {
+    if !is_writable(t, fd) {
+        return false;
+    }
+    if !t.fd_table().is_monitoring(fd) {
+        let monitor = MmappedFileMonitor::new(t, fd);
+        t.fd_table_shr_ptr()
+            .borrow_mut()
+            .add_monitor(t, fd, Box::new(monitor));
+    }
+    true
+}
+
+fn process_mremap(
+    t: &mut RecordTask,
+    old_addr: RemotePtr<Void>,
+    old_length: usize,
+    new_length: usize,
+) {
+    if t.regs_ref().syscall_failed() {
+        // We purposely mess with the flags arguments to mremap, so it's possible
+        // that it failed due to that.
+        return;
+    }
+
+    let old_size = ceil_page_size(old_length);
+    let new_size = ceil_page_size(new_length);
+    let new_addr = RemotePtr::<Void>::from(t.regs_ref().syscall_result());
+
+    t.vm_shr_ptr()
+        .remap(t, old_addr, old_size, new_addr, new_size);
+    let (m_map, st) = {
+        let m = t.vm().mapping_of(new_addr).unwrap();
+        let st = match m.mapped_file_stat {
+            Some(st) => st,
+            None => m.map.fake_stat(),
+        };
+        (m.map.clone(), st)
+    };
+    let km = m_map.subrange(new_addr, new_addr + min(new_size, old_size));
+
+    // Make sure that the trace records the mapping at the new location, even
+    // if the mapping didn't grow.
+    let r = t.trace_writer_mut().write_mapped_region(
+        t,
+        &km,
+        &st,
+        &[],
+        Some(MappingOrigin::RemapMapping),
+        None,
+    );
+    ed_assert_eq!(t, r, RecordInTrace::DontRecordInTrace);
+    if old_size < new_size {
+        // Write a mapping record for the new region, and record its contents if
+        // necessary.
+        let km = m_map.subrange(new_addr + old_size, new_addr + new_size);
+        let mode = t
+            .trace_writer_mut()
+            .write_mapped_region(t, &km, &st, &[], None, None);
+        if mode == RecordInTrace::RecordInTrace {
+            let end = max(st.st_size as i64 - km.file_offset_bytes() as i64, 0) as usize;
+            t.record_remote(km.start(), min(end, km.size()));
+        }
+    }
+}
+
+fn process_shmat(t: &mut RecordTask, shmid: i32, shm_flags: i32, addr: RemotePtr<Void>) {
+    if t.regs_ref().syscall_failed() {
+        // We purposely mess with the flags arguments to shmat, so it's possible
+        // that it failed due to that.
+        return;
+    }
+
+    let mut ds: x64::shmid64_ds = Default::default();
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_shmctl,
+            shmid,
+            IPC_STAT as i32 | IPC_64 as i32,
+            &mut ds as *mut x64::shmid64_ds,
+        )
+    };
+    ed_assert_eq!(
+        t,
+        ret,
+        0,
+        "shmid should be readable by rd since rd has the same UID as tracees"
+    );
+    let segsz = ds.shm_segsz as usize;
+    let size = ceil_page_size(segsz);
+
+    let prot = shm_flags_to_mmap_prot(shm_flags);
+    let flags = MapFlags::MAP_SHARED;
+
+    // Read the kernel's mapping for the shm segment. We need to know its
+    // device/inode so that fork-based checkpointing etc doesn't break.
+    let kernel_info = AddressSpace::read_kernel_mapping(t, addr);
+    let km = t.vm_shr_ptr().map(
+        t,
+        addr,
+        size,
+        prot,
+        flags,
+        0,
+        kernel_info.fsname(),
+        kernel_info.device(),
+        kernel_info.inode(),
+        None,
+        None,
+        None,
+        None,
+        None,
+    );
+    t.vm().set_shm_size(km.start(), km.size());
+    let disposition =
+        t.trace_writer_mut()
+            .write_mapped_region(t, &km, &km.fake_stat(), &[], None, None);
+    ed_assert_eq!(t, disposition, RecordInTrace::RecordInTrace);
+    t.record_remote(addr, size);
+
+    log!(
+        LogDebug,
+        "Optimistically hoping that SysV segment is not used outside of tracees"
+    );
+}
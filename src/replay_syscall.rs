@@ -919,7 +919,18 @@ fn rep_process_syscall_arch<Arch: Architecture>(
     }
 
     if nsys == Arch::RDCALL_RELOAD_AUXV {
-        unimplemented!();
+        // An rd replaying under us has set up the initial address space of
+        // one of its tasks.
+        let target_tid = trace_regs.arg1() as pid_t;
+        if target_tid == t.rec_tid {
+            t.vm_shr_ptr().save_auxv(t);
+        } else {
+            let target_rc = t.session().find_task_from_rec_tid(target_tid);
+            ed_assert!(t, target_rc.is_some(), "Unknown task {}", target_tid);
+            let target_rc = target_rc.unwrap();
+            let mut target = target_rc.borrow_mut();
+            target.vm_shr_ptr().save_auxv(target.as_mut());
+        }
     }
 }
 
@@ -1,2 +1,862 @@
-/// @TODO
-pub struct RunDirection;
+//! This class manages a set of ReplaySessions corresponding to different points
+//! in the same recording. It provides an API for explicitly managing
+//! checkpoints along this timeline and navigating to specific events.
+//!
+//! Seeking to a point in the trace restores the closest checkpoint before
+//! that point (a clone of the session made earlier, see
+//! `ReplaySession::clone_replay()`) and replays forward from there. If there
+//! is no such checkpoint we replay the trace again from the start.
+//!
+//! DIFF NOTE: rr ranks its checkpoints by cost and thins them out as the
+//! timeline grows. We simply take one every `CHECKPOINT_INTERVAL` events
+//! and drop the oldest when we have more than `MAX_CHECKPOINTS`.
+
+use crate::{
+    log::LogLevel::LogDebug,
+    perf_counters::PerfCounters,
+    registers::Registers,
+    remote_code_ptr::RemoteCodePtr,
+    remote_ptr::{RemotePtr, Void},
+    session::{
+        address_space::{address_space::AddressSpace, BreakpointType, WatchType},
+        replay_session::{
+            self,
+            ReplayResult,
+            ReplaySession,
+            ReplayStatus,
+            ReplayStepKey,
+            StepConstraints,
+        },
+        session_inner::{BreakStatus, RunCommand},
+        task::{Task, TaskSharedPtr},
+        Session,
+        SessionSharedPtr,
+    },
+    taskish_uid::{AddressSpaceUid, TaskUid},
+    ticks::Ticks,
+    trace::trace_frame::FrameTime,
+};
+use std::{ffi::OsString, rc::Rc};
+
+/// Try to take a checkpoint at least this often (in trace events) while
+/// replaying.
+const CHECKPOINT_INTERVAL: FrameTime = 500;
+
+/// The most checkpoints we keep around (not counting the one at the reverse
+/// execution barrier). Each one is a set of forked tracee processes.
+const MAX_CHECKPOINTS: usize = 32;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RunDirection {
+    RunForward,
+    RunBackward,
+}
+
+/// A condition that must hold for a breakpoint or watchpoint hit to be
+/// reported.
+pub trait BreakpointCondition {
+    fn evaluate(&self, t: &mut dyn Task) -> bool;
+}
+
+/// The position of a `Mark` within the trace. Marks with different keys are
+/// totally ordered; marks with the same key are distinguished by their
+/// registers.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub struct MarkKey {
+    pub trace_time: FrameTime,
+    pub ticks: Ticks,
+    pub step_key: ReplayStepKey,
+}
+
+/// A Mark is an opaque reference to a specific point in the replay: the
+/// state of the current task of the ReplaySession at that point.
+#[derive(Clone)]
+pub struct Mark {
+    key: MarkKey,
+    regs: Registers,
+    tuid: TaskUid,
+}
+
+impl Mark {
+    pub fn time(&self) -> FrameTime {
+        self.key.trace_time
+    }
+
+    pub fn ticks(&self) -> Ticks {
+        self.key.ticks
+    }
+
+    pub fn regs(&self) -> &Registers {
+        &self.regs
+    }
+
+    /// The task that was current when this mark was created.
+    pub fn tuid(&self) -> TaskUid {
+        self.tuid
+    }
+}
+
+impl PartialEq for Mark {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key && self.tuid == other.tuid && self.regs.matches(&other.regs)
+    }
+}
+
+struct Breakpoint {
+    auid: AddressSpaceUid,
+    addr: RemoteCodePtr,
+    condition: Option<Box<dyn BreakpointCondition>>,
+    applied: bool,
+}
+
+struct Watchpoint {
+    auid: AddressSpaceUid,
+    addr: RemotePtr<Void>,
+    num_bytes: usize,
+    type_: WatchType,
+    condition: Option<Box<dyn BreakpointCondition>>,
+    applied: bool,
+}
+
+pub struct ReplayTimeline {
+    current: SessionSharedPtr,
+    /// Where the trace we're replaying lives and the flags its sessions were
+    /// created with, so we can start the replay over.
+    trace_dir: OsString,
+    flags: replay_session::Flags,
+    /// Reverse execution never goes back before this point.
+    reverse_execution_barrier: Option<Mark>,
+    /// User breakpoints and watchpoints. These are recorded here so they can
+    /// be applied to (and removed from) every session we replay with.
+    breakpoints: Vec<Breakpoint>,
+    watchpoints: Vec<Watchpoint>,
+    /// Pristine clones of the session at various points in the trace, in
+    /// increasing order of their marks. These are never replayed directly;
+    /// we replay clones of them.
+    checkpoints: Vec<(Mark, SessionSharedPtr)>,
+}
+
+impl ReplayTimeline {
+    pub fn new(session: SessionSharedPtr) -> ReplayTimeline {
+        let (trace_dir, flags) = {
+            let replay = session.as_replay().unwrap();
+            (replay.trace_reader().dir(), *replay.flags())
+        };
+        ReplayTimeline {
+            current: session,
+            trace_dir,
+            flags,
+            reverse_execution_barrier: None,
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            checkpoints: Vec::new(),
+        }
+    }
+
+    pub fn current_session(&self) -> &ReplaySession {
+        self.current.as_replay().unwrap()
+    }
+
+    pub fn current_session_shr_ptr(&self) -> SessionSharedPtr {
+        self.current.clone()
+    }
+
+    /// Return a mark for the current state. The current session must have a
+    /// current task.
+    pub fn mark(&self) -> Mark {
+        match self.proto_mark() {
+            Some(m) => m,
+            None => fatal!("Can't create a mark without a current task"),
+        }
+    }
+
+    pub fn reverse_execution_barrier(&self) -> Option<&Mark> {
+        self.reverse_execution_barrier.as_ref()
+    }
+
+    /// Reverse execution will not go back before `mark`. When it would, it
+    /// stops at `mark` and reports the start of execution.
+    pub fn set_reverse_execution_barrier(&mut self, mark: Mark) {
+        let at_mark = self.is_at_mark(&mark);
+        self.reverse_execution_barrier = Some(mark);
+        if at_mark {
+            self.add_checkpoint();
+        }
+    }
+
+    /// Restore the current session to the state of `mark`.
+    pub fn seek_to_mark(&mut self, mark: &Mark) {
+        if self.is_at_mark(mark) {
+            return;
+        }
+        let maybe_current_key = self
+            .proto_mark()
+            .filter(|current| current.key < mark.key)
+            .map(|current| current.key);
+        let maybe_checkpoint = self
+            .checkpoints
+            .iter()
+            .rev()
+            .find(|(m, _)| m.key < mark.key || m == mark)
+            .map(|(m, session)| (m.key, session.clone()));
+        match (maybe_current_key, maybe_checkpoint) {
+            // We're already on our way to `mark` and closer to it than any
+            // checkpoint, so just keep going.
+            (Some(current_key), Some((checkpoint_key, _))) if current_key >= checkpoint_key => (),
+            (Some(_), None) => (),
+            (_, Some((_, session))) => self.restore_checkpoint(&session),
+            (None, None) => self.restart_from_start_of_trace(),
+        }
+        self.unapply_breakpoints_and_watchpoints();
+        while !self.is_at_mark(mark) {
+            let result = self.replay_step_to_mark(mark);
+            if result.status == ReplayStatus::ReplayExited {
+                fatal!("Reached end of trace while seeking to mark");
+            }
+            self.assert_not_past(mark);
+            self.maybe_add_checkpoint();
+        }
+    }
+
+    /// Restore the current session to the state just before `time` gets
+    /// replayed.
+    pub fn seek_to_before_event(&mut self, time: FrameTime) {
+        let current_time = self.current_session().current_frame_time();
+        let current_usable =
+            current_time <= time && !self.current_session().current_step_key().in_execution();
+        // Checkpoints are never taken in the middle of an event.
+        let maybe_checkpoint = self
+            .checkpoints
+            .iter()
+            .rev()
+            .find(|(m, _)| m.time() <= time)
+            .map(|(m, session)| (m.time(), session.clone()));
+        match maybe_checkpoint {
+            Some((checkpoint_time, _)) if current_usable && current_time >= checkpoint_time => (),
+            Some((_, session)) => self.restore_checkpoint(&session),
+            None if current_usable => (),
+            None => self.restart_from_start_of_trace(),
+        }
+        self.unapply_breakpoints_and_watchpoints();
+        while self.current_session().current_frame_time() < time {
+            let result = self.current_session().replay_step(RunCommand::RunContinue);
+            if result.status == ReplayStatus::ReplayExited {
+                break;
+            }
+            self.maybe_add_checkpoint();
+        }
+    }
+
+    /// Return a clone of the current session that can later be passed to
+    /// `restore_checkpoint()`, or None if the current session can't be
+    /// cloned right now.
+    pub fn checkpoint_current_session(&mut self) -> Option<SessionSharedPtr> {
+        if !self.current_session().can_clone() {
+            return None;
+        }
+        // Our breakpoints would be baked into the clone otherwise.
+        self.unapply_breakpoints_and_watchpoints();
+        Some(self.current_session().clone_replay())
+    }
+
+    /// Fork a diversion off the current session. Our breakpoints and
+    /// watchpoints are not carried over; gdb inserts the ones it wants into
+    /// the diversion itself.
+    pub fn clone_diversion(&mut self) -> SessionSharedPtr {
+        self.unapply_breakpoints_and_watchpoints();
+        self.current_session().clone_diversion()
+    }
+
+    /// Make a clone of `checkpoint` the current session. `checkpoint` itself
+    /// is left untouched so it can be restored again later.
+    pub fn restore_checkpoint(&mut self, checkpoint: &SessionSharedPtr) {
+        log!(
+            LogDebug,
+            "Restoring checkpoint at event {}",
+            checkpoint.as_replay().unwrap().current_frame_time()
+        );
+        self.unapply_breakpoints_and_watchpoints();
+        self.current = checkpoint.as_replay().unwrap().clone_replay();
+    }
+
+    /// Step the current session forward with all breakpoints and watchpoints
+    /// applied. Breakpoint and watchpoint hits whose conditions aren't
+    /// satisfied are not reported.
+    pub fn replay_step_forward(
+        &mut self,
+        command: RunCommand,
+        stop_at_time: FrameTime,
+    ) -> ReplayResult {
+        debug_assert!(command != RunCommand::RunSinglestepFastForward);
+        self.apply_breakpoints_and_watchpoints();
+        // Watchpoints that didn't fit in the debug registers are only checked
+        // after singlesteps, so continue one instruction at a time.
+        let step_command =
+            if command == RunCommand::RunContinue && self.current_vm_has_software_watchpoints() {
+                RunCommand::RunSinglestep
+            } else {
+                command
+            };
+        let mut constraints = StepConstraints::new(step_command);
+        constraints.stop_at_time = stop_at_time;
+        let mut result = self
+            .current_session()
+            .replay_step_with_constraints(constraints);
+        if command == RunCommand::RunContinue {
+            // Hide any singlestepping we did
+            result.break_status.singlestep_complete = false;
+        }
+        if !result.break_status.any_break() {
+            self.maybe_add_checkpoint();
+        }
+
+        let did_hit_breakpoint = result.break_status.hardware_or_software_breakpoint_hit();
+        self.evaluate_conditions(&mut result);
+        if did_hit_breakpoint && !result.break_status.any_break() {
+            // Singlestep past the breakpoint
+            result = self.singlestep_with_breakpoints_disabled();
+            if command == RunCommand::RunContinue {
+                result.break_status.singlestep_complete = false;
+            }
+        }
+        result
+    }
+
+    /// Execute backwards from the current point until we hit a breakpoint,
+    /// watchpoint or signal for which `stop_filter` returns true, or reach the
+    /// reverse execution barrier.
+    ///
+    /// `interrupt_check` is called periodically; if it returns true we stop
+    /// where we started and return a result with no breaks.
+    pub fn reverse_continue(
+        &mut self,
+        stop_filter: &dyn Fn(&TaskSharedPtr, &BreakStatus) -> bool,
+        interrupt_check: &mut dyn FnMut() -> bool,
+    ) -> ReplayResult {
+        let end = self.mark();
+        let start = self.barrier();
+        log!(
+            LogDebug,
+            "ReplayTimeline::reverse_continue from {}",
+            end.time()
+        );
+        if start == end {
+            return self.reached_start();
+        }
+
+        // Replay from the barrier up to `end`, remembering the last stop.
+        self.seek_to_mark(&start);
+        let mut last_stop: Option<(Mark, BreakStatus)> = None;
+        while !self.is_at_mark(&end) {
+            if interrupt_check() {
+                log!(LogDebug, "Interrupted during reverse_continue");
+                self.seek_to_mark(&end);
+                return ReplayResult::new(ReplayStatus::ReplayContinue);
+            }
+            self.apply_breakpoints_and_watchpoints();
+            let mut result = if self.current_vm_has_software_watchpoints() {
+                // Singlestepping can't skip past `end`, and it's the only way
+                // software watchpoints get checked.
+                self.current_session()
+                    .replay_step(RunCommand::RunSinglestep)
+            } else {
+                self.replay_step_to_mark(&end)
+            };
+            if result.status == ReplayStatus::ReplayExited {
+                fatal!("Reached end of trace during reverse_continue");
+            }
+            self.assert_not_past(&end);
+            // A stop exactly at `end` is where we started, so it doesn't count.
+            if self.is_at_mark(&end) {
+                break;
+            }
+            self.evaluate_conditions(&mut result);
+            self.maybe_add_checkpoint();
+            let bs = &result.break_status;
+            if bs.hardware_or_software_breakpoint_hit()
+                || !bs.data_watchpoints_hit().is_empty()
+                || bs.signal.is_some()
+            {
+                if let Some(t) = bs.task.as_ref().and_then(|w| w.upgrade()) {
+                    if stop_filter(&t, bs) {
+                        last_stop = Some((self.mark(), result.break_status.clone()));
+                    }
+                }
+            }
+        }
+
+        let (stop, break_status) = match last_stop {
+            Some(stop) => stop,
+            None => {
+                self.seek_to_mark(&start);
+                return self.reached_start();
+            }
+        };
+        self.seek_to_mark(&stop);
+        let mut result = ReplayResult::new(ReplayStatus::ReplayContinue);
+        if !break_status.data_watchpoints_hit().is_empty() || break_status.signal.is_some() {
+            // Watchpoints and signals are reported after the instruction that
+            // triggered them. Going backwards, the debugger expects us to stop
+            // before that instruction.
+            log!(
+                LogDebug,
+                "Performing final reverse-singlestep to pass over watch/signal"
+            );
+            let tuid = stop.tuid();
+            self.reverse_singlestep(tuid, interrupt_check);
+        }
+        result.break_status = break_status;
+        result.break_status.singlestep_complete = false;
+        result
+    }
+
+    /// Execute `tuid` backwards by one instruction (or replay step, where
+    /// that's not the same thing).
+    pub fn reverse_singlestep(
+        &mut self,
+        tuid: TaskUid,
+        interrupt_check: &mut dyn FnMut() -> bool,
+    ) -> ReplayResult {
+        let end = self.mark();
+        let start = self.barrier();
+        log!(
+            LogDebug,
+            "ReplayTimeline::reverse_singlestep from {}",
+            end.time()
+        );
+        if start == end {
+            return self.reached_start();
+        }
+
+        // Replay from the barrier up to `end`, remembering the last state of
+        // `tuid` before `end`.
+        self.seek_to_mark(&start);
+        let mut previous: Option<Mark> = None;
+        while !self.is_at_mark(&end) {
+            if interrupt_check() {
+                log!(LogDebug, "Interrupted during reverse_singlestep");
+                self.seek_to_mark(&end);
+                return ReplayResult::new(ReplayStatus::ReplayContinue);
+            }
+            if let Some(m) = self.proto_mark() {
+                if m.tuid == tuid && m.key < end.key {
+                    previous = Some(m);
+                }
+            }
+            let result = self.replay_step_to_mark(&end);
+            if result.status == ReplayStatus::ReplayExited {
+                fatal!("Reached end of trace during reverse_singlestep");
+            }
+            self.assert_not_past(&end);
+            self.maybe_add_checkpoint();
+        }
+
+        let previous = match previous {
+            Some(previous) => previous,
+            None => {
+                self.seek_to_mark(&start);
+                return self.reached_start();
+            }
+        };
+        self.seek_to_mark(&previous);
+        let mut result = ReplayResult::new(ReplayStatus::ReplayContinue);
+        result.break_status.task = self
+            .current_session()
+            .find_task_from_task_uid(tuid)
+            .map(|t| t.borrow().weak_self_ptr());
+        result.break_status.singlestep_complete = true;
+        result
+    }
+
+    pub fn add_breakpoint(
+        &mut self,
+        t: &mut dyn Task,
+        addr: RemoteCodePtr,
+        condition: Option<Box<dyn BreakpointCondition>>,
+    ) -> bool {
+        let auid = t.vm().uid();
+        // Add it to the address space right away; we need to actually try
+        // adding this breakpoint to see if it works.
+        if !t
+            .vm_shr_ptr()
+            .add_breakpoint(t, addr, BreakpointType::BkptUser)
+        {
+            return false;
+        }
+        self.breakpoints.push(Breakpoint {
+            auid,
+            addr,
+            condition,
+            applied: true,
+        });
+        true
+    }
+
+    pub fn remove_breakpoint(&mut self, t: &mut dyn Task, addr: RemoteCodePtr) {
+        let auid = t.vm().uid();
+        match self
+            .breakpoints
+            .iter()
+            .position(|bp| bp.auid == auid && bp.addr == addr)
+        {
+            Some(i) => {
+                let bp = self.breakpoints.remove(i);
+                if bp.applied {
+                    t.vm_shr_ptr()
+                        .remove_breakpoint(addr, BreakpointType::BkptUser, t);
+                }
+            }
+            None => log!(LogDebug, "No breakpoint at {} to remove", addr),
+        }
+    }
+
+    pub fn add_watchpoint(
+        &mut self,
+        t: &mut dyn Task,
+        addr: RemotePtr<Void>,
+        num_bytes: usize,
+        type_: WatchType,
+        condition: Option<Box<dyn BreakpointCondition>>,
+    ) -> bool {
+        let auid = t.vm().uid();
+        // Add it to the address space right away; we need to actually try
+        // adding this watchpoint to see if it works.
+        if !t.vm_shr_ptr().add_watchpoint(addr, num_bytes, type_, t) {
+            return false;
+        }
+        self.watchpoints.push(Watchpoint {
+            auid,
+            addr,
+            num_bytes,
+            type_,
+            condition,
+            applied: true,
+        });
+        true
+    }
+
+    pub fn remove_watchpoint(
+        &mut self,
+        t: &mut dyn Task,
+        addr: RemotePtr<Void>,
+        num_bytes: usize,
+        type_: WatchType,
+    ) {
+        let auid = t.vm().uid();
+        match self.watchpoints.iter().position(|wp| {
+            wp.auid == auid && wp.addr == addr && wp.num_bytes == num_bytes && wp.type_ == type_
+        }) {
+            Some(i) => {
+                let wp = self.watchpoints.remove(i);
+                if wp.applied {
+                    t.vm_shr_ptr().remove_watchpoint(addr, num_bytes, type_, t);
+                }
+            }
+            None => log!(LogDebug, "No watchpoint at {} to remove", addr),
+        }
+    }
+
+    pub fn remove_breakpoints_and_watchpoints(&mut self) {
+        self.unapply_breakpoints_and_watchpoints();
+        self.breakpoints.clear();
+        self.watchpoints.clear();
+    }
+
+    pub fn has_breakpoint_at_address(&self, t: &dyn Task, addr: RemoteCodePtr) -> bool {
+        let auid = t.vm().uid();
+        self.breakpoints
+            .iter()
+            .any(|bp| bp.auid == auid && bp.addr == addr)
+    }
+
+    fn barrier(&mut self) -> Mark {
+        match self.reverse_execution_barrier.clone() {
+            Some(mark) => mark,
+            None => {
+                // Nobody told us where to stop, so the barrier is the first
+                // point where the initial exec has completed.
+                self.restart_from_start_of_trace();
+                loop {
+                    let replay = self.current_session();
+                    if replay.done_initial_exec()
+                        && replay.current_task().is_some()
+                        && !replay.current_step_key().in_execution()
+                    {
+                        break;
+                    }
+                    if replay.replay_step(RunCommand::RunContinue).status
+                        == ReplayStatus::ReplayExited
+                    {
+                        fatal!("Trace has no tasks to debug");
+                    }
+                }
+                let mark = self.mark();
+                self.reverse_execution_barrier = Some(mark.clone());
+                self.add_checkpoint();
+                mark
+            }
+        }
+    }
+
+    fn reached_start(&self) -> ReplayResult {
+        log!(LogDebug, "Reached start of reverse-executable timeline");
+        let mut result = ReplayResult::new(ReplayStatus::ReplayContinue);
+        result.break_status.task = self
+            .current_session()
+            .current_task()
+            .map(|t| t.borrow().weak_self_ptr());
+        result.break_status.task_exit = true;
+        result
+    }
+
+    fn proto_mark(&self) -> Option<Mark> {
+        let replay = self.current_session();
+        let t = replay.current_task()?;
+        let tb = t.borrow();
+        Some(Mark {
+            key: MarkKey {
+                trace_time: replay.current_frame_time(),
+                ticks: tb.tick_count(),
+                step_key: replay.current_step_key(),
+            },
+            regs: tb.regs_ref().clone(),
+            tuid: tb.tuid(),
+        })
+    }
+
+    fn is_at_mark(&self, mark: &Mark) -> bool {
+        match self.proto_mark() {
+            Some(current) => current == *mark,
+            None => false,
+        }
+    }
+
+    fn assert_not_past(&self, mark: &Mark) {
+        if let Some(current) = self.proto_mark() {
+            if current.key > mark.key {
+                fatal!(
+                    "Replay went past mark at event {} ticks {} (now at event {} ticks {})",
+                    mark.time(),
+                    mark.ticks(),
+                    current.time(),
+                    current.ticks()
+                );
+            }
+        }
+    }
+
+    /// Take a checkpoint if we can and there isn't one nearby already.
+    fn maybe_add_checkpoint(&mut self) {
+        let replay = self.current_session();
+        if replay.current_step_key().in_execution() {
+            return;
+        }
+        let time = replay.current_frame_time();
+        let have_nearby = self.checkpoints.iter().any(|(m, _)| {
+            let (lo, hi) = if m.time() < time {
+                (m.time(), time)
+            } else {
+                (time, m.time())
+            };
+            hi - lo < CHECKPOINT_INTERVAL
+        });
+        if !have_nearby {
+            self.add_checkpoint();
+        }
+    }
+
+    /// Add a checkpoint of the current session at the current mark, if the
+    /// session can be cloned here.
+    fn add_checkpoint(&mut self) {
+        let mark = match self.proto_mark() {
+            Some(mark) => mark,
+            None => return,
+        };
+        if self.checkpoints.iter().any(|(m, _)| *m == mark) {
+            return;
+        }
+        let session = match self.checkpoint_current_session() {
+            Some(session) => session,
+            None => return,
+        };
+        log!(LogDebug, "Adding checkpoint at event {}", mark.time());
+        let pos = self
+            .checkpoints
+            .iter()
+            .position(|(m, _)| m.key > mark.key)
+            .unwrap_or(self.checkpoints.len());
+        self.checkpoints.insert(pos, (mark, session));
+
+        if self.checkpoints.len() > MAX_CHECKPOINTS + 1 {
+            // Drop the oldest checkpoint, but never the one at the barrier since
+            // reverse execution always starts its scans there.
+            let barrier = self.reverse_execution_barrier.as_ref();
+            match self
+                .checkpoints
+                .iter()
+                .position(|(m, _)| Some(m) != barrier)
+            {
+                Some(i) => {
+                    self.checkpoints.remove(i);
+                }
+                None => (),
+            }
+        }
+    }
+
+    /// Throw away the current session and start replaying the trace again
+    /// from the beginning.
+    fn restart_from_start_of_trace(&mut self) {
+        log!(LogDebug, "Restarting replay from start of trace");
+        self.unapply_breakpoints_and_watchpoints();
+        self.current = ReplaySession::create(Some(&self.trace_dir), self.flags);
+    }
+
+    /// Take one step towards `mark`, trying not to step past it. Near the mark
+    /// we singlestep so we stop exactly at it.
+    fn replay_step_to_mark(&self, mark: &Mark) -> ReplayResult {
+        let replay = self.current_session();
+        let time = replay.current_frame_time();
+        let maybe_t = replay.current_task();
+        let approaching = match maybe_t.as_ref() {
+            Some(t) => {
+                t.borrow().tuid() == mark.tuid
+                    && (time == mark.time()
+                        || (time + 1 == mark.time() && !mark.key.step_key.in_execution()))
+            }
+            None => false,
+        };
+        if !approaching {
+            // Each RunContinue can only advance by at most one trace event, so
+            // this can't go past the mark.
+            return replay.replay_step(RunCommand::RunContinue);
+        }
+
+        let ticks = maybe_t.unwrap().borrow().tick_count();
+        if mark.ticks() > ticks + PerfCounters::skid_size() + 1 {
+            // Try to make progress by just continuing with a ticks constraint
+            // set to stop us before the mark.
+            let mut constraints = StepConstraints::new(RunCommand::RunContinue);
+            constraints.ticks_target = mark.ticks() - 1;
+            replay.replay_step_with_constraints(constraints)
+        } else {
+            replay.replay_step(RunCommand::RunSinglestep)
+        }
+    }
+
+    fn current_vm_has_software_watchpoints(&self) -> bool {
+        self.current_session()
+            .current_task()
+            .map_or(false, |t| t.borrow().vm().has_software_watchpoints())
+    }
+
+    fn singlestep_with_breakpoints_disabled(&mut self) -> ReplayResult {
+        self.unapply_breakpoints_and_watchpoints();
+        let result = self
+            .current_session()
+            .replay_step(RunCommand::RunSinglestep);
+        self.apply_breakpoints_and_watchpoints();
+        result
+    }
+
+    /// Clear breakpoint and watchpoint hits in `result` whose conditions
+    /// aren't satisfied.
+    fn evaluate_conditions(&self, result: &mut ReplayResult) {
+        let t = match result.break_status.task.as_ref().and_then(|w| w.upgrade()) {
+            Some(t) => t,
+            None => return,
+        };
+        let (auid, ip) = {
+            let tb = t.borrow();
+            (tb.vm().uid(), tb.ip())
+        };
+
+        if result.break_status.breakpoint_hit {
+            let hit = self
+                .breakpoints
+                .iter()
+                .filter(|bp| bp.auid == auid && bp.addr == ip)
+                .any(|bp| condition_satisfied(&bp.condition, &t));
+            if !hit {
+                result.break_status.breakpoint_hit = false;
+            }
+        }
+
+        let watchpoints = &self.watchpoints;
+        result.break_status.watchpoints_hit.retain(|w| {
+            watchpoints
+                .iter()
+                .filter(|wp| {
+                    wp.auid == auid
+                        && wp.addr == w.addr
+                        && wp.num_bytes == w.num_bytes
+                        && wp.type_ == w.type_
+                })
+                .any(|wp| condition_satisfied(&wp.condition, &t))
+        });
+    }
+
+    /// Apply any breakpoints and watchpoints that aren't applied to the current
+    /// session yet. Breakpoints for address spaces that don't exist (yet) stay
+    /// unapplied; we try again before the next step.
+    fn apply_breakpoints_and_watchpoints(&mut self) {
+        let needs_work = self.breakpoints.iter().any(|bp| !bp.applied)
+            || self.watchpoints.iter().any(|wp| !wp.applied);
+        if !needs_work {
+            return;
+        }
+        let vms = self.current.vms();
+        for bp in self.breakpoints.iter_mut().filter(|bp| !bp.applied) {
+            if let Some((vm, t)) = find_vm_and_task(&vms, bp.auid) {
+                bp.applied =
+                    vm.add_breakpoint(t.borrow_mut().as_mut(), bp.addr, BreakpointType::BkptUser);
+            }
+        }
+        for wp in self.watchpoints.iter_mut().filter(|wp| !wp.applied) {
+            if let Some((vm, t)) = find_vm_and_task(&vms, wp.auid) {
+                wp.applied =
+                    vm.add_watchpoint(wp.addr, wp.num_bytes, wp.type_, t.borrow_mut().as_mut());
+            }
+        }
+    }
+
+    fn unapply_breakpoints_and_watchpoints(&mut self) {
+        let needs_work = self.breakpoints.iter().any(|bp| bp.applied)
+            || self.watchpoints.iter().any(|wp| wp.applied);
+        if !needs_work {
+            return;
+        }
+        let vms = self.current.vms();
+        for bp in self.breakpoints.iter_mut().filter(|bp| bp.applied) {
+            if let Some((vm, t)) = find_vm_and_task(&vms, bp.auid) {
+                vm.remove_breakpoint(bp.addr, BreakpointType::BkptUser, t.borrow_mut().as_mut());
+            }
+            bp.applied = false;
+        }
+        for wp in self.watchpoints.iter_mut().filter(|wp| wp.applied) {
+            if let Some((vm, t)) = find_vm_and_task(&vms, wp.auid) {
+                vm.remove_watchpoint(wp.addr, wp.num_bytes, wp.type_, t.borrow_mut().as_mut());
+            }
+            wp.applied = false;
+        }
+    }
+}
+
+fn condition_satisfied(
+    condition: &Option<Box<dyn BreakpointCondition>>,
+    t: &TaskSharedPtr,
+) -> bool {
+    match condition {
+        None => true,
+        Some(c) => c.evaluate(t.borrow_mut().as_mut()),
+    }
+}
+
+fn find_vm_and_task(
+    vms: &[Rc<AddressSpace>],
+    auid: AddressSpaceUid,
+) -> Option<(Rc<AddressSpace>, TaskSharedPtr)> {
+    let vm = vms.iter().find(|vm| vm.uid() == auid)?;
+    let t = vm.any_task_from_task_set()?;
+    Some((vm.clone(), t))
+}
@@ -35,35 +35,88 @@
 //! The main parameter to the scheduler is `max_ticks`, which controls the
 //! length of each timeslice.
 use crate::{
-    event::Switchable,
-    log::LogWarn,
+    bindings::{
+        kernel::{itimerval, setitimer, ITIMER_REAL},
+        ptrace::PTRACE_EVENT_EXIT,
+    },
+    event::{EventType, Switchable, SyscallState},
+    kernel_abi::{is_exit_group_syscall, is_sched_yield_syscall, SupportedArch},
+    log::{LogDebug, LogWarn},
     session::{
-        record_session::RecordSession,
-        task::{record_task::RecordTask, TaskSharedPtr, TaskSharedWeakPtr},
+        task::{
+            record_task::{EmulatedStopType, RecordTask},
+            task_inner::{ResumeRequest, TicksRequest, WaitRequest},
+            Task,
+            TaskSharedPtr,
+            TaskSharedWeakPtr,
+        },
         SessionSharedPtr,
         SessionSharedWeakPtr,
     },
+    sig,
+    taskish_uid::TaskUid,
     ticks::Ticks,
+    util::monotonic_now_sec,
+    wait_status::WaitStatus,
 };
-use libc::{sysconf, _SC_NPROCESSORS_CONF};
+use libc::{sysconf, waitpid, _SC_NPROCESSORS_CONF, __WALL, EINTR};
 use nix::{
+    errno::errno,
     sched::{sched_getaffinity, CpuSet},
     unistd::Pid,
 };
-use rand::{seq::SliceRandom, thread_rng};
+use rand::{rngs::StdRng, seq::SliceRandom, thread_rng, Rng, SeedableRng};
 use std::{
+    cell::{Cell, RefCell, RefMut},
+    cmp::max,
     collections::{BTreeMap, VecDeque},
     mem,
-    rc::Weak,
+    ptr,
+    rc::{Rc, Weak},
+    thread::sleep,
+    time::Duration,
 };
 
 // Tasks sorted by priority.
-type TaskPrioritySet = BTreeMap<i32, TaskSharedWeakPtr>;
+///
+/// DIFF NOTE: rr uses a std::set of (priority, RecordTask*) pairs. Here every
+/// priority maps to the tasks with that priority in round-robin order.
+type TaskPrioritySet = BTreeMap<i32, Vec<TaskSharedWeakPtr>>;
 type TaskQueue = VecDeque<TaskSharedWeakPtr>;
 
+/// Chaos mode: Every so often we rerandomize the priorities of all tasks.
+/// This is the maximum time in seconds between rerandomizations.
+const PRIORITIES_REFRESH_MAX_INTERVAL: f64 = 20.0;
+
+/// Chaos mode: The probability that a task gets low priority.
+const LOW_PRIORITY_PROBABILITY: f64 = 0.1;
+
+/// Chaos mode: High-priority-only intervals.
+///
+/// We assume that for a test failure we want to reproduce, we will reproduce a
+/// failure if we completely avoid scheduling a certain thread for a period of
+/// D seconds, where the start of that period must fall between S and S+T
+/// seconds since the start of the test. All these constants are unknown to
+/// rd, but we assume 1ms <= D <= 2s.
+///
+/// We pick the duration of a high-priority-only interval D' to be 2^k ms for
+/// a random k, so for any D there is a good chance D' is not much smaller.
+/// These intervals are repeated periodically so that only a fixed fraction
+/// of the total run time is spent in them.
+const MIN_HIGH_PRIORITY_ONLY_DURATION: f64 = 0.001;
+const HIGH_PRIORITY_ONLY_DURATION_STEPS: i32 = 12;
+const HIGH_PRIORITY_ONLY_DURATION_STEP_FACTOR: f64 = 2.0;
+/// Allow this much of overall runtime to be in the "high priority only" interval
+const HIGH_PRIORITY_ONLY_FRACTION: f64 = 0.2;
+
 /// DIFF NOTE: In rr we deal with *RecordTasks. Here we are dealing with the
 /// "superclass" Task (see the various TaskSharedWeakPtr-s). This will mean
 /// that we need to do as_record_task() in various locations. An extra step...
+///
+/// DIFF NOTE: Tasks call back into the scheduler while the scheduler is
+/// waiting on them (e.g. `expire_timeslice()` from `did_waitpid()`), so the
+/// state that changes during recording uses interior mutability and those
+/// methods take `&self`.
 pub struct Scheduler {
     /// @TODO Is this what we want?
     session: SessionSharedWeakPtr,
@@ -74,25 +127,25 @@ pub struct Scheduler {
     /// task_priority_set is a set of pairs of (task->priority, task). This
     /// lets us efficiently iterate over the tasks with a given priority, or
     /// all tasks in priority order.
-    task_priority_set: TaskPrioritySet,
-    task_round_robin_queue: TaskQueue,
+    task_priority_set: RefCell<TaskPrioritySet>,
+    task_round_robin_queue: RefCell<TaskQueue>,
 
     /// The currently scheduled task. This may be `None` if the last scheduled
     /// task has been destroyed.
-    current_: Option<TaskSharedWeakPtr>,
-    current_timeslice_end_: Ticks,
+    current_: RefCell<Option<TaskSharedWeakPtr>>,
+    current_timeslice_end_: Cell<Ticks>,
 
     /// At this time (or later) we should refresh these values.
-    high_priority_only_intervals_refresh_time: f64,
-    high_priority_only_intervals_start: f64,
-    high_priority_only_intervals_duration: f64,
-    high_priority_only_intervals_period: f64,
+    high_priority_only_intervals_refresh_time: Cell<f64>,
+    high_priority_only_intervals_start: Cell<f64>,
+    high_priority_only_intervals_duration: Cell<f64>,
+    high_priority_only_intervals_period: Cell<f64>,
     /// At this time (or later) we should rerandomize RecordTask priorities.
-    priorities_refresh_time: f64,
+    priorities_refresh_time: Cell<f64>,
 
     max_ticks_: Ticks,
 
-    must_run_task: Option<TaskSharedWeakPtr>,
+    must_run_task: RefCell<Option<TaskSharedWeakPtr>>,
 
     pretend_affinity_mask_: CpuSet,
 
@@ -104,9 +157,20 @@ pub struct Scheduler {
     /// When true, make random scheduling decisions to try to increase the
     /// probability of finding buggy schedules.
     enable_chaos: bool,
+    /// DIFF NOTE: rr uses random(3) for its scheduling decisions. We use our own
+    /// generator so that the seed can be stored in the trace, which helps
+    /// explain what happened in a chaos mode recording later on.
+    ///
+    /// Note that the seed alone does not reproduce a chaos mode schedule: when
+    /// priorities are rerandomized and where the high-priority-only intervals
+    /// fall are measured against `monotonic_now_sec()`, i.e. wall clock time,
+    /// just like in rr. The same seed gives the same sequence of random
+    /// choices, but which events consume them depends on timing.
+    rng_seed: u64,
+    rng: RefCell<StdRng>,
 
-    enable_poll: bool,
-    last_reschedule_in_high_priority_only_interval: bool,
+    enable_poll: Cell<bool>,
+    last_reschedule_in_high_priority_only_interval: Cell<bool>,
 }
 
 #[repr(u64)]
@@ -141,14 +205,15 @@ pub enum TicksHowMany {
 /// which case, result.by_waitpid will be true.
 #[derive(Copy, Clone, Eq, PartialEq)]
 pub struct Rescheduled {
-    interrupted_by_signal: bool,
-    by_waitpid: bool,
-    started_new_timeslice: bool,
+    pub interrupted_by_signal: bool,
+    pub by_waitpid: bool,
+    pub started_new_timeslice: bool,
 }
 
 impl Scheduler {
     /// DIFF This constructor does NOT call regenerate_affinity_mask() like in rr.
     pub fn new(max_ticks: Ticks, always_switch: bool) -> Scheduler {
+        let rng_seed: u64 = thread_rng().gen();
         Scheduler {
             session: Weak::new(),
             task_priority_set: Default::default(),
@@ -166,6 +231,8 @@ impl Scheduler {
             pretend_num_cores_: 1,
             always_switch,
             enable_chaos: Default::default(),
+            rng_seed,
+            rng: RefCell::new(StdRng::seed_from_u64(rng_seed)),
             enable_poll: Default::default(),
             last_reschedule_in_high_priority_only_interval: Default::default(),
         }
@@ -179,10 +246,6 @@ impl Scheduler {
         self.session.upgrade().unwrap()
     }
 
-    pub fn record_session(&self) -> &RecordSession {
-        unimplemented!()
-    }
-
     pub fn set_max_ticks(&mut self, max_ticks: Ticks) {
         debug_assert!(max_ticks <= TicksHowMany::MaxMaxTicks as u64);
         self.max_ticks_ = max_ticks;
@@ -196,12 +259,33 @@ impl Scheduler {
         self.always_switch = always_switch;
     }
 
-    pub fn set_enable_chaos(&mut self, _enable_chaos: bool) {
-        unimplemented!()
+    pub fn set_enable_chaos(&mut self, enable_chaos: bool) {
+        self.enable_chaos = enable_chaos;
+        // When chaos mode is enabled, pretend to have 1-8 cores at random, otherwise
+        // return 1 to maximize throughput (since effectively we really only have
+        // one core).
+        self.pretend_num_cores_ = if enable_chaos {
+            self.rng.get_mut().gen_range(1, 9)
+        } else {
+            1
+        };
+        self.regenerate_affinity_mask();
+    }
+
+    pub fn set_num_cores(&mut self, num_cores: u32) {
+        self.pretend_num_cores_ = num_cores;
+        self.regenerate_affinity_mask();
+    }
+
+    /// The seed of the random number generator used for scheduling decisions.
+    pub fn rng_seed(&self) -> u64 {
+        self.rng_seed
     }
 
-    pub fn set_num_cores(&mut self, _num_cores: u32) {
-        unimplemented!()
+    /// The random number generator used for scheduling decisions. Also used for
+    /// other chaos mode decisions e.g. where to place mmaps.
+    pub fn rng(&self) -> RefMut<'_, StdRng> {
+        self.rng.borrow_mut()
     }
 
     /// Schedule a new runnable task (which may be the same as current()).
@@ -209,13 +293,273 @@ impl Scheduler {
     /// The new current() task is guaranteed to either have already been
     /// runnable, or have been made runnable by a waitpid status change (in
     /// which case, result.by_waitpid will be true.
-    pub fn reschedule(&mut self, _switchable: Switchable) -> Rescheduled {
-        unimplemented!()
+    pub fn reschedule(&self, switchable: Switchable) -> Rescheduled {
+        let mut result = Rescheduled {
+            interrupted_by_signal: false,
+            by_waitpid: false,
+            started_new_timeslice: false,
+        };
+
+        log!(LogDebug, "Scheduling next task ({:?})", switchable);
+
+        *self.must_run_task.borrow_mut() = None;
+        self.enable_poll.set(false);
+
+        let mut now = monotonic_now_sec();
+
+        let maybe_current = self.current();
+        if switchable == Switchable::PreventSwitch {
+            let rc_current = maybe_current.unwrap();
+            let mut currentb = rc_current.borrow_mut();
+            log!(
+                LogDebug,
+                "  ({} is un-switchable at {})",
+                currentb.tid,
+                currentb.as_record_task().unwrap().ev()
+            );
+            if currentb.is_running() {
+                log!(LogDebug, "  and running; waiting for state change");
+                // `current` is un-switchable, but already running. Wait for it to change
+                // state before "scheduling it", so avoid busy-waiting with our client.
+                currentb.wait(Some(self.interrupt_after_elapsed_time()));
+                result.by_waitpid = true;
+                log!(LogDebug, "  new status is {}", currentb.status());
+            }
+            drop(currentb);
+            self.validate_scheduled_task();
+            return result;
+        }
+
+        self.maybe_reset_priorities(now);
+
+        let mut maybe_next: Option<TaskSharedPtr>;
+        loop {
+            self.maybe_reset_high_priority_only_intervals(now);
+            self.last_reschedule_in_high_priority_only_interval
+                .set(self.in_high_priority_only_interval(now));
+
+            if let Some(rc_current) = maybe_current.as_ref() {
+                // Determine if we should run current again
+                let maybe_round_robin_task = self.get_round_robin_task();
+                if maybe_round_robin_task.is_none() {
+                    let current_priority = priority_of(rc_current);
+                    maybe_next = self.find_next_runnable_task(
+                        Some(rc_current),
+                        &mut result.by_waitpid,
+                        current_priority - 1,
+                    );
+                    if maybe_next.is_some() {
+                        // There is a runnable higher-priority task. Run it.
+                        break;
+                    }
+                }
+                // To run current again:
+                // -- its timeslice must not have expired
+                // -- it must be high priority if we're in a high-priority-only interval
+                // -- it must be the head of the round-robin queue or the queue is empty
+                // (this might not hold if it was at the head of the queue but we
+                // rejected current and popped it in a previous iteration of this loop)
+                // -- it must be runnable, and not in an unstable exit.
+                if !self.always_switch
+                    && maybe_round_robin_task
+                        .as_ref()
+                        .map_or(true, |rrt| Rc::ptr_eq(rrt, rc_current))
+                    && (self.treat_as_high_priority(rc_current)
+                        || !self.last_reschedule_in_high_priority_only_interval.get())
+                    && rc_current.borrow().tick_count() < self.current_timeslice_end()
+                    && self.is_task_runnable(rc_current, &mut result.by_waitpid)
+                {
+                    log!(
+                        LogDebug,
+                        "  Carrying on with task {}",
+                        rc_current.borrow().tid
+                    );
+                    self.validate_scheduled_task();
+                    return result;
+                }
+                // Having rejected current, be prepared to run the next task in the
+                // round-robin queue.
+                self.maybe_pop_round_robin_task(rc_current);
+            }
+
+            log!(LogDebug, "  need to reschedule");
+
+            maybe_next = self.get_round_robin_task();
+            if let Some(next) = maybe_next.as_ref() {
+                log!(
+                    LogDebug,
+                    "Trying task {} from yield queue",
+                    next.borrow().tid
+                );
+                if self.is_task_runnable(next, &mut result.by_waitpid) {
+                    break;
+                }
+                self.maybe_pop_round_robin_task(next);
+                continue;
+            }
+
+            maybe_next = self.find_next_runnable_task(
+                maybe_current.as_ref(),
+                &mut result.by_waitpid,
+                i32::MAX,
+            );
+
+            // When there's only one thread, treat it as low priority for the
+            // purposes of high-priority-only-intervals. Otherwise single-threaded
+            // workloads mostly don't get any chaos mode effects.
+            if let Some(next) = maybe_next.as_ref() {
+                if !self.treat_as_high_priority(next)
+                    && self.last_reschedule_in_high_priority_only_interval.get()
+                {
+                    if result.by_waitpid {
+                        log!(
+                            LogDebug,
+                            "Waking up low-priority task with by_waitpid; not sleeping"
+                        );
+                        // We must run this low-priority task. Fortunately it's just waking
+                        // up from a blocking syscall; we'll record the syscall event and then
+                        // (unless it was an interrupted syscall) we'll return to
+                        // reschedule(), which will either run a higher-priority thread
+                        // or (more likely) reach here again but in the !by_waitpid case.
+                    } else {
+                        log!(
+                            LogDebug,
+                            "Waking up low-priority task without by_waitpid; sleeping"
+                        );
+                        sleep(Duration::from_millis(1));
+                        now = monotonic_now_sec();
+                        continue;
+                    }
+                }
+            }
+            break;
+        }
+
+        let next = match maybe_next {
+            Some(next) => {
+                log!(LogDebug, "  selecting task {}", next.borrow().tid);
+                next
+            }
+            None => {
+                // All the tasks are blocked (or we found an unstable-exit task).
+                // Wait for the next one to change state.
+
+                // Clear the round-robin queue since we will no longer be able to service
+                // those tasks in-order.
+                while let Some(t) = self.get_round_robin_task() {
+                    self.maybe_pop_round_robin_task(&t);
+                }
+
+                log!(
+                    LogDebug,
+                    "  all tasks blocked or some unstable, waiting for runnable ({} total)",
+                    self.task_priority_set
+                        .borrow()
+                        .values()
+                        .map(|v| v.len())
+                        .sum::<usize>()
+                );
+
+                let mut status: WaitStatus;
+                let next = loop {
+                    let mut raw_status: i32 = 0;
+                    if self.enable_poll.get() {
+                        let mut timer: itimerval = Default::default();
+                        // poll 1ms
+                        timer.it_value.tv_usec = 1000;
+                        if unsafe { setitimer(ITIMER_REAL as u32, &timer, ptr::null_mut()) } < 0 {
+                            fatal!("Failed to set itimer");
+                        }
+                        log!(LogDebug, "  Arming one-second timer for polling");
+                    }
+                    let tid = unsafe { waitpid(-1, &mut raw_status, __WALL) };
+                    if self.enable_poll.get() {
+                        let timer: itimerval = Default::default();
+                        if unsafe { setitimer(ITIMER_REAL as u32, &timer, ptr::null_mut()) } < 0 {
+                            fatal!("Failed to set itimer");
+                        }
+                        log!(LogDebug, "  Disarming one-second timer for polling");
+                    }
+                    status = WaitStatus::new(raw_status);
+                    if -1 == tid {
+                        if EINTR == errno() {
+                            log!(LogDebug, "  waitpid(-1) interrupted");
+                            let maybe_must_run_task = self
+                                .must_run_task
+                                .borrow()
+                                .as_ref()
+                                .and_then(|w| w.upgrade());
+                            if let Some(must_run_task) = maybe_must_run_task {
+                                ed_assert!(
+                                    &must_run_task.borrow(),
+                                    false,
+                                    "Interrupted but must_run_task is set"
+                                );
+                            }
+                            result.interrupted_by_signal = true;
+                            return result;
+                        }
+                        fatal!("Failed to waitpid()");
+                    }
+                    log!(LogDebug, "  {} changed status to {}", tid, status);
+
+                    match self.session_shr_ptr().find_task_from_rec_tid(tid) {
+                        Some(next) => break next,
+                        None => log!(LogDebug, "    ... but it's dead"),
+                    }
+                };
+
+                {
+                    let mut nextb = next.borrow_mut();
+                    ed_assert!(
+                        &nextb,
+                        nextb.unstable.get()
+                            || nextb.as_record_task().unwrap().may_be_blocked()
+                            || status.maybe_ptrace_event() == PTRACE_EVENT_EXIT,
+                        "Scheduled task should have been blocked or unstable"
+                    );
+                    nextb.did_waitpid(status);
+                }
+                result.by_waitpid = true;
+                *self.must_run_task.borrow_mut() = Some(Rc::downgrade(&next));
+                next
+            }
+        };
+
+        if let Some(rc_current) = maybe_current.as_ref() {
+            if !Rc::ptr_eq(rc_current, &next) {
+                let currentb = rc_current.borrow();
+                let nextb = next.borrow();
+                log!(
+                    LogDebug,
+                    "Switching from {}({:?}) to {}({:?}) (priority {} to {}) at {}",
+                    currentb.tid,
+                    currentb.name(),
+                    nextb.tid,
+                    nextb.name(),
+                    currentb.as_record_task().unwrap().priority.get(),
+                    nextb.as_record_task().unwrap().priority.get(),
+                    currentb.trace_writer().time()
+                );
+            }
+        }
+
+        self.maybe_reset_high_priority_only_intervals(now);
+        *self.current_.borrow_mut() = Some(Rc::downgrade(&next));
+        self.validate_scheduled_task();
+        self.setup_new_timeslice();
+        result.started_new_timeslice = true;
+        result
     }
 
     /// Set the priority of `t` to `value` and update related state.
-    pub fn update_task_priority(&mut self, _t: &RecordTask, _value: i32) {
-        unimplemented!()
+    pub fn update_task_priority(&self, t: &RecordTask, value: i32) {
+        if self.enable_chaos {
+            // Ignore requests to change priority in chaos mode
+            return;
+        }
+
+        self.update_task_priority_internal(t, value);
     }
 
     /// Do one round of round-robin scheduling if we're not already doing one.
@@ -223,42 +567,149 @@ impl Scheduler {
     /// task to be scheduled.
     /// If the task_round_robin_queue is empty this moves all tasks into it,
     /// putting last_task last.
-    pub fn schedule_one_round_robin(&mut self, _last_task: &RecordTask) {
-        unimplemented!()
+    pub fn schedule_one_round_robin(&self, last_task: &RecordTask) {
+        log!(
+            LogDebug,
+            "Scheduling round-robin because of task {}",
+            last_task.tid
+        );
+
+        let last_task_weak = last_task.weak_self_ptr();
+        ed_assert!(
+            last_task,
+            self.current_
+                .borrow()
+                .as_ref()
+                .map_or(false, |c| c.ptr_eq(&last_task_weak))
+        );
+        // DIFF NOTE: Can't use maybe_pop_round_robin_task() as the caller has
+        // already borrowed `last_task`.
+        if self
+            .task_round_robin_queue
+            .borrow()
+            .front()
+            .map_or(false, |front| front.ptr_eq(&last_task_weak))
+        {
+            self.task_round_robin_queue.borrow_mut().pop_front();
+            last_task.in_round_robin_queue.set(false);
+            self.task_priority_set
+                .borrow_mut()
+                .entry(last_task.priority.get())
+                .or_default()
+                .push(last_task_weak.clone());
+        }
+        ed_assert!(last_task, !last_task.in_round_robin_queue.get());
+
+        let priority_set = mem::take(&mut *self.task_priority_set.borrow_mut());
+        let mut round_robin_queue = self.task_round_robin_queue.borrow_mut();
+        for w in priority_set.values().flatten() {
+            if w.ptr_eq(&last_task_weak) {
+                continue;
+            }
+            let rc_t = w.upgrade().unwrap();
+            let tb = rc_t.borrow();
+            let t = tb.as_record_task().unwrap();
+            if !t.in_round_robin_queue.get() {
+                round_robin_queue.push_back(w.clone());
+                t.in_round_robin_queue.set(true);
+            }
+        }
+        round_robin_queue.push_back(last_task_weak);
+        last_task.in_round_robin_queue.set(true);
+        drop(round_robin_queue);
+        self.expire_timeslice();
     }
 
-    pub fn on_create_task(&mut self, _t: TaskSharedPtr) {
-        unimplemented!()
+    pub fn on_create_task(&self, t: TaskSharedPtr) {
+        let tb = t.borrow();
+        let rt = tb.as_record_task().unwrap();
+        debug_assert!(!rt.in_round_robin_queue.get());
+        if self.enable_chaos {
+            // new tasks get a random priority
+            rt.priority.set(self.choose_random_priority(rt));
+        }
+        self.task_priority_set
+            .borrow_mut()
+            .entry(rt.priority.get())
+            .or_default()
+            .push(Rc::downgrade(&t));
     }
 
     ///  De-register a thread. This function should be called when a thread exits.
-    pub fn on_destroy_task(&mut self, _t: TaskSharedPtr) {
-        unimplemented!()
+    ///
+    /// DIFF NOTE: Takes a TaskUid because the task is in the middle of being
+    /// dropped when this is called. This also means its weak pointer can no
+    /// longer be upgraded, which is how we find it.
+    pub fn on_destroy_task(&self, tuid: TaskUid) {
+        log!(LogDebug, "Removing task {:?} from the scheduler", tuid);
+        let is_dead = |w: &TaskSharedWeakPtr| w.upgrade().is_none();
+        {
+            let mut current = self.current_.borrow_mut();
+            if current.as_ref().map_or(false, is_dead) {
+                *current = None;
+            }
+        }
+
+        self.task_round_robin_queue
+            .borrow_mut()
+            .retain(|w| !is_dead(w));
+        let mut priority_set = self.task_priority_set.borrow_mut();
+        for tasks in priority_set.values_mut() {
+            tasks.retain(|w| !is_dead(w));
+        }
+        priority_set.retain(|_, tasks| !tasks.is_empty());
     }
 
-    pub fn current(&self) -> &RecordTask {
-        unimplemented!()
+    /// The currently scheduled task, if it still exists.
+    pub fn current(&self) -> Option<TaskSharedPtr> {
+        self.current_.borrow().as_ref().and_then(|w| w.upgrade())
     }
 
-    pub fn set_current(&mut self, _t: &RecordTask) {
-        unimplemented!()
+    pub fn set_current(&self, maybe_t: Option<TaskSharedWeakPtr>) {
+        *self.current_.borrow_mut() = maybe_t;
     }
 
     pub fn current_timeslice_end(&self) -> Ticks {
-        self.current_timeslice_end_
+        self.current_timeslice_end_.get()
     }
 
-    pub fn expire_timeslice(&mut self) {
-        self.current_timeslice_end_ = 0;
+    pub fn expire_timeslice(&self) {
+        self.current_timeslice_end_.set(0);
     }
 
-    pub fn interrupt_after_elapsed_time() -> f64 {
-        unimplemented!()
+    pub fn interrupt_after_elapsed_time(&self) -> f64 {
+        // Where does the 3 seconds come from?  No especially
+        // good reason.  We want this to be pretty high,
+        // because it's a last-ditch recovery mechanism, not a
+        // primary thread scheduler.  Though in theory the
+        // PTRACE_INTERRUPT's shouldn't interfere with other
+        // events, that's hard to test thoroughly so try to
+        // avoid it.
+        let mut delay: f64 = 3.0;
+        if self.enable_chaos {
+            let now = monotonic_now_sec();
+            let start = self.high_priority_only_intervals_start.get();
+            if start != 0.0 {
+                let period = self.high_priority_only_intervals_period.get();
+                let next_interval_start =
+                    ((now - start) / period).floor() * period + period + start;
+                delay = delay.min(next_interval_start - now);
+            }
+            let refresh_time = self.high_priority_only_intervals_refresh_time.get();
+            if refresh_time != 0.0 {
+                delay = delay.min(refresh_time - now);
+            }
+            let priorities_refresh_time = self.priorities_refresh_time.get();
+            if priorities_refresh_time != 0.0 {
+                delay = delay.min(priorities_refresh_time - now);
+            }
+        }
+        delay.max(0.001)
     }
 
     /// Return the number of cores we should report to applications.
     pub fn pretend_num_cores(&self) -> u32 {
-        unimplemented!()
+        self.pretend_num_cores_
     }
 
     /// Return the processor affinity masks we should report to applications.
@@ -266,8 +717,8 @@ impl Scheduler {
         self.pretend_affinity_mask_
     }
 
-    pub fn in_stable_exit(&self, _t: &RecordTask) {
-        unimplemented!()
+    pub fn in_stable_exit(&self, t: &RecordTask) {
+        self.update_task_priority_internal(t, t.priority.get());
     }
 
     /// Pull a task from the round-robin queue if available. Otherwise,
@@ -276,60 +727,322 @@ impl Scheduler {
     /// the next runnable task after 't' in round-robin order.
     /// Sets 'by_waitpid' to true if we determined the task was runnable by
     /// calling waitpid on it and observing a state change. This task *must*
-    /// be returned by get_next_thread, and is_runnable_task must not be called
+    /// be returned by reschedule(), and is_task_runnable must not be called
     /// on it again until it has run.
     /// Considers only tasks with priority <= priority_threshold.
-    fn find_next_runnable_task(_t: &RecordTask, _by_waitpid: &bool, _priority_threshold: i32) {
-        unimplemented!()
+    fn find_next_runnable_task(
+        &self,
+        maybe_t: Option<&TaskSharedPtr>,
+        by_waitpid: &mut bool,
+        priority_threshold: i32,
+    ) -> Option<TaskSharedPtr> {
+        *by_waitpid = false;
+
+        let maybe_t_weak = maybe_t.map(Rc::downgrade);
+        // The outer loop has one iteration per unique priority value.
+        // The inner loop iterates over all tasks with that priority.
+        //
+        // DIFF NOTE: The tasks are copied out of the priority set since
+        // is_task_runnable() may end up calling back into the scheduler.
+        let priority_set = self.task_priority_set.borrow().clone();
+        for (&priority, same_priority) in priority_set.range(..=priority_threshold) {
+            let len = same_priority.len();
+            // Start right after `t` so that `t` is considered last.
+            let begin_at = maybe_t_weak
+                .as_ref()
+                .and_then(|tw| same_priority.iter().position(|w| w.ptr_eq(tw)))
+                .map_or(0, |pos| pos + 1);
+            log!(
+                LogDebug,
+                "  Checking {} task(s) with priority {}",
+                len,
+                priority
+            );
+            for i in 0..len {
+                let rc_t = same_priority[(begin_at + i) % len].upgrade().unwrap();
+                if self.is_task_runnable(&rc_t, by_waitpid) {
+                    return Some(rc_t);
+                }
+            }
+        }
+
+        None
     }
 
-    /// Returns the first task in the round-robin queue or null if it's empty,
-    /// removing it from the round-robin queue.
-    fn get_round_robin_task(&self) -> &RecordTask {
-        unimplemented!()
+    /// Returns the first task in the round-robin queue or None if it's empty.
+    fn get_round_robin_task(&self) -> Option<TaskSharedPtr> {
+        self.task_round_robin_queue
+            .borrow()
+            .front()
+            .map(|w| w.upgrade().unwrap())
     }
 
-    fn maybe_pop_round_robin_task(_t: &RecordTask) {
-        unimplemented!()
+    /// If `t` is at the front of the round-robin queue, remove it from the queue
+    /// and put it back in the priority set.
+    fn maybe_pop_round_robin_task(&self, t: &TaskSharedPtr) {
+        let t_weak = Rc::downgrade(t);
+        {
+            let mut round_robin_queue = self.task_round_robin_queue.borrow_mut();
+            match round_robin_queue.front() {
+                Some(front) if front.ptr_eq(&t_weak) => {
+                    round_robin_queue.pop_front();
+                }
+                _ => return,
+            }
+        }
+        let tb = t.borrow();
+        let rt = tb.as_record_task().unwrap();
+        rt.in_round_robin_queue.set(false);
+        self.task_priority_set
+            .borrow_mut()
+            .entry(rt.priority.get())
+            .or_default()
+            .push(t_weak);
     }
 
-    fn get_next_task_with_same_priority(&self, _t: &RecordTask) {
-        unimplemented!()
+    fn setup_new_timeslice(&self) {
+        let mut max_timeslice_duration = self.max_ticks_;
+        let mut rng = self.rng.borrow_mut();
+        if self.enable_chaos {
+            // Hypothesis: some bugs require short timeslices to expose. But we don't
+            // want the average timeslice to be too small. So make 10% of timeslices
+            // very short, 10% short-medium, etc.
+            let power: u32 = rng.gen_range(0, 10);
+            max_timeslice_duration /= 2u64.pow(power);
+        }
+        max_timeslice_duration = max(1, max_timeslice_duration);
+        let ticks = self.current().unwrap().borrow().tick_count();
+        self.current_timeslice_end_
+            .set(ticks + rng.gen_range(0, max_timeslice_duration));
     }
 
-    fn setup_new_timeslice() {
-        unimplemented!()
+    fn maybe_reset_priorities(&self, now: f64) {
+        if !self.enable_chaos || self.priorities_refresh_time.get() > now {
+            return;
+        }
+        // Reset task priorities again at some point in the future.
+        let refresh_interval = self.random_frac() * PRIORITIES_REFRESH_MAX_INTERVAL;
+        self.priorities_refresh_time.set(now + refresh_interval);
+
+        let mut tasks: Vec<TaskSharedWeakPtr> = self
+            .task_priority_set
+            .borrow()
+            .values()
+            .flatten()
+            .cloned()
+            .collect();
+        tasks.extend(self.task_round_robin_queue.borrow().iter().cloned());
+        for rc_t in tasks.iter().filter_map(|w| w.upgrade()) {
+            let tb = rc_t.borrow();
+            let t = tb.as_record_task().unwrap();
+            self.update_task_priority_internal(t, self.choose_random_priority(t));
+        }
     }
 
-    fn maybe_reset_priorities(&self, _now: f64) {
-        unimplemented!()
+    /// The priority doesn't depend on the task (rr doesn't look at it either);
+    /// every task independently has a `LOW_PRIORITY_PROBABILITY` chance of
+    /// getting low priority.
+    fn choose_random_priority(&self, _t: &RecordTask) -> i32 {
+        if self.random_frac() < LOW_PRIORITY_PROBABILITY {
+            1
+        } else {
+            0
+        }
     }
 
-    fn choose_random_priority(self, _t: &RecordTask) {
-        unimplemented!()
+    /// A random number in [0, 1).
+    fn random_frac(&self) -> f64 {
+        self.rng.borrow_mut().gen()
     }
-    fn update_task_priority_internal(_t: &RecordTask, _value: i32) {
-        unimplemented!()
+
+    fn update_task_priority_internal(&self, t: &RecordTask, mut value: i32) {
+        if t.stable_exit && !self.enable_chaos {
+            // Tasks in a stable exit have the highest priority. We should force them
+            // to complete exiting ASAP to clean up resources. They may not be runnable
+            // due to waiting for PTRACE_EVENT_EXIT to complete.
+            value = -9999;
+        }
+        if t.priority.get() == value {
+            return;
+        }
+        if t.in_round_robin_queue.get() {
+            t.priority.set(value);
+            return;
+        }
+
+        let t_weak = t.weak_self_ptr();
+        let mut priority_set = self.task_priority_set.borrow_mut();
+        let old_priority = t.priority.get();
+        if let Some(tasks) = priority_set.get_mut(&old_priority) {
+            tasks.retain(|w| !w.ptr_eq(&t_weak));
+            if tasks.is_empty() {
+                priority_set.remove(&old_priority);
+            }
+        }
+        t.priority.set(value);
+        priority_set.entry(value).or_default().push(t_weak);
     }
 
-    fn maybe_reset_high_priority_only_intervals(&self, _now: f64) {
-        unimplemented!()
+    fn maybe_reset_high_priority_only_intervals(&self, now: f64) {
+        if !self.enable_chaos || self.high_priority_only_intervals_refresh_time.get() > now {
+            return;
+        }
+        let duration_step: i32 = self
+            .rng
+            .borrow_mut()
+            .gen_range(0, HIGH_PRIORITY_ONLY_DURATION_STEPS);
+        let duration = MIN_HIGH_PRIORITY_ONLY_DURATION
+            * HIGH_PRIORITY_ONLY_DURATION_STEP_FACTOR.powi(duration_step);
+        let period = duration / HIGH_PRIORITY_ONLY_FRACTION;
+        self.high_priority_only_intervals_duration.set(duration);
+        self.high_priority_only_intervals_period.set(period);
+        self.high_priority_only_intervals_start
+            .set(now + self.random_frac() * period);
+        self.high_priority_only_intervals_refresh_time.set(
+            now + MIN_HIGH_PRIORITY_ONLY_DURATION
+                * HIGH_PRIORITY_ONLY_DURATION_STEP_FACTOR
+                    .powi(HIGH_PRIORITY_ONLY_DURATION_STEPS - 1)
+                / HIGH_PRIORITY_ONLY_FRACTION,
+        );
+        log!(
+            LogDebug,
+            "Set up high-priority-only intervals of {}s every {}s starting at {}",
+            duration,
+            period,
+            self.high_priority_only_intervals_start.get()
+        );
     }
 
-    fn in_high_priority_only_interval(self, _now: f64) {
-        unimplemented!()
+    fn in_high_priority_only_interval(&self, now: f64) -> bool {
+        let start = self.high_priority_only_intervals_start.get();
+        if !self.enable_chaos || now < start {
+            return false;
+        }
+        let offset = (now - start) % self.high_priority_only_intervals_period.get();
+        offset < self.high_priority_only_intervals_duration.get()
     }
 
-    fn treat_as_high_priority(&self, _t: &RecordTask) {
-        unimplemented!()
+    fn treat_as_high_priority(&self, t: &TaskSharedPtr) -> bool {
+        let num_tasks: usize = self
+            .task_priority_set
+            .borrow()
+            .values()
+            .map(|v| v.len())
+            .sum();
+        num_tasks > 1 && priority_of(t) == 0
     }
 
-    fn is_task_runnable(&self, _t: RecordTask, _by_waitpid: &mut bool) {
-        unimplemented!()
+    fn is_task_runnable(&self, rc_t: &TaskSharedPtr, by_waitpid: &mut bool) -> bool {
+        let mut tb = rc_t.borrow_mut();
+        ed_assert!(
+            &tb,
+            self.must_run_task.borrow().is_none(),
+            "is_task_runnable called again after it returned a task that must run!"
+        );
+
+        if tb.unstable.get() {
+            log!(LogDebug, "  {} is unstable", tb.tid);
+            return true;
+        }
+
+        let t = tb.as_record_task_mut().unwrap();
+        if !t.may_be_blocked() {
+            log!(LogDebug, "  {} isn't blocked", t.tid);
+            return true;
+        }
+
+        if t.emulated_stop_type != EmulatedStopType::NotStopped {
+            if t.is_signal_pending(sig::SIGCONT) {
+                // We have to do this here. RecordTask::signal_delivered can't always
+                // do it because if we don't PTRACE_CONT the task, we'll never see the
+                // SIGCONT.
+                t.emulate_sigcont();
+                // We shouldn't run any user code since there is at least one signal
+                // pending.
+                t.resume_execution(
+                    ResumeRequest::ResumeCont,
+                    WaitRequest::ResumeWait,
+                    TicksRequest::ResumeNoTicks,
+                    None,
+                );
+                *by_waitpid = true;
+                *self.must_run_task.borrow_mut() = Some(Rc::downgrade(rc_t));
+                log!(
+                    LogDebug,
+                    "  Got {} out of emulated stop due to pending SIGCONT",
+                    t.tid
+                );
+                return true;
+            } else {
+                log!(LogDebug, "  {} is stopped by ptrace or signal", t.tid);
+                // We have no way to detect a SIGCONT coming from outside the tracees.
+                // We just have to poll SigPnd in /proc/<pid>/status.
+                self.enable_poll.set(true);
+                // We also need to check if the task got killed.
+                t.try_wait();
+                // N.B.: If we supported ptrace exit notifications for killed tracee's
+                // that would need handling here, but we don't at the moment.
+                return false;
+            }
+        }
+
+        if EventType::EvSyscall == t.ev().event_type()
+            && SyscallState::ProcessingSyscall == t.ev().syscall().state
+            && treat_syscall_as_nonblocking(t.ev().syscall().number, t.arch())
+        {
+            // These syscalls never really block but the kernel may report that
+            // the task is not stopped yet if we pass WNOHANG. To make them
+            // behave predictably, do a blocking wait.
+            t.wait(None);
+            *by_waitpid = true;
+            *self.must_run_task.borrow_mut() = Some(Rc::downgrade(rc_t));
+            log!(LogDebug, "  {} was waiting for syscall to complete", t.tid);
+            return true;
+        }
+
+        if t.is_running() {
+            log!(
+                LogDebug,
+                "  {} is blocked on {}; checking status ...",
+                t.tid,
+                t.ev()
+            );
+            if t.try_wait() {
+                *by_waitpid = true;
+                *self.must_run_task.borrow_mut() = Some(Rc::downgrade(rc_t));
+                log!(LogDebug, "  ready with status {}", t.status());
+                return true;
+            }
+            log!(LogDebug, "  still blocked");
+            // Try next task
+            return false;
+        }
+
+        // The task is stopped at the end of a syscall we already waited for.
+        true
     }
 
     fn validate_scheduled_task(&self) {
-        unimplemented!()
+        let rc_current = match self.current() {
+            Some(rc_current) => rc_current,
+            None => return,
+        };
+        let current_weak = Rc::downgrade(&rc_current);
+        let currentb = rc_current.borrow();
+        ed_assert!(
+            &currentb,
+            self.must_run_task
+                .borrow()
+                .as_ref()
+                .map_or(true, |w| w.ptr_eq(&current_weak))
+        );
+        ed_assert!(
+            &currentb,
+            self.task_round_robin_queue
+                .borrow()
+                .front()
+                .map_or(true, |w| w.ptr_eq(&current_weak))
+        );
     }
 
     /// Compute an affinity mask to report via sched_getaffinity.
@@ -402,8 +1115,7 @@ impl Scheduler {
                     other_cpus.push(i);
                 }
             }
-            let mut rg = thread_rng();
-            other_cpus.shuffle(&mut rg);
+            other_cpus.shuffle(self.rng.get_mut());
             for i in 0..self.pretend_num_cores_ as usize - 1 {
                 // DIFF NOTE: rr swallows any error. We don't for now.
                 pretend_affinity_mask.set(other_cpus[i] as usize).unwrap();
@@ -412,3 +1124,11 @@ impl Scheduler {
         self.pretend_affinity_mask_ = pretend_affinity_mask;
     }
 }
+
+fn priority_of(t: &TaskSharedPtr) -> i32 {
+    t.borrow().as_record_task().unwrap().priority.get()
+}
+
+fn treat_syscall_as_nonblocking(syscallno: i32, arch: SupportedArch) -> bool {
+    is_sched_yield_syscall(syscallno, arch) || is_exit_group_syscall(syscallno, arch)
+}
@@ -31,6 +31,17 @@ fn bpf_jump(code: u16, k: u32, jt: u8, jf: u8) -> sock_filter {
     sock_filter { code, jt, jf, k }
 }
 
+/// The instruction class of a BPF opcode. See BPF_CLASS() in linux/filter.h
+pub fn bpf_class(code: u16) -> u32 {
+    code as u32 & 0x07
+}
+
+/// The source of the return value of a BPF_RET instruction. See BPF_RVAL() in
+/// linux/filter.h
+pub fn bpf_rval(code: u16) -> u32 {
+    code as u32 & 0x18
+}
+
 #[derive(Clone)]
 pub struct SeccompFilter {
     pub filters: Vec<sock_filter>,
@@ -1,3 +1,19 @@
+use crate::{
+    arch::Architecture,
+    auto_remote_syscalls::{AutoRemoteSyscalls, AutoRestoreMem},
+    bindings::kernel::{sock_filter, BPF_K, BPF_RET},
+    kernel_supplement::{SECCOMP_RET_ALLOW, SECCOMP_RET_DATA, SECCOMP_RET_TRACE},
+    log::LogDebug,
+    remote_ptr::RemotePtr,
+    seccomp_bpf::{bpf_class, bpf_rval, SeccompFilter},
+    session::task::{
+        record_task::RecordTask,
+        task_common::{read_mem, read_val_mem, write_mem, write_val_mem},
+        Task,
+    },
+};
+use std::{cell::RefCell, collections::HashMap, mem::size_of};
+
 /// When seccomp decides not to execute a syscall the kernel returns to userspace
 /// without modifying the registers. There is no negative return value to
 /// indicate that whatever side effects the syscall would happen did not take
@@ -15,4 +31,157 @@
 /// kernel itself.
 pub const SECCOMP_MAGIC_SKIP_ORIGINAL_SYSCALLNO: isize = -2;
 
-pub struct SeccompFilterRewriter;
+/// Object to support install_patched_seccomp_filter.
+///
+/// DIFF NOTE: The mappings live in RefCells because the rewriter is reached
+/// through a shared reference to the RecordSession.
+#[derive(Default)]
+pub struct SeccompFilterRewriter {
+    /// Seccomp filters can return 32-bit result values. We need to map all of
+    /// them into a single 16 bit data field. Fortunately (so far) all the
+    /// filters we've seen return constants, so there aren't too many distinct
+    /// values we need to deal with. For each constant value that gets returned,
+    /// we'll add it as the key in `result_to_index`, with the corresponding value
+    /// being the 16-bit data value that our rewritten filter returns.
+    result_to_index: RefCell<HashMap<u32, u16>>,
+    index_to_result: RefCell<Vec<u32>>,
+}
+
+impl SeccompFilterRewriter {
+    pub fn new() -> SeccompFilterRewriter {
+        Default::default()
+    }
+
+    /// Assuming `t` is set up for a prctl or seccomp syscall that
+    /// installs a seccomp-bpf filter, patch the filter to signal the tracer
+    /// instead of silently delivering an errno, and install it.
+    pub fn install_patched_seccomp_filter(&self, t: &mut RecordTask) {
+        rd_arch_function_selfless!(install_patched_seccomp_filter_arch, t.arch(), t, self)
+    }
+
+    /// Map the 16-bit data value returned by our rewritten filter back to the
+    /// result the tracee's filter wanted. Returns None if `value` isn't
+    /// one of ours.
+    pub fn map_filter_data_to_real_result(&self, value: u16) -> Option<u32> {
+        self.index_to_result.borrow().get(value as usize).copied()
+    }
+
+    /// Return the 16-bit data value for `result`, allocating a new one if we
+    /// haven't seen `result` before.
+    fn index_of_result(&self, result: u32) -> u16 {
+        if let Some(&index) = self.result_to_index.borrow().get(&result) {
+            return index;
+        }
+        let mut index_to_result = self.index_to_result.borrow_mut();
+        // SECCOMP_RET_DATA itself is reserved for rd's own filter.
+        if index_to_result.len() >= SECCOMP_RET_DATA as usize {
+            fatal!("Too many distinct constants used in seccomp-bpf programs");
+        }
+        let index = index_to_result.len() as u16;
+        index_to_result.push(result);
+        self.result_to_index.borrow_mut().insert(result, index);
+        index
+    }
+}
+
+fn set_syscall_result(t: &mut RecordTask, ret: isize) {
+    let mut r = t.regs_ref().clone();
+    r.set_syscall_result_signed(ret);
+    t.set_regs(&r);
+}
+
+/// Run the original seccomp/prctl syscall unmodified. We only do this when we
+/// know it's going to fail, so that the tracee gets the right error.
+fn pass_through_seccomp_filter(t: &mut RecordTask) {
+    let regs = t.regs_ref().clone();
+    let ret = {
+        let mut remote = AutoRemoteSyscalls::new(t);
+        rd_syscall!(
+            remote,
+            regs.original_syscallno() as i32,
+            regs.arg1(),
+            regs.arg2(),
+            regs.arg3()
+        )
+    };
+    set_syscall_result(t, ret);
+    ed_assert!(t, t.regs_ref().syscall_failed());
+}
+
+fn install_patched_seccomp_filter_arch<Arch: Architecture>(
+    t: &mut RecordTask,
+    rewriter: &SeccompFilterRewriter,
+) {
+    // Take advantage of the fact that the filter program is arg3() in both
+    // prctl and seccomp syscalls.
+    let mut ok = true;
+    let prog_ptr = RemotePtr::<Arch::sock_fprog>::from(t.regs_ref().arg3());
+    let mut prog = read_val_mem(t, prog_ptr, Some(&mut ok));
+    if !ok {
+        // We'll probably return EFAULT but a kernel that doesn't support
+        // seccomp(2) should return ENOSYS instead, so just run the original
+        // system call to get the correct error.
+        pass_through_seccomp_filter(t);
+        return;
+    }
+    let (len, filter_ptr) = Arch::get_sock_fprog(&prog);
+    let mut code = read_mem(t, filter_ptr, len, Some(&mut ok));
+    if !ok {
+        pass_through_seccomp_filter(t);
+        return;
+    }
+
+    // Convert all returns to TRACE returns so that rd can handle them.
+    // See handle_ptrace_event in RecordSession.
+    for u in code.iter_mut() {
+        if bpf_class(u.code) == BPF_RET {
+            ed_assert_eq!(
+                t,
+                bpf_rval(u.code),
+                BPF_K,
+                "seccomp-bpf program uses BPF_RET with A/X register, not supported"
+            );
+            if u.k != SECCOMP_RET_ALLOW {
+                u.k = rewriter.index_of_result(u.k) as u32 | SECCOMP_RET_TRACE;
+            }
+        }
+    }
+
+    let mut f = SeccompFilter::new();
+    // Syscalls made by rd through the privileged traced syscall instruction
+    // must never be affected by the tracee's filter.
+    if let Some(ip) = t.vm().privileged_traced_syscall_ip() {
+        f.allow_syscalls_from_callsite(ip);
+    }
+    f.filters.extend(code);
+
+    let regs = t.regs_ref().clone();
+    let ret = {
+        let mut remote = AutoRemoteSyscalls::new(t);
+        let filters_len = f.filters.len() * size_of::<sock_filter>();
+        let mut mem = AutoRestoreMem::new(
+            &mut remote,
+            None,
+            filters_len + size_of::<Arch::sock_fprog>(),
+        );
+        let code_ptr = RemotePtr::<sock_filter>::cast(mem.get().unwrap());
+        write_mem(mem.task_mut(), code_ptr, &f.filters, None);
+        Arch::set_sock_fprog(&mut prog, f.filters.len(), code_ptr);
+        let child_prog_ptr = RemotePtr::<Arch::sock_fprog>::cast(mem.get().unwrap() + filters_len);
+        write_val_mem(mem.task_mut(), child_prog_ptr, &prog, None);
+
+        rd_syscall!(
+            mem,
+            regs.original_syscallno() as i32,
+            regs.arg1(),
+            regs.arg2(),
+            child_prog_ptr.as_usize()
+        )
+    };
+    set_syscall_result(t, ret);
+
+    if !t.regs_ref().syscall_failed() {
+        log!(LogDebug, "Installed patched seccomp filter");
+        t.prctl_seccomp_status = 2;
+    }
+}
@@ -1,16 +1,35 @@
 use crate::{
-    auto_remote_syscalls::AutoRemoteSyscalls,
+    auto_remote_syscalls::{AutoRemoteSyscalls, AutoRestoreMem},
     emu_fs::EmuFs,
-    kernel_abi::SupportedArch,
+    kernel_abi::{
+        syscall_number_for_close,
+        syscall_number_for_munmap,
+        syscall_number_for_openat,
+        SupportedArch,
+    },
+    log::LogLevel::LogDebug,
+    preload_interface::syscallbuf_hdr,
+    rd::RD_RESERVED_ROOT_DIR_FD,
     remote_ptr::{RemotePtr, Void},
     session::{
-        address_space::{address_space::AddressSpaceSharedPtr, MappingFlags},
+        address_space::{
+            address_space::{AddressSpace, AddressSpaceSharedPtr},
+            memory_range::MemoryRangeKey,
+            MappingFlags,
+        },
         diversion_session::DiversionSession,
         record_session::RecordSession,
         replay_session::ReplaySession,
-        session_inner::{AddressSpaceMap, SessionInner, TaskMap, ThreadGroupMap},
+        session_inner::{
+            AddressSpaceClone,
+            AddressSpaceMap,
+            CloneCompletion,
+            SessionInner,
+            TaskMap,
+            ThreadGroupMap,
+        },
         task::{
-            task_common,
+            task_common::{self, read_mem, read_val_mem},
             task_inner::{CloneFlags, WriteFlags},
             Task,
             TaskSharedPtr,
@@ -19,10 +38,13 @@ use crate::{
     taskish_uid::{AddressSpaceUid, TaskUid, ThreadGroupUid},
     thread_group::{ThreadGroup, ThreadGroupSharedPtr},
     trace::trace_stream::TraceStream,
+    util::page_size,
 };
-use libc::pid_t;
+use libc::{pid_t, O_RDWR};
+use nix::sys::mman::MapFlags;
 use std::{
     cell::{Ref, RefMut},
+    mem::size_of,
     ops::DerefMut,
     rc::{Rc, Weak},
 };
@@ -117,9 +139,99 @@ pub trait Session: DerefMut<Target = SessionInner> {
     /// DIFF NOTE: Simply called on_create() in rr
     fn on_create_task(&self, t: TaskSharedPtr);
 
+    /// Fork all the address spaces of this session into `dest` and capture the
+    /// state of the tasks in them. The remaining tasks are created lazily by
+    /// `finish_initializing()` on `dest`.
+    ///
     /// NOTE: called Session::copy_state_to() in rr.
-    fn copy_state_to_session(&self, _dest: &SessionInner, _emu_fs: &EmuFs, _dest_emu_fs: EmuFs) {
-        unimplemented!()
+    fn copy_state_to_session(
+        &self,
+        dest: SessionSharedPtr,
+        emu_fs: &EmuFs,
+        dest_emu_fs: &mut EmuFs,
+    ) {
+        self.assert_fully_initialized();
+        debug_assert!(dest.clone_completion.borrow().is_none());
+
+        let mut completion = CloneCompletion {
+            address_spaces: Vec::new(),
+        };
+        let vms: Vec<AddressSpaceSharedPtr> = self
+            .vm_map()
+            .values()
+            .map(|vm| vm.upgrade().unwrap())
+            .collect();
+        for vm in vms {
+            // Pick an arbitrary task to be group leader. The actual group leader
+            // might have died already.
+            let group_leader_rc = vm.task_set().iter().next().unwrap();
+            let mut group_leader = group_leader_rc.borrow_mut();
+            log!(
+                LogDebug,
+                "  forking tg {} (real: {})",
+                group_leader.tgid(),
+                group_leader.real_tgid()
+            );
+
+            let clone_leader = task_common::os_fork_into(group_leader.as_mut(), dest.clone());
+            dest.on_create_task(clone_leader.clone());
+            log!(
+                LogDebug,
+                "  forked new group leader {}",
+                clone_leader.borrow().tid
+            );
+
+            let mut captured_memory = Vec::new();
+            {
+                let mut clone_leader_b = clone_leader.borrow_mut();
+                let mut remote = AutoRemoteSyscalls::new(clone_leader_b.as_mut());
+                let mut syscallbufs = Vec::new();
+                let mut shared_maps_to_clone = Vec::new();
+                for (&k, m) in &remote.vm().maps() {
+                    if m.flags.contains(MappingFlags::IS_SYSCALLBUF) {
+                        // Special case the syscallbuf as a performance optimization. The
+                        // amount of data we need to capture is usually significantly
+                        // smaller than the size of the mapping. The mapping itself is
+                        // recreated in finish_initializing().
+                        syscallbufs.push((m.map.start(), m.map.size()));
+                    } else if m.local_addr.is_some() {
+                        ed_assert_eq!(
+                            remote.task(),
+                            m.map.start(),
+                            AddressSpace::preload_thread_locals_start()
+                        );
+                    } else if m.recorded_map.flags().contains(MapFlags::MAP_SHARED)
+                        && emu_fs.has_file_for(&m.recorded_map)
+                    {
+                        shared_maps_to_clone.push(k);
+                    }
+                }
+                // Do these in separate loops to avoid iteration invalidation issues
+                for (start, size) in syscallbufs {
+                    captured_memory
+                        .push((start, capture_syscallbuf(remote.task_mut(), start, size)));
+                }
+                for k in shared_maps_to_clone {
+                    remap_shared_mmap(&mut remote, emu_fs, dest_emu_fs, k);
+                }
+            }
+
+            let mut member_states = Vec::new();
+            for t in vm.task_set().iter_except(group_leader.weak_self_ptr()) {
+                log!(LogDebug, "    cloning {}", t.borrow().rec_tid);
+                member_states.push(t.borrow_mut().capture_state());
+            }
+
+            completion.address_spaces.push(AddressSpaceClone {
+                clone_leader: Rc::downgrade(&clone_leader),
+                clone_leader_state: group_leader.capture_state(),
+                member_states,
+                captured_memory,
+            });
+        }
+
+        *dest.clone_completion.borrow_mut() = Some(Box::new(completion));
+        debug_assert!(dest.vm_map().len() > 0);
     }
 
     /// Call this before doing anything that requires access to the full set
@@ -136,24 +248,19 @@ pub trait Session: DerefMut<Target = SessionInner> {
             let rc = tgleader.clone_leader.upgrade().unwrap();
             let mut leader = rc.borrow_mut();
             {
-                let mut found_syscall_buf = None;
+                let mut found_syscall_bufs = Vec::new();
                 let mut remote = AutoRemoteSyscalls::new(leader.as_mut());
                 for (&mk, m) in &remote.vm().maps() {
                     if m.flags.contains(MappingFlags::IS_SYSCALLBUF) {
                         // DIFF NOTE: The whole reason why this approach is a bit different from rr because its
                         // its tougher to iterate and modify a map at the same time in rust vs c++.
-                        found_syscall_buf = Some(mk);
-                        // DIFF NOTE: We are assuming only a single syscall buffer in the memory maps.
-                        break;
+                        found_syscall_bufs.push(mk);
                     }
                 }
 
-                match found_syscall_buf {
-                    Some(k) => {
-                        // Creating this mapping was delayed in capture_state for performance
-                        remote.recreate_shared_mmap(k, None, None);
-                    }
-                    None => (),
+                for k in found_syscall_bufs {
+                    // Creating this mapping was delayed in copy_state_to_session() for performance
+                    remote.recreate_shared_mmap(k, None, None);
                 }
             }
 
@@ -165,16 +272,12 @@ pub trait Session: DerefMut<Target = SessionInner> {
                 let mut remote2 = AutoRemoteSyscalls::new(leader.as_mut());
                 for tgmember in &tgleader.member_states {
                     let t_clone = task_common::os_clone_into(tgmember, &mut remote2);
-                    self.on_create_task(t_clone);
+                    self.on_create_task(t_clone.clone());
+                    task_common::copy_state(t_clone.borrow_mut().as_mut(), tgmember);
                 }
             }
 
-            tgleader
-                .clone_leader
-                .upgrade()
-                .unwrap()
-                .borrow_mut()
-                .copy_state(&tgleader.clone_leader_state);
+            task_common::copy_state(leader.as_mut(), &tgleader.clone_leader_state);
         }
         // Don't need to set clone completion to `None`. Its already been done!
     }
@@ -345,3 +448,103 @@ fn on_create_task_common<S: Session>(sess: &S, t: TaskSharedPtr) {
     let rec_tid = t.borrow().rec_tid;
     sess.task_map.borrow_mut().insert(rec_tid, t);
 }
+
+/// Read the part of the syscallbuf at `start` that needs to be restored in a
+/// clone.
+fn capture_syscallbuf(t: &mut dyn Task, start: RemotePtr<Void>, size: usize) -> Vec<u8> {
+    let hdr_addr = RemotePtr::<syscallbuf_hdr>::cast(start);
+    let hdr = read_val_mem(t, hdr_addr, None);
+    // Copy out of the packed struct before use
+    let locked = hdr.locked;
+    let num_rec_bytes = hdr.num_rec_bytes;
+    let data_size = if locked.is_empty() {
+        size_of::<syscallbuf_hdr>() + num_rec_bytes as usize
+    } else {
+        // There may be an incomplete syscall record after num_rec_bytes that
+        // we need to capture here. We don't know how big that record is,
+        // so just record the entire buffer. This should not be common.
+        size
+    };
+    read_mem(t, RemotePtr::<u8>::cast(start), data_size, None)
+}
+
+/// Replace the MAP_SHARED emufs-backed mapping at `k` in the task behind
+/// `remote` with a mapping of a private copy of the emufs file. Otherwise the
+/// clone would share the file contents with the session it was cloned from.
+fn remap_shared_mmap(
+    remote: &mut AutoRemoteSyscalls,
+    emu_fs: &EmuFs,
+    dest_emu_fs: &mut EmuFs,
+    k: MemoryRangeKey,
+) {
+    let m = remote.vm().mapping_of(k.start()).unwrap().clone();
+    log!(
+        LogDebug,
+        "    remapping shared region at {}-{}",
+        m.map.start(),
+        m.map.end()
+    );
+    let arch = remote.arch();
+    rd_infallible_syscall!(
+        remote,
+        syscall_number_for_munmap(arch),
+        m.map.start().as_usize(),
+        m.map.size()
+    );
+
+    let emu_file = dest_emu_fs.clone_file(emu_fs.at(&m.recorded_map).unwrap());
+    // @TODO This duplicates some code in replay_syscall.rs, but
+    // it's somewhat nontrivial to factor that code out.
+    let remote_fd: i32 = {
+        let path = emu_file.borrow().proc_path();
+        let mut child_path = AutoRestoreMem::push_cstr(remote, path.as_str());
+        // Always open the emufs file O_RDWR, even if the current mapping prot
+        // is read-only. We might mprotect it to read-write later.
+        // Skip leading '/' since we want the path to be relative to the root fd
+        let addr: RemotePtr<Void> = child_path.get().unwrap() + 1usize;
+        rd_infallible_syscall!(
+            child_path,
+            syscall_number_for_openat(arch),
+            RD_RESERVED_ROOT_DIR_FD,
+            addr.as_usize(),
+            O_RDWR
+        ) as i32
+    };
+
+    let real_file = remote.task().stat_fd(remote_fd);
+    let real_file_name = remote.task().file_name_of_fd(remote_fd);
+    // XXX this condition is x86/x64-specific, I imagine.
+    remote.infallible_mmap_syscall(
+        Some(m.map.start()),
+        m.map.size(),
+        m.map.prot(),
+        // The remapped segment *must* be
+        // remapped at the same address,
+        // or else many things will go
+        // haywire.
+        (m.map.flags() & !MapFlags::MAP_ANONYMOUS) | MapFlags::MAP_FIXED,
+        remote_fd,
+        m.map.file_offset_bytes() / page_size() as u64,
+    );
+
+    // We update the AddressSpace mapping too, since that tracks the real file
+    // name and we need to update that.
+    remote.vm().map(
+        remote.task(),
+        m.map.start(),
+        m.map.size(),
+        m.map.prot(),
+        m.map.flags(),
+        m.map.file_offset_bytes(),
+        &real_file_name,
+        real_file.st_dev,
+        real_file.st_ino,
+        None,
+        Some(&m.recorded_map),
+        Some(emu_file),
+        None,
+        None,
+    );
+
+    rd_infallible_syscall!(remote, syscall_number_for_close(arch), remote_fd);
+}
@@ -246,6 +246,7 @@ pub mod address_space {
         PROT_GROWSUP,
     };
     use nix::{fcntl::OFlag, sys::mman::munmap, unistd::getpid};
+    use rand::Rng;
     use std::{
         cell::{Cell, Ref, RefCell, RefMut},
         cmp::{max, min},
@@ -267,6 +268,8 @@ pub mod address_space {
         sync::atomic::{AtomicUsize, Ordering},
     };
 
+    const CHAOS_MODE_LOWEST_ADDR: usize = 0x10000;
+
     fn find_offset_of_syscall_instruction_in(arch: SupportedArch, vdso: &[u8]) -> Option<usize> {
         let instruction = syscall_instruction(arch);
         let instruction_size = instruction.len();
@@ -648,12 +651,15 @@ pub mod address_space {
         /// First mapped byte of the vdso.
         vdso_start_addr: Cell<RemotePtr<Void>>,
         /// The monkeypatcher that's handling this address space.
-        monkeypatch_state: Option<MonkeyPatcher>,
+        monkeypatch_state: Option<RefCell<MonkeyPatcher>>,
         /// The watchpoints set for tasks in this VM.  Watchpoints are
         /// programmed per Task, but we track them per address space on
         /// behalf of debuggers that assume that model.
         watchpoints: RefCell<HashMap<MemoryRange, Watchpoint>>,
         saved_watchpoints: RefCell<Vec<HashMap<MemoryRange, Watchpoint>>>,
+        /// True when `watchpoints` don't fit in the debug registers and have to
+        /// be checked by singlestepping instead.
+        software_watchpoints: Cell<bool>,
         /// Tracee memory is read and written through this fd, which is
         /// opened for the tracee's magic /proc/{tid}/mem device.  The
         /// advantage of this over ptrace is that we can access it even
@@ -1122,8 +1128,11 @@ pub mod address_space {
                 // If the first segment we protect underflows the
                 // region, remap the underflow region with previous
                 // prot.
-                let monitored = m.monitored_shared_memory.clone();
                 if m.map.start() < new_start {
+                    let monitored = m
+                        .monitored_shared_memory
+                        .clone()
+                        .map(|r| r.borrow().subrange(0, rem.start() - m.map.start()));
                     let mut underflow = Mapping::new(
                         m.map.subrange(m.map.start(), rem.start()),
                         m.recorded_map.subrange(m.recorded_map.start(), rem.start()),
@@ -1458,7 +1467,13 @@ pub mod address_space {
                 .unwrap()
                 .watch(Self::access_bits_of(type_));
 
-            self.allocate_watchpoints(active_task, None)
+            if self.allocate_watchpoints(active_task, None) {
+                return true;
+            }
+            // Don't leave behind a watchpoint the caller thinks failed. This
+            // also reprograms the watchpoints that did fit.
+            self.remove_watchpoint(addr, num_bytes, type_, active_task);
+            false
         }
 
         /// DIFF NOTE: Additional param `active_task`
@@ -1470,10 +1485,12 @@ pub mod address_space {
             active_task: &mut dyn Task,
         ) {
             let r = range_for_watchpoint(addr, num_bytes);
-            if let Some(wp) = self.watchpoints.borrow_mut().get_mut(&r) {
-                if 0 == wp.unwatch(Self::access_bits_of(type_)) {
-                    self.watchpoints.borrow_mut().remove(&r);
-                }
+            let unused = match self.watchpoints.borrow_mut().get_mut(&r) {
+                Some(wp) => 0 == wp.unwatch(Self::access_bits_of(type_)),
+                None => false,
+            };
+            if unused {
+                self.watchpoints.borrow_mut().remove(&r);
             }
             self.allocate_watchpoints(active_task, None);
         }
@@ -1558,6 +1575,41 @@ pub mod address_space {
             triggered
         }
 
+        /// Check our watchpoints after a singlestep when they couldn't be
+        /// programmed into the debug registers. `ip` is the address of the
+        /// next instruction to execute. Returns true if any watchpoint
+        /// triggered.
+        ///
+        /// EXEC watchpoints fire when `ip` is at the watched address, just as
+        /// a hardware execution watchpoint fires before the instruction runs.
+        /// WRITE watchpoints fire when the watched value changed. Reads can't
+        /// be observed this way, which is why `allocate_watchpoints()` never
+        /// falls back to software for READ watchpoints.
+        pub fn notify_software_watchpoints(&self, ip: RemoteCodePtr) -> bool {
+            debug_assert!(self.software_watchpoints.get());
+            let mut triggered = false;
+            let mut for_update_watchpoint: Vec<MemoryRange> = Vec::new();
+            for (range, w) in self.watchpoints.borrow_mut().iter_mut() {
+                let watched_bits = w.watched_bits();
+                if watched_bits.contains(RwxBits::EXEC_BIT)
+                    && range.contains_ptr(ip.to_data_ptr::<Void>())
+                {
+                    w.changed = true;
+                    triggered = true;
+                }
+                if watched_bits.contains(RwxBits::WRITE_BIT) {
+                    for_update_watchpoint.push(*range);
+                }
+            }
+
+            for range in &for_update_watchpoint {
+                if self.update_watchpoint_value(range, Some(true)) {
+                    triggered = true;
+                }
+            }
+            triggered
+        }
+
         /// Return true if any watchpoint has fired. Will keep returning true until
         /// consume_watchpoint_changes() is called.
         pub fn has_any_watchpoint_changes(&self) -> bool {
@@ -1692,6 +1744,12 @@ pub mod address_space {
         pub fn has_watchpoints(&self) -> bool {
             !self.watchpoints.borrow().is_empty()
         }
+        /// True when our watchpoints couldn't all be programmed into the debug
+        /// registers. Tasks in this address space must then be singlestepped
+        /// for the watchpoints to fire; see `notify_software_watchpoints()`.
+        pub fn has_software_watchpoints(&self) -> bool {
+            self.software_watchpoints.get() && self.has_watchpoints()
+        }
 
         /// Encoding of the `int $3` instruction.
         pub const BREAKPOINT_INSN: u8 = 0xCC;
@@ -1706,8 +1764,12 @@ pub mod address_space {
             *self.child_mem_fd.borrow_mut() = fd;
         }
 
-        pub fn monkeypatcher(&self) -> Option<&MonkeyPatcher> {
-            self.monkeypatch_state.as_ref()
+        pub fn monkeypatcher(&self) -> Option<Ref<MonkeyPatcher>> {
+            self.monkeypatch_state.as_ref().map(|mp| mp.borrow())
+        }
+
+        pub fn monkeypatcher_mut(&self) -> Option<RefMut<MonkeyPatcher>> {
+            self.monkeypatch_state.as_ref().map(|mp| mp.borrow_mut())
         }
 
         pub fn at_preload_init(&self, t: &mut dyn Task) {
@@ -1899,8 +1961,69 @@ pub mod address_space {
             8 * 1024 * 1024
         }
 
-        pub fn chaos_mode_find_free_memory(_t: &RecordTask, _len: usize) -> RemotePtr<Void> {
-            unimplemented!()
+        /// Find a random free region of `len` bytes in `t`'s address space. Half the time we
+        /// start searching from a completely random address, the other half from the start
+        /// of a randomly chosen existing mapping. We then search in a random direction for
+        /// a gap that fits and, failing that, in the other direction.
+        ///
+        /// Returns a null pointer if no suitable region could be found.
+        pub fn chaos_mode_find_free_memory(t: &RecordTask, len: usize) -> RemotePtr<Void> {
+            let session = t.session();
+            let sched = session.as_record().unwrap().scheduler();
+            let mut rng = sched.rng();
+            let len = ceil_page_size(len);
+            let addr_space_end: usize = match t.arch() {
+                SupportedArch::X86 => 1 << 32,
+                SupportedArch::X64 => 1 << 47,
+            };
+            // Don't place anything in the first 64KB or right at the end of the
+            // address space.
+            let lowest = CHAOS_MODE_LOWEST_ADDR;
+            if lowest + 3 * page_size() + len > addr_space_end {
+                return RemotePtr::null();
+            }
+            let highest = addr_space_end - 3 * page_size() - len;
+
+            let ranges: Vec<MemoryRange> = t.vm().maps().into_iter().map(|(_, m)| *m.map).collect();
+            let start = if ranges.is_empty() || rng.gen::<bool>() {
+                floor_page_size(rng.gen_range(0, addr_space_end))
+            } else {
+                ranges[rng.gen_range(0, ranges.len())].start().as_usize()
+            };
+            let start = min(max(start, lowest), highest);
+
+            let search_up = |mut addr: usize| -> Option<usize> {
+                for r in &ranges {
+                    if MemoryRange::new_range(addr.into(), len).intersects(r) {
+                        addr = r.end().as_usize();
+                        if addr > highest {
+                            return None;
+                        }
+                    }
+                }
+                Some(addr)
+            };
+            let search_down = |mut addr: usize| -> Option<usize> {
+                for r in ranges.iter().rev() {
+                    if MemoryRange::new_range(addr.into(), len).intersects(r) {
+                        if r.start().as_usize() < lowest + len {
+                            return None;
+                        }
+                        addr = r.start().as_usize() - len;
+                    }
+                }
+                Some(addr)
+            };
+
+            let found = if rng.gen::<bool>() {
+                search_up(start).or_else(|| search_down(start))
+            } else {
+                search_down(start).or_else(|| search_up(start))
+            };
+            match found {
+                Some(addr) => RemotePtr::from(addr),
+                None => RemotePtr::null(),
+            }
         }
 
         /// We assume this method always succeeds
@@ -2115,7 +2238,7 @@ pub mod address_space {
             exec_count: u32,
         ) -> AddressSpace {
             let patcher = if t.session().is_recording() {
-                Some(MonkeyPatcher::new())
+                Some(RefCell::new(MonkeyPatcher::new()))
             } else {
                 None
             };
@@ -2132,6 +2255,7 @@ pub mod address_space {
                 // Implicit
                 breakpoints: Default::default(),
                 watchpoints: Default::default(),
+                software_watchpoints: Default::default(),
                 mem: Default::default(),
                 shm_sizes: Default::default(),
                 monitored_mem: Default::default(),
@@ -2200,6 +2324,7 @@ pub mod address_space {
                 saved_auxv_: o.saved_auxv_.clone(),
                 first_run_event_: Default::default(),
                 watchpoints: o.watchpoints.clone(),
+                software_watchpoints: Cell::new(o.software_watchpoints.get()),
                 breakpoints: o.breakpoints.clone(),
                 // rr does not explicitly initialize these.
                 child_mem_fd: Default::default(),
@@ -2310,8 +2435,11 @@ pub mod address_space {
 
                 // If the first segment we unmap underflows the unmap
                 // region, remap the underflow region.
-                let monitored = m.monitored_shared_memory.clone();
                 if m.map.start() < rem.start() {
+                    let monitored = m
+                        .monitored_shared_memory
+                        .clone()
+                        .map(|r| r.borrow().subrange(0, rem.start() - m.map.start()));
                     let mut underflow = Mapping::new(
                         m.map.subrange(m.map.start(), rem.start()),
                         m.recorded_map.subrange(m.map.start(), rem.start()),
@@ -2691,6 +2819,7 @@ pub mod address_space {
                     }
                 }
                 if ok {
+                    self.software_watchpoints.set(false);
                     return true;
                 }
             }
@@ -2711,7 +2840,22 @@ pub mod address_space {
                 v.debug_regs_for_exec_read.clear();
             }
 
-            false
+            // There are more watchpoints than debug registers. Fall back to
+            // checking them after every singlestep, unless we'd have to
+            // detect reads, which we can't do in software.
+            let software_ok = self
+                .watchpoints
+                .borrow()
+                .values()
+                .all(|w| !w.watched_bits().contains(RwxBits::READ_BIT));
+            if software_ok {
+                log!(
+                    LogDebug,
+                    "Watchpoints don't fit in the debug registers; checking them in software"
+                );
+            }
+            self.software_watchpoints.set(software_ok);
+            software_ok
         }
 
         /// Merge the mappings adjacent to `key` in memory that are
@@ -2978,10 +3122,9 @@ pub mod address_space {
             self.syscallbuf_enabled_.set(true);
 
             if t.session().is_recording() {
-                self.monkeypatch_state
-                    .as_ref()
+                self.monkeypatcher_mut()
                     .unwrap()
-                    .patch_at_preload_init(t.as_record_task().unwrap());
+                    .patch_at_preload_init(t.as_record_task_mut().unwrap());
             }
         }
 
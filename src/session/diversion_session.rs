@@ -1,17 +1,34 @@
-use super::{on_create_task_common, session_common::kill_all_tasks, task::TaskSharedPtr};
+use super::{
+    on_create_task_common,
+    session_common::kill_all_tasks,
+    task::{replay_task::ReplayTask, TaskSharedPtr},
+};
 use crate::{
+    arch::Architecture,
+    auto_remote_syscalls::AutoRemoteSyscalls,
+    bindings::ptrace::PTRACE_EVENT_EXIT,
     emu_fs::{EmuFs, EmuFsSharedPtr},
+    kernel_abi::SupportedArch,
+    kernel_metadata::syscall_name,
+    log::LogLevel::LogDebug,
+    perf_counters::TIME_SLICE_SIGNAL,
+    preload_interface::preload_globals,
+    remote_ptr::RemotePtr,
     session::{
         session_inner::{BreakStatus, RunCommand, SessionInner},
-        task::Task,
+        task::{
+            task_common::write_val_mem,
+            task_inner::{ResumeRequest, TicksRequest, WaitRequest},
+            Task,
+        },
         Session,
     },
     sig::Sig,
 };
+use libc::{pid_t, ENOSYS, PR_SET_TSC};
 use std::{
-    cell::{Ref, RefCell, RefMut},
+    cell::{Ref, RefMut},
     ops::{Deref, DerefMut},
-    rc::Rc,
 };
 
 /// A DiversionSession lets you run task(s) forward without replay.
@@ -36,7 +53,19 @@ pub struct DiversionSession {
 
 impl Drop for DiversionSession {
     fn drop(&mut self) {
-        unimplemented!()
+        // We won't permanently leak any OS resources by not ensuring
+        // we've cleaned up here, but sessions can be created and
+        // destroyed many times, and we don't want to temporarily hog
+        // resources.
+        self.kill_all_tasks();
+        debug_assert!(self.task_map.borrow().is_empty());
+        debug_assert!(self.vm_map.borrow().is_empty());
+        debug_assert_eq!(self.emufs().size(), 0);
+        log!(
+            LogDebug,
+            "DiversionSession {:?} destroyed",
+            self as *const Self
+        );
     }
 }
 
@@ -52,8 +81,6 @@ pub struct DiversionResult {
     pub break_status: BreakStatus,
 }
 
-pub type DiversionSessionSharedPtr = Rc<RefCell<DiversionSession>>;
-
 impl DiversionSession {
     pub fn emufs(&self) -> Ref<'_, EmuFs> {
         self.emu_fs.borrow()
@@ -62,17 +89,237 @@ impl DiversionSession {
         self.emu_fs.borrow_mut()
     }
     pub fn new() -> DiversionSession {
-        unimplemented!()
+        DiversionSession {
+            session_inner: SessionInner::new(),
+            emu_fs: EmuFs::create(),
+        }
     }
+
     /// Try make progress in this diversion session. Run task t if possible.
+    ///
+    /// A `command` of `None` is treated as `RunCommand::RunContinue`.
     pub fn diversion_step(
         &self,
-        _t: &mut dyn Task,
-        _command: Option<RunCommand>,
-        _signal_to_deliver: Option<Sig>,
+        t: &mut dyn Task,
+        command: Option<RunCommand>,
+        signal_to_deliver: Option<Sig>,
     ) -> DiversionResult {
-        unimplemented!()
+        let command = command.unwrap_or(RunCommand::RunContinue);
+        debug_assert_ne!(command, RunCommand::RunSinglestepFastForward);
+        self.assert_fully_initialized();
+
+        let mut result = DiversionResult {
+            status: DiversionStatus::DiversionContinue,
+            break_status: BreakStatus::new(),
+        };
+
+        // An exit might have occurred while processing a previous syscall.
+        if t.maybe_ptrace_event() == PTRACE_EVENT_EXIT {
+            result.status = DiversionStatus::DiversionExited;
+            return result;
+        }
+
+        set_in_diversion(t);
+
+        loop {
+            match command {
+                RunCommand::RunContinue => {
+                    log!(LogDebug, "Continuing to next syscall");
+                    t.resume_execution(
+                        ResumeRequest::ResumeSysemu,
+                        WaitRequest::ResumeWait,
+                        TicksRequest::ResumeUnlimitedTicks,
+                        signal_to_deliver,
+                    );
+                }
+                RunCommand::RunSinglestep => {
+                    log!(LogDebug, "Stepping to next insn/syscall");
+                    t.resume_execution(
+                        ResumeRequest::ResumeSysemuSinglestep,
+                        WaitRequest::ResumeWait,
+                        TicksRequest::ResumeUnlimitedTicks,
+                        signal_to_deliver,
+                    );
+                }
+                _ => fatal!("Illegal run command {:?}", command),
+            }
+
+            if t.maybe_ptrace_event() == PTRACE_EVENT_EXIT {
+                result.status = DiversionStatus::DiversionExited;
+                result.break_status.task = Some(t.weak_self_ptr());
+                result.break_status.task_exit = true;
+                return result;
+            }
+
+            result.status = DiversionStatus::DiversionContinue;
+            if t.maybe_stop_sig().is_sig() {
+                if t.maybe_stop_sig() == TIME_SLICE_SIGNAL {
+                    // This would normally be triggered by constraints.ticks_target but it's
+                    // also possible to get stray signals here.
+                    continue;
+                }
+                result.break_status = self.diagnose_debugger_trap(t, command);
+                log!(
+                    LogDebug,
+                    "Diversion break at ip={}; break={}, watch={}, singlestep={}",
+                    t.ip(),
+                    result.break_status.breakpoint_hit,
+                    !result.break_status.watchpoints_hit.is_empty(),
+                    result.break_status.singlestep_complete
+                );
+                ed_assert!(
+                    t,
+                    !result.break_status.singlestep_complete
+                        || command == RunCommand::RunSinglestep
+                );
+                return result;
+            }
+
+            break;
+        }
+
+        let syscallno = t.regs_ref().original_syscallno() as i32;
+        process_syscall(t, syscallno);
+        self.check_for_watchpoint_changes(t, &mut result.break_status);
+        result
+    }
+}
+
+/// Let the preload library know that it's running in a diversion so that it
+/// doesn't try to use syscallbuf state that only makes sense during replay.
+fn set_in_diversion(t: &mut dyn Task) {
+    let globals = match t.preload_globals {
+        Some(globals) => globals,
+        None => return,
+    };
+    let addr = RemotePtr::<u8>::cast(globals) + offset_of!(preload_globals, in_diversion);
+    write_val_mem(t, addr, &1u8, None);
+}
+
+fn finish_emulated_syscall_with_ret(t: &mut dyn Task, ret: isize) {
+    t.finish_emulated_syscall();
+    let mut r = t.regs_ref().clone();
+    r.set_syscall_result_signed(ret);
+    t.set_regs(&r);
+}
+
+/// Execute the syscall contained in `t`'s current register set.  The
+/// return value of the syscall is set for `t`'s registers, to be
+/// returned to the tracee task.
+fn execute_syscall(t: &mut dyn Task) {
+    t.finish_emulated_syscall();
+
+    let mut remote = AutoRemoteSyscalls::new(t);
+    let r = remote.initial_regs_ref().clone();
+    let ret = remote.syscall(
+        r.original_syscallno() as i32,
+        &[r.arg1(), r.arg2(), r.arg3(), r.arg4(), r.arg5(), r.arg6()],
+    );
+    remote.initial_regs_mut().set_syscall_result_signed(ret);
+}
+
+fn process_syscall(t: &mut dyn Task, syscallno: i32) {
+    let arch = t.arch();
+    rd_arch_function_selfless!(process_syscall_arch, arch, t, syscallno)
+}
+
+fn process_syscall_arch<Arch: Architecture>(t: &mut dyn Task, syscallno: i32) {
+    log!(
+        LogDebug,
+        "Processing {}",
+        syscall_name(syscallno, Arch::arch())
+    );
+
+    if syscallno == Arch::IOCTL && t.is_desched_event_syscall() {
+        // The arm/disarm-desched ioctls are emulated as no-ops.
+        // However, because the rd preload library expects these
+        // syscalls to succeed and aborts if they don't, we fudge a
+        // "0" return value.
+        finish_emulated_syscall_with_ret(t, 0);
+        return;
+    }
+
+    // We blacklist these syscalls because the params include
+    // namespaced identifiers that are different in replay than
+    // recording, and during replay they may refer to different,
+    // live resources.  For example, if a recorded tracees kills
+    // one of its threads, then during replay that killed pid
+    // might refer to a live process outside the tracee tree.  We
+    // don't want diversion tracees randomly shooting down other
+    // processes!
+    //
+    // We optimistically assume that filesystem operations were
+    // intended by the user.
+    //
+    // There's a potential problem with "fd confusion": in the
+    // diversion tasks, fds returned from open() during replay are
+    // emulated.  But those fds may accidentally refer to live fds
+    // in the task fd table.  So write()s etc may not be writing
+    // to the file the tracee expects.  However, the only real fds
+    // that leak into tracees are the stdio fds, and there's not
+    // much harm that can be caused by accidental writes to them.
+    //
+    // fork/vfork/clone are likely to lead to disaster because we only
+    // ever allow a single task to run.
+    if syscallno == Arch::IPC
+        || syscallno == Arch::KILL
+        || syscallno == Arch::RT_SIGQUEUEINFO
+        || syscallno == Arch::RT_TGSIGQUEUEINFO
+        || syscallno == Arch::TGKILL
+        || syscallno == Arch::TKILL
+        || syscallno == Arch::FORK
+        || syscallno == Arch::VFORK
+        || syscallno == Arch::CLONE
+    {
+        log!(
+            LogDebug,
+            "Suppressing syscall {}",
+            syscall_name(syscallno, t.arch())
+        );
+        suppress_syscall(t);
+        return;
+    }
+
+    if syscallno == Arch::PRCTL && t.regs_ref().arg1_signed() as i32 == PR_SET_TSC {
+        log!(
+            LogDebug,
+            "Suppressing syscall {}",
+            syscall_name(syscallno, t.arch())
+        );
+        suppress_syscall(t);
+        return;
+    }
+
+    if syscallno == Arch::GETTID {
+        let tid: pid_t = t.own_namespace_tid();
+        log!(LogDebug, "Emulating gettid with {}", tid);
+        set_syscall_result(t, tid as isize);
+        return;
     }
+
+    if syscallno == Arch::GETPID {
+        let pid: pid_t = t.thread_group().real_tgid_own_namespace;
+        log!(LogDebug, "Emulating getpid with {}", pid);
+        set_syscall_result(t, pid as isize);
+        return;
+    }
+
+    log!(
+        LogDebug,
+        "Executing syscall {}",
+        syscall_name(syscallno, t.arch())
+    );
+    execute_syscall(t);
+}
+
+fn suppress_syscall(t: &mut dyn Task) {
+    set_syscall_result(t, -ENOSYS as isize);
+}
+
+fn set_syscall_result(t: &mut dyn Task, ret: isize) {
+    let mut r = t.regs_ref().clone();
+    r.set_syscall_result_signed(ret);
+    t.set_regs(&r);
 }
 
 impl Deref for DiversionSession {
@@ -107,6 +354,19 @@ impl Session for DiversionSession {
         Some(self)
     }
 
+    /// DIFF NOTE: rr creates plain `Task`s here. rd has no such type so the
+    /// tasks of a diversion are `ReplayTask`s. Nothing in the diversion relies
+    /// on their replay-specific state.
+    fn new_task(
+        &self,
+        tid: pid_t,
+        rec_tid: Option<pid_t>,
+        serial: u32,
+        a: SupportedArch,
+    ) -> Box<dyn Task> {
+        Box::new(ReplayTask::new(self, tid, rec_tid, serial, a))
+    }
+
     fn on_create_task(&self, t: TaskSharedPtr) {
         on_create_task_common(self, t);
     }
@@ -1,52 +1,137 @@
 use super::{
+    address_space::{address_space::AddressSpace, Privileged},
     on_create_task_common,
     session_common::kill_all_tasks,
     task::{
-        record_task::RecordTask,
-        task_common::write_val_mem,
-        task_inner::{ResumeRequest, SaveTraceeFdNumber, TaskInner, TicksRequest, WaitRequest},
+        record_task::{AllowSyscallbufReset, EmulatedStopType, FlushSyscallbuf, RecordTask},
+        task_common::{read_val_mem, write_val_mem},
+        task_inner::{
+            PtraceData,
+            ResumeRequest,
+            SaveTraceeFdNumber,
+            TaskInner,
+            TicksRequest,
+            WaitRequest,
+            MAX_TICKS_REQUEST,
+        },
     },
     SessionSharedPtr,
 };
 use crate::{
+    arch::Architecture,
     bindings::{
         audit::{AUDIT_ARCH_I386, AUDIT_ARCH_X86_64},
-        signal::siginfo_t,
+        kernel::{FUTEX_OWNER_DIED, FUTEX_TID_MASK, FUTEX_WAITERS},
+        ptrace::{
+            ptrace,
+            PTRACE_EVENT_EXEC,
+            PTRACE_EVENT_EXIT,
+            PTRACE_EVENT_SECCOMP,
+            PTRACE_GETEVENTMSG,
+            PTRACE_O_TRACEEXEC,
+            PTRACE_O_TRACESECCOMP,
+            PTRACE_SINGLESTEP,
+            PTRACE_SYSCALL,
+            PTRACE_SYSEMU,
+            PTRACE_SYSEMU_SINGLESTEP,
+        },
+        signal::{siginfo_t, POLL_IN, SI_KERNEL, SI_SIGIO, SI_TIMER, SI_USER},
     },
     commands::record_command::RecordCommand,
-    event::{Event, EventType, SignalDeterministic, Switchable, SyscallState},
-    kernel_abi::{native_arch, SupportedArch},
-    kernel_supplement::SYS_SECCOMP,
-    log::{LogDebug, LogError},
-    perf_counters::TicksSemantics,
+    event::{
+        Event,
+        EventType,
+        SignalDeterministic,
+        SignalResolvedDisposition,
+        Switchable,
+        SyscallEventData,
+        SyscallState,
+    },
+    file_monitor::virtual_perf_counter_monitor::VirtualPerfCounterMonitor,
+    flags::Flags,
+    kernel_abi::{
+        is_at_syscall_instruction,
+        is_exit_group_syscall,
+        is_pause_syscall,
+        is_rdcall_notify_syscall_hook_exit_syscall,
+        is_restart_syscall_syscall,
+        native_arch,
+        syscall_number_for_gettid,
+        syscall_number_for_restart_syscall,
+        x86,
+        SupportedArch,
+    },
+    kernel_metadata::{errno_name, is_sigreturn, ptrace_event_name, syscall_name},
+    kernel_supplement::{
+        sig_set_t,
+        ERESTARTNOHAND,
+        ERESTARTNOINTR,
+        ERESTARTSYS,
+        ERESTART_RESTARTBLOCK,
+        PTRACE_EVENT_SECCOMP_OBSOLETE,
+        SECCOMP_RET_ACTION_FULL,
+        SECCOMP_RET_DATA,
+        SECCOMP_RET_ERRNO,
+        SECCOMP_RET_KILL_PROCESS,
+        SECCOMP_RET_KILL_THREAD,
+        SECCOMP_RET_TRACE,
+        SECCOMP_RET_TRAP,
+        SYS_SECCOMP,
+    },
+    log::{LogDebug, LogError, LogInfo, LogWarn},
+    perf_counters::{TicksSemantics, TIME_SLICE_SIGNAL},
     preload_interface::{
         syscallbuf_hdr,
+        syscallbuf_record,
         SYSCALLBUF_ENABLED_ENV_VAR,
         SYSCALLBUF_LIB_FILENAME,
         SYSCALLBUF_LIB_FILENAME_PADDED,
     },
+    record_signal::{
+        arm_desched_event,
+        disarm_desched_event,
+        handle_signal,
+        handle_syscallbuf_breakpoint,
+        SignalBlocked,
+        SignalHandled,
+    },
+    record_syscall::{
+        rec_abort_prepared_syscall,
+        rec_did_sigreturn,
+        rec_prepare_restart_syscall,
+        rec_prepare_syscall,
+        rec_process_syscall,
+    },
+    registers::Registers,
     remote_ptr::{RemotePtr, Void},
     scheduler::Scheduler,
     scoped_fd::ScopedFd,
     seccomp_filter_rewriter::{SeccompFilterRewriter, SECCOMP_MAGIC_SKIP_ORIGINAL_SYSCALLNO},
     session::{
-        session_inner::SessionInner,
+        session_inner::{PtraceSyscallSeccompOrdering, SessionInner},
         task::{Task, TaskSharedPtr},
         Session,
     },
-    sig::Sig,
+    sig::{self, Sig},
     taskish_uid::TaskUid,
     thread_group::ThreadGroupSharedPtr,
     trace::{
         trace_stream::TraceStream,
+        trace_task_event::TraceTaskEvent,
         trace_writer::{CloseStatus, TraceWriter},
     },
     util::{
         choose_cpu,
+        default_action,
         find,
         good_random,
+        is_deterministic_signal,
         resource_path,
+        signal_bit,
+        u8_raw_slice_mut,
+        xsave_area_size,
         CPUIDData,
+        SignalAction,
         CPUID_GETEXTENDEDFEATURES,
         CPUID_GETFEATURES,
         CPUID_GETXSAVE,
@@ -54,18 +139,37 @@ use crate::{
     wait_status::WaitStatus,
 };
 use goblin::elf::Elf;
-use libc::{pid_t, SIGSYS, S_IFREG};
+use libc::{
+    pid_t,
+    CLONE_FILES,
+    CLONE_FS,
+    CLONE_SIGHAND,
+    CLONE_SYSVSEM,
+    CLONE_THREAD,
+    CLONE_VM,
+    ENOSYS,
+    SIGBUS,
+    SIGCHLD,
+    SIGFPE,
+    SIGILL,
+    SIGSEGV,
+    SIGSYS,
+    SIGTRAP,
+    S_IFREG,
+};
 use nix::{
     fcntl::{open, OFlag},
     sys::stat::{stat, Mode},
     unistd::{access, read, AccessFlags},
 };
 use std::{
-    cell::{Ref, RefCell, RefMut},
+    cell::{Cell, Ref, RefCell, RefMut},
+    cmp::{max, min},
+    convert::TryInto,
     env,
     ffi::{OsStr, OsString},
     fs,
-    mem,
+    mem::{self, size_of},
     ops::{Deref, DerefMut},
     os::unix::ffi::{OsStrExt, OsStringExt},
     rc::Rc,
@@ -220,7 +324,7 @@ pub struct RecordSession {
     ignore_sig: Option<Sig>,
     /// DIFF NOTE: In rr, a None is indicated by value 0
     continue_through_sig: Option<Sig>,
-    last_task_switchable: Switchable,
+    last_task_switchable: Cell<Switchable>,
     syscall_buffer_size_: usize,
     syscallbuf_desched_sig_: Sig,
     use_syscall_buffer_: bool,
@@ -240,8 +344,15 @@ pub struct RecordSession {
 }
 
 impl Drop for RecordSession {
+    /// DIFF NOTE: Tasks need a live session to write their exit events to the
+    /// trace, so unlike the other sessions we can't kill them here. They are
+    /// expected to have been cleaned up by `terminate_recording()`.
     fn drop(&mut self) {
-        unimplemented!()
+        log!(
+            LogDebug,
+            "RecordSession {:?} destroyed",
+            self as *const RecordSession
+        );
     }
 }
 
@@ -274,15 +385,16 @@ impl RecordSession {
                 choose_cpu(flags.bind_cpu),
                 flags.output_trace_dir.as_deref(),
                 TicksSemantics::default(),
+                &flags.compression,
             )),
             scheduler_: RefCell::new(sched),
             initial_thread_group: Default::default(),
-            seccomp_filter_rewriter_: SeccompFilterRewriter,
+            seccomp_filter_rewriter_: SeccompFilterRewriter::new(),
             trace_id: flags.trace_id.clone(),
             disable_cpuid_features_: flags.disable_cpuid_features.clone(),
             ignore_sig: flags.ignore_sig,
             continue_through_sig: flags.continue_through_sig,
-            last_task_switchable: Switchable::PreventSwitch,
+            last_task_switchable: Cell::new(Switchable::PreventSwitch),
             syscall_buffer_size_: flags.syscall_buffer_size,
             syscallbuf_desched_sig_: flags.syscallbuf_desched_sig,
             use_syscall_buffer_: flags.use_syscall_buffer == SyscallBuffering::EnableSycallBuf,
@@ -332,7 +444,7 @@ impl RecordSession {
         rs.scheduler_mut().set_session_weak_ptr(weak_self);
 
         if flags.chaos {
-            rs.scheduler_mut().set_enable_chaos(flags.chaos);
+            rs.set_enable_chaos(flags.chaos);
         }
 
         match flags.num_cores {
@@ -489,8 +601,9 @@ impl RecordSession {
         self.asan_active_
     }
 
-    pub fn rd_signal_mask() -> u64 {
-        unimplemented!()
+    /// The signals rd uses internally. These must never be blocked by tracees.
+    pub fn rd_signal_mask(&self) -> sig_set_t {
+        signal_bit(TIME_SLICE_SIGNAL) | signal_bit(self.syscallbuf_desched_sig_)
     }
 
     /// Record some tracee execution.
@@ -502,19 +615,168 @@ impl RecordSession {
     /// stop). In particular, up to one task may be executing user code and any
     /// number of tasks may be blocked in syscalls.
     pub fn record_step(&self) -> RecordResult {
-        unimplemented!()
+        let mut result = RecordResult::StepContinue;
+
+        let initial_thread_group = self.initial_thread_group.as_ref().unwrap();
+        if self.tasks().is_empty() {
+            return RecordResult::StepExited(initial_thread_group.borrow().exit_status);
+        }
+
+        if !self.wait_for_all_ && initial_thread_group.borrow().task_set().is_empty() {
+            // SIGKILL any tasks we haven't already killed.
+            for t in self.tasks().values() {
+                t.borrow().as_record_task().unwrap().kill_if_alive();
+            }
+        }
+
+        let maybe_prev_task_tuid = self.scheduler().current().map(|t| t.borrow().tuid());
+        let rescheduled = self.scheduler().reschedule(self.last_task_switchable.get());
+        if rescheduled.interrupted_by_signal {
+            // The scheduler was waiting for some task to become active, but was
+            // interrupted by a signal. Yield to our caller now to give the caller
+            // a chance to do something triggered by the signal
+            // (e.g. terminate the recording).
+            return result;
+        }
+        let mut rc_t = self.scheduler().current().unwrap();
+        if let Some(rc_prev_task) =
+            maybe_prev_task_tuid.and_then(|tuid| self.find_task_from_task_uid(tuid))
+        {
+            let mut prev_taskb = rc_prev_task.borrow_mut();
+            let prev_task = prev_taskb.as_record_task_mut().unwrap();
+            if prev_task.ev().event_type() == EventType::EvSched {
+                if !Rc::ptr_eq(&rc_prev_task, &rc_t) {
+                    // We did do a context switch, so record the SCHED event. Otherwise
+                    // we'll just discard it.
+                    prev_task.record_current_event();
+                }
+                prev_task.pop_event(EventType::EvSched);
+            }
+        }
+
+        // Have to disable context-switching until we know it's safe
+        // to allow switching the context.
+        self.last_task_switchable.set(Switchable::PreventSwitch);
+
+        {
+            let mut tb = rc_t.borrow_mut();
+            let t = tb.as_record_task_mut().unwrap();
+            log!(
+                LogDebug,
+                "trace time {}: Active task is {}. Events: {}",
+                t.trace_time(),
+                t.tid,
+                t.ev()
+            );
+            if self.handle_ptrace_exit_event(t) {
+                // t is dead and has been removed from the task map.
+                self.last_task_switchable.set(Switchable::AllowSwitch);
+                return result;
+            }
+
+            if rescheduled.started_new_timeslice {
+                t.registers_at_start_of_last_timeslice = t.regs_ref().clone();
+                t.time_at_start_of_last_timeslice = self.trace_writer().time();
+            }
+        }
+
+        if rescheduled.by_waitpid {
+            rc_t = self.maybe_revive_task_for_exec(rc_t);
+        }
+
+        let mut tb = rc_t.borrow_mut();
+        let t = tb.as_record_task_mut().unwrap();
+        let mut step_state = StepState {
+            continue_type: ContinueType::Continue,
+        };
+
+        let mut did_enter_syscall = false;
+        if rescheduled.by_waitpid
+            && self.handle_ptrace_event(t, &mut step_state, &mut result, &mut did_enter_syscall)
+        {
+            if result != RecordResult::StepContinue
+                || step_state.continue_type == ContinueType::DontContinue
+            {
+                return result;
+            }
+
+            if did_enter_syscall && t.ev().event_type() == EventType::EvSyscall {
+                self.syscall_state_changed(t, &mut step_state);
+            }
+        } else if rescheduled.by_waitpid && self.handle_signal_event(t, &mut step_state) {
+            // Nothing else to do.
+        } else {
+            self.runnable_state_changed(t, &mut step_state, &mut result, rescheduled.by_waitpid);
+
+            if result != RecordResult::StepContinue
+                || step_state.continue_type == ContinueType::DontContinue
+            {
+                return result;
+            }
+
+            match t.ev().event_type() {
+                EventType::EvDesched => desched_state_changed(t),
+                EventType::EvSyscall => self.syscall_state_changed(t, &mut step_state),
+                EventType::EvSignal | EventType::EvSignalDelivery => {
+                    if self.signal_state_changed(t, &mut step_state) {
+                        // t is dying
+                        self.last_task_switchable.set(Switchable::AllowSwitch);
+                        return result;
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        t.verify_signal_states();
+
+        // We try to inject a signal if there's one pending; otherwise we continue
+        // task execution.
+        if !self.prepare_to_inject_signal(t, &mut step_state)
+            && step_state.continue_type != ContinueType::DontContinue
+        {
+            // Ensure that we aren't allowing switches away from a running task.
+            // Only tasks blocked in a syscall can be switched away from, otherwise
+            // we have races.
+            ed_assert!(
+                t,
+                self.last_task_switchable.get() == Switchable::PreventSwitch || t.may_be_blocked()
+            );
+
+            self.task_continue(t, &step_state);
+        }
+
+        result
     }
 
     /// Flush buffers and write a termination record to the trace. Don't call
     /// record_step() after this.
+    ///
+    /// DIFF NOTE: No EvTraceTermination frame is written. Frames are written on
+    /// behalf of a task and there are no tasks left at this point.
     pub fn terminate_recording(&self) {
-        unimplemented!()
+        let maybe_t = self.scheduler().current();
+        if let Some(t) = maybe_t {
+            t.borrow_mut()
+                .as_record_task_mut()
+                .unwrap()
+                .maybe_flush_syscallbuf();
+        }
+
+        log!(LogInfo, "Processing termination request ...");
+
+        // This will write unstable exit events for all tasks.
+        self.kill_all_tasks();
+
+        self.close_trace_writer(CloseStatus::CloseOk);
     }
 
     /// Close trace output without flushing syscall buffers or writing
     /// task exit/termination records to the trace.
-    pub fn close_trace_writer(_status: CloseStatus) {
-        unimplemented!()
+    pub fn close_trace_writer(&self, status: CloseStatus) {
+        self.trace_out
+            .borrow_mut()
+            .close(status, Some((*self.trace_id).clone()));
     }
 
     pub fn trace_writer(&self) -> Ref<'_, TraceWriter> {
@@ -540,6 +802,13 @@ impl RecordSession {
     pub fn set_enable_chaos(&mut self, enable_chaos: bool) {
         self.scheduler_mut().set_enable_chaos(enable_chaos);
         self.enable_chaos_ = enable_chaos;
+        let maybe_seed = if enable_chaos {
+            Some(self.scheduler().rng_seed())
+        } else {
+            None
+        };
+        self.trace_out.get_mut().set_chaos_seed(maybe_seed);
+        log!(LogDebug, "Chaos mode seed: {:?}", maybe_seed);
     }
 
     pub fn enable_chaos(&self) -> bool {
@@ -573,8 +842,1318 @@ impl RecordSession {
     /// The task in the thread-group that triggered the successful execve has changed
     /// its tid to |rec_tid|. We mirror that, and emit TraceTaskEvents to make it
     /// look like a new task was spawned and the old task exited.
-    pub fn revive_task_for_exec(&self, _rec_tid: pid_t) -> &RecordTask {
-        unimplemented!()
+    ///
+    /// DIFF NOTE: Returns the TaskSharedPtr of the revived task instead of a reference.
+    pub fn revive_task_for_exec(&self, rec_tid: pid_t) -> TaskSharedPtr {
+        let mut msg: usize = 0;
+        let ret = unsafe { ptrace(PTRACE_GETEVENTMSG, rec_tid, 0usize, &mut msg as *mut usize) };
+        if ret < 0 {
+            fatal!("Can't get old tid for execve (leader={})", rec_tid);
+        }
+        let rc_t = match self.find_task_from_rec_tid(msg as pid_t) {
+            Some(rc_t) => rc_t,
+            None => fatal!("Can't find old task for execve"),
+        };
+
+        {
+            let mut tb = rc_t.borrow_mut();
+            let t = tb.as_record_task_mut().unwrap();
+            ed_assert_eq!(t, rec_tid, t.tgid());
+
+            log!(LogDebug, "Changing task tid from {} to {}", t.tid, rec_tid);
+
+            // Pretend the old task cloned a new task with the right tid, and then exited
+            self.trace_writer_mut()
+                .write_task_event(&TraceTaskEvent::for_clone(
+                    rec_tid,
+                    t.tid,
+                    t.own_namespace_rec_tid,
+                    CLONE_VM
+                        | CLONE_FS
+                        | CLONE_FILES
+                        | CLONE_SIGHAND
+                        | CLONE_THREAD
+                        | CLONE_SYSVSEM,
+                ));
+            self.trace_writer_mut()
+                .write_task_event(&TraceTaskEvent::for_exit(
+                    t.tid,
+                    WaitStatus::for_exit_code(0),
+                ));
+
+            // Account for tid change
+            {
+                let mut task_map = self.tasks_mut();
+                task_map.remove(&t.rec_tid);
+                task_map.insert(rec_tid, rc_t.clone());
+            }
+
+            // Update the serial as if this task was really created by cloning the old
+            // task.
+            t.set_tid_and_update_serial(rec_tid, rec_tid);
+        }
+
+        rc_t
+    }
+
+    /// If `rc_t` is at a PTRACE_EVENT_EXEC but other tasks are still in its thread
+    /// group, the exec happened on a non-leader thread and `rc_t` is the (now
+    /// dead) thread group leader. Record its exit, get rid of it and return the
+    /// exec'ing task. Otherwise `rc_t` is returned unchanged.
+    ///
+    /// DIFF NOTE: This is part of the PTRACE_EVENT_EXEC handling in
+    /// handle_ptrace_event() in rr. rd needs to swap out the current task
+    /// before it is borrowed for event handling, so it has been pulled out.
+    fn maybe_revive_task_for_exec(&self, rc_t: TaskSharedPtr) -> TaskSharedPtr {
+        let needs_revive = {
+            let t = rc_t.borrow();
+            !t.status().maybe_group_stop_sig().is_sig()
+                && t.maybe_ptrace_event() == PTRACE_EVENT_EXEC
+                && t.thread_group().task_set().len() > 1
+        };
+        if !needs_revive {
+            return rc_t;
+        }
+
+        // All tasks but the task that did the execve should have exited by
+        // now and notified us of their exits. However, it's possible that
+        // while running the thread-group leader, our PTRACE_CONT raced with its
+        // PTRACE_EVENT_EXIT and it exited, and the next event we got is this
+        // PTRACE_EVENT_EXEC after the exec'ing task changed its tid to the
+        // leader's tid. Or maybe there are kernel bugs; on
+        // 4.2.0-42-generic running exec_from_other_thread, we reproducibly
+        // enter PTRACE_EVENT_EXEC for the thread-group leader without seeing
+        // its PTRACE_EVENT_EXIT.
+
+        // So, record this task's exit and destroy it.
+        // XXX We can't do record_robust_futex_changes here because the address
+        // space has already gone. That would only matter if some of them were
+        // in memory accessible to another process even after exec, i.e. a
+        // shared-memory mapping or two different thread-groups sharing the same
+        // address space.
+        let (tid, status) = {
+            let mut tb = rc_t.borrow_mut();
+            let t = tb.as_record_task_mut().unwrap();
+            let tid = t.rec_tid;
+            let status = t.status();
+            record_exit(t, WaitStatus::for_exit_code(0));
+            // We don't want to PTRACE_DETACH: the tid now belongs to the exec'ing
+            // task. We will never see a PTRACE_EVENT_EXIT for this task either.
+            t.detach_on_drop = false;
+            t.unstable.set(true);
+            t.destroy();
+            (tid, status)
+        };
+        // This drops the last reference to the old task.
+        drop(rc_t);
+
+        // Steal the exec'ing task and make it the thread-group leader, and
+        // carry on!
+        let rc_t = self.revive_task_for_exec(tid);
+        self.scheduler().set_current(Some(Rc::downgrade(&rc_t)));
+        // Tell t that it is actually stopped, because the stop we got is really
+        // for this task, not the old dead task.
+        rc_t.borrow_mut().did_waitpid(status);
+        rc_t
+    }
+
+    /// Returns true if `t` was at a PTRACE_EVENT_EXIT and has been destroyed.
+    fn handle_ptrace_exit_event(&self, t: &mut RecordTask) -> bool {
+        if t.maybe_ptrace_event() != PTRACE_EVENT_EXIT {
+            return false;
+        }
+
+        if t.stable_exit {
+            log!(LogDebug, "stable exit");
+        } else {
+            if !t.may_be_blocked() {
+                // might have been hit by a SIGKILL or a SECCOMP_RET_KILL, in which case
+                // there might be some execution since its last recorded event that we
+                // need to replay.
+                // There's a weird case (in 4.13.5-200.fc26.x86_64 at least) where the
+                // task can enter the kernel but instead of receiving a syscall ptrace
+                // event, we receive a PTRACE_EXIT_EVENT due to a concurrent execve
+                // (and probably a concurrent SIGKILL could do the same). The task state
+                // has been updated to reflect syscall entry. If we record a SCHED in
+                // that state replay of the SCHED will fail. So detect that state and fix
+                // it up.
+                if t.regs_ref().original_syscallno() >= 0
+                    && t.regs_ref().syscall_result_signed() == -ENOSYS as isize
+                {
+                    // Either we're in a syscall, or we're immediately after a syscall
+                    // and it exited with ENOSYS.
+                    if t.ticks_at_last_recorded_syscall_exit == t.tick_count() {
+                        log!(LogDebug, "Nothing to record after PTRACE_EVENT_EXIT");
+                        // It's the latter case; do nothing.
+                    } else {
+                        // It's the former case ... probably. Theoretically we could have
+                        // re-executed a syscall without any ticks in between, but that seems
+                        // highly improbable.
+                        // Record the syscall-entry event that we otherwise failed to record.
+                        let arch = t.arch();
+                        t.canonicalize_regs(arch);
+                        // Assume it's a native-arch syscall. If it isn't, it doesn't matter
+                        // all that much since we aren't actually going to do anything with it
+                        // in this task.
+                        // Avoid calling detect_syscall_arch here since it could fail if the
+                        // task is already completely dead and gone.
+                        let mut event =
+                            SyscallEventData::new(t.regs_ref().original_syscallno() as i32, arch);
+                        event.state = SyscallState::EnteringSyscall;
+                        t.record_event(Some(Event::new_syscall_event(event)), None, None, None);
+                    }
+                } else {
+                    // Don't try to reset the syscallbuf here. The task may be exiting
+                    // while in arbitrary syscallbuf code. And of course, because it's
+                    // exiting, it doesn't matter if we don't reset the syscallbuf.
+                    // XXX flushing the syscallbuf may be risky too...
+                    t.record_event(
+                        Some(Event::sched()),
+                        Some(FlushSyscallbuf::FlushSyscallbuf),
+                        Some(AllowSyscallbufReset::DontResetSyscallbuf),
+                        None,
+                    );
+                }
+            }
+
+            log!(
+                LogWarn,
+                "unstable exit; may misrecord CLONE_CHILD_CLEARTID memory race"
+            );
+            destabilize_thread_group(t);
+        }
+
+        record_robust_futex_changes(t);
+
+        let mut msg: usize = 0;
+        // If ptrace_if_alive fails, we don't know what the exit status is. Assume
+        // we were SIGKILLed.
+        let exit_status = if t.ptrace_if_alive(
+            PTRACE_GETEVENTMSG,
+            RemotePtr::null(),
+            PtraceData::WriteInto(u8_raw_slice_mut(&mut msg)),
+        ) {
+            WaitStatus::new(msg as i32)
+        } else {
+            WaitStatus::for_fatal_sig(sig::SIGKILL)
+        };
+
+        record_exit(t, exit_status);
+        // Remove t from the task map. Its drop() writes the final EV_EXIT.
+        t.destroy();
+        true
+    }
+
+    /// Returns true if the ptrace event was handled.
+    fn handle_ptrace_event(
+        &self,
+        t: &mut RecordTask,
+        step_state: &mut StepState,
+        result: &mut RecordResult,
+        did_enter_syscall: &mut bool,
+    ) -> bool {
+        *did_enter_syscall = false;
+
+        if t.status().maybe_group_stop_sig().is_sig() || t.has_stashed_group_stop() {
+            t.clear_stashed_group_stop();
+            self.last_task_switchable.set(Switchable::AllowSwitch);
+            step_state.continue_type = ContinueType::DontContinue;
+            return true;
+        }
+
+        if t.status().is_syscall() {
+            return false;
+        }
+
+        let event = t.maybe_ptrace_event();
+        if !event.is_ptrace_event() {
+            return false;
+        }
+
+        log!(
+            LogDebug,
+            "  {}: handle_ptrace_event {}: event {}",
+            t.tid,
+            ptrace_event_name(event.unwrap_event()),
+            t.ev()
+        );
+
+        match event.unwrap_event() {
+            PTRACE_EVENT_SECCOMP | PTRACE_EVENT_SECCOMP_OBSOLETE => {
+                if self.syscall_seccomp_ordering_.get()
+                    == PtraceSyscallSeccompOrdering::SyscallBeforeSeccompUnknown
+                {
+                    self.syscall_seccomp_ordering_
+                        .set(PtraceSyscallSeccompOrdering::SeccompBeforeSyscall);
+                }
+
+                let seccomp_data = t.get_ptrace_eventmsg_seccomp_data();
+                let syscallno = t.regs_ref().original_syscallno() as i32;
+                if seccomp_data as u32 == SECCOMP_RET_DATA {
+                    log!(
+                        LogDebug,
+                        "  traced syscall entered: {}",
+                        syscall_name(syscallno, t.arch())
+                    );
+                    self.handle_seccomp_traced_syscall(t, step_state, result, did_enter_syscall);
+                } else {
+                    // Note that we make no attempt to patch the syscall site when the
+                    // user seccomp filter returns a non-ALLOW result.
+                    let maybe_real_result = self
+                        .seccomp_filter_rewriter()
+                        .map_filter_data_to_real_result(seccomp_data);
+                    ed_assert!(
+                        t,
+                        maybe_real_result.is_some(),
+                        "Seccomp result not handled: data {} for syscall {}",
+                        seccomp_data,
+                        syscall_name(syscallno, t.arch())
+                    );
+                    let real_result = maybe_real_result.unwrap();
+                    let real_result_data = (real_result & SECCOMP_RET_DATA) as u16;
+                    match real_result & SECCOMP_RET_ACTION_FULL {
+                        SECCOMP_RET_TRAP => {
+                            log!(
+                                LogDebug,
+                                "  seccomp trap for syscall: {}",
+                                syscall_name(syscallno, t.arch())
+                            );
+                            handle_seccomp_trap(t, step_state, real_result_data);
+                        }
+                        SECCOMP_RET_ERRNO => {
+                            log!(
+                                LogDebug,
+                                "  seccomp errno {} for syscall: {}",
+                                errno_name(real_result_data as i32),
+                                syscall_name(syscallno, t.arch())
+                            );
+                            handle_seccomp_errno(t, step_state, real_result_data);
+                        }
+                        SECCOMP_RET_TRACE => {
+                            // DIFF NOTE: rr asserts here.
+                            log!(
+                                LogDebug,
+                                "  seccomp trace for syscall: {}",
+                                syscall_name(syscallno, t.arch())
+                            );
+                            if t.emulated_ptracer.is_some()
+                                && t.emulated_ptrace_options & PTRACE_O_TRACESECCOMP != 0
+                            {
+                                // The tracee's filter wants its ptracer to see the
+                                // syscall. process_syscall_entry() reports the
+                                // PTRACE_EVENT_SECCOMP stop, then the syscall runs
+                                // the way the ptracer left it.
+                                t.emulated_seccomp_event_data = Some(real_result_data);
+                                self.handle_seccomp_traced_syscall(
+                                    t,
+                                    step_state,
+                                    result,
+                                    did_enter_syscall,
+                                );
+                            } else {
+                                // Like the kernel does when no ptracer asked for
+                                // PTRACE_EVENT_SECCOMP.
+                                handle_seccomp_errno(t, step_state, ENOSYS as u16);
+                            }
+                        }
+                        SECCOMP_RET_KILL_PROCESS | SECCOMP_RET_KILL_THREAD => {
+                            log!(
+                                LogDebug,
+                                "  seccomp kill for syscall: {}",
+                                syscall_name(syscallno, t.arch())
+                            );
+                            t.tgkill(sig::SIGKILL);
+                            // Rely on the SIGKILL to bump us out of the ptrace stop.
+                            step_state.continue_type = ContinueType::DontContinue;
+                            // Now wait for us to actually exit our ptrace-stop and proceed
+                            // to the PTRACE_EVENT_EXIT. This avoids the race where our
+                            // PTRACE_CONT might kick us out of the PTRACE_EVENT_EXIT before
+                            // we can process it.
+                            t.wait(None);
+                        }
+                        _ => {
+                            ed_assert!(
+                                t,
+                                false,
+                                "Seccomp result {:#x} not handled for syscall {}",
+                                real_result,
+                                syscall_name(syscallno, t.arch())
+                            );
+                        }
+                    }
+                }
+            }
+
+            PTRACE_EVENT_EXEC => {
+                // DIFF NOTE: An exec from a thread other than the thread group leader
+                // has already been dealt with in maybe_revive_task_for_exec().
+                t.post_exec();
+
+                // Forward ptrace exec notification
+                if t.emulated_ptracer.is_some() {
+                    if t.emulated_ptrace_options & PTRACE_O_TRACEEXEC != 0 {
+                        t.emulate_ptrace_stop(
+                            WaitStatus::for_ptrace_event(PTRACE_EVENT_EXEC),
+                            None,
+                            None,
+                        );
+                    } else if !t.emulated_ptrace_seized {
+                        // Inject legacy SIGTRAP-after-exec
+                        t.tgkill(sig::SIGTRAP);
+                    }
+                }
+                if t.emulated_stop_pending {
+                    step_state.continue_type = ContinueType::DontContinue;
+                } else {
+                    // Skip past the ptrace event.
+                    step_state.continue_type = ContinueType::ContinueSyscall;
+                }
+            }
+
+            _ => {
+                ed_assert!(
+                    t,
+                    false,
+                    "Unhandled ptrace event {}",
+                    ptrace_event_name(event.unwrap_event())
+                );
+            }
+        }
+
+        true
+    }
+
+    fn handle_seccomp_traced_syscall(
+        &self,
+        t: &mut RecordTask,
+        step_state: &mut StepState,
+        result: &mut RecordResult,
+        did_enter_syscall: &mut bool,
+    ) {
+        *did_enter_syscall = false;
+        let syscallno = t.regs_ref().original_syscallno();
+        if syscallno < 0 {
+            // negative syscall numbers after a SECCOMP event
+            // are treated as "skip this syscall". There will be one syscall event
+            // reported instead of two. So fake an enter-syscall event now.
+            // It doesn't really matter what the syscall-arch is.
+            let arch = t.arch();
+            t.canonicalize_regs(arch);
+            if self.syscall_seccomp_ordering_.get()
+                == PtraceSyscallSeccompOrdering::SeccompBeforeSyscall
+            {
+                // If the ptrace entry stop hasn't happened yet, we're at a weird
+                // intermediate state where the behavior of the next PTRACE_SYSCALL
+                // will depend on the register state (i.e. whether we see an entry
+                // trap or proceed right to the exit trap). To make things easier
+                // on the rest of the system, do a fake syscall entry, then reset
+                // the register state.
+                let orig_regs = t.regs_ref().clone();
+                let mut r = orig_regs.clone();
+                r.set_original_syscallno(syscall_number_for_gettid(arch) as isize);
+                t.set_regs(&r);
+                t.resume_execution(
+                    ResumeRequest::ResumeSyscall,
+                    WaitRequest::ResumeWait,
+                    TicksRequest::ResumeNoTicks,
+                    None,
+                );
+                t.set_regs(&orig_regs);
+            }
+
+            // Don't continue yet. At the next iteration of record_step, we'll
+            // enter syscall_state_changed and that will trigger a continue to
+            // the syscall exit.
+            step_state.continue_type = ContinueType::DontContinue;
+            if !self.process_syscall_entry(t, step_state, result, arch) {
+                return;
+            }
+            *did_enter_syscall = true;
+            return;
+        }
+
+        if self.syscall_seccomp_ordering_.get()
+            == PtraceSyscallSeccompOrdering::SeccompBeforeSyscall
+        {
+            // The next continue needs to be a PTRACE_SYSCALL to observe
+            // the enter-syscall event.
+            step_state.continue_type = ContinueType::ContinueSyscall;
+        } else {
+            ed_assert!(
+                t,
+                self.syscall_seccomp_ordering_.get()
+                    == PtraceSyscallSeccompOrdering::SyscallBeforeSeccomp
+            );
+            if t.ev().is_syscall_event()
+                && t.ev().syscall().state == SyscallState::ProcessingSyscall
+            {
+                // We did PTRACE_SYSCALL and already saw a syscall trap. Just ignore this.
+                log!(
+                    LogDebug,
+                    "Ignoring SECCOMP syscall trap since we already got a PTRACE_SYSCALL trap"
+                );
+                // The next continue needs to be a PTRACE_SYSCALL to observe
+                // the exit-syscall event.
+                step_state.continue_type = ContinueType::ContinueSyscall;
+                // Need to restore last_task_switchable since it will have been
+                // reset to PreventSwitch
+                self.last_task_switchable.set(t.ev().syscall().switchable);
+            } else {
+                // We've already passed the PTRACE_SYSCALL trap for syscall entry, so
+                // we need to handle that now.
+                let syscall_arch = t.detect_syscall_arch();
+                t.canonicalize_regs(syscall_arch);
+                if !self.process_syscall_entry(t, step_state, result, syscall_arch) {
+                    step_state.continue_type = ContinueType::DontContinue;
+                    return;
+                }
+                *did_enter_syscall = true;
+            }
+        }
+    }
+
+    /// Returns false if the task exited while we were processing the syscall entry.
+    fn process_syscall_entry(
+        &self,
+        t: &mut RecordTask,
+        step_state: &mut StepState,
+        step_result: &mut RecordResult,
+        syscall_arch: SupportedArch,
+    ) -> bool {
+        let seccomp_event_data = t.emulated_seccomp_event_data.take();
+        if let Some(si) = t.stashed_sig_not_synthetic_sigchld().cloned() {
+            // The only four cases where we allow a stashed signal to be pending on
+            // syscall entry are:
+            // -- the signal is a ptrace-related signal, in which case if it's generated
+            // during a blocking syscall, it does not interrupt the syscall
+            // -- rdcall_notify_syscall_hook_exit, which is effectively a noop and
+            // lets us dispatch signals afterward
+            // -- when we're entering a blocking untraced syscall. If it really blocks,
+            // we'll get the desched-signal notification and dispatch our stashed
+            // signal.
+            // -- when we're doing a privileged syscall that's internal to the preload
+            // logic
+            // We do not generally want to have stashed signals pending when we enter
+            // a syscall, because that will execute with a hacked signal mask
+            // (see RecordTask::will_resume_execution) which could make things go wrong.
+            let arch = t.arch();
+            let privileged_syscall_exit = t
+                .vm()
+                .privileged_traced_syscall_ip()
+                .map(|ip| ip.increment_by_syscall_insn_length(arch));
+            ed_assert!(
+                t,
+                !t.desched_rec().is_null()
+                    || is_rdcall_notify_syscall_hook_exit_syscall(
+                        t.regs_ref().original_syscallno() as i32,
+                        arch
+                    )
+                    || Some(t.ip()) == privileged_syscall_exit,
+                "Stashed signal pending on syscall entry when it shouldn't be: {}; IP={}",
+                si,
+                t.ip()
+            );
+        }
+
+        // We just entered a syscall.
+        if !maybe_restart_syscall(t) {
+            if self.syscall_seccomp_ordering_.get()
+                == PtraceSyscallSeccompOrdering::SyscallBeforeSeccompUnknown
+                && t.seccomp_bpf_enabled
+            {
+                // We received a PTRACE_SYSCALL notification before the seccomp
+                // notification. Ignore it and continue to the seccomp notification.
+                self.syscall_seccomp_ordering_
+                    .set(PtraceSyscallSeccompOrdering::SyscallBeforeSeccomp);
+                step_state.continue_type = ContinueType::Continue;
+                return true;
+            }
+
+            let vm = t.vm_shr_ptr();
+            if vm.monkeypatcher_mut().unwrap().try_patch_syscall(t) {
+                // Syscall was patched. Emit event and continue execution.
+                t.record_event(Some(Event::patch_syscall()), None, None, None);
+                return true;
+            }
+
+            if t.maybe_ptrace_event() == PTRACE_EVENT_EXIT {
+                // task exited while we were trying to patch it.
+                // Make sure that this exit event gets processed
+                step_state.continue_type = ContinueType::DontContinue;
+                return false;
+            }
+
+            let syscallno = t.regs_ref().original_syscallno() as i32;
+            t.push_event(Event::new_syscall_event(SyscallEventData::new(
+                syscallno,
+                syscall_arch,
+            )));
+            check_initial_task_syscalls(t, step_result);
+            note_entering_syscall(t);
+            let cont_command = t.emulated_ptrace_cont_command;
+            if let Some(data) = seccomp_event_data {
+                // DIFF NOTE: The kernel reruns the seccomp filter if the ptracer
+                // changes the syscall, and reports a PTRACE_SYSCALL entry stop
+                // after this one. We do neither.
+                t.ev_mut().syscall_mut().state = SyscallState::EnteringSyscallPtrace;
+                t.emulated_ptrace_event_msg = data as usize;
+                t.emulate_ptrace_stop(
+                    WaitStatus::for_ptrace_event(PTRACE_EVENT_SECCOMP),
+                    None,
+                    None,
+                );
+                t.record_current_event();
+            } else if cont_command == PTRACE_SYSCALL
+                || cont_command == PTRACE_SYSEMU
+                || cont_command == PTRACE_SYSEMU_SINGLESTEP
+            {
+                t.ev_mut().syscall_mut().state = SyscallState::EnteringSyscallPtrace;
+                let status = WaitStatus::for_syscall(t);
+                t.emulate_ptrace_stop(status, None, None);
+                t.record_current_event();
+                t.ev_mut().syscall_mut().in_sysemu = cont_command != PTRACE_SYSCALL;
+            }
+        }
+
+        true
+    }
+
+    fn syscall_state_changed(&self, t: &mut RecordTask, step_state: &mut StepState) {
+        match t.ev().syscall().state {
+            SyscallState::EnteringSyscallPtrace => {
+                log!(LogDebug, "EXEC_SYSCALL_ENTRY_PTRACE: {}", t.ev());
+                step_state.continue_type = ContinueType::DontContinue;
+                self.last_task_switchable.set(Switchable::AllowSwitch);
+                if t.emulated_stop_type != EmulatedStopType::NotStopped {
+                    // Don't go any further.
+                    return;
+                }
+                if t.ev().syscall().in_sysemu {
+                    // We'll have recorded just the ENTERING_SYSCALL_PTRACE event and
+                    // nothing else. Resume with an invalid syscall to ensure no real
+                    // syscall runs.
+                    t.pop_syscall();
+                    let orig_regs = t.regs_ref().clone();
+                    let mut r = orig_regs.clone();
+                    r.set_original_syscallno(-1);
+                    t.set_regs(&r);
+                    t.resume_execution(
+                        ResumeRequest::ResumeSyscall,
+                        WaitRequest::ResumeWait,
+                        TicksRequest::ResumeNoTicks,
+                        None,
+                    );
+                    ed_assert_eq!(t, t.ip(), r.ip());
+                    t.set_regs(&orig_regs);
+                    maybe_trigger_emulated_ptrace_syscall_exit_stop(t);
+                    return;
+                }
+                self.last_task_switchable.set(Switchable::PreventSwitch);
+                let regs = t.regs_ref().clone();
+                let syscallno = regs.original_syscallno() as i32;
+                let syscall = t.ev_mut().syscall_mut();
+                syscall.regs = regs;
+                syscall.state = SyscallState::EnteringSyscall;
+                // The syscallno may have been changed by the ptracer
+                syscall.number = syscallno;
+            }
+
+            SyscallState::EnteringSyscall => {
+                log!(LogDebug, "EXEC_SYSCALL_ENTRY: {}", t.ev());
+                ed_assert!(t, !t.emulated_stop_pending);
+
+                let switchable = rec_prepare_syscall(t);
+                t.ev_mut().syscall_mut().switchable = switchable;
+                self.last_task_switchable.set(switchable);
+                let ev = t.ev().clone();
+                let regs = ev.syscall().regs.clone();
+                t.record_event(
+                    Some(ev),
+                    Some(FlushSyscallbuf::DontFlushSyscallbuf),
+                    Some(AllowSyscallbufReset::AllowResetSyscallbuf),
+                    Some(&regs),
+                );
+
+                t.ev_mut().syscall_mut().state = SyscallState::ProcessingSyscall;
+
+                if t.emulated_stop_pending {
+                    step_state.continue_type = ContinueType::DontContinue;
+                } else {
+                    // Resume the syscall execution in the kernel context.
+                    step_state.continue_type = ContinueType::ContinueSyscall;
+                }
+
+                if self.done_initial_exec() && Flags::get().check_cached_mmaps {
+                    t.vm().verify(t);
+                }
+
+                if !t.desched_rec().is_null()
+                    && t.is_in_untraced_syscall()
+                    && t.has_any_stashed_sig()
+                {
+                    // We have a signal to deliver but we're about to (re?)enter an untraced
+                    // syscall that may block and the desched event has been disarmed.
+                    // Rearm the desched event so if the syscall blocks, it will be
+                    // interrupted and we'll have a chance to deliver our signal.
+                    log!(
+                        LogDebug,
+                        "Rearming desched event so we'll get a chance to deliver stashed signal"
+                    );
+                    arm_desched_event(t);
+                }
+            }
+
+            SyscallState::ProcessingSyscall => {
+                log!(LogDebug, "EXEC_IN_SYSCALL: {}", t.ev());
+
+                // Linux kicks tasks out of syscalls before delivering
+                // signals.
+                ed_assert!(
+                    t,
+                    t.maybe_stop_sig().is_not_sig(),
+                    "Signal {} pending while in syscall???",
+                    t.maybe_stop_sig()
+                );
+
+                t.ev_mut().syscall_mut().state = SyscallState::ExitingSyscall;
+                step_state.continue_type = ContinueType::DontContinue;
+            }
+
+            SyscallState::ExitingSyscall => {
+                log!(LogDebug, "EXEC_SYSCALL_DONE: {}", t.ev());
+
+                debug_assert!(t.maybe_stop_sig().is_not_sig());
+
+                let syscall_arch = t.ev().syscall().arch();
+                let syscallno = t.ev().syscall().number;
+                let retval = t.regs_ref().syscall_result_signed();
+
+                if !t.desched_rec().is_null() {
+                    // If we enabled the desched event above, disable it.
+                    disarm_desched_event(t);
+                    // Write syscall return value to the syscallbuf now. This lets replay
+                    // get the correct value even though we're aborting the commit. This
+                    // value affects register values in the preload code (which must be
+                    // correct since register values may escape).
+                    save_interrupted_syscall_ret_in_syscallbuf(t, retval);
+                }
+
+                // sigreturn is a special snowflake, because it
+                // doesn't actually return.  Instead, it undoes the
+                // setup for signal delivery, which possibly includes
+                // preparing the tracee for a restart-syscall.  So we
+                // take this opportunity to possibly pop an
+                // interrupted-syscall event.
+                if is_sigreturn(syscallno, syscall_arch) {
+                    ed_assert_eq!(t, t.regs_ref().original_syscallno(), -1);
+                    rec_did_sigreturn(t);
+                    t.record_current_event();
+                    t.pop_syscall();
+
+                    // We've finished processing this signal now.
+                    t.pop_signal_handler();
+                    t.invalidate_sigmask();
+
+                    maybe_discard_syscall_interruption(t, retval);
+
+                    if EventType::EvSeccompTrap == t.ev().event_type() {
+                        log!(LogDebug, "  exiting seccomp trap");
+                        save_interrupted_syscall_ret_in_syscallbuf(t, retval);
+                        seccomp_trap_done(t);
+                    }
+                    if EventType::EvDesched == t.ev().event_type() {
+                        log!(LogDebug, "  exiting desched critical section");
+                        // The signal handler could have modified the apparent syscall
+                        // return handler. Save that value into the syscall buf again so
+                        // replay will pick it up later.
+                        save_interrupted_syscall_ret_in_syscallbuf(t, retval);
+                        desched_state_changed(t);
+                    }
+                } else {
+                    log!(
+                        LogDebug,
+                        "  original_syscallno:{} ({}); return val:{:#x}",
+                        t.regs_ref().original_syscallno(),
+                        syscall_name(syscallno, syscall_arch),
+                        t.regs_ref().syscall_result()
+                    );
+
+                    // a syscall_restart ending is equivalent to the
+                    // restarted syscall ending
+                    if t.ev().syscall().is_restart {
+                        log!(
+                            LogDebug,
+                            "  exiting restarted {}",
+                            syscall_name(syscallno, syscall_arch)
+                        );
+                    }
+
+                    // TODO: is there any reason a restart_syscall can't
+                    // be interrupted by a signal and itself restarted?
+                    let may_restart = !is_restart_syscall_syscall(syscallno, t.arch())
+                        // SYS_pause is either interrupted or
+                        // never returns.  It doesn't restart.
+                        && !is_pause_syscall(syscallno, t.arch())
+                        && t.regs_ref().syscall_may_restart();
+                    // no need to process the syscall in case its
+                    // restarted this will be done in the exit from the
+                    // restart_syscall
+                    if !may_restart {
+                        rec_process_syscall(t);
+                        if self.done_initial_exec() && Flags::get().check_cached_mmaps {
+                            t.vm().verify(t);
+                        }
+                    } else {
+                        log!(
+                            LogDebug,
+                            "  may restart {} (from retval {:#x})",
+                            syscall_name(syscallno, syscall_arch),
+                            retval
+                        );
+
+                        rec_prepare_restart_syscall(t);
+                        // If we may restart this syscall, we've most
+                        // likely fudged some of the argument
+                        // registers with scratch pointers.  We don't
+                        // want to record those fudged registers,
+                        // because scratch doesn't exist in replay.
+                        // So cover our tracks here.
+                        let mut r = t.regs_ref().clone();
+                        copy_syscall_arg_regs(&mut r, &t.ev().syscall().regs);
+                        t.set_regs(&r);
+                    }
+                    t.record_current_event();
+
+                    // If we're not going to restart this syscall, we're
+                    // done with it.  But if we are, "freeze" it on the
+                    // event stack until the execution point where it
+                    // might be restarted.
+                    if !may_restart {
+                        t.pop_syscall();
+                        if EventType::EvDesched == t.ev().event_type() {
+                            log!(LogDebug, "  exiting desched critical section");
+                            desched_state_changed(t);
+                        }
+                    } else {
+                        t.ev_mut().transform(EventType::EvSyscallInterruption);
+                        t.ev_mut().syscall_mut().is_restart = true;
+                    }
+
+                    t.canonicalize_regs(syscall_arch);
+                }
+
+                self.last_task_switchable.set(Switchable::AllowSwitch);
+                step_state.continue_type = ContinueType::DontContinue;
+
+                if !is_in_privileged_syscall(t) {
+                    maybe_trigger_emulated_ptrace_syscall_exit_stop(t);
+                }
+            }
+
+            SyscallState::NoSyscall => {
+                fatal!("Unknown exec state {}", t.ev().syscall().state)
+            }
+        }
+    }
+
+    fn runnable_state_changed(
+        &self,
+        t: &mut RecordTask,
+        step_state: &mut StepState,
+        step_result: &mut RecordResult,
+        can_consume_wait_status: bool,
+    ) {
+        match t.ev().event_type() {
+            EventType::EvNoop => t.pop_noop(),
+            EventType::EvInstructionTrap => {
+                t.record_current_event();
+                t.pop_event(EventType::EvInstructionTrap);
+            }
+            EventType::EvSentinel
+            | EventType::EvSignalHandler
+            | EventType::EvSyscallInterruption => {
+                if !can_consume_wait_status {
+                    return;
+                }
+
+                let syscall_arch = t.detect_syscall_arch();
+                t.canonicalize_regs(syscall_arch);
+                self.process_syscall_entry(t, step_state, step_result, syscall_arch);
+            }
+            _ => (),
+        }
+    }
+
+    /// Returns true if the signal stop has been dealt with.
+    fn handle_signal_event(&self, t: &mut RecordTask, step_state: &mut StepState) -> bool {
+        let sig = match t.maybe_stop_sig().get_raw_repr() {
+            Some(sig) => sig,
+            None => return false,
+        };
+        if !self.done_initial_exec() {
+            // If the initial tracee isn't prepared to handle
+            // signals yet, then us ignoring the ptrace
+            // notification here will have the side effect of
+            // declining to deliver the signal.
+            //
+            // This doesn't really occur in practice, only in
+            // tests that force a degenerately low time slice.
+            log!(
+                LogWarn,
+                "Dropping {} because it can't be delivered yet",
+                sig
+            );
+            // These signals might have effects on the sigmask.
+            t.invalidate_sigmask();
+            // No events to be recorded, so no syscallbuf updates
+            // needed.
+            return true;
+        }
+
+        if sig == sig::SIGTRAP && handle_syscallbuf_breakpoint(t) {
+            return true;
+        }
+
+        let deterministic = is_deterministic_signal(t);
+        // The kernel might have forcibly unblocked the signal. Check whether it
+        // was blocked now, before we update our cached sigmask.
+        let signal_was_blocked = if t.is_sig_blocked(sig) {
+            SignalBlocked::SigBlocked
+        } else {
+            SignalBlocked::SigUnblocked
+        };
+        if deterministic == SignalDeterministic::DeterministicSig
+            || sig == self.syscallbuf_desched_sig()
+        {
+            // Don't stash these signals; deliver them immediately.
+            // We don't want them to be reordered around other signals.
+            // invalidate_sigmask() must not be called before we reach handle_signal!
+            let mut siginfo = t.get_siginfo().clone();
+            match handle_signal(t, &mut siginfo, deterministic, signal_was_blocked) {
+                SignalHandled::SignalPtraceStop => {
+                    // Emulated ptrace-stop. Don't run the task again yet.
+                    self.last_task_switchable.set(Switchable::AllowSwitch);
+                    step_state.continue_type = ContinueType::DontContinue;
+                    return true;
+                }
+                SignalHandled::DeferSignal => {
+                    ed_assert!(
+                        t,
+                        false,
+                        "Can't defer deterministic or internal signal {} at ip {}",
+                        siginfo,
+                        t.ip()
+                    );
+                }
+                SignalHandled::SignalHandled => {
+                    if t.maybe_ptrace_event() == PTRACE_EVENT_SECCOMP {
+                        // `handle_desched_event` detected a spurious desched followed
+                        // by a SECCOMP event, which it left pending. Handle that SECCOMP
+                        // event now.
+                        let mut dummy_did_enter_syscall = false;
+                        let mut dummy_result = RecordResult::StepContinue;
+                        self.handle_ptrace_event(
+                            t,
+                            step_state,
+                            &mut dummy_result,
+                            &mut dummy_did_enter_syscall,
+                        );
+                        ed_assert!(t, !dummy_did_enter_syscall);
+                    }
+                }
+            }
+            return false;
+        }
+        // Conservatively invalidate the sigmask in case just accepting a signal has
+        // sigmask effects.
+        t.invalidate_sigmask();
+        if sig == TIME_SLICE_SIGNAL {
+            let si_signo = t.get_siginfo().si_signo;
+            let si_code = t.get_siginfo().si_code;
+            // This implementation will of course fall over if rd tries to
+            // record itself.
+            //
+            // NB: we can't check that the ticks is >= the programmed
+            // target, because this signal may have become pending before
+            // we reset the HPC counters.  There be a way to handle that
+            // more elegantly, but bridge will be crossed in due time.
+            //
+            // We can't check that the fd matches t.hpc.ticks_fd() because this
+            // signal could have been queued quite a long time ago and the PerfCounters
+            // might have been stopped (and restarted!), perhaps even more than once,
+            // since the signal was queued. possibly changing its fd. We could check
+            // against all fds the PerfCounters have ever used, but that seems like
+            // overkill.
+            ed_assert!(
+                t,
+                TIME_SLICE_SIGNAL.as_raw() == si_signo && si_code == POLL_IN as i32,
+                "Tracee is using SIGSTKFLT??? (code={})",
+                si_code
+            );
+        }
+        t.stash_sig();
+        true
+    }
+
+    /// Returns true if we took the signal off the stash and arranged for it to
+    /// be delivered (or determined it should be ignored).
+    fn prepare_to_inject_signal(&self, t: &mut RecordTask, step_state: &mut StepState) -> bool {
+        if !self.done_initial_exec() || step_state.continue_type != ContinueType::Continue {
+            return false;
+        }
+
+        let sig = loop {
+            let sig = match t.peek_stashed_sig_to_deliver() {
+                Some(sig) => sig.clone(),
+                None => return false,
+            };
+            if Some(sig.siginfo.si_signo) == self.get_ignore_sig().map(|s| s.as_raw()) {
+                log!(
+                    LogDebug,
+                    "Declining to deliver {} by user request",
+                    sig.siginfo.si_signo
+                );
+                t.pop_stash_sig(&sig);
+                t.stashed_signal_processed();
+            } else {
+                break sig;
+            }
+        };
+
+        if sig.deterministic == SignalDeterministic::DeterministicSig
+            && sig.siginfo.si_signo == SIGSYS
+            && t.is_sig_blocked(sig::SIGSYS)
+        {
+            // Our synthesized deterministic SIGSYS (seccomp trap) needs to match the
+            // kernel behavior of unblocking the signal and resetting disposition to
+            // default.
+            t.unblock_signal(sig::SIGSYS);
+            t.set_sig_handler_default(sig::SIGSYS);
+        }
+        let mut si = sig.siginfo.clone();
+        match handle_signal(t, &mut si, sig.deterministic, SignalBlocked::SigUnblocked) {
+            SignalHandled::SignalPtraceStop => {
+                // Emulated ptrace-stop. Don't run the task again yet.
+                self.last_task_switchable.set(Switchable::AllowSwitch);
+                log!(LogDebug, "Signal {}, emulating ptrace stop", si.si_signo);
+            }
+            SignalHandled::DeferSignal => {
+                log!(LogDebug, "Signal {} deferred", si.si_signo);
+                // Leave signal on the stack and continue task execution. We'll try again
+                // later.
+                return false;
+            }
+            SignalHandled::SignalHandled => {
+                log!(LogDebug, "Signal {} handled", si.si_signo);
+                // Signal is now a pending event on `t`'s event stack
+
+                if t.ev().event_type() == EventType::EvSched {
+                    if t.maybe_in_spinlock() {
+                        log!(
+                            LogDebug,
+                            "Detected possible spinlock, forcing one round-robin"
+                        );
+                        self.scheduler().schedule_one_round_robin(t);
+                    }
+                    // Allow switching after a SCHED. We'll flush the SCHED if and only if
+                    // we really do a switch.
+                    self.last_task_switchable.set(Switchable::AllowSwitch);
+                }
+            }
+        }
+        step_state.continue_type = ContinueType::DontContinue;
+        t.pop_stash_sig(&sig);
+        if t.ev().event_type() != EventType::EvSignal {
+            t.stashed_signal_processed();
+        }
+        true
+    }
+
+    /// Returns true if `t` is dying because of a fatal signal.
+    fn signal_state_changed(&self, t: &mut RecordTask, step_state: &mut StepState) -> bool {
+        let sig = t.ev().signal_event().maybe_sig().unwrap();
+
+        match t.ev().event_type() {
+            EventType::EvSignal => {
+                // This event is used by the replayer to advance to
+                // the point of signal delivery.
+                t.record_current_event();
+                t.ev_mut().transform(EventType::EvSignalDelivery);
+                let mut sigframe_size = 0;
+
+                let has_handler = t.signal_has_user_handler(sig);
+                if has_handler {
+                    log!(LogDebug, "  {}: {} has user handler", t.tid, sig);
+
+                    if !inject_handled_signal(t) {
+                        // Signal delivery isn't happening. Prepare to process the new
+                        // signal that aborted signal delivery.
+                        t.signal_delivered(sig);
+                        t.pop_signal_delivery();
+                        step_state.continue_type = ContinueType::DontContinue;
+                        self.last_task_switchable.set(Switchable::PreventSwitch);
+                        return false;
+                    }
+
+                    // It's somewhat difficult engineering-wise to
+                    // compute the sigframe size at compile time,
+                    // and it can vary across kernel versions and CPU
+                    // microarchitectures. So this size is an overestimate
+                    // of the real size(s).
+                    //
+                    // If this size becomes too small in the
+                    // future, and unit tests that use sighandlers
+                    // are run with checksumming enabled, then
+                    // they can catch errors here.
+                    sigframe_size = 1152 /* Overestimate of kernel sigframe */
+                        + 128 /* Redzone */
+                        // this returns 512 when XSAVE unsupported
+                        + xsave_area_size();
+
+                    t.ev_mut().transform(EventType::EvSignalHandler);
+                    t.signal_delivered(sig);
+                    // We already continued! Don't continue now, and allow switching.
+                    step_state.continue_type = ContinueType::DontContinue;
+                    self.last_task_switchable.set(Switchable::AllowSwitch);
+                } else {
+                    t.stashed_signal_processed();
+                    log!(LogDebug, "  {}: no user handler for {}", t.tid, sig);
+                    // Don't do another task continue. We want to deliver the signal
+                    // as the next thing that the task does.
+                    step_state.continue_type = ContinueType::DontContinue;
+                    // If we didn't set up the sighandler frame, we need
+                    // to ensure that this tracee is scheduled next so
+                    // that we can deliver the signal normally.  We have
+                    // to do that because setting up the sighandler frame
+                    // is synchronous, but delivery otherwise is async.
+                    // But right after this, we may have to process some
+                    // syscallbuf state, so we can't let the tracee race
+                    // with us.
+                    self.last_task_switchable.set(Switchable::PreventSwitch);
+                }
+
+                // We record this data even if sigframe_size is zero to simplify replay.
+                // Stop recording data if we run off the end of a writable mapping.
+                // Our sigframe size is conservative so we need to do this.
+                let sp = t.regs_ref().sp();
+                t.record_remote_writable(sp, sigframe_size);
+
+                // This event is used by the replayer to set up the signal handler frame.
+                // But if we don't have a handler, we don't want to record the event
+                // until we deal with the EvSignalDelivery.
+                if has_handler {
+                    t.record_current_event();
+                }
+            }
+
+            EventType::EvSignalDelivery => {
+                // A SIGSTOP requires us to allow switching to another task.
+                // So does a fatal, core-dumping signal, since we need to allow other
+                // tasks to proceed to their exit events.
+                let is_fatal = t.ev().signal_event().disposition
+                    == SignalResolvedDisposition::DispositionFatal;
+                let can_switch = if (is_fatal && default_action(sig) == SignalAction::DumpCore)
+                    || sig == sig::SIGSTOP
+                {
+                    Switchable::AllowSwitch
+                } else {
+                    Switchable::PreventSwitch
+                };
+
+                // We didn't record this event above, so do that now.
+                // NB: If there is no handler, and we interrupted a syscall, and there are
+                // no more actionable signals, the kernel sets us up for a syscall
+                // restart. But it does that *after* the ptrace trap. To replay this
+                // correctly we need to fake those changes here. But we don't do this
+                // if we're going to switch away at the ptrace trap, and for the moment,
+                // 'can_switch' is actually 'will_switch'.
+                // This is essentially copied from do_signal in arch/x86/kernel/signal.c
+                let has_other_signals = t.has_any_actionable_signal();
+                let mut r = t.regs_ref().clone();
+                if can_switch == Switchable::PreventSwitch
+                    && !has_other_signals
+                    && r.original_syscallno() >= 0
+                    && r.syscall_may_restart()
+                {
+                    match -r.syscall_result_signed() as u32 {
+                        ERESTARTNOHAND | ERESTARTSYS | ERESTARTNOINTR => {
+                            r.set_syscallno(r.original_syscallno());
+                            r.set_ip(r.ip().decrement_by_syscall_insn_length(r.arch()));
+                        }
+                        ERESTART_RESTARTBLOCK => {
+                            r.set_syscallno(syscall_number_for_restart_syscall(r.arch()) as isize);
+                            r.set_ip(r.ip().decrement_by_syscall_insn_length(r.arch()));
+                        }
+                        _ => (),
+                    }
+                }
+
+                t.record_event(
+                    None,
+                    Some(FlushSyscallbuf::FlushSyscallbuf),
+                    Some(AllowSyscallbufReset::AllowResetSyscallbuf),
+                    Some(&r),
+                );
+                // Don't actually set_regs(r), the kernel does these modifications.
+
+                // Only inject fatal signals. Non-fatal signals with signal handlers
+                // were taken care of above; for non-fatal signals without signal
+                // handlers, there is no need to deliver the signal at all. In fact,
+                // there is really no way to inject a non-fatal, non-handled signal
+                // without letting the task execute at least one instruction, which
+                // we don't want to do here.
+                if is_fatal && Some(sig) != self.get_continue_through_sig() {
+                    log!(LogDebug, "Delivering fatal signal {}", sig);
+                    preinject_signal(t);
+                    t.resume_execution(
+                        ResumeRequest::ResumeCont,
+                        WaitRequest::ResumeNonblocking,
+                        TicksRequest::ResumeNoTicks,
+                        Some(sig),
+                    );
+                    log!(
+                        LogWarn,
+                        "Delivered core-dumping signal; may misrecord CLONE_CHILD_CLEARTID memory race"
+                    );
+                    destabilize_thread_group(t);
+                    t.signal_delivered(sig);
+                    return true;
+                }
+
+                t.signal_delivered(sig);
+                t.pop_signal_delivery();
+                // A task that just entered an emulated stop won't run until
+                // something continues it, so let other tasks run.
+                if t.emulated_stop_type != EmulatedStopType::NotStopped {
+                    self.last_task_switchable.set(Switchable::AllowSwitch);
+                } else {
+                    self.last_task_switchable.set(can_switch);
+                }
+                step_state.continue_type = ContinueType::DontContinue;
+            }
+
+            _ => fatal!("Unhandled signal state {}", t.ev()),
+        }
+
+        false
+    }
+
+    fn task_continue(&self, t: &mut RecordTask, step_state: &StepState) {
+        ed_assert!(t, step_state.continue_type != ContinueType::DontContinue);
+        // A task in an emulated ptrace-stop must really stay stopped
+        ed_assert!(t, !t.emulated_stop_pending);
+
+        let may_restart = t.at_may_restart_syscall();
+
+        if may_restart && t.seccomp_bpf_enabled {
+            log!(
+                LogDebug,
+                "  PTRACE_SYSCALL to possibly-restarted {}",
+                t.ev()
+            );
+        }
+
+        if t.vm().first_run_event() == 0 {
+            t.vm().set_first_run_event(self.trace_writer().time());
+        }
+
+        let mut singlestep = t.emulated_ptrace_cont_command == PTRACE_SINGLESTEP
+            || t.emulated_ptrace_cont_command == PTRACE_SYSEMU_SINGLESTEP;
+        let ip = t.ip();
+        if singlestep && is_at_syscall_instruction(t, ip) {
+            // We're about to singlestep into a syscall instruction.
+            // Act like we're NOT singlestepping since doing a PTRACE_SINGLESTEP would
+            // skip over the system call.
+            log!(
+                LogDebug,
+                "Clearing singlestep because we're about to enter a syscall"
+            );
+            singlestep = false;
+        }
+
+        let mut ticks_request: TicksRequest;
+        let resume: ResumeRequest;
+        if step_state.continue_type == ContinueType::ContinueSyscall {
+            ticks_request = TicksRequest::ResumeNoTicks;
+            resume = ResumeRequest::ResumeSyscall;
+        } else {
+            if t.has_stashed_sig(TIME_SLICE_SIGNAL) {
+                // timeslice signal already stashed, no point in generating another one
+                // (and potentially slow)
+                ticks_request = TicksRequest::ResumeUnlimitedTicks;
+            } else {
+                let timeslice_end = self.scheduler().current_timeslice_end();
+                // DIFF NOTE: rd asserts that at least one tick is requested, rr
+                // clamps to 1 in resume_execution().
+                ticks_request = TicksRequest::ResumeWithTicksRequest(min(
+                    MAX_TICKS_REQUEST,
+                    max(1, timeslice_end.saturating_sub(t.tick_count())),
+                ));
+            }
+
+            t.next_pmc_interrupt_is_for_user = false;
+            let maybe_vpmc = VirtualPerfCounterMonitor::interrupting_virtual_pmc_for_task(t);
+            if let Some(vpmc) = maybe_vpmc {
+                let target_ticks = {
+                    let vpmc_b = vpmc.borrow();
+                    let v = vpmc_b.as_virtual_perf_counter_monitor().unwrap();
+                    ed_assert!(t, v.target_tuid() == t.tuid());
+                    v.target_ticks()
+                };
+                let after = min(
+                    MAX_TICKS_REQUEST,
+                    max(1, target_ticks.saturating_sub(t.tick_count())),
+                );
+                let constrained = match ticks_request {
+                    TicksRequest::ResumeWithTicksRequest(num_ticks) => after < num_ticks,
+                    _ => true,
+                };
+                if constrained {
+                    log!(
+                        LogDebug,
+                        "ticks_request constrained from {:?} to {} for vpmc",
+                        ticks_request,
+                        after
+                    );
+                    ticks_request = TicksRequest::ResumeWithTicksRequest(after);
+                    t.next_pmc_interrupt_is_for_user = true;
+                }
+            }
+
+            // We won't receive PTRACE_EVENT_SECCOMP events until
+            // the seccomp filter is installed by the
+            // syscall_buffer lib in the child, therefore we must
+            // record in the traditional way (with PTRACE_SYSCALL)
+            // until it is installed.
+            //
+            // Kernel commit
+            // https://github.com/torvalds/linux/commit/93e35efb8de45393cf61ed07f7b407629bf698ea
+            // makes PTRACE_SYSCALL traps be delivered *before* seccomp RET_TRACE
+            // traps.
+            // Detect and handle this.
+            if singlestep {
+                resume = ResumeRequest::ResumeSinglestep;
+            } else if !t.seccomp_bpf_enabled
+                || may_restart
+                || self.syscall_seccomp_ordering_.get()
+                    == PtraceSyscallSeccompOrdering::SyscallBeforeSeccompUnknown
+            {
+                resume = ResumeRequest::ResumeSyscall;
+            } else {
+                // When the seccomp filter is on, instead of capturing
+                // syscalls by using PTRACE_SYSCALL, the filter will
+                // generate the ptrace events. This means we allow the
+                // process to run using PTRACE_CONT, and rely on the
+                // seccomp filter to generate the special
+                // PTRACE_EVENT_SECCOMP event once a syscall happens.
+                // This event is handled here by simply allowing the
+                // process to continue to the actual entry point of
+                // the syscall (using cont_syscall_block()) and then
+                // using the same logic as before.
+                resume = ResumeRequest::ResumeCont;
+            }
+        }
+        t.resume_execution(resume, WaitRequest::ResumeNonblocking, ticks_request, None);
     }
 }
 
@@ -606,8 +2185,8 @@ impl Session for RecordSession {
         kill_all_tasks(self)
     }
 
-    fn on_destroy_task(&self, _t: TaskUid) {
-        unimplemented!()
+    fn on_destroy_task(&self, tuid: TaskUid) {
+        self.scheduler().on_destroy_task(tuid);
     }
 
     fn as_session_inner(&self) -> &SessionInner {
@@ -630,7 +2209,7 @@ impl Session for RecordSession {
 
     fn on_create_task(&self, t: TaskSharedPtr) {
         on_create_task_common(self, t.clone());
-        self.scheduler_mut().on_create_task(t);
+        self.scheduler().on_create_task(t);
     }
 
     fn trace_stream(&self) -> Option<Ref<'_, TraceStream>> {
@@ -817,6 +2396,35 @@ fn inject_ld_helper_library(env: &mut Vec<(OsString, OsString)>, name: &OsStr, v
     }
 }
 
+fn is_in_privileged_syscall(t: &RecordTask) -> bool {
+    match AddressSpace::rd_page_syscall_from_exit_point(t.ip()) {
+        Some(syscall_type) => syscall_type.privileged == Privileged::Privileged,
+        None => false,
+    }
+}
+
+fn maybe_trigger_emulated_ptrace_syscall_exit_stop(t: &mut RecordTask) {
+    // DIFF NOTE: If the syscall already produced an emulated stop (e.g.
+    // PTRACE_EVENT_VFORK_DONE) we don't report the syscall-exit stop after it.
+    if t.emulated_stop_type != EmulatedStopType::NotStopped {
+        return;
+    }
+    if t.emulated_ptrace_cont_command == PTRACE_SYSCALL {
+        let status = WaitStatus::for_syscall(t);
+        t.emulate_ptrace_stop(status, None, None);
+    } else if t.emulated_ptrace_cont_command == PTRACE_SINGLESTEP
+        || t.emulated_ptrace_cont_command == PTRACE_SYSEMU_SINGLESTEP
+    {
+        // Deliver the singlestep trap now that we've finished executing the
+        // syscall.
+        t.emulate_ptrace_stop(
+            WaitStatus::for_stop_sig(sig::SIGTRAP),
+            None,
+            Some(SI_KERNEL),
+        );
+    }
+}
+
 union USiginfo {
     native_api: native_arch::siginfo_t,
     linux_api: siginfo_t,
@@ -935,10 +2543,582 @@ fn handle_seccomp_trap(t: &mut RecordTask, step_state: &mut StepState, seccomp_d
     step_state.continue_type = ContinueType::DontContinue;
 }
 
-fn note_entering_syscall(_t: &mut RecordTask) {
-    unimplemented!()
+fn handle_seccomp_errno(t: &mut RecordTask, step_state: &mut StepState, seccomp_data: u16) {
+    let arch = t.detect_syscall_arch();
+    t.canonicalize_regs(arch);
+
+    let mut r = t.regs_ref().clone();
+    let syscallno = r.original_syscallno() as i32;
+    // Cause kernel processing to skip the syscall
+    r.set_original_syscallno(SECCOMP_MAGIC_SKIP_ORIGINAL_SYSCALLNO);
+    t.set_regs(&r);
+
+    if !t.is_in_untraced_syscall() {
+        t.push_syscall_event(syscallno);
+        // Note that the syscall failed. prepare_clone() needs to know
+        // this during replay of the syscall entry.
+        t.ev_mut().syscall_mut().failed_during_preparation = true;
+        note_entering_syscall(t);
+    }
+
+    r.set_syscall_result_signed(-(seccomp_data as isize));
+    t.set_regs(&r);
+    // Don't continue yet. At the next iteration of record_step, if we
+    // recorded the syscall-entry we'll enter syscall_state_changed and
+    // that will trigger a continue to the syscall exit.
+    step_state.continue_type = ContinueType::DontContinue;
+}
+
+fn note_entering_syscall(t: &mut RecordTask) {
+    ed_assert_eq!(t, EventType::EvSyscall, t.ev().event_type());
+    t.ev_mut().syscall_mut().state = SyscallState::EnteringSyscall;
+    if !t.ev().syscall().is_restart {
+        // Save a copy of the arg registers so that we
+        // can use them to detect later restarted
+        // syscalls, if this syscall ends up being
+        // restarted.  We have to save the registers
+        // in this rather awkward place because we
+        // need the original registers; the restart
+        // (if it's not a SYS_restart_syscall restart)
+        // will use the original registers.
+        let regs = t.regs_ref().clone();
+        t.ev_mut().syscall_mut().regs = regs;
+    } else {
+        let syscallno = t.regs_ref().syscallno();
+        t.ev_mut().syscall_mut().regs.set_syscallno(syscallno);
+    }
+}
+
+fn maybe_restart_syscall(t: &mut RecordTask) -> bool {
+    if is_restart_syscall_syscall(t.regs_ref().original_syscallno() as i32, t.arch()) {
+        log!(LogDebug, "  {}: SYS_restart_syscall'ing {}", t.tid, t.ev());
+    }
+    if t.is_syscall_restart() {
+        t.ev_mut().transform(EventType::EvSyscall);
+        let mut regs = t.regs_ref().clone();
+        regs.set_original_syscallno(t.ev().syscall().regs.original_syscallno());
+        t.set_regs(&regs);
+        let arch = t.arch();
+        t.canonicalize_regs(arch);
+        return true;
+    }
+    if EventType::EvSyscallInterruption == t.ev().event_type() {
+        syscall_not_restarted(t);
+    }
+    false
+}
+
+fn syscall_not_restarted(t: &mut RecordTask) {
+    log!(
+        LogDebug,
+        "  {}: popping abandoned interrupted {}",
+        t.tid,
+        t.ev()
+    );
+    t.pop_syscall_interruption();
+}
+
+fn maybe_discard_syscall_interruption(t: &mut RecordTask, ret: isize) {
+    if EventType::EvSyscallInterruption != t.ev().event_type() {
+        // We currently don't track syscalls interrupted with
+        // ERESTARTSYS or ERESTARTNOHAND, so it's possible for
+        // a sigreturn not to affect the event stack.
+        log!(LogDebug, "  (no interrupted syscall to retire)");
+        return;
+    }
+
+    let syscallno = t.ev().syscall().number;
+    if 0 > ret {
+        syscall_not_restarted(t);
+    } else {
+        ed_assert_eq!(
+            t,
+            syscallno as isize,
+            ret,
+            "Interrupted call was {} and sigreturn claims to be restarting {}",
+            t.ev().syscall().syscall_name(),
+            syscall_name(ret as i32, t.ev().syscall().arch())
+        );
+    }
+}
+
+fn check_initial_task_syscalls(t: &mut RecordTask, step_result: &mut RecordResult) {
+    if t.session().done_initial_exec() {
+        return;
+    }
+
+    if is_exit_group_syscall(t.ev().syscall().number, t.arch()) {
+        *step_result = RecordResult::StepSpawnFailed(t.session().read_spawned_task_error());
+    }
+}
+
+fn copy_syscall_arg_regs(to: &mut Registers, from: &Registers) {
+    to.set_arg1(from.arg1());
+    to.set_arg2(from.arg2());
+    to.set_arg3(from.arg3());
+    to.set_arg4(from.arg4());
+    to.set_arg5(from.arg5());
+    to.set_arg6(from.arg6());
+}
+
+fn save_interrupted_syscall_ret_in_syscallbuf(t: &mut RecordTask, retval: isize) {
+    // Record storing the return value in the syscallbuf record, where
+    // we expect to find it during replay.
+    let child_rec = t.next_syscallbuf_record();
+    let ret = retval as i64;
+    t.record_local_for(
+        RemotePtr::<i64>::cast(
+            RemotePtr::<u8>::cast(child_rec) + offset_of!(syscallbuf_record, ret),
+        ),
+        &ret,
+    );
+}
+
+fn syscallbuf_hdr_field(t: &RecordTask, offset: usize) -> RemotePtr<u8> {
+    RemotePtr::<u8>::cast(t.syscallbuf_child) + offset
+}
+
+fn seccomp_trap_done(t: &mut RecordTask) {
+    t.pop_seccomp_trap();
+
+    // It's safe to reset the syscall buffer now.
+    t.delay_syscallbuf_reset_for_seccomp_trap = false;
+
+    let failed_during_preparation =
+        syscallbuf_hdr_field(t, offset_of!(syscallbuf_hdr, failed_during_preparation));
+    write_val_mem(t, failed_during_preparation, &1u8, None);
+    t.record_local_for(failed_during_preparation, &1u8);
+
+    if EventType::EvDesched == t.ev().event_type() {
+        // Desched processing will do the rest for us
+        return;
+    }
+
+    // Abort the current syscallbuf record, which corresponds to the syscall that
+    // wasn't actually executed due to seccomp.
+    let abort_commit = syscallbuf_hdr_field(t, offset_of!(syscallbuf_hdr, abort_commit));
+    write_val_mem(t, abort_commit, &1u8, None);
+    t.record_event(Some(Event::syscallbuf_abort_commit()), None, None, None);
+
+    // In fact, we need to. Running the syscall exit hook will ensure we
+    // reset the buffer before we try to buffer another a syscall.
+    let notify_on_syscall_hook_exit =
+        syscallbuf_hdr_field(t, offset_of!(syscallbuf_hdr, notify_on_syscall_hook_exit));
+    write_val_mem(t, notify_on_syscall_hook_exit, &1u8, None);
+}
+
+fn desched_state_changed(t: &mut RecordTask) {
+    log!(LogDebug, "desched: IN_SYSCALL");
+    // We need to ensure that the syscallbuf code doesn't
+    // try to commit the current record; we've already
+    // recorded that syscall.  The following event sets
+    // the abort-commit bit.
+    let abort_commit = syscallbuf_hdr_field(t, offset_of!(syscallbuf_hdr, abort_commit));
+    write_val_mem(t, abort_commit, &1u8, None);
+    t.record_event(Some(Event::syscallbuf_abort_commit()), None, None, None);
+
+    advance_to_disarm_desched_syscall(t);
+
+    t.pop_desched();
+
+    // The tracee has just finished sanity-checking the
+    // aborted record, and won't touch the syscallbuf
+    // during this (aborted) transaction again.  So now
+    // is a good time for us to reset the record counter.
+    t.delay_syscallbuf_reset_for_desched = false;
+    // Run the syscallbuf exit hook. This ensures we'll be able to reset
+    // the syscallbuf before trying to buffer another syscall.
+    let notify_on_syscall_hook_exit =
+        syscallbuf_hdr_field(t, offset_of!(syscallbuf_hdr, notify_on_syscall_hook_exit));
+    write_val_mem(t, notify_on_syscall_hook_exit, &1u8, None);
+}
+
+fn advance_to_disarm_desched_syscall(t: &mut RecordTask) {
+    let desched_sig = t.session().as_record().unwrap().syscallbuf_desched_sig();
+    let mut old_sig = None;
+
+    log!(LogDebug, "desched: DISARMING_DESCHED_EVENT");
+    // TODO: send this through main loop.
+    // TODO: mask off signals and avoid this loop.
+    loop {
+        t.resume_execution(
+            ResumeRequest::ResumeSyscall,
+            WaitRequest::ResumeWait,
+            TicksRequest::ResumeUnlimitedTicks,
+            None,
+        );
+        // We can safely ignore TIME_SLICE_SIGNAL while trying to
+        // reach the disarm-desched ioctl: once we reach it,
+        // the desched'd syscall will be "done" and the tracee
+        // will be at a preemption point.  In fact, we *want*
+        // to ignore this signal.  Syscalls like read() can
+        // have large buffers passed to them, and we have to
+        // copy-out the buffered out data to the user's
+        // buffer.  This happens in the interval where we're
+        // reaching the disarm-desched ioctl, so that code is
+        // susceptible to receiving TIME_SLICE_SIGNAL.
+        let maybe_sig = t.maybe_stop_sig().get_raw_repr();
+        match maybe_sig {
+            Some(sig) if sig == TIME_SLICE_SIGNAL => (),
+            // We should not receive the desched signal since it should already
+            // have been disarmed. However, we observe these being received here when
+            // we arm the desched signal before we restart a blocking syscall, which
+            // completes successfully, then we disarm, then we see a desched signal
+            // here.
+            Some(sig) if sig == desched_sig => (),
+            Some(sig) if maybe_sig == old_sig => {
+                log!(LogDebug, "  coalescing pending {}", sig);
+            }
+            Some(sig) => {
+                log!(LogDebug, "  {} now pending", sig);
+                t.stash_sig();
+                old_sig = maybe_sig;
+            }
+            None => (),
+        }
+        if t.is_disarm_desched_event_syscall() {
+            break;
+        }
+    }
+
+    // Exit the syscall.
+    t.resume_execution(
+        ResumeRequest::ResumeSyscall,
+        WaitRequest::ResumeWait,
+        TicksRequest::ResumeNoTicks,
+        None,
+    );
+}
+
+/// DIFF NOTE: rr calls ThreadGroup::destabilize() which would try to borrow
+/// `t` again. Mark `t` directly and the other tasks via the task set.
+fn destabilize_thread_group(t: &RecordTask) {
+    log!(LogDebug, "destabilizing thread group {}", t.tgid());
+    t.unstable.set(true);
+    for tt in t.thread_group().task_set().iter_except(t.weak_self_ptr()) {
+        tt.borrow().unstable.set(true);
+        log!(LogDebug, "  destabilized task {}", tt.borrow().tid);
+    }
+}
+
+fn record_exit(t: &RecordTask, exit_status: WaitStatus) {
+    t.trace_writer_mut()
+        .write_task_event(&TraceTaskEvent::for_exit(t.tid, exit_status));
+    if t.thread_group().tgid == t.tid {
+        t.thread_group_shr_ptr().borrow_mut().exit_status = exit_status;
+    }
+}
+
+fn read_robust_list_word<Arch: Architecture>(
+    t: &mut RecordTask,
+    addr: RemotePtr<Void>,
+    ok: &mut bool,
+) -> usize {
+    let w: Arch::unsigned_word = read_val_mem(t, RemotePtr::cast(addr), Some(ok));
+    w.try_into().unwrap()
+}
+
+/// We can't just record the current futex value because at this point
+/// in task exit the robust futex handling has not happened yet. So we have
+/// to emulate what the kernel will do!
+fn record_robust_futex_change(t: &mut RecordTask, futex_offset: isize, base: RemotePtr<Void>) {
+    if base.is_null() {
+        return;
+    }
+    let futex_ptr =
+        RemotePtr::<u32>::new_from_val((base.as_usize() as isize + futex_offset) as usize);
+    let mut ok = true;
+    let mut val: u32 = read_val_mem(t, futex_ptr, Some(&mut ok));
+    if !ok {
+        return;
+    }
+    if (val & FUTEX_TID_MASK) as pid_t != t.own_namespace_rec_tid {
+        return;
+    }
+    val = (val & FUTEX_WAITERS) | FUTEX_OWNER_DIED;
+    // Update memory now so that the kernel doesn't decide to do it later, at
+    // a time that might race with other tracee execution.
+    write_val_mem(t, futex_ptr, &val, None);
+    t.record_local_for(futex_ptr, &val);
+}
+
+/// Any user-space writes performed by robust futex handling are captured here.
+/// They must be emulated during replay; the kernel will not do it for us
+/// during replay because the TID value in each futex is the recorded
+/// TID, not the actual TID of the dying task.
+///
+/// DIFF NOTE: The robust_list_head struct is read word by word as
+/// { list.next, futex_offset, list_op_pending }.
+fn record_robust_futex_changes_arch<Arch: Architecture>(t: &mut RecordTask) {
+    if t.did_record_robust_futex_changes {
+        return;
+    }
+    t.did_record_robust_futex_changes = true;
+
+    let head_ptr = t.robust_list();
+    if head_ptr.is_null() {
+        return;
+    }
+    let word_size = size_of::<Arch::unsigned_word>();
+    ed_assert_eq!(t, t.robust_list_len(), 3 * word_size);
+    let mut ok = true;
+    let list_next = read_robust_list_word::<Arch>(t, head_ptr, &mut ok);
+    let futex_offset_word = read_robust_list_word::<Arch>(t, head_ptr + word_size, &mut ok);
+    let list_op_pending = read_robust_list_word::<Arch>(t, head_ptr + 2 * word_size, &mut ok);
+    if !ok {
+        return;
+    }
+    let futex_offset = if word_size == 4 {
+        futex_offset_word as u32 as i32 as isize
+    } else {
+        futex_offset_word as isize
+    };
+
+    record_robust_futex_change(t, futex_offset, RemotePtr::new_from_val(list_op_pending));
+    let mut current = RemotePtr::<Void>::new_from_val(list_next);
+    while current != head_ptr {
+        record_robust_futex_change(t, futex_offset, current);
+        let next = read_robust_list_word::<Arch>(t, current, &mut ok);
+        if !ok {
+            return;
+        }
+        current = RemotePtr::new_from_val(next);
+    }
+}
+
+fn record_robust_futex_changes(t: &mut RecordTask) {
+    let arch = t.arch();
+    rd_arch_function_selfless!(record_robust_futex_changes_arch, arch, t)
 }
 
-fn rec_abort_prepared_syscall(_t: &mut RecordTask) {
-    unimplemented!()
+/// Force the task into a signal-stop so the signal at the top of its event
+/// stack can be injected. Returns false if the task exited prematurely.
+fn preinject_signal(t: &mut RecordTask) -> bool {
+    // Signal injection is tricky. Per the ptrace(2) man page, injecting
+    // a signal while the task is not in a signal-stop is not guaranteed to work
+    // (and indeed, we see that the kernel sometimes ignores such signals).
+    // But some signals must be delayed until after the signal-stop that notified
+    // us of them.
+    // So, first we check if we're in a signal-stop that we can use to inject
+    // a signal. Some (all?) SIGTRAP stops are *not* usable for signal injection.
+    if t.maybe_stop_sig().is_sig() && t.maybe_stop_sig() != sig::SIGTRAP {
+        log!(LogDebug, "    in signal-stop for {}", t.maybe_stop_sig());
+    } else {
+        // We're not in a usable signal-stop. Force a signal-stop by sending
+        // a new signal with tgkill (as the ptrace(2) man page recommends).
+        log!(
+            LogDebug,
+            "    maybe not in signal-stop (status {}); doing tgkill(SYSCALLBUF_DESCHED_SIGNAL)",
+            t.status()
+        );
+        // Always send the desched signal because other signals (except
+        // TIME_SLICE_SIGNAL) will be blocked by
+        // RecordTask::will_resume_execution().
+        let desched_sig = t.session().as_record().unwrap().syscallbuf_desched_sig();
+        t.tgkill(desched_sig);
+
+        // Now singlestep the task until we're in a signal-stop for the signal
+        // we've just sent. We must absorb and forget that signal here since we
+        // don't want it delivered to the task for real.
+        let old_ip = t.ip();
+        loop {
+            t.resume_execution(
+                ResumeRequest::ResumeSinglestep,
+                WaitRequest::ResumeWait,
+                TicksRequest::ResumeNoTicks,
+                None,
+            );
+            if t.maybe_ptrace_event() == PTRACE_EVENT_EXIT {
+                return false;
+            }
+            ed_assert!(
+                t,
+                old_ip == t.ip(),
+                "Singlestep actually advanced when we just expected a signal; was at {} now at {} with status {}",
+                old_ip,
+                t.ip(),
+                t.status()
+            );
+            // Ignore any pending TIME_SLICE_SIGNALs and continue until we get our
+            // desched signal.
+            if t.maybe_stop_sig() != TIME_SLICE_SIGNAL {
+                break;
+            }
+        }
+
+        ed_assert!(
+            t,
+            t.maybe_stop_sig() == desched_sig,
+            "Expected the desched signal, got {}",
+            t.status()
+        );
+        // We're now in a signal-stop
+    }
+
+    // Now that we're in a signal-stop, we can inject our signal and advance
+    // to the signal handler with one single-step.
+    let siginfo = t.ev().signal_event().siginfo.clone();
+    log!(LogDebug, "    injecting signal {}", siginfo);
+    t.set_siginfo(&siginfo);
+    true
+}
+
+/// Returns false if the signal handler frame could not be set up.
+fn inject_handled_signal(t: &mut RecordTask) -> bool {
+    if !preinject_signal(t) {
+        // Task prematurely exited.
+        return false;
+    }
+    // If there aren't any more fatal signals pending, we can allow other
+    // signals to be delivered again.
+    t.stashed_signal_processed();
+
+    let sig = t.ev().signal_event().maybe_sig().unwrap();
+    let desched_sig = t.session().as_record().unwrap().syscallbuf_desched_sig();
+    loop {
+        // We are ready to inject our signal.
+        // XXX we assume the kernel won't respond by notifying us of a different
+        // signal. We don't want to do this with signals blocked because that will
+        // save a bogus signal mask in the signal frame.
+        t.resume_execution(
+            ResumeRequest::ResumeSinglestep,
+            WaitRequest::ResumeWait,
+            TicksRequest::ResumeNoTicks,
+            Some(sig),
+        );
+        // Signal injection can change the sigmask due to sa_mask effects, lack of
+        // SA_NODEFER, and signal frame construction triggering a synchronous
+        // SIGSEGV.
+        t.invalidate_sigmask();
+        // Repeat injection if we got a desched signal. We observe in Linux 4.14.12
+        // that we get SYSCALLBUF_DESCHED_SIGNAL here once in a while.
+        if t.maybe_stop_sig() != desched_sig {
+            break;
+        }
+    }
+
+    if t.maybe_stop_sig() == sig::SIGSEGV {
+        // Constructing the signal handler frame must have failed. Stash the signal
+        // to deliver it later.
+        t.stash_sig();
+        if sig == sig::SIGSEGV {
+            // The kernel will kill the process after this. Make sure we know to treat
+            // it as fatal when we inject it. Also disable the signal handler to match
+            // what the kernel does.
+            t.did_set_sig_handler_default(sig::SIGSEGV);
+        }
+        return false;
+    }
+
+    // We stepped into a user signal handler.
+    ed_assert!(
+        t,
+        t.maybe_stop_sig() == sig::SIGTRAP,
+        "Got unexpected status {}",
+        t.status()
+    );
+    ed_assert!(
+        t,
+        t.get_signal_user_handler(sig) == Some(t.ip()),
+        "Expected handler IP {:?}, got {}; is the signal handler already set up?",
+        t.get_signal_user_handler(sig),
+        t.ip()
+    );
+
+    if t.signal_handler_takes_siginfo(sig) {
+        // The kernel copied siginfo into userspace so it can pass a pointer to
+        // the signal handler. Replace the contents of that siginfo with
+        // the exact data we want to deliver. (We called set_siginfo()
+        // above to set that data, but the kernel sanitizes the passed-in data
+        // which wipes out certain fields; e.g. we can't set SI_KERNEL in si_code.)
+        let siginfo = t.ev().signal_event().siginfo.clone();
+        setup_sigframe_siginfo(t, &siginfo);
+    }
+
+    // The kernel clears the FPU state on entering the signal handler, but prior
+    // to 4.7 or thereabouts ptrace can still return stale values. Fix that here.
+    // This also sets bit 0 of the XINUSE register to 1 to avoid issues with
+    // get/set_extra_regs.
+    let mut e = t.extra_regs_ref().clone();
+    e.reset();
+    t.set_extra_regs(&e);
+
+    true
+}
+
+fn setup_sigframe_siginfo(t: &mut RecordTask, siginfo: &siginfo_t) {
+    match t.arch() {
+        SupportedArch::X64 => {
+            let dest = RemotePtr::<siginfo_t>::new_from_val(t.regs_ref().si());
+            write_val_mem(t, dest, siginfo, None);
+        }
+        SupportedArch::X86 => {
+            // The handler's arguments are on the stack above the return
+            // address: the signal number, then the siginfo pointer.
+            let p = RemotePtr::<u32>::cast(t.regs_ref().sp()) + 2usize;
+            let dest_addr = read_val_mem(t, p, None);
+            let dest = RemotePtr::<x86::siginfo_t>::new_from_val(dest_addr as usize);
+            let mut si = read_val_mem(t, dest, None);
+            set_x86_siginfo(siginfo, &mut si);
+            write_val_mem(t, dest, &si, None);
+        }
+    }
+}
+
+/// Convert `siginfo` to the layout of an x86 tracee. Which union members are
+/// meaningful depends on the signal and si_code, as in the kernel's
+/// copy_siginfo_to_user32().
+fn set_x86_siginfo(siginfo: &siginfo_t, si: &mut x86::siginfo_t) {
+    si.si_signo = siginfo.si_signo;
+    si.si_errno = siginfo.si_errno;
+    si.si_code = siginfo.si_code;
+    let code = siginfo.si_code;
+    let from = &siginfo._sifields;
+    let to = &mut si._sifields;
+    let to_x86_ptr = |addr: usize| x86::ptr::<u8>::from_remote_ptr(RemotePtr::new_from_val(addr));
+    unsafe {
+        if code > SI_USER && code < SI_KERNEL {
+            match siginfo.si_signo {
+                SIGILL | SIGFPE | SIGSEGV | SIGBUS | SIGTRAP => {
+                    to._sigfault.si_addr_ = to_x86_ptr(from._sigfault.si_addr as usize);
+                    to._sigfault.si_addr_lsb_ = from._sigfault.si_addr_lsb;
+                }
+                SIGCHLD => {
+                    to._sigchld = x86::siginfo_sigchld {
+                        si_pid_: from._sigchld.si_pid,
+                        si_uid_: from._sigchld.si_uid,
+                        si_status_: from._sigchld.si_status,
+                        si_utime_: from._sigchld.si_utime as _,
+                        si_stime_: from._sigchld.si_stime as _,
+                    };
+                }
+                SIGSYS => {
+                    to._sigsys = x86::siginfo_sigsys {
+                        _call_addr: to_x86_ptr(from._sigsys._call_addr as usize),
+                        _syscall: from._sigsys._syscall,
+                        _arch: from._sigsys._arch,
+                    };
+                }
+                _ => {
+                    to._sigpoll.si_band_ = from._sigpoll.si_band as _;
+                    to._sigpoll.si_fd_ = from._sigpoll.si_fd;
+                }
+            }
+        } else if code == SI_TIMER {
+            to._timer.si_tid_ = from._timer.si_tid;
+            to._timer.si_overrun_ = from._timer.si_overrun;
+            to._timer.si_sigval_.sival_int = from._timer.si_sigval.sival_int;
+        } else if code == SI_SIGIO {
+            to._sigpoll.si_band_ = from._sigpoll.si_band as _;
+            to._sigpoll.si_fd_ = from._sigpoll.si_fd;
+        } else {
+            // SI_USER, SI_KERNEL, SI_QUEUE etc.: the sender and for the
+            // queued signals a value. _kill is a prefix of _rt.
+            to._rt.si_pid_ = from._rt.si_pid;
+            to._rt.si_uid_ = from._rt.si_uid;
+            if code < 0 {
+                to._rt.si_sigval_.sival_int = from._rt.si_sigval.sival_int;
+            }
+        }
+    }
 }
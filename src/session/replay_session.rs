@@ -20,6 +20,7 @@ use crate::{
     emu_fs::{EmuFs, EmuFsSharedPtr},
     event::{Event, EventType, SignalDeterministic, SignalEventData, SyscallState},
     fast_forward::{fast_forward_through_instruction, FastForwardStatus},
+    file_monitor::magic_save_data_monitor::SaveDataMismatch,
     flags::Flags as ProgramFlags,
     kernel_abi::{is_execve_syscall, syscall_number_for_exit, SupportedArch},
     kernel_metadata::syscall_name,
@@ -50,7 +51,7 @@ use crate::{
             Enabled,
             Traced,
         },
-        diversion_session::DiversionSessionSharedPtr,
+        diversion_session::DiversionSession,
         replay_session::ReplayTraceStepType::TstepNone,
         session_inner::{BreakStatus, RunCommand, SessionInner},
         task::{
@@ -76,11 +77,14 @@ use crate::{
         cpuid,
         cpuid_compatible,
         default_action,
+        dump_process_memory,
         find_cpuid_record,
         running_under_rd,
+        should_checksum,
         should_dump_memory,
         trapped_instruction_at,
         trapped_instruction_len,
+        validate_process_memory,
         xcr0,
         xsave_enabled,
         CPUIDData,
@@ -111,8 +115,6 @@ use std::{
 
 const USE_BREAKPOINT_TARGET: bool = true;
 
-pub type ReplaySessionSharedPtr = Rc<RefCell<ReplaySession>>;
-
 /// ReplayFlushBufferedSyscallState is saved in Session and cloned with its
 /// Session, so it needs to be simple data, i.e. not holding pointers to
 /// per-Session data.
@@ -367,6 +369,12 @@ pub struct ReplaySession {
     syscall_bp_vm: RefCell<Option<AddressSpaceSharedPtr>>,
     // @TODO Set to the 0 address on init. More principled solution?!
     syscall_bp_addr: Cell<RemoteCodePtr>,
+    /// Save-data divergences seen so far when `Flags::keep_going_on_save_data_mismatch`
+    /// is set.
+    /// Shared with all clones of this session, so whoever holds the original
+    /// session (e.g. the replay command while gdb drives a timeline of clones)
+    /// sees them all.
+    save_data_mismatches: Rc<RefCell<Vec<SaveDataMismatch>>>,
 }
 
 #[derive(Copy, Clone)]
@@ -374,6 +382,9 @@ pub struct Flags {
     pub redirect_stdio: bool,
     pub share_private_mappings: bool,
     pub cpu_unbound: bool,
+    /// Report divergences in the contents of writes to RD_MAGIC_SAVE_DATA_FD
+    /// and keep replaying instead of aborting.
+    pub keep_going_on_save_data_mismatch: bool,
 }
 
 impl Drop for ReplaySession {
@@ -412,20 +423,117 @@ impl ReplaySession {
     /// to keep a session around inactive, keep the clone and not the original
     /// session. Partially initialized sessions automatically finish
     /// initializing when necessary.
-    pub fn clone_replay(&self) -> ReplaySessionSharedPtr {
-        unimplemented!()
+    ///
+    /// DIFF NOTE: Called ReplaySession::clone() in rr.
+    pub fn clone_replay(&self) -> SessionSharedPtr {
+        log!(
+            LogDebug,
+            "Deepforking ReplaySession {:?} ...",
+            self as *const Self
+        );
+        self.finish_initializing();
+        let maybe_bp_task = self
+            .syscall_bp_vm
+            .borrow()
+            .as_ref()
+            .and_then(|vm| vm.task_set().iter().next());
+        match maybe_bp_task {
+            Some(t) => self.clear_syscall_bp(t.borrow_mut().as_mut()),
+            None => (),
+        }
+
+        let session = ReplaySession {
+            session_inner: SessionInner::new_from(&self.session_inner),
+            emu_fs: EmuFs::create(),
+            trace_in: RefCell::new(self.trace_in.borrow().clone()),
+            trace_frame: RefCell::new(self.trace_frame.borrow().clone()),
+            current_step: Cell::new(self.current_step.get()),
+            ticks_at_start_of_event: Cell::new(self.ticks_at_start_of_event.get()),
+            cpuid_bug_detector: RefCell::new(self.cpuid_bug_detector.borrow().clone()),
+            last_siginfo_: Cell::new(self.last_siginfo_.get()),
+            flags_: self.flags_,
+            fast_forward_status: Cell::new(self.fast_forward_status.get()),
+            trace_start_time: Cell::new(self.trace_start_time.get()),
+            syscall_bp_vm: Default::default(),
+            syscall_bp_addr: Default::default(),
+            save_data_mismatches: self.save_data_mismatches.clone(),
+        };
+
+        let mut rc: SessionSharedPtr = Rc::new(Box::new(session));
+        let weak_self = Rc::downgrade(&rc);
+        // We never change the weak_self pointer so its a good idea to use
+        // a bit of unsafe here.
+        unsafe { Rc::get_mut_unchecked(&mut rc) }.weak_self = weak_self;
+        log!(
+            LogDebug,
+            "  deepfork session is {:?}",
+            rc.as_replay().unwrap() as *const Self
+        );
+
+        self.copy_state_to_session(
+            rc.clone(),
+            &self.emufs(),
+            &mut rc.as_replay().unwrap().emufs_mut(),
+        );
+        rc
     }
 
     /// Return true if we're in a state where it's OK to clone. For example,
     /// we can't clone in some syscalls.
     pub fn can_clone(&self) -> bool {
-        unimplemented!()
+        self.finish_initializing();
+        self.current_task().is_some()
+            && self.done_initial_exec()
+            && can_checkpoint_at(&self.current_trace_frame())
     }
 
     /// Like `clone()`, but return a session in "diversion" mode,
     /// which allows free execution.
-    pub fn clone_diversion(&self) -> DiversionSessionSharedPtr {
-        unimplemented!()
+    ///
+    /// The returned session is fully initialized.
+    pub fn clone_diversion(&self) -> SessionSharedPtr {
+        self.finish_initializing();
+        let maybe_bp_task = self
+            .syscall_bp_vm
+            .borrow()
+            .as_ref()
+            .and_then(|vm| vm.task_set().iter().next());
+        match maybe_bp_task {
+            Some(t) => self.clear_syscall_bp(t.borrow_mut().as_mut()),
+            None => (),
+        }
+
+        log!(
+            LogDebug,
+            "Deepforking ReplaySession {:?} to DiversionSession...",
+            self as *const Self
+        );
+
+        let mut session = DiversionSession::new();
+        session.ticks_semantics_ = self.ticks_semantics_;
+        session.tracee_socket = self.tracee_socket.clone();
+        session
+            .tracee_socket_fd_number
+            .set(self.tracee_socket_fd_number.get());
+
+        let mut rc: SessionSharedPtr = Rc::new(Box::new(session));
+        let weak_self = Rc::downgrade(&rc);
+        // We never change the weak_self pointer so its a good idea to use
+        // a bit of unsafe here.
+        unsafe { Rc::get_mut_unchecked(&mut rc) }.weak_self = weak_self;
+        log!(
+            LogDebug,
+            "  deepfork session is {:?}",
+            rc.as_diversion().unwrap() as *const DiversionSession
+        );
+
+        self.copy_state_to_session(
+            rc.clone(),
+            &self.emufs(),
+            &mut rc.as_diversion().unwrap().emufs_mut(),
+        );
+        rc.finish_initializing();
+        rc
     }
 
     pub fn emufs(&self) -> Ref<'_, EmuFs> {
@@ -482,6 +590,41 @@ impl ReplaySession {
         &self.flags_
     }
 
+    /// Clones (e.g. when reverse executing under gdb) can replay the same
+    /// event more than once, so a mismatch that was already seen is ignored.
+    pub fn add_save_data_mismatch(&self, mismatch: SaveDataMismatch) {
+        let mut mismatches = self.save_data_mismatches.borrow_mut();
+        if !mismatches
+            .iter()
+            .any(|m| m.time == mismatch.time && m.tid == mismatch.tid)
+        {
+            mismatches.push(mismatch);
+        }
+    }
+
+    pub fn save_data_mismatches(&self) -> Ref<'_, Vec<SaveDataMismatch>> {
+        self.save_data_mismatches.borrow()
+    }
+
+    /// Return a semantic description of where we are in the replay of the
+    /// current trace frame.
+    pub fn current_step_key(&self) -> ReplayStepKey {
+        ReplayStepKey::new_with(self.current_step.get().action)
+    }
+
+    /// Return true if the next replay step is the successful exit of the
+    /// syscall `syscallno` (i.e. we are at a syscall exit event for it that
+    /// we haven't started replaying yet).
+    pub fn next_step_is_successful_syscall_exit(&self, syscallno: i32) -> bool {
+        let frame = self.current_trace_frame();
+        let ev = frame.event();
+        self.current_step.get().action == ReplayTraceStepType::TstepNone
+            && ev.is_syscall_event()
+            && ev.syscall_event().number == syscallno
+            && ev.syscall_event().state == SyscallState::ExitingSyscall
+            && !frame.regs_ref().syscall_failed()
+    }
+
     fn new<T: AsRef<OsStr>>(dir: Option<&T>, flags: Flags) -> ReplaySession {
         let mut rs = ReplaySession {
             emu_fs: EmuFs::create(),
@@ -497,6 +640,7 @@ impl ReplaySession {
             fast_forward_status: Default::default(),
             syscall_bp_vm: Default::default(),
             syscall_bp_addr: Default::default(),
+            save_data_mismatches: Default::default(),
         };
 
         let semantics = rs.trace_in.borrow().ticks_semantics();
@@ -1076,7 +1220,7 @@ impl ReplaySession {
         t: &mut ReplayTask,
         constraints: &StepConstraints,
     ) -> Completion {
-        let mut ticks_request: TicksRequest = Default::default();
+        let mut ticks_request = TicksRequest::ResumeUnlimitedTicks;
         if !compute_ticks_request(t, constraints, &mut ticks_request) {
             return Completion::Incomplete;
         }
@@ -1429,10 +1573,19 @@ impl ReplaySession {
 
     fn advance_to_ticks_target(
         &self,
-        _t: &ReplayTask,
-        _constraints: &StepConstraints,
+        t: &mut ReplayTask,
+        constraints: &StepConstraints,
     ) -> Completion {
-        unimplemented!();
+        loop {
+            let mut ticks_request = TicksRequest::ResumeUnlimitedTicks;
+            if !compute_ticks_request(t, constraints, &mut ticks_request) {
+                return Completion::Incomplete;
+            }
+            self.continue_or_step(t, constraints, ticks_request, None);
+            if t.maybe_stop_sig() == SIGTRAP {
+                return Completion::Incomplete;
+            }
+        }
     }
 
     fn emulate_deterministic_signal(
@@ -2304,6 +2457,19 @@ fn is_fatal_default_action(sig: Sig) -> bool {
 /// Return true if replaying `ev` by running `step` should result in
 /// the target task having the same ticks value as it did during
 /// recording.
+fn can_checkpoint_at(frame: &TraceFrame) -> bool {
+    let ev = frame.event();
+    if ev.has_ticks_slop() {
+        return false;
+    }
+    match ev.event_type() {
+        // At exits, we can't clone the exiting tasks, so
+        // don't event bother trying to clone.
+        EventType::EvExit => false,
+        _ => true,
+    }
+}
+
 fn has_deterministic_ticks(ev: &Event, step: ReplayTraceStep) -> bool {
     if ev.has_ticks_slop() {
         return false;
@@ -2315,10 +2481,16 @@ fn has_deterministic_ticks(ev: &Event, step: ReplayTraceStep) -> bool {
     ReplayTraceStepType::TstepProgramAsyncSignalInterrupt != step.action
 }
 
-fn debug_memory(t: &ReplayTask) {
+fn debug_memory(t: &mut ReplayTask) {
     let current_time = t.current_frame_time();
-    if should_dump_memory(t.current_trace_frame().event(), current_time) {
-        unimplemented!()
+    let ev = t.current_trace_frame().event().clone();
+    if should_dump_memory(&ev, current_time) {
+        dump_process_memory(t, current_time, "rep");
+    }
+    if t.session().done_initial_exec() && should_checksum(&ev, current_time) {
+        // Validate the checksum we computed during the
+        // recording phase.
+        validate_process_memory(t, current_time);
     }
 }
 
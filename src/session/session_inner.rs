@@ -11,6 +11,7 @@ use crate::{
             address_space::{AddressSpace, AddressSpaceSharedPtr, AddressSpaceSharedWeakPtr},
             BreakpointType,
             WatchConfig,
+            WatchType,
         },
         task::{
             task_inner::{CapturedState, TrapReasons},
@@ -79,12 +80,22 @@ impl BreakStatus {
     /// True when we stopped because we hit a software or hardware breakpoint at
     /// `task`'s current ip().
     pub fn hardware_or_software_breakpoint_hit(&self) -> bool {
-        unimplemented!()
+        // Hardware execution watchpoints behave like breakpoints: the CPU
+        // stops before the instruction is executed.
+        self.breakpoint_hit
+            || self
+                .watchpoints_hit
+                .iter()
+                .any(|w| w.type_ == WatchType::WatchExec)
     }
 
     /// Returns just the data watchpoints hit.
     pub fn data_watchpoints_hit(&self) -> Vec<WatchConfig> {
-        unimplemented!()
+        self.watchpoints_hit
+            .iter()
+            .filter(|w| w.type_ != WatchType::WatchExec)
+            .cloned()
+            .collect()
     }
 
     pub fn any_break(&self) -> bool {
@@ -96,7 +107,7 @@ impl BreakStatus {
     }
 }
 
-#[derive(Copy, Clone, PartialEq, Eq)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub enum RunCommand {
     /// Continue until we hit a breakpoint or a new replay event
     RunContinue,
@@ -376,6 +387,25 @@ impl SessionInner {
         s
     }
 
+    /// Create a session that shares the tracee socket and other global state
+    /// of `other` but no tasks. Used when cloning a session for checkpoints.
+    ///
+    /// DIFF NOTE: Session copy constructor in rr.
+    pub(super) fn new_from(other: &SessionInner) -> SessionInner {
+        let mut s = SessionInner::new();
+        *s.statistics_.get_mut() = *other.statistics_.borrow();
+        s.tracee_socket = other.tracee_socket.clone();
+        s.tracee_socket_fd_number
+            .set(other.tracee_socket_fd_number.get());
+        s.next_task_serial_.set(other.next_task_serial_.get());
+        s.syscall_seccomp_ordering_
+            .set(other.syscall_seccomp_ordering_.get());
+        s.ticks_semantics_ = other.ticks_semantics_;
+        s.done_initial_exec_.set(other.done_initial_exec_.get());
+        s.visible_execution_ = other.visible_execution_;
+        s
+    }
+
     pub(super) fn create_spawn_task_error_pipe(&mut self) -> ScopedFd {
         let res = pipe2(OFlag::O_CLOEXEC);
         match res {
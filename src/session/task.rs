@@ -31,7 +31,7 @@ use crate::{
     util::{is_zombie_process, to_timeval},
     wait_status::{MaybeStopSignal, WaitStatus},
 };
-use libc::{pid_t, waitpid, EINTR, ENOSYS, SIGSTOP, SIGTRAP};
+use libc::{pid_t, waitpid, __WALL, EINTR, ENOSYS, SIGSTOP, SIGTRAP, WNOHANG, WSTOPPED};
 use nix::errno::errno;
 use std::{
     cell::RefCell,
@@ -347,8 +347,28 @@ pub trait Task: DerefMut<Target = TaskInner> {
 
     /// Return true if the status of this has changed, but don't
     /// block.
-    fn try_wait(&self) -> bool {
-        unimplemented!()
+    fn try_wait(&mut self) -> bool {
+        let mut raw_status: i32 = 0;
+        let ret = unsafe { waitpid(self.tid, &mut raw_status, WNOHANG | __WALL | WSTOPPED) };
+        ed_assert!(
+            self,
+            0 <= ret,
+            "waitpid({}, NOHANG) failed with {}",
+            self.tid,
+            ret
+        );
+        log!(
+            LogDebug,
+            "waitpid({}, NOHANG) returns {}, status {}",
+            self.tid,
+            ret,
+            WaitStatus::new(raw_status)
+        );
+        if ret == self.tid {
+            self.did_waitpid(WaitStatus::new(raw_status));
+            return true;
+        }
+        false
     }
 
     /// Block until the status of self changes. wait() expects the wait to end
@@ -1,6 +1,7 @@
 use super::{
     task_common::{
         at_preload_init_common,
+        on_syscall_exit,
         post_vm_clone_common,
         read_mem,
         read_val_mem,
@@ -10,6 +11,7 @@ use super::{
         write_val_mem,
     },
     task_inner::PtraceData,
+    TaskSharedPtr,
     TaskSharedWeakPtr,
 };
 use crate::{
@@ -19,13 +21,16 @@ use crate::{
         kernel::user_desc,
         perf_event::{PERF_EVENT_IOC_DISABLE, PERF_EVENT_IOC_ENABLE},
         ptrace::{
+            PTRACE_EVENT_CLONE,
+            PTRACE_EVENT_FORK,
+            PTRACE_EVENT_VFORK,
             PTRACE_GETEVENTMSG,
             PTRACE_GETSIGMASK,
             PTRACE_O_TRACEEXIT,
             PTRACE_SETSIGINFO,
             PTRACE_SETSIGMASK,
         },
-        signal::{siginfo_t, __SIGRTMIN},
+        signal::{siginfo_t, __SIGRTMIN, SI_QUEUE},
     },
     event::{
         Event,
@@ -34,11 +39,16 @@ use crate::{
         SignalResolvedDisposition,
         SyscallEventData,
         SyscallState,
+        SyscallbufFlushEventData,
     },
+    file_monitor::preserve_file_monitor::PreserveFileMonitor,
     kernel_abi::{
         is_exit_group_syscall,
         is_exit_syscall,
         is_restart_syscall_syscall,
+        is_wait4_syscall,
+        is_waitid_syscall,
+        is_waitpid_syscall,
         native_arch,
         sigaction_sigset_size,
         syscall_number_for_execve,
@@ -47,11 +57,23 @@ use crate::{
         SupportedArch,
     },
     kernel_metadata::syscall_name,
-    kernel_supplement::{sig_set_t, SA_RESETHAND, SA_SIGINFO, _NSIG},
+    kernel_supplement::{sig_set_t, _NSIG, SA_RESETHAND, SA_SIGINFO},
     log::{LogDebug, LogWarn},
-    preload_interface::{preload_globals, syscallbuf_record, PRELOAD_THREAD_LOCALS_SIZE},
-    preload_interface_arch::{preload_thread_locals, rdcall_init_preload_params},
+    perf_counters::TIME_SLICE_SIGNAL,
+    preload_interface::{
+        mprotect_record,
+        preload_globals,
+        syscallbuf_hdr,
+        syscallbuf_record,
+        PRELOAD_THREAD_LOCALS_SIZE,
+    },
+    preload_interface_arch::{
+        preload_thread_locals,
+        rdcall_init_buffers_params,
+        rdcall_init_preload_params,
+    },
     record_signal::disarm_desched_event,
+    record_syscall::TaskSyscallStateSharedPtr,
     registers::{with_converted_registers, Registers},
     remote_code_ptr::RemoteCodePtr,
     remote_ptr::{RemotePtr, Void},
@@ -60,6 +82,7 @@ use crate::{
     session::{
         address_space::{
             address_space::AddressSpace,
+            kernel_mapping::KernelMapping,
             memory_range::MemoryRange,
             BreakpointType,
             Enabled,
@@ -124,19 +147,35 @@ use crate::{
         SignalAction,
     },
     wait_status::WaitStatus,
-    weak_ptr_set::WeakPtrSet,
+    weak_ptr_set::{WeakPtrSet, WeakPtrWrap},
+};
+use libc::{
+    pid_t,
+    syscall,
+    SYS_rt_sigqueueinfo,
+    SYS_rt_tgsigqueueinfo,
+    SYS_tgkill,
+    EINVAL,
+    EIO,
+    PR_TSC_ENABLE,
+    SIGCHLD,
+};
+use nix::{
+    errno::errno,
+    fcntl::readlink,
+    sched::sched_yield,
+    sys::{mman::ProtFlags, stat::stat},
 };
-use libc::{pid_t, syscall, SYS_tgkill, EINVAL, EIO, PR_TSC_ENABLE, SIGCHLD};
-use nix::{errno::errno, fcntl::readlink, sched::sched_yield, sys::mman::ProtFlags};
 use owning_ref::OwningHandle;
 use ptr::NonNull;
 use std::{
-    cell::{Ref, RefCell, RefMut},
+    cell::{Cell, Ref, RefCell, RefMut},
     cmp::min,
     collections::VecDeque,
     convert::{TryFrom, TryInto},
     error::Error,
     ffi::{c_void, CString, OsStr, OsString},
+    fs,
     mem::size_of,
     ops::{Deref, DerefMut},
     ptr::{self, copy_nonoverlapping},
@@ -342,9 +381,10 @@ pub enum SignalDisposition {
     SignalHandler,
 }
 
+#[derive(Clone)]
 pub struct StashedSignal {
-    siginfo: siginfo_t,
-    deterministic: SignalDeterministic,
+    pub siginfo: siginfo_t,
+    pub deterministic: SignalDeterministic,
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
@@ -377,10 +417,10 @@ pub struct RecordTask {
     /// We use this to drive scheduling decisions. rd's scheduler is
     /// deliberately simple and unfair; a task never runs as long as there's
     /// another runnable task with a lower nice value.
-    pub priority: i32,
+    pub priority: Cell<i32>,
     /// Tasks with in_round_robin_queue set are in the session's
     /// in_round_robin_queue instead of its task_priority_set.
-    pub in_round_robin_queue: bool,
+    pub in_round_robin_queue: Cell<bool>,
 
     /// ptrace emulation state
     ///
@@ -395,9 +435,14 @@ pub struct RecordTask {
     pub emulated_stop_code: WaitStatus,
     /// Always zero while no ptracer is attached.
     pub emulated_ptrace_options: u32,
-    /// One of PTRACE_CONT, PTRACE_SYSCALL --- or 0 if the tracee has not been
+    /// One of PTRACE_CONT, PTRACE_SYSCALL, PTRACE_SINGLESTEP, PTRACE_SYSEMU,
+    /// PTRACE_SYSEMU_SINGLESTEP --- or 0 if the tracee has not been
     /// continued by its ptracer yet, or has no ptracer.
     pub emulated_ptrace_cont_command: u32,
+    /// The SECCOMP_RET_DATA of a SECCOMP_RET_TRACE result of the tracee's own
+    /// seccomp filter, while the PTRACE_EVENT_SECCOMP stop that it causes for
+    /// the emulated ptracer is waiting for the syscall entry.
+    pub emulated_seccomp_event_data: Option<u16>,
     /// true when a ptracer/waiter wait() can return `emulated_stop_code`.
     pub emulated_stop_pending: bool,
     /// true if this task needs to send a SIGCHLD to its ptracer for its
@@ -500,6 +545,9 @@ pub struct RecordTask {
     pub next_pmc_interrupt_is_for_user: bool,
 
     pub did_record_robust_futex_changes: bool,
+
+    /// State for the syscall currently being recorded, if any.
+    pub syscall_state: Option<TaskSyscallStateSharedPtr>,
 }
 
 impl Deref for RecordTask {
@@ -575,6 +623,7 @@ impl Task for RecordTask {
     }
 
     fn on_syscall_exit(&mut self, syscallno: i32, arch: SupportedArch, regs: &Registers) {
+        on_syscall_exit(self, syscallno, arch, regs);
         with_converted_registers(regs, arch, |regs| {
             rd_arch_function!(self, on_syscall_exit_arch, arch, syscallno, regs);
         })
@@ -729,6 +778,9 @@ impl Task for RecordTask {
 
 impl RecordTask {
     pub const SIGCHLD_SYNTHETIC: i32 = 0xbeadf00du32 as i32;
+    /// si_code of a TIME_SLICE_SIGNAL we send ourselves to kick a task out of
+    /// a blocking syscall.
+    pub const SYNTHETIC_TIME_SLICE_SI_CODE: i32 = -9999;
 
     /// Every Task owned by a RecordSession is a RecordTask. Functionality that
     /// only applies during recording belongs here.
@@ -742,12 +794,13 @@ impl RecordTask {
             task_inner: TaskInner::new(session, tid, None, serial, a),
             ticks_at_last_recorded_syscall_exit: 0,
             time_at_start_of_last_timeslice: 0,
-            priority: 0,
-            in_round_robin_queue: false,
+            priority: Cell::new(0),
+            in_round_robin_queue: Cell::new(false),
             emulated_ptracer: None,
             emulated_ptrace_event_msg: 0,
             emulated_ptrace_options: 0,
             emulated_ptrace_cont_command: 0,
+            emulated_seccomp_event_data: None,
             emulated_stop_pending: false,
             emulated_ptrace_sigchld_pending: false,
             emulated_sigchld_pending: false,
@@ -777,6 +830,7 @@ impl RecordTask {
             break_at_syscallbuf_final_instruction: false,
             next_pmc_interrupt_is_for_user: false,
             did_record_robust_futex_changes: false,
+            syscall_state: None,
             // Implicit
             registers_at_start_of_last_timeslice: Registers::new(a),
             emulated_ptrace_tracees: Default::default(),
@@ -858,13 +912,83 @@ impl RecordTask {
     /// of *exit from* the rdcall.  Registers will be updated with
     /// the return value from the rdcall, which is also returned
     /// from this call.
-    pub fn init_buffers(&self) {
+    pub fn init_buffers(&mut self) {
         let arch = self.arch();
         rd_arch_function!(self, init_buffers_arch, arch)
     }
 
-    fn init_buffers_arch<Arch: Architecture>(&self) {
-        unimplemented!()
+    fn init_buffers_arch<Arch: Architecture>(&mut self) {
+        // NB: the tracee can't be interrupted with a signal while
+        // we're processing the rdcall, because it's masked off all
+        // signals.
+        let child_args =
+            RemotePtr::<rdcall_init_buffers_params<Arch>>::from(self.regs_ref().arg1());
+        let mut args = read_val_mem(self, child_args, None);
+
+        // @TODO Read cloning is not supported yet so the tracee never gets a
+        // cloned file data fd.
+        args.cloned_file_data_fd = -1;
+        let (use_syscall_buffer, syscall_buffer_size) = {
+            let sess = self.session();
+            let rec_sess = sess.as_record().unwrap();
+            (
+                rec_sess.use_syscall_buffer(),
+                rec_sess.syscall_buffer_size(),
+            )
+        };
+        if use_syscall_buffer {
+            self.syscallbuf_size = syscall_buffer_size;
+            let desched_fd_child = args.desched_counter_fd;
+            let km: KernelMapping;
+            let desched_fd: ScopedFd;
+            {
+                let mut remote = AutoRemoteSyscalls::new(self);
+                km = remote.init_syscall_buffer(RemotePtr::null());
+                remote.task_mut().desched_fd_child = desched_fd_child;
+                // Prevent the child from closing this fd
+                remote
+                    .task_mut()
+                    .fd_table_shr_ptr()
+                    .borrow_mut()
+                    .add_monitor(
+                        remote.task_mut(),
+                        desched_fd_child,
+                        Box::new(PreserveFileMonitor::new()),
+                    );
+                desched_fd = remote.retrieve_fd(desched_fd_child);
+            }
+            self.desched_fd = desched_fd;
+
+            let mode = self.trace_writer_mut().write_mapped_region(
+                self,
+                &km,
+                &km.fake_stat(),
+                &[],
+                Some(MappingOrigin::RdBufferMapping),
+                None,
+            );
+            ed_assert_eq!(self, mode, RecordInTrace::DontRecordInTrace);
+
+            args.syscallbuf_ptr =
+                Arch::from_remote_ptr(RemotePtr::<u8>::cast(self.syscallbuf_child));
+            args.syscallbuf_size = self.syscallbuf_size as u32;
+        } else {
+            args.syscallbuf_ptr = Arch::from_remote_ptr(RemotePtr::<u8>::null());
+            args.syscallbuf_size = 0;
+        }
+        args.scratch_buf = Arch::from_remote_ptr(RemotePtr::<u8>::cast(self.scratch_ptr));
+        args.usable_scratch_size = self.usable_scratch_size() as u32;
+
+        // Return the mapped buffers to the child.
+        write_val_mem(self, child_args, &args, None);
+
+        // The tracee doesn't need this addr returned, because it's
+        // already written to the inout `args` param, but we stash it
+        // away in the return value slot so that we can easily check
+        // that we map the segment at the same addr during replay.
+        let mut r = self.regs_ref().clone();
+        r.set_syscall_result(self.syscallbuf_child.as_usize());
+        self.set_regs(&r);
     }
 
     pub fn post_exec(&mut self) {
@@ -924,8 +1048,30 @@ impl RecordTask {
     }
 
     /// Emulate 'tracer' ptracing this task.
-    pub fn set_emulated_ptracer(&self, _tracer: &RecordTask) {
-        unimplemented!()
+    pub fn set_emulated_ptracer(&mut self, tracer: &mut RecordTask) {
+        ed_assert!(self, self.emulated_ptracer.is_none());
+        self.emulated_ptracer = Some(tracer.weak_self_ptr());
+        tracer.emulated_ptrace_tracees.insert(self.weak_self_ptr());
+    }
+
+    /// Stop emulating 'tracer' ptracing this task.
+    /// DIFF NOTE: rr calls set_emulated_ptracer(nullptr) for this. The tracer is
+    /// almost always borrowed further up the stack (e.g. it is doing the
+    /// PTRACE_DETACH) so we need it to be passed in explicitly.
+    pub fn clear_emulated_ptracer(&mut self, tracer: &mut RecordTask) {
+        ed_assert!(
+            self,
+            self.emulated_ptracer
+                .as_ref()
+                .map_or(false, |w| w.ptr_eq(&tracer.weak_self))
+        );
+        ed_assert!(
+            self,
+            self.emulated_stop_type == EmulatedStopType::NotStopped
+                || self.emulated_stop_type == EmulatedStopType::GroupStop
+        );
+        tracer.emulated_ptrace_tracees.erase(self.weak_self_ptr());
+        self.emulated_ptracer = None;
     }
 
     /// Call this when an event occurs that should stop a ptraced task.
@@ -935,17 +1081,76 @@ impl RecordTask {
     /// make one up based on the status (unless the status is an exit code).
     /// Returns true if the task is stopped-for-emulated-ptrace, false otherwise.
     pub fn emulate_ptrace_stop(
-        &self,
-        _status: WaitStatus,
-        _siginfo: Option<&siginfo_t>,
-        _si_code: Option<i32>,
+        &mut self,
+        status: WaitStatus,
+        siginfo: Option<&siginfo_t>,
+        si_code: Option<i32>,
     ) -> bool {
-        unimplemented!()
+        ed_assert!(
+            self,
+            self.emulated_stop_type == EmulatedStopType::NotStopped
+        );
+        if self.emulated_ptracer.is_none() {
+            return false;
+        }
+        match siginfo {
+            Some(si) => {
+                ed_assert_eq!(
+                    self,
+                    status.ptrace_signal().map(|s| s.as_raw()),
+                    Some(si.si_signo)
+                );
+                self.save_ptrace_signal_siginfo(si);
+            }
+            None => {
+                let mut si: siginfo_t = Default::default();
+                si.si_signo = status.ptrace_signal().unwrap().as_raw();
+                if status.maybe_ptrace_event().is_ptrace_event() || status.is_syscall() {
+                    si.si_code = status.get() >> 8;
+                } else {
+                    si.si_code = si_code.unwrap_or(0);
+                }
+                self.save_ptrace_signal_siginfo(&si);
+            }
+        }
+        self.force_emulate_ptrace_stop(status);
+        true
     }
 
     /// Force the ptrace-stop state no matter what state the task is currently in.
-    pub fn force_emulate_ptrace_stopstatus(&self) -> WaitStatus {
-        unimplemented!()
+    /// DIFF NOTE: If the ptracer is already borrowed (it is usually the task
+    /// we're currently processing), it can't be notified from here. In that case
+    /// the caller must call send_synthetic_sigchld_if_necessary() on the ptracer.
+    pub fn force_emulate_ptrace_stop(&mut self, status: WaitStatus) {
+        self.emulated_stop_type = if status.maybe_group_stop_sig().is_sig() {
+            EmulatedStopType::GroupStop
+        } else {
+            EmulatedStopType::SignalDeliveryStop
+        };
+        self.emulated_stop_code = status;
+        self.emulated_stop_pending = true;
+        self.emulated_ptrace_sigchld_pending = true;
+
+        let tracer = self.emulated_ptracer.as_ref().unwrap().upgrade().unwrap();
+        match tracer.try_borrow_mut() {
+            Ok(mut tracer) => tracer
+                .as_record_task_mut()
+                .unwrap()
+                .send_synthetic_sigchld_if_necessary_with(Some(self)),
+            Err(_) => log!(
+                LogDebug,
+                "Emulated ptracer of {} is busy; leaving SIGCHLD to the caller",
+                self.tid
+            ),
+        }
+        // The SIGCHLD will eventually be reported to rd via a ptrace stop,
+        // interrupting wake_task's syscall (probably a waitpid) if necessary. At
+        // that point, we'll fix up the siginfo data with values that match what
+        // the kernel would have delivered for a real ptracer's SIGCHLD. When the
+        // signal handler (if any) returns, if wake_task was in a blocking wait that
+        // wait will be resumed, at which point rec_prepare_syscall_arch will
+        // discover the pending ptrace result and emulate the wait syscall to
+        // return that result immediately.
     }
 
     /// Called when we're about to deliver a signal to this task. If it's a
@@ -955,8 +1160,57 @@ impl RecordTask {
     /// Note that we can't set the correct siginfo when we send the signal, because
     /// it requires us to set information only the kernel has permission to set.
     /// Returns false if this signal should be deferred.
-    pub fn set_siginfo_for_synthetic_sigchld(&self, _si: &siginfo_t) -> bool {
-        unimplemented!()
+    pub fn set_siginfo_for_synthetic_sigchld(&mut self, si: &mut siginfo_t) -> bool {
+        if !is_synthetic_SIGCHLD(si) {
+            return true;
+        }
+
+        if self.is_syscall_restart() && EventType::EvSyscallInterruption == self.ev().event_type() {
+            let syscallno = self.regs_ref().original_syscallno() as i32;
+            let syscall_arch = self.ev().syscall_event().arch();
+            if is_waitpid_syscall(syscallno, syscall_arch)
+                || is_waitid_syscall(syscallno, syscall_arch)
+                || is_wait4_syscall(syscallno, syscall_arch)
+            {
+                // Wait-like syscalls always check for notifications from waited-for processes
+                // before they check for pending signals. So, if the tracee has a pending
+                // notification that also generated a signal, the wait syscall will return
+                // normally rather than returning with ERESTARTSYS etc. (The signal will
+                // be dequeued and any handler run on the return to userspace, however.)
+                // We need to emulate this by deferring our synthetic ptrace signal
+                // until after the wait syscall has returned.
+                log!(LogDebug, "Deferring signal because we're in a wait");
+                // Return false to tell the caller to defer the signal and resume
+                // the syscall.
+                return false;
+            }
+        }
+
+        for tracee in self.emulated_ptrace_tracees.iter() {
+            let mut tracee_b = tracee.borrow_mut();
+            let rtracee = tracee_b.as_record_task_mut().unwrap();
+            if rtracee.emulated_ptrace_sigchld_pending {
+                rtracee.emulated_ptrace_sigchld_pending = false;
+                rtracee.set_siginfo_for_waited_task::<NativeArch>(as_native_siginfo(si));
+                si._sifields._timer.si_sigval.sival_int = 0;
+                return true;
+            }
+        }
+
+        for child_tg in self.thread_group().children().iter() {
+            for child in child_tg.borrow().task_set().iter() {
+                let mut child_b = child.borrow_mut();
+                let rchild = child_b.as_record_task_mut().unwrap();
+                if rchild.emulated_sigchld_pending {
+                    rchild.emulated_sigchld_pending = false;
+                    rchild.set_siginfo_for_waited_task::<NativeArch>(as_native_siginfo(si));
+                    si._sifields._timer.si_sigval.sival_int = 0;
+                    return true;
+                }
+            }
+        }
+
+        true
     }
 
     pub fn set_siginfo_for_waited_task<Arch: Architecture>(&self, si: &mut Arch::siginfo_t) {
@@ -973,38 +1227,144 @@ impl RecordTask {
     /// Return a reference to the saved siginfo record for the stop-signal
     /// that we're currently in a ptrace-stop for.
     pub fn get_saved_ptrace_siginfo(&self) -> &siginfo_t {
-        unimplemented!()
+        let sig = self.emulated_stop_code.ptrace_signal();
+        ed_assert!(self, sig.is_some());
+        let sig = sig.unwrap().as_raw();
+        match self
+            .saved_ptrace_siginfos
+            .iter()
+            .find(|si| si.si_signo == sig)
+        {
+            Some(si) => si,
+            None => fatal!("No saved siginfo found for stop-signal???"),
+        }
     }
 
     /// When emulating a ptrace-continue with a signal number, extract the siginfo
     /// that was saved by `save_ptrace_signal_siginfo`. If no such siginfo was
     /// saved, make one up.
-    pub fn take_ptrace_signal_siginfo(&self, _sig: Sig) -> siginfo_t {
-        unimplemented!()
+    pub fn take_ptrace_signal_siginfo(&mut self, sig: Sig) -> siginfo_t {
+        match self
+            .saved_ptrace_siginfos
+            .iter()
+            .position(|si| si.si_signo == sig.as_raw())
+        {
+            Some(pos) => self.saved_ptrace_siginfos.remove(pos),
+            None => {
+                let mut si: siginfo_t = Default::default();
+                si.si_signo = sig.as_raw();
+                si
+            }
+        }
     }
 
     /// Returns true if this task is in a waitpid or similar that would return
     /// when t's status changes due to a ptrace event.
-    pub fn is_waiting_for_ptrace(&self, _t: &RecordTask) -> bool {
-        unimplemented!()
+    pub fn is_waiting_for_ptrace(&self, t: &RecordTask) -> bool {
+        // This task's process must be a ptracer of t.
+        // DIFF NOTE: We check for membership in our thread group rather than
+        // comparing thread groups as the ptracer may be borrowed.
+        match &t.emulated_ptracer {
+            Some(ptracer)
+                if self
+                    .thread_group()
+                    .task_set()
+                    .contains(&WeakPtrWrap(ptracer.clone())) =>
+            {
+                ()
+            }
+            _ => return false,
+        }
+        // XXX need to check `options` to make sure this task is eligible!!
+        match self.in_wait_type {
+            WaitType::WaitTypeNone => false,
+            WaitType::WaitTypeAny => true,
+            WaitType::WaitTypeSamePgid => getpgid(t.tgid()) == getpgid(self.tgid()),
+            WaitType::WaitTypePgid => getpgid(t.tgid()) == self.in_wait_pid,
+            // When waiting for a ptracee, a specific pid is interpreted as the
+            // exact tid.
+            WaitType::WaitTypePid => t.tid == self.in_wait_pid,
+        }
     }
 
     /// Returns true if this task is in a waitpid or similar that would return
     /// when t's status changes due to a regular event (exit).
-    pub fn is_waiting_for(&self, _t: &RecordTask) -> bool {
-        unimplemented!()
+    pub fn is_waiting_for(&self, t: &RecordTask) -> bool {
+        // t must be a child of this task.
+        match t.thread_group().parent() {
+            Some(parent) if Rc::ptr_eq(&parent, &self.thread_group_shr_ptr()) => (),
+            _ => return false,
+        }
+        match self.in_wait_type {
+            WaitType::WaitTypeNone => false,
+            WaitType::WaitTypeAny => true,
+            WaitType::WaitTypeSamePgid => getpgid(t.tgid()) == getpgid(self.tgid()),
+            WaitType::WaitTypePgid => getpgid(t.tgid()) == self.in_wait_pid,
+            WaitType::WaitTypePid => t.tgid() == self.in_wait_pid,
+        }
     }
 
     /// Call this to force a group stop for this task with signal 'sig',
     /// notifying ptracer if necessary.
-    pub fn apply_group_stop(&self, _sig: Sig) {
-        unimplemented!()
+    pub fn apply_group_stop(&mut self, sig: Sig) {
+        if self.emulated_stop_type == EmulatedStopType::NotStopped {
+            log!(
+                LogDebug,
+                "setting {} to GroupStop due to signal {}",
+                self.tid,
+                sig
+            );
+            let status = WaitStatus::for_group_sig(sig, self);
+            if !self.emulate_ptrace_stop(status, None, None) {
+                self.emulated_stop_type = EmulatedStopType::GroupStop;
+                self.emulated_stop_code = status;
+                self.emulated_stop_pending = true;
+                self.emulated_sigchld_pending = true;
+                let maybe_parent = self.session().find_task_from_rec_tid(self.get_parent_pid());
+                if let Some(parent) = maybe_parent {
+                    // DIFF NOTE: If the parent is busy further up the stack it will
+                    // pick up our emulated_sigchld_pending when it next calls
+                    // send_synthetic_sigchld_if_necessary.
+                    match parent.try_borrow_mut() {
+                        Ok(mut pb) => pb
+                            .as_record_task_mut()
+                            .unwrap()
+                            .send_synthetic_sigchld_if_necessary_with(Some(self)),
+                        Err(_) => log!(
+                            LogDebug,
+                            "parent of {} is busy, not sending SIGCHLD now",
+                            self.tid
+                        ),
+                    }
+                }
+            }
+        }
     }
 
     /// Call this after `sig` is delivered to this task.  Emulate
     /// sighandler updates induced by the signal delivery.
-    pub fn signal_delivered(&self, _sig: Sig) {
-        unimplemented!()
+    pub fn signal_delivered(&mut self, sig: Sig) {
+        {
+            let mut shb = self.sighandlers.borrow_mut();
+            let h: &mut Sighandler = shb.get_mut(sig);
+            if h.resethand {
+                reset_handler(h, self.arch());
+            }
+        }
+
+        if !self.is_sig_ignored(sig) {
+            if sig == sig::SIGTSTP || sig == sig::SIGTTIN || sig == sig::SIGTTOU {
+                if !self.signal_has_user_handler(sig) {
+                    self.apply_group_stop_to_thread_group(sig);
+                }
+            } else if sig == sig::SIGSTOP {
+                self.apply_group_stop_to_thread_group(sig);
+            } else if sig == sig::SIGCONT {
+                self.emulate_sigcont();
+            }
+        }
+
+        self.send_synthetic_sigchld_if_necessary();
     }
 
     /// Return true if `sig` is pending but hasn't been reported to ptrace yet
@@ -1037,8 +1397,49 @@ impl RecordTask {
     }
 
     /// Get all threads out of an emulated GROUP_STOP
-    pub fn emulate_sigcont(&self) {
-        unimplemented!()
+    pub fn emulate_sigcont(&mut self) {
+        // All threads in the process are resumed.
+        for t in self.thread_group_tasks() {
+            if Rc::as_ptr(&t) == self.weak_self.as_ptr() {
+                self.end_group_stop();
+            } else {
+                t.borrow_mut()
+                    .as_record_task_mut()
+                    .unwrap()
+                    .end_group_stop();
+            }
+        }
+    }
+
+    fn end_group_stop(&mut self) {
+        log!(
+            LogDebug,
+            "setting {} to NotStopped due to SIGCONT",
+            self.tid
+        );
+        self.clear_stashed_group_stop();
+        self.emulated_stop_pending = false;
+        self.emulated_stop_type = EmulatedStopType::NotStopped;
+    }
+
+    /// A stopping signal stops every thread in the process.
+    fn apply_group_stop_to_thread_group(&mut self, sig: Sig) {
+        for t in self.thread_group_tasks() {
+            if Rc::as_ptr(&t) == self.weak_self.as_ptr() {
+                self.apply_group_stop(sig);
+            } else {
+                t.borrow_mut()
+                    .as_record_task_mut()
+                    .unwrap()
+                    .apply_group_stop(sig);
+            }
+        }
+    }
+
+    /// DIFF NOTE: Snapshot of our thread group's tasks so that we can update
+    /// them (including ourselves) without holding a borrow of the thread group.
+    fn thread_group_tasks(&self) -> Vec<TaskSharedPtr> {
+        self.thread_group().task_set().iter().collect()
     }
 
     /// Return true if the disposition of `sig` in `table` isn't
@@ -1358,9 +1759,14 @@ impl RecordTask {
         self.stashed_signals.get(0)
     }
 
+    /// DIFF NOTE: rr compares pointers here. Callers in rd need to work with a
+    /// clone of the stashed signal (the task is mutated while the signal is being
+    /// handled) so we compare by content instead.
     pub fn pop_stash_sig(&mut self, stashed: &StashedSignal) {
         for (pos, it) in self.stashed_signals.iter().enumerate() {
-            if ptr::eq(it, stashed) {
+            if it.deterministic == stashed.deterministic
+                && unsafe { *u8_raw_slice(&it.siginfo) == *u8_raw_slice(&stashed.siginfo) }
+            {
                 self.stashed_signals.remove(pos);
                 return;
             }
@@ -1519,15 +1925,39 @@ impl RecordTask {
 
     /// Return true if `self` is within the syscallbuf library.  This
     /// *does not* imply that $ip is at a buffered syscall.
-    pub fn is_in_syscallbuf(&self) -> bool {
+    pub fn is_in_syscallbuf(&mut self) -> bool {
         if !self.vm().syscallbuf_enabled() {
             // Even if we're in the rd page, if syscallbuf isn't enabled then the
             // rd page is not being used by syscallbuf.
             return false;
         }
 
-        // @TODO
-        unimplemented!()
+        let mut p = self.ip();
+        if self.is_in_rd_page()
+            || (self.syscallbuf_code_layout.get_pc_thunks_start <= p
+                && p < self.syscallbuf_code_layout.get_pc_thunks_end)
+        {
+            // Look at the caller to see if we're in the syscallbuf or not.
+            let mut ok = true;
+            let sp = self.regs_ref().sp();
+            let addr = match self.arch() {
+                SupportedArch::X86 => {
+                    read_val_mem(self, RemotePtr::<u32>::cast(sp), Some(&mut ok)) as usize
+                }
+                SupportedArch::X64 => {
+                    read_val_mem(self, RemotePtr::<u64>::cast(sp), Some(&mut ok)) as usize
+                }
+            };
+            if ok {
+                p = RemoteCodePtr::from_val(addr);
+            }
+        }
+
+        self.vm()
+            .monkeypatcher()
+            .map_or(false, |mp| mp.is_jump_stub_instruction(p))
+            || (self.syscallbuf_code_layout.syscallbuf_code_start <= p
+                && p < self.syscallbuf_code_layout.syscallbuf_code_end)
     }
 
     /// Shortcut to the most recent `pending_event->desched.rec` when
@@ -1583,7 +2013,7 @@ impl RecordTask {
     ///
     /// DIFF NOTE: @TODO In the rr implementation ssize_t is being used instead of size_t
     /// for the record_* methods in many places. Why?
-    pub fn record_local(&self, addr: RemotePtr<Void>, data: &[u8]) {
+    pub fn record_local(&mut self, addr: RemotePtr<Void>, data: &[u8]) {
         self.maybe_flush_syscallbuf();
 
         if addr.is_null() {
@@ -1593,11 +2023,11 @@ impl RecordTask {
         self.trace_writer_mut().write_raw(self.rec_tid, data, addr);
     }
 
-    pub fn record_local_for<T>(&self, addr: RemotePtr<T>, data: &T) {
+    pub fn record_local_for<T>(&mut self, addr: RemotePtr<T>, data: &T) {
         self.record_local(RemotePtr::<Void>::cast(addr), u8_slice(data))
     }
 
-    pub fn record_local_for_slice<T>(&self, addr: RemotePtr<T>, data: &[T]) {
+    pub fn record_local_for_slice<T>(&mut self, addr: RemotePtr<T>, data: &[T]) {
         let num = data.len();
         let data =
             unsafe { slice::from_raw_parts(data.as_ptr() as *const u8, num * size_of::<T>()) };
@@ -1676,8 +2106,9 @@ impl RecordTask {
 
     /// Simple helper that attempts to use the local mapping to record if one
     /// exists
-    pub fn record_remote_by_local_map(&self, addr: RemotePtr<Void>, num_bytes: usize) -> bool {
-        match self.vm().local_mapping(addr, num_bytes) {
+    pub fn record_remote_by_local_map(&mut self, addr: RemotePtr<Void>, num_bytes: usize) -> bool {
+        let vm = self.vm_shr_ptr();
+        match vm.local_mapping(addr, num_bytes) {
             Some(local_data) => {
                 self.record_local(addr, local_data);
                 true
@@ -1775,7 +2206,7 @@ impl RecordTask {
     /// a chance to reset the syscallbuf (i.e. record some other kind of event)
     /// before the tracee runs again in a way that might append another buffered
     /// syscall --- so we can't flush too early
-    pub fn maybe_flush_syscallbuf(&self) {
+    pub fn maybe_flush_syscallbuf(&mut self) {
         if EventType::EvSyscallbufFlush == self.ev().event_type() {
             // Already flushing.
             return;
@@ -1784,7 +2215,77 @@ impl RecordTask {
             return;
         }
 
-        unimplemented!()
+        // This can be called while the task is not stopped, when we prematurely
+        // terminate the trace. In that case, the tracee could be concurrently
+        // modifying the header. We'll take a snapshot of the header now.
+        // The syscallbuf code ensures that writes to syscallbuf records
+        // complete before num_rec_bytes is incremented.
+        let child_addr = self.syscallbuf_child;
+        let hdr: syscallbuf_hdr = read_val_mem(self, child_addr, None);
+
+        ed_assert!(
+            self,
+            !self.flushed_syscallbuf || self.flushed_num_rec_bytes == hdr.num_rec_bytes
+        );
+
+        if hdr.num_rec_bytes == 0 || self.flushed_syscallbuf {
+            // no records, or we've already flushed.
+            return;
+        }
+
+        self.push_event(Event::new_syscallbuf_flush_event(
+            SyscallbufFlushEventData::new(),
+        ));
+
+        // Apply buffered mprotect operations and flush the buffer in the tracee.
+        if hdr.mprotect_record_count > 0 {
+            let records_addr = RemotePtr::<mprotect_record>::cast(
+                RemotePtr::<u8>::cast(self.preload_globals.unwrap())
+                    + offset_of!(preload_globals, mprotect_records),
+            );
+            let records: Vec<mprotect_record> =
+                read_mem(self, records_addr, hdr.mprotect_record_count as usize, None);
+            for r in &records {
+                self.vm_shr_ptr().protect(
+                    self,
+                    RemotePtr::from(r.start as usize),
+                    r.size as usize,
+                    ProtFlags::from_bits_truncate(r.prot),
+                );
+            }
+            self.ev_mut().syscallbuf_flush_event_mut().mprotect_records = records;
+        }
+
+        // Write the entire buffer in one shot without parsing it,
+        // because replay will take care of that.
+        if self.is_running() {
+            let mut buf =
+                Vec::<u8>::with_capacity(size_of::<syscallbuf_hdr>() + hdr.num_rec_bytes as usize);
+            buf.extend_from_slice(u8_slice(&hdr));
+            let records = read_mem(
+                self,
+                RemotePtr::<u8>::cast(child_addr + 1usize),
+                hdr.num_rec_bytes as usize,
+                None,
+            );
+            buf.extend_from_slice(&records);
+            self.record_local(RemotePtr::cast(child_addr), &buf);
+        } else {
+            let data_size = self.syscallbuf_data_size();
+            self.record_remote(RemotePtr::cast(child_addr), data_size);
+        }
+
+        self.record_current_event();
+        self.pop_event(EventType::EvSyscallbufFlush);
+
+        self.flushed_syscallbuf = true;
+        self.flushed_num_rec_bytes = hdr.num_rec_bytes;
+
+        log!(
+            LogDebug,
+            "Syscallbuf flushed with num_rec_bytes={}",
+            hdr.num_rec_bytes
+        );
     }
 
     /// Call this after recording an event when it might be safe to reset the
@@ -1897,15 +2398,80 @@ impl RecordTask {
     /// Return the pid of the newborn thread created by this task.
     /// Called when this task has a PTRACE_CLONE_EVENT with CLONE_THREAD.
     pub fn find_newborn_thread(&self) -> pid_t {
-        unimplemented!()
+        ed_assert!(self, self.session().is_recording());
+        ed_assert!(self, self.maybe_ptrace_event() == PTRACE_EVENT_CLONE);
+
+        let hint: pid_t = self.get_ptrace_eventmsg_pid();
+        let path = format!("/proc/{}/task/{}", self.tid, hint);
+        // This should always succeed, but may fail in old kernels due to
+        // a kernel bug. See RecordSession::handle_ptrace_event.
+        if self.session().find_task_from_rec_tid(hint).is_none() && stat(path.as_str()).is_ok() {
+            return hint;
+        }
+
+        let path = format!("/proc/{}/task", self.tid);
+        let dir = fs::read_dir(&path);
+        ed_assert!(self, dir.is_ok(), "Could not read {}", path);
+        for entry in dir.unwrap() {
+            let name = entry.unwrap().file_name();
+            match name.to_str().map(|n| n.parse::<pid_t>()) {
+                Some(Ok(thread_tid))
+                    if self.session().find_task_from_rec_tid(thread_tid).is_none() =>
+                {
+                    return thread_tid;
+                }
+                _ => (),
+            }
+        }
+
+        ed_assert!(self, false, "Could not find newborn thread of {}", self.tid);
+        unreachable!()
     }
 
     /// Return the pid of the newborn process (whose parent has pid `parent_pid`,
     /// which need not be the same as the current task's pid, due to CLONE_PARENT)
     /// created by this task. Called when this task has a PTRACE_CLONE_EVENT
     /// without CLONE_THREAD, or PTRACE_FORK_EVENT.
-    pub fn find_newborn_process(&self, _child_parent: pid_t) -> pid_t {
-        unimplemented!()
+    pub fn find_newborn_process(&self, child_parent: pid_t) -> pid_t {
+        ed_assert!(self, self.session().is_recording());
+        ed_assert!(
+            self,
+            self.maybe_ptrace_event() == PTRACE_EVENT_CLONE
+                || self.maybe_ptrace_event() == PTRACE_EVENT_VFORK
+                || self.maybe_ptrace_event() == PTRACE_EVENT_FORK
+        );
+
+        let hint: pid_t = self.get_ptrace_eventmsg_pid();
+        // This should always succeed, but may fail in old kernels due to
+        // a kernel bug. See RecordSession::handle_ptrace_event.
+        if self.session().find_task_from_rec_tid(hint).is_none()
+            && get_ppid(hint).ok() == Some(child_parent)
+        {
+            return hint;
+        }
+
+        let dir = fs::read_dir("/proc");
+        ed_assert!(self, dir.is_ok(), "Could not read /proc");
+        for entry in dir.unwrap() {
+            let name = entry.unwrap().file_name();
+            match name.to_str().map(|n| n.parse::<pid_t>()) {
+                Some(Ok(proc_tid))
+                    if self.session().find_task_from_rec_tid(proc_tid).is_none()
+                        && get_ppid(proc_tid).ok() == Some(child_parent) =>
+                {
+                    return proc_tid;
+                }
+                _ => (),
+            }
+        }
+
+        ed_assert!(
+            self,
+            false,
+            "Could not find newborn process with parent {}",
+            child_parent
+        );
+        unreachable!()
     }
 
     /// Do a tgkill to send a specific signal to this task.
@@ -1941,7 +2507,7 @@ impl RecordTask {
 
     /// Return true if this is a "clone child" per the wait(2) man page.
     pub fn is_clone_child(&self) -> bool {
-        unimplemented!()
+        self.termination_signal != Some(sig::SIGCHLD)
     }
 
     pub fn set_termination_signal(&mut self, maybe_sig: Option<Sig>) {
@@ -1951,8 +2517,10 @@ impl RecordTask {
     /// When a signal triggers an emulated a ptrace-stop for this task,
     /// save the siginfo so a later emulated ptrace-continue with this signal
     /// number can use it.
-    pub fn save_ptrace_signal_siginfo(&self, _si: &siginfo_t) {
-        unimplemented!()
+    pub fn save_ptrace_signal_siginfo(&mut self, si: &siginfo_t) {
+        self.saved_ptrace_siginfos
+            .retain(|saved| saved.si_signo != si.si_signo);
+        self.saved_ptrace_siginfos.push(si.clone());
     }
 
     /// Tasks normally can't change their tid. There is one very special situation
@@ -2107,8 +2675,158 @@ impl RecordTask {
     /// SIGCHLD to the task if there are still tasks that need a SIGCHLD
     /// sent for them.
     /// May queue signals for specific tasks.
-    fn send_synthetic_sigchld_if_necessary(&self) {
-        unimplemented!()
+    pub fn send_synthetic_sigchld_if_necessary(&mut self) {
+        self.send_synthetic_sigchld_if_necessary_with(None)
+    }
+
+    /// DIFF NOTE: `borrowed` is a task (a tracee or child of this task) that
+    /// the caller already has borrowed and so must not be borrowed again here.
+    fn send_synthetic_sigchld_if_necessary_with(&mut self, borrowed: Option<&RecordTask>) {
+        let mut wake_task: Option<TaskSharedPtr> = None;
+        let mut need_signal = false;
+        for tracee in self.emulated_ptrace_tracees.iter() {
+            let maybe_waiter = with_record_task(&tracee, borrowed, |tracee| {
+                if tracee.emulated_ptrace_sigchld_pending {
+                    // check to see if any thread in the ptracer process is in a waitpid that
+                    // could read the status of 'tracee'. If it is, we should wake up that
+                    // thread. Otherwise we send SIGCHLD to the ptracer thread.
+                    Some(self.waiter_in_thread_group(|rt| rt.is_waiting_for_ptrace(tracee)))
+                } else {
+                    None
+                }
+            })
+            .flatten();
+            if let Some(waiter) = maybe_waiter {
+                need_signal = true;
+                wake_task = waiter;
+                if wake_task.is_some() {
+                    break;
+                }
+            }
+        }
+        if !need_signal {
+            for child_tg in self.thread_group().children().iter() {
+                for child in child_tg.borrow().task_set().iter() {
+                    let maybe_waiter = with_record_task(&child, borrowed, |rchild| {
+                        if rchild.emulated_sigchld_pending {
+                            Some(self.waiter_in_thread_group(|rt| rt.is_waiting_for(rchild)))
+                        } else {
+                            None
+                        }
+                    })
+                    .flatten();
+                    if let Some(waiter) = maybe_waiter {
+                        need_signal = true;
+                        wake_task = waiter;
+                        if wake_task.is_some() {
+                            break;
+                        }
+                    }
+                }
+                if wake_task.is_some() {
+                    break;
+                }
+            }
+            if !need_signal {
+                return;
+            }
+        }
+
+        // ptrace events trigger SIGCHLD in the ptracer's wake_task.
+        // We can't set all the siginfo values to their correct values here, so
+        // we'll patch this up when the signal is received.
+        // If there's already a pending SIGCHLD, this signal will be ignored,
+        // but at some point the pending SIGCHLD will be delivered and then
+        // send_synthetic_sigchld_if_necessary will be called again to deliver a new
+        // SIGCHLD if necessary.
+        let mut si: siginfo_t = Default::default();
+        si.si_code = SI_QUEUE;
+        si._sifields._timer.si_sigval.sival_int = RecordTask::SIGCHLD_SYNTHETIC;
+        match wake_task {
+            Some(wake_task) => {
+                let is_self = Rc::as_ptr(&wake_task) == self.weak_self.as_ptr();
+                let (wake_tgid, wake_tid, sigchld_blocked) = if is_self {
+                    (
+                        self.real_tgid(),
+                        self.tid,
+                        self.is_sig_blocked(sig::SIGCHLD),
+                    )
+                } else {
+                    let mut wt_b = wake_task.borrow_mut();
+                    let wt = wt_b.as_record_task_mut().unwrap();
+                    (wt.real_tgid(), wt.tid, wt.is_sig_blocked(sig::SIGCHLD))
+                };
+                log!(LogDebug, "Sending synthetic SIGCHLD to tid {}", wake_tid);
+                // We must use the raw SYS_rt_tgsigqueueinfo syscall here to ensure the
+                // signal is sent to the correct thread by tid.
+                let ret = unsafe {
+                    syscall(
+                        SYS_rt_tgsigqueueinfo,
+                        wake_tgid,
+                        wake_tid,
+                        SIGCHLD,
+                        &si as *const siginfo_t,
+                    )
+                };
+                ed_assert_eq!(self, ret, 0);
+                if sigchld_blocked {
+                    log!(
+                        LogDebug,
+                        "SIGCHLD is blocked, kicking it out of the syscall"
+                    );
+                    // Just sending SIGCHLD won't wake it up. Send it a TIME_SLICE_SIGNAL
+                    // as well to make sure it exits a blocking syscall. We ensure those
+                    // can never be blocked.
+                    // We have to send a negative code here because only the kernel can set
+                    // positive codes. We set a magic number so we can recognize it
+                    // when received.
+                    si.si_code = RecordTask::SYNTHETIC_TIME_SLICE_SI_CODE;
+                    let ret = unsafe {
+                        syscall(
+                            SYS_rt_tgsigqueueinfo,
+                            wake_tgid,
+                            wake_tid,
+                            TIME_SLICE_SIGNAL.as_raw(),
+                            &si as *const siginfo_t,
+                        )
+                    };
+                    ed_assert_eq!(self, ret, 0);
+                }
+            }
+            None => {
+                // Send the signal to the process as a whole and let the kernel
+                // decide which thread gets it.
+                let ret = unsafe {
+                    syscall(
+                        SYS_rt_sigqueueinfo,
+                        self.real_tgid(),
+                        SIGCHLD,
+                        &si as *const siginfo_t,
+                    )
+                };
+                ed_assert_eq!(self, ret, 0);
+                log!(LogDebug, "Sending synthetic SIGCHLD to pid {}", self.tgid());
+            }
+        }
+    }
+
+    /// Return the first task in our thread group for which `pred` holds.
+    fn waiter_in_thread_group<F: Fn(&RecordTask) -> bool>(&self, pred: F) -> Option<TaskSharedPtr> {
+        for t in self.thread_group().task_set().iter() {
+            let waiting = if Rc::as_ptr(&t) == self.weak_self.as_ptr() {
+                pred(self)
+            } else {
+                // A task that is busy further up the stack isn't blocked in a wait.
+                match t.try_borrow() {
+                    Ok(rt) => pred(rt.as_record_task().unwrap()),
+                    Err(_) => false,
+                }
+            };
+            if waiting {
+                return Some(t);
+            }
+        }
+        None
     }
 
     /// Call this when SYS_sigaction is finishing with `regs`.
@@ -2305,6 +3023,30 @@ impl Drop for RecordTask {
     }
 }
 
+/// Run `f` on the RecordTask in `rc`, using `borrowed` instead if it is that
+/// same task (and so can't be borrowed again).
+/// Returns None if the task is mutably borrowed further up the stack. Such a
+/// task is in the middle of being updated and will notify its waiter itself
+/// once it is done.
+fn with_record_task<R, F: FnOnce(&RecordTask) -> R>(
+    rc: &TaskSharedPtr,
+    borrowed: Option<&RecordTask>,
+    f: F,
+) -> Option<R> {
+    match borrowed {
+        Some(b) if b.weak_self.as_ptr() == Rc::as_ptr(rc) => Some(f(b)),
+        _ => rc.try_borrow().ok().map(|t| f(t.as_record_task().unwrap())),
+    }
+}
+
+fn as_native_siginfo(si: &mut siginfo_t) -> &mut native_arch::siginfo_t {
+    unsafe { &mut *(si as *mut siginfo_t as *mut native_arch::siginfo_t) }
+}
+
+fn getpgid(pid: pid_t) -> pid_t {
+    unsafe { libc::getpgid(pid) }
+}
+
 fn get_ppid(pid: pid_t) -> Result<pid_t, Box<dyn Error>> {
     let mut ppid_str = read_proc_status_fields(pid, &[b"PPid"])?;
     let actual_ppid = pid_t::from_str_radix(&ppid_str.pop().unwrap().into_string().unwrap(), 10)?;
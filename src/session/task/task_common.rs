@@ -13,7 +13,11 @@
 
 use crate::{
     arch::Architecture,
-    auto_remote_syscalls::{AutoRemoteSyscalls, AutoRestoreMem},
+    auto_remote_syscalls::{
+        AutoRemoteSyscalls,
+        AutoRestoreMem,
+        MemParamsEnabled::DisableMemoryParams,
+    },
     bindings::{
         kernel::{
             user_desc,
@@ -52,13 +56,14 @@ use crate::{
         syscall_number_for_mprotect,
         syscall_number_for_munmap,
         syscall_number_for_openat,
+        syscall_number_for_prctl,
         x64,
         x86,
         CloneTLSType,
         FcntlOperation,
         SupportedArch,
     },
-    kernel_metadata::ptrace_req_name,
+    kernel_metadata::{errno_name, ptrace_req_name},
     kernel_supplement::ARCH_SET_CPUID,
     log::LogLevel::{LogDebug, LogInfo, LogWarn},
     perf_counters::TIME_SLICE_SIGNAL,
@@ -105,11 +110,13 @@ use crate::{
             PRELOAD_THREAD_LOCALS_SIZE,
         },
         Session,
+        SessionSharedPtr,
     },
     sig,
     ticks::Ticks,
     util::{
         ceil_page_size,
+        clone_flags_to_task_flags,
         cpuid,
         floor_page_size,
         is_kernel_trap,
@@ -131,16 +138,24 @@ use libc::{
     pid_t,
     pread64,
     waitpid,
+    __WALL,
     CLONE_FILES,
+    CLONE_FS,
+    CLONE_SIGHAND,
+    CLONE_SYSVSEM,
+    CLONE_THREAD,
+    CLONE_VM,
+    EAGAIN,
     ECHILD,
     EPERM,
     ESRCH,
     PR_SET_NAME,
     PR_SET_SECCOMP,
     SECCOMP_MODE_FILTER,
+    SEEK_SET,
+    SIGCHLD,
     SIGTRAP,
     WNOHANG,
-    __WALL,
 };
 use nix::{
     errno::{errno, Errno},
@@ -687,7 +702,7 @@ pub(super) fn did_waitpid<T: Task>(task: &mut T, mut status: WaitStatus) {
                 task.session()
                     .as_record()
                     .unwrap()
-                    .scheduler_mut()
+                    .scheduler()
                     .expire_timeslice();
             }
             status = WaitStatus::for_stop_sig(TIME_SLICE_SIGNAL);
@@ -1053,8 +1068,224 @@ fn cpu_has_knl_string_singlestep_bug() -> bool {
     *CPU_HAS_KNL_STRING_SINGLESTEP_BUG_INIT
 }
 
-pub fn os_clone_into(_state: &CapturedState, _remote: &mut AutoRemoteSyscalls) -> TaskSharedPtr {
-    unimplemented!()
+/// Copy the state from `state` into `t`. This is used when restoring a
+/// checkpoint: `t` is a fresh clone created via `os_fork_into()` or
+/// `os_clone_into()`.
+///
+/// DIFF NOTE: Called Task::copy_state() in rr.
+pub fn copy_state(t: &mut dyn Task, state: &CapturedState) {
+    t.set_regs(&state.regs);
+    t.set_extra_regs(&state.extra_regs);
+    {
+        let mut remote = AutoRemoteSyscalls::new(t);
+        {
+            let mut prname = [0u8; 16];
+            let name_bytes = state.prname.as_bytes();
+            let len = min(name_bytes.len(), prname.len());
+            prname[0..len].copy_from_slice(&name_bytes[0..len]);
+            let prctl_no = syscall_number_for_prctl(remote.arch());
+            let mut mem = AutoRestoreMem::new(&mut remote, Some(&prname), prname.len());
+            let addr = mem.get().unwrap();
+            rd_infallible_syscall!(mem, prctl_no, PR_SET_NAME, addr.as_usize());
+            mem.task_mut().update_prname(addr);
+        }
+
+        copy_tls(state, &mut remote);
+        remote.task_mut().thread_areas_ = state.thread_areas.clone();
+        remote.task_mut().syscallbuf_size = state.syscallbuf_size;
+
+        ed_assert!(
+            remote.task(),
+            remote.task().syscallbuf_child.is_null(),
+            "Syscallbuf should not already be initialized in clone"
+        );
+        if !state.syscallbuf_child.is_null() {
+            // All these fields are preserved by the fork.
+            remote.task_mut().desched_fd_child = state.desched_fd_child;
+            remote.task_mut().cloned_file_data_fd_child = state.cloned_file_data_fd_child;
+            if state.cloned_file_data_fd_child >= 0 {
+                remote.infallible_lseek_syscall(
+                    state.cloned_file_data_fd_child,
+                    state.cloned_file_data_offset as i64,
+                    SEEK_SET,
+                );
+            }
+            remote.task_mut().syscallbuf_child = state.syscallbuf_child;
+        }
+    }
+
+    t.preload_globals = if state.preload_globals.is_null() {
+        None
+    } else {
+        Some(state.preload_globals)
+    };
+    ed_assert_ne!(t, t.vm().thread_locals_tuid(), t.tuid());
+    t.thread_locals = state.thread_locals;
+    // The scratch buffer (for now) is merely a private mapping in the remote
+    // task.  The CoW copy made by fork()'ing the address space has
+    // the semantics we want.  It's not used in replay anyway.
+    t.scratch_ptr = state.scratch_ptr;
+    t.scratch_size = state.scratch_size as usize;
+
+    // Whatever `from`'s last wait status was is what ours would
+    // have been.
+    t.wait_status = state.wait_status;
+
+    t.ticks = state.ticks;
+}
+
+fn copy_tls(state: &CapturedState, remote: &mut AutoRemoteSyscalls) {
+    rd_arch_function_selfless!(copy_tls_arch, remote.arch(), state, remote)
+}
+
+fn copy_tls_arch<Arch: Architecture>(state: &CapturedState, remote: &mut AutoRemoteSyscalls) {
+    if Arch::CLONE_TLS_TYPE == CloneTLSType::UserDescPointer {
+        for t in &state.thread_areas {
+            let bytes = unsafe { &*u8_raw_slice(t) };
+            let mut remote_tls = AutoRestoreMem::new(remote, Some(bytes), size_of::<user_desc>());
+            let addr = remote_tls.get().unwrap();
+            rd_infallible_syscall!(remote_tls, Arch::SET_THREAD_AREA, addr.as_usize());
+        }
+    }
+}
+
+/// Fork and exec the task `t` into `session`, returning the new leader task
+/// of the cloned address space. The new task is in the same state as `t`
+/// (but see `copy_state()` for the state that needs to be restored separately).
+///
+/// DIFF NOTE: Called Task::os_fork_into() in rr.
+pub fn os_fork_into(t: &mut dyn Task, session: SessionSharedPtr) -> TaskSharedPtr {
+    let rec_tid = t.rec_tid;
+    let serial = t.serial;
+    let mut remote = AutoRemoteSyscalls::new_with_mem_params(t, DisableMemoryParams);
+    let child = os_clone(
+        CloneReason::SessionCloneLeader,
+        session,
+        &mut remote,
+        rec_tid,
+        serial,
+        // Most likely, we'll be setting up a
+        // CLEARTID futex.  That's not done
+        // here, but rather later in
+        // `copy_state()`.
+        //
+        // We also don't use any of the SETTID
+        // flags because that earlier work will
+        // be copied by fork()ing the address
+        // space.
+        SIGCHLD,
+        RemotePtr::null(),
+    );
+    // When we forked ourselves, the child inherited the setup we
+    // did to make the clone() call.  So we have to "finish" the
+    // remote calls (i.e. undo fudged state) in the child too,
+    // even though we never made any syscalls there.
+    remote.restore_state_to(Some(child.borrow_mut().as_mut()));
+    child
+}
+
+/// Return a new task running in the same address space as the task
+/// behind `remote`, with the rec_tid and serial number from `state`.
+/// Used to recreate the non-leader threads of a checkpoint.
+///
+/// DIFF NOTE: Called Task::os_clone_into() in rr.
+pub fn os_clone_into(state: &CapturedState, remote: &mut AutoRemoteSyscalls) -> TaskSharedPtr {
+    let session = remote.task().session();
+    os_clone(
+        CloneReason::SessionCloneNonleader,
+        session,
+        remote,
+        state.rec_tid,
+        state.serial,
+        // We don't actually /need/ to specify the
+        // SIGHAND/SYSVMEM flags because those things
+        // are emulated in the tracee.  But we use the
+        // same flags as glibc to be on the safe side
+        // wrt kernel bugs.
+        //
+        // We don't pass CLONE_SETTLS here *only*
+        // because we'll do it later in
+        // `copy_state()`.
+        //
+        // See `os_fork_into()` above for discussion
+        // of the CTID flags.
+        CLONE_VM | CLONE_FS | CLONE_FILES | CLONE_SIGHAND | CLONE_THREAD | CLONE_SYSVSEM,
+        state.top_of_stack,
+    )
+}
+
+/// Make the OS-level calls to clone the task behind `remote` into
+/// `session` and return the resulting Task metadata for that new
+/// process. This is as opposed to `Session::clone_task()`, which only
+/// attaches Task metadata to an /existing/ process.
+///
+/// The new clone will be tracked in `session`. The other arguments are
+/// as for `Session::clone_task()` above.
+fn os_clone(
+    reason: CloneReason,
+    session: SessionSharedPtr,
+    remote: &mut AutoRemoteSyscalls,
+    rec_child_tid: pid_t,
+    new_serial: u32,
+    base_flags: i32,
+    stack: RemotePtr<Void>,
+) -> TaskSharedPtr {
+    let mut ret: isize;
+    loop {
+        ret = perform_remote_clone(remote, base_flags, stack);
+        if ret != -EAGAIN as isize {
+            break;
+        }
+    }
+    ed_assert!(
+        remote.task(),
+        ret >= 0,
+        "remote clone failed with errno {}",
+        errno_name(-ret as i32)
+    );
+
+    let new_tid = remote.new_tid().unwrap();
+    clone_task_common(
+        remote.task_mut(),
+        reason,
+        clone_flags_to_task_flags(base_flags),
+        stack,
+        RemotePtr::null(),
+        RemotePtr::null(),
+        new_tid,
+        Some(rec_child_tid),
+        new_serial,
+        Some(session),
+    )
+}
+
+/// DIFF NOTE: rr also passes the ptid, tls and ctid here but they're always
+/// null for checkpoint clones.
+fn perform_remote_clone(
+    remote: &mut AutoRemoteSyscalls,
+    base_flags: i32,
+    stack: RemotePtr<Void>,
+) -> isize {
+    rd_arch_function_selfless!(
+        perform_remote_clone_arch,
+        remote.arch(),
+        remote,
+        base_flags,
+        stack
+    )
+}
+
+fn perform_remote_clone_arch<Arch: Architecture>(
+    remote: &mut AutoRemoteSyscalls,
+    base_flags: i32,
+    stack: RemotePtr<Void>,
+) -> isize {
+    // ptid, tls and ctid are all null so we don't need to worry about
+    // Arch::CLONE_PARAMETER_ORDERING here.
+    remote.syscall(
+        Arch::CLONE,
+        &[base_flags as usize, stack.as_usize(), 0, 0, 0],
+    )
 }
 
 fn on_syscall_exit_arch<Arch: Architecture>(t: &mut dyn Task, sys: i32, regs: &Registers) {
@@ -1449,6 +1680,12 @@ pub(super) fn compute_trap_reasons<T: Task>(t: &mut T) -> TrapReasons {
             },
         );
     }
+    if t.vm().has_software_watchpoints() && is_singlestep_resume(t.how_last_execution_resumed) {
+        // Our watchpoints aren't in the debug registers so `status` can't
+        // report them. Check them ourselves.
+        let ip = t.ip();
+        t.vm().notify_software_watchpoints(ip);
+    }
     reasons.watchpoint = t.vm().has_any_watchpoint_changes()
         || (status & DebugStatus::DsWatchpointAny as usize != 0);
 
@@ -1896,7 +2133,9 @@ pub(super) fn destroy_buffers<T: Task>(t: &mut T) {
 pub(super) fn task_drop_common<T: Task>(t: &T) {
     log!(LogDebug, "task {} (rec:{}) is dying ...", t.tid, t.rec_tid);
 
-    t.fallible_ptrace(PTRACE_DETACH, RemotePtr::null(), PtraceData::None);
+    if t.detach_on_drop {
+        t.fallible_ptrace(PTRACE_DETACH, RemotePtr::null(), PtraceData::None);
+    }
 
     if t.unstable.get() {
         log!(
@@ -12,7 +12,6 @@ use crate::{
 
 use crate::{
     arch::Architecture,
-    auto_remote_syscalls::AutoRemoteSyscalls,
     bindings::{
         kernel::{sock_fprog, user, user_desc, CAP_SYS_ADMIN, NT_X86_XSTATE},
         ptrace::{
@@ -73,7 +72,9 @@ use crate::{
     session::{
         address_space::{
             address_space::{AddressSpace, AddressSpaceSharedPtr},
+            Enabled,
             MappingFlags,
+            Privileged,
             Traced,
             WatchConfig,
             WatchType,
@@ -91,6 +92,7 @@ use crate::{
     util::{
         choose_cpu,
         has_effective_caps,
+        page_size,
         restore_initial_resource_limits,
         running_under_rd,
         set_cpu_affinity,
@@ -430,6 +432,10 @@ pub struct TaskInner {
     /// A counter for the number of stops for which the stop may have been caused
     /// by PTRACE_INTERRUPT. See description in do_waitpid
     pub(in super::super) expecting_ptrace_interrupt_stop: u32,
+    /// DIFF NOTE: Not present in rr. When false, dropping the task will not
+    /// PTRACE_DETACH from its tid. rr gets the same effect by deleting the task
+    /// directly instead of going through Task::destroy().
+    pub(in super::super) detach_on_drop: bool,
 
     /// Important. Weak dyn Task pointer to self.
     pub(in super::super) weak_self: TaskSharedWeakPtr,
@@ -597,6 +603,25 @@ impl TaskInner {
         res.unwrap()
     }
 
+    /// Return the current file offset of `fd` in the context of this task's fd
+    /// table.
+    pub fn fd_offset(&self, fd: i32) -> u64 {
+        let path = format!("/proc/{}/fdinfo/{}", self.tid, fd);
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) => fatal!("Can't read {}: {:?}", path, e),
+        };
+        for line in contents.lines() {
+            if let Some(pos) = line.strip_prefix("pos:") {
+                match pos.trim().parse::<u64>() {
+                    Ok(offset) => return offset,
+                    Err(_) => break,
+                }
+            }
+        }
+        fatal!("Failed to read position for fd {} from {}", fd, path);
+    }
+
     /// Syscalls have side effects on registers (e.g. setting the flags register).
     /// Perform those side effects on `registers` to make it look like a syscall
     /// happened.
@@ -698,8 +723,11 @@ impl TaskInner {
     }
 
     /// Emulate a jump to a new IP, updating the ticks counter as appropriate.
-    pub fn emulate_jump(&self, _ptr: RemoteCodePtr) {
-        unimplemented!()
+    pub fn emulate_jump(&mut self, ip: RemoteCodePtr) {
+        let mut r = self.regs_ref().clone();
+        r.set_ip(ip);
+        self.set_regs(&r);
+        self.ticks += PerfCounters::ticks_for_unconditional_indirect_branch(self);
     }
 
     /// Return true if this is at an arm-desched-event or
@@ -715,11 +743,34 @@ impl TaskInner {
     /// is implied by this. Note that once we've entered the traced syscall,
     /// ip() is immediately after the syscall instruction.
     pub fn is_in_traced_syscall(&self) -> bool {
-        unimplemented!()
+        let ip = self.ip();
+        ip == AddressSpace::rd_page_syscall_exit_point(
+            Traced::Traced,
+            Privileged::Unprivileged,
+            Enabled::RecordingAndReplay,
+        ) || ip
+            == AddressSpace::rd_page_syscall_exit_point(
+                Traced::Traced,
+                Privileged::Privileged,
+                Enabled::RecordingAndReplay,
+            )
     }
 
     pub fn is_at_traced_syscall_entry(&self) -> bool {
-        unimplemented!()
+        let ip = self.ip();
+        let arch = self.arch();
+        ip == AddressSpace::rd_page_syscall_entry_point(
+            Traced::Traced,
+            Privileged::Unprivileged,
+            Enabled::RecordingAndReplay,
+            arch,
+        ) || ip
+            == AddressSpace::rd_page_syscall_entry_point(
+                Traced::Traced,
+                Privileged::Privileged,
+                Enabled::RecordingAndReplay,
+                arch,
+            )
     }
 
     /// Return true when this task is in an untraced syscall, i.e. one
@@ -763,15 +814,10 @@ impl TaskInner {
         &self.prname
     }
 
-    /// Call this method when this task has exited a successful execve() syscall.
-    /// At this point it is safe to make remote syscalls.
-    pub fn post_exec_syscall(&self) {
-        unimplemented!()
-    }
-
     /// Return true if this task has execed.
+    /// The flag is set by `post_exec_for_exe()`.
     pub fn execed(&self) -> bool {
-        unimplemented!()
+        self.thread_group().execed
     }
 
     /// Read `N` bytes from `child_addr` into `buf`, or don't
@@ -1007,8 +1053,17 @@ impl TaskInner {
     }
 
     /// @TODO should this be a GdbRegister type?
-    pub fn get_debug_reg(&self, _regno: usize) -> usize {
-        unimplemented!()
+    pub fn get_debug_reg(&self, regno: usize) -> usize {
+        Errno::clear();
+        let result = self.fallible_ptrace(
+            PTRACE_PEEKUSER,
+            dr_user_word_offset(regno).into(),
+            PtraceData::None,
+        );
+        if errno() == ESRCH {
+            return 0;
+        }
+        result as usize
     }
 
     /// @TODO should this be a GdbRegister type?
@@ -1047,7 +1102,7 @@ impl TaskInner {
     }
 
     pub fn thread_areas(&self) -> Vec<user_desc> {
-        unimplemented!()
+        self.thread_areas_.clone()
     }
 
     pub fn set_status(&mut self, status: WaitStatus) {
@@ -1112,7 +1167,8 @@ impl TaskInner {
 
     /// Return the dir of the trace we're using.
     pub fn trace_dir(&self) -> OsString {
-        unimplemented!()
+        let trace = self.trace_stream().unwrap();
+        trace.dir()
     }
 
     /// Get the current "time" measured as ticks on recording trace
@@ -1227,9 +1283,11 @@ impl TaskInner {
         self.address_of_last_execution_resume
     }
 
-    pub fn usable_scratch_size(&self) {
-        unimplemented!()
+    /// The last page of the scratch buffer is the syscallbuf alt stack.
+    pub fn usable_scratch_size(&self) -> usize {
+        self.scratch_size.saturating_sub(page_size())
     }
+
     pub fn syscallbuf_alt_stack(&self) -> RemotePtr<Void> {
         if self.scratch_ptr.is_null() {
             RemotePtr::null()
@@ -1351,6 +1409,7 @@ impl TaskInner {
             seen_ptrace_exit_event: false,
             thread_locals: array_init::array_init(|_| 0),
             expecting_ptrace_interrupt_stop: 0,
+            detach_on_drop: true,
             // DIFF NOTE: These are not explicitly set in rr
             syscallbuf_child: Default::default(),
             preload_globals: None,
@@ -1369,21 +1428,38 @@ impl TaskInner {
 
     /// Grab state from this task into a structure that we can use to
     /// initialize a new task via os_clone_into/os_fork_into and copy_state.
-    pub(in super::super) fn capture_state(&self) -> CapturedState {
-        unimplemented!()
-    }
-
-    /// Make this task look like an identical copy of the task whose state
-    /// was captured by capture_task_state(), in
-    /// every way relevant to replay.  This task should have been
-    /// created by calling os_clone_into() or os_fork_into(),
-    /// and if it wasn't results are undefined.
     ///
-    /// Some task state must be copied into this by injecting and
-    /// running syscalls in this task.  Other state is metadata
-    /// that can simply be copied over in local memory.
-    pub(in super::super) fn copy_state(&mut self, _stat: &CapturedState) {
-        unimplemented!()
+    /// DIFF NOTE: Takes &mut self because fetching the extra registers and the
+    /// preload thread locals may need to update cached state.
+    /// `num_syscallbuf_bytes` is always 0 here; the syscallbuf contents are
+    /// captured separately by `Session::copy_state_to_session()`.
+    pub(in super::super) fn capture_state(&mut self) -> CapturedState {
+        let cloned_file_data_offset = if self.cloned_file_data_fd_child >= 0 {
+            self.fd_offset(self.cloned_file_data_fd_child)
+        } else {
+            0
+        };
+        CapturedState {
+            ticks: self.ticks,
+            regs: self.regs_ref().clone(),
+            extra_regs: self.extra_regs_ref().clone(),
+            prname: self.prname.clone(),
+            thread_areas: self.thread_areas_.clone(),
+            syscallbuf_child: self.syscallbuf_child,
+            syscallbuf_size: self.syscallbuf_size,
+            num_syscallbuf_bytes: 0,
+            preload_globals: self.preload_globals.unwrap_or_default(),
+            scratch_ptr: self.scratch_ptr,
+            scratch_size: self.scratch_size as isize,
+            top_of_stack: self.top_of_stack,
+            cloned_file_data_offset,
+            thread_locals: *self.fetch_preload_thread_locals(),
+            rec_tid: self.rec_tid,
+            serial: self.serial,
+            desched_fd_child: self.desched_fd_child,
+            cloned_file_data_fd_child: self.cloned_file_data_fd_child,
+            wait_status: self.wait_status,
+        }
     }
 
     /// Make the ptrace `request` with `addr` and `data`, return
@@ -1512,23 +1588,6 @@ impl TaskInner {
         unimplemented!()
     }
 
-    /// Make the OS-level calls to create a new fork or clone that
-    /// will eventually be a copy of this task and return that Task
-    /// metadata.  These methods are used in concert with
-    /// `Task::copy_state()` to create task copies during
-    /// checkpointing.
-    ///
-    /// For `os_fork_into()`, `session` will be tracking the
-    /// returned fork child.
-    ///
-    /// For `os_clone_into()`, `task_leader` is the "main thread"
-    /// in the process into which the copy of this task will be
-    /// created.  `task_leader` will perform the actual OS calls to
-    /// create the new child.
-    pub(in super::super) fn os_fork_into(&self, _session: &dyn Session) -> &TaskInner {
-        unimplemented!()
-    }
-
     /// Return the TraceStream that we're using, if in recording or replay.
     /// Returns `None` if we're not in record or replay.
     pub(in super::super) fn trace_stream(
@@ -1540,7 +1599,7 @@ impl TaskInner {
         let shr_ptr = self.session();
         let owning_handle =
             OwningHandle::new_with_fn(shr_ptr, |s| match unsafe { (*s).as_record() } {
-                Some(_rec_sess) => unimplemented!(),
+                Some(rec_sess) => Ref::map(rec_sess.trace_writer(), |tw| tw.deref()),
                 None => match unsafe { (*s).as_replay() } {
                     Some(rep_sess) => Ref::map(rep_sess.trace_reader(), |tr| tr.deref()),
                     None => unreachable!(),
@@ -1550,28 +1609,6 @@ impl TaskInner {
         Some(owning_handle)
     }
 
-    /// Make the OS-level calls to clone `parent` into `session`
-    /// and return the resulting Task metadata for that new
-    /// process.  This is as opposed to `Task::clone()`, which only
-    /// attaches Task metadata to an /existing/ process.
-    ///
-    /// The new clone will be tracked in `session`.  The other
-    /// arguments are as for `Task::clone()` above.
-    pub(in super::super) fn os_clone(
-        _reason: CloneReason,
-        _session: &dyn Session,
-        _remote: &AutoRemoteSyscalls,
-        _rec_child_tid: pid_t,
-        _new_serial: u32,
-        _base_flags: u32,
-        _stack: RemotePtr<Void>,
-        _ptid: RemotePtr<i32>,
-        _tls: RemotePtr<Void>,
-        _ctid: RemotePtr<i32>,
-    ) {
-        unimplemented!()
-    }
-
     /// Fork and exec the initial task. If something goes wrong later
     /// (i.e. an exec does not occur before an exit), an error may be
     /// readable from the other end of the pipe whose write end is error_fd.
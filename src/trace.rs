@@ -1,6 +1,8 @@
 pub mod compressed_reader;
 pub mod compressed_writer;
+pub mod compression;
 pub mod trace_frame;
+pub mod trace_index;
 pub mod trace_reader;
 pub mod trace_stream;
 pub mod trace_task_event;
@@ -1,9 +1,11 @@
 use crate::{
     scoped_fd::{ScopedFd, ScopedFdSharedPtr},
-    trace::compressed_writer::BlockHeader,
+    trace::{
+        compressed_writer::BlockHeader,
+        compression::{decompress, Codec, Compression},
+    },
     util::read_to_end,
 };
-use brotli_sys::{BrotliDecoderDecompress, BROTLI_DECODER_RESULT_SUCCESS};
 use nix::{
     fcntl::OFlag,
     sys::uio::pread,
@@ -72,6 +74,14 @@ impl Read for CompressedReader {
     }
 }
 
+/// Where a compressed block starts in the file, and where its data starts in
+/// the uncompressed stream.
+#[derive(Copy, Clone)]
+pub struct BlockLocation {
+    pub file_offset: u64,
+    pub uncompressed_start: u64,
+}
+
 pub struct CompressedReaderState {
     saved_fd_offset: u64,
     saved_buffer: Vec<u8>,
@@ -184,11 +194,61 @@ impl CompressedReader {
         self.buffer_read_pos = state.saved_buffer_read_pos;
     }
 
-    /// Gathers stats on the file stream. These are independent of what's
-    /// actually been read.
-    pub fn uncompressed_bytes(&self) -> io::Result<u64> {
+    /// Move the read position to `offset_in_block` bytes into the uncompressed
+    /// data of the block at `block_offset` in the file. `block_offset` may also
+    /// be the end of the file, with an `offset_in_block` of 0.
+    pub fn seek_to_block(&mut self, block_offset: u64, offset_in_block: usize) -> io::Result<()> {
+        self.fd_offset = block_offset;
+        self.buffer.clear();
+        self.buffer_read_pos = 0;
+        self.eof = block_offset >= self.compressed_bytes()?;
+        if !self.eof {
+            self.refill_buffer()?;
+        }
+        if offset_in_block > self.buffer.len() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "Seek offset is beyond the end of the block",
+            ));
+        }
+        self.buffer_read_pos = offset_in_block;
+        Ok(())
+    }
+
+    /// Return the location of every block in the file, followed by one for the
+    /// end of the file. These are independent of what's actually been read.
+    pub fn block_locations(&self) -> io::Result<Vec<BlockLocation>> {
+        let mut offset: u64 = 0;
+        let mut uncompressed_start: u64 = 0;
+        let mut blocks = Vec::new();
+        let mut header_arr = [0u8; size_of::<BlockHeader>()];
+        loop {
+            blocks.push(BlockLocation {
+                file_offset: offset,
+                uncompressed_start,
+            });
+            if !read_all(
+                &self.fd.as_ref().unwrap().borrow(),
+                &mut header_arr,
+                &mut offset,
+            )? {
+                break;
+            }
+            let header: BlockHeader = unsafe { transmute(header_arr.clone()) };
+            uncompressed_start += header.uncompressed_length as u64;
+            offset += header.compressed_length() as u64;
+        }
+        Ok(blocks)
+    }
+
+    /// The compression the file was written with, as far as the block headers
+    /// tell: the codec of the first block that isn't stored as is (blocks that
+    /// don't get smaller are stored uncompressed whatever the codec), at that
+    /// codec's default level because levels aren't recorded. `None` if the
+    /// file has no blocks.
+    pub fn compression(&self) -> io::Result<Option<Compression>> {
         let mut offset: u64 = 0;
-        let mut uncompressed_bytes: u64 = 0;
+        let mut result = None;
         let mut header_arr = [0u8; size_of::<BlockHeader>()];
         while read_all(
             &self.fd.as_ref().unwrap().borrow(),
@@ -196,10 +256,25 @@ impl CompressedReader {
             &mut offset,
         )? {
             let header: BlockHeader = unsafe { transmute(header_arr.clone()) };
-            uncompressed_bytes += header.uncompressed_length as u64;
-            offset += header.compressed_length as u64;
+            match header.codec() {
+                Some(Codec::Uncompressed) => result = Some(Compression::new(Codec::Uncompressed)),
+                Some(codec) => return Ok(Some(Compression::new(codec))),
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Unknown codec in block header",
+                    ))
+                }
+            }
+            offset += header.compressed_length() as u64;
         }
-        Ok(uncompressed_bytes)
+        Ok(result)
+    }
+
+    /// Gathers stats on the file stream. These are independent of what's
+    /// actually been read.
+    pub fn uncompressed_bytes(&self) -> io::Result<u64> {
+        Ok(self.block_locations()?.last().unwrap().uncompressed_start)
     }
 
     pub fn compressed_bytes(&self) -> io::Result<u64> {
@@ -239,8 +314,17 @@ impl CompressedReader {
             );
         }
 
-        let mut compressed_buf: Vec<u8> = Vec::with_capacity(header.compressed_length as usize);
-        compressed_buf.resize(header.compressed_length as usize, 0);
+        let codec = match header.codec() {
+            Some(codec) => codec,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Unknown codec in block header",
+                ))
+            }
+        };
+        let mut compressed_buf: Vec<u8> = Vec::with_capacity(header.compressed_length() as usize);
+        compressed_buf.resize(header.compressed_length() as usize, 0);
         if false
             == read_all(
                 &self.fd.as_ref().unwrap().borrow(),
@@ -268,10 +352,10 @@ impl CompressedReader {
 
         self.buffer.resize(header.uncompressed_length as usize, 0);
         self.buffer_read_pos = 0;
-        if !do_decompress(compressed_buf.as_slice(), &mut self.buffer) {
+        if !decompress(codec, compressed_buf.as_slice(), &mut self.buffer) {
             return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "Decompression Error. @TODO",
+                io::ErrorKind::InvalidData,
+                format!("Decompression of {} block failed", codec.name()),
             ));
         }
 
@@ -292,20 +376,6 @@ pub fn read_all(fd: &ScopedFd, data: &mut [u8], offset: &mut u64) -> io::Result<
     }
 }
 
-pub fn do_decompress(compressed: &[u8], uncompressed: &mut [u8]) -> bool {
-    let mut out_size = uncompressed.len();
-    let decompress_result = unsafe {
-        BrotliDecoderDecompress(
-            compressed.len(),
-            compressed.as_ptr(),
-            &raw mut out_size,
-            uncompressed.as_mut_ptr(),
-        )
-    };
-
-    decompress_result == BROTLI_DECODER_RESULT_SUCCESS && out_size == uncompressed.len()
-}
-
 impl BufRead for CompressedReader {
     fn fill_buf(&mut self) -> io::Result<&[u8]> {
         // If available to read bytes are "empty" and we have not yet reached EOF
@@ -1,21 +1,16 @@
-use crate::{scoped_fd::ScopedFd, util::write_all};
-use brotli_sys::{
-    BrotliEncoderCompressStream,
-    BrotliEncoderCreateInstance,
-    BrotliEncoderDestroyInstance,
-    BrotliEncoderSetParameter,
-    BROTLI_OPERATION_FINISH,
-    BROTLI_OPERATION_PROCESS,
-    BROTLI_PARAM_QUALITY,
+use crate::{
+    scoped_fd::ScopedFd,
+    trace::compression::{compress, Codec, Compression},
+    util::write_all,
 };
 use nix::{fcntl::OFlag, sys::stat::Mode, unistd::fsync};
 use std::{
+    borrow::Cow,
     cmp::min,
     convert::TryInto,
     ffi::OsStr,
     io::{Error, ErrorKind, Result, Write},
     mem::size_of,
-    ptr,
     ptr::copy_nonoverlapping,
     slice,
     sync::{Arc, Condvar, Mutex},
@@ -35,17 +30,49 @@ pub enum WaitFlag {
     NoWait,
 }
 
+/// The codec is stored in the top bits of the compressed length.
+const CODEC_SHIFT: u32 = 28;
+
+/// Blocks must compress to less than 1 << CODEC_SHIFT bytes, even with slop
+/// for incompressible data.
+pub const MAX_BLOCK_SIZE: usize = 128 * 1024 * 1024;
+
+/// DIFF NOTE: rr blocks are always brotli and rr's header has no codec. We keep
+/// the codec in the top bits of the compressed length, which is never that
+/// large. Brotli is 0 so rr traces can still be read. Older readers would take
+/// the codec bits for part of the length, which is why rd traces have a newer
+/// TRACE_VERSION.
 #[derive(Copy, Clone, Default)]
 pub struct BlockHeader {
-    pub compressed_length: u32,
+    compressed_length_and_codec: u32,
     pub uncompressed_length: u32,
 }
 
+impl BlockHeader {
+    pub fn new(codec: Codec, compressed_length: u32, uncompressed_length: u32) -> BlockHeader {
+        debug_assert!(compressed_length < 1 << CODEC_SHIFT);
+        BlockHeader {
+            compressed_length_and_codec: compressed_length | (codec as u32) << CODEC_SHIFT,
+            uncompressed_length,
+        }
+    }
+
+    pub fn compressed_length(&self) -> u32 {
+        self.compressed_length_and_codec & ((1 << CODEC_SHIFT) - 1)
+    }
+
+    /// `None` if the block uses a codec we don't know about.
+    pub fn codec(&self) -> Option<Codec> {
+        Codec::from_u32(self.compressed_length_and_codec >> CODEC_SHIFT)
+    }
+}
+
 /// CompressedWriter opens an output file and writes compressed blocks to it.
 /// Blocks of a fixed but unspecified size (currently 1MB) are compressed.
 /// Each block of compressed data is written to the file preceded by two
 /// 32-bit words: the size of the compressed data (excluding block header)
-/// and the size of the uncompressed data, in that order. See BlockHeader below.
+/// and the size of the uncompressed data, in that order. The top bits of the
+/// first word hold the codec. See BlockHeader above.
 ///
 /// We use multiple threads to perform compression. The threads are
 /// responsible for the actual data writes. The thread that creates the
@@ -53,7 +80,8 @@ pub struct BlockHeader {
 /// 'write'. The producer thread may block in 'write' if 'buffer_size' bytes are
 /// being compressed.
 ///
-/// Each data block is compressed independently using brotli.
+/// Each data block is compressed independently using the writer's
+/// Compression (brotli by default).
 pub struct CompressedWriter {
     /// Immutable while threads are running
     fd: ScopedFd,
@@ -94,9 +122,21 @@ unsafe impl Send for SharedBuf {}
 
 impl CompressedWriter {
     pub fn good(&self) -> bool {
-        self.error
+        !self.error
+    }
+
+    /// The number of uncompressed bytes written so far.
+    pub fn uncompressed_pos(&self) -> u64 {
+        self.producer_reserved_write_pos
     }
-    pub fn new(filename: &OsStr, block_size: usize, num_threads: usize) -> CompressedWriter {
+
+    pub fn new(
+        filename: &OsStr,
+        block_size: usize,
+        num_threads: usize,
+        compression: Compression,
+    ) -> CompressedWriter {
+        assert!(block_size > 0 && block_size <= MAX_BLOCK_SIZE);
         let fd = ScopedFd::open_path_with_mode(
             filename,
             OFlag::O_CLOEXEC
@@ -173,45 +213,44 @@ impl CompressedWriter {
                                 ((block_size as f64 * 1.1) as usize) + size_of::<BlockHeader>(),
                                 0u8,
                             );
-                            let mut header: BlockHeader = Default::default();
 
                             loop {
                                 if !g.write_error
                                     && g.next_thread_pos < g.next_thread_end_pos
                                     && (g.closing
                                         || g.next_thread_pos + block_size as u64
-                                            <= next_thread_end_pos)
+                                            <= g.next_thread_end_pos)
                                 {
                                     g.thread_pos[thread_index] = Some(g.next_thread_pos);
                                     g.next_thread_pos = min(
-                                        next_thread_end_pos,
-                                        next_thread_pos + block_size as u64,
+                                        g.next_thread_end_pos,
+                                        g.next_thread_pos + block_size as u64,
                                     );
-                                    // header.uncompressed_length must be <= block_size,
+                                    // uncompressed_length must be <= block_size,
                                     // therefore fits in a size_t.
-                                    header.uncompressed_length = (g.next_thread_pos
+                                    let uncompressed_length: u32 = (g.next_thread_pos
                                         - g.thread_pos[thread_index].unwrap())
                                     .try_into()
                                     .unwrap();
 
                                     let offset_in_input_buf = g.thread_pos[thread_index].unwrap();
                                     drop(g);
-                                    let compressed_length: usize = unsafe {
-                                        do_compress(
+                                    let (codec, compressed_length) = compress(
+                                        compression,
+                                        &block_input(
                                             buffer,
                                             offset_in_input_buf,
-                                            header.uncompressed_length as usize,
-                                            &mut outputbuf[size_of::<BlockHeader>()..],
-                                        )
-                                    };
+                                            uncompressed_length as usize,
+                                        ),
+                                        &mut outputbuf[size_of::<BlockHeader>()..],
+                                    );
+                                    let header = BlockHeader::new(
+                                        codec,
+                                        compressed_length as u32,
+                                        uncompressed_length,
+                                    );
                                     g = mutex.lock().unwrap();
 
-                                    if 0 == compressed_length {
-                                        g.write_error = true;
-                                    } else {
-                                        header.compressed_length = compressed_length as u32;
-                                    }
-
                                     unsafe {
                                         copy_nonoverlapping(
                                             &raw const header as *const u8,
@@ -242,7 +281,7 @@ impl CompressedWriter {
                                         write_all(
                                             fd_raw,
                                             &outputbuf[0..size_of::<BlockHeader>()
-                                                + header.compressed_length as usize],
+                                                + header.compressed_length() as usize],
                                         );
                                         g = mutex.lock().unwrap();
                                     }
@@ -383,59 +422,15 @@ impl Write for CompressedWriter {
     }
 }
 
-/// See http://robert.ocallahan.org/2017/07/selecting-compression-algorithm-for-rr.html
-const RD_BROTLI_LEVEL: u32 = 5;
-
-unsafe fn do_compress(
-    shared_buf: &[u8],
-    mut stream_offset: u64,
-    mut uncompressed_len: usize,
-    output_buf: &mut [u8],
-) -> usize {
-    let state = BrotliEncoderCreateInstance(None, None, ptr::null_mut());
-    if state.is_null() {
-        fatal!("BrotliEncoderCreateInstance failed");
-    }
-
-    if 0 == BrotliEncoderSetParameter(state, BROTLI_PARAM_QUALITY, RD_BROTLI_LEVEL) {
-        fatal!("Brotli initialization failed");
-    }
-
-    let mut ret: usize = 0;
-    let mut output_buf_len: usize = output_buf.len();
-    let mut outp: *mut u8 = &raw mut output_buf[0];
-    while uncompressed_len > 0 {
-        let shared_buf_offset: usize = (stream_offset % shared_buf.len() as u64) as usize;
-        let mut amount: usize = min(uncompressed_len, shared_buf.len() - shared_buf_offset);
-        let mut inp = &raw const shared_buf[shared_buf_offset];
-        if 0 == BrotliEncoderCompressStream(
-            state,
-            BROTLI_OPERATION_PROCESS,
-            &mut amount,
-            &raw mut inp,
-            &mut output_buf_len,
-            &raw mut outp,
-            &raw mut ret,
-        ) {
-            fatal!("Brotli compression failed");
-        }
-        let consumed = inp as u64 - &raw const shared_buf[shared_buf_offset] as u64;
-        stream_offset += consumed;
-        uncompressed_len -= consumed as usize;
-    }
-    let mut zero: usize = 0;
-    if 0 == BrotliEncoderCompressStream(
-        state,
-        BROTLI_OPERATION_FINISH,
-        &raw mut zero,
-        ptr::null_mut(),
-        &mut output_buf_len,
-        &raw mut outp,
-        &raw mut ret,
-    ) {
-        fatal!("Brotli compression failed");
+/// The `len` bytes at stream position `offset` in the ring buffer `buffer`.
+fn block_input(buffer: &[u8], offset: u64, len: usize) -> Cow<'_, [u8]> {
+    let start = (offset % buffer.len() as u64) as usize;
+    if start + len <= buffer.len() {
+        Cow::Borrowed(&buffer[start..start + len])
+    } else {
+        let mut data = Vec::with_capacity(len);
+        data.extend_from_slice(&buffer[start..]);
+        data.extend_from_slice(&buffer[0..len - (buffer.len() - start)]);
+        Cow::Owned(data)
     }
-
-    BrotliEncoderDestroyInstance(state);
-    ret
 }
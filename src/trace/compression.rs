@@ -0,0 +1,321 @@
+//! The codecs CompressedWriter can compress blocks with. Every block header
+//! records the codec of its block, so readers don't need to be told how a
+//! trace was written and different substreams (or even blocks) can use
+//! different codecs.
+
+use brotli_sys::{
+    BrotliDecoderDecompress,
+    BrotliEncoderCompressStream,
+    BrotliEncoderCreateInstance,
+    BrotliEncoderDestroyInstance,
+    BrotliEncoderSetParameter,
+    BROTLI_DECODER_RESULT_SUCCESS,
+    BROTLI_OPERATION_FINISH,
+    BROTLI_OPERATION_PROCESS,
+    BROTLI_PARAM_QUALITY,
+};
+use std::{
+    fmt::{self, Display},
+    ptr,
+    str::FromStr,
+};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Codec {
+    Brotli = 0,
+    Zstd = 1,
+    Lz4 = 2,
+    /// The data is stored as is.
+    Uncompressed = 3,
+}
+
+const CODECS: [Codec; 4] = [Codec::Brotli, Codec::Zstd, Codec::Lz4, Codec::Uncompressed];
+
+impl Codec {
+    /// `None` if `value` isn't a known codec.
+    pub fn from_u32(value: u32) -> Option<Codec> {
+        CODECS.iter().copied().find(|&c| c as u32 == value)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::Brotli => "brotli",
+            Codec::Zstd => "zstd",
+            Codec::Lz4 => "lz4",
+            Codec::Uncompressed => "none",
+        }
+    }
+
+    /// The default and the maximum level. For lz4, level 0 is the fast mode
+    /// and higher levels use lz4hc.
+    fn levels(self) -> (u32, u32) {
+        match self {
+            // See http://robert.ocallahan.org/2017/07/selecting-compression-algorithm-for-rr.html
+            Codec::Brotli => (5, 11),
+            Codec::Zstd => (3, 22),
+            Codec::Lz4 => (0, 12),
+            Codec::Uncompressed => (0, 0),
+        }
+    }
+}
+
+/// A codec together with the level to compress with.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Compression {
+    pub codec: Codec,
+    pub level: u32,
+}
+
+impl Compression {
+    pub fn new(codec: Codec) -> Compression {
+        Compression {
+            codec,
+            level: codec.levels().0,
+        }
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::new(Codec::Brotli)
+    }
+}
+
+impl Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.codec {
+            Codec::Uncompressed => write!(f, "{}", self.codec.name()),
+            _ => write!(f, "{}:{}", self.codec.name(), self.level),
+        }
+    }
+}
+
+/// Parses `<codec>[:<level>]`, e.g. `zstd:19`.
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, ':');
+        let name = parts.next().unwrap();
+        let codec = match CODECS.iter().find(|c| c.name() == name) {
+            Some(&codec) => codec,
+            None => {
+                let names: Vec<&str> = CODECS.iter().map(|c| c.name()).collect();
+                return Err(format!(
+                    "Unknown codec `{}`. Expected one of: {}",
+                    name,
+                    names.join(", ")
+                ));
+            }
+        };
+        let mut compression = Compression::new(codec);
+        if let Some(level_str) = parts.next() {
+            let max_level = codec.levels().1;
+            compression.level = match level_str.parse::<u32>() {
+                Ok(level) if level <= max_level => level,
+                _ => {
+                    return Err(format!(
+                        "Invalid level `{}` for {}. Expected 0-{}",
+                        level_str,
+                        codec.name(),
+                        max_level
+                    ))
+                }
+            };
+        }
+        Ok(compression)
+    }
+}
+
+/// Compress `input` into `output`, which must be at least as large as
+/// `input`. Returns the codec the data ended up compressed with and the
+/// compressed length.
+///
+/// Blocks that the codec fails on or that don't get any smaller are stored
+/// uncompressed.
+pub fn compress(compression: Compression, input: &[u8], output: &mut [u8]) -> (Codec, usize) {
+    let maybe_len = match compression.codec {
+        Codec::Brotli => unsafe { brotli_compress(compression.level, input, output) },
+        Codec::Zstd => {
+            zstd::block::compress_to_buffer(input, output, compression.level as i32).ok()
+        }
+        Codec::Lz4 => {
+            let mode = if compression.level == 0 {
+                lz4::block::CompressionMode::DEFAULT
+            } else {
+                lz4::block::CompressionMode::HIGHCOMPRESSION(compression.level as i32)
+            };
+            match lz4::block::compress(input, Some(mode), false) {
+                Ok(compressed) if compressed.len() <= output.len() => {
+                    output[0..compressed.len()].copy_from_slice(&compressed);
+                    Some(compressed.len())
+                }
+                _ => None,
+            }
+        }
+        Codec::Uncompressed => None,
+    };
+
+    match maybe_len {
+        Some(len) if len < input.len() => (compression.codec, len),
+        _ => {
+            output[0..input.len()].copy_from_slice(input);
+            (Codec::Uncompressed, input.len())
+        }
+    }
+}
+
+/// Decompress `compressed` into `uncompressed`. Returns false unless the data
+/// decompresses to exactly the size of `uncompressed`.
+pub fn decompress(codec: Codec, compressed: &[u8], uncompressed: &mut [u8]) -> bool {
+    match codec {
+        Codec::Brotli => brotli_decompress(compressed, uncompressed),
+        Codec::Zstd => match zstd::block::decompress_to_buffer(compressed, uncompressed) {
+            Ok(len) => len == uncompressed.len(),
+            Err(_) => false,
+        },
+        Codec::Lz4 => match lz4::block::decompress(compressed, Some(uncompressed.len() as i32)) {
+            Ok(data) if data.len() == uncompressed.len() => {
+                uncompressed.copy_from_slice(&data);
+                true
+            }
+            _ => false,
+        },
+        Codec::Uncompressed => {
+            if compressed.len() != uncompressed.len() {
+                return false;
+            }
+            uncompressed.copy_from_slice(compressed);
+            true
+        }
+    }
+}
+
+/// Returns `None` if `output` is too small.
+unsafe fn brotli_compress(level: u32, input: &[u8], output: &mut [u8]) -> Option<usize> {
+    let state = BrotliEncoderCreateInstance(None, None, ptr::null_mut());
+    if state.is_null() {
+        fatal!("BrotliEncoderCreateInstance failed");
+    }
+
+    if 0 == BrotliEncoderSetParameter(state, BROTLI_PARAM_QUALITY, level) {
+        fatal!("Brotli initialization failed");
+    }
+
+    let mut ret: usize = 0;
+    let mut output_buf_len: usize = output.len();
+    let mut outp: *mut u8 = output.as_mut_ptr();
+    let mut amount: usize = input.len();
+    let mut inp: *const u8 = input.as_ptr();
+    let mut ok = true;
+    while ok && amount > 0 {
+        if 0 == BrotliEncoderCompressStream(
+            state,
+            BROTLI_OPERATION_PROCESS,
+            &mut amount,
+            &raw mut inp,
+            &mut output_buf_len,
+            &raw mut outp,
+            &raw mut ret,
+        ) {
+            fatal!("Brotli compression failed");
+        }
+        // Out of output space
+        ok = output_buf_len > 0;
+    }
+    let mut zero: usize = 0;
+    if ok
+        && 0 == BrotliEncoderCompressStream(
+            state,
+            BROTLI_OPERATION_FINISH,
+            &raw mut zero,
+            ptr::null_mut(),
+            &mut output_buf_len,
+            &raw mut outp,
+            &raw mut ret,
+        )
+    {
+        fatal!("Brotli compression failed");
+    }
+    // FINISH may run out of output space as well.
+    ok = ok && output_buf_len > 0;
+
+    BrotliEncoderDestroyInstance(state);
+    if ok {
+        Some(ret)
+    } else {
+        None
+    }
+}
+
+fn brotli_decompress(compressed: &[u8], uncompressed: &mut [u8]) -> bool {
+    let mut out_size = uncompressed.len();
+    let decompress_result = unsafe {
+        BrotliDecoderDecompress(
+            compressed.len(),
+            compressed.as_ptr(),
+            &raw mut out_size,
+            uncompressed.as_mut_ptr(),
+        )
+    };
+
+    decompress_result == BROTLI_DECODER_RESULT_SUCCESS && out_size == uncompressed.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(compression: Compression, input: &[u8]) {
+        let mut compressed = vec![0u8; input.len()];
+        let (codec, len) = compress(compression, input, &mut compressed);
+        let mut output = vec![0u8; input.len()];
+        assert!(decompress(codec, &compressed[0..len], &mut output));
+        assert_eq!(output, input);
+    }
+
+    #[test]
+    fn round_trips() {
+        let compressible: Vec<u8> = (0..64 * 1024).map(|i| (i % 7) as u8).collect();
+        let noise: Vec<u8> = (0..4096u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+        for s in &[
+            "brotli",
+            "brotli:11",
+            "zstd",
+            "zstd:19",
+            "lz4",
+            "lz4:9",
+            "none",
+        ] {
+            let compression: Compression = s.parse().unwrap();
+            round_trip(compression, &compressible);
+            round_trip(compression, &noise);
+        }
+    }
+
+    #[test]
+    fn incompressible_blocks_are_stored() {
+        let input = [1u8, 2, 3];
+        let mut output = [0u8; 3];
+        let (codec, len) = compress(Compression::new(Codec::Zstd), &input, &mut output);
+        assert_eq!(codec, Codec::Uncompressed);
+        assert_eq!(&output[0..len], &input);
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            "zstd:7".parse(),
+            Ok(Compression {
+                codec: Codec::Zstd,
+                level: 7
+            })
+        );
+        assert_eq!("lz4".parse(), Ok(Compression::new(Codec::Lz4)));
+        assert!("brotli:12".parse::<Compression>().is_err());
+        assert!("gzip".parse::<Compression>().is_err());
+        assert_eq!(Compression::default().to_string(), "brotli:5");
+    }
+}
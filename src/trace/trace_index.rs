@@ -0,0 +1,391 @@
+//! The optional trace index, which lets readers start at a frame in the middle
+//! of a trace. See FrameIndex in trace.capnp.
+
+use crate::{
+    log::LogLevel::LogWarn,
+    trace::{
+        compressed_reader::BlockLocation,
+        trace_frame::FrameTime,
+        trace_stream::SUBSTREAM_COUNT,
+    },
+    trace_capnp::frame_index,
+};
+use capnp::{
+    message::{self, ReaderOptions},
+    serialize_packed::{read_message, write_message},
+};
+use std::{
+    convert::TryInto,
+    ffi::OsStr,
+    fs::File,
+    io::{self, BufReader},
+};
+
+/// Where the data for a frame starts in a substream file: the offset of the
+/// compressed block in the file and the offset into its uncompressed data.
+#[derive(Copy, Clone, Default)]
+pub struct StreamPosition {
+    pub block_offset: u64,
+    pub offset_in_block: usize,
+}
+
+impl StreamPosition {
+    /// Find the uncompressed position `pos` in a substream file with the given
+    /// blocks. See `CompressedReader::block_locations()`.
+    pub fn locate(blocks: &[BlockLocation], pos: u64) -> StreamPosition {
+        let b = blocks
+            .iter()
+            .rev()
+            .find(|b| b.uncompressed_start <= pos)
+            .unwrap();
+        StreamPosition {
+            block_offset: b.file_offset,
+            offset_in_block: (pos - b.uncompressed_start) as usize,
+        }
+    }
+
+    /// The inverse of `locate()`. Returns `None` if this isn't a position in a
+    /// file with the given blocks.
+    pub fn uncompressed_pos(&self, blocks: &[BlockLocation]) -> Option<u64> {
+        let i = blocks
+            .iter()
+            .position(|b| b.file_offset == self.block_offset)?;
+        let block_len = match blocks.get(i + 1) {
+            Some(next) => next.uncompressed_start - blocks[i].uncompressed_start,
+            None => 0,
+        };
+        if self.offset_in_block as u64 > block_len {
+            return None;
+        }
+        Some(blocks[i].uncompressed_start + self.offset_in_block as u64)
+    }
+}
+
+/// There is an entry for the first frame that starts in each block of the
+/// events substream.
+#[derive(Copy, Clone)]
+pub struct IndexEntry {
+    pub frame_time: FrameTime,
+    /// Indexed by Substream
+    pub positions: [StreamPosition; SUBSTREAM_COUNT],
+}
+
+/// Read the trace index at `path`. Traces without a (valid) index just can't
+/// be seeked in, so this never fails.
+pub fn read_index(path: &OsStr) -> Vec<IndexEntry> {
+    let f = match File::open(path) {
+        Ok(f) => f,
+        Err(_) => return Vec::new(),
+    };
+    match parse_index(f) {
+        Ok(index) => index,
+        Err(e) => {
+            log!(LogWarn, "Ignoring invalid trace index {:?}: {}", path, e);
+            Vec::new()
+        }
+    }
+}
+
+fn parse_index(f: File) -> Result<Vec<IndexEntry>, String> {
+    let mut reader = BufReader::new(f);
+    let index_msg =
+        read_message(&mut reader, ReaderOptions::new()).map_err(|e| format!("{:?}", e))?;
+    let index = index_msg
+        .get_root::<frame_index::Reader>()
+        .map_err(|e| format!("{:?}", e))?;
+    let mut entries = Vec::new();
+    for e in index.get_entries().map_err(|e| format!("{:?}", e))?.iter() {
+        if e.get_frame_time() <= 0 {
+            return Err(format!("Invalid frame time {}", e.get_frame_time()));
+        }
+        let positions_reader = e.get_positions().map_err(|e| format!("{:?}", e))?;
+        if positions_reader.len() as usize != SUBSTREAM_COUNT {
+            return Err(format!("Expected {} positions", SUBSTREAM_COUNT));
+        }
+        let mut positions = [StreamPosition::default(); SUBSTREAM_COUNT];
+        for (i, p) in positions_reader.iter().enumerate() {
+            positions[i] = StreamPosition {
+                block_offset: p.get_block_offset(),
+                offset_in_block: p.get_offset_in_block() as usize,
+            };
+        }
+        entries.push(IndexEntry {
+            frame_time: e.get_frame_time() as FrameTime,
+            positions,
+        });
+    }
+    Ok(entries)
+}
+
+pub fn write_index(path: &OsStr, entries: &[IndexEntry]) -> io::Result<()> {
+    let mut index_msg = message::Builder::new_default();
+    let index = index_msg.init_root::<frame_index::Builder>();
+    let mut entries_builder = index.init_entries(entries.len() as u32);
+    for (i, entry) in entries.iter().enumerate() {
+        let mut e = entries_builder.reborrow().get(i as u32);
+        // DIFF NOTE: global_time is a u64 in rd and i64 on rr
+        e.set_frame_time(entry.frame_time as i64);
+        let mut positions_builder = e.init_positions(SUBSTREAM_COUNT as u32);
+        for (j, p) in entry.positions.iter().enumerate() {
+            let mut position = positions_builder.reborrow().get(j as u32);
+            position.set_block_offset(p.block_offset);
+            position.set_offset_in_block(p.offset_in_block.try_into().unwrap());
+        }
+    }
+
+    let mut f = File::create(path)?;
+    write_message(&mut f, &index_msg)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))
+}
+
+/// Move the positions of `index` from the old to the new files of a trace,
+/// given the blocks of each substream file before and after. `new_pos` maps an
+/// uncompressed position in the old data of a substream (indexed by Substream)
+/// to the matching position in the new data. Returns `None` if the index
+/// doesn't match the old files or a position can't be mapped.
+pub fn remap_index<F: Fn(usize, u64) -> Option<u64>>(
+    index: &[IndexEntry],
+    old_blocks: &[Vec<BlockLocation>],
+    new_blocks: &[Vec<BlockLocation>],
+    new_pos: F,
+) -> Option<Vec<IndexEntry>> {
+    let mut new_index = Vec::with_capacity(index.len());
+    for entry in index {
+        let mut new_entry = *entry;
+        for (i, p) in entry.positions.iter().enumerate() {
+            let pos = new_pos(i, p.uncompressed_pos(&old_blocks[i])?)?;
+            new_entry.positions[i] = StreamPosition::locate(&new_blocks[i], pos);
+        }
+        new_index.push(new_entry);
+    }
+    Some(new_index)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        trace::{
+            compressed_reader::CompressedReader,
+            compressed_writer::CompressedWriter,
+            compression::{Codec, Compression},
+        },
+        util::test_dir,
+    };
+    use std::{
+        fs,
+        io::{Read, Write},
+    };
+
+    const BLOCK_SIZE: usize = 256;
+    const FRAME_SIZE: usize = 16;
+
+    fn frame_data(time: FrameTime) -> [u8; FRAME_SIZE] {
+        let mut data = [0u8; FRAME_SIZE];
+        data[0..8].copy_from_slice(&time.to_le_bytes());
+        data[8..16].copy_from_slice(&(time * 3).to_le_bytes());
+        data
+    }
+
+    /// Write frames 1..=`count` to `path` and index them the way TraceWriter
+    /// does: an entry for the first frame that starts in each block. Every
+    /// substream position is the same, because there is only one stream here.
+    fn write_frames(path: &OsStr, count: FrameTime) -> Vec<IndexEntry> {
+        let mut writer = CompressedWriter::new(path, BLOCK_SIZE, 1, Compression::new(Codec::Lz4));
+        let mut index: Vec<(FrameTime, u64)> = Vec::new();
+        for time in 1..=count {
+            let pos = writer.uncompressed_pos();
+            match index.last() {
+                Some(&(_, last)) if last / BLOCK_SIZE as u64 == pos / BLOCK_SIZE as u64 => (),
+                _ => index.push((time, pos)),
+            }
+            writer.write_all(&frame_data(time)).unwrap();
+        }
+        writer.close(None);
+        assert!(writer.good());
+
+        let blocks = CompressedReader::new(path).block_locations().unwrap();
+        index
+            .iter()
+            .map(|&(frame_time, pos)| IndexEntry {
+                frame_time,
+                positions: [StreamPosition::locate(&blocks, pos); SUBSTREAM_COUNT],
+            })
+            .collect()
+    }
+
+    fn blocks() -> Vec<BlockLocation> {
+        // Three blocks of 100 uncompressed bytes, then the end of the file.
+        (0..4)
+            .map(|i| BlockLocation {
+                file_offset: i * 40,
+                uncompressed_start: i * 100,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn locate_test() {
+        let blocks = blocks();
+        for &(pos, block_offset, offset_in_block) in &[
+            (0, 0, 0),
+            (99, 0, 99),
+            (100, 40, 0),
+            (250, 80, 50),
+            // The end of the file
+            (300, 120, 0),
+        ] {
+            let p = StreamPosition::locate(&blocks, pos);
+            assert_eq!(p.block_offset, block_offset);
+            assert_eq!(p.offset_in_block, offset_in_block);
+            assert_eq!(p.uncompressed_pos(&blocks), Some(pos));
+        }
+    }
+
+    #[test]
+    fn uncompressed_pos_test() {
+        let blocks = blocks();
+        let pos = |block_offset, offset_in_block| {
+            StreamPosition {
+                block_offset,
+                offset_in_block,
+            }
+            .uncompressed_pos(&blocks)
+        };
+        assert_eq!(pos(40, 0), Some(100));
+        // The end of a block is also a position in it.
+        assert_eq!(pos(40, 100), Some(200));
+        assert_eq!(pos(40, 101), None);
+        // Not the start of a block
+        assert_eq!(pos(41, 0), None);
+        assert_eq!(pos(120, 0), Some(300));
+        assert_eq!(pos(120, 1), None);
+        assert_eq!(pos(160, 0), None);
+    }
+
+    #[test]
+    fn remap_index_test() {
+        let old_blocks = vec![blocks(); SUBSTREAM_COUNT];
+        // The same 300 bytes in blocks of 150.
+        let new_blocks = vec![
+            (0..3)
+                .map(|i| BlockLocation {
+                    file_offset: i * 70,
+                    uncompressed_start: i * 150,
+                })
+                .collect::<Vec<_>>();
+            SUBSTREAM_COUNT
+        ];
+        let entry = |block_offset, offset_in_block| IndexEntry {
+            frame_time: 1,
+            positions: [StreamPosition {
+                block_offset,
+                offset_in_block,
+            }; SUBSTREAM_COUNT],
+        };
+
+        let index = [entry(0, 10), entry(40, 60), entry(120, 0)];
+        let new_index = remap_index(&index, &old_blocks, &new_blocks, |_, pos| Some(pos)).unwrap();
+        let positions: Vec<(u64, usize)> = new_index
+            .iter()
+            .map(|e| (e.positions[0].block_offset, e.positions[0].offset_in_block))
+            .collect();
+        assert_eq!(positions, vec![(0, 10), (70, 10), (140, 0)]);
+
+        // Data moved around in the new files
+        let new_index =
+            remap_index(&index, &old_blocks, &new_blocks, |_, pos| Some(pos / 2)).unwrap();
+        assert_eq!(new_index[1].positions[0].block_offset, 0);
+        assert_eq!(new_index[1].positions[0].offset_in_block, 80);
+        assert!(remap_index(&index, &old_blocks, &new_blocks, |_, _| None).is_none());
+
+        // Not a position in the old files
+        assert!(
+            remap_index(&[entry(41, 0)], &old_blocks, &new_blocks, |_, pos| Some(
+                pos
+            ))
+            .is_none()
+        );
+    }
+
+    #[test]
+    fn index_round_trip_test() {
+        let dir = test_dir("index-round-trip");
+        let frames_path = dir.join("frames");
+        let index_path = dir.join("index");
+        let entries = write_frames(frames_path.as_os_str(), 1000);
+        // 1000 frames of 16 bytes in 256 byte blocks.
+        assert_eq!(entries.len(), 63);
+
+        write_index(index_path.as_os_str(), &entries).unwrap();
+        let read_entries = read_index(index_path.as_os_str());
+        assert_eq!(read_entries.len(), entries.len());
+        for (e, r) in entries.iter().zip(read_entries.iter()) {
+            assert_eq!(e.frame_time, r.frame_time);
+            for (p, q) in e.positions.iter().zip(r.positions.iter()) {
+                assert_eq!(p.block_offset, q.block_offset);
+                assert_eq!(p.offset_in_block, q.offset_in_block);
+            }
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn seek_to_indexed_frame_test() {
+        let dir = test_dir("index-seek");
+        let frames_path = dir.join("frames");
+        let index_path = dir.join("index");
+        write_index(
+            index_path.as_os_str(),
+            &write_frames(frames_path.as_os_str(), 1000),
+        )
+        .unwrap();
+        let index = read_index(index_path.as_os_str());
+
+        for &target in &[1, 16, 17, 500, 999, 1000] {
+            let entry = index.iter().rev().find(|e| e.frame_time <= target).unwrap();
+            // Never more than a block's worth of frames to read through.
+            assert!(target - entry.frame_time < (BLOCK_SIZE / FRAME_SIZE) as FrameTime);
+
+            let mut reader = CompressedReader::new(frames_path.as_os_str());
+            let p = entry.positions[0];
+            reader
+                .seek_to_block(p.block_offset, p.offset_in_block)
+                .unwrap();
+            let mut data = [0u8; FRAME_SIZE];
+            for time in entry.frame_time..=target {
+                reader.read_exact(&mut data).unwrap();
+                assert_eq!(data, frame_data(time));
+            }
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_index_test() {
+        let dir = test_dir("index-invalid");
+        let index_path = dir.join("index");
+        assert!(read_index(index_path.as_os_str()).is_empty());
+
+        let entry = IndexEntry {
+            frame_time: 1,
+            positions: Default::default(),
+        };
+        write_index(index_path.as_os_str(), &[entry; 8]).unwrap();
+        assert_eq!(read_index(index_path.as_os_str()).len(), 8);
+        let data = fs::read(&index_path).unwrap();
+        fs::write(&index_path, &data[0..data.len() / 2]).unwrap();
+        assert!(read_index(index_path.as_os_str()).is_empty());
+
+        let entry = IndexEntry {
+            frame_time: 0,
+            positions: Default::default(),
+        };
+        write_index(index_path.as_os_str(), &[entry]).unwrap();
+        assert!(read_index(index_path.as_os_str()).is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
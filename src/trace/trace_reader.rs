@@ -21,7 +21,9 @@ use crate::{
     session::{address_space::kernel_mapping::KernelMapping, record_session::TraceUuid},
     trace::{
         compressed_reader::{CompressedReader, CompressedReaderState},
+        compression::Compression,
         trace_frame::{FrameTime, TraceFrame},
+        trace_index::{read_index, IndexEntry},
         trace_stream::{
             latest_trace_symlink,
             to_trace_arch,
@@ -32,7 +34,9 @@ use crate::{
             Substream,
             TraceRemoteFd,
             TraceStream,
+            BROTLI_ONLY_TRACE_VERSION,
             SUBSTREAMS,
+            SUBSTREAM_COUNT,
             TRACE_VERSION,
         },
         trace_task_event::{
@@ -67,7 +71,6 @@ use crate::{
 use capnp::{message::ReaderOptions, serialize_packed::read_message};
 use libc::{ino_t, pid_t, time_t, ENOENT};
 use nix::{
-    errno::errno,
     sys::{
         mman::{MapFlags, ProtFlags},
         stat::{stat, FileStat},
@@ -128,6 +131,11 @@ pub struct TraceReader {
     uuid_: TraceUuid,
     trace_uses_cpuid_faulting: bool,
     preload_thread_locals_recorded_: bool,
+    chaos_seed_: Option<u64>,
+    /// Empty if the trace doesn't record how its substreams were compressed
+    compression_: Vec<Compression>,
+    /// Empty if the trace has no index
+    index: Vec<IndexEntry>,
 }
 
 impl Deref for TraceReader {
@@ -580,6 +588,33 @@ impl TraceReader {
         self.global_time = 0;
     }
 
+    /// Use the trace index to move to the latest indexed frame at or before
+    /// `time`, without reading the frames in between. The next `read_frame`
+    /// returns that frame; reading on from there to `time` is up to the caller.
+    /// Only ever moves forward. Returns false and does nothing if the trace has
+    /// no index or no indexed frame would get us closer to `time`.
+    pub fn seek_to_indexed_frame(&mut self, time: FrameTime) -> bool {
+        let next_time = self.global_time + 1;
+        let entry = match self.index.iter().rev().find(|e| e.frame_time <= time) {
+            Some(&entry) if entry.frame_time > next_time => entry,
+            _ => return false,
+        };
+
+        log!(LogDebug, "Seeking to indexed frame {}", entry.frame_time);
+        for &s in &SUBSTREAMS {
+            let p = entry.positions[s as usize];
+            if let Err(e) = self
+                .reader_mut(s)
+                .seek_to_block(p.block_offset, p.offset_in_block)
+            {
+                fatal!("Unable to seek to frame {}: {:?}", entry.frame_time, e);
+            }
+        }
+        self.raw_recs.clear();
+        self.global_time = entry.frame_time - 1;
+        true
+    }
+
     pub fn uncompressed_bytes(&self) -> u64 {
         let mut total: u64 = 0;
         for w in self.readers.values() {
@@ -605,124 +640,51 @@ impl TraceReader {
             readers.insert(s, CompressedReader::new(&trace_stream.path(s)));
         }
 
-        let path = trace_stream.version_path();
-        let version_file: File = match File::open(&path) {
-            Err(e) => {
-                if errno() == ENOENT {
-                    let incomplete_path = trace_stream.incomplete_version_path();
-                    if access(incomplete_path.as_os_str(), AccessFlags::F_OK).is_ok() {
-                        eprintln!(
-                            "\nrd: Trace file {:?} found.\n\
-                             rd recording terminated abnormally and the trace is incomplete: {:?}.\n",
-                            incomplete_path, e
-                        );
-                    } else {
-                        eprintln!(
-                            "\nrd: Trace file {:?} not found. There is no trace there: {:?}.\n",
-                            path, e
-                        );
-                    }
-                } else {
-                    eprintln!("\nrd: Trace file {:?} not readable: {:?}\n", path, e);
-                }
-                exit(EX_DATAERR as i32);
-            }
-            Ok(f) => f,
-        };
-        let mut version_str = String::new();
-        let mut buf_reader = BufReader::new(version_file);
-        let res = buf_reader.read_line(&mut version_str);
-        match res {
+        let header = match read_header(&trace_stream) {
+            Ok(header) => header,
             Err(e) => {
-                eprintln!("Could not read from the version file {:?}: {:?}", path, e);
+                eprintln!("\nrd: {}\n", e);
                 exit(EX_DATAERR as i32);
             }
-            Ok(_) => (),
-        }
-
-        let maybe_version = version_str.trim().parse::<u32>();
-        let version: u32 = match maybe_version {
-            Ok(ver) => ver,
-            Err(e) => {
-                fatal!(
-                    "Could not successfully parse version file {:?}: {:?}",
-                    path,
-                    e
-                );
-            }
         };
-
-        if TRACE_VERSION != version {
-            eprintln!(
-                "\nrd: error: Recorded trace {:?} has an incompatible version {}; expected\n\
-                 {}.  Did you record {:?} with an older version of rd?  If so,\n\
-                 you'll need to replay {:?} with that older version.  Otherwise,\n\
-                 your trace is likely corrupted.\n",
-                path, version, TRACE_VERSION, path, path
-            );
-            exit(EX_DATAERR as i32);
-        }
-
-        let maybe_res = read_message(&mut buf_reader, ReaderOptions::new());
-        let header_msg = match maybe_res {
-            Ok(res) => res,
-            Err(e) => {
-                fatal!("Could not read version file {:?}: {:?}", path, e);
-            }
-        };
-
-        let header = header_msg.get_root::<header::Reader>().unwrap();
-        let bind_to_cpu = header.get_bind_to_cpu();
-        debug_assert!(bind_to_cpu >= 0);
-        // DIFF NOTE: In rd the bound cpu is Option<u32>.
-        // In rr it is signed with -1 denoting unbound.
-        trace_stream.bind_to_cpu = if bind_to_cpu == -1 {
-            None
-        } else if bind_to_cpu >= 0 {
-            Some(bind_to_cpu as u32)
-        } else {
-            fatal!("Unexpected value of `{}` for bound cpu", bind_to_cpu);
-        };
-        let trace_uses_cpuid_faulting = header.get_has_cpuid_faulting();
-        let cpuid_records_bytes = header.get_cpuid_records().unwrap();
-        let len = cpuid_records_bytes.len() / size_of::<CPUIDRecord>();
-        if cpuid_records_bytes.len() != len * size_of::<CPUIDRecord>() {
-            fatal!("Invalid CPUID records length");
-        }
-        let mut cpuid_records_: Vec<CPUIDRecord> = Vec::with_capacity(len);
-        cpuid_records_.resize(len, Default::default());
-        unsafe {
-            copy_nonoverlapping(
-                cpuid_records_bytes.as_ptr(),
-                cpuid_records_.as_mut_ptr() as *mut u8,
-                len * size_of::<CPUIDRecord>(),
-            );
-        }
-        let xcr0_ = header.get_xcr0();
-        let preload_thread_locals_recorded_ = header.get_preload_thread_locals_recorded();
-        let ticks_semantics_ = from_trace_ticks_semantics(header.get_ticks_semantics().unwrap());
-        let uuid_from_trace = header.get_uuid().unwrap();
-        let mut uuid_ = TraceUuid::zero();
-        if uuid_from_trace.len() != uuid_.bytes.len() {
-            fatal!("Invalid UUID length");
-        }
-        uuid_.bytes = uuid_from_trace.try_into().unwrap();
+        trace_stream.bind_to_cpu = header.bind_to_cpu;
 
         // Set the global time at 0, so that when we tick it for the first
         // event, it matches the initial global time at recording, 1.
         trace_stream.global_time = 0;
+        let index = read_index(&trace_stream.index_path());
         TraceReader {
             trace_stream,
-            xcr0_,
+            xcr0_: header.xcr0,
             readers,
-            cpuid_records_,
-            ticks_semantics_,
-            uuid_,
-            trace_uses_cpuid_faulting,
-            preload_thread_locals_recorded_,
+            cpuid_records_: header.cpuid_records,
+            ticks_semantics_: header.ticks_semantics,
+            uuid_: header.uuid,
+            trace_uses_cpuid_faulting: header.uses_cpuid_faulting,
+            preload_thread_locals_recorded_: header.preload_thread_locals_recorded,
+            chaos_seed_: header.chaos_seed,
+            compression_: header.compression,
             monotonic_time_: 0.0,
             raw_recs: vec![],
+            index,
+        }
+    }
+
+    /// Find the trace in `maybe_dir` like `new()` does, without reading
+    /// anything in it. `None` if there's no trace directory there.
+    pub fn find_trace<T: AsRef<OsStr>>(maybe_dir: Option<&T>) -> Option<TraceStream> {
+        let dir = resolve_trace_name(maybe_dir);
+        if !dir_exists(dir.as_os_str()) {
+            return None;
         }
+        Some(TraceStream::new(&dir, 1))
+    }
+
+    /// Check that this version of rd can read the trace's version file.
+    /// Returns what's wrong instead of exiting like `new()`, for tools that
+    /// check traces.
+    pub fn check_version_file(trace_stream: &TraceStream) -> Result<(), String> {
+        read_header(trace_stream).map(|_| ())
     }
 
     pub fn cpuid_records(&self) -> &[CPUIDRecord] {
@@ -761,6 +723,17 @@ impl TraceReader {
         &self.uuid_
     }
 
+    /// The scheduler's random seed if the trace was recorded in chaos mode.
+    pub fn chaos_seed(&self) -> Option<u64> {
+        self.chaos_seed_
+    }
+
+    /// How substream `s` was compressed, codec and level. `None` for traces
+    /// that don't record it.
+    pub fn substream_compression(&self, s: Substream) -> Option<Compression> {
+        self.compression_.get(s as usize).copied()
+    }
+
     pub fn ticks_semantics(&self) -> TicksSemantics {
         self.ticks_semantics_
     }
@@ -838,6 +811,150 @@ fn from_trace_signal(event_type: EventType, signal: signal::Reader) -> Event {
     Event::new_signal_event(event_type, sig_event)
 }
 
+/// What the version file says about a trace.
+struct TraceHeader {
+    bind_to_cpu: Option<u32>,
+    uses_cpuid_faulting: bool,
+    cpuid_records: Vec<CPUIDRecord>,
+    xcr0: u64,
+    preload_thread_locals_recorded: bool,
+    chaos_seed: Option<u64>,
+    ticks_semantics: TicksSemantics,
+    uuid: TraceUuid,
+    compression: Vec<Compression>,
+}
+
+/// Read the version file of the trace and check that this version of rd can
+/// read the trace. Returns what's wrong if it can't.
+fn read_header(trace_stream: &TraceStream) -> Result<TraceHeader, String> {
+    let path = trace_stream.version_path();
+    let version_file: File = match File::open(&path) {
+        Err(e) => {
+            if e.raw_os_error() == Some(ENOENT) {
+                let incomplete_path = trace_stream.incomplete_version_path();
+                if access(incomplete_path.as_os_str(), AccessFlags::F_OK).is_ok() {
+                    return Err(format!(
+                        "Trace file {:?} found.\n\
+                         rd recording terminated abnormally and the trace is incomplete: {:?}.",
+                        incomplete_path, e
+                    ));
+                }
+                return Err(format!(
+                    "Trace file {:?} not found. There is no trace there: {:?}.",
+                    path, e
+                ));
+            }
+            return Err(format!("Trace file {:?} not readable: {:?}", path, e));
+        }
+        Ok(f) => f,
+    };
+    let mut version_str = String::new();
+    let mut buf_reader = BufReader::new(version_file);
+    if let Err(e) = buf_reader.read_line(&mut version_str) {
+        return Err(format!(
+            "Could not read from the version file {:?}: {:?}",
+            path, e
+        ));
+    }
+
+    let version: u32 = match version_str.trim().parse::<u32>() {
+        Ok(ver) => ver,
+        Err(e) => {
+            return Err(format!(
+                "Could not successfully parse version file {:?}: {:?}",
+                path, e
+            ));
+        }
+    };
+
+    if TRACE_VERSION != version && BROTLI_ONLY_TRACE_VERSION != version {
+        return Err(format!(
+            "error: Recorded trace {:?} has an incompatible version {}; expected\n\
+             {}.  Did you record {:?} with an older version of rd?  If so,\n\
+             you'll need to replay {:?} with that older version.  Otherwise,\n\
+             your trace is likely corrupted.",
+            path, version, TRACE_VERSION, path, path
+        ));
+    }
+
+    let header_msg = match read_message(&mut buf_reader, ReaderOptions::new()) {
+        Ok(res) => res,
+        Err(e) => return Err(format!("Could not read version file {:?}: {:?}", path, e)),
+    };
+    let invalid = |e: capnp::Error| format!("Invalid header in version file {:?}: {:?}", path, e);
+
+    let header = header_msg.get_root::<header::Reader>().map_err(invalid)?;
+    let bind_to_cpu = header.get_bind_to_cpu();
+    // DIFF NOTE: In rd the bound cpu is Option<u32>.
+    // In rr it is signed with -1 denoting unbound.
+    let bind_to_cpu = if bind_to_cpu == -1 {
+        None
+    } else if bind_to_cpu >= 0 {
+        Some(bind_to_cpu as u32)
+    } else {
+        return Err(format!(
+            "Unexpected value of `{}` for bound cpu",
+            bind_to_cpu
+        ));
+    };
+    let cpuid_records_bytes = header.get_cpuid_records().map_err(invalid)?;
+    let len = cpuid_records_bytes.len() / size_of::<CPUIDRecord>();
+    if cpuid_records_bytes.len() != len * size_of::<CPUIDRecord>() {
+        return Err("Invalid CPUID records length".to_owned());
+    }
+    let mut cpuid_records: Vec<CPUIDRecord> = Vec::with_capacity(len);
+    cpuid_records.resize(len, Default::default());
+    unsafe {
+        copy_nonoverlapping(
+            cpuid_records_bytes.as_ptr(),
+            cpuid_records.as_mut_ptr() as *mut u8,
+            len * size_of::<CPUIDRecord>(),
+        );
+    }
+    let chaos_seed = if header.get_chaos_mode() {
+        Some(header.get_chaos_seed())
+    } else {
+        None
+    };
+    let ticks_semantics = match header.get_ticks_semantics() {
+        Ok(semantics) => from_trace_ticks_semantics(semantics),
+        Err(e) => return Err(format!("Invalid ticks semantics: {:?}", e)),
+    };
+    let uuid_from_trace = header.get_uuid().map_err(invalid)?;
+    let mut uuid = TraceUuid::zero();
+    if uuid_from_trace.len() != uuid.bytes.len() {
+        return Err("Invalid UUID length".to_owned());
+    }
+    uuid.bytes = uuid_from_trace.try_into().unwrap();
+    let mut compression = Vec::new();
+    for c in header.get_compression().map_err(invalid)?.iter() {
+        let c = c.map_err(invalid)?;
+        match c.parse::<Compression>() {
+            Ok(c) => compression.push(c),
+            Err(e) => return Err(format!("Invalid substream compression `{}`: {}", c, e)),
+        }
+    }
+    if !compression.is_empty() && compression.len() != SUBSTREAM_COUNT {
+        return Err(format!(
+            "Expected the compression of {} substreams, got {}",
+            SUBSTREAM_COUNT,
+            compression.len()
+        ));
+    }
+
+    Ok(TraceHeader {
+        bind_to_cpu,
+        uses_cpuid_faulting: header.get_has_cpuid_faulting(),
+        cpuid_records,
+        xcr0: header.get_xcr0(),
+        preload_thread_locals_recorded: header.get_preload_thread_locals_recorded(),
+        chaos_seed,
+        ticks_semantics,
+        uuid,
+        compression,
+    })
+}
+
 fn from_trace_ticks_semantics(semantics: TraceTicksSemantics) -> TicksSemantics {
     match semantics {
         TraceTicksSemantics::RetiredConditionalBranches => {
@@ -2,7 +2,7 @@ use crate::{
     kernel_abi::SupportedArch,
     remote_ptr::{RemotePtr, Void},
     taskish_uid::TaskUid,
-    trace::trace_frame::FrameTime,
+    trace::{compression::Compression, trace_frame::FrameTime},
     trace_capnp::Arch as TraceArch,
     util::{dir_exists, ensure_dir, real_path},
 };
@@ -17,13 +17,19 @@ use std::{
     slice::Iter,
 };
 
-pub const TRACE_VERSION: u32 = 85;
+/// DIFF NOTE: rr is at version 85. rd traces can have blocks that aren't
+/// brotli compressed (see BlockHeader), which an rd or rr that only knows
+/// version 85 would misread, so rd writes version 86.
+pub const TRACE_VERSION: u32 = 86;
+
+/// Traces of this version only have brotli blocks, which rd reads just fine.
+pub const BROTLI_ONLY_TRACE_VERSION: u32 = 85;
 
 pub const SUBSTREAM_COUNT: usize = 4;
 
 /// Update `substreams` and TRACE_VERSION when you update this list.
 #[repr(usize)]
-#[derive(Copy, Clone, Eq, PartialEq, Hash)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
 pub enum Substream {
     /// Substream that stores events (trace frames).
     Events = 0,
@@ -77,6 +83,41 @@ impl Substream {
     pub fn iter() -> Iter<'static, Substream> {
         SUBSTREAMS.iter()
     }
+
+    /// The name of the substream's file in the trace directory.
+    pub fn name(self) -> &'static str {
+        substream(self).name
+    }
+
+    pub fn from_name(name: &str) -> Option<Substream> {
+        SUBSTREAMS.iter().copied().find(|s| s.name() == name)
+    }
+
+    /// The block size the substream is recorded with.
+    pub fn block_size(self) -> usize {
+        substream(self).block_size
+    }
+
+    /// The number of compression threads the substream is recorded with.
+    pub fn threads(self) -> usize {
+        substream(self).threads
+    }
+}
+
+/// Turn `--compression` options into the compression of each substream,
+/// indexed by Substream. Later options override earlier ones, options
+/// without a substream apply to all substreams.
+pub fn compression_for_substreams(
+    options: &[(Option<Substream>, Compression)],
+) -> [Compression; SUBSTREAM_COUNT] {
+    let mut result = [Compression::default(); SUBSTREAM_COUNT];
+    for &(maybe_substream, c) in options {
+        match maybe_substream {
+            Some(s) => result[s as usize] = c,
+            None => result = [c; SUBSTREAM_COUNT],
+        }
+    }
+    result
 }
 
 pub(super) struct SubstreamData {
@@ -133,6 +174,11 @@ impl TraceStream {
         substream(Substream::Mmaps).block_size
     }
 
+    /// Return the path of the mmaps substream. `rd pack` rewrites it.
+    pub fn mmaps_path(&self) -> OsString {
+        self.path(Substream::Mmaps)
+    }
+
     pub(super) fn new(trace_dir: &OsStr, initial_time: FrameTime) -> TraceStream {
         TraceStream {
             trace_dir: real_path(trace_dir),
@@ -143,13 +189,21 @@ impl TraceStream {
     }
 
     /// Return the path of the file for the given substream.
-    pub(super) fn path(&self, s: Substream) -> OsString {
+    pub fn path(&self, s: Substream) -> OsString {
         let mut path_vec: Vec<u8> = Vec::from(self.trace_dir.as_bytes());
         path_vec.extend_from_slice(b"/");
         path_vec.extend_from_slice(substream(s).name.as_bytes());
         OsString::from_vec(path_vec)
     }
 
+    /// Return the path of the optional "index" file that lets readers seek
+    /// to a frame. See FrameIndex in trace.capnp.
+    pub fn index_path(&self) -> OsString {
+        let mut index_path: Vec<u8> = self.trace_dir.clone().into_vec();
+        index_path.extend_from_slice(b"/index");
+        OsString::from_vec(index_path)
+    }
+
     /// Return the path of "version" file, into which the current
     /// trace format version of rd is stored upon creation of the
     /// trace.
@@ -335,3 +389,36 @@ pub(super) fn to_trace_arch(arch: SupportedArch) -> TraceArch {
         SupportedArch::X64 => TraceArch::X8664,
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::trace::compression::Codec;
+
+    #[test]
+    fn compression_for_substreams_test() {
+        let zstd = Compression::new(Codec::Zstd);
+        let lz4 = Compression::new(Codec::Lz4);
+        let none = Compression::new(Codec::Uncompressed);
+        let brotli = Compression::default();
+
+        assert_eq!(compression_for_substreams(&[]), [brotli; SUBSTREAM_COUNT]);
+        assert_eq!(
+            compression_for_substreams(&[(None, zstd)]),
+            [zstd; SUBSTREAM_COUNT]
+        );
+        assert_eq!(
+            compression_for_substreams(&[(None, zstd), (Some(Substream::RawData), lz4)]),
+            [zstd, lz4, zstd, zstd]
+        );
+        assert_eq!(
+            compression_for_substreams(&[(Some(Substream::Mmaps), none)]),
+            [brotli, brotli, none, brotli]
+        );
+        // A later option for all substreams overrides earlier ones.
+        assert_eq!(
+            compression_for_substreams(&[(Some(Substream::RawData), lz4), (None, zstd)]),
+            [zstd; SUBSTREAM_COUNT]
+        );
+    }
+}
@@ -92,12 +92,53 @@ impl TraceTaskEventExit {
     }
 }
 
+#[derive(Clone)]
 pub struct TraceTaskEvent {
     pub(super) variant: TraceTaskEventVariant,
     pub(super) tid_: pid_t,
 }
 
 impl TraceTaskEvent {
+    pub fn for_clone(tid: pid_t, parent_tid: pid_t, own_ns_tid: pid_t, clone_flags: i32) -> Self {
+        TraceTaskEvent {
+            variant: TraceTaskEventVariant::Clone(TraceTaskEventClone {
+                parent_tid_: parent_tid,
+                own_ns_tid_: own_ns_tid,
+                clone_flags_: clone_flags,
+            }),
+            tid_: tid,
+        }
+    }
+
+    /// The exe base is not known until the exec has completed. Use
+    /// `set_exe_base()` to fill it in.
+    pub fn for_exec(tid: pid_t, file_name: &OsStr, cmd_line: Vec<OsString>) -> Self {
+        TraceTaskEvent {
+            variant: TraceTaskEventVariant::Exec(TraceTaskEventExec {
+                file_name_: file_name.to_owned(),
+                cmd_line_: cmd_line,
+                exe_base_: RemotePtr::null(),
+            }),
+            tid_: tid,
+        }
+    }
+
+    pub fn for_exit(tid: pid_t, exit_status: WaitStatus) -> Self {
+        TraceTaskEvent {
+            variant: TraceTaskEventVariant::Exit(TraceTaskEventExit {
+                exit_status_: exit_status,
+            }),
+            tid_: tid,
+        }
+    }
+
+    pub fn set_exe_base(&mut self, exe_base: RemotePtr<Void>) {
+        match &mut self.variant {
+            TraceTaskEventVariant::Exec(v) => v.exe_base_ = exe_base,
+            _ => panic!("Not a TraceTaskEventTypeExec"),
+        }
+    }
+
     pub fn tid(&self) -> pid_t {
         self.tid_
     }
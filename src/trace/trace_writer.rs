@@ -5,7 +5,7 @@ use crate::{
     event::{Event, EventType, SignalDeterministic, SignalResolvedDisposition, SyscallState},
     kernel_abi::{syscall_number_for_restart_syscall, RD_NATIVE_ARCH},
     kernel_supplement::{btrfs_ioctl_clone_range_args, BTRFS_IOC_CLONE_, BTRFS_IOC_CLONE_RANGE_},
-    log::LogLevel::LogDebug,
+    log::LogLevel::{LogDebug, LogWarn},
     perf_counters::{PerfCounters, TicksSemantics},
     preload_interface::{mprotect_record, SYSCALLBUF_PROTOCOL_VERSION},
     registers::Registers,
@@ -17,7 +17,11 @@ use crate::{
         task::record_task::RecordTask,
     },
     trace::{
+        compressed_reader::{BlockLocation, CompressedReader},
         compressed_writer::CompressedWriter,
+        compression::Compression,
+        trace_frame::FrameTime,
+        trace_index::{self, IndexEntry, StreamPosition},
         trace_stream::{
             latest_trace_symlink,
             make_trace_dir,
@@ -30,6 +34,7 @@ use crate::{
             TraceRemoteFd,
             TraceStream,
             SUBSTREAMS,
+            SUBSTREAM_COUNT,
             TRACE_VERSION,
         },
         trace_task_event::{TraceTaskEvent, TraceTaskEventVariant},
@@ -58,9 +63,10 @@ use crate::{
 };
 use capnp::{
     message,
+    message::ReaderOptions,
     primitive_list,
     private::layout::ListBuilder,
-    serialize_packed::write_message,
+    serialize_packed::{read_message, write_message},
 };
 use libc::{dev_t, ino_t, ioctl, pid_t, EEXIST, STDOUT_FILENO};
 use nix::{
@@ -76,13 +82,14 @@ use std::{
     collections::HashMap,
     convert::TryInto,
     ffi::{OsStr, OsString},
-    fs::{hard_link, rename, File},
-    io::Write,
+    fs::{hard_link, rename, File, OpenOptions},
+    io,
+    io::{BufRead, BufReader, Write},
     mem::size_of,
     ops::{Deref, DerefMut},
     os::unix::{
         ffi::{OsStrExt, OsStringExt},
-        fs::symlink,
+        fs::{symlink, OpenOptionsExt},
         io::FromRawFd,
     },
     path::Path,
@@ -162,6 +169,13 @@ pub struct TraceWriter {
     mmap_count: u32,
     has_cpuid_faulting_: bool,
     supports_file_data_cloning_: bool,
+    /// The scheduler's random seed if recording in chaos mode.
+    chaos_seed: Option<u64>,
+    /// The first frame that starts in each events block, with the uncompressed
+    /// position of every substream at that frame. Becomes the trace index.
+    index: Vec<(FrameTime, [u64; SUBSTREAM_COUNT])>,
+    /// How each substream is compressed, indexed by Substream.
+    compression: [Compression; SUBSTREAM_COUNT],
 }
 
 impl Deref for TraceWriter {
@@ -327,7 +341,55 @@ impl TraceWriter {
             Ok(_) => (),
         }
 
-        self.tick_time()
+        self.tick_time();
+        self.maybe_index_next_frame();
+    }
+
+    /// Add an index entry if the next frame starts in a new events block.
+    fn maybe_index_next_frame(&mut self) {
+        let block_size = substream(Substream::Events).block_size as u64;
+        let events_pos = self.writer(Substream::Events).uncompressed_pos();
+        if let Some((_, positions)) = self.index.last() {
+            if positions[Substream::Events as usize] / block_size == events_pos / block_size {
+                return;
+            }
+        }
+
+        let mut positions = [0u64; SUBSTREAM_COUNT];
+        for &s in Substream::iter() {
+            positions[s as usize] = self.writer(s).uncompressed_pos();
+        }
+        self.index.push((self.global_time, positions));
+    }
+
+    /// Write the index of the closed substreams. Entries are stored as block
+    /// offsets in the compressed files, which are only known now.
+    fn write_index(&self) -> io::Result<()> {
+        let mut blocks: Vec<Vec<BlockLocation>> = Vec::with_capacity(SUBSTREAM_COUNT);
+        for &s in &SUBSTREAMS {
+            blocks.push(CompressedReader::new(&self.path(s)).block_locations()?);
+        }
+        // The last entry may be for a frame that was never written.
+        let events_end = blocks[Substream::Events as usize]
+            .last()
+            .unwrap()
+            .uncompressed_start;
+        let entries: Vec<_> = self
+            .index
+            .iter()
+            .filter(|(_, positions)| positions[Substream::Events as usize] < events_end)
+            .map(|(time, positions)| {
+                let mut entry = IndexEntry {
+                    frame_time: *time,
+                    positions: Default::default(),
+                };
+                for (j, &pos) in positions.iter().enumerate() {
+                    entry.positions[j] = StreamPosition::locate(&blocks[j], pos);
+                }
+                entry
+            })
+            .collect();
+        trace_index::write_index(&self.index_path(), &entries)
     }
 
     /// Write mapped-region record to the trace.
@@ -577,11 +639,13 @@ impl TraceWriter {
     /// were not bound.
     /// The trace name is determined by `file_name` and _RD_TRACE_DIR/_RR_TRACE_DIR (if set)
     /// or by setting -o=<OUTPUT_TRACE_DIR>.
+    /// Substreams are compressed as given by `compression` (indexed by Substream).
     pub fn new(
         file_name: &OsStr,
         bind_to_cpu: Option<u32>,
         output_trace_dir: Option<&OsStr>,
         ticks_semantics_: TicksSemantics,
+        compression: &[Compression; SUBSTREAM_COUNT],
     ) -> TraceWriter {
         let mut tw = TraceWriter {
             trace_stream: TraceStream::new(&make_trace_dir(file_name, output_trace_dir), 1),
@@ -594,6 +658,9 @@ impl TraceWriter {
             cpuid_records: vec![],
             version_fd: ScopedFd::new(),
             supports_file_data_cloning_: false,
+            chaos_seed: None,
+            index: vec![],
+            compression: *compression,
         };
 
         tw.bind_to_cpu = bind_to_cpu;
@@ -601,9 +668,15 @@ impl TraceWriter {
         for &s in Substream::iter() {
             tw.writers.insert(
                 s,
-                CompressedWriter::new(&tw.path(s), substream(s).block_size, substream(s).threads),
+                CompressedWriter::new(
+                    &tw.path(s),
+                    substream(s).block_size,
+                    substream(s).threads,
+                    compression[s as usize],
+                ),
             );
         }
+        tw.maybe_index_next_frame();
 
         let ver_path = tw.incomplete_version_path();
         tw.version_fd = ScopedFd::open_path_with_mode(
@@ -665,6 +738,11 @@ impl TraceWriter {
         tw
     }
 
+    /// Record that we're in chaos mode and the random seed the scheduler uses.
+    pub fn set_chaos_seed(&mut self, maybe_seed: Option<u64>) {
+        self.chaos_seed = maybe_seed;
+    }
+
     /// Called after the calling thread is actually bound to `bind_to_cpu`.
     pub fn setup_cpuid_records(
         &mut self,
@@ -696,6 +774,11 @@ impl TraceWriter {
             w.close(None);
         }
 
+        // The index is optional, readers just can't seek without it.
+        if let Err(e) = self.write_index() {
+            log!(LogWarn, "Unable to write trace index: {:?}", e);
+        }
+
         let mut header_msg = message::Builder::new_default();
         let mut header = header_msg.init_root::<header::Builder>();
         // DIFF NOTE: In rd the bound cpu is an Option<u32>. In rr it is signed.
@@ -714,6 +797,9 @@ impl TraceWriter {
         ));
         header.set_syscallbuf_protocol_version(SYSCALLBUF_PROTOCOL_VERSION);
         header.set_preload_thread_locals_recorded(true);
+        header.set_chaos_mode(self.chaos_seed.is_some());
+        header.set_chaos_seed(self.chaos_seed.unwrap_or(0));
+        set_compression(&mut header, &self.compression);
         // Add a random UUID to the trace metadata. This lets tools identify a trace
         // easily.
         match maybe_uuid {
@@ -950,6 +1036,51 @@ fn try_make_process_file_name(t: &RecordTask, file_name: &OsStr) -> OsString {
     OsString::from_vec(process_file_name)
 }
 
+fn set_compression(header: &mut header::Builder, compression: &[Compression; SUBSTREAM_COUNT]) {
+    let mut list = header.reborrow().init_compression(SUBSTREAM_COUNT as u32);
+    for (i, c) in compression.iter().enumerate() {
+        list.set(i as u32, &c.to_string());
+    }
+}
+
+/// Copy the version file of `trace` to the new file `to`, recording
+/// `compression` as the compression of the substreams. The rest of the header,
+/// including the trace UUID, is kept as it is.
+pub fn copy_version_file_with_compression(
+    trace: &TraceStream,
+    to: &OsStr,
+    compression: &[Compression; SUBSTREAM_COUNT],
+) -> io::Result<()> {
+    let from = trace.version_path();
+    let invalid = |e: capnp::Error| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid header in version file {:?}: {:?}", from, e),
+        )
+    };
+    let mut buf_reader = BufReader::new(File::open(&from)?);
+    // The version line is rewritten below: the new compression may need it.
+    let mut version_str = String::new();
+    buf_reader.read_line(&mut version_str)?;
+    let old_msg = read_message(&mut buf_reader, ReaderOptions::new()).map_err(invalid)?;
+
+    let mut header_msg = message::Builder::new_default();
+    header_msg
+        .set_root(old_msg.get_root::<header::Reader>().map_err(invalid)?)
+        .map_err(invalid)?;
+    let mut header = header_msg.get_root::<header::Builder>().map_err(invalid)?;
+    set_compression(&mut header, compression);
+
+    let mut f = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(to)?;
+    f.write_all(format!("{}\n", TRACE_VERSION).as_bytes())?;
+    write_message(&mut f, &header_msg).map_err(invalid)?;
+    f.sync_all()
+}
+
 fn to_trace_ticks_semantics(semantics: TicksSemantics) -> TraceTicksSemantics {
     match semantics {
         TicksSemantics::TicksRetiredConditionalBranches => {
@@ -18,12 +18,19 @@ use crate::{
     kernel_abi::{native_arch, CloneParameterOrdering},
     kernel_supplement::sig_set_t,
     log::LogLevel::{LogDebug, LogWarn},
+    preload_interface::{syscallbuf_hdr, syscallbuf_record},
     registers::Registers,
     remote_code_ptr::RemoteCodePtr,
     remote_ptr::{RemotePtr, Void},
     scoped_fd::ScopedFd,
     session::{
-        address_space::{address_space::AddressSpace, kernel_mapping::KernelMapping},
+        address_space::{
+            address_space::{AddressSpace, Mapping},
+            kernel_map_iterator::KernelMapIterator,
+            kernel_mapping::KernelMapping,
+            MappingFlags,
+        },
+        session_inner::SessionInner,
         task::{
             task_common::{read_mem, read_val_mem},
             task_inner::CloneFlags,
@@ -38,6 +45,7 @@ use libc::{
     pwrite64,
     siginfo_t,
     ucontext_t,
+    _SC_NPROCESSORS_ONLN,
     CLONE_CHILD_CLEARTID,
     CLONE_CHILD_SETTID,
     CLONE_FILES,
@@ -57,7 +65,6 @@ use libc::{
     STDERR_FILENO,
     S_IFDIR,
     S_IFREG,
-    _SC_NPROCESSORS_ONLN,
 };
 use nix::{
     errno::errno,
@@ -91,16 +98,16 @@ use std::{
     env,
     env::var_os,
     ffi::{c_void, CStr, CString, OsStr, OsString},
-    fs::File,
+    fs::{create_dir_all, File},
     io,
-    io::{BufRead, BufReader, Error, ErrorKind, Read},
+    io::{BufRead, BufReader, Error, ErrorKind, Read, Write},
     mem,
     mem::{size_of, size_of_val, zeroed},
     os::{
         raw::c_long,
         unix::ffi::{OsStrExt, OsStringExt},
     },
-    path::Path,
+    path::{Path, PathBuf},
     ptr::copy_nonoverlapping,
     slice,
     sync::Mutex,
@@ -135,6 +142,7 @@ pub const CPUID_INTELBRANDSTRINGEND: u32 = 0x80000004;
 
 pub const OSXSAVE_FEATURE_FLAG: u32 = 1 << 27;
 pub const AVX_FEATURE_FLAG: u32 = 1 << 28;
+pub const PKU_FEATURE_FLAG: u32 = 1 << 3;
 pub const HLE_FEATURE_FLAG: u32 = 1 << 4;
 pub const XSAVEC_FEATURE_FLAG: u32 = 1 << 1;
 
@@ -534,6 +542,16 @@ pub fn tmp_dir() -> OsString {
     OsString::from("/tmp")
 }
 
+/// An empty directory for test `name` to put files in. The test should
+/// remove it when done.
+#[cfg(test)]
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = Path::new(&tmp_dir()).join(format!("rd-test-{}-{}", name, getpid()));
+    let _ = std::fs::remove_dir_all(&dir);
+    create_dir_all(&dir).unwrap();
+    dir
+}
+
 /// Create directory `dir`, creating parent directories as needed.
 /// `dir_type` is printed in error messages. Fails if the resulting directory
 /// is not writeable.
@@ -1590,10 +1608,55 @@ pub fn should_dump_memory(event: &Event, time: FrameTime) -> bool {
         || flags.dump_at == Some(time)
 }
 
-/// Dump all of the memory in `t`'s address to the file
-/// "<trace_dir>/<t.tid>_<global_time>_<tag>"
-pub fn dump_process_memory(_t: &dyn Task, _global_time: FrameTime, _tag: &str) {
-    unimplemented!()
+/// Dump all of the readable memory in `t`'s address space to the directory
+/// "<trace_dir>/<t.rec_tid>_<global_time>_<tag>".
+///
+/// Each mapping's contents go to a file named "<start>-<end>" and the mappings
+/// are listed in a "maps" file in the format of /proc/<pid>/maps, so dumps from
+/// recording and replay can be compared with `diff -r`.
+pub fn dump_process_memory(t: &mut dyn Task, global_time: FrameTime, tag: &str) {
+    let dirname = dump_dir_name(t, global_time, tag);
+    if let Err(e) = create_dir_all(&dirname) {
+        fatal!("Failed to create dump directory {:?}: {:?}", dirname, e);
+    }
+    let maps_filename = dirname.join("maps");
+    let mut maps_file = match File::create(&maps_filename) {
+        Ok(f) => f,
+        Err(e) => fatal!("Failed to open dump file {:?}: {:?}", maps_filename, e),
+    };
+
+    let mut mappings = Vec::new();
+    for (_, m) in &t.vm().maps() {
+        mappings.push(m.clone());
+    }
+
+    for m in mappings {
+        if !m.map.prot().contains(ProtFlags::PROT_READ) {
+            continue;
+        }
+        let mut mem = Vec::new();
+        mem.resize(m.map.size(), 0u8);
+        let mem_len = t.read_bytes_fallible(m.map.start(), &mut mem).unwrap_or(0);
+
+        let chunk_filename = dirname.join(format!(
+            "{:x}-{:x}",
+            m.map.start().as_usize(),
+            m.map.end().as_usize()
+        ));
+        let result = File::create(&chunk_filename)
+            .and_then(|mut f| f.write_all(&mem[0..mem_len]))
+            // During replay `m.map` is the emufs mapping, with a different
+            // fsname, device and inode. Write what was recorded so the maps
+            // files of recording and replay dumps match.
+            .and_then(|_| writeln!(maps_file, "{}", m.recorded_map));
+        if let Err(e) = result {
+            fatal!("Failed to write dump file {:?}: {:?}", chunk_filename, e);
+        }
+    }
+}
+
+fn dump_dir_name(t: &dyn Task, global_time: FrameTime, tag: &str) -> PathBuf {
+    Path::new(&t.trace_dir()).join(format!("{}_{}_{}", t.rec_tid, global_time, tag))
 }
 
 /// Return true if the user has requested `t`'s memory be
@@ -1624,14 +1687,272 @@ pub fn should_checksum(event: &Event, time: FrameTime) -> bool {
 /// Write a checksum of each mapped region in `t`'s address space to a
 /// special log, where it can be read by `validate_process_memory()`
 /// during replay
-pub fn checksum_process_memory(_t: &dyn Task, _global_time: FrameTime) {
-    unimplemented!()
+///
+/// Each line is "(<checksum>) [<page checksums>] <mapping>". The page checksums
+/// let replay name the first page that differs, not just the mapping.
+pub fn checksum_process_memory(t: &mut dyn Task, global_time: FrameTime) {
+    let filename = checksum_file_name(t, global_time);
+    let mut checksums_file = match File::create(&filename) {
+        Ok(f) => f,
+        Err(e) => fatal!("Failed to open checksum file {:?}: {:?}", filename, e),
+    };
+
+    for c in mapping_checksums(t) {
+        let pages: Vec<String> = c
+            .page_checksums
+            .iter()
+            .map(|p| format!("{:x}", p))
+            .collect();
+        if let Err(e) = writeln!(
+            checksums_file,
+            "({:x}) [{}] {}",
+            c.checksum,
+            pages.join(","),
+            c.mapping.map
+        ) {
+            fatal!("Failed to write checksum file {:?}: {:?}", filename, e);
+        }
+    }
 }
 
 /// Validate the checksum of `t`'s address space that was written
 /// during recording
-fn validate_process_memory(_t: &dyn Task, _global_time: FrameTime) {
-    unimplemented!()
+pub fn validate_process_memory(t: &mut dyn Task, global_time: FrameTime) {
+    let filename = checksum_file_name(t, global_time);
+    let checksums_file = match File::open(&filename) {
+        Ok(f) => f,
+        Err(e) => fatal!("Failed to open checksum file {:?}: {:?}", filename, e),
+    };
+    let mut lines = BufReader::new(checksums_file).lines();
+
+    for c in mapping_checksums(t) {
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            _ => fatal!(
+                "Checksum file {:?} has no entry for {}",
+                filename,
+                c.mapping.map
+            ),
+        };
+        let rec = match parse_checksum_line(&line) {
+            Some(rec) => rec,
+            None => fatal!("Failed to parse checksum line `{}' in {:?}", line, filename),
+        };
+
+        ed_assert!(
+            t,
+            rec.start == c.mapping.map.start() && rec.end == c.mapping.map.end(),
+            "Segment {}-{} changed to {}??",
+            rec.start,
+            rec.end,
+            c.mapping.map
+        );
+
+        if is_start_of_scratch_region(t, rec.start) {
+            // Replay doesn't touch scratch regions, so
+            // their contents are allowed to diverge.
+            // Tracees can't observe those segments
+            // unless they do something sneaky (or
+            // disastrously buggy).
+            log!(LogDebug, "Not validating scratch starting at {}", rec.start);
+            continue;
+        }
+        if c.checksum != rec.checksum {
+            notify_checksum_error(t, global_time, &c, &rec, &line);
+        }
+    }
+}
+
+struct MappingChecksum {
+    mapping: Mapping,
+    checksum: u32,
+    /// One checksum per page of the checksummed bytes
+    page_checksums: Vec<u32>,
+}
+
+struct ParsedChecksumLine {
+    checksum: u32,
+    page_checksums: Vec<u32>,
+    start: RemotePtr<Void>,
+    end: RemotePtr<Void>,
+}
+
+fn checksum_file_name(t: &dyn Task, global_time: FrameTime) -> PathBuf {
+    Path::new(&t.trace_dir()).join(format!("{}_{}", t.rec_tid, global_time))
+}
+
+fn compute_checksum(data: &[u8]) -> u32 {
+    let mut checksum = data.len() as u32;
+    for word in data.chunks_exact(size_of::<u32>()) {
+        let w = u32::from_ne_bytes(word.try_into().unwrap());
+        checksum = (checksum << 4).wrapping_add(checksum).wrapping_add(w);
+    }
+    checksum
+}
+
+/// Returns true if the contents of `m` may diverge and so are worth
+/// checksumming.
+fn checksum_segment_filter(m: &Mapping) -> bool {
+    let file_name = m.map.fsname();
+    let st = match stat(file_name) {
+        Ok(st) => st,
+        Err(_) => {
+            // If there's no persistent resource backing this
+            // mapping, we should expect it to change.
+            log!(LogDebug, "CHECKSUMMING unlinked {:?}", file_name);
+            return true;
+        }
+    };
+    // If we're pretty sure the backing resource is effectively
+    // immutable, skip checksumming, it's a waste of time.  Except
+    // if the mapping is mutable, for example the rw data segment
+    // of a system library, then it's interesting.
+    let is_mmap_clone = Path::new(file_name)
+        .file_name()
+        .map_or(false, |f| f.as_bytes().starts_with(b"mmap_clone_"));
+    let may_diverge = !is_mmap_clone
+        && (should_copy_mmap_region(&m.map, &st) || m.map.prot().contains(ProtFlags::PROT_WRITE));
+    log!(
+        LogDebug,
+        "{} {:?}",
+        if may_diverge {
+            "CHECKSUMMING"
+        } else {
+            "  skipping"
+        },
+        file_name
+    );
+    may_diverge
+}
+
+fn mapping_checksums(t: &mut dyn Task) -> Vec<MappingChecksum> {
+    let mut mappings = Vec::new();
+    for (_, m) in &t.vm().maps() {
+        mappings.push(m.clone());
+    }
+
+    let mut result = Vec::with_capacity(mappings.len());
+    for m in mappings {
+        let mut mem = Vec::new();
+        let mut valid_mem_len = 0;
+        if checksum_segment_filter(&m) {
+            mem.resize(m.map.size(), 0u8);
+            // Areas not read are treated as zero. We have to do this because
+            // mappings not backed by valid file data are not readable during
+            // recording but are read as 0 during replay.
+            valid_mem_len = t.read_bytes_fallible(m.map.start(), &mut mem).unwrap_or(0);
+        }
+
+        if m.flags.contains(MappingFlags::IS_SYSCALLBUF) {
+            // The syscallbuf consists of a region that's written
+            // deterministically wrt the trace events, and a
+            // region that's written nondeterministically in the
+            // same way as trace scratch buffers.  The
+            // deterministic region comprises committed syscallbuf
+            // records, and possibly the one pending record
+            // metadata.  The nondeterministic region starts at
+            // the "extra data" for the possibly one pending
+            // record.
+            //
+            // So here, we set things up so that we only checksum
+            // the deterministic region.
+            let child_hdr = RemotePtr::<syscallbuf_hdr>::cast(m.map.start());
+            let hdr = read_val_mem(t, child_hdr, None);
+            let deterministic_len = size_of::<syscallbuf_hdr>()
+                + hdr.num_rec_bytes as usize
+                + size_of::<syscallbuf_record>();
+            valid_mem_len = min(valid_mem_len, deterministic_len);
+        }
+
+        let data = &mem[0..valid_mem_len];
+        result.push(MappingChecksum {
+            checksum: compute_checksum(data),
+            page_checksums: data.chunks(page_size()).map(compute_checksum).collect(),
+            mapping: m,
+        });
+    }
+
+    result
+}
+
+fn parse_checksum_line(line: &str) -> Option<ParsedChecksumLine> {
+    let rest = line.strip_prefix('(')?;
+    let (checksum_str, rest) = rest.split_at(rest.find(')')?);
+    let rest = rest[1..].trim_start().strip_prefix('[')?;
+    let (pages_str, rest) = rest.split_at(rest.find(']')?);
+    let rest = rest[1..].trim_start();
+    let (start_str, rest) = rest.split_at(rest.find('-')?);
+    let end_str = rest[1..].split_whitespace().next()?;
+
+    let mut page_checksums = Vec::new();
+    for p in pages_str.split(',').filter(|p| !p.is_empty()) {
+        page_checksums.push(u32::from_str_radix(p, 16).ok()?);
+    }
+
+    Some(ParsedChecksumLine {
+        checksum: u32::from_str_radix(checksum_str, 16).ok()?,
+        page_checksums,
+        start: parse_hex_addr(start_str)?,
+        end: parse_hex_addr(end_str)?,
+    })
+}
+
+fn parse_hex_addr(s: &str) -> Option<RemotePtr<Void>> {
+    usize::from_str_radix(s.trim_start_matches("0x"), 16)
+        .ok()
+        .map(RemotePtr::from)
+}
+
+fn is_start_of_scratch_region(t: &dyn Task, start_addr: RemotePtr<Void>) -> bool {
+    if start_addr == t.scratch_ptr {
+        return true;
+    }
+    // `t` itself is borrowed up-stack, so skip tasks we can't borrow.
+    t.session().tasks().values().any(|c| {
+        c.try_borrow()
+            .map_or(false, |c| start_addr == c.scratch_ptr)
+    })
+}
+
+fn notify_checksum_error(
+    t: &mut dyn Task,
+    global_time: FrameTime,
+    c: &MappingChecksum,
+    rec: &ParsedChecksumLine,
+    raw_map_line: &str,
+) {
+    // The first page whose checksum differs. If all the common pages match,
+    // the number of readable pages differs.
+    let first_bad_page = c
+        .page_checksums
+        .iter()
+        .zip(rec.page_checksums.iter())
+        .position(|(rep, rec)| rep != rec)
+        .unwrap_or(min(c.page_checksums.len(), rec.page_checksums.len()));
+    dump_process_memory(t, global_time, "rep");
+    let rep_dump = dump_dir_name(t, global_time, "rep");
+    let ev = t
+        .as_replay_task()
+        .unwrap()
+        .current_trace_frame()
+        .event()
+        .clone();
+    ed_assert!(
+        t,
+        false,
+        "Divergence in contents of memory segment after '{}':\n\n{}\n    \
+         (recorded checksum: {:#x}; replaying checksum: {:#x})\n    \
+         first differing page: {}\n\n\
+         The replaying address space was dumped to {:?}. A dump of the \
+         recorded address space can be generated with --dump-at={}",
+        ev,
+        raw_map_line,
+        rec.checksum,
+        c.checksum,
+        c.mapping.map.start() + first_bad_page * page_size(),
+        rep_dump,
+        global_time
+    );
 }
 
 pub fn is_proc_mem_file(filename_os: &OsStr) -> bool {
@@ -1644,8 +1965,23 @@ pub fn is_proc_fd_dir(filename_os: &OsStr) -> bool {
     filename.starts_with(b"/proc/") && (filename.ends_with(b"/fd") || filename.ends_with(b"/fd/"))
 }
 
+/// Check that the sessions of this process have been torn down completely:
+/// none of the memory rd shared with tracees may still be mapped.
+///
+/// DIFF NOTE: rr aborts on a leak. A leak at this point can't affect the trace
+/// or the replay any more, so we only warn.
 pub fn check_for_leaks() {
-    unimplemented!()
+    // Don't do leak checking. The outcome doesn't change anything.
+    if running_under_rd() {
+        return;
+    }
+
+    let prefix = SessionInner::rd_mapping_prefix().as_bytes();
+    for km in KernelMapIterator::new_from_tid(getpid().as_raw()) {
+        if find(km.fsname().as_bytes(), prefix).is_some() {
+            log!(LogWarn, "Leaked {}", km);
+        }
+    }
 }
 
 pub fn signal_bit(sig: Sig) -> sig_set_t {
@@ -1693,3 +2029,45 @@ pub fn is_deterministic_signal(t: &mut dyn Task) -> SignalDeterministic {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_checksum_line_test() {
+        let parsed = parse_checksum_line(
+            "(1a2b3c) [aa,bb,0] 0x7f0000001000-0x7f0000004000 r-xp 00000000 08:01 1234       \
+             \"/lib/libc.so.6\"",
+        )
+        .unwrap();
+        assert_eq!(parsed.checksum, 0x1a2b3c);
+        assert_eq!(parsed.page_checksums, vec![0xaa, 0xbb, 0]);
+        assert_eq!(parsed.start, RemotePtr::from(0x7f00_0000_1000usize));
+        assert_eq!(parsed.end, RemotePtr::from(0x7f00_0000_4000usize));
+    }
+
+    #[test]
+    fn parse_checksum_line_padded_addresses_test() {
+        // KernelMapping pads short addresses with spaces.
+        let parsed =
+            parse_checksum_line("(0) []   0x1000-  0x2000 rw-p 00000000 00:00 0          \"\"")
+                .unwrap();
+        assert_eq!(parsed.checksum, 0);
+        assert!(parsed.page_checksums.is_empty());
+        assert_eq!(parsed.start, RemotePtr::from(0x1000usize));
+        assert_eq!(parsed.end, RemotePtr::from(0x2000usize));
+    }
+
+    #[test]
+    fn parse_checksum_line_malformed_test() {
+        assert!(parse_checksum_line("").is_none());
+        assert!(parse_checksum_line("1a2b [] 0x1000-0x2000").is_none());
+        assert!(parse_checksum_line("(1a2b [] 0x1000-0x2000").is_none());
+        assert!(parse_checksum_line("(1a2b) 0x1000-0x2000").is_none());
+        assert!(parse_checksum_line("(xyz) [] 0x1000-0x2000").is_none());
+        assert!(parse_checksum_line("(1a2b) [aa,zz] 0x1000-0x2000").is_none());
+        assert!(parse_checksum_line("(1a2b) [] 0x1000").is_none());
+        assert!(parse_checksum_line("(1a2b) [] 0x1000-").is_none());
+    }
+}
@@ -0,0 +1,55 @@
+//! End-to-end smoke tests. These need working perf counters and ptrace, which
+//! most CI containers don't provide, so they are ignored by default. Run them
+//! with `cargo test -- --ignored`.
+
+use std::{
+    env,
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+fn rd<P: AsRef<Path>>(args: &[&str], trace_dir: P) -> Output {
+    let output = Command::new(env!("CARGO_BIN_EXE_rd"))
+        .args(args)
+        .arg(trace_dir.as_ref())
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "rd {:?} failed: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output
+}
+
+fn trace_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("rd-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+#[test]
+#[ignore]
+fn record_then_replay() {
+    let dir = trace_dir("record-replay");
+    let record = Command::new(env!("CARGO_BIN_EXE_rd"))
+        .args(&["record", "-o"])
+        .arg(&dir)
+        .args(&["echo", "hello rd"])
+        .output()
+        .unwrap();
+    assert!(
+        record.status.success(),
+        "rd record failed: {}",
+        String::from_utf8_lossy(&record.stderr)
+    );
+    assert_eq!(String::from_utf8_lossy(&record.stdout), "hello rd\n");
+
+    let replay = rd(&["replay", "-a"], &dir);
+    assert_eq!(String::from_utf8_lossy(&replay.stdout), "hello rd\n");
+
+    rd(&["dump"], &dir);
+    fs::remove_dir_all(&dir).unwrap();
+}